@@ -0,0 +1,81 @@
+//! Packing and unpacking package archives: serializing a tree, and compressing it with each number of workers.
+
+use std::io::{self, Read as _, Write as _};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use porkg_bench::{
+    nar,
+    pack::{Compression, Decoder, Encoder},
+    sample, Tree,
+};
+
+const SIZE: usize = 64 * 1024 * 1024;
+
+fn serialize(c: &mut Criterion) {
+    let mut group = c.benchmark_group("archive/nar");
+    let tree = Tree::new("archive", 64, 64, 4096).unwrap();
+    let mut archive = Vec::new();
+    nar::dump(tree.path(), &mut archive).unwrap();
+    group.throughput(Throughput::Bytes(tree.size()));
+    group.bench_function("dump", |b| {
+        b.iter(|| nar::dump(tree.path(), io::sink()).unwrap())
+    });
+
+    let target = std::env::temp_dir().join(format!("porkg-bench-unpack-{}", std::process::id()));
+    group.bench_function("unpack", |b| {
+        b.iter(|| {
+            nar::unpack(&archive[..], &target).unwrap();
+            std::fs::remove_dir_all(&target).unwrap();
+        })
+    });
+    group.finish();
+}
+
+fn compress(c: &mut Criterion) {
+    let data = sample(SIZE);
+    let available = std::thread::available_parallelism().map_or(1, |v| v.get());
+    let mut workers = vec![1, 2, 4, available];
+    workers.sort_unstable();
+    workers.dedup();
+
+    let mut group = c.benchmark_group("archive/pack");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    for &workers in &workers {
+        group.bench_with_input(BenchmarkId::from_parameter(workers), &data, |b, data| {
+            b.iter(|| {
+                let mut encoder =
+                    Encoder::new(Vec::new(), Compression { level: 3, workers }).unwrap();
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            })
+        });
+    }
+    group.finish();
+
+    // Archives are decoded as the encoder wrote them, as a frame per block.
+    let compression = Compression {
+        level: 3,
+        workers: available,
+    };
+    let mut encoder = Encoder::new(Vec::new(), compression).unwrap();
+    encoder.write_all(&data).unwrap();
+    let compressed = encoder.finish().unwrap();
+    let mut group = c.benchmark_group("archive/unpack");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(SIZE as u64));
+    group.bench_function("decoder", |b| {
+        b.iter(|| {
+            let mut output = Vec::with_capacity(SIZE);
+            Decoder::new(io::Cursor::new(compressed.clone()))
+                .unwrap()
+                .read_to_end(&mut output)
+                .unwrap();
+            output
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, serialize, compress);
+criterion_main!(benches);
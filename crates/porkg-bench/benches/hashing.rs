@@ -0,0 +1,58 @@
+//! Hashing of content, of values that identify packages, and of directory trees.
+
+use std::{collections::BTreeMap, hint::black_box};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use porkg_bench::{nar, sample, Tree};
+use porkg_model::hashing::{resumable::ResumableHasher, StableHashExt as _, SupportedHasher};
+
+fn content(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hash/content");
+    for size in [64 * 1024, 16 * 1024 * 1024] {
+        let data = sample(size);
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::new("blake3", size), &data, |b, data| {
+            b.iter(|| {
+                let mut hasher = SupportedHasher::blake3();
+                hasher.update(data);
+                hasher.finalize()
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("resumable", size), &data, |b, data| {
+            b.iter(|| {
+                let mut hasher = ResumableHasher::new();
+                // Downloads are hashed as they arrive, in pieces of about this size.
+                for piece in data.chunks(64 * 1024) {
+                    hasher.update(piece);
+                }
+                hasher.finalize()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn stable(c: &mut Criterion) {
+    let value = (0..1000)
+        .map(|i| (format!("dependency-{i}"), vec![i as u64; 8]))
+        .collect::<BTreeMap<_, _>>();
+    c.bench_function("hash/stable", |b| {
+        b.iter(|| black_box(&value).hash(SupportedHasher::blake3()))
+    });
+}
+
+fn tree(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hash/tree");
+    for (name, dirs, files, size) in [
+        ("small-files", 64, 64, 1024),
+        ("large-files", 4, 4, 4 * 1024 * 1024),
+    ] {
+        let tree = Tree::new(name, dirs, files, size).unwrap();
+        group.throughput(Throughput::Bytes(tree.size()));
+        group.bench_function(name, |b| b.iter(|| nar::hash(tree.path()).unwrap()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, content, stable, tree);
+criterion_main!(benches);
@@ -0,0 +1,70 @@
+//! Taking and returning pooled values, alone and while other threads contend for the pool.
+
+use std::{
+    hint::black_box,
+    sync::Barrier,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use porkg_private::mem::{get_buffer, Pool, PoolBuilder};
+
+static POOL: Pool<'static, Box<u64>> = PoolBuilder::<Box<u64>>::new(64).build(|| Box::new(0));
+
+fn uncontended(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pool/uncontended");
+    group.bench_function("take-return", |b| b.iter(|| black_box(POOL.take())));
+    group.bench_function("buffer", |b| {
+        b.iter(|| {
+            let mut buffer = get_buffer();
+            buffer.extend_from_slice(b"message");
+            black_box(buffer)
+        })
+    });
+    group.finish();
+}
+
+fn contended(c: &mut Criterion) {
+    let mut group = c.benchmark_group("pool/contended");
+    let available = std::thread::available_parallelism().map_or(1, |v| v.get());
+    for threads in [2, 4, available.max(8)] {
+        group.throughput(Throughput::Elements(threads as u64));
+        group.bench_with_input(
+            BenchmarkId::from_parameter(threads),
+            &threads,
+            |b, &threads| {
+                // Every thread takes and returns `iters` values, so the time is that of one round across the threads.
+                b.iter_custom(|iters| {
+                    let barrier = Barrier::new(threads + 1);
+                    std::thread::scope(|s| {
+                        let handles = (0..threads)
+                            .map(|_| {
+                                s.spawn(|| {
+                                    barrier.wait();
+                                    let started = Instant::now();
+                                    for _ in 0..iters {
+                                        // Holds two values at once, so that the pool is both empty and full at times.
+                                        let first = POOL.take();
+                                        black_box(POOL.take());
+                                        black_box(first);
+                                    }
+                                    started.elapsed()
+                                })
+                            })
+                            .collect::<Vec<_>>();
+                        barrier.wait();
+                        handles
+                            .into_iter()
+                            .map(|v| v.join().unwrap())
+                            .max()
+                            .unwrap_or(Duration::ZERO)
+                    })
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, uncontended, contended);
+criterion_main!(benches);
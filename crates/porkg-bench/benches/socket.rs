@@ -0,0 +1,96 @@
+//! Throughput of framed messages over unix sockets, which is how the daemon talks to its sandboxes.
+
+use std::{
+    os::unix::net::UnixStream,
+    time::{Duration, Instant},
+};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use porkg_private::io::{DomainSocket as _, DomainSocketAsyncExt as _};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Message {
+    id: u64,
+    payload: Vec<u8>,
+}
+
+const SIZES: [usize; 3] = [64, 16 * 1024, 1024 * 1024];
+
+fn message(size: usize) -> Message {
+    Message {
+        id: 1,
+        payload: porkg_bench::sample(size),
+    }
+}
+
+fn blocking(c: &mut Criterion) {
+    let mut group = c.benchmark_group("socket/blocking");
+    for size in SIZES {
+        let message = message(size);
+        let (sender, receiver) = UnixStream::pair().unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &message, |b, message| {
+            b.iter_custom(|iters| {
+                let started = Instant::now();
+                std::thread::scope(|s| {
+                    s.spawn(|| {
+                        for _ in 0..iters {
+                            receiver.recv_message::<Message>(&mut Vec::new()).unwrap();
+                        }
+                    });
+                    for _ in 0..iters {
+                        sender.send_message(message, &[]).unwrap();
+                    }
+                });
+                started.elapsed()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn asynchronous(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("socket/async");
+    for size in SIZES {
+        let message = message(size);
+        let (sender, receiver) = runtime
+            .block_on(async { tokio::net::UnixStream::pair() })
+            .unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(size), &message, |b, message| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let started = Instant::now();
+                    let send = async {
+                        for _ in 0..iters {
+                            sender.send_message(message, &[]).await.unwrap();
+                        }
+                    };
+                    let receive = async {
+                        for _ in 0..iters {
+                            receiver
+                                .recv_message::<Message>(&mut Vec::new())
+                                .await
+                                .unwrap();
+                        }
+                    };
+                    tokio::join!(send, receive);
+                    started.elapsed()
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().measurement_time(Duration::from_secs(10));
+    targets = blocking, asynchronous
+}
+criterion_main!(benches);
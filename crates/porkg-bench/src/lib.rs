@@ -0,0 +1,87 @@
+//! Shared setup for the benchmarks of the hot paths in porkg.
+//!
+//! The daemon is a binary, so the modules of it that are measured are compiled into this crate as well. They only
+//! depend on the other crates in the workspace.
+//!
+//! ```text
+//! just bench
+//! just bench --bench archive -- --save-baseline before
+//! ```
+
+use std::{
+    fs,
+    io::{self, Write},
+    os::unix::fs::{symlink, PermissionsExt as _},
+    path::{Path, PathBuf},
+};
+
+#[path = "../../porkg-daemon/src/store/nar.rs"]
+pub mod nar;
+#[path = "../../porkg-daemon/src/store/pack.rs"]
+pub mod pack;
+
+/// Generates data that compresses moderately, like most package content.
+pub fn sample(size: usize) -> Vec<u8> {
+    let mut state = 1u64;
+    (0..size)
+        .map(|i| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            if i % 3 == 0 {
+                state as u8
+            } else {
+                b'a' + (i % 26) as u8
+            }
+        })
+        .collect()
+}
+
+/// A directory tree that is shaped like a package, which is removed when dropped.
+pub struct Tree {
+    path: PathBuf,
+    size: u64,
+}
+
+impl Tree {
+    /// Creates `dirs` directories of `files` files each, of `size` bytes, and some symlinks and executables.
+    pub fn new(name: &str, dirs: usize, files: usize, size: usize) -> io::Result<Self> {
+        let path = std::env::temp_dir().join(format!("porkg-bench-{name}-{}", std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path)?;
+        }
+        let tree = Self {
+            path,
+            size: (dirs * files * size) as u64,
+        };
+        let data = sample(size);
+        for dir in 0..dirs {
+            let dir = tree.path.join(format!("share/{dir}"));
+            fs::create_dir_all(&dir)?;
+            for file in 0..files {
+                let path = dir.join(format!("{file}.txt"));
+                fs::File::create(&path)?.write_all(&data)?;
+                if file % 8 == 0 {
+                    fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+                }
+            }
+            symlink("0.txt", dir.join("link"))?;
+        }
+        Ok(tree)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Gets the number of bytes of file content in the tree.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Drop for Tree {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+    }
+}
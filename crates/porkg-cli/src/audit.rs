@@ -0,0 +1,46 @@
+use porkg_model::audit::AuditReport;
+
+use crate::client::{read_string, Client};
+
+#[derive(Debug, clap::Args)]
+pub struct AuditArgs {
+    /// The hash of a package, or the name of a profile to audit its newest generation.
+    target: String,
+}
+
+/// Prints the packages in the closure of the target that are affected by vulnerabilities, and the packages that should
+/// be rebuilt once they are updated. Fails if any package is affected.
+pub async fn audit(client: &Client, args: AuditArgs) -> anyhow::Result<()> {
+    let response = client
+        .get(&format!("/api/v1/audit/{}", args.target))
+        .await?;
+    let report: AuditReport = serde_json::from_str(&read_string(response.into_body()).await?)?;
+
+    for finding in report.findings.iter() {
+        println!("{} {} {}", finding.name, finding.version, finding.path);
+        for vulnerability in finding.vulnerabilities.iter() {
+            let mut ids = vec![vulnerability.id.as_str()];
+            ids.extend(vulnerability.aliases.iter().map(String::as_str));
+            match &vulnerability.summary {
+                Some(summary) => println!("  {}: {summary}", ids.join(", ")),
+                None => println!("  {}", ids.join(", ")),
+            }
+        }
+    }
+    if !report.rebuild.is_empty() {
+        println!("rebuild after updating the affected packages:");
+        for package in report.rebuild.iter() {
+            println!("  {} {}", package.name, package.hash);
+        }
+    }
+
+    if !report.findings.is_empty() {
+        anyhow::bail!(
+            "{} of {} packages are affected by vulnerabilities",
+            report.findings.len(),
+            report.packages
+        );
+    }
+    eprintln!("no vulnerabilities in {} packages", report.packages);
+    Ok(())
+}
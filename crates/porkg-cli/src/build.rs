@@ -0,0 +1,226 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+use bytes::Bytes;
+use http_body_util::Full;
+use porkg_model::{
+    channel::check_yanked,
+    overlay::Overlay,
+    package::{LockDefinition, Package},
+    workspace::{self, Lock, Workspace, LOCK_FILE, WORKSPACE_FILE, WORKSPACE_LOCK_FILE},
+};
+
+use crate::{
+    channel,
+    client::{read_string, Client},
+};
+
+const MANIFEST: &str = "porkg.toml";
+
+#[derive(Debug, clap::Args)]
+pub struct BuildArgs {
+    /// The directory of the package, or of the workspace with `--workspace`.
+    #[arg(default_value = ".")]
+    dir: PathBuf,
+    /// Build every member of the workspace, in the order of their dependencies on each other.
+    #[arg(long)]
+    workspace: bool,
+    /// Resolve dependencies through the index snapshot that a channel pins, instead of the lock. Versions that the
+    /// index yanked are refused, unless the lock already pins them.
+    #[arg(long)]
+    channel: Option<String>,
+    /// Overlays to apply to every package, in order of increasing precedence.
+    #[arg(long = "overlay", value_name = "PATH")]
+    overlays: Vec<PathBuf>,
+}
+
+async fn read_toml<T: serde::de::DeserializeOwned>(path: &Path) -> anyhow::Result<T> {
+    let text = tokio::fs::read_to_string(path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    toml::from_str(&text).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+/// Reads a lock, which is empty if it doesn't exist.
+async fn read_lock(path: &Path) -> anyhow::Result<Lock> {
+    if tokio::fs::try_exists(path).await? {
+        read_toml(path).await
+    } else {
+        Ok(Lock::default())
+    }
+}
+
+/// Archives the directory of a package, so that it can be imported as the source of the package.
+///
+/// If the manifest was changed by an overlay or by passing through hashed variables, the changed manifest is archived in
+/// place of the one in the directory.
+fn archive(dir: &Path, manifest: Option<&str>) -> anyhow::Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(Vec::new());
+    builder.follow_symlinks(false);
+    let Some(manifest) = manifest else {
+        builder.append_dir_all("src", dir)?;
+        return Ok(builder.into_inner()?);
+    };
+
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_name() == MANIFEST {
+            continue;
+        }
+        let name = Path::new("src").join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            builder.append_dir_all(name, entry.path())?;
+        } else {
+            builder.append_path_with_name(entry.path(), name)?;
+        }
+    }
+    let mut header = tar::Header::new_gnu();
+    header.set_size(manifest.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(
+        &mut header,
+        Path::new("src").join(MANIFEST),
+        manifest.as_bytes(),
+    )?;
+    Ok(builder.into_inner()?)
+}
+
+/// Refuses dependencies that the index yanked (see [`check_yanked`]), and warns about the others that it has advisories
+/// for.
+fn check_advisories(
+    name: &str,
+    index: &Lock,
+    definition: &LockDefinition,
+    existing: &Lock,
+) -> anyhow::Result<()> {
+    if let Err(yanked) = check_yanked(index, definition, existing) {
+        let reasons: Vec<_> = yanked.iter().map(ToString::to_string).collect();
+        anyhow::bail!("refusing to build {name}: {}", reasons.join("; "));
+    }
+    let resolved = definition
+        .dependencies
+        .iter()
+        .chain(definition.build_dependencies.iter());
+    for (dependency, hash) in resolved {
+        if let Some(advisory) = index.advisories.get(hash) {
+            let mut notes = advisory.ids.clone();
+            if advisory.yanked {
+                notes.push("yanked".to_string());
+            }
+            eprintln!(
+                "warning: {name} depends on {dependency} {hash} ({})",
+                notes.join(", ")
+            );
+        }
+    }
+    Ok(())
+}
+
+pub async fn build(client: &Client, args: BuildArgs) -> anyhow::Result<()> {
+    let (dirs, lock) = if args.workspace {
+        let workspace: Workspace = read_toml(&args.dir.join(WORKSPACE_FILE)).await?;
+        let dirs = workspace
+            .workspace
+            .members
+            .iter()
+            .map(|v| args.dir.join(v))
+            .collect::<Vec<_>>();
+        (dirs, read_lock(&args.dir.join(WORKSPACE_LOCK_FILE)).await?)
+    } else {
+        (
+            vec![args.dir.clone()],
+            read_lock(&args.dir.join(LOCK_FILE)).await?,
+        )
+    };
+
+    // The lock that the packages were built with before, which may keep using versions that the channel yanked.
+    let existing = lock.clone();
+    let (lock, channel) = match &args.channel {
+        Some(name) => {
+            let resolved = channel::resolve(client, name).await?;
+            eprintln!("resolving through {name} at {}", resolved.channel.index);
+            (resolved.lock, Some(resolved.channel))
+        }
+        None => (lock, None),
+    };
+    // The lock without the overlay, which tells whether the overlay changed what a dependency resolves to.
+    let vanilla = lock.clone();
+    let (lock, overlay) = {
+        let mut lock = lock;
+        let mut overlay = Overlay::default();
+        for path in args.overlays.iter() {
+            overlay.extend(read_toml(path).await?);
+        }
+        overlay.apply_lock(&mut lock);
+        (lock, overlay)
+    };
+
+    let mut members = Vec::with_capacity(dirs.len());
+    let mut overrides = Vec::with_capacity(dirs.len());
+    let mut env = Vec::with_capacity(dirs.len());
+    for dir in dirs.iter() {
+        let mut package = read_toml::<Package>(&dir.join(MANIFEST)).await?;
+        overrides.push(overlay.apply(&mut package, &vanilla));
+        env.push(package.pass_env(|name| std::env::var(name).ok()));
+        members.push(package);
+    }
+    let order = workspace::plan(&members)?;
+    eprintln!(
+        "building {}",
+        order
+            .iter()
+            .map(|v| members[*v].package.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    let mut built = BTreeMap::new();
+    for i in order {
+        let (dir, package, overrides, env) = (&dirs[i], &members[i], &overrides[i], &env[i]);
+        let name = &package.package.name;
+        // Hashed variables are written into the manifest, so that they are part of the source hash.
+        let manifest = if overrides.is_empty() && package.env_passthrough.hashed.is_empty() {
+            None
+        } else {
+            Some(toml::to_string(package)?)
+        };
+
+        let encoded: String = url::form_urlencoded::byte_serialize(name.as_bytes()).collect();
+        let response = client
+            .post(
+                &format!("/api/v1/store/source?name={encoded}&strip_components=1"),
+                Full::new(Bytes::from(archive(dir, manifest.as_deref())?)),
+            )
+            .await
+            .with_context(|| format!("failed to import the source of {name}"))?;
+        let hash = read_string(response.into_body()).await?;
+
+        let definition = workspace::lock_member(package, &lock, &built)?;
+        if channel.is_some() {
+            check_advisories(name, &lock, &definition, &existing)?;
+        }
+        let request = serde_json::json!({
+            "name": name,
+            "hash": hash,
+            "lock": definition,
+            "overrides": overrides,
+            "channel": channel,
+            "env": env,
+        });
+        client
+            .post(
+                "/api/v1/build",
+                Full::new(Bytes::from(serde_json::to_vec(&request)?)),
+            )
+            .await
+            .with_context(|| format!("failed to build {name}"))?;
+
+        println!("{name} {hash}");
+        built.insert(name.clone(), hash);
+    }
+    Ok(())
+}
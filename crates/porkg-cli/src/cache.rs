@@ -0,0 +1,88 @@
+use std::{
+    fs,
+    io::{Read as _, Seek as _},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+use hyper::Method;
+use porkg_model::cache::{CacheIndex, INDEX_FILE};
+
+use crate::client::{copy_body, Client};
+
+/// The archive that the daemon writes, which is kept alongside the cache until it has been unpacked.
+const PARTIAL_FILE: &str = ".porkg-cache.tar";
+
+#[derive(Debug, clap::Args)]
+pub struct ExportArgs {
+    /// The directory to write the cache to, which may already contain a cache.
+    output: PathBuf,
+    /// The hashes of the packages whose closures are published.
+    #[arg(required = true)]
+    hashes: Vec<String>,
+    /// The zstd compression level of the archives.
+    #[arg(long, default_value_t = 19)]
+    level: i32,
+}
+
+pub async fn export(client: &Client, args: ExportArgs) -> anyhow::Result<()> {
+    fs::create_dir_all(&args.output)?;
+    let partial = args.output.join(PARTIAL_FILE);
+
+    let request = serde_json::json!({ "hashes": args.hashes });
+    let response = client
+        .send_json(
+            Method::POST,
+            &format!("/api/v1/store/cache?level={}", args.level),
+            &request,
+        )
+        .await?;
+    let mut file = tokio::fs::File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&partial)
+        .await?;
+    copy_body(response.into_body(), &mut file).await?;
+
+    let mut file = file.into_std().await;
+    file.rewind()?;
+    let result = unpack(&args.output, file);
+    fs::remove_file(&partial).ok();
+    let (added, total) = result?;
+    eprintln!("published {added} packages, {total} in the cache");
+    Ok(())
+}
+
+/// Unpacks the files of a cache, merging its index with the index of the existing cache.
+fn unpack(output: &Path, file: fs::File) -> anyhow::Result<(usize, usize)> {
+    let index_path = output.join(INDEX_FILE);
+    let mut index = match fs::read(&index_path) {
+        Ok(data) => serde_json::from_slice(&data)
+            .with_context(|| format!("{index_path:?} is not a cache index"))?,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => CacheIndex::default(),
+        Err(error) => return Err(error.into()),
+    };
+
+    let mut written = None;
+    for entry in tar::Archive::new(file).entries()? {
+        let mut entry = entry?;
+        if entry.path()? == Path::new(INDEX_FILE) {
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            written = Some(serde_json::from_slice::<CacheIndex>(&data)?);
+        } else {
+            entry.unpack_in(output)?;
+        }
+    }
+
+    let written = written.context("the daemon didn't write an index")?;
+    let added = written.packages.len();
+    index.merge(written);
+    // The index is replaced atomically, so that it never lists packages that are still being written.
+    let temporary = output.join(format!("{INDEX_FILE}.tmp"));
+    fs::write(&temporary, serde_json::to_vec_pretty(&index)?)?;
+    fs::rename(&temporary, &index_path)?;
+    Ok((added, index.packages.len()))
+}
@@ -0,0 +1,123 @@
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::Method;
+use porkg_model::{
+    channel::Channel,
+    workspace::{Lock, LOCK_FILE},
+};
+
+use crate::client::{read_string, Client};
+
+#[derive(Debug, clap::Subcommand)]
+pub enum ChannelCommand {
+    /// List the channels and the index snapshots that they pin.
+    List,
+    /// Show the index snapshot that a channel pins, and its lock.
+    Show { name: String },
+    /// Pin a channel to an index snapshot, creating the channel if it doesn't exist.
+    Update(UpdateArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct UpdateArgs {
+    name: String,
+    /// The hash of the index snapshot in the store.
+    #[arg(required_unless_present = "lock", conflicts_with = "lock")]
+    index: Option<String>,
+    /// Import a lock as a new index snapshot, and pin it.
+    #[arg(long, value_name = "PATH")]
+    lock: Option<PathBuf>,
+    /// Only advance the channel if it still pins this snapshot.
+    #[arg(long, value_name = "HASH")]
+    expect: Option<String>,
+}
+
+/// A channel along with the lock of the snapshot that it pins.
+#[derive(Debug, serde::Deserialize)]
+pub struct Resolved {
+    pub channel: Channel,
+    pub lock: Lock,
+}
+
+/// Resolves a channel to its current snapshot.
+pub async fn resolve(client: &Client, name: &str) -> anyhow::Result<Resolved> {
+    let response = client
+        .get(&format!("/api/v1/channel/{name}"))
+        .await
+        .with_context(|| format!("failed to resolve the channel {name}"))?;
+    Ok(serde_json::from_str(
+        &read_string(response.into_body()).await?,
+    )?)
+}
+
+/// Imports a lock as the source of an index snapshot.
+async fn import_lock(client: &Client, channel: &str, path: &PathBuf) -> anyhow::Result<String> {
+    let lock = tokio::fs::read(path)
+        .await
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let text = std::str::from_utf8(&lock)?;
+    toml::from_str::<Lock>(text).with_context(|| format!("failed to parse {}", path.display()))?;
+
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_gnu();
+    header.set_size(lock.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+    builder.append_data(&mut header, LOCK_FILE, &lock[..])?;
+
+    let response = client
+        .post(
+            &format!("/api/v1/store/source?name=index-{channel}"),
+            Full::new(Bytes::from(builder.into_inner()?)),
+        )
+        .await
+        .context("failed to import the index")?;
+    read_string(response.into_body()).await
+}
+
+pub async fn channel(client: &Client, command: ChannelCommand) -> anyhow::Result<()> {
+    match command {
+        ChannelCommand::List => {
+            let response = client.get("/api/v1/channel").await?;
+            let channels: Vec<Channel> =
+                serde_json::from_str(&read_string(response.into_body()).await?)?;
+            for channel in channels {
+                println!("{} {}", channel.name, channel.index);
+            }
+        }
+        ChannelCommand::Show { name } => {
+            let resolved = resolve(client, &name).await?;
+            println!("{} {}", resolved.channel.name, resolved.channel.index);
+            print!("{}", toml::to_string(&resolved.lock)?);
+        }
+        ChannelCommand::Update(args) => {
+            let index = match (args.index, &args.lock) {
+                (Some(index), _) => index,
+                (None, Some(path)) => import_lock(client, &args.name, path).await?,
+                (None, None) => unreachable!("clap requires an index or a lock"),
+            };
+            let request = serde_json::json!({
+                "index": index,
+                "expected": args.expect,
+            });
+            let response = client
+                .send_json(
+                    Method::PUT,
+                    &format!("/api/v1/channel/{}", args.name),
+                    &request,
+                )
+                .await?;
+            let update: serde_json::Value =
+                serde_json::from_str(&read_string(response.into_body()).await?)?;
+            match update["previous"].as_str() {
+                Some(previous) => eprintln!("advanced {} from {previous}", args.name),
+                None => eprintln!("created {}", args.name),
+            }
+            println!("{} {index}", args.name);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,147 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Context as _;
+use bytes::Bytes;
+use http_body_util::{BodyExt as _, Empty, Full};
+use hyper::{
+    body::{Body, Incoming},
+    upgrade::Upgraded,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncWrite, AsyncWriteExt as _};
+
+/// A client for the daemon API.
+pub struct Client {
+    socket: PathBuf,
+}
+
+impl Client {
+    pub fn new(socket: PathBuf) -> Self {
+        Self { socket }
+    }
+
+    /// Sends a GET request, failing if the daemon responds with an error.
+    pub async fn get(&self, path: &str) -> anyhow::Result<Response<Incoming>> {
+        self.send(Request::get(path).body(Empty::<Bytes>::new())?)
+            .await
+    }
+
+    /// Sends a DELETE request, failing if the daemon responds with an error.
+    pub async fn delete(&self, path: &str) -> anyhow::Result<Response<Incoming>> {
+        self.send(Request::delete(path).body(Empty::<Bytes>::new())?)
+            .await
+    }
+
+    /// Sends a POST request, failing if the daemon responds with an error.
+    pub async fn post<B>(&self, path: &str, body: B) -> anyhow::Result<Response<Incoming>>
+    where
+        B: Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        self.send(Request::post(path).body(body)?).await
+    }
+
+    /// Sends a request with a JSON body, failing if the daemon responds with an error.
+    pub async fn send_json(
+        &self,
+        method: Method,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> anyhow::Result<Response<Incoming>> {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(serde_json::to_vec(body)?)))?;
+        self.send(request).await
+    }
+
+    /// Sends a POST request with a JSON body that switches the connection to `protocol`, failing if the daemon responds
+    /// with an error instead.
+    pub async fn upgrade(
+        &self,
+        path: &str,
+        protocol: &'static str,
+        body: &impl serde::Serialize,
+    ) -> anyhow::Result<Upgraded> {
+        let request = Request::post(path)
+            .header(hyper::header::CONNECTION, "upgrade")
+            .header(hyper::header::UPGRADE, protocol)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(serde_json::to_vec(body)?)))?;
+        let response = self.send(request).await?;
+        anyhow::ensure!(
+            response.status() == StatusCode::SWITCHING_PROTOCOLS,
+            "the daemon didn't switch to {protocol}"
+        );
+        Ok(hyper::upgrade::on(response).await?)
+    }
+
+    async fn send<B>(&self, mut request: Request<B>) -> anyhow::Result<Response<Incoming>>
+    where
+        B: Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let stream = connect(&self.socket)
+            .await
+            .with_context(|| format!("failed to connect to {:?}", self.socket))?;
+        let (mut sender, connection) =
+            hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(connection.with_upgrades());
+
+        request.headers_mut().insert(
+            hyper::header::HOST,
+            hyper::header::HeaderValue::from_static("localhost"),
+        );
+        let response = sender.send_request(request).await?;
+
+        let status = response.status();
+        if status.is_success() || status == StatusCode::SWITCHING_PROTOCOLS {
+            return Ok(response);
+        }
+
+        let body = response.into_body().collect().await?.to_bytes();
+        let message = serde_json::from_slice::<serde_json::Value>(&body)
+            .ok()
+            .and_then(|v| v["message"].as_str().map(ToString::to_string))
+            .unwrap_or_else(|| String::from_utf8_lossy(&body).into_owned());
+        anyhow::bail!("{status}: {message}")
+    }
+}
+
+#[cfg(unix)]
+async fn connect(socket: &Path) -> std::io::Result<tokio::net::UnixStream> {
+    tokio::net::UnixStream::connect(socket).await
+}
+
+/// The daemon only listens on unix sockets, which other platforms don't have.
+#[cfg(not(unix))]
+async fn connect(_socket: &Path) -> std::io::Result<tokio::io::DuplexStream> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "the daemon is reached over a unix socket, which this platform doesn't have",
+    ))
+}
+
+/// Copies a response body into a writer.
+pub async fn copy_body(
+    mut body: Incoming,
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> anyhow::Result<()> {
+    while let Some(frame) = body.frame().await {
+        if let Ok(data) = frame?.into_data() {
+            writer.write_all(&data).await?;
+        }
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads a response body as a string.
+pub async fn read_string(body: Incoming) -> anyhow::Result<String> {
+    let body = body.collect().await?.to_bytes();
+    Ok(String::from_utf8(body.to_vec())?)
+}
@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use tokio::io::AsyncWriteExt as _;
+
+const NAME: &str = "@name@";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Template {
+    /// A library built with make.
+    Library,
+    /// An application built with autotools.
+    Autotools,
+    /// An application built with cargo.
+    Cargo,
+}
+
+impl Template {
+    fn contents(self) -> &'static str {
+        match self {
+            Template::Library => include_str!("../templates/library.toml"),
+            Template::Autotools => include_str!("../templates/autotools.toml"),
+            Template::Cargo => include_str!("../templates/cargo.toml"),
+        }
+    }
+
+    /// Renders the manifest of a package named `name`.
+    fn render(self, name: &str) -> String {
+        self.contents().replace(NAME, name)
+    }
+}
+
+#[derive(Debug, clap::Args)]
+pub struct InitArgs {
+    /// The kind of package to create.
+    template: Template,
+    /// The name of the package, instead of the name of the directory.
+    #[arg(short, long)]
+    name: Option<String>,
+    /// The directory to create the manifest in.
+    #[arg(short, long, default_value = ".")]
+    dir: PathBuf,
+    /// Replace an existing manifest.
+    #[arg(long)]
+    force: bool,
+}
+
+pub async fn init(args: InitArgs) -> anyhow::Result<()> {
+    let name = match args.name {
+        Some(name) => name,
+        None => tokio::fs::canonicalize(&args.dir)
+            .await?
+            .file_name()
+            .and_then(|v| v.to_str())
+            .map(ToString::to_string)
+            .context("the package name can't be derived from the directory, use --name")?,
+    };
+    anyhow::ensure!(
+        !name.is_empty() && !name.contains(['"', '\\', '\n']),
+        "invalid package name {name:?}"
+    );
+
+    let path = args.dir.join("porkg.toml");
+    let mut file = tokio::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .create_new(!args.force)
+        .open(&path)
+        .await
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    file.write_all(args.template.render(&name).as_bytes())
+        .await?;
+    file.flush().await?;
+
+    eprintln!("created {}", path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use clap::ValueEnum as _;
+    use porkg_model::{lint::lint, package::Package};
+
+    use super::*;
+
+    #[test]
+    fn render_templates() {
+        for template in Template::value_variants() {
+            let package: Package = toml::from_str(&template.render("hello")).unwrap();
+            assert_eq!(package.package.name, "hello");
+            assert_eq!(package.package.format, porkg_model::package::FORMAT_VERSION);
+            assert_eq!(lint(&package), vec![], "{template:?}");
+        }
+    }
+}
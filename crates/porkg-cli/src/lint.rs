@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use http_body_util::Full;
+
+use crate::client::{read_string, Client};
+
+#[derive(Debug, clap::Args)]
+pub struct LintArgs {
+    /// The manifest to check.
+    #[arg(default_value = "porkg.toml")]
+    path: PathBuf,
+    /// Print the findings as JSON.
+    #[arg(long)]
+    json: bool,
+}
+
+pub async fn lint(client: &Client, args: LintArgs) -> anyhow::Result<()> {
+    let manifest = tokio::fs::read(&args.path).await?;
+    let response = client
+        .post("/api/v1/lint", Full::new(Bytes::from(manifest)))
+        .await?;
+
+    let body = read_string(response.into_body()).await?;
+    let findings: serde_json::Value = serde_json::from_str(&body)?;
+    let findings = findings["findings"].as_array().cloned().unwrap_or_default();
+    if args.json {
+        println!("{}", serde_json::Value::Array(findings.clone()));
+    } else {
+        for finding in findings.iter() {
+            println!(
+                "{}[{}] {}: {}",
+                finding["severity"].as_str().unwrap_or_default(),
+                finding["code"].as_str().unwrap_or_default(),
+                finding["path"].as_str().unwrap_or_default(),
+                finding["message"].as_str().unwrap_or_default(),
+            );
+        }
+    }
+
+    let errors = findings.iter().filter(|v| v["severity"] == "error").count();
+    if errors > 0 {
+        anyhow::bail!("{} has {errors} error(s)", args.path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,88 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use client::Client;
+
+mod audit;
+mod build;
+mod cache;
+mod channel;
+mod client;
+mod init;
+mod lint;
+mod oci;
+mod profile;
+mod run;
+mod store;
+mod sync;
+
+#[derive(Debug, Parser)]
+#[command(name = "porkg", version, about)]
+struct Cli {
+    /// The socket that the daemon is listening on.
+    #[arg(
+        long,
+        env = "PORKG__BIND__SOCKET",
+        default_value = "/var/lib/porkg/porkg.sock"
+    )]
+    socket: PathBuf,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Check the closure of a package or profile against the advisory database of the daemon.
+    Audit(audit::AuditArgs),
+    /// Build a package, or every package in a workspace.
+    Build(build::BuildArgs),
+    /// Manage the channels that pin repository index snapshots.
+    #[command(subcommand)]
+    Channel(channel::ChannelCommand),
+    /// Delete a package from the store.
+    Delete(store::DeleteArgs),
+    /// Create a manifest for a new package from a template.
+    Init(init::InitArgs),
+    /// Check a manifest for likely mistakes.
+    Lint(lint::LintArgs),
+    /// Write the closures of packages into a directory as a static binary cache, which any static host can serve.
+    ExportCache(cache::ExportArgs),
+    /// Export the closure of a package as an OCI image layout archive.
+    ExportOci(oci::ExportArgs),
+    /// Import an OCI image layout archive as a root file system that builds can use as their base.
+    ImportOci(oci::ImportArgs),
+    /// Inspect the generations of profiles.
+    #[command(subcommand)]
+    Profile(profile::ProfileCommand),
+    /// Run an executable of a package in a sandbox that only contains the closure of the package.
+    Run(run::RunArgs),
+    /// Transfer packages between the store and a peer or remote, skipping the packages that the receiver already has.
+    #[command(subcommand)]
+    Sync(sync::SyncCommand),
+}
+
+fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()?;
+
+    runtime.block_on(async move {
+        let client = Client::new(cli.socket);
+        match cli.command {
+            Command::Audit(args) => audit::audit(&client, args).await,
+            Command::Build(args) => build::build(&client, args).await,
+            Command::Channel(command) => channel::channel(&client, command).await,
+            Command::Delete(args) => store::delete(&client, args).await,
+            Command::Init(args) => init::init(args).await,
+            Command::Lint(args) => lint::lint(&client, args).await,
+            Command::ExportCache(args) => cache::export(&client, args).await,
+            Command::ExportOci(args) => oci::export(&client, args).await,
+            Command::ImportOci(args) => oci::import(&client, args).await,
+            Command::Profile(command) => profile::profile(&client, command).await,
+            Command::Run(args) => run::run(&client, args).await,
+            Command::Sync(command) => sync::sync(&client, command).await,
+        }
+    })
+}
@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+
+use futures_util::TryStreamExt as _;
+use http_body_util::StreamBody;
+use hyper::body::Frame;
+use tokio::io::AsyncWrite;
+use tokio_util::io::ReaderStream;
+
+use crate::client::{copy_body, read_string, Client};
+
+#[derive(Debug, clap::Args)]
+pub struct ExportArgs {
+    /// The hash of the package to export.
+    hash: String,
+    /// The file to write the archive to, instead of stdout.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+pub async fn export(client: &Client, args: ExportArgs) -> anyhow::Result<()> {
+    let response = client
+        .get(&format!("/api/v1/store/{}/oci", args.hash))
+        .await?;
+
+    let mut writer: Box<dyn AsyncWrite + Unpin> = match args.output {
+        Some(path) => Box::new(tokio::fs::File::create(path).await?),
+        None => Box::new(tokio::io::stdout()),
+    };
+    copy_body(response.into_body(), &mut writer).await
+}
+
+#[derive(Debug, clap::Args)]
+pub struct ImportArgs {
+    /// The OCI image layout archive to import.
+    path: PathBuf,
+    /// The name to import the image as.
+    #[arg(short, long)]
+    name: String,
+}
+
+pub async fn import(client: &Client, args: ImportArgs) -> anyhow::Result<()> {
+    let file = tokio::fs::File::open(&args.path).await?;
+    let body = StreamBody::new(ReaderStream::new(file).map_ok(Frame::data));
+
+    let name: String = url::form_urlencoded::byte_serialize(args.name.as_bytes()).collect();
+    let response = client
+        .post(&format!("/api/v1/store/oci?name={name}"), body)
+        .await?;
+
+    println!("{}", read_string(response.into_body()).await?);
+    Ok(())
+}
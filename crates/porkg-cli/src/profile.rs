@@ -0,0 +1,109 @@
+use hyper::Method;
+use porkg_model::profile::{ClosureDiff, SwitchRequest, Switched};
+
+use crate::client::{read_string, Client};
+
+#[derive(Debug, clap::Subcommand)]
+pub enum ProfileCommand {
+    /// Show how the closure of a generation differs from that of another one, such as before switching to it.
+    Diff(DiffArgs),
+    /// Switch a profile to one of its generations, running the activation commands of its packages. The profile is
+    /// switched back if one of them fails.
+    Switch(SwitchArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct DiffArgs {
+    /// The generation to compare from.
+    from: u64,
+    /// The generation to compare to.
+    to: u64,
+    #[arg(long, default_value = "default")]
+    profile: String,
+}
+
+#[derive(Debug, clap::Args)]
+pub struct SwitchArgs {
+    /// The generation to switch to.
+    generation: u64,
+    #[arg(long, default_value = "default")]
+    profile: String,
+}
+
+pub async fn profile(client: &Client, command: ProfileCommand) -> anyhow::Result<()> {
+    match command {
+        ProfileCommand::Diff(args) => diff(client, args).await,
+        ProfileCommand::Switch(args) => switch(client, args).await,
+    }
+}
+
+async fn diff(client: &Client, args: DiffArgs) -> anyhow::Result<()> {
+    let response = client
+        .get(&format!(
+            "/api/v1/profile/{}/diff?from={}&to={}",
+            args.profile, args.from, args.to
+        ))
+        .await?;
+    let diff: ClosureDiff = serde_json::from_str(&read_string(response.into_body()).await?)?;
+    print_diff(&diff);
+    Ok(())
+}
+
+async fn switch(client: &Client, args: SwitchArgs) -> anyhow::Result<()> {
+    let request = serde_json::to_value(SwitchRequest {
+        generation: args.generation,
+    })?;
+    let response = client
+        .send_json(
+            Method::POST,
+            &format!("/api/v1/profile/{}/switch", args.profile),
+            &request,
+        )
+        .await?;
+    let switched: Switched = serde_json::from_str(&read_string(response.into_body()).await?)?;
+
+    print_diff(&switched.diff);
+    for package in switched.activated.iter() {
+        eprintln!("activated {package}");
+    }
+    match switched.previous {
+        Some(previous) => eprintln!(
+            "switched {} from generation {previous} to {}",
+            args.profile, switched.generation
+        ),
+        None => eprintln!(
+            "switched {} to generation {}",
+            args.profile, switched.generation
+        ),
+    }
+    Ok(())
+}
+
+fn print_diff(diff: &ClosureDiff) {
+    for package in diff.removed.iter() {
+        println!(
+            "- {} {} ({} bytes)",
+            package.name, package.path, package.size
+        );
+    }
+    for package in diff.added.iter() {
+        println!(
+            "+ {} {} ({} bytes)",
+            package.name, package.path, package.size
+        );
+    }
+    for change in diff.changed.iter() {
+        println!(
+            "~ {} -> {} {} ({} -> {} bytes)",
+            change.from.name, change.to.name, change.to.path, change.from.size, change.to.size
+        );
+    }
+    eprintln!(
+        "{} added, {} removed, {} changed; the closure goes from {} to {} bytes",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len(),
+        diff.size_from,
+        diff.size_to
+    );
+}
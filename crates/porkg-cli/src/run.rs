@@ -0,0 +1,65 @@
+use hyper_util::rt::TokioIo;
+use porkg_model::{
+    log::LogStream,
+    run::{Frame, RunRequest, MAX_FRAME_SIZE, UPGRADE},
+};
+use tokio::io::{AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+
+use crate::client::Client;
+
+#[derive(Debug, clap::Args)]
+pub struct RunArgs {
+    /// The hash of the package to run.
+    hash: String,
+    /// The executable to run, instead of the default executable of the package.
+    #[arg(short, long)]
+    exec: Option<String>,
+    /// The arguments to pass to the executable.
+    #[arg(last = true)]
+    args: Vec<String>,
+}
+
+pub async fn run(client: &Client, args: RunArgs) -> anyhow::Result<()> {
+    let path = match &args.exec {
+        Some(exec) => {
+            let exec: String = url::form_urlencoded::byte_serialize(exec.as_bytes()).collect();
+            format!("/api/v1/run/{}/{exec}", args.hash)
+        }
+        None => format!("/api/v1/run/{}", args.hash),
+    };
+    let upgraded = client
+        .upgrade(&path, UPGRADE, &RunRequest { args: args.args })
+        .await?;
+    let (mut reader, mut writer) = tokio::io::split(TokioIo::new(upgraded));
+
+    tokio::spawn(async move {
+        tokio::io::copy(&mut tokio::io::stdin(), &mut writer).await?;
+        writer.shutdown().await
+    });
+
+    let mut stdout = tokio::io::stdout();
+    let mut stderr = tokio::io::stderr();
+    let mut buf = Vec::with_capacity(2 * MAX_FRAME_SIZE);
+    loop {
+        if reader.read_buf(&mut buf).await? == 0 {
+            anyhow::bail!("the daemon stopped responding before the command exited");
+        }
+
+        let mut start = 0;
+        while let Some((frame, len)) = Frame::decode(&buf[start..])? {
+            start += len;
+            match frame {
+                Frame::Output { stream, data } => {
+                    let writer: &mut (dyn AsyncWrite + Unpin) = match stream {
+                        LogStream::Stdout => &mut stdout,
+                        LogStream::Stderr => &mut stderr,
+                    };
+                    writer.write_all(&data).await?;
+                    writer.flush().await?;
+                }
+                Frame::Exit(code) => std::process::exit(code),
+            }
+        }
+        buf.drain(..start);
+    }
+}
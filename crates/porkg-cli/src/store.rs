@@ -0,0 +1,35 @@
+use crate::client::{read_string, Client};
+
+#[derive(Debug, clap::Args)]
+pub struct DeleteArgs {
+    /// The hash of the package to delete.
+    hash: String,
+    /// Also delete the packages that only this package references.
+    #[arg(short, long)]
+    recursive: bool,
+    /// Show what would be deleted without deleting anything.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+pub async fn delete(client: &Client, args: DeleteArgs) -> anyhow::Result<()> {
+    let response = client
+        .delete(&format!(
+            "/api/v1/store/{}?recursive={}&dry_run={}",
+            args.hash, args.recursive, args.dry_run
+        ))
+        .await?;
+
+    let plan: serde_json::Value = serde_json::from_str(&read_string(response.into_body()).await?)?;
+    for hash in plan["packages"].as_array().into_iter().flatten() {
+        println!("{}", hash.as_str().unwrap_or_default());
+    }
+
+    let bytes = plan["bytes"].as_u64().unwrap_or_default();
+    if args.dry_run {
+        eprintln!("would free {bytes} bytes");
+    } else {
+        eprintln!("freed {bytes} bytes");
+    }
+    Ok(())
+}
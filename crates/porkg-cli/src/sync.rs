@@ -0,0 +1,44 @@
+use hyper::Method;
+
+use crate::client::{read_string, Client};
+
+#[derive(Debug, clap::Subcommand)]
+pub enum SyncCommand {
+    /// Copy the closures of packages from a peer or remote into the store.
+    Pull(SyncArgs),
+    /// Copy the closures of packages from the store to a peer or remote.
+    Push(SyncArgs),
+}
+
+#[derive(Debug, clap::Args)]
+pub struct SyncArgs {
+    /// The name of the peer or remote, as configured in the daemon.
+    peer: String,
+    /// The hashes of the packages.
+    #[arg(required = true)]
+    hashes: Vec<String>,
+}
+
+pub async fn sync(client: &Client, command: SyncCommand) -> anyhow::Result<()> {
+    let (path, args) = match command {
+        SyncCommand::Pull(args) => ("/api/v1/sync/pull", args),
+        SyncCommand::Push(args) => ("/api/v1/sync/push", args),
+    };
+    let request = serde_json::json!({
+        "peer": args.peer,
+        "hashes": args.hashes,
+    });
+    let response = client.send_json(Method::POST, path, &request).await?;
+
+    let transferred: serde_json::Value =
+        serde_json::from_str(&read_string(response.into_body()).await?)?;
+    let hashes = transferred["hashes"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default();
+    for hash in hashes.iter() {
+        println!("{}", hash.as_str().unwrap_or_default());
+    }
+    eprintln!("transferred {} packages", hashes.len());
+    Ok(())
+}
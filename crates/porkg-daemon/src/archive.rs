@@ -0,0 +1,544 @@
+//! Extraction of source archives and image layers.
+//!
+//! Archives come from untrusted sources, so extraction is strict: entries can't be placed outside of the target
+//! (either directly, or through a symlink that was extracted earlier), special files are skipped, and permissions are
+//! normalized so that only whether a file is executable is retained.
+
+use std::{
+    cell::Cell,
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read},
+    os::unix::{
+        ffi::OsStringExt as _,
+        fs::{OpenOptionsExt as _, PermissionsExt as _},
+    },
+    path::{Component, Path, PathBuf},
+};
+
+use thiserror::Error;
+
+const MAX_LINKS: usize = 40;
+const MODE_DIRECTORY: u32 = 0o755;
+const MODE_EXECUTABLE: u32 = 0o555;
+const MODE_FILE: u32 = 0o444;
+const ZIP_MODE_TYPE: u32 = 0o170000;
+const ZIP_MODE_SYMLINK: u32 = 0o120000;
+
+#[derive(Debug, Error)]
+pub enum ArchiveError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error(transparent)]
+    Zip(#[from] zip::result::ZipError),
+    #[error("unsafe path {0:?} in archive")]
+    UnsafePath(PathBuf),
+    #[error("link {path:?} to {target:?} leaves the archive")]
+    UnsafeLink { path: PathBuf, target: PathBuf },
+    #[error("unknown archive format")]
+    UnknownFormat,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    TarGzip,
+    TarXz,
+    TarZstd,
+    Zip,
+}
+
+impl Format {
+    /// Guesses the format of an archive from its file name.
+    pub fn from_name(name: &str) -> Option<Self> {
+        const SUFFIXES: &[(&str, Format)] = &[
+            (".tar", Format::Tar),
+            (".tar.gz", Format::TarGzip),
+            (".tgz", Format::TarGzip),
+            (".tar.xz", Format::TarXz),
+            (".txz", Format::TarXz),
+            (".tar.zst", Format::TarZstd),
+            (".tzst", Format::TarZstd),
+            (".zip", Format::Zip),
+        ];
+
+        SUFFIXES
+            .iter()
+            .find(|(suffix, _)| name.ends_with(suffix))
+            .map(|(_, format)| *format)
+    }
+
+    /// Detects the format of an archive from its leading bytes.
+    pub fn detect(header: &[u8]) -> Option<Self> {
+        if header.starts_with(&[0x1f, 0x8b]) {
+            Some(Format::TarGzip)
+        } else if header.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Some(Format::TarXz)
+        } else if header.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Some(Format::TarZstd)
+        } else if header.starts_with(b"PK\x03\x04") {
+            Some(Format::Zip)
+        } else if header.get(257..262) == Some(b"ustar") {
+            Some(Format::Tar)
+        } else {
+            None
+        }
+    }
+
+    /// Determines the format of the archive at `path`, from its file name or otherwise its contents.
+    pub fn of(path: &Path) -> Result<Self, ArchiveError> {
+        if let Some(format) = path
+            .file_name()
+            .and_then(|v| v.to_str())
+            .and_then(Self::from_name)
+        {
+            return Ok(format);
+        }
+
+        let mut header = Vec::with_capacity(512);
+        File::open(path)?.take(512).read_to_end(&mut header)?;
+        Self::detect(&header).ok_or(ArchiveError::UnknownFormat)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    /// The number of leading components to remove from each path; entries with fewer components are skipped.
+    pub strip_components: usize,
+    /// Allows symlinks that point outside of the target, such as the absolute links within a root file system. Such
+    /// links are still resolved against the target while extracting.
+    pub allow_external_links: bool,
+}
+
+/// Places archive entries within a target directory.
+pub struct Extractor {
+    root: PathBuf,
+    options: ExtractOptions,
+    latest: Cell<u64>,
+}
+
+impl Extractor {
+    pub fn new(root: impl Into<PathBuf>, options: ExtractOptions) -> Self {
+        Self {
+            root: root.into(),
+            options,
+            latest: Cell::new(0),
+        }
+    }
+
+    /// Gets the latest modification time of the tar entries that were extracted, in seconds since the Unix epoch.
+    /// Modification times aren't applied to the extracted files, as they aren't part of the hash of a directory.
+    pub fn latest_mtime(&self) -> Option<u64> {
+        Some(self.latest.get()).filter(|&v| v != 0)
+    }
+
+    /// Converts the path of an entry into a path relative to the target, or `None` if the entry is stripped.
+    pub fn entry_path(&self, path: &Path) -> Result<Option<PathBuf>, ArchiveError> {
+        let mut components = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(v) => components.push(v),
+                Component::RootDir | Component::CurDir => {}
+                Component::ParentDir | Component::Prefix(_) => {
+                    return Err(ArchiveError::UnsafePath(path.to_path_buf()))
+                }
+            }
+        }
+
+        let result = components
+            .into_iter()
+            .skip(self.options.strip_components)
+            .collect::<PathBuf>();
+        Ok((!result.as_os_str().is_empty()).then_some(result))
+    }
+
+    /// Resolves a relative path within the target, following symlinks as if the target were the file system root.
+    pub fn resolve(&self, path: &Path) -> Result<PathBuf, ArchiveError> {
+        let mut resolved = PathBuf::new();
+        let mut pending: Vec<OsString> = path
+            .components()
+            .rev()
+            .map(|v| v.as_os_str().to_owned())
+            .collect();
+        let mut links = 0;
+
+        while let Some(component) = pending.pop() {
+            match component.to_str() {
+                Some("/") => resolved.clear(),
+                Some(".") => {}
+                Some("..") => {
+                    resolved.pop();
+                }
+                _ => {
+                    let candidate = resolved.join(&component);
+                    let full = self.root.join(&candidate);
+                    match fs::symlink_metadata(&full) {
+                        Ok(metadata) if metadata.is_symlink() => {
+                            links += 1;
+                            if links > MAX_LINKS {
+                                return Err(ArchiveError::UnsafePath(path.to_path_buf()));
+                            }
+                            let target = fs::read_link(&full)?;
+                            pending.extend(
+                                target.components().rev().map(|v| v.as_os_str().to_owned()),
+                            );
+                        }
+                        _ => resolved = candidate,
+                    }
+                }
+            }
+        }
+
+        Ok(self.root.join(resolved))
+    }
+
+    /// Removes an entry, without following it if it is a symlink.
+    pub fn remove(&self, path: &Path) -> Result<(), ArchiveError> {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Ok(());
+        };
+        let target = self.resolve(parent)?.join(name);
+        match fs::symlink_metadata(&target) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&target)?,
+            Ok(_) => fs::remove_file(&target)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+        Ok(())
+    }
+
+    /// Gets the location for a new entry, replacing anything that is already there.
+    fn prepare(&self, path: &Path, directory: bool) -> Result<PathBuf, ArchiveError> {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            return Err(ArchiveError::UnsafePath(path.to_path_buf()));
+        };
+
+        let parent = self.resolve(parent)?;
+        fs::create_dir_all(&parent)?;
+        let target = parent.join(name);
+        match fs::symlink_metadata(&target) {
+            Ok(metadata) if metadata.is_dir() && directory => {}
+            Ok(_) => self.remove(path)?,
+            Err(_) => {}
+        }
+        Ok(target)
+    }
+
+    pub fn directory(&self, path: &Path) -> Result<(), ArchiveError> {
+        let target = self.prepare(path, true)?;
+        if !target.exists() {
+            fs::create_dir(&target)?;
+        }
+        fs::set_permissions(&target, fs::Permissions::from_mode(MODE_DIRECTORY))?;
+        Ok(())
+    }
+
+    pub fn file(
+        &self,
+        path: &Path,
+        mut reader: impl Read,
+        executable: bool,
+    ) -> Result<(), ArchiveError> {
+        let target = self.prepare(path, false)?;
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(if executable {
+                MODE_EXECUTABLE
+            } else {
+                MODE_FILE
+            })
+            .open(target)?;
+        io::copy(&mut reader, &mut file)?;
+        Ok(())
+    }
+
+    pub fn symlink(&self, path: &Path, link: &Path) -> Result<(), ArchiveError> {
+        if !self.options.allow_external_links && !is_contained(path, link) {
+            return Err(ArchiveError::UnsafeLink {
+                path: path.to_path_buf(),
+                target: link.to_path_buf(),
+            });
+        }
+
+        let target = self.prepare(path, false)?;
+        std::os::unix::fs::symlink(link, target)?;
+        Ok(())
+    }
+
+    /// Creates a hard link to a file that was previously extracted.
+    pub fn hard_link(&self, path: &Path, link: &Path) -> Result<(), ArchiveError> {
+        let unsafe_link = || ArchiveError::UnsafeLink {
+            path: path.to_path_buf(),
+            target: link.to_path_buf(),
+        };
+
+        let source = self.entry_path(link)?.ok_or_else(unsafe_link)?;
+        let source = self.resolve(&source)?;
+        if !fs::symlink_metadata(&source).is_ok_and(|v| v.is_file()) {
+            return Err(unsafe_link());
+        }
+
+        let target = self.prepare(path, false)?;
+        fs::hard_link(source, target)?;
+        Ok(())
+    }
+
+    /// Extracts a single tar entry.
+    pub fn tar_entry<R: Read>(&self, entry: &mut tar::Entry<R>) -> Result<(), ArchiveError> {
+        let Some(path) = self.entry_path(&entry.path()?)? else {
+            return Ok(());
+        };
+        if let Ok(mtime) = entry.header().mtime() {
+            self.latest.set(self.latest.get().max(mtime));
+        }
+
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => self.directory(&path),
+            tar::EntryType::Regular | tar::EntryType::Continuous => {
+                let executable = entry.header().mode()? & 0o111 != 0;
+                self.file(&path, entry, executable)
+            }
+            tar::EntryType::Symlink => {
+                let link = entry
+                    .link_name()?
+                    .ok_or(ArchiveError::UnsafePath(path.clone()))?;
+                self.symlink(&path, &link)
+            }
+            tar::EntryType::Link => {
+                let link = entry
+                    .link_name()?
+                    .ok_or(ArchiveError::UnsafePath(path.clone()))?;
+                self.hard_link(&path, &link)
+            }
+            kind => {
+                tracing::warn!(?path, ?kind, "skipping unsupported entry");
+                Ok(())
+            }
+        }
+    }
+
+    fn tar(&self, reader: impl Read) -> Result<(), ArchiveError> {
+        for entry in tar::Archive::new(reader).entries()? {
+            self.tar_entry(&mut entry?)?;
+        }
+        Ok(())
+    }
+
+    fn zip(&self, file: File) -> Result<(), ArchiveError> {
+        let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index)?;
+            let Some(path) = self.entry_path(Path::new(entry.name()))? else {
+                continue;
+            };
+
+            let mode = entry.unix_mode().unwrap_or(0o644);
+            if entry.is_dir() {
+                self.directory(&path)?;
+            } else if mode & ZIP_MODE_TYPE == ZIP_MODE_SYMLINK {
+                let mut link = Vec::new();
+                entry.read_to_end(&mut link)?;
+                let link = PathBuf::from(OsString::from_vec(link));
+                self.symlink(&path, &link)?;
+            } else {
+                self.file(&path, &mut entry, mode & 0o111 != 0)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Determines whether a relative symlink at `path` stays within the root.
+fn is_contained(path: &Path, link: &Path) -> bool {
+    let mut depth = path.components().count().saturating_sub(1);
+    for component in link.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+/// Extracts the archive at `source` into `target`, returning the latest modification time of its entries (see
+/// [`Extractor::latest_mtime`]). Zip archives record local times without a time zone, so their times aren't returned.
+///
+/// This performs blocking IO.
+#[tracing::instrument(skip(options))]
+pub fn extract(
+    format: Format,
+    source: &Path,
+    target: &Path,
+    options: ExtractOptions,
+) -> Result<Option<u64>, ArchiveError> {
+    fs::create_dir_all(target)?;
+    let extractor = Extractor::new(target, options);
+    let file = File::open(source)?;
+
+    match format {
+        Format::Tar => extractor.tar(BufReader::new(file)),
+        Format::TarGzip => extractor.tar(flate2::bufread::GzDecoder::new(BufReader::new(file))),
+        Format::TarXz => extractor.tar(xz2::bufread::XzDecoder::new(BufReader::new(file))),
+        Format::TarZstd => extractor.tar(zstd::Decoder::new(file)?),
+        Format::Zip => return extractor.zip(file).map(|_| None),
+    }?;
+    Ok(extractor.latest_mtime())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write as _;
+
+    use super::*;
+
+    fn entry(kind: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(kind);
+        header.set_mode(mode);
+        header.set_size(size);
+        header
+    }
+
+    fn sample_tar() -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut append = |path: &str, mode: u32, data: &[u8]| {
+            let mut header = entry(tar::EntryType::Regular, mode, data.len() as u64);
+            builder.append_data(&mut header, path, data).unwrap();
+        };
+        append("src-1.0/configure", 0o4775, b"#!/bin/sh\n");
+        append("src-1.0/README", 0o666, b"readme");
+
+        let mut header = entry(tar::EntryType::Symlink, 0o777, 0);
+        builder
+            .append_link(&mut header, "src-1.0/docs/README", "../README")
+            .unwrap();
+        let mut header = entry(tar::EntryType::Link, 0o644, 0);
+        builder
+            .append_link(&mut header, "src-1.0/COPY", "src-1.0/README")
+            .unwrap();
+        let mut header = entry(tar::EntryType::Char, 0o666, 0);
+        builder
+            .append_data(&mut header, "src-1.0/null", io::empty())
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    fn assert_sample(target: &Path) {
+        let mode = |path: &str| {
+            fs::metadata(target.join(path))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o7777
+        };
+        assert_eq!(mode("configure"), MODE_EXECUTABLE);
+        assert_eq!(mode("README"), MODE_FILE);
+        assert_eq!(fs::read(target.join("docs/README")).unwrap(), b"readme");
+        assert_eq!(fs::read(target.join("COPY")).unwrap(), b"readme");
+        assert!(!target.join("null").exists());
+    }
+
+    #[test]
+    fn extract_compressed() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let tar = sample_tar();
+
+        let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        gzip.write_all(&tar).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 1);
+        xz.write_all(&tar).unwrap();
+        let archives = [
+            ("a.tar", tar.clone()),
+            ("a.tar.gz", gzip.finish().unwrap()),
+            ("a.tar.xz", xz.finish().unwrap()),
+            ("a.tar.zst", zstd::encode_all(&tar[..], 1).unwrap()),
+        ];
+
+        for (name, data) in archives {
+            let source = dir.join(name);
+            fs::write(&source, &data).unwrap();
+            let format = Format::from_name(name).unwrap();
+            assert_eq!(Format::detect(&data), Some(format));
+
+            let target = dir.join(format!("{name}.out"));
+            let options = ExtractOptions {
+                strip_components: 1,
+                ..Default::default()
+            };
+            extract(format, &source, &target, options).unwrap();
+            assert_sample(&target);
+        }
+    }
+
+    #[test]
+    fn extract_zip() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+
+        let source = dir.join("a.zip");
+        let mut zip = zip::ZipWriter::new(File::create(&source).unwrap());
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated);
+        zip.start_file("bin/tool", options.unix_permissions(0o4755))
+            .unwrap();
+        zip.write_all(b"tool").unwrap();
+        zip.add_symlink("tool", "bin/tool", options).unwrap();
+        zip.add_symlink("escape", "../outside", options).unwrap();
+        zip.finish().unwrap();
+
+        let target = dir.join("out");
+        let result = extract(Format::Zip, &source, &target, ExtractOptions::default());
+        assert!(matches!(result, Err(ArchiveError::UnsafeLink { .. })));
+
+        let mode = fs::metadata(target.join("bin/tool"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, MODE_EXECUTABLE);
+        assert_eq!(fs::read(target.join("tool")).unwrap(), b"tool");
+    }
+
+    #[test]
+    fn extract_unsafe() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let extractor = Extractor::new(dir, ExtractOptions::default());
+
+        assert!(matches!(
+            extractor.entry_path(Path::new("a/../../b")),
+            Err(ArchiveError::UnsafePath(_))
+        ));
+        assert!(matches!(
+            extractor.symlink(Path::new("a/b"), Path::new("../../etc")),
+            Err(ArchiveError::UnsafeLink { .. })
+        ));
+        assert!(matches!(
+            extractor.symlink(Path::new("a"), Path::new("/etc")),
+            Err(ArchiveError::UnsafeLink { .. })
+        ));
+        assert!(matches!(
+            extractor.hard_link(Path::new("passwd"), Path::new("/etc/passwd")),
+            Err(ArchiveError::UnsafeLink { .. })
+        ));
+
+        // Even when links are allowed to leave the target, they are resolved within it.
+        let extractor = Extractor::new(
+            dir,
+            ExtractOptions {
+                allow_external_links: true,
+                ..Default::default()
+            },
+        );
+        extractor
+            .symlink(Path::new("up"), Path::new("../.."))
+            .unwrap();
+        extractor
+            .file(Path::new("up/file"), &b"data"[..], false)
+            .unwrap();
+        assert_eq!(fs::read(dir.join("file")).unwrap(), b"data");
+    }
+}
@@ -1,68 +1,455 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    io,
+    os::fd::OwnedFd,
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
 
-use porkg_model::hashing::SupportedHash;
-use porkg_private::sandbox::{SandboxOptions, SandboxTask};
+use futures_util::{stream::FuturesUnordered, StreamExt as _};
+use porkg_model::hashing::{
+    ManifestHash, OutputHash, StableHasherExt as _, SupportedHash, SupportedHasher,
+};
+use porkg_private::sandbox::{ResourceLimits, SandboxOptions, SandboxTask, SyscallFilter};
+use thiserror::Error;
 use tokio::fs;
 
-use crate::Erro;
+use crate::{
+    fetch::CheckoutTask,
+    store::{oci::ROOTFS, provenance::Environment, Store, StoreMount},
+    Erro,
+};
+
+pub mod activation;
+pub mod hook;
+pub mod jobs;
+pub mod middleware;
+pub mod output;
+pub mod run;
+pub mod sandbox;
+pub mod scheduler;
+pub mod scratch;
+pub mod usage;
+
+/// The range of dates that are derived for sources without a modification time: from 2000 up to 2020.
+const DERIVED_EPOCH_START: u64 = 946_684_800;
+const DERIVED_EPOCH_RANGE: u64 = 1_577_836_800 - DERIVED_EPOCH_START;
+
+/// The number of inputs of a build that are looked up in the store at once.
+const MAX_CONCURRENT_CHECKS: usize = 32;
+
+/// The entries of a base root file system that the sandbox provides itself.
+const SANDBOX_ENTRIES: &[&str] = &["dev", "proc", "sys", "tmp"];
+
+/// The tasks that run in the sandbox.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub enum Task {
+    Build(BuildTask),
+    Run(run::RunTask),
+    Checkout(Box<CheckoutTask>),
+}
+
+impl SandboxTask for Task {
+    type ExecuteError = Erro;
+
+    fn create_sandbox_options(&self) -> SandboxOptions {
+        match self {
+            Task::Build(task) => task.create_sandbox_options(),
+            Task::Run(task) => task.create_sandbox_options(),
+            Task::Checkout(task) => task.create_sandbox_options(),
+        }
+    }
+
+    fn execute(&self, fds: impl AsRef<[OwnedFd]>) -> Result<(), Self::ExecuteError> {
+        match self {
+            Task::Build(task) => task.execute(fds),
+            Task::Run(task) => task.execute(fds),
+            Task::Checkout(task) => task.execute(fds),
+        }
+    }
+}
+
+/// A problem with a build request. Every problem that is found is reported at once.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Error, serde::Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum BuildProblem {
+    #[error("invalid hash provided: {hash}")]
+    InvalidHash { hash: String },
+    #[error("invalid dependency hash provided for {name}: {hash}")]
+    InvalidDependencyHash { name: String, hash: String },
+    #[error("invalid base hash provided: {hash}")]
+    InvalidBaseHash { hash: String },
+    #[error("source directory not found")]
+    MissingSource,
+    #[error("porkg.toml not found")]
+    MissingManifest,
+    #[error("invalid porkg.toml: {error}")]
+    InvalidManifest { error: String },
+    #[error("dependency {name} not found")]
+    MissingDependency { name: String, hash: String },
+    #[error("build dependency {name} not found")]
+    MissingBuildDependency { name: String, hash: String },
+    #[error("base root file system not found")]
+    MissingBase { hash: String },
+}
 
 #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct BuildTask {
     pub name: String,
-    pub hash: SupportedHash,
-    pub dependencies: BTreeMap<String, SupportedHash>,
-    pub build_dependencies: BTreeMap<String, SupportedHash>,
+    pub hash: ManifestHash,
+    pub dependencies: BTreeMap<String, OutputHash>,
+    pub build_dependencies: BTreeMap<String, OutputHash>,
+    pub base: Option<OutputHash>,
+    /// The environment variables of the host that are passed into the build without being part of its hash.
+    pub env: BTreeMap<String, String>,
+    /// The time that the build observes as `SOURCE_DATE_EPOCH`, in seconds since the Unix epoch.
+    pub source_date_epoch: u64,
+    /// Limits on the resources of the sandbox of the build.
+    pub limits: ResourceLimits,
+    /// How long the build may run for before its sandbox is killed, even if the daemon has stopped waiting for it.
+    pub timeout: Option<Duration>,
+    /// The directory of the host that the build works in (see [`scratch`]), which is bound at the same path.
+    pub scratch: Option<PathBuf>,
+    /// The store that the inputs of the build are bound read-only from.
+    pub store: Option<StoreMount>,
+    /// The empty directory of the host that the root file system of the sandbox is mounted on. Builds with a base get
+    /// one, and the others share the root file system of the host.
+    pub root: Option<PathBuf>,
+    /// The top-level entries of the root file system of the base (see [`BuildTask::resolve_base`]), as paths of the
+    /// host and the paths within the sandbox that they are bound read-only at.
+    pub base_binds: Vec<(PathBuf, PathBuf)>,
 }
 
 impl BuildTask {
-    pub async fn validate(&self, config: &crate::config::StoreConfig) -> Result<(), String> {
-        let src_dir = config
-            .path
-            .join("pkg/by-hash/")
-            .join(self.hash.to_string())
-            .join("src");
+    /// Checks that the source and inputs of the build are in the store, reporting every input that is missing.
+    pub async fn validate(
+        &self,
+        config: &crate::config::StoreConfig,
+    ) -> Result<(), Vec<BuildProblem>> {
+        let store = config.path.join("pkg/by-hash/");
+        let src_dir = store.join(self.hash.to_string()).join("src");
 
         if !fs::try_exists(&src_dir).await.unwrap_or_default() {
-            return Err("source directory not found".to_string());
+            return Err(vec![BuildProblem::MissingSource]);
         }
 
-        let porkg_toml = src_dir.join("porkg.toml");
-        if !fs::try_exists(&porkg_toml).await.unwrap_or_default() {
-            return Err("porkg.toml not found".to_string());
+        let mut checks = vec![(BuildProblem::MissingManifest, src_dir.join("porkg.toml"))];
+        for (name, hash) in &self.dependencies {
+            let problem = BuildProblem::MissingDependency {
+                name: name.clone(),
+                hash: hash.to_string(),
+            };
+            checks.push((problem, store.join(hash.to_string())));
+        }
+        for (name, hash) in &self.build_dependencies {
+            let problem = BuildProblem::MissingBuildDependency {
+                name: name.clone(),
+                hash: hash.to_string(),
+            };
+            checks.push((problem, store.join(hash.to_string())));
+        }
+        if let Some(base) = &self.base {
+            let problem = BuildProblem::MissingBase {
+                hash: base.to_string(),
+            };
+            checks.push((problem, store.join(base.to_string()).join(ROOTFS)));
         }
 
-        for (dep, hash) in &self.dependencies {
-            let dep_dir = config.path.join("pkg/by-hash/").join(hash.to_string());
-
-            if !fs::try_exists(&dep_dir).await.unwrap_or_default() {
-                return Err(format!("dependency {} not found", dep));
+        let mut checks = checks.into_iter();
+        let mut running = FuturesUnordered::new();
+        let mut problems = Vec::new();
+        loop {
+            while running.len() < MAX_CONCURRENT_CHECKS {
+                let Some((problem, path)) = checks.next() else {
+                    break;
+                };
+                running.push(find_missing(problem, path));
+            }
+            match running.next().await {
+                Some(Some(problem)) => problems.push(problem),
+                Some(None) => {}
+                None => break,
             }
         }
 
-        for (dep, hash) in &self.build_dependencies {
-            let dep_dir = config.path.join("pkg/by-hash/").join(hash.to_string());
+        if !problems.is_empty() {
+            problems.sort();
+            return Err(problems);
+        }
+
+        Ok(())
+    }
+}
+
+/// Gets `problem` back if nothing exists at `path`.
+async fn find_missing(problem: BuildProblem, path: PathBuf) -> Option<BuildProblem> {
+    (!fs::try_exists(&path).await.unwrap_or_default()).then_some(problem)
+}
+
+impl BuildTask {
+    /// Determines the `SOURCE_DATE_EPOCH` of the build: the latest modification time of the source archive if it is
+    /// known, otherwise a date that is derived from the hashes of the source and the lock, so that it is the same
+    /// wherever the package is built.
+    ///
+    /// This performs blocking IO.
+    pub fn resolve_source_date_epoch(&self, store: &Store) -> u64 {
+        if let Some(epoch) = store
+            .info(self.hash.as_ref())
+            .ok()
+            .and_then(|info| info.source_date_epoch)
+        {
+            return epoch;
+        }
+
+        let mut hasher = SupportedHasher::blake3();
+        hasher
+            .update_hash(self.hash)
+            .update_hash(&self.dependencies)
+            .update_hash(&self.build_dependencies)
+            .update_hash(self.base);
+        let SupportedHash::Blake3(hash) = hasher.finalize();
+        let value = u64::from_le_bytes(hash[..8].try_into().unwrap());
+        DERIVED_EPOCH_START + value % DERIVED_EPOCH_RANGE
+    }
 
-            if !fs::try_exists(&dep_dir).await.unwrap_or_default() {
-                return Err(format!("build dependency {} not found", dep));
+    /// Lists the top-level entries of the root file system of the base, which are bound into the sandbox on top of its
+    /// own `/dev` and `/tmp`. Symlinks between the top-level directories (such as `/bin` to `usr/bin`) are resolved
+    /// within the base, and any that leave it are skipped.
+    ///
+    /// This performs blocking IO.
+    pub fn resolve_base(&self, store: &Store) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+        let Some(base) = &self.base else {
+            return Ok(Vec::new());
+        };
+        let rootfs = store.by_hash(base.as_ref()).join(ROOTFS);
+        let mut binds = Vec::new();
+        for entry in std::fs::read_dir(&rootfs)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if SANDBOX_ENTRIES.iter().any(|v| name == *v) {
+                continue;
+            }
+            let mut source = entry.path();
+            if entry.file_type()?.is_symlink() {
+                let Some(target) = resolve_link(&rootfs, &std::fs::read_link(&source)?) else {
+                    tracing::debug!(?source, "skipping a link that leaves the base");
+                    continue;
+                };
+                source = target;
             }
+            binds.push((source, Path::new("/").join(name)));
         }
+        binds.sort_by(|a, b| a.1.cmp(&b.1));
+        Ok(binds)
+    }
 
-        Ok(())
+    /// Describes what the build can observe, with the store directories of the source and dependencies as its
+    /// declared inputs.
+    pub fn environment(&self, store: &Store) -> Environment {
+        let inputs = std::iter::once(self.hash.as_ref())
+            .chain(self.dependencies.values().map(AsRef::as_ref))
+            .chain(self.build_dependencies.values().map(AsRef::as_ref))
+            .chain(self.base.as_ref().map(AsRef::as_ref))
+            .map(|v| store.by_hash(v))
+            .collect();
+        let mut environment = Environment::new(&self.create_sandbox_options(), inputs);
+        environment.host_env = self.env.keys().cloned().collect();
+        environment
+    }
+
+    /// Gets the environment variables that the build is run with: those passed from the host, and
+    /// `SOURCE_DATE_EPOCH`, which takes precedence over one of the host.
+    pub fn environment_variables(&self) -> BTreeMap<String, String> {
+        let mut env = self.env.clone();
+        env.insert(
+            "SOURCE_DATE_EPOCH".to_string(),
+            self.source_date_epoch.to_string(),
+        );
+        env
     }
 }
 
 impl SandboxTask for BuildTask {
     type ExecuteError = Erro;
 
-    fn create_sandbox_options(&self) -> porkg_private::sandbox::SandboxOptions {
-        SandboxOptions::default()
+    fn create_sandbox_options(&self) -> SandboxOptions {
+        let mut options = SandboxOptions::default();
+        options
+            .with_syscall_filter(SyscallFilter::restrictive())
+            .with_limits(self.limits);
+        if let Some(timeout) = self.timeout {
+            options.with_timeout(timeout);
+        }
+        if let Some(root) = &self.root {
+            options.with_root(root);
+            for (source, target) in &self.base_binds {
+                options.with_bind(source, target, true);
+            }
+        }
+        if let Some(store) = &self.store {
+            options.with_bind(store.path.join("pkg"), store.prefix.join("pkg"), true);
+        }
+        if let Some(scratch) = &self.scratch {
+            options.with_bind(scratch, scratch, false);
+        }
+        options
     }
 
-    fn execute(
-        &self,
-        _fds: impl AsRef<[std::os::unix::prelude::OwnedFd]>,
-    ) -> Result<(), Self::ExecuteError> {
+    fn execute(&self, _fds: impl AsRef<[OwnedFd]>) -> Result<(), Self::ExecuteError> {
+        if let Some(scratch) = &self.scratch {
+            std::env::set_current_dir(scratch).map_err(|error| {
+                tracing::error!(?error, ?scratch, "failed to enter the scratch directory");
+                Erro
+            })?;
+        }
+        // The worker is the build, so whatever it exports is the environment that the build observes.
+        for (name, value) in self.environment_variables() {
+            std::env::set_var(name, value);
+        }
         tracing::trace!("running");
         Ok(())
     }
 }
+
+/// Resolves the target of a top-level symlink of `rootfs` within it, as long as it is a real file or directory of it.
+fn resolve_link(rootfs: &Path, target: &Path) -> Option<PathBuf> {
+    let mut path = rootfs.to_path_buf();
+    for component in target.components() {
+        match component {
+            Component::RootDir | Component::CurDir => {}
+            Component::Normal(v) => path.push(v),
+            Component::ParentDir | Component::Prefix(_) => return None,
+        }
+    }
+    let metadata = std::fs::symlink_metadata(&path).ok()?;
+    (path != rootfs && !metadata.is_symlink()).then_some(path)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::store::testing::TestStore;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn report_every_missing_input() {
+        let store = TestStore::new("validate");
+        let config = store.config();
+        let hash = ManifestHash::new(SupportedHash::Blake3([1; 32]));
+        let present = OutputHash::new(SupportedHash::Blake3([2; 32]));
+        let missing = |v| OutputHash::new(SupportedHash::Blake3([v; 32]));
+        let by_hash = store.root().join("pkg/by-hash");
+        std::fs::create_dir_all(by_hash.join(hash.to_string()).join("src")).unwrap();
+        std::fs::write(by_hash.join(hash.to_string()).join("src/porkg.toml"), "").unwrap();
+        std::fs::create_dir_all(by_hash.join(present.to_string())).unwrap();
+
+        let mut task = BuildTask {
+            name: "app".to_string(),
+            hash,
+            dependencies: [
+                ("libc".to_string(), present),
+                ("zlib".to_string(), missing(3)),
+            ]
+            .into(),
+            build_dependencies: [("cc".to_string(), missing(4))].into(),
+            base: Some(present),
+            env: BTreeMap::new(),
+            source_date_epoch: 0,
+            limits: ResourceLimits::default(),
+            timeout: None,
+            scratch: None,
+            store: None,
+            root: None,
+            base_binds: Vec::new(),
+        };
+        assert_eq!(
+            task.validate(&config).await,
+            Err(vec![
+                BuildProblem::MissingDependency {
+                    name: "zlib".to_string(),
+                    hash: missing(3).to_string(),
+                },
+                BuildProblem::MissingBuildDependency {
+                    name: "cc".to_string(),
+                    hash: missing(4).to_string(),
+                },
+                BuildProblem::MissingBase {
+                    hash: present.to_string(),
+                },
+            ])
+        );
+
+        task.dependencies.remove("zlib");
+        task.build_dependencies.clear();
+        task.base = None;
+        assert_eq!(task.validate(&config).await, Ok(()));
+    }
+
+    #[test]
+    fn export_environment() {
+        let task = BuildTask {
+            name: "app".to_string(),
+            hash: ManifestHash::new(SupportedHash::Blake3([1; 32])),
+            dependencies: BTreeMap::new(),
+            build_dependencies: BTreeMap::new(),
+            base: None,
+            env: [
+                ("PORKG_TEST_BUILD_ENV".to_string(), "passed".to_string()),
+                ("SOURCE_DATE_EPOCH".to_string(), "1".to_string()),
+            ]
+            .into(),
+            source_date_epoch: 1_700_000_000,
+            limits: ResourceLimits::default(),
+            timeout: None,
+            scratch: None,
+            store: None,
+            root: None,
+            base_binds: Vec::new(),
+        };
+
+        // This is what the worker runs before the build.
+        task.execute([]).unwrap();
+        assert_eq!(std::env::var("PORKG_TEST_BUILD_ENV").unwrap(), "passed");
+        assert_eq!(std::env::var("SOURCE_DATE_EPOCH").unwrap(), "1700000000");
+    }
+
+    #[test]
+    fn resolve_base() {
+        let store = TestStore::new("base");
+        let base = OutputHash::new(SupportedHash::Blake3([5; 32]));
+        let rootfs = store.by_hash(base.as_ref()).join(ROOTFS);
+        std::fs::create_dir_all(rootfs.join("usr/bin")).unwrap();
+        std::fs::create_dir_all(rootfs.join("usr/lib")).unwrap();
+        std::fs::create_dir_all(rootfs.join("dev")).unwrap();
+        std::os::unix::fs::symlink("usr/bin", rootfs.join("bin")).unwrap();
+        std::os::unix::fs::symlink("/usr/lib", rootfs.join("lib")).unwrap();
+        std::os::unix::fs::symlink("../../..", rootfs.join("host")).unwrap();
+
+        let mut task = BuildTask {
+            name: "app".to_string(),
+            hash: ManifestHash::new(SupportedHash::Blake3([1; 32])),
+            dependencies: BTreeMap::new(),
+            build_dependencies: BTreeMap::new(),
+            base: None,
+            env: BTreeMap::new(),
+            source_date_epoch: 0,
+            limits: ResourceLimits::default(),
+            timeout: None,
+            scratch: None,
+            store: None,
+            root: None,
+            base_binds: Vec::new(),
+        };
+        assert_eq!(task.resolve_base(&store).unwrap(), []);
+
+        task.base = Some(base);
+        assert_eq!(
+            task.resolve_base(&store).unwrap(),
+            [
+                (rootfs.join("usr/bin"), PathBuf::from("/bin")),
+                (rootfs.join("usr/lib"), PathBuf::from("/lib")),
+                (rootfs.join("usr"), PathBuf::from("/usr")),
+            ]
+        );
+    }
+}
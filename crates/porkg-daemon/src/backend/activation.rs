@@ -0,0 +1,137 @@
+//! Activation commands, which packages run when a profile is switched to a generation that contains them, such as to
+//! reload a service (see [`porkg_model::package::Package::activate`]).
+//!
+//! Each command runs like `porkg run` does, in a sandbox that only contains the closure of its package, with the tree
+//! of the generation bound read-only at [`PROFILE_MOUNT`]. It receives the search paths of its package and:
+//!
+//! - `PORKG_PROFILE`: where the tree of the generation is.
+//! - `PORKG_GENERATION`: the generation that the profile is switched to.
+//!
+//! The commands run in the order of the closure, so that a package activates after everything that it references. A
+//! command that exits unsuccessfully, or that runs for longer than the hook timeout, fails the switch.
+
+use std::{collections::VecDeque, io, path::Path, time::Duration};
+
+use porkg_model::{hashing::SupportedHash, log::LogRecord, store::PackageInfo};
+use thiserror::Error;
+
+use super::{
+    hook::{run_sandboxed, Finished},
+    run::{RunTask, PROFILE_MOUNT},
+    sandbox::Sandbox,
+};
+use crate::store::{
+    wrapper::{WrapperError, Wrappers},
+    Store, StoreError,
+};
+
+#[derive(Debug, Error)]
+pub enum ActivationError {
+    #[error(transparent)]
+    Wrapper(#[from] WrapperError),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error("failed to start the activation of {name}: {error}")]
+    Spawn { name: String, error: String },
+    #[error("the activation of {name} failed with exit code {code}")]
+    Failed {
+        name: String,
+        code: i32,
+        output: Vec<LogRecord>,
+    },
+    #[error("the activation of {name} did not finish within {seconds} seconds")]
+    Timeout {
+        name: String,
+        seconds: u64,
+        output: Vec<LogRecord>,
+    },
+}
+
+/// Prepares the activation commands of the packages in `closure`, in its order, for switching to `generation`, whose
+/// tree is `tree`.
+///
+/// This performs blocking IO.
+pub fn prepare(
+    store: &Store,
+    closure: &[(SupportedHash, PackageInfo)],
+    tree: &Path,
+    generation: u64,
+) -> Result<Vec<(String, RunTask)>, ActivationError> {
+    let tree = tree.canonicalize()?;
+    let mut result = Vec::new();
+    for (hash, info) in closure {
+        let Some(activate) = &info.activate else {
+            continue;
+        };
+        let mut wrappers = Wrappers::new(&store.by_hash(hash), &store.logical(hash))?;
+        for (reference, reference_info) in closure {
+            if reference != hash && info.references.contains(reference) {
+                wrappers.add_reference(
+                    &reference_info.name,
+                    &store.by_hash(reference),
+                    &store.logical(reference),
+                )?;
+            }
+        }
+        let command = wrappers.command("activate", activate)?;
+        let mut env = command.env;
+        env.extend(
+            command
+                .search_paths
+                .into_iter()
+                .map(|(variable, value)| (variable.to_string(), value)),
+        );
+        env.insert("PORKG_PROFILE".to_string(), PROFILE_MOUNT.to_string());
+        env.insert("PORKG_GENERATION".to_string(), generation.to_string());
+
+        let task = RunTask {
+            exec: command.exec,
+            env,
+            root: store.temp_path("activate"),
+            closure: store
+                .closure(hash)?
+                .iter()
+                .map(|(v, _)| store.by_hash(v))
+                .collect(),
+            store: store.mount(),
+            profile: Some(tree.clone()),
+            binds: Vec::new(),
+            isolate_network: false,
+        };
+        result.push((info.name.clone(), task));
+    }
+    Ok(result)
+}
+
+/// Runs the activation command of the package `name`, logging its output line by line.
+pub async fn run(
+    sandbox: &Sandbox,
+    name: &str,
+    task: RunTask,
+    timeout: Duration,
+) -> Result<(), ActivationError> {
+    let mut output = VecDeque::new();
+    let finished = run_sandboxed(sandbox, task, timeout, &mut output, |record| {
+        tracing::info!(name, stream = ?record.stream, "{}", record.line);
+    })
+    .await
+    .map_err(|error| ActivationError::Spawn {
+        name: name.to_string(),
+        error: error.to_string(),
+    })?;
+    match finished {
+        Finished::Exited(0) => Ok(()),
+        Finished::Exited(code) => Err(ActivationError::Failed {
+            name: name.to_string(),
+            code,
+            output: output.into(),
+        }),
+        Finished::TimedOut => Err(ActivationError::Timeout {
+            name: name.to_string(),
+            seconds: timeout.as_secs(),
+            output: output.into(),
+        }),
+    }
+}
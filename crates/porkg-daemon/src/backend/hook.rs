@@ -0,0 +1,369 @@
+//! Hooks that the daemon runs before and after each build, such as policy checks or scans of the output.
+//!
+//! Hooks are executables from the daemon configuration, which run one after another. Each runs in a sandbox like
+//! activation commands do (see [`super::activation`]), without network access, in a root file system that only
+//! contains the system directories of the host, the hook itself and the source of the package, all read-only. It
+//! receives no environment besides `PATH` and:
+//!
+//! - `PORKG_HOOK`: `pre-build` or `post-build`.
+//! - `PORKG_PACKAGE`: the name of the package.
+//! - `PORKG_SOURCE`: the directory of the source of the package within the sandbox.
+//!
+//! A hook that exits unsuccessfully, or that runs for longer than the configured timeout, rejects the build. The output
+//! of hooks is logged line by line, and the end of it is returned with a rejection.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    os::fd::AsRawFd as _,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use porkg_linux::sandbox::CreateSandboxError;
+use porkg_model::log::{LogLines, LogRecord, LogStream};
+use porkg_private::{
+    io::{into_async, pair, SocketOptions},
+    sandbox::SandboxBackend as _,
+};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt as _};
+
+use super::{run::RunTask, sandbox::Sandbox, Task};
+use crate::{config::HooksConfig, store::Store};
+
+/// The number of lines of output that are kept for a failure.
+const MAX_OUTPUT_LINES: usize = 50;
+
+const PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// The directories of the host that hooks can read, for the programs and libraries that they run with. Those that the
+/// host doesn't have are skipped.
+const SYSTEM_DIRS: &[&str] = &["/usr", "/bin", "/sbin", "/lib", "/lib64", "/etc"];
+
+/// The exit code that is reported when the sandbox exits without reporting the exit code of the command.
+const SANDBOX_FAILED: i32 = 255;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookStage {
+    PreBuild,
+    PostBuild,
+}
+
+impl fmt::Display for HookStage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            HookStage::PreBuild => "pre-build",
+            HookStage::PostBuild => "post-build",
+        })
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum HookError {
+    #[error("failed to run the {stage} hook {hook:?}")]
+    Spawn {
+        stage: HookStage,
+        hook: PathBuf,
+        #[source]
+        error: CreateSandboxError,
+    },
+    #[error("the {stage} hook {hook:?} rejected the build")]
+    Rejected {
+        stage: HookStage,
+        hook: PathBuf,
+        status: Option<i32>,
+        output: Vec<LogRecord>,
+    },
+    #[error("the {stage} hook {hook:?} did not finish within {seconds} seconds")]
+    Timeout {
+        stage: HookStage,
+        hook: PathBuf,
+        seconds: u64,
+        output: Vec<LogRecord>,
+    },
+}
+
+/// What a hook is run for.
+#[derive(Debug, Clone, Copy)]
+pub struct HookContext<'a> {
+    pub package: &'a str,
+    pub source: &'a Path,
+}
+
+/// Sends what `reader` reads to `sender` until it ends.
+pub(super) async fn forward(
+    mut reader: impl AsyncRead + Unpin,
+    stream: LogStream,
+    sender: flume::Sender<(LogStream, Vec<u8>, SystemTime)>,
+) {
+    let mut buffer = vec![0; 8192];
+    while let Ok(size @ 1..) = reader.read(&mut buffer).await {
+        let data = buffer[..size].to_vec();
+        if sender
+            .send_async((stream, data, SystemTime::now()))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Splits what `receiver` receives into lines until every sender is gone, passing each line to `log` and keeping the
+/// last of them in `output`.
+pub(super) async fn capture(
+    receiver: flume::Receiver<(LogStream, Vec<u8>, SystemTime)>,
+    output: &mut VecDeque<LogRecord>,
+    log: impl Fn(&LogRecord),
+) {
+    let mut keep = |records: Vec<LogRecord>| {
+        for record in records {
+            let record = record.plain();
+            log(&record);
+            if output.len() == MAX_OUTPUT_LINES {
+                output.pop_front();
+            }
+            output.push_back(record);
+        }
+    };
+    let mut lines = LogLines::new();
+    while let Ok((stream, data, time)) = receiver.recv_async().await {
+        keep(lines.push(stream, &data, time));
+    }
+    keep(lines.finish(SystemTime::now()));
+}
+
+/// How a command that [`run_sandboxed`] ran ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Finished {
+    /// The command exited with this code.
+    Exited(i32),
+    /// The command ran for longer than the timeout, and was killed.
+    TimedOut,
+}
+
+/// Runs `task` in a sandbox without input, passing each line of its output to `log` and keeping the last of them in
+/// `output`. The command is killed if it runs for longer than `timeout`.
+pub(super) async fn run_sandboxed(
+    sandbox: &Sandbox,
+    task: RunTask,
+    timeout: Duration,
+    output: &mut VecDeque<LogRecord>,
+    log: impl Fn(&LogRecord),
+) -> Result<Finished, CreateSandboxError> {
+    let root = task.root.clone();
+    tokio::fs::create_dir_all(&root).await?;
+    let result = run_in(sandbox, task, timeout, output, log).await;
+    // The root is only a mount point on the host, as the sandbox mounted its file system in its own namespace.
+    if let Err(error) = tokio::fs::remove_dir(&root).await {
+        tracing::warn!(?error, ?root, "failed to remove the root of a sandbox");
+    }
+    result
+}
+
+async fn run_in(
+    sandbox: &Sandbox,
+    task: RunTask,
+    timeout: Duration,
+    output: &mut VecDeque<LogRecord>,
+    log: impl Fn(&LogRecord),
+) -> Result<Finished, CreateSandboxError> {
+    let mut ours = Vec::new();
+    let mut theirs = Vec::new();
+    for _ in 0..4 {
+        let (host, sandboxed) = pair(SocketOptions::default())?;
+        ours.push(into_async(host)?);
+        theirs.push(sandboxed);
+    }
+    let fds: Vec<_> = theirs.iter().map(|v| v.as_raw_fd()).collect();
+    let handle = sandbox.spawn(Task::Run(task), &fds).await?;
+    // The sandbox holds its own copies now, so that the streams end when it exits.
+    drop(theirs);
+    let [stdin, stdout, stderr, mut status]: [_; 4] = ours.try_into().unwrap();
+    drop(stdin);
+
+    let (sender, receiver) = flume::unbounded();
+    tokio::spawn(forward(stdout, LogStream::Stdout, sender.clone()));
+    tokio::spawn(forward(stderr, LogStream::Stderr, sender));
+
+    let capture = capture(receiver, output, log);
+    let result = tokio::time::timeout(timeout, async {
+        let mut code = [0u8; 4];
+        let ((), read) = tokio::join!(capture, status.read_exact(&mut code));
+        read.map(|_| i32::from_be_bytes(code))
+    })
+    .await;
+    match result {
+        Ok(code) => Ok(Finished::Exited(code.unwrap_or(SANDBOX_FAILED))),
+        Err(_) => {
+            if let Err(error) = sandbox.kill(Sandbox::id(&handle)).await {
+                tracing::warn!(?error, "failed to kill a command that timed out");
+            }
+            Ok(Finished::TimedOut)
+        }
+    }
+}
+
+/// Runs the hooks of `stage` in order, stopping at the first that fails.
+pub async fn run(
+    sandbox: &Sandbox,
+    store: &Store,
+    config: &HooksConfig,
+    stage: HookStage,
+    context: HookContext<'_>,
+) -> Result<(), HookError> {
+    let hooks = match stage {
+        HookStage::PreBuild => &config.pre_build,
+        HookStage::PostBuild => &config.post_build,
+    };
+    for hook in hooks {
+        let task = prepare(store, hook, stage, context);
+        run_one(sandbox, task, stage, Duration::from_secs(config.timeout)).await?;
+    }
+    Ok(())
+}
+
+/// Prepares the sandboxed command that runs `hook`.
+fn prepare(store: &Store, hook: &Path, stage: HookStage, context: HookContext<'_>) -> RunTask {
+    let mount = store.mount();
+    let mut binds: Vec<_> = SYSTEM_DIRS
+        .iter()
+        .map(PathBuf::from)
+        .filter(|v| v.exists())
+        .collect();
+    binds.push(hook.to_path_buf());
+    RunTask {
+        exec: vec![hook.to_string_lossy().into_owned()],
+        env: [
+            ("PATH".to_string(), PATH.to_string()),
+            ("PORKG_HOOK".to_string(), stage.to_string()),
+            ("PORKG_PACKAGE".to_string(), context.package.to_string()),
+            (
+                "PORKG_SOURCE".to_string(),
+                mount.logical(context.source).to_string_lossy().into_owned(),
+            ),
+        ]
+        .into(),
+        root: store.temp_path("hook"),
+        closure: vec![context.source.to_path_buf()],
+        store: mount,
+        profile: None,
+        binds,
+        isolate_network: true,
+    }
+}
+
+async fn run_one(
+    sandbox: &Sandbox,
+    task: RunTask,
+    stage: HookStage,
+    timeout: Duration,
+) -> Result<(), HookError> {
+    let hook = PathBuf::from(&task.exec[0]);
+    let spawn_error = |error| HookError::Spawn {
+        stage,
+        hook: hook.clone(),
+        error,
+    };
+    // The sandbox would only report a hook that is missing as a failure to bind it.
+    tokio::fs::metadata(&hook)
+        .await
+        .map_err(|error| spawn_error(error.into()))?;
+
+    let mut output = VecDeque::with_capacity(MAX_OUTPUT_LINES);
+    let finished = run_sandboxed(sandbox, task, timeout, &mut output, |record| {
+        tracing::info!(%stage, ?hook, stream = ?record.stream, "{}", record.line);
+    })
+    .await
+    .map_err(spawn_error)?;
+    match finished {
+        Finished::Exited(0) => Ok(()),
+        Finished::Exited(code) => Err(HookError::Rejected {
+            stage,
+            hook,
+            status: Some(code),
+            output: output.into(),
+        }),
+        Finished::TimedOut => Err(HookError::Timeout {
+            stage,
+            hook,
+            seconds: timeout.as_secs(),
+            output: output.into(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use porkg_private::sandbox::{SandboxFlags, SandboxTask as _};
+
+    use crate::store::testing::TestStore;
+
+    use super::*;
+
+    #[test]
+    fn prepare_hook() {
+        let store = TestStore::new("hook");
+        let source = store.root().join("pkg/by-hash/source");
+        let hook = Path::new("/etc/porkg/hooks/scan");
+        let context = HookContext {
+            package: "hello",
+            source: &source,
+        };
+
+        let task = prepare(&store, hook, HookStage::PostBuild, context);
+        assert_eq!(task.exec, ["/etc/porkg/hooks/scan"]);
+        assert_eq!(task.env["PORKG_HOOK"], "post-build");
+        assert_eq!(task.env["PORKG_PACKAGE"], "hello");
+        assert_eq!(
+            Path::new(&task.env["PORKG_SOURCE"]),
+            store.mount().logical(&source)
+        );
+        assert_eq!(task.env.len(), 4);
+        assert_eq!(task.closure, [source.as_path()]);
+
+        let options = task.create_sandbox_options();
+        assert!(options.flags().contains(SandboxFlags::NETWORK_ISOLATION));
+        assert!(options.binds().iter().all(|v| v.read_only));
+        assert!(options
+            .binds()
+            .iter()
+            .any(|v| v.source == hook && v.target == hook));
+        assert!(options
+            .binds()
+            .iter()
+            .any(|v| v.source == source && v.target == store.mount().logical(&source)));
+    }
+
+    #[tokio::test]
+    async fn capture_output() {
+        let (sender, receiver) = flume::unbounded();
+        let time = SystemTime::now();
+        sender
+            .send((LogStream::Stdout, b"scanning hello\n".to_vec(), time))
+            .unwrap();
+        sender
+            .send((
+                LogStream::Stderr,
+                b"\x1b[31mvirus found\x1b[0m\n".to_vec(),
+                time,
+            ))
+            .unwrap();
+        for i in 1..MAX_OUTPUT_LINES {
+            sender
+                .send((LogStream::Stdout, format!("{i}\n").into_bytes(), time))
+                .unwrap();
+        }
+        drop(sender);
+
+        let mut output = VecDeque::new();
+        let logged = std::cell::Cell::new(0);
+        capture(receiver, &mut output, |_| logged.set(logged.get() + 1)).await;
+        assert_eq!(logged.get(), MAX_OUTPUT_LINES + 1);
+        assert_eq!(output.len(), MAX_OUTPUT_LINES);
+        assert_eq!(output[0].line, "virus found");
+        assert_eq!(output[0].stream, LogStream::Stderr);
+        assert_eq!(output[1].line, "1");
+    }
+}
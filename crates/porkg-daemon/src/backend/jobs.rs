@@ -0,0 +1,1199 @@
+//! The builds that are queued or running, so that operators can see them and recover from builds that are stuck.
+//!
+//! A running job is considered stuck once it has gone without output for longer than
+//! [`SchedulerConfig::stuck_after`]. Builds don't capture their output yet, so that is measured from when the job
+//! started. Stuck jobs are reported by [`detector`], and can be requeued (which abandons the current attempt and
+//! queues the build again) or failed.
+//!
+//! Jobs belong to the tenant that submitted them (see [`Tenant`](crate::frontend::auth::Tenant)), and a tenant can
+//! only see and control its own jobs. Jobs of other tenants are reported as not existing.
+//!
+//! Once a job finishes, whether it succeeded is kept for the last [`SchedulerConfig::finished_jobs`] jobs, so that
+//! clients can poll builds that they submitted. With a journal (see [`Jobs::with_journal`]), they are kept across
+//! restarts of the daemon, and jobs that were interrupted by a restart are resumed from their requests (see
+//! [`Jobs::take_interrupted`]), or reported as lost if they have none. Jobs are recorded in the journal by a thread of
+//! its own, so that recording a job never blocks the runtime.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use porkg_model::time::Timestamp;
+use porkg_private::journal::Journal;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{sync::oneshot, time::Instant};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    clock::Time,
+    config::{Config, SchedulerConfig},
+    events::{Event, EventBus, JobChange},
+};
+
+/// The number of records that the journal may have beyond the jobs that it describes before it is compacted.
+const COMPACT_AFTER: usize = 1024;
+
+/// The identifier of a job, which isn't reused when the daemon restarts with the same journal (see
+/// [`Jobs::with_journal`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct JobId(pub u64);
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum JobError {
+    #[error("the job {0} doesn't exist")]
+    NotFound(JobId),
+    #[error("the job {0} isn't running")]
+    NotRunning(JobId),
+}
+
+/// What an operator asked a running job to do.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Control {
+    Requeue,
+    Fail(String),
+    /// The client that submitted the job cancelled it.
+    Cancel,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobState {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    /// The sandbox failed underneath the job, rather than the job itself.
+    Infrastructure,
+    Cancelled,
+    /// The daemon restarted before the job finished.
+    Lost,
+}
+
+/// A job, as listed to operators.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobInfo {
+    pub id: JobId,
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub state: JobState,
+    /// The number of times that the job was started, including the current attempt.
+    pub attempts: u32,
+    /// How long the job has been in its current state, in seconds.
+    pub elapsed: u64,
+    pub stuck: bool,
+    /// Why a failed job failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// The hash of the manifest that the job builds.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// The exit code of the sandbox of the last attempt, once it exited.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub submitted: Option<Timestamp>,
+    /// When the last attempt started.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub started: Option<Timestamp>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished: Option<Timestamp>,
+}
+
+/// What a job was submitted with, which is kept in the journal until it finishes so that it can be resumed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRequest {
+    /// The hash of the manifest that the job builds.
+    pub hash: String,
+    pub body: serde_json::Value,
+}
+
+/// A job that the daemon was running when it stopped, which is resumed from its request.
+#[derive(Debug)]
+pub struct Interrupted {
+    pub id: JobId,
+    pub name: String,
+    pub tenant: Option<String>,
+    pub request: JobRequest,
+    submitted: Timestamp,
+}
+
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    tenant: Option<String>,
+    request: Option<JobRequest>,
+    submitted: Timestamp,
+    started: Option<Timestamp>,
+    exit_code: Option<i32>,
+    state: JobState,
+    attempts: u32,
+    since: Instant,
+    /// How long the previous attempts ran for.
+    ran: Duration,
+    control: Option<oneshot::Sender<Control>>,
+    /// Whether the current attempt was reported as stuck.
+    reported: bool,
+    /// The scratch directory of the current attempt.
+    scratch: Option<PathBuf>,
+}
+
+impl Entry {
+    fn visible(&self, scope: Option<&str>) -> bool {
+        scope.map_or(true, |v| self.tenant.as_deref() == Some(v))
+    }
+}
+
+/// A job that finished, with its [`JobInfo::elapsed`] measured from when it finished.
+#[derive(Debug)]
+struct FinishedEntry {
+    info: JobInfo,
+    since: Instant,
+}
+
+#[derive(Debug)]
+struct Shared {
+    jobs: Mutex<BTreeMap<JobId, Entry>>,
+    finished: Mutex<VecDeque<FinishedEntry>>,
+    /// The jobs that were recovered from the journal to be resumed.
+    interrupted: Mutex<Vec<Interrupted>>,
+    time: Time,
+    stuck_after: Duration,
+    finished_jobs: usize,
+    events: EventBus,
+    /// Whether the daemon is stopping, so that jobs that are dropped were interrupted rather than abandoned.
+    stopping: AtomicBool,
+}
+
+/// The jobs of the daemon.
+#[derive(Debug, Clone)]
+pub struct Jobs {
+    shared: Arc<Shared>,
+    /// The journal that the jobs are recorded in.
+    journal: Option<Arc<Writer>>,
+}
+
+impl Jobs {
+    /// Creates an empty set of jobs, which publishes their state changes to `events`. Jobs are numbered and timed by
+    /// `time`.
+    pub fn new(config: &SchedulerConfig, events: EventBus, time: Time) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                jobs: Mutex::new(BTreeMap::new()),
+                finished: Mutex::new(VecDeque::new()),
+                interrupted: Mutex::new(Vec::new()),
+                time,
+                stuck_after: Duration::from_secs(config.stuck_after),
+                finished_jobs: config.finished_jobs,
+                events,
+                stopping: AtomicBool::new(false),
+            }),
+            journal: None,
+        }
+    }
+
+    /// Records the jobs in the journal at `path`, and recovers the jobs that finished or were interrupted before the
+    /// daemon restarted. New jobs get identifiers above those in the journal.
+    ///
+    /// This performs blocking IO.
+    pub fn with_journal(mut self, path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let (writer, recovered) = Writer::open(path, self.shared.finished_jobs)?;
+        if let Some(id) = recovered.issued {
+            self.shared.time.ids.skip_past(id.0);
+        }
+        let now = self.now();
+        let wall = Timestamp::now();
+        self.lock_finished()
+            .extend(recovered.finished.into_iter().map(|info| {
+                let time = info.finished.unwrap_or(wall);
+                let ago = Duration::from_secs(wall.as_secs().saturating_sub(time.as_secs()));
+                FinishedEntry {
+                    info,
+                    since: now.checked_sub(ago).unwrap_or(now),
+                }
+            }));
+        *self.lock_interrupted() = recovered.interrupted;
+        self.journal = Some(Arc::new(writer));
+        Ok(self)
+    }
+
+    fn record(&self, record: Record) {
+        if let Some(journal) = &self.journal {
+            journal.record(record);
+        }
+    }
+
+    /// Waits until the jobs so far are recorded in the journal, such as before the daemon exits.
+    ///
+    /// This blocks.
+    pub fn flush(&self) {
+        if let Some(journal) = &self.journal {
+            journal.flush();
+        }
+    }
+
+    /// Marks the daemon as stopping. Jobs that are dropped from then on without finishing were interrupted, so they
+    /// stay in the journal to be resumed (or reported as lost) when the daemon starts again.
+    pub fn stop(&self) {
+        self.shared.stopping.store(true, Ordering::Relaxed);
+    }
+
+    fn publish(&self, id: JobId, entry: &Entry, change: JobChange) {
+        self.shared.events.publish(Event::Job {
+            id,
+            name: entry.name.clone(),
+            tenant: entry.tenant.clone(),
+            change,
+        });
+    }
+
+    fn now(&self) -> Instant {
+        self.shared.time.clock.now()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<JobId, Entry>> {
+        self.shared
+            .jobs
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+
+    fn lock_finished(&self) -> MutexGuard<'_, VecDeque<FinishedEntry>> {
+        self.shared
+            .finished
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+
+    fn lock_interrupted(&self) -> MutexGuard<'_, Vec<Interrupted>> {
+        self.shared
+            .interrupted
+            .lock()
+            .unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// Adds a queued job for `tenant`, which is removed when the handle is dropped. A job with a request is resumed
+    /// if the daemon restarts before it finishes.
+    pub fn register(
+        &self,
+        name: &str,
+        tenant: Option<String>,
+        request: Option<JobRequest>,
+    ) -> JobHandle {
+        let id = JobId(self.shared.time.ids.next_id());
+        let submitted = Timestamp::now();
+        self.record(Record::Submitted {
+            id,
+            name: name.to_string(),
+            tenant: tenant.clone(),
+            request: request.clone(),
+            time: submitted,
+        });
+        self.add(id, name.to_string(), tenant, request, submitted)
+    }
+
+    /// Takes the jobs that were interrupted by a restart of the daemon, to be resumed with [`Jobs::resume`].
+    pub fn take_interrupted(&self) -> Vec<Interrupted> {
+        std::mem::take(&mut *self.lock_interrupted())
+    }
+
+    /// Queues a job that was interrupted again. It keeps its id, and stays in the journal until it finishes.
+    pub fn resume(&self, job: Interrupted) -> JobHandle {
+        self.add(
+            job.id,
+            job.name,
+            job.tenant,
+            Some(job.request),
+            job.submitted,
+        )
+    }
+
+    fn add(
+        &self,
+        id: JobId,
+        name: String,
+        tenant: Option<String>,
+        request: Option<JobRequest>,
+        submitted: Timestamp,
+    ) -> JobHandle {
+        let entry = Entry {
+            name,
+            tenant,
+            request,
+            submitted,
+            started: None,
+            exit_code: None,
+            state: JobState::Queued,
+            attempts: 0,
+            since: self.now(),
+            ran: Duration::ZERO,
+            control: None,
+            reported: false,
+            scratch: None,
+        };
+        self.publish(id, &entry, JobChange::Queued);
+        self.lock().insert(id, entry);
+        JobHandle {
+            jobs: self.clone(),
+            id,
+            result: None,
+            failure: JobState::Failed,
+        }
+    }
+
+    fn is_stuck(&self, entry: &Entry, now: Instant) -> bool {
+        entry.state == JobState::Running && now - entry.since > self.shared.stuck_after
+    }
+
+    fn info(&self, id: JobId, entry: &Entry, now: Instant) -> JobInfo {
+        JobInfo {
+            id,
+            name: entry.name.clone(),
+            tenant: entry.tenant.clone(),
+            state: entry.state,
+            attempts: entry.attempts,
+            elapsed: (now - entry.since).as_secs(),
+            stuck: self.is_stuck(entry, now),
+            error: None,
+            hash: entry.request.as_ref().map(|v| v.hash.clone()),
+            exit_code: entry.exit_code,
+            submitted: Some(entry.submitted),
+            started: entry.started,
+            finished: None,
+        }
+    }
+
+    fn finished_info(entry: &FinishedEntry, now: Instant) -> JobInfo {
+        JobInfo {
+            elapsed: (now - entry.since).as_secs(),
+            ..entry.info.clone()
+        }
+    }
+
+    /// Lists the jobs that are queued or running and visible within `scope`, which is every job if there is no
+    /// scope.
+    pub fn list(&self, scope: Option<&str>) -> Vec<JobInfo> {
+        let now = self.now();
+        self.lock()
+            .iter()
+            .filter(|(_, entry)| entry.visible(scope))
+            .map(|(id, entry)| self.info(*id, entry, now))
+            .collect()
+    }
+
+    /// Lists the finished jobs that are visible within `scope`, oldest first.
+    pub fn finished(&self, scope: Option<&str>) -> Vec<JobInfo> {
+        let now = self.now();
+        self.lock_finished()
+            .iter()
+            .filter(|entry| scope.map_or(true, |v| entry.info.tenant.as_deref() == Some(v)))
+            .map(|entry| Self::finished_info(entry, now))
+            .collect()
+    }
+
+    /// Gets a job that is visible within `scope`, whether or not it finished.
+    pub fn get(&self, id: JobId, scope: Option<&str>) -> Result<JobInfo, JobError> {
+        let now = self.now();
+        let info = match self.lock().get(&id) {
+            Some(entry) => Some(self.info(id, entry, now)),
+            None => self
+                .lock_finished()
+                .iter()
+                .find(|entry| entry.info.id == id)
+                .map(|entry| Self::finished_info(entry, now)),
+        };
+        info.filter(|v| scope.map_or(true, |scope| v.tenant.as_deref() == Some(scope)))
+            .ok_or(JobError::NotFound(id))
+    }
+
+    /// Interrupts a running job that is visible within `scope`.
+    pub fn control(
+        &self,
+        id: JobId,
+        control: Control,
+        scope: Option<&str>,
+    ) -> Result<(), JobError> {
+        let mut jobs = self.lock();
+        let entry = jobs
+            .get_mut(&id)
+            .filter(|v| v.visible(scope))
+            .ok_or(JobError::NotFound(id))?;
+        let sender = entry.control.take().ok_or(JobError::NotRunning(id))?;
+        tracing::info!(%id, name = entry.name, ?control, "interrupting job");
+        sender.send(control).map_err(|_| JobError::NotRunning(id))
+    }
+
+    /// Gets the scratch directory of a running job that is visible within `scope`.
+    pub fn scratch(&self, id: JobId, scope: Option<&str>) -> Result<PathBuf, JobError> {
+        let jobs = self.lock();
+        let entry = jobs
+            .get(&id)
+            .filter(|v| v.visible(scope))
+            .ok_or(JobError::NotFound(id))?;
+        entry.scratch.clone().ok_or(JobError::NotRunning(id))
+    }
+
+    /// Marks the stuck jobs that haven't been reported yet as reported, and returns them.
+    fn newly_stuck(&self) -> Vec<(JobId, String, Duration)> {
+        let now = self.now();
+        let mut jobs = self.lock();
+        jobs.iter_mut()
+            .filter(|(_, entry)| !entry.reported && self.is_stuck(entry, now))
+            .map(|(id, entry)| {
+                entry.reported = true;
+                (*id, entry.name.clone(), now - entry.since)
+            })
+            .collect()
+    }
+}
+
+/// A job that is queued or running.
+#[derive(Debug)]
+pub struct JobHandle {
+    jobs: Jobs,
+    id: JobId,
+    /// Whether the job succeeded, once it finished.
+    result: Option<Result<(), String>>,
+    /// The state that the job finishes in if it failed.
+    failure: JobState,
+}
+
+impl JobHandle {
+    pub fn id(&self) -> JobId {
+        self.id
+    }
+
+    /// Records whether the job succeeded, and removes it from the jobs that are queued or running.
+    pub fn finish(mut self, result: Result<(), String>) {
+        self.result = Some(result);
+    }
+
+    /// Records that the job failed because the sandbox failed underneath it.
+    pub fn finish_infrastructure(mut self, error: String) {
+        self.failure = JobState::Infrastructure;
+        self.finish(Err(error));
+    }
+
+    /// Records that the client that submitted the job cancelled it.
+    pub fn finish_cancelled(mut self, error: String) {
+        self.failure = JobState::Cancelled;
+        self.finish(Err(error));
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Entry) -> JobChange) {
+        if let Some(entry) = self.jobs.lock().get_mut(&self.id) {
+            let change = f(entry);
+            self.jobs.publish(self.id, entry, change);
+        }
+    }
+
+    /// Gets how long the attempts of the job ran for, including an attempt that is running.
+    pub fn ran(&self) -> Duration {
+        let now = self.jobs.now();
+        self.jobs
+            .lock()
+            .get(&self.id)
+            .map_or(Duration::ZERO, |entry| match entry.state {
+                JobState::Running => entry.ran + (now - entry.since),
+                _ => entry.ran,
+            })
+    }
+
+    /// Records the exit code of the sandbox of the current attempt.
+    pub fn set_exit_code(&self, code: i32) {
+        if let Some(entry) = self.jobs.lock().get_mut(&self.id) {
+            entry.exit_code = Some(code);
+        }
+    }
+
+    /// Records the scratch directory of the current attempt, which is forgotten once the attempt finishes.
+    pub fn set_scratch(&self, scratch: &Path) {
+        if let Some(entry) = self.jobs.lock().get_mut(&self.id) {
+            entry.scratch = Some(scratch.to_path_buf());
+        }
+    }
+
+    /// Runs an attempt of the job, unless an operator interrupts it first.
+    pub async fn attempt<F: Future>(&self, attempt: F) -> Result<F::Output, Control> {
+        let (sender, receiver) = oneshot::channel();
+        let now = self.jobs.now();
+        self.update(|entry| {
+            entry.state = JobState::Running;
+            entry.attempts += 1;
+            entry.since = now;
+            entry.started = Some(Timestamp::now());
+            entry.exit_code = None;
+            entry.control = Some(sender);
+            entry.reported = false;
+            JobChange::Running {
+                attempt: entry.attempts,
+            }
+        });
+
+        // The job is queued again even if the attempt is dropped before it finishes, such as when it times out.
+        let _finished = Finished(self);
+        tokio::select! {
+            output = attempt => Ok(output),
+            Ok(control) = receiver => Err(control),
+        }
+    }
+}
+
+struct Finished<'a>(&'a JobHandle);
+
+impl Drop for Finished<'_> {
+    fn drop(&mut self) {
+        let now = self.0.jobs.now();
+        self.0.update(|entry| {
+            entry.ran += now - entry.since;
+            entry.state = JobState::Queued;
+            entry.since = now;
+            entry.control = None;
+            entry.scratch = None;
+            JobChange::Queued
+        });
+    }
+}
+
+impl Drop for JobHandle {
+    fn drop(&mut self) {
+        let Some(entry) = self.jobs.lock().remove(&self.id) else {
+            return;
+        };
+        self.jobs.publish(self.id, &entry, JobChange::Removed);
+
+        let result = match self.result.take() {
+            Some(result) => result,
+            None if self.jobs.shared.stopping.load(Ordering::Relaxed) => {
+                tracing::info!(id = %self.id, name = entry.name, "the daemon stopped before the job finished");
+                return;
+            }
+            None => Err("the job was abandoned".to_string()),
+        };
+        let now = self.jobs.now();
+        let mut info = self.jobs.info(self.id, &entry, now);
+        info.state = match result {
+            Ok(()) => JobState::Succeeded,
+            Err(_) => self.failure,
+        };
+        info.stuck = false;
+        info.error = result.err();
+        info.finished = Some(Timestamp::now());
+        self.jobs.record(Record::Finished { info: info.clone() });
+        let mut finished = self.jobs.lock_finished();
+        finished.push_back(FinishedEntry { info, since: now });
+        while finished.len() > self.jobs.shared.finished_jobs {
+            finished.pop_front();
+        }
+    }
+}
+
+/// A record of the journal of jobs, each of which is a JSON payload of a [`Journal`] record.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "kebab-case")]
+enum Record {
+    Submitted {
+        id: JobId,
+        name: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        tenant: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request: Option<JobRequest>,
+        time: Timestamp,
+    },
+    Finished {
+        #[serde(flatten)]
+        info: JobInfo,
+    },
+    /// The largest id that was issued before the journal was compacted, which the jobs that are kept may not have.
+    Issued { id: JobId },
+}
+
+/// The jobs that a journal describes, which it is compacted to.
+#[derive(Debug)]
+struct Described {
+    /// The jobs that were submitted and haven't finished, as they were submitted.
+    submitted: BTreeMap<JobId, Record>,
+    /// The newest finished jobs, oldest first.
+    finished: VecDeque<JobInfo>,
+    /// The number of finished jobs that are kept.
+    keep: usize,
+    /// The largest id that was recorded.
+    issued: Option<JobId>,
+}
+
+impl Described {
+    fn new(keep: usize) -> Self {
+        Self {
+            submitted: BTreeMap::new(),
+            finished: VecDeque::new(),
+            keep,
+            issued: None,
+        }
+    }
+
+    fn apply(&mut self, record: Record) {
+        let id = match &record {
+            Record::Submitted { id, .. } | Record::Issued { id } => *id,
+            Record::Finished { info } => info.id,
+        };
+        self.issued = self.issued.max(Some(id));
+        match record {
+            Record::Submitted { id, .. } => {
+                self.submitted.insert(id, record);
+            }
+            Record::Finished { info } => {
+                self.submitted.remove(&info.id);
+                self.finished.push_back(info);
+                while self.finished.len() > self.keep {
+                    self.finished.pop_front();
+                }
+            }
+            Record::Issued { .. } => {}
+        }
+    }
+
+    /// Replaces the records of `journal` with the jobs that it describes.
+    ///
+    /// This performs blocking IO.
+    fn compact(&self, journal: &mut Journal) -> io::Result<()> {
+        let finished = self
+            .finished
+            .iter()
+            .map(|info| Record::Finished { info: info.clone() });
+        let records = self
+            .issued
+            .map(|id| Record::Issued { id })
+            .into_iter()
+            .chain(finished)
+            .chain(self.submitted.values().cloned())
+            .map(|record| serde_json::to_vec(&record))
+            .collect::<Result<Vec<_>, _>>()?;
+        journal.compact(records.iter().map(Vec::as_slice))
+    }
+
+    /// Appends `record` to `journal`, compacting the journal once it grew too far beyond the jobs that it describes.
+    ///
+    /// This performs blocking IO.
+    fn append(&mut self, journal: &mut Journal, record: Record) -> io::Result<()> {
+        let payload = serde_json::to_vec(&record)?;
+        // A record that fails to be appended is still written by the next compaction.
+        self.apply(record);
+        journal.append(&payload)?;
+        if journal.len() > self.submitted.len() + self.finished.len() + COMPACT_AFTER {
+            self.compact(journal)?;
+        }
+        Ok(())
+    }
+}
+
+/// What was recovered from a journal.
+#[derive(Debug)]
+struct Recovered {
+    /// The newest finished jobs, oldest first.
+    finished: Vec<JobInfo>,
+    /// The jobs that were interrupted, and can be resumed from their requests.
+    interrupted: Vec<Interrupted>,
+    /// The largest id that was issued.
+    issued: Option<JobId>,
+}
+
+enum Message {
+    Record(Record),
+    Flush(flume::Sender<()>),
+}
+
+/// Appends records to the journal of jobs in the order that they are sent, from a thread of its own.
+#[derive(Debug)]
+struct Writer {
+    sender: flume::Sender<Message>,
+}
+
+impl Writer {
+    /// Opens the journal at `path`, recovering the newest `keep` finished jobs and the jobs that never finished. Those
+    /// that can't be resumed are recorded as lost, and the journal is compacted to the jobs that it describes.
+    ///
+    /// This performs blocking IO.
+    fn open(path: &Path, keep: usize) -> io::Result<(Self, Recovered)> {
+        let (mut journal, records) = Journal::open(path)?;
+        let mut described = Described::new(keep);
+        for record in records {
+            match serde_json::from_slice::<Record>(&record) {
+                Ok(record) => described.apply(record),
+                Err(error) => tracing::warn!(?path, ?error, "ignoring an invalid job record"),
+            }
+        }
+
+        let now = Timestamp::now();
+        let mut interrupted = Vec::new();
+        let mut lost = Vec::new();
+        for record in described.submitted.values() {
+            let Record::Submitted {
+                id,
+                name,
+                tenant,
+                request,
+                time,
+            } = record.clone()
+            else {
+                continue;
+            };
+            match request {
+                Some(request) => interrupted.push(Interrupted {
+                    id,
+                    name,
+                    tenant,
+                    request,
+                    submitted: time,
+                }),
+                None => {
+                    tracing::warn!(%id, name, "the daemon restarted before the job finished");
+                    lost.push(JobInfo {
+                        id,
+                        name,
+                        tenant,
+                        hash: None,
+                        state: JobState::Lost,
+                        attempts: 0,
+                        elapsed: 0,
+                        stuck: false,
+                        error: Some("the daemon restarted before the job finished".to_string()),
+                        exit_code: None,
+                        submitted: Some(time),
+                        started: None,
+                        finished: Some(now),
+                    });
+                }
+            }
+        }
+        for info in lost {
+            described.apply(Record::Finished { info });
+        }
+        described.compact(&mut journal)?;
+        let recovered = Recovered {
+            finished: described.finished.iter().cloned().collect(),
+            interrupted,
+            issued: described.issued,
+        };
+
+        let path = path.to_path_buf();
+        let (sender, receiver) = flume::unbounded();
+        std::thread::Builder::new()
+            .name("porkg-jobs".to_string())
+            .spawn(move || {
+                for message in receiver.iter() {
+                    match message {
+                        Message::Record(record) => {
+                            if let Err(error) = described.append(&mut journal, record) {
+                                tracing::error!(
+                                    ?error,
+                                    ?path,
+                                    "failed to record a job in the journal"
+                                );
+                            }
+                        }
+                        Message::Flush(done) => {
+                            done.send(()).ok();
+                        }
+                    }
+                }
+            })?;
+        Ok((Self { sender }, recovered))
+    }
+
+    /// Queues a record to be appended, without waiting for it.
+    fn record(&self, record: Record) {
+        // The thread only stops once every sender is gone.
+        self.sender.send(Message::Record(record)).ok();
+    }
+
+    /// Waits until the records that were queued so far are appended.
+    ///
+    /// This blocks.
+    fn flush(&self) {
+        let (done, wait) = flume::bounded(1);
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            wait.recv().ok();
+        }
+    }
+}
+
+/// Reports jobs as they become stuck.
+pub async fn detector(
+    config: Arc<Config>,
+    jobs: Jobs,
+    cancellation_token: CancellationToken,
+) -> anyhow::Result<()> {
+    let period = Duration::from_secs((config.scheduler.stuck_after / 4).clamp(1, 60));
+    let mut interval = tokio::time::interval(period);
+    loop {
+        tokio::select! {
+            _ = cancellation_token.cancelled() => return Ok(()),
+            _ = interval.tick() => {}
+        }
+        for (id, name, elapsed) in jobs.newly_stuck() {
+            tracing::warn!(%id, name, ?elapsed, "job appears to be stuck");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::SystemTime;
+
+    use futures_util::FutureExt as _;
+
+    use crate::clock::{ManualClock, Sequential};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn time_jobs_by_clock() {
+        let clock = Arc::new(ManualClock::new(SystemTime::UNIX_EPOCH));
+        let time = Time {
+            clock: clock.clone(),
+            ids: Arc::new(Sequential::default()),
+        };
+        let jobs = Jobs::new(
+            &SchedulerConfig {
+                stuck_after: 60,
+                ..Default::default()
+            },
+            EventBus::default(),
+            time,
+        );
+
+        let job = jobs.register("hello", None, None);
+        assert_eq!(job.id(), JobId(1));
+        let attempt = job.attempt(std::future::pending::<()>());
+        tokio::pin!(attempt);
+        assert!((&mut attempt).now_or_never().is_none());
+
+        clock.advance(Duration::from_secs(60));
+        assert!(jobs.newly_stuck().is_empty());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            jobs.newly_stuck(),
+            [(JobId(1), "hello".to_string(), Duration::from_secs(61))]
+        );
+        assert_eq!(jobs.list(None)[0].elapsed, 61);
+        assert_eq!(job.ran(), Duration::from_secs(61));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn control_jobs() {
+        let events = EventBus::default();
+        let mut subscription = events.subscribe();
+        let jobs = Jobs::new(
+            &SchedulerConfig {
+                stuck_after: 60,
+                ..Default::default()
+            },
+            events,
+            Time::default(),
+        );
+        let job = jobs.register("hello", None, None);
+        assert!(matches!(
+            jobs.control(job.id(), Control::Requeue, None),
+            Err(JobError::NotRunning(_))
+        ));
+        assert!(matches!(
+            jobs.control(JobId(job.id().0 + 1), Control::Requeue, None),
+            Err(JobError::NotFound(_))
+        ));
+
+        let running = tokio::spawn({
+            let jobs = jobs.clone();
+            async move {
+                let job = jobs.register("stuck", Some("team".to_string()), None);
+                let first = job.attempt(std::future::pending::<()>()).await;
+                let second = job.attempt(std::future::pending::<()>()).await;
+                (first, second)
+            }
+        });
+        tokio::time::sleep(Duration::from_secs(61)).await;
+
+        let listed = jobs.list(None);
+        assert_eq!(listed.len(), 2);
+        let stuck = &listed[1];
+        assert_eq!(
+            (
+                stuck.name.as_str(),
+                stuck.state,
+                stuck.attempts,
+                stuck.stuck
+            ),
+            ("stuck", JobState::Running, 1, true)
+        );
+        assert!(!listed[0].stuck);
+
+        // Other tenants can't see or control the job.
+        assert_eq!(jobs.list(Some("team")).len(), 1);
+        assert!(jobs.list(Some("other")).is_empty());
+        assert!(matches!(
+            jobs.control(stuck.id, Control::Requeue, Some("other")),
+            Err(JobError::NotFound(_))
+        ));
+
+        assert_eq!(jobs.newly_stuck().len(), 1);
+        assert!(jobs.newly_stuck().is_empty());
+
+        jobs.control(stuck.id, Control::Requeue, None).unwrap();
+        tokio::task::yield_now().await;
+        assert_eq!(jobs.list(None)[1].attempts, 2);
+        jobs.control(stuck.id, Control::Fail("wedged".to_string()), Some("team"))
+            .unwrap();
+        let (first, second) = running.await.unwrap();
+        assert_eq!(first, Err(Control::Requeue));
+        assert_eq!(second, Err(Control::Fail("wedged".to_string())));
+
+        // Finished jobs are removed.
+        assert_eq!(jobs.list(None).len(), 1);
+        assert_eq!(job.attempt(async { 1 }).await, Ok(1));
+
+        // Only the time that attempts spend running is counted.
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        let attempt = job.attempt(tokio::time::sleep(Duration::from_secs(3)));
+        assert!(tokio::time::timeout(Duration::from_secs(2), attempt)
+            .await
+            .is_err());
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(job.ran(), Duration::from_secs(2));
+        job.attempt(tokio::time::sleep(Duration::from_secs(3)))
+            .await
+            .unwrap();
+        assert_eq!(job.ran(), Duration::from_secs(5));
+
+        // Every state change was published.
+        let stuck = stuck.id;
+        let mut changes = Vec::new();
+        while let Some(Some(event)) = subscription.recv().now_or_never() {
+            if let Event::Job { id, change, .. } = *event {
+                if id == stuck {
+                    changes.push(change);
+                }
+            }
+        }
+        assert_eq!(
+            changes,
+            [
+                JobChange::Queued,
+                JobChange::Running { attempt: 1 },
+                JobChange::Queued,
+                JobChange::Running { attempt: 2 },
+                JobChange::Queued,
+                JobChange::Removed,
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keep_finished_jobs() {
+        let jobs = Jobs::new(
+            &SchedulerConfig {
+                finished_jobs: 4,
+                ..Default::default()
+            },
+            EventBus::default(),
+            Time::default(),
+        );
+        let succeeded = jobs.register("hello", Some("team".to_string()), None);
+        let id = succeeded.id();
+        assert_eq!(jobs.get(id, None).unwrap().state, JobState::Queued);
+        succeeded.attempt(async {}).await.unwrap();
+        succeeded.finish(Ok(()));
+        tokio::time::sleep(Duration::from_secs(5)).await;
+
+        let info = jobs.get(id, Some("team")).unwrap();
+        assert_eq!(
+            (info.state, info.attempts, info.elapsed),
+            (JobState::Succeeded, 1, 5)
+        );
+        assert!(jobs.list(None).is_empty());
+        assert!(matches!(
+            jobs.get(id, Some("other")),
+            Err(JobError::NotFound(_))
+        ));
+
+        jobs.register("failed", None, None)
+            .finish(Err("exited with 1".to_string()));
+        jobs.register("lost", None, None)
+            .finish_infrastructure("the zygote exited".to_string());
+        jobs.register("cancelled", None, None)
+            .finish_cancelled("the build was cancelled".to_string());
+        drop(jobs.register("abandoned", None, None));
+        let finished = jobs.finished(None);
+        let finished: Vec<_> = finished
+            .iter()
+            .map(|v| (v.name.as_str(), v.state, v.error.as_deref()))
+            .collect();
+        assert_eq!(
+            finished,
+            [
+                ("failed", JobState::Failed, Some("exited with 1")),
+                ("lost", JobState::Infrastructure, Some("the zygote exited")),
+                (
+                    "cancelled",
+                    JobState::Cancelled,
+                    Some("the build was cancelled")
+                ),
+                ("abandoned", JobState::Failed, Some("the job was abandoned")),
+            ]
+        );
+
+        // Only the most recent jobs are kept.
+        assert!(jobs.get(id, None).is_err());
+        assert!(jobs.finished(Some("team")).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recover_jobs() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let path = dir.join("jobs");
+        let config = SchedulerConfig {
+            finished_jobs: 2,
+            ..Default::default()
+        };
+        let request = JobRequest {
+            hash: "blake3-aaaa".to_string(),
+            body: serde_json::json!({ "name": "resumed" }),
+        };
+        let jobs = Jobs::new(&config, EventBus::default(), Time::default())
+            .with_journal(&path)
+            .unwrap();
+        jobs.register("first", None, None).finish(Ok(()));
+        let failed = jobs.register("second", Some("team".to_string()), Some(request.clone()));
+        failed.attempt(async {}).await.unwrap();
+        failed.set_exit_code(1);
+        failed.finish(Err("exited with 1".to_string()));
+        let interrupted = [
+            jobs.register("interrupted", None, None),
+            jobs.register("resumed", Some("team".to_string()), Some(request.clone())),
+        ];
+        let [lost, resumed] = interrupted.each_ref().map(JobHandle::id);
+        // The daemon stops without dropping the jobs.
+        std::mem::forget(interrupted);
+        jobs.flush();
+
+        let jobs = Jobs::new(&config, EventBus::default(), Time::default())
+            .with_journal(&path)
+            .unwrap();
+        let finished: Vec<_> = jobs
+            .finished(None)
+            .into_iter()
+            .map(|v| (v.name, v.state, v.tenant))
+            .collect();
+        assert_eq!(
+            finished,
+            [
+                (
+                    "second".to_string(),
+                    JobState::Failed,
+                    Some("team".to_string())
+                ),
+                ("interrupted".to_string(), JobState::Lost, None),
+            ]
+        );
+        assert_eq!(jobs.get(lost, None).unwrap().state, JobState::Lost);
+        let failed = &jobs.finished(None)[0];
+        assert_eq!(failed.hash.as_deref(), Some("blake3-aaaa"));
+        assert_eq!(failed.exit_code, Some(1));
+        assert!(failed.submitted <= failed.started && failed.started <= failed.finished);
+
+        // The job with a request is resumed with its id, and stays in the journal until it finishes.
+        let interrupted = jobs.take_interrupted();
+        assert_eq!(interrupted.len(), 1);
+        assert_eq!(interrupted[0].id, resumed);
+        assert_eq!(interrupted[0].request.body, request.body);
+        assert!(jobs.take_interrupted().is_empty());
+        let job = jobs.resume(interrupted.into_iter().next().unwrap());
+        assert_eq!(job.id(), resumed);
+        assert_eq!(
+            jobs.get(resumed, Some("team")).unwrap().state,
+            JobState::Queued
+        );
+        jobs.flush();
+        std::mem::forget(job);
+
+        // The journal was compacted, so the lost job isn't lost again.
+        let jobs = Jobs::new(&config, EventBus::default(), Time::default())
+            .with_journal(&path)
+            .unwrap();
+        assert_eq!(jobs.finished(None).len(), 2);
+        let job = jobs.resume(jobs.take_interrupted().pop().unwrap());
+        job.finish(Ok(()));
+        jobs.flush();
+
+        let jobs = Jobs::new(&config, EventBus::default(), Time::default())
+            .with_journal(&path)
+            .unwrap();
+        assert!(jobs.take_interrupted().is_empty());
+        assert_eq!(jobs.get(resumed, None).unwrap().state, JobState::Succeeded);
+    }
+
+    #[test]
+    fn recover_stopped_jobs() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("jobs");
+        let config = SchedulerConfig::default();
+        let request = JobRequest {
+            hash: "blake3-aaaa".to_string(),
+            body: serde_json::json!({ "name": "resumed" }),
+        };
+        let jobs = Jobs::new(&config, EventBus::default(), Time::default())
+            .with_journal(&path)
+            .unwrap();
+        let resumed = jobs.register("resumed", None, Some(request.clone()));
+        let lost = jobs.register("lost", None, None);
+        let (lost_id, resumed_id) = (lost.id(), resumed.id());
+        // The daemon stops cleanly, dropping the jobs with the runtime.
+        jobs.stop();
+        drop((lost, resumed));
+        assert!(jobs.finished(None).is_empty());
+        jobs.flush();
+
+        let jobs = Jobs::new(&config, EventBus::default(), Time::default())
+            .with_journal(&path)
+            .unwrap();
+        assert_eq!(jobs.get(lost_id, None).unwrap().state, JobState::Lost);
+        let interrupted = jobs.take_interrupted();
+        assert_eq!(interrupted.len(), 1);
+        assert_eq!(interrupted[0].id, resumed_id);
+        assert_eq!(interrupted[0].request.body, request.body);
+
+        // The ids of the jobs that were dropped from the journal aren't reused either.
+        let config = SchedulerConfig {
+            finished_jobs: 0,
+            ..Default::default()
+        };
+        let jobs = Jobs::new(&config, EventBus::default(), Time::default())
+            .with_journal(&path)
+            .unwrap();
+        assert!(jobs.get(lost_id, None).is_err());
+        let jobs = Jobs::new(&config, EventBus::default(), Time::default())
+            .with_journal(&path)
+            .unwrap();
+        assert!(jobs.register("next", None, None).id() > lost_id);
+    }
+}
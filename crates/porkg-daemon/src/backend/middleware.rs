@@ -0,0 +1,55 @@
+//! The middleware that every task runs through in its worker.
+
+use std::{os::fd::OwnedFd, time::Instant};
+
+use porkg_private::{
+    crash,
+    sandbox::{Next, SandboxTask, TaskChain, TaskMiddleware},
+};
+
+use super::Task;
+
+/// The middleware of the tasks of the daemon, in the order that they wrap them.
+pub fn chain() -> TaskChain<Task> {
+    TaskChain::default().with(CrashContext).with(Timing)
+}
+
+/// Records the package that the worker builds in its crash reports.
+#[derive(Debug, Clone, Copy)]
+pub struct CrashContext;
+
+impl TaskMiddleware<Task> for CrashContext {
+    fn execute(
+        &self,
+        task: &Task,
+        fds: Vec<OwnedFd>,
+        next: Next<'_, Task>,
+    ) -> Result<(), <Task as SandboxTask>::ExecuteError> {
+        if let Task::Build(task) = task {
+            crash::set_task(Some(task.hash.to_string()));
+        }
+        next.run(task, fds)
+    }
+}
+
+/// Traces how long tasks take to execute, including the middleware after it.
+#[derive(Debug, Clone, Copy)]
+pub struct Timing;
+
+impl TaskMiddleware<Task> for Timing {
+    fn execute(
+        &self,
+        task: &Task,
+        fds: Vec<OwnedFd>,
+        next: Next<'_, Task>,
+    ) -> Result<(), <Task as SandboxTask>::ExecuteError> {
+        let start = Instant::now();
+        let result = next.run(task, fds);
+        tracing::info!(
+            elapsed = ?start.elapsed(),
+            success = result.is_ok(),
+            "task finished"
+        );
+        result
+    }
+}
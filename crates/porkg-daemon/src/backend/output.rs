@@ -0,0 +1,298 @@
+//! The output of tasks, which the zygote redirects into pipes that the daemon reads (see [`OutputStream`]).
+
+use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use porkg_linux::sandbox::OutputStream;
+use porkg_model::log::{LogLines, LogRecord, LogStream};
+use porkg_private::fanout::{Chunk, FanOut, Subscriber};
+
+use crate::redact::Redactor;
+
+use super::{hook::forward, jobs::JobId};
+
+/// The number of lines that are kept of each build, after which the oldest lines are dropped.
+const MAX_LINES: usize = 2000;
+/// The number of bytes of output that a client following a build may fall behind by before it misses some.
+const FOLLOW_CAPACITY: usize = 1024 * 1024;
+/// The most bytes that a follower reads from the stream at once.
+const READ_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+struct Log {
+    records: VecDeque<LogRecord>,
+    /// Streams the lines, as JSON followed by a newline, to the clients that follow the build until its output ends.
+    live: Option<FanOut>,
+}
+
+#[derive(Debug, Default)]
+struct Logs {
+    by_job: HashMap<JobId, Log>,
+    /// The jobs that have logs, oldest first.
+    order: VecDeque<JobId>,
+}
+
+/// The output of the most recent builds, which clients can read and follow.
+#[derive(Debug, Clone)]
+pub struct BuildLogs {
+    logs: Arc<Mutex<Logs>>,
+    capacity: usize,
+    redactor: Redactor,
+}
+
+impl BuildLogs {
+    /// Keeps the output of the most recent `capacity` builds.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            logs: Default::default(),
+            capacity: capacity.max(1),
+            redactor: Redactor::default(),
+        }
+    }
+
+    /// Redacts secrets from the output before it is kept or sent to followers.
+    pub fn with_redactor(mut self, redactor: Redactor) -> Self {
+        self.redactor = redactor;
+        self
+    }
+
+    /// Starts recording the output of an attempt of `job`, after the output of its previous attempts.
+    fn start(&self, job: JobId) {
+        let mut logs = self.logs.lock().unwrap();
+        if !logs.by_job.contains_key(&job) {
+            logs.order.push_back(job);
+            while logs.order.len() > self.capacity {
+                if let Some(oldest) = logs.order.pop_front() {
+                    logs.by_job.remove(&oldest);
+                }
+            }
+        }
+        let log = logs.by_job.entry(job).or_insert_with(|| Log {
+            records: VecDeque::new(),
+            live: None,
+        });
+        log.live.get_or_insert_with(|| FanOut::new(FOLLOW_CAPACITY));
+    }
+
+    fn push(&self, job: JobId, mut record: LogRecord) {
+        if let Cow::Owned(line) = self.redactor.redact(record.line.as_bytes()) {
+            record.line = String::from_utf8_lossy(&line).into_owned();
+        }
+        let mut logs = self.logs.lock().unwrap();
+        let Some(log) = logs.by_job.get_mut(&job) else {
+            return;
+        };
+        if let (Some(live), Ok(mut line)) = (&log.live, serde_json::to_vec(&record)) {
+            line.push(b'\n');
+            live.write(&line);
+        }
+        if log.records.len() == MAX_LINES {
+            log.records.pop_front();
+        }
+        log.records.push_back(record);
+    }
+
+    /// Stops following the output of `job`, which ends the streams of its followers.
+    fn finish(&self, job: JobId) {
+        if let Some(live) = self
+            .logs
+            .lock()
+            .unwrap()
+            .by_job
+            .get_mut(&job)
+            .and_then(|v| v.live.take())
+        {
+            live.close();
+        }
+    }
+
+    /// Gets the output of `job` so far, and the lines that follow it until the output ends if it hasn't ended yet.
+    pub fn read(&self, job: JobId) -> (Vec<LogRecord>, Option<Follower>) {
+        let logs = self.logs.lock().unwrap();
+        match logs.by_job.get(&job) {
+            Some(log) => (
+                log.records.iter().cloned().collect(),
+                log.live.as_ref().map(|live| Follower {
+                    // The lines that were written so far are among the records.
+                    subscriber: live.subscribe_at(live.position()),
+                    pending: Vec::new(),
+                    ready: VecDeque::new(),
+                }),
+            ),
+            None => (Vec::new(), None),
+        }
+    }
+}
+
+/// What a client that follows a build receives next.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Followed {
+    Record(LogRecord),
+    /// The client fell behind, and missed this many bytes of the stream, which may have cut off a line.
+    Lagged {
+        missed: u64,
+    },
+}
+
+/// Follows the output of a build, without holding on to more of it than one read.
+#[derive(Debug)]
+pub struct Follower {
+    subscriber: Subscriber,
+    /// The start of a line whose end hasn't been read yet.
+    pending: Vec<u8>,
+    ready: VecDeque<LogRecord>,
+}
+
+impl Follower {
+    /// Waits for the next line of output. Returns `None` once the output has ended.
+    pub async fn next(&mut self) -> Option<Followed> {
+        loop {
+            if let Some(record) = self.ready.pop_front() {
+                return Some(Followed::Record(record));
+            }
+            match self.subscriber.next(READ_SIZE).await? {
+                Chunk::Lagged { from, to } => {
+                    // The lines that were read before are complete, but whatever follows is cut off.
+                    self.pending.clear();
+                    return Some(Followed::Lagged { missed: to - from });
+                }
+                Chunk::Data(data) => self.parse(&data),
+            }
+        }
+    }
+
+    fn parse(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+        let Some(end) = self.pending.iter().rposition(|v| *v == b'\n') else {
+            return;
+        };
+        let rest = self.pending.split_off(end + 1);
+        for line in std::mem::replace(&mut self.pending, rest).split(|v| *v == b'\n') {
+            match serde_json::from_slice(line) {
+                Ok(record) => self.ready.push_back(record),
+                // The end of a line that was cut off by falling behind.
+                Err(_) if !line.is_empty() => tracing::debug!("skipped a partial log line"),
+                Err(_) => {}
+            }
+        }
+    }
+}
+
+/// Logs the output of the build of `name` line by line until the task exits, and records it in `logs`.
+pub async fn log(job: JobId, name: String, output: OutputStream, logs: BuildLogs) {
+    let (sender, receiver) = flume::unbounded();
+    tokio::spawn(forward(output.stdout, LogStream::Stdout, sender.clone()));
+    tokio::spawn(forward(output.stderr, LogStream::Stderr, sender));
+
+    logs.start(job);
+    let log = |records: Vec<LogRecord>| {
+        for record in records {
+            let plain = record.clone().plain();
+            tracing::info!(%job, name, stream = ?plain.stream, "{}", plain.line);
+            logs.push(job, record);
+        }
+    };
+    let mut lines = LogLines::new();
+    while let Ok((stream, data, time)) = receiver.recv_async().await {
+        log(lines.push(stream, &data, time));
+    }
+    log(lines.finish(SystemTime::now()));
+    logs.finish(job);
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::Config;
+
+    use super::*;
+
+    fn record(line: &str) -> LogRecord {
+        LogRecord {
+            time: 0,
+            stream: LogStream::Stdout,
+            line: line.to_string(),
+            partial: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn follow_logs() {
+        let logs = BuildLogs::new(1);
+        logs.start(JobId(1));
+        logs.push(JobId(1), record("first"));
+
+        let (records, receiver) = logs.read(JobId(1));
+        assert_eq!(records, [record("first")]);
+        let mut receiver = receiver.unwrap();
+        logs.push(JobId(1), record("second"));
+        assert_eq!(
+            receiver.next().await,
+            Some(Followed::Record(record("second")))
+        );
+
+        logs.finish(JobId(1));
+        assert_eq!(receiver.next().await, None);
+        let (records, receiver) = logs.read(JobId(1));
+        assert_eq!(records.len(), 2);
+        assert!(receiver.is_none());
+
+        // Only the output of the most recent build is kept.
+        logs.start(JobId(2));
+        assert!(logs.read(JobId(1)).0.is_empty());
+    }
+
+    #[tokio::test]
+    async fn redact_logs() {
+        let mut config = Config::default();
+        config
+            .log
+            .redact
+            .insert("github".to_string(), "ghp_[A-Za-z0-9]{8}".to_string());
+        let logs = BuildLogs::new(1).with_redactor(Redactor::new(&config).unwrap());
+        logs.start(JobId(1));
+        let (_, receiver) = logs.read(JobId(1));
+        let mut receiver = receiver.unwrap();
+        logs.push(JobId(1), record("cloning with ghp_abcd1234"));
+
+        let redacted = record("cloning with [REDACTED]");
+        assert_eq!(
+            receiver.next().await,
+            Some(Followed::Record(redacted.clone()))
+        );
+        assert_eq!(logs.read(JobId(1)).0, [redacted]);
+    }
+
+    #[tokio::test]
+    async fn follow_behind() {
+        let logs = BuildLogs::new(1);
+        logs.start(JobId(1));
+        let (_, receiver) = logs.read(JobId(1));
+        let mut receiver = receiver.unwrap();
+
+        let long = "x".repeat(FOLLOW_CAPACITY / 4);
+        for _ in 0..5 {
+            logs.push(JobId(1), record(&long));
+        }
+        logs.push(JobId(1), record("last"));
+        logs.finish(JobId(1));
+
+        // The client is told that it fell behind, and receives the complete lines that are still buffered.
+        let Some(Followed::Lagged { missed }) = receiver.next().await else {
+            panic!("the client should have fallen behind");
+        };
+        assert!(missed > 0);
+        let mut received = Vec::new();
+        while let Some(followed) = receiver.next().await {
+            let Followed::Record(record) = followed else {
+                panic!("the client should only fall behind once");
+            };
+            received.push(record.line);
+        }
+        assert_eq!(received.len(), 4);
+        assert_eq!(received.last().unwrap(), "last");
+    }
+}
@@ -0,0 +1,102 @@
+//! Running the executables of packages in a sandbox that only contains their closure.
+//!
+//! The sandbox receives the standard streams of the command as its first three file descriptors, and a fourth that it
+//! writes the exit code of the command to (as a big-endian `i32`) once the command has finished.
+
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::Write as _,
+    os::{fd::OwnedFd, unix::process::ExitStatusExt as _},
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use porkg_private::sandbox::{SandboxOptions, SandboxTask};
+
+use crate::{store::StoreMount, Erro};
+
+/// The exit code that is reported when the command couldn't be started, as in shells.
+pub const NOT_FOUND: i32 = 127;
+
+/// Where the tree of a profile generation is bound within the sandbox, for activation commands.
+pub const PROFILE_MOUNT: &str = "/profile";
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct RunTask {
+    /// The program and its arguments.
+    pub exec: Vec<String>,
+    pub env: BTreeMap<String, String>,
+    /// The empty directory of the host that the root file system of the sandbox is mounted on.
+    pub root: PathBuf,
+    /// The store directories of the closure of the package, which are bound read-only at their paths within the
+    /// sandbox.
+    pub closure: Vec<PathBuf>,
+    pub store: StoreMount,
+    /// The tree of a profile generation, which is bound read-only at [`PROFILE_MOUNT`] within the sandbox.
+    pub profile: Option<PathBuf>,
+    /// Paths of the host that are bound read-only at the same paths within the sandbox.
+    pub binds: Vec<PathBuf>,
+    /// Whether the command is cut off from the network.
+    pub isolate_network: bool,
+}
+
+impl SandboxTask for RunTask {
+    type ExecuteError = Erro;
+
+    fn create_sandbox_options(&self) -> SandboxOptions {
+        let mut options = SandboxOptions::default();
+        options.with_root(&self.root);
+        for path in &self.closure {
+            options.with_bind(path, self.store.logical(path), true);
+        }
+        if let Some(profile) = &self.profile {
+            options.with_bind(profile, PROFILE_MOUNT, true);
+        }
+        for path in &self.binds {
+            options.with_bind(path, path, true);
+        }
+        options.with_network_isolation(self.isolate_network);
+        options
+    }
+
+    fn execute(&self, fds: impl AsRef<[OwnedFd]>) -> Result<(), Self::ExecuteError> {
+        let [stdin, stdout, stderr, status] = fds.as_ref() else {
+            tracing::error!(count = fds.as_ref().len(), "expected four file descriptors");
+            return Err(Erro);
+        };
+        let clone = |fd: &OwnedFd| {
+            fd.try_clone()
+                .inspect_err(|error| tracing::error!(?error, "failed to clone a file descriptor"))
+                .map_err(|_| Erro)
+        };
+
+        let (program, args) = self.exec.split_first().ok_or(Erro)?;
+        let result = Command::new(program)
+            .args(args)
+            .env_clear()
+            .envs(&self.env)
+            .current_dir("/tmp")
+            .stdin(Stdio::from(clone(stdin)?))
+            .stdout(Stdio::from(clone(stdout)?))
+            .stderr(Stdio::from(clone(stderr)?))
+            .status();
+        let code = match result {
+            Ok(status) => status
+                .code()
+                .unwrap_or_else(|| 128 + status.signal().unwrap_or_default()),
+            Err(error) => {
+                writeln!(
+                    File::from(clone(stderr)?),
+                    "failed to run {program}: {error}"
+                )
+                .ok();
+                NOT_FOUND
+            }
+        };
+        File::from(clone(status)?)
+            .write_all(&code.to_be_bytes())
+            .inspect_err(|error| tracing::error!(?error, "failed to report the exit code"))
+            .map_err(|_| Erro)
+    }
+}
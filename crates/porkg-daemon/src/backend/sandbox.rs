@@ -0,0 +1,121 @@
+//! The sandboxes that tasks run in, which are either the namespaces of the zygote or the containers of an OCI runtime
+//! (see [`crate::config::SandboxConfig`]).
+
+use std::{os::fd::RawFd, time::Duration};
+
+use porkg_linux::sandbox::{
+    oci::OciController, CreateSandboxError, SandboxController, SandboxTaskError, SandboxTaskHandle,
+    StartControllerProcessError, ZygoteStatus,
+};
+use porkg_private::{backoff::Backoff, sandbox::SandboxBackend};
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use super::Task;
+use crate::events::{Event, EventBus};
+
+/// The delay before the zygote is first restarted, which doubles while it keeps exiting.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+/// How long a restarted zygote must stay up before it is restarted without delay again.
+const STABLE_AFTER: Duration = Duration::from_secs(300);
+
+#[derive(Debug, Clone)]
+pub enum Sandbox {
+    Namespaces(SandboxController<Task>),
+    Oci(OciController<Task>),
+}
+
+/// The state of the sandboxes, as reported to operators.
+#[derive(Debug, Clone, serde::Serialize)]
+#[serde(rename_all = "kebab-case", tag = "backend")]
+pub enum SandboxStatus {
+    Namespaces {
+        #[serde(flatten)]
+        zygote: ZygoteStatus,
+    },
+    Oci {
+        tasks: usize,
+    },
+}
+
+impl Sandbox {
+    pub async fn status(&self) -> SandboxStatus {
+        match self {
+            Sandbox::Namespaces(v) => SandboxStatus::Namespaces {
+                zygote: v.status().await,
+            },
+            Sandbox::Oci(v) => SandboxStatus::Oci { tasks: v.tasks() },
+        }
+    }
+}
+
+impl SandboxBackend<Task> for Sandbox {
+    type Handle = SandboxTaskHandle;
+    type TaskError = SandboxTaskError;
+    type Error = CreateSandboxError;
+
+    async fn spawn(&self, task: Task, fds: &[RawFd]) -> Result<Self::Handle, Self::Error> {
+        match self {
+            Sandbox::Namespaces(v) => v.spawn_async(task, fds).await,
+            Sandbox::Oci(v) => v.spawn_async(task, fds).await,
+        }
+    }
+
+    async fn kill(&self, id: u64) -> Result<(), Self::Error> {
+        match self {
+            Sandbox::Namespaces(v) => v.kill(id).await,
+            Sandbox::Oci(v) => v.kill(id).await,
+        }
+    }
+
+    fn id(handle: &Self::Handle) -> u64 {
+        handle.id()
+    }
+}
+
+/// Restarts the zygote whenever it exits, so that builds can run again. The builds that were running in it fail.
+pub async fn supervise(
+    sandbox: Sandbox,
+    events: EventBus,
+    cancellation_token: CancellationToken,
+) -> anyhow::Result<()> {
+    // Containers are started by the OCI runtime, which has nothing to restart.
+    let Sandbox::Namespaces(controller) = sandbox else {
+        return Ok(());
+    };
+    let backoff = Backoff::new(RESTART_DELAY)
+        .with_max(MAX_RESTART_DELAY)
+        .with_jitter(0.5);
+    let mut retry = backoff.start();
+    let mut started = Instant::now();
+    loop {
+        let reason = tokio::select! {
+            _ = cancellation_token.cancelled() => return Ok(()),
+            reason = controller.exited() => reason,
+        };
+        tracing::error!(reason, "the sandbox zygote exited, restarting it");
+        events.publish(Event::ZygoteExited { reason });
+        if started.elapsed() >= STABLE_AFTER {
+            retry = backoff.start();
+        }
+
+        loop {
+            tokio::select! {
+                _ = cancellation_token.cancelled() => return Ok(()),
+                _ = retry.wait() => {}
+            }
+            match controller.restart().await {
+                Ok(()) => break,
+                // The daemon was upgraded, and must be restarted itself.
+                Err(error @ StartControllerProcessError::Incompatible { .. }) => {
+                    return Err(error.into())
+                }
+                Err(error) => {
+                    tracing::error!(?error, "failed to restart the sandbox zygote");
+                }
+            }
+        }
+        started = Instant::now();
+    }
+}
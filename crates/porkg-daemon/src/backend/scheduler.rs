@@ -0,0 +1,153 @@
+//! Limits how many builds run at once, and for how long builds wait and run. Builds don't start while the daemon is
+//! degraded (see [`crate::degraded`]).
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use thiserror::Error;
+use tokio::sync::Semaphore;
+
+use crate::{
+    blocking::Occupancy,
+    clock::{Clock, Time},
+    config::SchedulerConfig,
+    degraded::Degraded,
+};
+
+#[derive(Debug, Error)]
+pub enum ScheduleError {
+    #[error("the build did not start within {0:?}")]
+    QueueTimeout(Duration),
+    #[error("the build did not finish within {0:?}")]
+    DeadlineExceeded(Duration),
+}
+
+/// The timeouts of a job, which are bounded by the configured maxima.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub queue_timeout: Duration,
+    pub max_runtime: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    slots: Arc<Semaphore>,
+    max_jobs: usize,
+    clock: Arc<dyn Clock>,
+    max_queue_timeout: Duration,
+    max_runtime: Duration,
+    degraded: Degraded,
+}
+
+impl Scheduler {
+    pub fn new(config: &SchedulerConfig, time: &Time) -> Self {
+        Self {
+            slots: Arc::new(Semaphore::new(config.max_jobs.max(1))),
+            max_jobs: config.max_jobs.max(1),
+            clock: time.clock.clone(),
+            max_queue_timeout: Duration::from_secs(config.max_queue_timeout),
+            max_runtime: Duration::from_secs(config.max_runtime),
+            degraded: Degraded::default(),
+        }
+    }
+
+    /// Holds builds in the queue while `degraded` is.
+    pub fn with_degraded(mut self, degraded: Degraded) -> Self {
+        self.degraded = degraded;
+        self
+    }
+
+    /// How many builds are running.
+    pub fn occupancy(&self) -> Occupancy {
+        Occupancy::of(&self.slots, self.max_jobs)
+    }
+
+    /// Gets the limits of a job that requested the given timeouts, in seconds. Missing or excessive timeouts are
+    /// replaced by the maxima.
+    pub fn limits(&self, queue_timeout: Option<u64>, max_runtime: Option<u64>) -> Limits {
+        let bound = |requested: Option<u64>, max: Duration| {
+            requested.map_or(max, |v| Duration::from_secs(v).min(max))
+        };
+        Limits {
+            queue_timeout: bound(queue_timeout, self.max_queue_timeout),
+            max_runtime: bound(max_runtime, self.max_runtime),
+        }
+    }
+
+    /// Runs `job` once a slot is free and the daemon isn't degraded, failing if that doesn't happen within the queue
+    /// timeout or if the job doesn't finish within its maximum runtime.
+    pub async fn run<F: Future>(&self, limits: Limits, job: F) -> Result<F::Output, ScheduleError> {
+        let queued = self.clock.now();
+        let slot = async {
+            self.degraded.healthy().await;
+            self.slots.acquire().await
+        };
+        let _permit = tokio::time::timeout(limits.queue_timeout, slot)
+            .await
+            .map_err(|_| ScheduleError::QueueTimeout(limits.queue_timeout))?
+            .expect("the semaphore is never closed");
+        tracing::debug!(waited = ?(self.clock.now() - queued), "job got a slot");
+
+        tokio::time::timeout(limits.max_runtime, job)
+            .await
+            .map_err(|_| ScheduleError::DeadlineExceeded(limits.max_runtime))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::DiskFault;
+
+    #[tokio::test(start_paused = true)]
+    async fn schedule_jobs() {
+        let scheduler = Scheduler::new(
+            &SchedulerConfig {
+                max_jobs: 1,
+                max_queue_timeout: 60,
+                max_runtime: 600,
+                ..Default::default()
+            },
+            &Time::default(),
+        );
+
+        let limits = scheduler.limits(Some(3600), Some(10));
+        assert_eq!(limits.queue_timeout, Duration::from_secs(60));
+        assert_eq!(limits.max_runtime, Duration::from_secs(10));
+
+        let result = scheduler
+            .run(limits, tokio::time::sleep(Duration::from_secs(20)))
+            .await;
+        assert!(matches!(result, Err(ScheduleError::DeadlineExceeded(_))));
+
+        // The only slot is taken, so the second job times out in the queue.
+        let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
+        let running = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.run(scheduler.limits(None, None), receiver).await }
+        });
+        tokio::task::yield_now().await;
+        let queued = scheduler
+            .run(scheduler.limits(Some(5), None), async {})
+            .await;
+        assert!(matches!(queued, Err(ScheduleError::QueueTimeout(_))));
+
+        sender.send(()).unwrap();
+        assert!(running.await.unwrap().is_ok());
+        assert!(scheduler.run(limits, async {}).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn hold_builds_while_degraded() {
+        let degraded = Degraded::default();
+        let scheduler = Scheduler::new(&SchedulerConfig::default(), &Time::default())
+            .with_degraded(degraded.clone());
+        let limits = scheduler.limits(Some(5), None);
+
+        degraded.enter(DiskFault::Full, "No space left on device".to_string());
+        let queued = scheduler.run(limits, async {}).await;
+        assert!(matches!(queued, Err(ScheduleError::QueueTimeout(_))));
+
+        degraded.leave();
+        assert!(scheduler.run(limits, async {}).await.is_ok());
+    }
+}
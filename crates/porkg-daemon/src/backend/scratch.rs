@@ -0,0 +1,215 @@
+//! The scratch directories of builds, which builds work in, and which clients can read while the build runs, such as
+//! to inspect the `config.log` of a long configure step.
+//!
+//! The build controls what is in its scratch directory, so paths within it are opened one component at a time without
+//! following symlinks, and a build can't make the daemon read anything outside of it.
+
+use std::{
+    fs::{self, File},
+    io,
+    os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd},
+    path::{Component, Path, PathBuf},
+};
+
+use nix::{fcntl::OFlag, sys::stat::Mode};
+use thiserror::Error;
+
+use crate::store::Store;
+
+/// The most entries that are listed of a scratch directory.
+const MAX_ENTRIES: usize = 10_000;
+
+#[derive(Debug, Error)]
+pub enum ScratchError {
+    #[error("invalid path {0:?}")]
+    InvalidPath(PathBuf),
+    #[error("{0:?} isn't a file in the scratch directory")]
+    NotFound(PathBuf),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// A file or directory in a scratch directory.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ScratchEntry {
+    /// The path relative to the scratch directory.
+    pub path: PathBuf,
+    pub directory: bool,
+    /// The size of a file, in bytes.
+    pub size: u64,
+}
+
+/// The scratch directory of an attempt of a build, which is removed when it is dropped.
+#[derive(Debug)]
+pub struct ScratchDir {
+    path: PathBuf,
+}
+
+impl ScratchDir {
+    /// Creates an empty scratch directory within the store.
+    pub async fn new(store: &Store) -> io::Result<Self> {
+        let path = store.temp_path("build");
+        tokio::fs::create_dir_all(&path).await?;
+        Ok(Self { path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        let path = std::mem::take(&mut self.path);
+        tokio::task::spawn_blocking(move || {
+            if let Err(error) = fs::remove_dir_all(&path) {
+                tracing::warn!(
+                    ?error,
+                    ?path,
+                    "failed to remove the scratch directory of a build"
+                );
+            }
+        });
+    }
+}
+
+/// Lists the files and directories in `dir`, without following symlinks, which aren't listed.
+///
+/// This performs blocking IO.
+pub fn list(dir: &Path) -> io::Result<Vec<ScratchEntry>> {
+    let mut result = Vec::new();
+    let mut pending = vec![PathBuf::new()];
+    while let Some(relative) = pending.pop() {
+        for entry in fs::read_dir(dir.join(&relative))? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            let path = relative.join(entry.file_name());
+            if file_type.is_dir() {
+                pending.push(path.clone());
+            } else if !file_type.is_file() {
+                continue;
+            }
+            if result.len() == MAX_ENTRIES {
+                tracing::debug!(
+                    ?dir,
+                    "listing only the first entries of a scratch directory"
+                );
+                return Ok(result);
+            }
+            result.push(ScratchEntry {
+                path,
+                directory: file_type.is_dir(),
+                size: if file_type.is_file() {
+                    entry.metadata()?.len()
+                } else {
+                    0
+                },
+            });
+        }
+    }
+    result.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(result)
+}
+
+fn openat(dir: &OwnedFd, name: &Path, flags: OFlag) -> nix::Result<OwnedFd> {
+    let flags = flags | OFlag::O_RDONLY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+    let fd = nix::fcntl::openat(Some(dir.as_raw_fd()), name, flags, Mode::empty())?;
+    // Safety: the descriptor was just opened, and nothing else owns it.
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Opens a file in `dir` for reading.
+///
+/// This performs blocking IO.
+pub fn open(dir: &Path, path: &Path) -> Result<File, ScratchError> {
+    let mut names = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(v) => names.push(Path::new(v)),
+            Component::RootDir | Component::CurDir => {}
+            Component::ParentDir | Component::Prefix(_) => {
+                return Err(ScratchError::InvalidPath(path.to_path_buf()))
+            }
+        }
+    }
+    let Some((name, parents)) = names.split_last() else {
+        return Err(ScratchError::InvalidPath(path.to_path_buf()));
+    };
+
+    let not_found = |error: nix::Error| match error {
+        // A symlink fails with `ELOOP`, or with `ENOTDIR` if it isn't the last component.
+        nix::Error::ENOENT | nix::Error::ELOOP | nix::Error::ENOTDIR => {
+            ScratchError::NotFound(path.to_path_buf())
+        }
+        error => io::Error::from(error).into(),
+    };
+    let mut parent: OwnedFd = File::open(dir)?.into();
+    for name in parents {
+        parent = openat(&parent, name, OFlag::O_DIRECTORY).map_err(not_found)?;
+    }
+    let file = File::from(openat(&parent, name, OFlag::empty()).map_err(not_found)?);
+    if !file.metadata()?.is_file() {
+        return Err(ScratchError::NotFound(path.to_path_buf()));
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read as _;
+
+    use crate::store::testing::TestStore;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn read_scratch_files() {
+        let store = TestStore::new("scratch");
+        let scratch = ScratchDir::new(&store).await.unwrap();
+        let dir = scratch.path();
+        fs::create_dir_all(dir.join("build")).unwrap();
+        fs::write(dir.join("build/config.log"), "checking for cc... yes").unwrap();
+        std::os::unix::fs::symlink("/etc", dir.join("etc")).unwrap();
+        std::os::unix::fs::symlink("/etc/hostname", dir.join("build/hostname")).unwrap();
+
+        assert_eq!(
+            list(dir).unwrap(),
+            [
+                ScratchEntry {
+                    path: "build".into(),
+                    directory: true,
+                    size: 0,
+                },
+                ScratchEntry {
+                    path: "build/config.log".into(),
+                    directory: false,
+                    size: 22,
+                },
+            ]
+        );
+
+        let mut contents = String::new();
+        open(dir, Path::new("/build/config.log"))
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "checking for cc... yes");
+
+        // Nothing outside of the scratch directory can be read.
+        for path in ["etc/hostname", "build/hostname", "build", "missing"] {
+            assert!(matches!(
+                open(dir, Path::new(path)),
+                Err(ScratchError::NotFound(_))
+            ));
+        }
+        assert!(matches!(
+            open(dir, Path::new("build/../../etc/hostname")),
+            Err(ScratchError::InvalidPath(_))
+        ));
+
+        let path = dir.to_path_buf();
+        drop(scratch);
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(!path.exists());
+    }
+}
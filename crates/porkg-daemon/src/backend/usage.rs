@@ -0,0 +1,229 @@
+//! Accounting of what tenants consume, so that their quotas (see [`QuotaConfig`](crate::config::QuotaConfig)) can be
+//! enforced.
+//!
+//! The disk space of a tenant is that of the packages it owns, which is measured from the store when it is needed. The
+//! time that builds run for isn't recorded anywhere else, so each build appends it to a journal in the store, which is
+//! replayed when the daemon starts.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use porkg_private::journal::Journal;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    config::TenantQuota,
+    store::{
+        gc::{self, GcError},
+        Store,
+    },
+};
+
+/// The number of records that the journal may have beyond one per tenant before it is compacted.
+const COMPACT_AFTER: usize = 1024;
+
+#[derive(Debug, Error)]
+pub enum QuotaError {
+    #[error("{tenant} has used {used} of its {limit} bytes of store space")]
+    StoreBytes {
+        tenant: String,
+        used: u64,
+        limit: u64,
+    },
+    #[error("{tenant} has used {used} of its {limit} build minutes")]
+    BuildMinutes {
+        tenant: String,
+        used: u64,
+        limit: u64,
+    },
+}
+
+/// What a tenant has consumed.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TenantUsage {
+    /// The disk space that the packages owned by the tenant use, in bytes.
+    pub store_bytes: u64,
+    /// The time that builds of the tenant ran for, in seconds.
+    pub build_seconds: u64,
+}
+
+impl TenantUsage {
+    /// Fails if the tenant has reached any of its quotas.
+    pub fn check(&self, tenant: &str, quota: &TenantQuota) -> Result<(), QuotaError> {
+        if let Some(limit) = quota.store_bytes.filter(|v| self.store_bytes >= *v) {
+            return Err(QuotaError::StoreBytes {
+                tenant: tenant.to_string(),
+                used: self.store_bytes,
+                limit,
+            });
+        }
+        if let Some(limit) = quota
+            .build_minutes
+            .filter(|v| self.build_seconds >= v.saturating_mul(60))
+        {
+            return Err(QuotaError::BuildMinutes {
+                tenant: tenant.to_string(),
+                used: self.build_seconds / 60,
+                limit,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Record {
+    tenant: String,
+    millis: u64,
+}
+
+#[derive(Debug)]
+struct State {
+    journal: Journal,
+    /// The time that the builds of each tenant ran for, in milliseconds.
+    build_millis: BTreeMap<String, u64>,
+}
+
+/// The consumption of every tenant.
+#[derive(Debug, Clone)]
+pub struct Usage {
+    state: Arc<Mutex<State>>,
+}
+
+impl Usage {
+    /// Opens the journal of build time at `path`.
+    ///
+    /// This performs blocking IO.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let (journal, records) = Journal::open(path)?;
+        let mut build_millis = BTreeMap::<_, u64>::new();
+        for record in records {
+            match serde_json::from_slice::<Record>(&record) {
+                Ok(record) => {
+                    let total = build_millis.entry(record.tenant).or_default();
+                    *total = total.saturating_add(record.millis);
+                }
+                Err(error) => tracing::warn!(?path, ?error, "ignoring an invalid usage record"),
+            }
+        }
+        Ok(Self {
+            state: Arc::new(Mutex::new(State {
+                journal,
+                build_millis,
+            })),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+
+    /// Adds to the time that the builds of `tenant` ran for.
+    ///
+    /// This performs blocking IO.
+    pub fn record_build(&self, tenant: &str, duration: Duration) -> io::Result<()> {
+        let record = Record {
+            tenant: tenant.to_string(),
+            millis: duration.as_millis().try_into().unwrap_or(u64::MAX),
+        };
+        let mut state = self.lock();
+        state.journal.append(&serde_json::to_vec(&record)?)?;
+        let total = state.build_millis.entry(record.tenant).or_default();
+        *total = total.saturating_add(record.millis);
+
+        if state.journal.len() > state.build_millis.len() + COMPACT_AFTER {
+            let State {
+                journal,
+                build_millis,
+            } = &mut *state;
+            let records = build_millis
+                .iter()
+                .map(|(tenant, millis)| {
+                    serde_json::to_vec(&Record {
+                        tenant: tenant.clone(),
+                        millis: *millis,
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            journal.compact(records.iter().map(Vec::as_slice))?;
+        }
+        Ok(())
+    }
+
+    /// Gets what each tenant that owns packages or ran builds has consumed.
+    ///
+    /// This performs blocking IO.
+    pub fn report(&self, store: &Store) -> Result<BTreeMap<String, TenantUsage>, GcError> {
+        let mut result = BTreeMap::<_, TenantUsage>::new();
+        for (tenant, millis) in self.lock().build_millis.iter() {
+            result.entry(tenant.clone()).or_default().build_seconds = millis / 1000;
+        }
+        for (tenant, bytes) in gc::tenant_usage(store)? {
+            result.entry(tenant).or_default().store_bytes = bytes;
+        }
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::store::testing::TestStore;
+
+    use super::*;
+
+    #[test]
+    fn account_build_time() {
+        let store = TestStore::new("usage");
+        let path = store.root().join("usage");
+
+        let usage = Usage::open(&path).unwrap();
+        usage.record_build("team", Duration::from_secs(90)).unwrap();
+        usage
+            .record_build("team", Duration::from_millis(30_500))
+            .unwrap();
+        usage.record_build("other", Duration::from_secs(1)).unwrap();
+        drop(usage);
+
+        // The journal is replayed, and compacted once it grows.
+        let usage = Usage::open(&path).unwrap();
+        for _ in 0..COMPACT_AFTER {
+            usage.record_build("other", Duration::ZERO).unwrap();
+        }
+        assert!(usage.lock().journal.len() <= 2 + COMPACT_AFTER);
+        let report = usage.report(&store).unwrap();
+        assert_eq!(report["team"].build_seconds, 120);
+        assert_eq!(report["other"].build_seconds, 1);
+
+        let quota = TenantQuota {
+            store_bytes: Some(100),
+            build_minutes: Some(2),
+        };
+        assert!(matches!(
+            report["team"].check("team", &quota),
+            Err(QuotaError::BuildMinutes {
+                used: 2,
+                limit: 2,
+                ..
+            })
+        ));
+        report["other"].check("other", &quota).unwrap();
+        let full = TenantUsage {
+            store_bytes: 100,
+            build_seconds: 0,
+        };
+        assert!(matches!(
+            full.check("other", &quota),
+            Err(QuotaError::StoreBytes { used: 100, .. })
+        ));
+        assert!(full.check("other", &TenantQuota::default()).is_ok());
+    }
+}
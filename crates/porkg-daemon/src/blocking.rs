@@ -0,0 +1,115 @@
+//! Runs blocking filesystem work, such as importing, hashing and exporting packages, off the async runtime.
+//!
+//! Tokio's blocking threads are also used by `tokio::fs`, so the number of operations that run at once is limited
+//! to leave threads free for the HTTP server. Operations that are waiting for a slot don't hold a thread.
+
+use std::{sync::Arc, time::Instant};
+
+use tokio::{sync::Semaphore, task::JoinError};
+
+/// How many of the slots of a pool are taken, as reported to operators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct Occupancy {
+    pub size: usize,
+    pub in_use: usize,
+}
+
+impl Occupancy {
+    pub fn of(slots: &Semaphore, size: usize) -> Self {
+        Self {
+            size,
+            in_use: size.saturating_sub(slots.available_permits()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockingPool {
+    slots: Arc<Semaphore>,
+    size: usize,
+}
+
+impl BlockingPool {
+    pub fn new(size: usize) -> Self {
+        let size = size.max(1);
+        Self {
+            slots: Arc::new(Semaphore::new(size)),
+            size,
+        }
+    }
+
+    pub fn occupancy(&self) -> Occupancy {
+        Occupancy::of(&self.slots, self.size)
+    }
+
+    /// Runs `f` on a blocking thread, within the current span, once a slot is free. The slot is held until `f`
+    /// returns, even if the returned future is dropped.
+    pub async fn run<R: Send + 'static>(
+        &self,
+        operation: &'static str,
+        f: impl FnOnce() -> R + Send + 'static,
+    ) -> Result<R, JoinError> {
+        let queued = Instant::now();
+        let permit = self
+            .slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let waited = queued.elapsed();
+
+        let span = tracing::Span::current();
+        tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            span.in_scope(|| {
+                let started = Instant::now();
+                let result = f();
+                tracing::debug!(
+                    operation,
+                    ?waited,
+                    elapsed = ?started.elapsed(),
+                    "blocking operation finished"
+                );
+                result
+            })
+        })
+        .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn limit_operations() {
+        let pool = BlockingPool::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+
+        let tasks = (0..8)
+            .map(|_| {
+                let pool = pool.clone();
+                let running = running.clone();
+                let peak = peak.clone();
+                tokio::spawn(async move {
+                    pool.run("test", move || {
+                        let current = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        peak.fetch_max(current, Ordering::SeqCst);
+                        std::thread::sleep(std::time::Duration::from_millis(10));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    })
+                    .await
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+        assert_eq!(pool.occupancy(), Occupancy { size: 2, in_use: 0 });
+    }
+}
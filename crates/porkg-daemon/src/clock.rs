@@ -0,0 +1,111 @@
+//! The time and identifiers that the engine observes, which tests replace to make timeouts, retention and the ages of
+//! garbage deterministic.
+//!
+//! Monotonic time is Tokio's, so that it also pauses with the runtime in tests.
+
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use tokio::time::Instant;
+
+pub trait Clock: fmt::Debug + Send + Sync + 'static {
+    /// The monotonic time, which durations are measured with.
+    fn now(&self) -> Instant;
+
+    /// The time of day, which is compared with the times of files.
+    fn system_time(&self) -> SystemTime;
+}
+
+pub trait IdGenerator: fmt::Debug + Send + Sync + 'static {
+    /// Gets an identifier that wasn't returned before.
+    fn next_id(&self) -> u64;
+
+    /// Makes sure that `id` and the identifiers before it aren't returned, such as those that were returned before the
+    /// daemon restarted.
+    fn skip_past(&self, id: u64);
+}
+
+/// The clock of the host.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Identifiers that count up from 1.
+#[derive(Debug)]
+pub struct Sequential(AtomicU64);
+
+impl Default for Sequential {
+    fn default() -> Self {
+        Self(AtomicU64::new(1))
+    }
+}
+
+impl IdGenerator for Sequential {
+    fn next_id(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn skip_past(&self, id: u64) {
+        self.0.fetch_max(id.saturating_add(1), Ordering::Relaxed);
+    }
+}
+
+/// The clock and identifiers of the engine, which are shared by everything that it creates.
+#[derive(Debug, Clone)]
+pub struct Time {
+    pub clock: Arc<dyn Clock>,
+    pub ids: Arc<dyn IdGenerator>,
+}
+
+impl Default for Time {
+    fn default() -> Self {
+        Self {
+            clock: Arc::new(SystemClock),
+            ids: Arc::new(Sequential::default()),
+        }
+    }
+}
+
+/// A clock that only moves when it is advanced.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct ManualClock(std::sync::Mutex<(Instant, SystemTime)>);
+
+#[cfg(test)]
+impl ManualClock {
+    pub fn new(system_time: SystemTime) -> Self {
+        Self(std::sync::Mutex::new((Instant::now(), system_time)))
+    }
+
+    pub fn advance(&self, duration: std::time::Duration) {
+        let mut now = self.0.lock().unwrap();
+        now.0 += duration;
+        now.1 += duration;
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.0.lock().unwrap().0
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.0.lock().unwrap().1
+    }
+}
@@ -1,7 +1,13 @@
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::PathBuf,
+};
 
 use anyhow::Context as _;
-use serde::Deserialize;
+use porkg_linux::sandbox::oci::OciRuntime;
+use porkg_private::sandbox::ResourceLimits;
+use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Default, Deserialize)]
 pub struct Config {
@@ -9,6 +15,30 @@ pub struct Config {
     pub bind: BindConfig,
     #[serde(default)]
     pub store: StoreConfig,
+    #[serde(default)]
+    pub fetch: FetchConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub scheduler: SchedulerConfig,
+    #[serde(default)]
+    pub gc: GcConfig,
+    #[serde(default)]
+    pub sync: SyncConfig,
+    #[serde(default)]
+    pub signing: SigningConfig,
+    #[serde(default)]
+    pub discovery: DiscoveryConfig,
+    #[serde(default)]
+    pub log: LogConfig,
+    #[serde(default)]
+    pub hooks: HooksConfig,
+    #[serde(default)]
+    pub quota: QuotaConfig,
+    #[serde(default)]
+    pub sandbox: SandboxConfig,
+    #[serde(default)]
+    pub audit: AuditConfig,
 }
 
 impl Config {
@@ -30,8 +60,81 @@ impl Config {
 pub struct BindConfig {
     #[serde(default = "default_socket_path", with = "porkg_private::ser::pathbuf")]
     pub socket: PathBuf,
+    /// The permissions of the socket in octal, such as `660`, which are otherwise whatever the umask leaves.
+    #[serde(default, with = "porkg_private::ser::option_string")]
+    pub socket_mode: Option<String>,
+    /// The group that owns the socket, by name or id.
+    #[serde(default, with = "porkg_private::ser::option_string")]
+    pub socket_group: Option<String>,
+    /// Whether the directories of the sockets are created if they are missing.
+    #[serde(default = "default_create_dirs")]
+    pub create_dirs: bool,
+    /// Another socket, which is trusted with administration ([`Operation::Admin`]) while the socket above no longer
+    /// is.
+    #[serde(default, with = "porkg_private::ser::option_pathbuf")]
+    pub admin_socket: Option<PathBuf>,
+    /// The permissions of the admin socket in octal.
+    #[serde(
+        default = "default_admin_socket_mode",
+        with = "porkg_private::ser::string"
+    )]
+    pub admin_socket_mode: String,
+    /// The group that owns the admin socket, by name or id.
+    #[serde(default, with = "porkg_private::ser::option_string")]
+    pub admin_socket_group: Option<String>,
     #[serde(default)]
     pub tcp: Vec<String>,
+    /// The certificate that the TCP sockets are served with. They are served over plaintext without one, while the
+    /// unix sockets always are.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
+    /// The most connections that are served at once, on every socket combined. Further connections wait to be
+    /// accepted.
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    /// How long a client may take to send the headers of an HTTP/1 request, in seconds.
+    #[serde(default = "default_header_timeout")]
+    pub header_timeout: u64,
+    /// The longest time that a connection is served for, in seconds. Connections that follow builds (see
+    /// `/build/:id/logs`) are closed too.
+    #[serde(default)]
+    pub connection_timeout: Option<u64>,
+    /// Whether clients may speak HTTP/2, which they negotiate over TLS, and otherwise speak with prior knowledge.
+    #[serde(default)]
+    pub http2: bool,
+    /// How often idle HTTP/2 connections are pinged, in seconds. They aren't unless this is set.
+    #[serde(default)]
+    pub http2_keepalive_interval: Option<u64>,
+    /// How long an HTTP/2 client may take to answer a ping before its connection is closed, in seconds.
+    #[serde(default = "default_http2_keepalive_timeout")]
+    pub http2_keepalive_timeout: u64,
+    /// The most requests that an HTTP/2 client may make at once on a connection.
+    #[serde(default = "default_http2_max_streams")]
+    pub http2_max_streams: u32,
+}
+
+fn default_create_dirs() -> bool {
+    true
+}
+
+fn default_max_connections() -> usize {
+    1024
+}
+
+fn default_header_timeout() -> u64 {
+    30
+}
+
+fn default_http2_keepalive_timeout() -> u64 {
+    20
+}
+
+fn default_http2_max_streams() -> u32 {
+    200
+}
+
+fn default_admin_socket_mode() -> String {
+    "600".into()
 }
 
 fn default_socket_path() -> PathBuf {
@@ -49,17 +152,598 @@ impl Default for BindConfig {
     fn default() -> Self {
         Self {
             socket: default_socket_path(),
+            socket_mode: None,
+            socket_group: None,
+            create_dirs: default_create_dirs(),
+            admin_socket: None,
+            admin_socket_mode: default_admin_socket_mode(),
+            admin_socket_group: None,
             tcp: Vec::new(),
+            tls: None,
+            max_connections: default_max_connections(),
+            header_timeout: default_header_timeout(),
+            connection_timeout: None,
+            http2: false,
+            http2_keepalive_interval: None,
+            http2_keepalive_timeout: default_http2_keepalive_timeout(),
+            http2_max_streams: default_http2_max_streams(),
         }
     }
 }
 
-#[derive(Debug, Default, Deserialize)]
+/// A certificate and its private key, in PEM files.
+#[derive(Debug, Deserialize)]
+pub struct TlsConfig {
+    /// The certificate chain, leaf first.
+    #[serde(with = "porkg_private::ser::pathbuf")]
+    pub cert: PathBuf,
+    #[serde(with = "porkg_private::ser::pathbuf")]
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Deserialize)]
 pub struct StoreConfig {
     #[serde(default = "default_store_path", with = "porkg_private::ser::pathbuf")]
     pub path: PathBuf,
+    /// Where the store appears within sandboxes, which is the path that packages refer to each other by. It is
+    /// independent of `path`, so that the store can be moved on disk without changing the packages in it.
+    #[serde(default = "default_store_prefix", with = "porkg_private::ser::pathbuf")]
+    pub prefix: PathBuf,
 }
 
 fn default_store_path() -> PathBuf {
     "/var/lib/porkg/store".into()
 }
+
+fn default_store_prefix() -> PathBuf {
+    "/porkg/store".into()
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        Self {
+            path: default_store_path(),
+            prefix: default_store_prefix(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FetchConfig {
+    /// The maximum combined download rate of all fetches, in bytes per second.
+    #[serde(default)]
+    pub bandwidth_limit: Option<u64>,
+    /// The number of times a download is retried before moving on to the next mirror.
+    #[serde(default = "default_fetch_retries")]
+    pub retries: u32,
+    /// Secrets that sources can use to access private hosts, by name.
+    #[serde(default)]
+    pub secrets: BTreeMap<String, SecretConfig>,
+    /// The Nix binary caches that store paths are fetched from, in order of preference.
+    #[serde(default = "default_nix_caches")]
+    pub nix_caches: Vec<String>,
+}
+
+fn default_fetch_retries() -> u32 {
+    3
+}
+
+fn default_nix_caches() -> Vec<String> {
+    vec!["https://cache.nixos.org".to_string()]
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            bandwidth_limit: None,
+            retries: default_fetch_retries(),
+            secrets: BTreeMap::new(),
+            nix_caches: default_nix_caches(),
+        }
+    }
+}
+
+/// Redaction of secrets from logs (see [`crate::redact`]), and crash reports (see [`porkg_private::crash`]).
+#[derive(Debug, Deserialize)]
+pub struct LogConfig {
+    /// Regular expressions, by name, whose matches are replaced before logs are written, such as
+    /// `PORKG__LOG__REDACT__GITHUB=ghp_[A-Za-z0-9]{36}`.
+    #[serde(default, with = "porkg_private::ser::string_map")]
+    pub redact: BTreeMap<String, String>,
+    /// Whether the values of the secrets (see [`FetchConfig::secrets`]) are redacted.
+    #[serde(default = "default_redact_secrets")]
+    pub redact_secrets: bool,
+    /// The directory that the daemon, the zygote and workers write a report to when they panic, in the store if it
+    /// isn't set.
+    #[serde(default, with = "porkg_private::ser::option_pathbuf")]
+    pub crash_dir: Option<PathBuf>,
+}
+
+impl LogConfig {
+    /// The directory that crash reports are written to.
+    pub fn crash_dir(&self, store: &StoreConfig) -> PathBuf {
+        self.crash_dir
+            .clone()
+            .unwrap_or_else(|| store.path.join("crash"))
+    }
+}
+
+fn default_redact_secrets() -> bool {
+    true
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            redact: BTreeMap::new(),
+            redact_secrets: default_redact_secrets(),
+            crash_dir: None,
+        }
+    }
+}
+
+/// How tasks are isolated from the host.
+#[derive(Debug, Default, Deserialize)]
+pub struct SandboxConfig {
+    /// Runs tasks in the containers of this OCI runtime (such as runsc, for gVisor) instead of namespaces.
+    pub runtime: Option<PathBuf>,
+    /// The directory that the bundles of containers are created in, next to the store if it isn't set.
+    pub bundles: Option<PathBuf>,
+}
+
+impl SandboxConfig {
+    /// The OCI runtime that runs tasks, if one is configured.
+    pub fn oci(&self, store: &StoreConfig) -> Option<OciRuntime> {
+        let runtime = self.runtime.clone()?;
+        Some(OciRuntime {
+            runtime,
+            bundles: self
+                .bundles
+                .clone()
+                .unwrap_or_else(|| store.path.with_file_name("bundles")),
+        })
+    }
+}
+
+/// Executables that run before and after each build (see [`crate::backend::hook`]).
+#[derive(Debug, Deserialize)]
+pub struct HooksConfig {
+    /// Hooks that run before a build starts, in order.
+    #[serde(default)]
+    pub pre_build: Vec<PathBuf>,
+    /// Hooks that run after a build finishes, in order.
+    #[serde(default)]
+    pub post_build: Vec<PathBuf>,
+    /// The longest time that a hook, or the activation command of a package (see [`crate::backend::activation`]), may
+    /// run for, in seconds.
+    #[serde(default = "default_hook_timeout")]
+    pub timeout: u64,
+}
+
+fn default_hook_timeout() -> u64 {
+    5 * 60
+}
+
+impl Default for HooksConfig {
+    fn default() -> Self {
+        Self {
+            pre_build: Vec::new(),
+            post_build: Vec::new(),
+            timeout: default_hook_timeout(),
+        }
+    }
+}
+
+/// A secret, and the origins that fetches may send it to.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecretConfig {
+    #[serde(flatten)]
+    pub source: SecretSource,
+    /// The origins (such as `https://git.example.com`) of the urls that a source may use the secret with. Sources can't
+    /// use secrets without any.
+    #[serde(default)]
+    pub origins: Vec<String>,
+}
+
+impl From<SecretSource> for SecretConfig {
+    fn from(source: SecretSource) -> Self {
+        Self {
+            source,
+            origins: Vec::new(),
+        }
+    }
+}
+
+/// Where the value of a secret is read from. The value is read whenever it is used.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SecretSource {
+    /// A file, which would usually be on a tmpfs.
+    File(#[serde(with = "porkg_private::ser::pathbuf")] PathBuf),
+    /// An environment variable of the daemon.
+    Env(#[serde(with = "porkg_private::ser::string")] String),
+    /// A systemd credential, which is found in `$CREDENTIALS_DIRECTORY`.
+    Credential(#[serde(with = "porkg_private::ser::string")] String),
+}
+
+/// Limits on running builds. Jobs can request shorter timeouts, but not longer ones.
+#[derive(Debug, Deserialize)]
+pub struct SchedulerConfig {
+    /// The number of builds that may run at once.
+    #[serde(default = "default_max_jobs")]
+    pub max_jobs: usize,
+    /// The longest time that a build may wait to start, in seconds.
+    #[serde(default = "default_max_queue_timeout")]
+    pub max_queue_timeout: u64,
+    /// The longest time that a build may run for, in seconds.
+    #[serde(default = "default_max_runtime")]
+    pub max_runtime: u64,
+    /// The number of blocking store operations, such as imports and exports, that may run at once.
+    #[serde(default = "default_max_blocking")]
+    pub max_blocking: usize,
+    /// The largest build request that is accepted, in bytes.
+    #[serde(default = "default_max_request_size")]
+    pub max_request_size: u64,
+    /// The number of threads that compress each archive that is published.
+    #[serde(default = "default_max_jobs")]
+    pub compression_workers: usize,
+    /// How long a running build may go without output before it is reported as stuck, in seconds.
+    #[serde(default = "default_stuck_after")]
+    pub stuck_after: u64,
+    /// How long the daemon waits for jobs to finish once it was asked to drain (with SIGTERM), in seconds.
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+    /// The number of finished builds whose outcome is kept, so that clients can poll builds that they submitted.
+    #[serde(default = "default_finished_jobs")]
+    pub finished_jobs: usize,
+    /// The file that jobs are recorded in, so that finished jobs are kept across restarts of the daemon, and jobs that
+    /// were interrupted by a restart are resumed. It is in the store if it isn't set.
+    #[serde(default, with = "porkg_private::ser::option_pathbuf")]
+    pub journal: Option<PathBuf>,
+    /// The most memory that each build may use, in bytes.
+    pub max_memory: Option<u64>,
+    /// The share of CPU time of each build relative to the others, from 1 to 10000.
+    pub cpu_weight: Option<u16>,
+    /// The most processes and threads that each build may run at once.
+    pub max_pids: Option<u64>,
+}
+
+impl SchedulerConfig {
+    /// The limits on the resources of each build.
+    pub fn resource_limits(&self) -> ResourceLimits {
+        ResourceLimits {
+            memory: self.max_memory,
+            cpu_weight: self.cpu_weight,
+            pids: self.max_pids,
+        }
+    }
+
+    /// The file that jobs are recorded in.
+    pub fn journal(&self, store: &StoreConfig) -> PathBuf {
+        self.journal
+            .clone()
+            .unwrap_or_else(|| store.path.join("jobs"))
+    }
+}
+
+fn default_max_jobs() -> usize {
+    std::thread::available_parallelism().map_or(1, |v| v.get())
+}
+
+fn default_max_queue_timeout() -> u64 {
+    60 * 60
+}
+
+fn default_max_runtime() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_stuck_after() -> u64 {
+    2 * 60 * 60
+}
+
+fn default_drain_timeout() -> u64 {
+    10 * 60
+}
+
+fn default_finished_jobs() -> usize {
+    256
+}
+
+fn default_max_blocking() -> usize {
+    default_max_jobs() * 2
+}
+
+fn default_max_request_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_jobs: default_max_jobs(),
+            max_queue_timeout: default_max_queue_timeout(),
+            max_runtime: default_max_runtime(),
+            max_blocking: default_max_blocking(),
+            max_request_size: default_max_request_size(),
+            compression_workers: default_max_jobs(),
+            stuck_after: default_stuck_after(),
+            drain_timeout: default_drain_timeout(),
+            finished_jobs: default_finished_jobs(),
+            journal: None,
+            max_memory: None,
+            cpu_weight: None,
+            max_pids: None,
+        }
+    }
+}
+
+/// Policies that the periodic collector enforces. Packages that are reachable from a root are never collected.
+#[derive(Debug, Default, Deserialize)]
+pub struct GcConfig {
+    /// How often the collector runs, in seconds. The collector doesn't run unless this is set.
+    #[serde(default)]
+    pub interval: Option<u64>,
+    /// The most disk space that packages may use, in bytes.
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// The least free space to leave on the file system of the store, in bytes.
+    #[serde(default)]
+    pub min_free: Option<u64>,
+    /// How long packages that aren't reachable from a root are kept, in seconds.
+    #[serde(default)]
+    pub max_age: Option<u64>,
+    /// The number of generations of each profile that are kept.
+    #[serde(default)]
+    pub keep_generations: Option<usize>,
+}
+
+/// Audits of the packages in the store against a database of vulnerabilities (see [`crate::store::audit`]).
+#[derive(Debug, Default, Deserialize)]
+pub struct AuditConfig {
+    /// The directory of OSV files to audit against. Audits fail without one.
+    #[serde(default, with = "porkg_private::ser::option_pathbuf")]
+    pub database: Option<PathBuf>,
+    /// The ecosystems whose vulnerabilities are matched, such as `Nix`, or all of them if there are none.
+    #[serde(default)]
+    pub ecosystems: Vec<String>,
+}
+
+/// Limits on what tenants may consume, which are checked when builds are submitted.
+#[derive(Debug, Default, Deserialize)]
+pub struct QuotaConfig {
+    /// The quotas of tenants, by name. Tenants without a quota are unlimited.
+    #[serde(default)]
+    pub tenants: BTreeMap<String, TenantQuota>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TenantQuota {
+    /// The most disk space that the packages owned by the tenant may use, in bytes.
+    #[serde(default)]
+    pub store_bytes: Option<u64>,
+    /// The most time that builds of the tenant may run for in total, in minutes.
+    #[serde(default)]
+    pub build_minutes: Option<u64>,
+}
+
+/// Other daemons and binary caches that the store can be synchronized with.
+#[derive(Debug, Deserialize)]
+pub struct SyncConfig {
+    /// The peers, by name.
+    #[serde(default)]
+    pub peers: BTreeMap<String, PeerConfig>,
+    /// The binary caches in object storage, by name. A peer with the same name takes precedence.
+    #[serde(default)]
+    pub remotes: BTreeMap<String, RemoteConfig>,
+    /// How long a substituter that lacked a package isn't asked for it again, in seconds.
+    #[serde(default = "default_negative_ttl")]
+    pub negative_ttl: u64,
+    /// How long a substituter that failed isn't used for, in seconds. The time doubles with each failure in a row.
+    #[serde(default = "default_backoff")]
+    pub backoff: u64,
+    /// The longest time that a failing substituter isn't used for, in seconds.
+    #[serde(default = "default_max_backoff")]
+    pub max_backoff: u64,
+}
+
+fn default_negative_ttl() -> u64 {
+    5 * 60
+}
+
+fn default_backoff() -> u64 {
+    10
+}
+
+fn default_max_backoff() -> u64 {
+    10 * 60
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        Self {
+            peers: BTreeMap::new(),
+            remotes: BTreeMap::new(),
+            negative_ttl: default_negative_ttl(),
+            backoff: default_backoff(),
+            max_backoff: default_max_backoff(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct PeerConfig {
+    /// The address of the API of the peer, such as `http://cache.internal:7000`.
+    #[serde(with = "porkg_private::ser::string")]
+    pub url: String,
+    /// The secret (see [`FetchConfig::secrets`]) that holds the bearer token to present to the peer.
+    #[serde(default, with = "porkg_private::ser::option_string")]
+    pub token: Option<String>,
+    /// Whether missing build inputs are pulled from the peer instead of failing the build.
+    #[serde(default)]
+    pub substitute: bool,
+    /// Whether packages from the peer must be signed by a trusted key.
+    #[serde(default)]
+    pub verify: bool,
+}
+
+/// A binary cache in S3-compatible object storage, which uses the layout of static caches.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RemoteConfig {
+    /// The URL of the bucket, either path-style (`https://s3.eu-west-1.amazonaws.com/my-cache`) or virtual-hosted
+    /// (`https://my-cache.s3.eu-west-1.amazonaws.com`), or a `file://` URL of a local directory.
+    #[serde(with = "porkg_private::ser::string")]
+    pub url: String,
+    #[serde(default = "default_remote_region", with = "porkg_private::ser::string")]
+    pub region: String,
+    /// The prefix of every key of the cache within the bucket, such as `porkg/`.
+    #[serde(default, with = "porkg_private::ser::string")]
+    pub prefix: String,
+    /// The secret (see [`FetchConfig::secrets`]) that holds the access key ID. Requests are anonymous without one.
+    #[serde(default, with = "porkg_private::ser::option_string")]
+    pub access_key_id: Option<String>,
+    /// The secret that holds the secret access key.
+    #[serde(default, with = "porkg_private::ser::option_string")]
+    pub secret_access_key: Option<String>,
+    /// Whether missing build inputs are pulled from the cache instead of failing the build.
+    #[serde(default)]
+    pub substitute: bool,
+    /// Whether packages from the cache must be signed by a trusted key.
+    #[serde(default = "default_remote_verify")]
+    pub verify: bool,
+    /// The size of the parts of multipart uploads, in bytes, which is at least 5 MiB. Smaller archives are uploaded
+    /// in a single request.
+    #[serde(default = "default_remote_part_size")]
+    pub part_size: u64,
+    /// The zstd compression level of published archives.
+    #[serde(default = "default_remote_level")]
+    pub level: i32,
+    /// Whether published packages are split into chunks that packages share, so that only the chunks that the cache
+    /// lacks are uploaded. Otherwise, each package is uploaded as a whole archive.
+    #[serde(default = "default_remote_chunking")]
+    pub chunking: bool,
+}
+
+fn default_remote_region() -> String {
+    "us-east-1".to_string()
+}
+
+fn default_remote_verify() -> bool {
+    true
+}
+
+fn default_remote_part_size() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_remote_level() -> i32 {
+    19
+}
+
+fn default_remote_chunking() -> bool {
+    true
+}
+
+/// Signing of the packages that peers pull, and the keys that are trusted when pulling from peers.
+#[derive(Debug, Default, Deserialize)]
+pub struct SigningConfig {
+    /// The secret (see [`FetchConfig::secrets`]) that holds the key that exported packages are signed with.
+    #[serde(default, with = "porkg_private::ser::option_string")]
+    pub key: Option<String>,
+    /// The public keys whose signatures are accepted, by key name.
+    #[serde(default, with = "porkg_private::ser::string_map")]
+    pub trusted: BTreeMap<String, String>,
+}
+
+/// Discovery of other daemons on the local network with mDNS, which are used as substituters after the configured
+/// peers. Packages from discovered daemons are always verified against the trusted keys.
+#[derive(Debug, Deserialize)]
+pub struct DiscoveryConfig {
+    #[serde(default)]
+    pub enable: bool,
+    /// The TCP port of the API to advertise to other daemons. Nothing is advertised without a port.
+    #[serde(default)]
+    pub advertise: Option<u16>,
+    /// How often other daemons are queried for, in seconds.
+    #[serde(default = "default_discovery_interval")]
+    pub interval: u64,
+}
+
+fn default_discovery_interval() -> u64 {
+    60
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            advertise: None,
+            interval: default_discovery_interval(),
+        }
+    }
+}
+
+/// A class of operations that clients can be permitted to perform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    /// Reading packages from the store, such as exporting images.
+    Read,
+    /// Submitting builds.
+    Build,
+    /// Adding packages to the store by importing or fetching them.
+    Import,
+    /// Registering garbage collection roots.
+    Root,
+    /// Running arbitrary tasks in the sandbox.
+    Run,
+    /// Deleting packages from the store.
+    Delete,
+    /// Advancing channels to new index snapshots.
+    Channel,
+    /// Managing the jobs of other clients, such as requeueing or failing stuck builds.
+    Admin,
+}
+
+/// Permissions for clients connecting over TCP. Clients connecting over the unix socket are trusted with every
+/// operation (except administration if there is an admin socket, see [`BindConfig::admin_socket`]), as access to the
+/// socket is controlled by its file permissions, and can be narrowed to some users and groups.
+#[derive(Debug, Default, Deserialize)]
+pub struct AuthConfig {
+    /// The users, by name or id, whose processes may use the unix sockets. If neither this nor `allowed_groups` is
+    /// set, any process that can connect to a socket may use it.
+    #[serde(default)]
+    pub allowed_users: Vec<String>,
+    /// The groups, by name or id, whose members may use the unix sockets. A process is a member of the groups that it
+    /// runs as, including its supplementary groups.
+    #[serde(default)]
+    pub allowed_groups: Vec<String>,
+    /// The operations that clients may perform without presenting a token.
+    #[serde(default)]
+    pub anonymous: BTreeSet<Operation>,
+    /// The identities that clients can authenticate as with a bearer token, by name.
+    #[serde(default)]
+    pub identities: BTreeMap<String, IdentityConfig>,
+}
+
+#[derive(Deserialize)]
+pub struct IdentityConfig {
+    #[serde(with = "porkg_private::ser::string")]
+    pub token: String,
+    #[serde(default)]
+    pub operations: BTreeSet<Operation>,
+    /// The tenant that the identity belongs to. Tenants only see their own jobs, and the packages that they add are
+    /// recorded as theirs. Identities without a tenant, and clients on the unix socket, see everything.
+    #[serde(default, with = "porkg_private::ser::option_string")]
+    pub tenant: Option<String>,
+}
+
+impl fmt::Debug for IdentityConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdentityConfig")
+            .field("token", &"<redacted>")
+            .field("operations", &self.operations)
+            .field("tenant", &self.tenant)
+            .finish()
+    }
+}
@@ -0,0 +1,177 @@
+//! Degraded mode, which the daemon enters when a write to the store fails because its disk is full or failing (see
+//! [`DiskFault`]).
+//!
+//! Rather than failing every build that is submitted with the same error, the scheduler holds builds in the queue
+//! while the daemon is degraded (see [`crate::backend::scheduler`]). The daemon leaves degraded mode once a write to
+//! the store succeeds again.
+
+use std::{
+    fs,
+    io::{self, Write as _},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    events::{Event, EventBus, Subscription},
+    store::{DiskFault, Store},
+};
+
+/// How often the store is checked while the daemon is degraded.
+const PROBE_INTERVAL: Duration = Duration::from_secs(30);
+/// The size of the file that is written to check the store, which is larger than what a nearly full disk may still
+/// have room for.
+const PROBE_SIZE: usize = 1 << 20;
+
+/// Why the daemon is degraded, as reported to operators.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DegradedStatus {
+    pub fault: DiskFault,
+    pub error: String,
+    /// When the daemon became degraded, in milliseconds since the Unix epoch.
+    pub since: u64,
+}
+
+/// Whether the daemon is degraded.
+#[derive(Debug, Clone)]
+pub struct Degraded(Arc<watch::Sender<Option<DegradedStatus>>>);
+
+impl Default for Degraded {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(None).0))
+    }
+}
+
+impl Degraded {
+    pub fn status(&self) -> Option<DegradedStatus> {
+        self.0.borrow().clone()
+    }
+
+    /// Waits until the daemon isn't degraded.
+    pub async fn healthy(&self) {
+        // The sender is never dropped while `self` holds it.
+        self.0.subscribe().wait_for(Option::is_none).await.ok();
+    }
+
+    /// Makes the daemon degraded, unless it already is.
+    pub fn enter(&self, fault: DiskFault, error: String) {
+        let since = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |v| v.as_millis() as u64);
+        self.0.send_if_modified(|status| {
+            if status.is_some() {
+                return false;
+            }
+            *status = Some(DegradedStatus {
+                fault,
+                error,
+                since,
+            });
+            true
+        });
+    }
+
+    pub fn leave(&self) {
+        self.0.send_replace(None);
+    }
+}
+
+/// Writes, syncs and removes a file in the store.
+///
+/// This performs blocking IO.
+fn probe(store: &Store) -> io::Result<()> {
+    let path = store.temp_path("probe");
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let result = fs::File::create(&path).and_then(|mut file| {
+        file.write_all(&vec![0; PROBE_SIZE])?;
+        file.sync_all()
+    });
+    fs::remove_file(&path).ok();
+    result
+}
+
+/// Enters degraded mode when `subscription` receives a failure of the disk, and leaves it once the store can be
+/// written to again.
+pub async fn monitor(
+    mut subscription: Subscription,
+    degraded: Degraded,
+    store: Store,
+    events: EventBus,
+    cancellation_token: CancellationToken,
+) -> anyhow::Result<()> {
+    loop {
+        let event = tokio::select! {
+            _ = cancellation_token.cancelled() => return Ok(()),
+            event = subscription.recv() => event,
+        };
+        let (fault, error) = match event.as_deref() {
+            Some(Event::DiskFault { fault, error }) => (*fault, error.clone()),
+            Some(_) => continue,
+            None => return Ok(()),
+        };
+        tracing::error!(%fault, error, "pausing builds until the store can be written to again");
+        degraded.enter(fault, error);
+
+        loop {
+            tokio::select! {
+                _ = cancellation_token.cancelled() => return Ok(()),
+                _ = tokio::time::sleep(PROBE_INTERVAL) => {}
+            }
+            let store = store.clone();
+            match tokio::task::spawn_blocking(move || probe(&store)).await? {
+                Ok(()) => break,
+                Err(error) => tracing::debug!(?error, "the store still can't be written to"),
+            }
+        }
+        tracing::info!("the store can be written to again, resuming builds");
+        degraded.leave();
+        events.publish(Event::DiskRecovered);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::store::testing::TestStore;
+
+    #[tokio::test(start_paused = true)]
+    async fn recover() {
+        let store = TestStore::new("degraded");
+        let events = EventBus::default();
+        let mut recovered = events.subscribe();
+        let degraded = Degraded::default();
+        let cancellation_token = CancellationToken::new();
+        let monitor = tokio::spawn(monitor(
+            events.subscribe(),
+            degraded.clone(),
+            (*store).clone(),
+            events.clone(),
+            cancellation_token.clone(),
+        ));
+
+        events.publish(Event::DiskFault {
+            fault: DiskFault::Full,
+            error: "No space left on device".to_string(),
+        });
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(degraded.status().unwrap().fault, DiskFault::Full);
+
+        // The store of the test can be written to, so the first probe succeeds.
+        tokio::time::timeout(Duration::from_secs(60), degraded.healthy())
+            .await
+            .unwrap();
+        loop {
+            if let Event::DiskRecovered = *recovered.recv().await.unwrap() {
+                break;
+            }
+        }
+        cancellation_token.cancel();
+        monitor.await.unwrap().unwrap();
+    }
+}
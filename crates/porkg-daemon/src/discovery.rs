@@ -0,0 +1,248 @@
+//! Discovery of other daemons on the local network with mDNS (see [`DiscoveryConfig`]).
+//!
+//! Daemons query for `_porkg._tcp.local` periodically, and those that advertise answer with a service instance that is
+//! named after their host and the port of their API. Only the port is taken from the answer: the address is that of
+//! the responder, so that a daemon doesn't need to know which of its addresses are reachable. Discovered daemons are
+//! forgotten when their answers expire.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    os::fd::AsRawFd as _,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use nix::sys::socket::{self, sockopt, AddressFamily, SockFlag, SockType, SockaddrIn};
+use tokio::{net::UdpSocket, time::MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{Config, DiscoveryConfig};
+
+use self::dns::{Data, Message, Question, Record};
+
+mod dns;
+
+const SERVICE: &str = "_porkg._tcp.local";
+const GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const PORT: u16 = 5353;
+/// How long other daemons remember an answer, in seconds.
+const TTL: u32 = 120;
+/// mDNS messages are limited to a single datagram.
+const MAX_MESSAGE: usize = 9000;
+
+/// The daemons that have been discovered, as the URLs of their APIs.
+#[derive(Debug, Clone, Default)]
+pub struct Discovered(Arc<Mutex<BTreeMap<String, Instant>>>);
+
+impl Discovered {
+    /// Remembers a daemon for `ttl` seconds, or forgets it if `ttl` is zero (as it is when a daemon stops).
+    fn insert(&self, url: String, ttl: u32) {
+        let mut urls = self.0.lock().unwrap();
+        if ttl == 0 {
+            urls.remove(&url);
+        } else {
+            urls.insert(url, Instant::now() + Duration::from_secs(ttl.into()));
+        }
+    }
+
+    /// Lists the daemons whose answers haven't expired.
+    pub fn urls(&self) -> Vec<String> {
+        let now = Instant::now();
+        let mut urls = self.0.lock().unwrap();
+        urls.retain(|_, expiry| *expiry > now);
+        urls.keys().cloned().collect()
+    }
+}
+
+/// Answers queries for this daemon, and records the answers of other daemons.
+struct Responder {
+    instance: String,
+    host: String,
+    port: Option<u16>,
+    discovered: Discovered,
+}
+
+impl Responder {
+    fn new(host: &str, config: &DiscoveryConfig, discovered: Discovered) -> Self {
+        Self {
+            instance: format!("{host}.{SERVICE}"),
+            host: format!("{host}.local"),
+            port: config.advertise,
+            discovered,
+        }
+    }
+
+    fn query() -> Message {
+        Message {
+            response: false,
+            questions: vec![Question {
+                name: SERVICE.to_string(),
+                kind: dns::TYPE_PTR,
+            }],
+            answers: Vec::new(),
+        }
+    }
+
+    fn answer(&self, port: u16) -> Message {
+        Message {
+            response: true,
+            questions: Vec::new(),
+            answers: vec![
+                Record {
+                    name: SERVICE.to_string(),
+                    ttl: TTL,
+                    data: Data::Ptr(self.instance.clone()),
+                },
+                Record {
+                    name: self.instance.clone(),
+                    ttl: TTL,
+                    data: Data::Srv {
+                        port,
+                        target: self.host.clone(),
+                    },
+                },
+                Record {
+                    name: self.instance.clone(),
+                    ttl: TTL,
+                    data: Data::Txt,
+                },
+            ],
+        }
+    }
+
+    /// Handles a message from `source`, returning the answer to send if it is a query for this service.
+    fn handle(&self, message: &Message, source: SocketAddr) -> Option<Message> {
+        if !message.response {
+            let asked = message.questions.iter().any(|question| {
+                question.name.eq_ignore_ascii_case(SERVICE) && question.kind == dns::TYPE_PTR
+            });
+            return self.port.filter(|_| asked).map(|port| self.answer(port));
+        }
+
+        for record in message.answers.iter() {
+            let Data::Srv { port, .. } = record.data else {
+                continue;
+            };
+            let is_service = record
+                .name
+                .to_ascii_lowercase()
+                .ends_with(&format!(".{SERVICE}"));
+            let is_self =
+                record.name.eq_ignore_ascii_case(&self.instance) && self.port == Some(port);
+            if is_service && !is_self {
+                let url = format!("http://{}:{port}", source.ip());
+                tracing::debug!(url, ttl = record.ttl, "discovered daemon");
+                self.discovered.insert(url, record.ttl);
+            }
+        }
+        None
+    }
+}
+
+/// Binds the mDNS port, which is shared with any other responders on the host.
+fn bind() -> io::Result<UdpSocket> {
+    let fd = socket::socket(
+        AddressFamily::Inet,
+        SockType::Datagram,
+        SockFlag::SOCK_CLOEXEC | SockFlag::SOCK_NONBLOCK,
+        None,
+    )?;
+    socket::setsockopt(&fd, sockopt::ReuseAddr, &true)?;
+    socket::setsockopt(&fd, sockopt::ReusePort, &true)?;
+    socket::bind(
+        fd.as_raw_fd(),
+        &SockaddrIn::from(std::net::SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, PORT)),
+    )?;
+
+    let socket = std::net::UdpSocket::from(fd);
+    socket.join_multicast_v4(&GROUP, &Ipv4Addr::UNSPECIFIED)?;
+    UdpSocket::from_std(socket)
+}
+
+pub async fn run(
+    config: Arc<Config>,
+    discovered: Discovered,
+    cancellation_token: CancellationToken,
+) -> anyhow::Result<()> {
+    if !config.discovery.enable {
+        return Ok(());
+    }
+
+    let host = nix::unistd::gethostname().context("while getting the host name")?;
+    let responder = Responder::new(&host.to_string_lossy(), &config.discovery, discovered);
+    let socket = bind().context("while binding the mDNS socket")?;
+    let group = SocketAddr::from((GROUP, PORT));
+    tracing::info!(instance = responder.instance, advertise = ?responder.port, "discovering daemons");
+
+    let mut interval = tokio::time::interval(Duration::from_secs(config.discovery.interval.max(1)));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut buffer = vec![0; MAX_MESSAGE];
+    loop {
+        let reply = tokio::select! {
+            _ = cancellation_token.cancelled() => break,
+            _ = interval.tick() => Some(Responder::query()),
+            received = socket.recv_from(&mut buffer) => {
+                let (len, source) = received?;
+                match Message::decode(&buffer[..len]) {
+                    Ok(message) => responder.handle(&message, source),
+                    Err(error) => {
+                        tracing::debug!(%source, %error, "ignoring invalid mDNS message");
+                        None
+                    }
+                }
+            }
+        };
+
+        if let Some(reply) = reply {
+            if let Err(error) = socket.send_to(&reply.encode(), group).await {
+                tracing::warn!(%error, "failed to send mDNS message");
+            }
+        }
+    }
+
+    // Other daemons forget this one immediately, rather than when its answer expires.
+    if let Some(port) = responder.port {
+        let mut goodbye = responder.answer(port);
+        goodbye.answers.iter_mut().for_each(|record| record.ttl = 0);
+        socket.send_to(&goodbye.encode(), group).await.ok();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn discover_daemons() {
+        let config = DiscoveryConfig {
+            enable: true,
+            advertise: Some(7000),
+            ..Default::default()
+        };
+        let first = Responder::new("build-1", &config, Discovered::default());
+        let second = Responder::new(
+            "build-2",
+            &DiscoveryConfig::default(),
+            Discovered::default(),
+        );
+        let source: SocketAddr = "192.168.1.20:5353".parse().unwrap();
+
+        let answer = first.handle(&Responder::query(), source).unwrap();
+        assert!(second.handle(&Responder::query(), source).is_none());
+
+        assert!(second.handle(&answer, source).is_none());
+        assert_eq!(second.discovered.urls(), ["http://192.168.1.20:7000"]);
+        // A daemon ignores its own answers, which are looped back to it.
+        first.handle(&answer, source);
+        assert!(first.discovered.urls().is_empty());
+
+        let mut goodbye = answer;
+        goodbye.answers.iter_mut().for_each(|record| record.ttl = 0);
+        second.handle(&goodbye, source);
+        assert!(second.discovered.urls().is_empty());
+    }
+}
@@ -0,0 +1,286 @@
+//! The subset of the DNS message format that mDNS service discovery uses (RFC 6762 and RFC 6763).
+//!
+//! Names are written without compression, but compressed names are understood when reading. Records of other types
+//! are skipped.
+
+use thiserror::Error;
+
+pub const TYPE_PTR: u16 = 12;
+pub const TYPE_TXT: u16 = 16;
+pub const TYPE_SRV: u16 = 33;
+const CLASS_IN: u16 = 1;
+/// Set on the class of a record to replace other records with the same name (the cache-flush bit).
+const CLASS_FLUSH: u16 = 0x8000;
+const FLAG_RESPONSE: u16 = 0x8400;
+/// Limits how many compression pointers are followed, so that loops can't hang the reader.
+const MAX_POINTERS: usize = 16;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DnsError {
+    #[error("the message is truncated")]
+    Truncated,
+    #[error("the message contains an invalid name")]
+    InvalidName,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Question {
+    pub name: String,
+    pub kind: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Data {
+    Ptr(String),
+    Srv {
+        port: u16,
+        target: String,
+    },
+    /// A TXT record without any attributes, as required for every service instance.
+    Txt,
+}
+
+impl Data {
+    fn kind(&self) -> u16 {
+        match self {
+            Data::Ptr(_) => TYPE_PTR,
+            Data::Srv { .. } => TYPE_SRV,
+            Data::Txt => TYPE_TXT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub name: String,
+    pub ttl: u32,
+    pub data: Data,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Message {
+    pub response: bool,
+    pub questions: Vec<Question>,
+    /// The records of the answer and additional sections.
+    pub answers: Vec<Record>,
+}
+
+fn write_name(buffer: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|v| !v.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        buffer.push(label.len() as u8);
+        buffer.extend_from_slice(label);
+    }
+    buffer.push(0);
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::with_capacity(512);
+        let flags = if self.response { FLAG_RESPONSE } else { 0 };
+        for value in [
+            0,
+            flags,
+            self.questions.len() as u16,
+            self.answers.len() as u16,
+            0,
+            0,
+        ] {
+            buffer.extend_from_slice(&value.to_be_bytes());
+        }
+
+        for question in self.questions.iter() {
+            write_name(&mut buffer, &question.name);
+            buffer.extend_from_slice(&question.kind.to_be_bytes());
+            buffer.extend_from_slice(&CLASS_IN.to_be_bytes());
+        }
+
+        for record in self.answers.iter() {
+            write_name(&mut buffer, &record.name);
+            buffer.extend_from_slice(&record.data.kind().to_be_bytes());
+            // Shared records (pointers to instances) must not flush the records of other responders.
+            let class = match record.data {
+                Data::Ptr(_) => CLASS_IN,
+                _ => CLASS_IN | CLASS_FLUSH,
+            };
+            buffer.extend_from_slice(&class.to_be_bytes());
+            buffer.extend_from_slice(&record.ttl.to_be_bytes());
+
+            let mut data = Vec::new();
+            match &record.data {
+                Data::Ptr(name) => write_name(&mut data, name),
+                Data::Srv { port, target } => {
+                    data.extend_from_slice(&[0, 0, 0, 0]);
+                    data.extend_from_slice(&port.to_be_bytes());
+                    write_name(&mut data, target);
+                }
+                Data::Txt => data.push(0),
+            }
+            buffer.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            buffer.extend_from_slice(&data);
+        }
+        buffer
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Self, DnsError> {
+        let mut reader = Reader { data, offset: 0 };
+        reader.u16()?;
+        let flags = reader.u16()?;
+        let questions = reader.u16()?;
+        let counts = [reader.u16()?, reader.u16()?, reader.u16()?];
+
+        let mut message = Message {
+            response: flags & 0x8000 != 0,
+            ..Default::default()
+        };
+        for _ in 0..questions {
+            let name = reader.name()?;
+            let kind = reader.u16()?;
+            reader.u16()?;
+            message.questions.push(Question { name, kind });
+        }
+
+        for _ in 0..counts.iter().map(|v| *v as usize).sum::<usize>() {
+            let name = reader.name()?;
+            let kind = reader.u16()?;
+            reader.u16()?;
+            let ttl = u32::from(reader.u16()?) << 16 | u32::from(reader.u16()?);
+            let len = reader.u16()? as usize;
+            let end = reader.offset + len;
+            if end > data.len() {
+                return Err(DnsError::Truncated);
+            }
+
+            let data = match kind {
+                TYPE_PTR => Some(Data::Ptr(reader.name()?)),
+                TYPE_SRV => {
+                    reader.take(4)?;
+                    let port = reader.u16()?;
+                    let target = reader.name()?;
+                    Some(Data::Srv { port, target })
+                }
+                TYPE_TXT => Some(Data::Txt),
+                _ => None,
+            };
+            reader.offset = end;
+            if let Some(data) = data {
+                message.answers.push(Record { name, ttl, data });
+            }
+        }
+        Ok(message)
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DnsError> {
+        let value = self
+            .data
+            .get(self.offset..self.offset + len)
+            .ok_or(DnsError::Truncated)?;
+        self.offset += len;
+        Ok(value)
+    }
+
+    fn u16(&mut self) -> Result<u16, DnsError> {
+        let value = self.take(2)?;
+        Ok(u16::from_be_bytes([value[0], value[1]]))
+    }
+
+    fn name(&mut self) -> Result<String, DnsError> {
+        let mut labels = Vec::new();
+        let mut offset = self.offset;
+        let mut end = None;
+        let mut pointers = 0;
+        loop {
+            let len = *self.data.get(offset).ok_or(DnsError::Truncated)? as usize;
+            match len {
+                0 => {
+                    end.get_or_insert(offset + 1);
+                    break;
+                }
+                // A pointer to the rest of the name, elsewhere in the message.
+                _ if len & 0xc0 == 0xc0 => {
+                    let low = *self.data.get(offset + 1).ok_or(DnsError::Truncated)? as usize;
+                    end.get_or_insert(offset + 2);
+                    pointers += 1;
+                    if pointers > MAX_POINTERS {
+                        return Err(DnsError::InvalidName);
+                    }
+                    offset = (len & 0x3f) << 8 | low;
+                }
+                _ if len & 0xc0 != 0 => return Err(DnsError::InvalidName),
+                _ => {
+                    let label = self
+                        .data
+                        .get(offset + 1..offset + 1 + len)
+                        .ok_or(DnsError::Truncated)?;
+                    labels.push(String::from_utf8_lossy(label).into_owned());
+                    offset += 1 + len;
+                }
+            }
+        }
+        self.offset = end.unwrap_or(offset);
+        Ok(labels.join("."))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn encode_and_decode() {
+        let message = Message {
+            response: true,
+            questions: vec![Question {
+                name: "_porkg._tcp.local".to_string(),
+                kind: TYPE_PTR,
+            }],
+            answers: vec![
+                Record {
+                    name: "_porkg._tcp.local".to_string(),
+                    ttl: 120,
+                    data: Data::Ptr("build-1._porkg._tcp.local".to_string()),
+                },
+                Record {
+                    name: "build-1._porkg._tcp.local".to_string(),
+                    ttl: 120,
+                    data: Data::Srv {
+                        port: 7000,
+                        target: "build-1.local".to_string(),
+                    },
+                },
+                Record {
+                    name: "build-1._porkg._tcp.local".to_string(),
+                    ttl: 4500,
+                    data: Data::Txt,
+                },
+            ],
+        };
+        assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+
+        // The second question refers to the name of the first one with a compression pointer.
+        let mut data = Message {
+            response: false,
+            questions: vec![Question {
+                name: "_porkg._tcp.local".to_string(),
+                kind: TYPE_PTR,
+            }],
+            answers: Vec::new(),
+        }
+        .encode();
+        data[5] = 2;
+        data.extend_from_slice(&[1, b'x', 0xc0, 12, 0, 12, 0, 1]);
+        let decoded = Message::decode(&data).unwrap();
+        assert_eq!(decoded.questions[1].name, "x._porkg._tcp.local");
+
+        assert_eq!(Message::decode(&data[..20]), Err(DnsError::Truncated));
+        let looped = [0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0xc0, 12, 0, 12, 0, 1];
+        assert_eq!(Message::decode(&looped), Err(DnsError::InvalidName));
+    }
+}
@@ -0,0 +1,343 @@
+//! The events of the daemon, which observers subscribe to instead of being called by the code that they observe.
+//!
+//! Events are broadcast to every subscriber. A subscriber that falls more than [`CAPACITY`] events behind misses the
+//! oldest of them, which is logged, so that a slow observer can't hold back the rest of the daemon.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use porkg_model::hashing::SupportedHash;
+use serde::{Deserialize, Serialize, Serializer};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::{backend::jobs::JobId, store::DiskFault};
+
+/// The number of events that are kept for subscribers that haven't received them yet.
+pub const CAPACITY: usize = 1024;
+
+/// The number of failures that [`RecentErrors`] keeps.
+pub const RECENT_ERRORS: usize = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "state")]
+pub enum JobChange {
+    Queued,
+    Running {
+        attempt: u32,
+    },
+    /// The job finished, or was abandoned by its client.
+    Removed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum StoreChange {
+    Added,
+    Deleted,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "state")]
+pub enum SandboxChange {
+    Started,
+    /// The task in the sandbox succeeded.
+    Finished,
+    Failed {
+        error: String,
+    },
+}
+
+/// What the daemon was asked to do by a signal (see [`crate::signals`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SignalAction {
+    /// Stop right away (SIGINT).
+    Shutdown,
+    /// Stop accepting connections, and stop once the jobs have finished (SIGTERM).
+    Drain,
+    /// Read the secrets that are redacted from logs again (SIGHUP).
+    Reload,
+    /// Switch between the configured log level and debug logs (SIGUSR1).
+    LogLevel,
+    /// Log a report of the state of the daemon (SIGQUIT).
+    DumpState,
+}
+
+/// The error that a background task of the daemon failed with, which is shared by every subscriber.
+#[derive(Clone)]
+pub struct TaskError(Arc<anyhow::Error>);
+
+impl TaskError {
+    pub fn new(error: anyhow::Error) -> Self {
+        Self(Arc::new(error))
+    }
+}
+
+impl fmt::Debug for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for TaskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl Error for TaskError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.0.source()
+    }
+}
+
+impl Serialize for TaskError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&format_args!("{:#}", self.0))
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "kind")]
+pub enum Event {
+    Job {
+        id: JobId,
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tenant: Option<String>,
+        #[serde(flatten)]
+        change: JobChange,
+    },
+    Store {
+        hash: SupportedHash,
+        name: String,
+        change: StoreChange,
+    },
+    Sandbox {
+        job: JobId,
+        name: String,
+        #[serde(flatten)]
+        change: SandboxChange,
+    },
+    Signal {
+        action: SignalAction,
+    },
+    /// A write to the store failed because of its disk, which pauses builds (see [`crate::degraded`]).
+    DiskFault {
+        fault: DiskFault,
+        error: String,
+    },
+    /// The store can be written to again after a failure of its disk.
+    DiskRecovered,
+    /// The sandbox zygote exited, and is restarted.
+    ZygoteExited {
+        reason: String,
+    },
+    /// A background task of the daemon failed, which stops the daemon.
+    TaskFailed {
+        task: &'static str,
+        error: TaskError,
+    },
+}
+
+/// Publishes events to every subscriber.
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Arc<Event>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::Sender::new(CAPACITY),
+        }
+    }
+}
+
+impl EventBus {
+    pub fn publish(&self, event: Event) {
+        // Nobody may be subscribed.
+        self.sender.send(Arc::new(event)).ok();
+    }
+
+    /// Receives the events that are published from now on.
+    pub fn subscribe(&self) -> Subscription {
+        Subscription {
+            receiver: self.sender.subscribe(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Subscription {
+    receiver: broadcast::Receiver<Arc<Event>>,
+}
+
+impl Subscription {
+    /// Waits for the next event, skipping over any that were missed.
+    pub async fn recv(&mut self) -> Option<Arc<Event>> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    tracing::warn!(missed, "an event subscriber fell behind");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+/// A failure that was published, as reported to operators.
+#[derive(Debug, Clone, Serialize)]
+pub struct RecentError {
+    /// When the failure was received, in milliseconds since the Unix epoch.
+    pub time: u64,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+/// The most recent failures of sandboxes and background tasks.
+#[derive(Debug, Clone, Default)]
+pub struct RecentErrors(Arc<Mutex<VecDeque<RecentError>>>);
+
+impl RecentErrors {
+    pub fn list(&self) -> Vec<RecentError> {
+        self.0.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn push(&self, event: &Event) {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |v| v.as_millis() as u64);
+        let mut errors = self.0.lock().unwrap();
+        if errors.len() == RECENT_ERRORS {
+            errors.pop_front();
+        }
+        errors.push_back(RecentError {
+            time,
+            event: event.clone(),
+        });
+    }
+}
+
+/// Keeps the failures that `subscription` receives in `errors` until cancelled.
+pub async fn record_errors(
+    mut subscription: Subscription,
+    errors: RecentErrors,
+    cancellation_token: CancellationToken,
+) -> anyhow::Result<()> {
+    loop {
+        let event = tokio::select! {
+            _ = cancellation_token.cancelled() => return Ok(()),
+            event = subscription.recv() => event,
+        };
+        match event.as_deref() {
+            Some(
+                event @ (Event::TaskFailed { .. }
+                | Event::ZygoteExited { .. }
+                | Event::DiskFault { .. }
+                | Event::Sandbox {
+                    change: SandboxChange::Failed { .. },
+                    ..
+                }),
+            ) => errors.push(event),
+            Some(_) => {}
+            None => return Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn broadcast_events() {
+        let bus = EventBus::default();
+        bus.publish(Event::Store {
+            hash: SupportedHash::Blake3([0; 32]),
+            name: "unobserved".into(),
+            change: StoreChange::Added,
+        });
+
+        let mut first = bus.subscribe();
+        let mut second = bus.subscribe();
+        for id in 0..=CAPACITY as u64 {
+            bus.publish(Event::Job {
+                id: JobId(id),
+                name: "hello".into(),
+                tenant: None,
+                change: JobChange::Running { attempt: 1 },
+            });
+        }
+
+        // The oldest event was dropped for both subscribers, but they still receive the rest.
+        for subscription in [&mut first, &mut second] {
+            let event = subscription.recv().await.unwrap();
+            assert!(matches!(*event, Event::Job { id: JobId(1), .. }));
+        }
+
+        let error = anyhow::anyhow!("disk full").context("while collecting garbage");
+        bus.publish(Event::TaskFailed {
+            task: "gc",
+            error: TaskError::new(error),
+        });
+        let event = loop {
+            let event = first.recv().await.unwrap();
+            if let Event::TaskFailed { .. } = *event {
+                break event;
+            }
+        };
+        assert_eq!(
+            serde_json::to_value(&*event).unwrap(),
+            serde_json::json!({
+                "kind": "task-failed",
+                "task": "gc",
+                "error": "while collecting garbage: disk full",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn record_errors() {
+        let bus = EventBus::default();
+        let errors = RecentErrors::default();
+        let task = tokio::spawn(super::record_errors(
+            bus.subscribe(),
+            errors.clone(),
+            CancellationToken::new(),
+        ));
+
+        for id in 0..=RECENT_ERRORS as u64 {
+            bus.publish(Event::Sandbox {
+                job: JobId(id),
+                name: "hello".into(),
+                change: SandboxChange::Failed {
+                    error: "exited with 1".into(),
+                },
+            });
+            bus.publish(Event::Sandbox {
+                job: JobId(id),
+                name: "hello".into(),
+                change: SandboxChange::Finished,
+            });
+        }
+        drop(bus);
+        task.await.unwrap().unwrap();
+
+        // Only the failures are kept, and the oldest was dropped.
+        let errors = errors.list();
+        assert_eq!(errors.len(), RECENT_ERRORS);
+        assert!(matches!(
+            errors[0].event,
+            Event::Sandbox { job: JobId(1), .. }
+        ));
+    }
+}
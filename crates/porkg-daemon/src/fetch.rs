@@ -0,0 +1,835 @@
+//! Downloads source archives over HTTP(S).
+//!
+//! Each source lists mirrors, which are tried in order. Failed downloads are retried, resuming with range requests
+//! where the server supports them (also from the next mirror), and the checksum is computed as data arrives. All downloads share the bandwidth
+//! limit configured for the daemon. The state of blake3 checksums is saved next to the download as it progresses, so
+//! that a resumed download only has to hash what was received after the last checkpoint again.
+//!
+//! Sources on private hosts can name a secret, which is sent as the `Authorization` header. Every mirror of the source
+//! must be within the origins that the secret is configured with. The header is only sent to the origin of the mirror, so redirects to other hosts (such as a CDN) don't receive it.
+
+use std::{
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use http_body_util::{BodyExt as _, Empty};
+use hyper::{body::Incoming, header, header::HeaderValue, Request, Response, StatusCode, Uri};
+use hyper_rustls::HttpsConnector;
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use porkg_model::{
+    hashing::{
+        resumable::{Checkpoint, ResumableHasher},
+        SupportedHash,
+    },
+    nix::NarInfoError,
+    source::{GitSource, Source, SourceHash},
+};
+use porkg_private::backoff::Backoff;
+use sha2::{Digest as _, Sha256};
+use thiserror::Error;
+use tokio::{
+    fs::{self, File},
+    io::{AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt as _},
+};
+use url::Url;
+
+use crate::{
+    backend::sandbox::Sandbox,
+    blocking::BlockingPool,
+    config::FetchConfig,
+    secret::{SecretError, Secrets},
+    store::{nar, nix::NixImportError, oci::OciError},
+};
+
+mod git;
+mod limit;
+mod nix;
+mod oci;
+
+pub use git::CheckoutTask;
+use limit::RateLimiter;
+
+const MAX_REDIRECTS: usize = 10;
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+const USER_AGENT: &str = concat!("porkg/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Error)]
+pub enum FetchError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error(transparent)]
+    Request(#[from] hyper::http::Error),
+    #[error(transparent)]
+    Client(#[from] hyper_util::client::legacy::Error),
+    #[error(transparent)]
+    Body(#[from] hyper::Error),
+    #[error("invalid url {0:?}")]
+    InvalidUrl(String),
+    #[error("unexpected status {status} from {url}")]
+    Status { url: Url, status: StatusCode },
+    #[error("{url} resumed the download at a different offset than {offset}")]
+    InvalidContentRange { url: Url, offset: u64 },
+    #[error("too many redirects from {0}")]
+    TooManyRedirects(Url),
+    #[error("checksum mismatch: expected {expected}, got {actual}")]
+    HashMismatch {
+        expected: SourceHash,
+        actual: SourceHash,
+    },
+    #[error("the source has no urls")]
+    NoMirrors,
+    #[error("{0:?} is not a full commit id")]
+    InvalidRevision(String),
+    #[error("git {command} failed: {message}")]
+    Git { command: String, message: String },
+    #[error("the sandbox of the checkout failed: {0}")]
+    Sandbox(String),
+    #[error("the fetch was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+    #[error(transparent)]
+    Secret(#[from] SecretError),
+    #[error("the secret {0:?} is not a valid header value")]
+    InvalidSecret(String),
+    #[error(transparent)]
+    NarInfo(#[from] NarInfoError),
+    #[error("invalid narinfo at {0}")]
+    InvalidNarInfo(Url),
+    #[error("no cache has the nix store path {0}")]
+    NotCached(String),
+    #[error("the references of the nix store paths form a cycle")]
+    ReferenceCycle,
+    #[error(transparent)]
+    NixImport(#[from] NixImportError),
+    #[error("invalid image {0:?}")]
+    InvalidImage(String),
+    #[error(transparent)]
+    Oci(#[from] OciError),
+}
+
+impl FetchError {
+    /// Determines whether the same mirror could succeed if the download were attempted again.
+    fn is_transient(&self) -> bool {
+        match self {
+            // The partial download was discarded, so the next attempt starts over.
+            FetchError::IO(_)
+            | FetchError::Client(_)
+            | FetchError::Body(_)
+            | FetchError::InvalidContentRange { .. } => true,
+            FetchError::Status { status, .. } => {
+                status.is_server_error()
+                    || *status == StatusCode::TOO_MANY_REQUESTS
+                    || *status == StatusCode::REQUEST_TIMEOUT
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Computes the checksum of a download, using the algorithm of the expected hash.
+enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<ResumableHasher>),
+}
+
+impl Hasher {
+    fn new(expected: &SourceHash) -> Self {
+        match expected {
+            SourceHash::Sha256(_) => Hasher::Sha256(Sha256::new()),
+            SourceHash::Blake3(_) => Hasher::Blake3(Box::default()),
+        }
+    }
+
+    /// Continues from a checkpoint of an earlier download, which only blake3 checksums have.
+    fn resume(expected: &SourceHash, checkpoint: &Checkpoint) -> Option<Self> {
+        match expected {
+            SourceHash::Sha256(_) => None,
+            SourceHash::Blake3(_) => Some(Hasher::Blake3(Box::new(ResumableHasher::resume(
+                checkpoint,
+            )?))),
+        }
+    }
+
+    fn checkpoint(&self) -> Option<&Checkpoint> {
+        match self {
+            Hasher::Sha256(_) => None,
+            Hasher::Blake3(v) => v.checkpoint(),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(v) => v.update(data),
+            Hasher::Blake3(v) => v.update(data),
+        }
+    }
+
+    fn finalize(self) -> SourceHash {
+        match self {
+            Hasher::Sha256(v) => SourceHash::Sha256(v.finalize().into()),
+            Hasher::Blake3(v) => match v.finalize() {
+                SupportedHash::Blake3(v) => SourceHash::Blake3(v),
+            },
+        }
+    }
+}
+
+impl io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Fetcher {
+    client: Client<HttpsConnector<HttpConnector>, Empty<Bytes>>,
+    limiter: Arc<RateLimiter>,
+    backoff: Backoff,
+    secrets: Secrets,
+    nix_caches: Arc<[String]>,
+    blocking: BlockingPool,
+}
+
+impl Fetcher {
+    pub fn new(config: &FetchConfig, blocking: BlockingPool) -> Self {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        Self {
+            client: Client::builder(TokioExecutor::new()).build(connector),
+            limiter: Arc::new(RateLimiter::new(config.bandwidth_limit)),
+            backoff: Backoff::new(RETRY_DELAY)
+                .with_max(MAX_RETRY_DELAY)
+                .with_jitter(0.5)
+                .with_retries(config.retries),
+            secrets: Secrets::new(&config.secrets),
+            nix_caches: config.nix_caches.clone().into(),
+            blocking,
+        }
+    }
+
+    /// Reads the secret `name` as an `Authorization` header, which is marked as sensitive, once it is known that it may
+    /// be sent to each of `urls`.
+    async fn authorization(
+        &self,
+        name: Option<&str>,
+        urls: &[String],
+    ) -> Result<Option<HeaderValue>, FetchError> {
+        let Some(name) = name else {
+            return Ok(None);
+        };
+        self.secrets
+            .check_origins(name, urls.iter().map(String::as_str))?;
+
+        let secrets = self.secrets.clone();
+        let owned = name.to_string();
+        let secret = self
+            .blocking
+            .run("read-secret", move || secrets.get(&owned))
+            .await??;
+        let mut value = HeaderValue::from_bytes(secret.expose())
+            .map_err(|_| FetchError::InvalidSecret(name.to_string()))?;
+        value.set_sensitive(true);
+        Ok(Some(value))
+    }
+
+    /// Downloads `source` to `path`, trying each of its mirrors in turn. Nothing is left at `path` if every mirror
+    /// fails.
+    #[tracing::instrument(skip_all, fields(source = source.name))]
+    pub async fn fetch(&self, source: &Source, path: &Path) -> Result<(), FetchError> {
+        let authorization = self
+            .authorization(source.secret.as_deref(), &source.urls)
+            .await?;
+        self.download_mirrors(&source.urls, &source.hash, authorization.as_ref(), path)
+            .await
+    }
+
+    /// Downloads what has the hash `expected` to `path` from the first of `urls` that provides it.
+    async fn download_mirrors(
+        &self,
+        urls: &[String],
+        expected: &SourceHash,
+        authorization: Option<&HeaderValue>,
+        path: &Path,
+    ) -> Result<(), FetchError> {
+        let mut last = FetchError::NoMirrors;
+        for url in urls.iter() {
+            let url = match Url::parse(url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => url,
+                _ => {
+                    last = FetchError::InvalidUrl(url.clone());
+                    continue;
+                }
+            };
+
+            let mut retry = self.backoff.start();
+            last = loop {
+                let result = self
+                    .download(url.clone(), expected, authorization, path)
+                    .await;
+                let error = match result {
+                    Ok(()) => {
+                        fs::remove_file(checkpoint_path(path)).await.ok();
+                        return Ok(());
+                    }
+                    Err(error) => error,
+                };
+                let delay = error.is_transient().then(|| retry.next_delay()).flatten();
+                let Some(delay) = delay else {
+                    tracing::warn!(?error, %url, "download failed, trying the next mirror");
+                    // The next mirror resumes the partial download, unless it is known to be wrong.
+                    if matches!(error, FetchError::HashMismatch { .. }) {
+                        remove_partial(path).await?;
+                    }
+                    break error;
+                };
+
+                tracing::debug!(?error, %url, ?delay, "download failed, retrying");
+                tokio::time::sleep(delay).await;
+            };
+        }
+        remove_partial(path).await?;
+        Err(last)
+    }
+
+    /// Exports the revision of `source` to `target`, trying each of its mirrors in turn, and verifies the hash of the
+    /// export. Git runs in `sandbox`, whose root file system is mounted on `root` (an empty directory that is created),
+    /// and doesn't take part in the bandwidth limit.
+    #[tracing::instrument(skip_all, fields(source = source.name, rev = source.rev))]
+    pub async fn fetch_git(
+        &self,
+        sandbox: &Sandbox,
+        source: &GitSource,
+        root: &Path,
+        target: &Path,
+    ) -> Result<(), FetchError> {
+        if !git::is_commit_id(&source.rev) {
+            return Err(FetchError::InvalidRevision(source.rev.clone()));
+        }
+
+        let authorization = self
+            .authorization(source.secret.as_deref(), &source.urls)
+            .await?;
+        let mut result = Err(FetchError::NoMirrors);
+        for url in source.urls.iter() {
+            let authorization = authorization.as_ref().map(HeaderValue::as_bytes);
+            let task = CheckoutTask::new(url, source, authorization, root, target);
+            result = git::run(sandbox, task).await;
+            match &result {
+                Ok(()) => break,
+                Err(error) => {
+                    tracing::warn!(?error, url, "checkout failed, trying the next mirror")
+                }
+            }
+            match fs::remove_dir_all(target).await {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+                _ => {}
+            }
+        }
+        result?;
+
+        let expected = source.hash;
+        let target = target.to_path_buf();
+        let actual = self
+            .blocking
+            .run("hash-git", move || {
+                git::remove_metadata(&target)?;
+                let mut hasher = Hasher::new(&expected);
+                nar::dump(&target, &mut hasher)?;
+                Ok::<_, io::Error>(hasher.finalize())
+            })
+            .await??;
+
+        if actual != expected {
+            return Err(FetchError::HashMismatch { expected, actual });
+        }
+        Ok(())
+    }
+
+    /// Sends a GET request for `url`, following redirects, and requests the content from `offset` on if it isn't 0.
+    /// Returns the final url with the response.
+    async fn get(
+        &self,
+        mut url: Url,
+        offset: u64,
+        authorization: Option<&HeaderValue>,
+        accept: Option<&str>,
+    ) -> Result<(Url, Response<Incoming>), FetchError> {
+        let origin = url.origin();
+        let mut redirects = 0;
+        let response = loop {
+            let uri: Uri = url
+                .as_str()
+                .parse()
+                .map_err(|_| FetchError::InvalidUrl(url.to_string()))?;
+            let mut request = Request::get(uri).header(header::USER_AGENT, USER_AGENT);
+            if offset > 0 {
+                request = request.header(header::RANGE, format!("bytes={offset}-"));
+            }
+            if let Some(accept) = accept {
+                request = request.header(header::ACCEPT, accept);
+            }
+            if let Some(authorization) = authorization.filter(|_| url.origin() == origin) {
+                request = request.header(header::AUTHORIZATION, authorization);
+            }
+
+            let response = self.client.request(request.body(Empty::new())?).await?;
+            if !response.status().is_redirection() {
+                break response;
+            }
+
+            redirects += 1;
+            let location = response
+                .headers()
+                .get(header::LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| url.join(v).ok());
+            url = match location {
+                Some(_) if redirects > MAX_REDIRECTS => {
+                    return Err(FetchError::TooManyRedirects(url))
+                }
+                Some(location) => location,
+                None => {
+                    return Err(FetchError::Status {
+                        url,
+                        status: response.status(),
+                    })
+                }
+            };
+        };
+
+        Ok((url, response))
+    }
+
+    async fn download(
+        &self,
+        url: Url,
+        expected: &SourceHash,
+        authorization: Option<&HeaderValue>,
+        path: &Path,
+    ) -> Result<(), FetchError> {
+        let mut file = File::options()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)
+            .await?;
+
+        // Content from an earlier attempt is hashed again so that the download can continue after it, starting from
+        // the last checkpoint if there is one.
+        let mut checkpoint = Checkpointer::new(path);
+        let (mut hasher, mut offset) = match checkpoint.read(expected, &file).await {
+            Some((hasher, offset)) => {
+                tracing::debug!(offset, "resuming the checksum from a checkpoint");
+                file.seek(SeekFrom::Start(offset)).await?;
+                (hasher, offset)
+            }
+            None => (Hasher::new(expected), 0),
+        };
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let len = file.read(&mut buffer).await?;
+            if len == 0 {
+                break;
+            }
+            hasher.update(&buffer[..len]);
+            offset += len as u64;
+        }
+        checkpoint.save(&hasher).await;
+
+        let (url, response) = self.get(url, offset, authorization, None).await?;
+        let receive = match response.status() {
+            StatusCode::PARTIAL_CONTENT if offset > 0 => {
+                if content_range_start(response.headers()) != Some(offset) {
+                    file.set_len(0).await?;
+                    checkpoint.remove().await;
+                    return Err(FetchError::InvalidContentRange { url, offset });
+                }
+                tracing::debug!(offset, "resuming download");
+                true
+            }
+            StatusCode::OK => {
+                if offset > 0 {
+                    tracing::debug!("server ignored the range request, restarting download");
+                    file.set_len(0).await?;
+                    checkpoint.remove().await;
+                    hasher = Hasher::new(expected);
+                }
+                true
+            }
+            // The earlier attempt already received everything.
+            StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => false,
+            status => return Err(FetchError::Status { url, status }),
+        };
+
+        if receive {
+            let mut body = response.into_body();
+            while let Some(frame) = body.frame().await {
+                if let Ok(data) = frame?.into_data() {
+                    self.limiter.acquire(data.len()).await;
+                    file.write_all(&data).await?;
+                    hasher.update(&data);
+                    checkpoint.save(&hasher).await;
+                }
+            }
+            file.flush().await?;
+        }
+
+        let actual = hasher.finalize();
+        if actual != *expected {
+            return Err(FetchError::HashMismatch {
+                expected: *expected,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Removes a partial download to `path`, and its checkpoint.
+async fn remove_partial(path: &Path) -> io::Result<()> {
+    for path in [path.to_path_buf(), checkpoint_path(path)] {
+        match fs::remove_file(path).await {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Reads where the content of a partial response starts from its `Content-Range` (`bytes <start>-<end>/<len>`).
+fn content_range_start(headers: &header::HeaderMap) -> Option<u64> {
+    let range = headers.get(header::CONTENT_RANGE)?.to_str().ok()?;
+    let (start, _) = range.strip_prefix("bytes ")?.split_once('-')?;
+    start.parse().ok()
+}
+
+/// Gets the path that the checkpoint of the checksum of a download to `path` is saved at.
+fn checkpoint_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".checkpoint");
+    path.with_file_name(name)
+}
+
+/// Saves the checkpoints of the checksum of a download, and reads them back when the download is resumed.
+struct Checkpointer {
+    path: PathBuf,
+    saved: u64,
+}
+
+impl Checkpointer {
+    fn new(download: &Path) -> Self {
+        Self {
+            path: checkpoint_path(download),
+            saved: 0,
+        }
+    }
+
+    /// Reads the checkpoint of an earlier attempt, if it is valid for the partial download in `file`.
+    async fn read(&mut self, expected: &SourceHash, file: &File) -> Option<(Hasher, u64)> {
+        let checkpoint: Checkpoint = serde_json::from_slice(&fs::read(&self.path).await.ok()?)
+            .inspect_err(|error| tracing::debug!(?error, "ignoring an invalid checkpoint"))
+            .ok()?;
+        if checkpoint.offset() > file.metadata().await.ok()?.len() {
+            return None;
+        }
+        let hasher = Hasher::resume(expected, &checkpoint)?;
+        self.saved = checkpoint.offset();
+        Some((hasher, checkpoint.offset()))
+    }
+
+    /// Saves the checkpoint of `hasher` if it is newer than the saved one. The content before it must have been
+    /// written to the download.
+    async fn save(&mut self, hasher: &Hasher) {
+        let Some(checkpoint) = hasher.checkpoint() else {
+            return;
+        };
+        if checkpoint.offset() <= self.saved {
+            return;
+        }
+        // A checkpoint that is lost only means that more of the download is hashed again.
+        let result = async {
+            let content = serde_json::to_vec(checkpoint).map_err(io::Error::from)?;
+            fs::write(&self.path, content).await
+        };
+        match result.await {
+            Ok(()) => self.saved = checkpoint.offset(),
+            Err(error) => tracing::debug!(?error, "failed to save a checkpoint"),
+        }
+    }
+
+    async fn remove(&mut self) {
+        fs::remove_file(&self.path).await.ok();
+        self.saved = 0;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use axum::{
+        http::HeaderMap,
+        response::{IntoResponse, Redirect},
+        routing::get,
+        Router,
+    };
+
+    use crate::config::{SecretConfig, SecretSource};
+
+    use super::*;
+
+    const CONTENT: &[u8] = b"the quick brown fox jumps over the lazy dog";
+
+    const TOKEN: &str = "Bearer token";
+
+    /// Content that is long enough for checkpoints.
+    fn large() -> Vec<u8> {
+        let len = 3 * porkg_model::hashing::resumable::SEGMENT_LEN + 100;
+        (0..len).map(|v| (v % 251) as u8).collect()
+    }
+
+    async fn serve(resumed: Arc<AtomicBool>) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let file = move |headers: HeaderMap| async move {
+            let offset = headers
+                .get(header::RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("bytes="))
+                .and_then(|v| v.strip_suffix('-'))
+                .and_then(|v| v.parse::<usize>().ok());
+            match offset {
+                Some(offset) => {
+                    resumed.store(true, Ordering::SeqCst);
+                    partial(offset, CONTENT)
+                }
+                None => CONTENT.into_response(),
+            }
+        };
+        let private = |headers: HeaderMap| async move {
+            match headers.get(header::AUTHORIZATION) {
+                Some(v) if v == TOKEN => CONTENT.into_response(),
+                _ => StatusCode::UNAUTHORIZED.into_response(),
+            }
+        };
+        // A different host name for the same server, which is a different origin.
+        let elsewhere = format!("http://localhost:{}/private", address.port());
+        let large = |headers: HeaderMap| async move {
+            let offset = headers
+                .get(header::RANGE)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("bytes="))
+                .and_then(|v| v.strip_suffix('-'))
+                .and_then(|v| v.parse::<usize>().ok())
+                .unwrap_or_default();
+            partial(offset, &large())
+        };
+        // Ignores the requested range, and sends everything as partial content.
+        let shifted = || async { partial(0, CONTENT) };
+        let app = Router::new()
+            .route("/file", get(file))
+            .route("/large", get(large))
+            .route("/shifted", get(shifted))
+            .route("/redirect", get(|| async { Redirect::temporary("file") }))
+            .route("/private", get(private))
+            .route(
+                "/elsewhere",
+                get(|| async move { Redirect::temporary(&elsewhere) }),
+            );
+
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{address}")
+    }
+
+    fn partial(offset: usize, content: &[u8]) -> axum::response::Response {
+        let range = format!("bytes {offset}-{}/{}", content.len() - 1, content.len());
+        (
+            StatusCode::PARTIAL_CONTENT,
+            [(header::CONTENT_RANGE, range)],
+            content[offset..].to_vec(),
+        )
+            .into_response()
+    }
+
+    fn source(base: &str, hash: SourceHash) -> Source {
+        Source {
+            name: "fox".into(),
+            urls: vec![format!("{base}/missing"), format!("{base}/redirect")],
+            hash,
+            strip_components: 0,
+            secret: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn fetch_mirrors() {
+        let resumed = Arc::new(AtomicBool::new(false));
+        let base = serve(resumed.clone()).await;
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let fetcher = Fetcher::new(
+            &FetchConfig {
+                retries: 0,
+                ..Default::default()
+            },
+            BlockingPool::new(1),
+        );
+
+        let hash = SourceHash::Sha256(Sha256::digest(CONTENT).into());
+        let path = dir.join("resumed");
+        fs::write(&path, &CONTENT[..10]).await.unwrap();
+        fetcher.fetch(&source(&base, hash), &path).await.unwrap();
+        assert_eq!(fs::read(&path).await.unwrap(), CONTENT);
+        // The partial file is kept when the first mirror fails, so the next one resumes it.
+        assert!(resumed.load(Ordering::SeqCst));
+
+        // Content that doesn't continue from the requested offset isn't appended.
+        let path = dir.join("shifted");
+        fs::write(&path, &CONTENT[..10]).await.unwrap();
+        let mut shifted = source(&base, hash);
+        shifted.urls = vec![format!("{base}/shifted"), format!("{base}/file")];
+        fetcher.fetch(&shifted, &path).await.unwrap();
+        assert_eq!(fs::read(&path).await.unwrap(), CONTENT);
+
+        shifted.urls.pop();
+        fs::write(&path, &CONTENT[..10]).await.unwrap();
+        let result = fetcher.fetch(&shifted, &path).await;
+        assert!(matches!(
+            result,
+            Err(FetchError::InvalidContentRange { offset: 10, .. })
+        ));
+        assert!(!path.exists());
+
+        let path = dir.join("mismatch");
+        let wrong = SourceHash::Blake3([0; 32]);
+        let result = fetcher.fetch(&source(&base, wrong), &path).await;
+        assert!(matches!(result, Err(FetchError::HashMismatch { .. })));
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn fetch_private() {
+        let base = serve(Arc::new(AtomicBool::new(false))).await;
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        fs::write(dir.join("token"), format!("{TOKEN}\n"))
+            .await
+            .unwrap();
+        let secret = SecretConfig {
+            source: SecretSource::File(dir.join("token")),
+            origins: vec![base.clone()],
+        };
+        let fetcher = Fetcher::new(
+            &FetchConfig {
+                retries: 0,
+                secrets: [("host".to_string(), secret)].into(),
+                ..Default::default()
+            },
+            BlockingPool::new(1),
+        );
+
+        let hash = SourceHash::Sha256(Sha256::digest(CONTENT).into());
+        let mut private = source(&base, hash);
+        private.urls = vec![format!("{base}/private")];
+        let path = dir.join("anonymous");
+        let result = fetcher.fetch(&private, &path).await;
+        assert!(matches!(result, Err(FetchError::Status { .. })));
+
+        private.secret = Some("host".into());
+        let path = dir.join("private");
+        fetcher.fetch(&private, &path).await.unwrap();
+        assert_eq!(fs::read(&path).await.unwrap(), CONTENT);
+
+        // The secret isn't sent to other origins.
+        private.urls = vec![format!("{base}/elsewhere")];
+        let path = dir.join("elsewhere");
+        let result = fetcher.fetch(&private, &path).await;
+        assert!(matches!(result, Err(FetchError::Status { .. })));
+
+        // Nor can a source use it with mirrors outside of the origins of the secret.
+        let port = base.rsplit_once(':').unwrap().1;
+        private.urls = vec![
+            format!("{base}/private"),
+            format!("http://localhost:{port}/private"),
+        ];
+        let result = fetcher.fetch(&private, &path).await;
+        assert!(matches!(
+            result,
+            Err(FetchError::Secret(SecretError::Origin { .. }))
+        ));
+
+        private.secret = Some("unknown".into());
+        let result = fetcher.fetch(&private, &path).await;
+        assert!(matches!(
+            result,
+            Err(FetchError::Secret(SecretError::Unknown(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn resume_checksum() {
+        use porkg_model::hashing::resumable::SEGMENT_LEN;
+
+        let base = serve(Arc::new(AtomicBool::new(false))).await;
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        let fetcher = Fetcher::new(
+            &FetchConfig {
+                retries: 0,
+                ..Default::default()
+            },
+            BlockingPool::new(1),
+        );
+        let content = large();
+        let mut hasher = ResumableHasher::new();
+        hasher.update(&content);
+        let SupportedHash::Blake3(digest) = hasher.finalize();
+        let mut source = source(&base, SourceHash::Blake3(digest));
+        source.urls = vec![format!("{base}/large")];
+
+        // An earlier attempt received three segments, and saved a checkpoint after two of them.
+        let received = 3 * SEGMENT_LEN as usize;
+        let mut hasher = ResumableHasher::new();
+        hasher.update(&content[..received]);
+        let checkpoint = hasher.checkpoint().unwrap();
+        assert_eq!(checkpoint.offset(), 2 * SEGMENT_LEN);
+        let path = dir.join("large");
+        // Content before the checkpoint isn't read again, so a difference there goes unnoticed.
+        let mut partial = content[..received].to_vec();
+        partial[0] ^= 1;
+        fs::write(&path, &partial).await.unwrap();
+        fs::write(
+            checkpoint_path(&path),
+            serde_json::to_vec(checkpoint).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        fetcher.fetch(&source, &path).await.unwrap();
+        assert_eq!(fs::read(&path).await.unwrap()[1..], content[1..]);
+        assert!(!checkpoint_path(&path).exists());
+
+        // Without a checkpoint, everything that was received is hashed again.
+        let path = dir.join("unchecked");
+        fs::write(&path, &partial).await.unwrap();
+        let result = fetcher.fetch(&source, &path).await;
+        assert!(matches!(result, Err(FetchError::HashMismatch { .. })));
+    }
+}
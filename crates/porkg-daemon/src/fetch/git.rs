@@ -0,0 +1,415 @@
+//! Exports revisions of git repositories by running `git` in a sandbox.
+//!
+//! Only the requested commit is fetched where the server allows it. Git runs with a fixed configuration so that user
+//! and system settings (such as line ending conversion) can't affect the checkout, and the repository metadata is
+//! removed afterwards, leaving a tree that depends only on the commit.
+//!
+//! The sandbox has an empty root file system with the programs of the host bound read-only into it (see
+//! [`HOST_PATHS`]) and only the checkout writable, so that hooks, filters and `submodule foreach` commands from the
+//! repository can't reach the rest of the host. Git can only use the network transports.
+//!
+//! The `Authorization` header of a source is passed to git through environment variables rather than arguments, which
+//! any user could read, and only applies to the origin of the mirror.
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt, fs,
+    io::{self, Write as _},
+    os::{
+        fd::{AsRawFd as _, OwnedFd},
+        unix::ffi::OsStringExt as _,
+    },
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use porkg_model::source::GitSource;
+use porkg_private::{
+    io::{into_async, pair, SocketOptions},
+    sandbox::{SandboxBackend as _, SandboxOptions, SandboxTask},
+};
+use tokio::io::AsyncReadExt as _;
+use url::Url;
+
+use super::FetchError;
+use crate::{
+    backend::{sandbox::Sandbox, Task},
+    Erro,
+};
+
+const GIT_DIR: &str = ".git";
+/// Transports that may be used, which notably excludes `file::`, `ssh::` and `ext::`.
+const ALLOWED_PROTOCOLS: &str = "http:https:git";
+const CONFIG: &[&str] = &[
+    "core.autocrlf=false",
+    "core.symlinks=true",
+    "advice.detachedHead=false",
+    "init.defaultBranch=main",
+];
+
+/// Where the checkout is bound within the sandbox.
+const CHECKOUT_MOUNT: &str = "/checkout";
+
+/// The paths of the host that git and its helpers need, which are bound read-only at the same paths if they exist.
+const HOST_PATHS: &[&str] = &[
+    "/bin",
+    "/lib",
+    "/lib64",
+    "/usr",
+    "/nix/store",
+    "/run/current-system",
+    "/etc/alternatives",
+    "/etc/ca-certificates",
+    "/etc/hosts",
+    "/etc/nsswitch.conf",
+    "/etc/pki",
+    "/etc/resolv.conf",
+    "/etc/ssl",
+];
+
+/// Determines whether `rev` is a full commit id, which is the only kind of revision that can't change.
+pub fn is_commit_id(rev: &str) -> bool {
+    matches!(rev.len(), 40 | 64) && rev.bytes().all(|v| matches!(v, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+/// The value of an `Authorization` header, which is never printed.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct Authorization(Vec<u8>);
+
+impl fmt::Debug for Authorization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Authorization(<redacted>)")
+    }
+}
+
+/// Checks out a revision of a git repository in a sandbox. The sandbox receives a file descriptor that it writes the
+/// outcome to, as JSON.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CheckoutTask {
+    pub url: String,
+    pub source: GitSource,
+    pub authorization: Option<Authorization>,
+    /// The empty directory of the host that the root file system of the sandbox is mounted on.
+    pub root: PathBuf,
+    /// The directory of the host that the revision is checked out into, which is bound at [`CHECKOUT_MOUNT`].
+    pub target: PathBuf,
+    /// The paths of the host that are bound read-only at the same paths.
+    pub host: Vec<PathBuf>,
+    /// The `PATH` that git is found on.
+    pub path: OsString,
+}
+
+impl CheckoutTask {
+    pub fn new(
+        url: &str,
+        source: &GitSource,
+        authorization: Option<&[u8]>,
+        root: &Path,
+        target: &Path,
+    ) -> Self {
+        Self {
+            url: url.to_string(),
+            source: source.clone(),
+            authorization: authorization.map(|v| Authorization(v.to_vec())),
+            root: root.to_path_buf(),
+            target: target.to_path_buf(),
+            host: HOST_PATHS
+                .iter()
+                .map(PathBuf::from)
+                .filter(|v| v.exists())
+                .collect(),
+            path: std::env::var_os("PATH").unwrap_or_else(|| "/usr/bin:/bin".into()),
+        }
+    }
+}
+
+impl SandboxTask for CheckoutTask {
+    type ExecuteError = Erro;
+
+    fn create_sandbox_options(&self) -> SandboxOptions {
+        let mut options = SandboxOptions::default();
+        options.with_root(&self.root);
+        for path in &self.host {
+            options.with_bind(path, path, true);
+        }
+        options.with_bind(&self.target, CHECKOUT_MOUNT, false);
+        options
+    }
+
+    fn execute(&self, fds: impl AsRef<[OwnedFd]>) -> Result<(), Self::ExecuteError> {
+        let [outcome] = fds.as_ref() else {
+            tracing::error!(count = fds.as_ref().len(), "expected one file descriptor");
+            return Err(Erro);
+        };
+        let git = Git::new(
+            Path::new(CHECKOUT_MOUNT),
+            &self.url,
+            self.authorization.as_ref(),
+            &self.path,
+        );
+        let result = checkout(&git, &self.url, &self.source).map_err(|error| match error {
+            FetchError::Git { command, message } => (command, message),
+            error => ("checkout".to_string(), error.to_string()),
+        });
+        let content = serde_json::to_vec(&result).map_err(|_| Erro)?;
+        fs::File::from(outcome.try_clone().map_err(|_| Erro)?)
+            .write_all(&content)
+            .inspect_err(|error| tracing::error!(?error, "failed to report the outcome"))
+            .map_err(|_| Erro)
+    }
+}
+
+/// Checks out `task` in `sandbox`. The root of the task is removed afterwards.
+pub async fn run(sandbox: &Sandbox, task: CheckoutTask) -> Result<(), FetchError> {
+    let root = task.root.clone();
+    tokio::fs::create_dir_all(&root).await?;
+    tokio::fs::create_dir_all(&task.target).await?;
+    let result = run_in(sandbox, task).await;
+    // The root is only a mount point on the host, as the sandbox mounted its file system in its own namespace.
+    if let Err(error) = tokio::fs::remove_dir(&root).await {
+        tracing::warn!(?error, ?root, "failed to remove the root of a sandbox");
+    }
+    result
+}
+
+async fn run_in(sandbox: &Sandbox, task: CheckoutTask) -> Result<(), FetchError> {
+    let (host, sandboxed) = pair(SocketOptions::default())?;
+    let mut host = into_async(host)?;
+    let handle = sandbox
+        .spawn(Task::Checkout(Box::new(task)), &[sandboxed.as_raw_fd()])
+        .await
+        .map_err(|error| FetchError::Sandbox(error.to_string()))?;
+    // The sandbox holds its own copy now, so that the stream ends when it exits.
+    drop(sandboxed);
+
+    let mut content = Vec::new();
+    host.read_to_end(&mut content).await?;
+    drop(handle);
+    let result: Result<(), (String, String)> = serde_json::from_slice(&content)
+        .map_err(|_| FetchError::Sandbox("the checkout exited without an outcome".to_string()))?;
+    result.map_err(|(command, message)| FetchError::Git { command, message })
+}
+
+/// Runs git commands in a repository.
+struct Git<'a> {
+    dir: &'a Path,
+    path: &'a OsStr,
+    /// Configuration that is passed through `GIT_CONFIG_*`.
+    config: Vec<(String, OsString)>,
+}
+
+impl<'a> Git<'a> {
+    fn new(
+        dir: &'a Path,
+        url: &str,
+        authorization: Option<&Authorization>,
+        path: &'a OsStr,
+    ) -> Self {
+        let mut config = Vec::new();
+        let url = Url::parse(url)
+            .ok()
+            .filter(|v| matches!(v.scheme(), "http" | "https"));
+        if let (Some(url), Some(authorization)) = (url, authorization) {
+            let mut header = b"Authorization: ".to_vec();
+            header.extend_from_slice(&authorization.0);
+            config.push((
+                format!("http.{}/.extraHeader", url.origin().ascii_serialization()),
+                OsString::from_vec(header),
+            ));
+        }
+        Self { dir, path, config }
+    }
+
+    fn run(&self, args: &[impl AsRef<OsStr>]) -> Result<(), FetchError> {
+        let mut command = Command::new("git");
+        command.arg("-C").arg(self.dir);
+        for config in CONFIG {
+            command.args(["-c", config]);
+        }
+
+        command
+            .env_clear()
+            .env("PATH", self.path)
+            .env("HOME", "/tmp")
+            .env("GIT_CONFIG_COUNT", self.config.len().to_string());
+        for (i, (key, value)) in self.config.iter().enumerate() {
+            command
+                .env(format!("GIT_CONFIG_KEY_{i}"), key)
+                .env(format!("GIT_CONFIG_VALUE_{i}"), value);
+        }
+
+        let output = command
+            .args(args)
+            .env("GIT_TERMINAL_PROMPT", "0")
+            .env("GIT_CONFIG_NOSYSTEM", "1")
+            .env("GIT_CONFIG_GLOBAL", "/dev/null")
+            .env("GIT_ALLOW_PROTOCOL", ALLOWED_PROTOCOLS)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .output()?;
+
+        if !output.status.success() {
+            let command = args
+                .iter()
+                .map(|v| v.as_ref().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" ");
+            return Err(FetchError::Git {
+                command,
+                message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Checks out `source` from `url` into the directory of `git`.
+///
+/// This performs blocking IO.
+fn checkout(git: &Git, url: &str, source: &GitSource) -> Result<(), FetchError> {
+    fs::create_dir_all(git.dir)?;
+    git.run(&["init", "-q"])?;
+    // Relative submodule urls are resolved against the origin.
+    git.run(&["remote", "add", "origin", url])?;
+
+    let rev = source.rev.as_str();
+    if let Err(error) = git.run(&["fetch", "-q", "--depth", "1", "--no-tags", "origin", rev]) {
+        tracing::debug!(?error, "shallow fetch failed, fetching all branches");
+        git.run(&[
+            "fetch",
+            "-q",
+            "--no-tags",
+            "origin",
+            "+refs/heads/*:refs/remotes/origin/*",
+        ])?;
+    }
+    git.run(&["checkout", "-q", "--detach", rev])?;
+
+    if source.submodules {
+        let update = ["submodule", "update", "-q", "--init", "--recursive"];
+        if let Err(error) = git.run(&[&update[..], &["--depth", "1"][..]].concat()) {
+            tracing::debug!(
+                ?error,
+                "shallow submodule update failed, fetching full history"
+            );
+            git.run(&update)?;
+        }
+    }
+
+    if source.lfs {
+        git.run(&["lfs", "install", "--local"])?;
+        git.run(&["lfs", "pull"])?;
+        if source.submodules {
+            git.run(&[
+                "submodule",
+                "foreach",
+                "-q",
+                "--recursive",
+                "git lfs install --local && git lfs pull",
+            ])?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes the repository metadata of `path` and any submodules within it.
+///
+/// This performs blocking IO.
+pub fn remove_metadata(path: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let kind = entry.file_type()?;
+        if entry.file_name() == GIT_DIR {
+            if kind.is_dir() {
+                fs::remove_dir_all(entry.path())?;
+            } else {
+                fs::remove_file(entry.path())?;
+            }
+        } else if kind.is_dir() {
+            remove_metadata(&entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{extract, http::StatusCode, response::IntoResponse, routing::get, Router};
+    use porkg_model::source::SourceHash;
+
+    use super::*;
+
+    fn run(dir: &Path, args: &[&str]) -> String {
+        let output = std::process::Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=porkg", "-c", "user.email=porkg@localhost"])
+            .args(args)
+            .env("GIT_CONFIG_GLOBAL", "/dev/null")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    /// Serves the bare repository `repo` over the dumb HTTP transport.
+    async fn serve(repo: PathBuf) -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let file = move |extract::Path(path): extract::Path<String>| async move {
+            match fs::read(repo.join(path)) {
+                Ok(content) => content.into_response(),
+                Err(_) => StatusCode::NOT_FOUND.into_response(),
+            }
+        };
+        let app = Router::new().route("/repo.git/*path", get(file));
+
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{address}/repo.git")
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn export_revision() {
+        let temp = tempfile::tempdir().unwrap();
+        let base = temp.path();
+        let repo = base.join("repo");
+        fs::create_dir_all(&repo).unwrap();
+        run(&repo, &["init", "-q"]);
+        fs::write(repo.join("README"), "first").unwrap();
+        run(&repo, &["add", "."]);
+        run(&repo, &["commit", "-q", "-m", "first"]);
+        let rev = run(&repo, &["rev-parse", "HEAD"]);
+        fs::write(repo.join("README"), "second").unwrap();
+        run(&repo, &["commit", "-q", "-am", "second"]);
+        run(base, &["clone", "-q", "--bare", "repo", "repo.git"]);
+        run(&base.join("repo.git"), &["update-server-info"]);
+        let url = serve(base.join("repo.git")).await;
+
+        let source = GitSource {
+            name: "repo".into(),
+            urls: vec![url.clone()],
+            rev,
+            submodules: true,
+            lfs: false,
+            hash: SourceHash::Blake3([0; 32]),
+            secret: None,
+        };
+        let path = std::env::var_os("PATH").unwrap();
+        let target = base.join("export");
+        let git = Git::new(&target, &url, None, &path);
+        // The task runs blocking IO.
+        tokio::task::block_in_place(|| checkout(&git, &url, &source)).unwrap();
+        assert_eq!(fs::read_to_string(target.join("README")).unwrap(), "first");
+        remove_metadata(&target).unwrap();
+        assert!(!target.join(GIT_DIR).exists());
+
+        // Repositories on the file system of the host can't be read.
+        let local = base.join("repo").display().to_string();
+        let target = base.join("local");
+        let git = Git::new(&target, &local, None, &path);
+        let result = checkout(&git, &local, &source);
+        assert!(matches!(result, Err(FetchError::Git { .. })), "{result:?}");
+        assert!(!target.join("README").exists());
+    }
+}
@@ -0,0 +1,77 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+/// A token bucket that limits the combined rate of all downloads.
+///
+/// Callers take tokens for data that they have already received and then wait until the bucket is no longer in
+/// debt, so a single large read delays the next reads of every download rather than being refused.
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: Option<u64>,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    available: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a limiter that allows `rate` bytes per second, or any rate if `None`.
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate: rate.filter(|v| *v > 0),
+            state: Mutex::new(State {
+                available: rate.unwrap_or_default() as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Accounts for `bytes` that have been received, waiting if the limit has been exceeded.
+    pub async fn acquire(&self, bytes: usize) {
+        let Some(rate) = self.rate else {
+            return;
+        };
+        let rate = rate as f64;
+
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.updated).as_secs_f64();
+            // At most one second worth of data can be received in a burst.
+            state.available = (state.available + elapsed * rate).min(rate) - bytes as f64;
+            state.updated = now;
+            (state.available < 0.0).then(|| Duration::from_secs_f64(-state.available / rate))
+        };
+
+        if let Some(wait) = wait {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn limit_rate() {
+        let limiter = RateLimiter::new(Some(1000));
+        let start = Instant::now();
+
+        // The initial burst is free, after which data is admitted at the configured rate.
+        limiter.acquire(1000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        for _ in 0..4 {
+            limiter.acquire(500).await;
+        }
+        assert_eq!(start.elapsed().as_secs(), 2);
+
+        let unlimited = RateLimiter::new(None);
+        unlimited.acquire(usize::MAX).await;
+        assert_eq!(start.elapsed().as_secs(), 2);
+    }
+}
@@ -0,0 +1,241 @@
+//! Fetches store paths, with the closure of their references, from Nix binary caches.
+//!
+//! The `.narinfo` of each path is looked up in the configured caches in order. Its NAR is downloaded like a source
+//! archive, verified against the `FileHash`, and imported once every path that it references has been imported.
+
+use std::{collections::BTreeMap, fs::File, io::BufReader};
+
+use http_body_util::{BodyExt as _, Limited};
+use hyper::StatusCode;
+use porkg_model::{
+    hashing::SupportedHash,
+    nix::{self, NarCompression, NarInfo, NarInfoError, NixHash},
+    source::{Source, SourceHash},
+};
+use url::Url;
+
+use crate::store::{
+    nix::{import_nar, imported},
+    Store,
+};
+
+use super::{FetchError, Fetcher};
+
+/// The largest `.narinfo` that is read.
+const MAX_NARINFO: usize = 64 * 1024;
+
+impl Fetcher {
+    /// Imports the Nix store path `base_name` (`<hash>-<name>`), and everything that it references, from the first
+    /// configured cache that has each of them. Paths that were imported before are not fetched again.
+    #[tracing::instrument(skip_all, fields(base_name = base_name))]
+    pub async fn fetch_nix(
+        &self,
+        store: &Store,
+        base_name: &str,
+    ) -> Result<SupportedHash, FetchError> {
+        nix::validate_base_name(base_name)?;
+
+        let mut pending = vec![base_name.to_string()];
+        let mut missing = BTreeMap::new();
+        while let Some(base) = pending.pop() {
+            if missing.contains_key(&base) || imported(store, &base).is_some() {
+                continue;
+            }
+            let (cache, info) = self.narinfo(&base).await?;
+            pending.extend(info.references.iter().filter(|v| **v != base).cloned());
+            missing.insert(base, (cache, info));
+        }
+
+        // References are resolved while importing, so they are imported first. Closures are acyclic apart from paths
+        // that refer to themselves.
+        while !missing.is_empty() {
+            let next = missing
+                .iter()
+                .find(|(base, (_, info))| {
+                    info.references
+                        .iter()
+                        .all(|v| v == *base || !missing.contains_key(v))
+                })
+                .map(|(base, _)| base.clone())
+                .ok_or(FetchError::ReferenceCycle)?;
+            let (cache, info) = missing.remove(&next).expect("the path is missing");
+            self.import_nar(store, &cache, info).await?;
+        }
+
+        imported(store, base_name).ok_or_else(|| FetchError::NotCached(base_name.to_string()))
+    }
+
+    /// Reads the `.narinfo` of `base_name` from the first cache that has it.
+    async fn narinfo(&self, base_name: &str) -> Result<(Url, NarInfo), FetchError> {
+        let hash = base_name.split_once('-').map_or(base_name, |(v, _)| v);
+        for cache in self.nix_caches.iter() {
+            let cache = Url::parse(&format!("{}/", cache.trim_end_matches('/')))
+                .ok()
+                .filter(|v| matches!(v.scheme(), "http" | "https"))
+                .ok_or_else(|| FetchError::InvalidUrl(cache.clone()))?;
+            let url = cache
+                .join(&format!("{hash}.narinfo"))
+                .map_err(|_| FetchError::InvalidUrl(cache.to_string()))?;
+
+            let (url, response) = self.get(url, 0, None, None).await?;
+            match response.status() {
+                StatusCode::OK => {}
+                StatusCode::NOT_FOUND | StatusCode::FORBIDDEN => continue,
+                status => return Err(FetchError::Status { url, status }),
+            }
+            let body = Limited::new(response.into_body(), MAX_NARINFO)
+                .collect()
+                .await
+                .map_err(|_| FetchError::InvalidNarInfo(url.clone()))?
+                .to_bytes();
+            let info: NarInfo = std::str::from_utf8(&body)
+                .map_err(|_| FetchError::InvalidNarInfo(url.clone()))?
+                .parse()?;
+            if info.base_name() != base_name {
+                return Err(FetchError::InvalidNarInfo(url));
+            }
+            return Ok((cache, info));
+        }
+        Err(FetchError::NotCached(base_name.to_string()))
+    }
+
+    /// Downloads the NAR of `info` from `cache` and imports it.
+    async fn import_nar(
+        &self,
+        store: &Store,
+        cache: &Url,
+        info: NarInfo,
+    ) -> Result<SupportedHash, FetchError> {
+        let url = cache
+            .join(&info.url)
+            .ok()
+            .filter(|v| v.origin() == cache.origin())
+            .ok_or_else(|| FetchError::InvalidUrl(info.url.clone()))?;
+        // An uncompressed NAR is its own file, so its hash is the NAR hash.
+        let uncompressed = info.compression == NarCompression::None;
+        let Some(NixHash::Sha256(digest)) =
+            info.file_hash.or(uncompressed.then_some(info.nar_hash))
+        else {
+            return Err(NarInfoError::MissingField("FileHash").into());
+        };
+        let source = Source {
+            name: info.name().to_string(),
+            urls: vec![url.to_string()],
+            hash: SourceHash::Sha256(digest),
+            strip_components: 0,
+            secret: None,
+        };
+
+        let staging = store.temp_path("nix-fetch");
+        let path = staging.join("nar");
+        let result = async {
+            tokio::fs::create_dir_all(&staging).await?;
+            self.fetch(&source, &path).await?;
+            let store = store.clone();
+            let path = path.clone();
+            Ok(self
+                .blocking
+                .run("import-nar", move || {
+                    import_nar(&store, &info, BufReader::new(File::open(path)?))
+                })
+                .await??)
+        }
+        .await;
+        tokio::fs::remove_dir_all(&staging).await.ok();
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read as _;
+
+    use axum::{extract::Path, response::IntoResponse, routing::get, Router};
+    use sha2::{Digest as _, Sha256};
+
+    use crate::{
+        blocking::BlockingPool,
+        config::FetchConfig,
+        store::{nar, testing::TestStore},
+    };
+
+    use super::*;
+
+    const LIB: &str = "00000000000000000000000000000000-lib";
+    const APP: &str = "11111111111111111111111111111111-app";
+
+    /// Describes the sample NAR as `base_name`, compressed with xz.
+    fn narinfo(base_name: &str, references: &[&str]) -> (String, Vec<u8>) {
+        let nar = nar::test::sample();
+        let mut xz = Vec::new();
+        xz2::read::XzEncoder::new(&nar[..], 6)
+            .read_to_end(&mut xz)
+            .unwrap();
+        let text = format!(
+            "StorePath: /nix/store/{base_name}\nURL: nar/{base_name}.nar.xz\nCompression: xz\n\
+             FileHash: {}\nFileSize: {}\nNarHash: {}\nNarSize: {}\nReferences: {}\n",
+            NixHash::Sha256(Sha256::digest(&xz).into()),
+            xz.len(),
+            NixHash::Sha256(Sha256::digest(&nar).into()),
+            nar.len(),
+            references.join(" "),
+        );
+        (text, xz)
+    }
+
+    async fn serve() -> String {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let files: BTreeMap<String, Vec<u8>> = [(LIB, &[][..]), (APP, &[APP, LIB][..])]
+            .into_iter()
+            .flat_map(|(base, references)| {
+                let (text, xz) = narinfo(base, references);
+                let hash = base.split_once('-').unwrap().0;
+                [
+                    (format!("{hash}.narinfo"), text.into_bytes()),
+                    (format!("nar/{base}.nar.xz"), xz),
+                ]
+            })
+            .collect();
+        let file = move |Path(path): Path<String>| async move {
+            match files.get(&path) {
+                Some(content) => content.clone().into_response(),
+                None => StatusCode::NOT_FOUND.into_response(),
+            }
+        };
+        let app = Router::new().route("/*path", get(file));
+
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        format!("http://{address}")
+    }
+
+    #[tokio::test]
+    async fn fetch_closure() {
+        let base = serve().await;
+        let store = TestStore::new("fetch-nix");
+        let fetcher = Fetcher::new(
+            &FetchConfig {
+                retries: 0,
+                nix_caches: vec![format!("{base}/missing"), base],
+                ..Default::default()
+            },
+            BlockingPool::new(1),
+        );
+
+        let hash = fetcher.fetch_nix(&store, APP).await.unwrap();
+        assert_eq!(imported(&store, LIB), Some(hash));
+        assert_eq!(imported(&store, APP), Some(hash));
+        assert!(store.by_hash(&hash).join("out/bin").exists());
+
+        assert!(matches!(
+            fetcher
+                .fetch_nix(&store, "22222222222222222222222222222222-missing")
+                .await,
+            Err(FetchError::NotCached(_))
+        ));
+        assert!(matches!(
+            fetcher.fetch_nix(&store, "../app").await,
+            Err(FetchError::NarInfo(NarInfoError::InvalidStorePath(_)))
+        ));
+    }
+}
@@ -0,0 +1,321 @@
+//! Pulls images from OCI registries (the distribution API that Docker registries implement).
+//!
+//! The manifest of the reference is resolved to the one for the current platform, and its config and layers are
+//! downloaded like source archives, verified against their digests. Registries that require a token, such as Docker
+//! Hub, are sent an anonymous token request. Registries on loopback addresses are reached over plain HTTP.
+
+use std::path::Path;
+
+use data_encoding::{HEXLOWER, HEXLOWER_PERMISSIVE};
+use http_body_util::{BodyExt as _, Limited};
+use hyper::{header, header::HeaderValue, StatusCode};
+use porkg_model::{hashing::SupportedHash, source::SourceHash};
+use sha2::{Digest as _, Sha256};
+use url::Url;
+
+use crate::store::{
+    oci::{self, Pulled},
+    Store,
+};
+
+use super::{FetchError, Fetcher};
+
+/// The registry that references without one are pulled from.
+const DEFAULT_REGISTRY: &str = "registry-1.docker.io";
+
+/// The largest manifest or token response that is read.
+const MAX_DOCUMENT: usize = 4 * 1024 * 1024;
+
+/// The number of indexes that may be nested before a manifest is found.
+const MAX_INDEX_DEPTH: usize = 4;
+
+const ACCEPT: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+/// A reference to an image, such as `debian:12` or `ghcr.io/owner/image@sha256:<digest>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct ImageReference {
+    registry: String,
+    repository: String,
+    /// A tag, or a digest.
+    reference: String,
+}
+
+impl ImageReference {
+    fn parse(image: &str) -> Result<Self, FetchError> {
+        let invalid = || FetchError::InvalidImage(image.to_string());
+        let (name, reference) = match image.split_once('@') {
+            Some((name, digest)) => (name, digest),
+            None => match image.rsplit_once(':') {
+                Some((name, tag)) if !tag.contains('/') => (name, tag),
+                _ => (image, "latest"),
+            },
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, path)) if host.contains(['.', ':']) || host == "localhost" => {
+                (host.to_string(), path.to_string())
+            }
+            Some(_) => (DEFAULT_REGISTRY.to_string(), name.to_string()),
+            None => (DEFAULT_REGISTRY.to_string(), format!("library/{name}")),
+        };
+        let registry = match registry.as_str() {
+            "docker.io" | "index.docker.io" => DEFAULT_REGISTRY.to_string(),
+            _ => registry,
+        };
+
+        let valid_repository = repository.split('/').all(|v| {
+            !v.is_empty()
+                && !v.starts_with('.')
+                && v.bytes()
+                    .all(|v| matches!(v, b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'-'))
+        });
+        let valid_reference = !reference.is_empty()
+            && reference
+                .bytes()
+                .all(|v| v.is_ascii_alphanumeric() || matches!(v, b'.' | b'_' | b'-' | b':'));
+        let valid_registry = registry
+            .split(['.', ':'])
+            .all(|v| !v.is_empty() && v.bytes().all(|v| v.is_ascii_alphanumeric() || v == b'-'));
+        if !valid_registry || !valid_repository || !valid_reference {
+            return Err(invalid());
+        }
+        Ok(Self {
+            registry,
+            repository,
+            reference: reference.to_string(),
+        })
+    }
+
+    fn url(&self, kind: &str, reference: &str) -> Result<Url, FetchError> {
+        let host = self
+            .registry
+            .rsplit_once(':')
+            .map_or(&*self.registry, |(v, _)| v);
+        let scheme = if is_loopback(host) { "http" } else { "https" };
+        let url = format!(
+            "{scheme}://{}/v2/{}/{kind}/{reference}",
+            self.registry, self.repository
+        );
+        Url::parse(&url).map_err(|_| FetchError::InvalidUrl(url))
+    }
+}
+
+fn is_loopback(host: &str) -> bool {
+    matches!(host, "localhost" | "127.0.0.1" | "[::1]")
+}
+
+/// Gets the hex digest of a `sha256:` digest, which names its blob.
+fn blob_name(digest: &str) -> Result<&str, FetchError> {
+    digest
+        .strip_prefix("sha256:")
+        .filter(|v| v.len() == 64 && v.bytes().all(|v| v.is_ascii_hexdigit()))
+        .ok_or_else(|| FetchError::InvalidImage(digest.to_string()))
+}
+
+/// Reads the value of a parameter of a `WWW-Authenticate` challenge.
+fn challenge_param<'a>(challenge: &'a str, name: &str) -> Option<&'a str> {
+    challenge.split(',').find_map(|v| {
+        let (key, value) = v.trim().split_once('=')?;
+        let key = key.rsplit(' ').next()?;
+        (key == name).then(|| value.trim_matches('"'))
+    })
+}
+
+impl Fetcher {
+    /// Pulls `image` from its registry and imports it into the store as a root file system named `name`.
+    #[tracing::instrument(skip_all, fields(image = image))]
+    pub async fn pull_oci(
+        &self,
+        store: &Store,
+        image: &str,
+        name: &str,
+    ) -> Result<SupportedHash, FetchError> {
+        let image = ImageReference::parse(image)?;
+        let staging = store.temp_path("oci-pull");
+        let result = self.pull_staged(store, &image, name, &staging).await;
+        tokio::fs::remove_dir_all(&staging).await.ok();
+        result
+    }
+
+    async fn pull_staged(
+        &self,
+        store: &Store,
+        image: &ImageReference,
+        name: &str,
+        staging: &Path,
+    ) -> Result<SupportedHash, FetchError> {
+        let blobs = staging.join("blobs");
+        tokio::fs::create_dir_all(&blobs).await?;
+
+        let mut authorization = None;
+        let mut reference = image.reference.clone();
+        let mut depth = 0;
+        let (manifest, digests) = loop {
+            let data = self
+                .read_manifest(image, &reference, &mut authorization)
+                .await?;
+            let digest = format!("sha256:{}", HEXLOWER.encode(&Sha256::digest(&data)));
+            if reference.starts_with("sha256:") && reference != digest {
+                return Err(FetchError::InvalidImage(format!(
+                    "{reference} has digest {digest}"
+                )));
+            }
+            tokio::fs::write(blobs.join(blob_name(&digest)?), &data).await?;
+
+            match Pulled::read(&data)? {
+                Pulled::Manifest { blobs } => break (digest, blobs),
+                Pulled::Index { .. } if depth == MAX_INDEX_DEPTH => {
+                    return Err(FetchError::InvalidImage(reference))
+                }
+                Pulled::Index { digest } => {
+                    reference = digest;
+                    depth += 1;
+                }
+            }
+        };
+
+        for digest in digests {
+            let name = blob_name(&digest)?;
+            let expected = HEXLOWER_PERMISSIVE
+                .decode(name.as_bytes())
+                .ok()
+                .and_then(|v| v.try_into().ok())
+                .ok_or_else(|| FetchError::InvalidImage(digest.clone()))?;
+            let url = image.url("blobs", &digest)?;
+            self.download_mirrors(
+                &[url.to_string()],
+                &SourceHash::Sha256(expected),
+                authorization.as_ref(),
+                &blobs.join(name),
+            )
+            .await?;
+        }
+
+        let store = store.clone();
+        let name = name.to_string();
+        Ok(self
+            .blocking
+            .run("import-oci", move || {
+                oci::import_pulled(&store, &name, &blobs, &manifest)
+            })
+            .await??)
+    }
+
+    /// Reads the manifest or index of `reference`, requesting a token for `authorization` if the registry wants one.
+    async fn read_manifest(
+        &self,
+        image: &ImageReference,
+        reference: &str,
+        authorization: &mut Option<HeaderValue>,
+    ) -> Result<Vec<u8>, FetchError> {
+        let url = image.url("manifests", reference)?;
+        let (url, mut response) = self
+            .get(url.clone(), 0, authorization.as_ref(), Some(ACCEPT))
+            .await?;
+        if response.status() == StatusCode::UNAUTHORIZED && authorization.is_none() {
+            let challenge = response
+                .headers()
+                .get(header::WWW_AUTHENTICATE)
+                .and_then(|v| v.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            *authorization = Some(self.token(image, &challenge).await?);
+            (_, response) = self
+                .get(url.clone(), 0, authorization.as_ref(), Some(ACCEPT))
+                .await?;
+        }
+        match response.status() {
+            StatusCode::OK => read_document(response.into_body(), &url).await,
+            status => Err(FetchError::Status { url, status }),
+        }
+    }
+
+    /// Requests an anonymous token to pull from the repository of `image`, as the `challenge` from the registry asks.
+    async fn token(
+        &self,
+        image: &ImageReference,
+        challenge: &str,
+    ) -> Result<HeaderValue, FetchError> {
+        let invalid = || FetchError::InvalidImage(format!("unsupported challenge {challenge:?}"));
+        if !challenge.starts_with("Bearer ") {
+            return Err(invalid());
+        }
+        let mut url = challenge_param(challenge, "realm")
+            .and_then(|v| Url::parse(v).ok())
+            .filter(|v| v.scheme() == "https" || v.host_str().is_some_and(is_loopback))
+            .ok_or_else(invalid)?;
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(service) = challenge_param(challenge, "service") {
+                query.append_pair("service", service);
+            }
+            query.append_pair("scope", &format!("repository:{}:pull", image.repository));
+        }
+
+        let (url, response) = self.get(url, 0, None, None).await?;
+        if response.status() != StatusCode::OK {
+            return Err(FetchError::Status {
+                url,
+                status: response.status(),
+            });
+        }
+        #[derive(serde::Deserialize)]
+        struct TokenJson {
+            token: Option<String>,
+            access_token: Option<String>,
+        }
+        let data = read_document(response.into_body(), &url).await?;
+        let token: TokenJson =
+            serde_json::from_slice(&data).map_err(|_| FetchError::InvalidImage(url.to_string()))?;
+        let token = token.token.or(token.access_token).ok_or_else(invalid)?;
+        let mut value = HeaderValue::from_str(&format!("Bearer {token}")).map_err(|_| invalid())?;
+        value.set_sensitive(true);
+        Ok(value)
+    }
+}
+
+async fn read_document(body: hyper::body::Incoming, url: &Url) -> Result<Vec<u8>, FetchError> {
+    Ok(Limited::new(body, MAX_DOCUMENT)
+        .collect()
+        .await
+        .map_err(|_| FetchError::InvalidImage(url.to_string()))?
+        .to_bytes()
+        .to_vec())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_reference() {
+        let image = ImageReference::parse("debian:12").unwrap();
+        assert_eq!(image.registry, DEFAULT_REGISTRY);
+        assert_eq!(image.repository, "library/debian");
+        assert_eq!(image.reference, "12");
+        assert_eq!(
+            image.url("manifests", "12").unwrap().as_str(),
+            "https://registry-1.docker.io/v2/library/debian/manifests/12"
+        );
+
+        let digest = format!("sha256:{}", "0".repeat(64));
+        let image = ImageReference::parse(&format!("ghcr.io/owner/image@{digest}")).unwrap();
+        assert_eq!(image.registry, "ghcr.io");
+        assert_eq!(image.repository, "owner/image");
+        assert_eq!(image.reference, digest);
+
+        let image = ImageReference::parse("localhost:5000/image").unwrap();
+        assert_eq!(image.reference, "latest");
+        assert!(image
+            .url("manifests", "latest")
+            .unwrap()
+            .as_str()
+            .starts_with("http://localhost:5000/"));
+
+        for image in ["../x", "x/../y", "Upper", "x:", "x@sha256:a/b"] {
+            assert!(ImageReference::parse(image).is_err(), "{image}");
+        }
+    }
+}
@@ -1,12 +1,19 @@
+use std::sync::Arc;
+
+use axum::middleware;
 use tokio_util::sync::CancellationToken;
 
 use crate::SetupState;
 
 mod api;
+mod auth;
 mod serve;
 
 pub async fn host(state: SetupState, cancellation_token: CancellationToken) -> anyhow::Result<()> {
-    let app = axum::Router::new().nest("/api/v1", api::v1::build(&state));
+    let peers = Arc::new(auth::PeerPolicy::new(&state.config.auth)?);
+    let app = axum::Router::new()
+        .nest("/api/v1", api::v1::build(&state)?)
+        .layer(middleware::from_fn_with_state(peers, auth::authorize_peer));
 
     serve::serve(&state.config.bind, app, cancellation_token).await
 }
@@ -1,31 +1,360 @@
 use std::sync::Arc;
 
+use anyhow::Context as _;
 use axum::{
-    routing::{get, post},
+    body::Body,
+    extract::{ConnectInfo, Request, State},
+    middleware::{self, Next},
+    routing::{delete, get, post, put},
     Router,
 };
-use porkg_linux::sandbox::SandboxController;
+use futures_util::TryStreamExt as _;
+use tokio::io::{AsyncSeekExt as _, AsyncWriteExt as _};
 
-use crate::{backend::BuildTask, config::Config};
+use crate::{
+    backend::{
+        jobs::Jobs, output::BuildLogs, sandbox::Sandbox, scheduler::Scheduler, usage::Usage,
+    },
+    blocking::BlockingPool,
+    config::{Config, Operation},
+    degraded::Degraded,
+    events::{EventBus, RecentErrors},
+    fetch::Fetcher,
+    frontend::{auth, serve::ClientInfo},
+    redact::Redactor,
+    store::Store,
+    sync::Peers,
+};
 
+mod admin;
+mod audit;
 mod build;
+mod cache;
+mod channel;
+mod fetch;
+mod lint;
+mod metrics;
+mod profile;
+mod run;
+mod store;
+mod sync;
+mod usage;
 
 #[derive(Debug, Clone)]
 struct SharedState {
-    controller: SandboxController<BuildTask>,
+    controller: Sandbox,
     config: Arc<Config>,
+    store: Store,
+    fetcher: Fetcher,
+    scheduler: Scheduler,
+    jobs: Jobs,
+    logs: BuildLogs,
+    usage: Usage,
+    events: EventBus,
+    blocking: BlockingPool,
+    peers: Peers,
+    redactor: Redactor,
+    errors: RecentErrors,
+    degraded: Degraded,
+}
+
+/// Writes a request body to `path`, which must not exist yet. Only the first `limit` bytes are written, and one more if
+/// the body is longer, so that readers can tell that it was.
+///
+/// The body is received without a slot of the blocking pool, so that slow clients don't hold one while they send it.
+async fn spool_body_to(
+    path: &std::path::Path,
+    body: Body,
+    limit: Option<u64>,
+) -> std::io::Result<tokio::fs::File> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut file = tokio::fs::File::options()
+        .create_new(true)
+        .read(true)
+        .write(true)
+        .open(path)
+        .await?;
+    let mut remaining = limit.map_or(u64::MAX, |v| v.saturating_add(1));
+    let mut stream = body.into_data_stream();
+    while let Some(data) = stream.try_next().await.map_err(std::io::Error::other)? {
+        let len = (data.len() as u64).min(remaining);
+        file.write_all(&data[..len as usize]).await?;
+        remaining -= len;
+        if remaining == 0 {
+            break;
+        }
+    }
+    file.flush().await?;
+    file.rewind().await?;
+    Ok(file)
+}
+
+/// Writes a request body to a temporary file of `store` (see [`spool_body_to`]), and returns the file to be read from
+/// blocking code. The file has no name, so nothing is left behind.
+async fn spool_body(
+    store: &Store,
+    body: Body,
+    limit: Option<u64>,
+) -> std::io::Result<std::fs::File> {
+    let path = store.temp_path("body");
+    let result = spool_body_to(&path, body, limit).await;
+    match tokio::fs::remove_file(&path).await {
+        Err(error) if error.kind() != std::io::ErrorKind::NotFound => return Err(error),
+        _ => {}
+    }
+    Ok(result?.into_std().await)
 }
 
 async fn root() -> String {
     "Hello World".to_string()
 }
 
-pub fn build(state: &crate::SetupState) -> Router<()> {
-    Router::new()
+pub fn build(state: &crate::SetupState) -> anyhow::Result<Router<()>> {
+    let blocking = BlockingPool::new(state.config.scheduler.max_blocking);
+    let config = state.config.clone();
+    let restrict = move |operation: Operation| {
+        middleware::from_fn_with_state(
+            config.clone(),
+            move |config: State<Arc<Config>>,
+                  client: ConnectInfo<ClientInfo>,
+                  request: Request,
+                  next: Next| {
+                auth::authorize(operation, config, client, request, next)
+            },
+        )
+    };
+
+    let peers = Peers::new(
+        &state.config,
+        state.discovered.clone(),
+        Store::new(&state.config.store)
+            .with_events(state.events.clone())
+            .with_origin("daemon", "sync"),
+        blocking.clone(),
+    )?;
+
+    let usage = Usage::open(&state.config.store.path.join("usage"))
+        .context("while opening the usage journal")?;
+
+    let shared = SharedState {
+        controller: state.controller.clone(),
+        config: state.config.clone(),
+        store: Store::new(&state.config.store).with_events(state.events.clone()),
+        fetcher: Fetcher::new(&state.config.fetch, blocking.clone()),
+        scheduler: Scheduler::new(&state.config.scheduler, &state.time)
+            .with_degraded(state.degraded.clone()),
+        jobs: state.jobs.clone(),
+        // The output of the builds that are running, or are kept once they finished.
+        logs: BuildLogs::new(
+            state.config.scheduler.max_jobs + state.config.scheduler.finished_jobs,
+        )
+        .with_redactor(state.redactor.clone()),
+        usage,
+        events: state.events.clone(),
+        peers,
+        blocking,
+        redactor: state.redactor.clone(),
+        errors: state.errors.clone(),
+        degraded: state.degraded.clone(),
+    };
+    tokio::spawn(build::resume(shared.clone()));
+
+    Ok(Router::new()
         .route("/", get(root))
-        .route("/build", post(build::post))
-        .with_state(SharedState {
-            controller: state.controller.clone(),
-            config: state.config.clone(),
-        })
+        .route(
+            "/admin/debug-report",
+            get(admin::debug_report).route_layer(restrict(Operation::Admin)),
+        )
+        .route(
+            "/admin/jobs",
+            get(admin::list_jobs).route_layer(restrict(Operation::Admin)),
+        )
+        .route(
+            "/admin/jobs/:id/requeue",
+            post(admin::requeue).route_layer(restrict(Operation::Admin)),
+        )
+        .route(
+            "/admin/jobs/:id/fail",
+            post(admin::fail).route_layer(restrict(Operation::Admin)),
+        )
+        .route(
+            "/audit/:target",
+            get(audit::audit).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/build",
+            get(build::list)
+                .route_layer(restrict(Operation::Read))
+                .merge(post(build::post).route_layer(restrict(Operation::Build))),
+        )
+        .route(
+            "/build/:id",
+            get(build::get)
+                .route_layer(restrict(Operation::Read))
+                .merge(delete(build::cancel).route_layer(restrict(Operation::Build))),
+        )
+        .route(
+            "/build/:id/logs",
+            get(build::logs).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/build/:id/files",
+            get(build::files).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/build/:id/files/*path",
+            get(build::file).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/channel",
+            get(channel::list).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/channel/:name",
+            get(channel::get)
+                .route_layer(restrict(Operation::Read))
+                .merge(put(channel::put).route_layer(restrict(Operation::Channel))),
+        )
+        .route(
+            "/fetch",
+            post(fetch::post).route_layer(restrict(Operation::Import)),
+        )
+        .route(
+            "/fetch/git",
+            post(fetch::post_git).route_layer(restrict(Operation::Import)),
+        )
+        .route(
+            "/fetch/nix",
+            post(fetch::post_nix).route_layer(restrict(Operation::Import)),
+        )
+        .route(
+            "/fetch/oci",
+            post(fetch::post_oci).route_layer(restrict(Operation::Import)),
+        )
+        .route(
+            "/lint",
+            post(lint::post).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/metrics",
+            get(metrics::get).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/profile/:name/diff",
+            get(profile::diff).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/profile/:name/switch",
+            post(profile::switch).route_layer(restrict(Operation::Run)),
+        )
+        .route(
+            "/run/:hash",
+            post(run::post_default).route_layer(restrict(Operation::Run)),
+        )
+        .route(
+            "/run/:hash/:exec",
+            post(run::post).route_layer(restrict(Operation::Run)),
+        )
+        .route(
+            "/store/cache",
+            post(sync::export_cache).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/store/closure",
+            post(sync::closure).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/store/missing",
+            post(sync::missing).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/store/history",
+            get(store::history).route_layer(restrict(Operation::Admin)),
+        )
+        .route(
+            "/store/nix",
+            post(store::import_nix).route_layer(restrict(Operation::Import)),
+        )
+        .route(
+            "/store/oci",
+            post(store::import_oci).route_layer(restrict(Operation::Import)),
+        )
+        .route(
+            "/store/source",
+            post(store::import_source).route_layer(restrict(Operation::Import)),
+        )
+        .route(
+            "/store/:hash",
+            delete(store::delete).route_layer(restrict(Operation::Delete)),
+        )
+        .route(
+            "/store/:hash/oci",
+            get(store::export_oci).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/store/:hash/package",
+            get(sync::export_package)
+                .route_layer(restrict(Operation::Read))
+                .merge(put(sync::import_package).route_layer(restrict(Operation::Import))),
+        )
+        .route(
+            "/store/:hash/advisories",
+            get(channel::advisories).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/store/:hash/provenance",
+            get(store::provenance).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/store/:hash/provenance/:build",
+            get(store::build_provenance).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/sync/pull",
+            post(sync::pull).route_layer(restrict(Operation::Import)),
+        )
+        .route(
+            "/sync/push",
+            post(sync::push).route_layer(restrict(Operation::Read)),
+        )
+        .route(
+            "/usage",
+            get(usage::get).route_layer(restrict(Operation::Read)),
+        )
+        .with_state(shared))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read as _;
+
+    use crate::store::testing::TestStore;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn spool_bodies() {
+        let store = TestStore::new("spool");
+        let mut content = String::new();
+        spool_body(&store, Body::from("request"), None)
+            .await
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "request");
+
+        // Only one byte more than the limit is kept.
+        let mut content = String::new();
+        spool_body(&store, Body::from("request"), Some(3))
+            .await
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "requ");
+
+        let tmp = store.root().join("tmp");
+        assert_eq!(std::fs::read_dir(tmp).unwrap().count(), 0);
+    }
 }
@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::header,
+    response::IntoResponse,
+    Extension, Json,
+};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::{
+        jobs::{Control, JobError, JobId, JobInfo},
+        sandbox::SandboxStatus,
+    },
+    blocking::Occupancy,
+    degraded::DegradedStatus,
+    error::{ApiError, AppError},
+    events::RecentError,
+    frontend::auth::Tenant,
+    sync::health::SubstituterMetrics,
+};
+
+use super::SharedState;
+
+impl ApiError for JobError {
+    type Data = ();
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            JobError::NotFound(_) => StatusCode::NOT_FOUND,
+            JobError::NotRunning(_) => StatusCode::CONFLICT,
+        }
+    }
+
+    fn data(self) -> Self::Data {}
+}
+
+/// Lists the jobs of the tenant of the client, or every job if it doesn't belong to one.
+pub async fn list_jobs(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+) -> Json<Vec<JobInfo>> {
+    Json(state.jobs.list(tenant.scope()))
+}
+
+/// Abandons the current attempt of a running job, and queues it again.
+pub async fn requeue(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<JobId>,
+) -> Result<(), AppError<JobError>> {
+    Ok(state.jobs.control(id, Control::Requeue, tenant.scope())?)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FailQuery {
+    /// Why the job was failed, which is returned to the client that submitted it.
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Abandons a running job, failing the build.
+pub async fn fail(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<JobId>,
+    Query(query): Query<FailQuery>,
+) -> Result<(), AppError<JobError>> {
+    let reason = query
+        .reason
+        .unwrap_or_else(|| "failed by an operator".to_string());
+    Ok(state
+        .jobs
+        .control(id, Control::Fail(reason), tenant.scope())?)
+}
+
+#[derive(Debug, Serialize)]
+pub struct DebugReport {
+    version: &'static str,
+    /// The builds that are running, out of the builds that may run at once.
+    scheduler: Occupancy,
+    sandbox: SandboxStatus,
+    /// The blocking operations that are running, out of the operations that may run at once.
+    blocking: Occupancy,
+    jobs: Vec<JobInfo>,
+    substituters: BTreeMap<String, SubstituterMetrics>,
+    /// The most recent failures, oldest first.
+    errors: Vec<RecentError>,
+    /// Why builds are paused, if they are.
+    degraded: Option<DegradedStatus>,
+}
+
+/// Reports the state of the daemon for bug reports, with the secrets in it redacted.
+pub async fn debug_report(State(state): State<SharedState>) -> impl IntoResponse {
+    let report = DebugReport {
+        version: env!("CARGO_PKG_VERSION"),
+        scheduler: state.scheduler.occupancy(),
+        sandbox: state.controller.status().await,
+        blocking: state.blocking.occupancy(),
+        jobs: state.jobs.list(None),
+        substituters: state.peers.substituter_metrics(),
+        errors: state.errors.list(),
+        degraded: state.degraded.status(),
+    };
+    let report = serde_json::to_vec(&report).expect("the report can be serialized");
+    let report = state.redactor.redact(&report).into_owned();
+    ([(header::CONTENT_TYPE, "application/json")], report)
+}
@@ -0,0 +1,67 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use hyper::StatusCode;
+use porkg_model::audit::AuditReport;
+use thiserror::Error;
+
+use crate::{
+    error::{ApiError, AppError},
+    store::{
+        audit::{self, AuditError, Target},
+        StoreError,
+    },
+};
+
+use super::SharedState;
+
+#[derive(Debug, Error)]
+pub enum AuditApiError {
+    #[error("the daemon has no advisory database to audit against")]
+    NoDatabase,
+    #[error(transparent)]
+    Audit(#[from] AuditError),
+    #[error("the audit was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+}
+
+impl ApiError for AuditApiError {
+    type Data = ();
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuditApiError::NoDatabase => StatusCode::NOT_IMPLEMENTED,
+            AuditApiError::Audit(AuditError::InvalidProfile(_)) => StatusCode::BAD_REQUEST,
+            AuditApiError::Audit(
+                AuditError::ProfileNotFound(_) | AuditError::Store(StoreError::NotFound(_)),
+            ) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn data(self) -> Self::Data {}
+}
+
+/// Audits the closure of a package, or of the newest generation of a profile, against the advisory database, reporting
+/// the affected packages and the packages that reference them.
+pub async fn audit(
+    State(state): State<SharedState>,
+    Path(target): Path<String>,
+) -> Result<Json<AuditReport>, AppError<AuditApiError>> {
+    let target = Target::parse(&target);
+    let config = &state.config.audit;
+    let database = config.database.clone().ok_or(AuditApiError::NoDatabase)?;
+    let ecosystems = config.ecosystems.clone();
+    let store = state.store.clone();
+    let report = state
+        .blocking
+        .run("audit", move || {
+            let database = audit::load(&database)?;
+            audit::audit(&store, &target, &database, &ecosystems)
+        })
+        .await
+        .map_err(AuditApiError::from)?
+        .map_err(AuditApiError::from)?;
+    Ok(Json(report))
+}
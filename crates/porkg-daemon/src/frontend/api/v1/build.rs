@@ -1,38 +1,205 @@
-use axum::{extract::State, Json};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, BufReader, Read},
+    path::PathBuf,
+};
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::header,
+    http::HeaderMap,
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension, Json,
+};
+use futures_util::{stream, Stream, StreamExt as _};
 use hyper::StatusCode;
 use itertools::Itertools;
-use porkg_model::package::LockDefinition;
+use porkg_linux::sandbox::SandboxTaskError;
+use porkg_model::{
+    channel::Channel,
+    log::LogRecord,
+    package::{LockDefinition, LockDrift, Package},
+    provenance::{Override, Provenance},
+};
+use porkg_private::{sandbox::SandboxBackend as _, ser::ErrorKind};
 use thiserror::Error;
+use tokio_util::io::ReaderStream;
 
 use crate::{
-    backend::BuildTask,
+    backend::{
+        hook::{self, HookContext, HookError, HookStage},
+        jobs::{Control, JobError, JobHandle, JobId, JobInfo, JobRequest},
+        output::{self, Followed},
+        sandbox::Sandbox,
+        scheduler::ScheduleError,
+        scratch::{self, ScratchDir, ScratchEntry, ScratchError},
+        BuildProblem, BuildTask, Task,
+    },
     error::{ApiError, AppError},
+    events::{Event, SandboxChange},
+    frontend::auth::Tenant,
+    store::{gc::temp::TempRoot, provenance, DiskFault, Store},
 };
 
-use super::SharedState;
+use super::{spool_body, SharedState};
 
-#[derive(Debug, serde::Deserialize)]
+/// The content type of build requests that are encoded in the internal serialization format (see
+/// [`porkg_private::ser`]), which is far more compact than JSON for locks with thousands of dependencies.
+const BINCODE: &str = "application/x-porkg-bincode";
+const JSON: &str = "application/json";
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
 pub struct BuildRequest {
     name: String,
     hash: String,
     lock: LockDefinition,
+    /// The longest time that the build may wait to start, in seconds.
+    #[serde(default)]
+    queue_timeout: Option<u64>,
+    /// The longest time that the build may run for, in seconds.
+    #[serde(default)]
+    max_runtime: Option<u64>,
+    /// The changes that overlays made to the package, which are recorded in its provenance.
+    #[serde(default)]
+    overrides: BTreeSet<Override>,
+    /// The channel snapshot that the lock was resolved against, which is recorded in the provenance of the package.
+    #[serde(default)]
+    channel: Option<Channel>,
+    /// The values of the environment variables that the manifest passes through from the host without hashing them
+    /// (see [`porkg_model::package::EnvPassthrough`]).
+    #[serde(default)]
+    env: BTreeMap<String, String>,
 }
 
 #[derive(Debug, Error, serde::Serialize)]
 pub enum StartError {
-    #[error("invalid hash provided: {hash}")]
-    InvalidHash { hash: String },
-    #[error("invalid dependency hash provided for {name}: {hash}")]
-    InvalidDependencyHash { name: String, hash: String },
-    #[error("failed to validate the build")]
-    ValidationError { error: String },
+    #[error("invalid build: {}", .problems.iter().join("; "))]
+    Invalid { problems: Vec<BuildProblem> },
+    #[error(
+        "the lock doesn't match the dependencies of the manifest, so they must be resolved again"
+    )]
+    LockDrift {
+        #[serde(flatten)]
+        drift: LockDrift,
+    },
+    #[error("the build did not start within {seconds} seconds")]
+    QueueTimeout { seconds: u64 },
+    #[error("the build did not finish within {seconds} seconds")]
+    DeadlineExceeded { seconds: u64 },
+    #[error("failed to start the build")]
+    SpawnError { error: String },
+    #[error("the build failed")]
+    BuildFailed { error: String },
+    /// The sandbox failed underneath the build, which may succeed if it is submitted again.
+    #[error("the sandbox failed while the build ran")]
+    Infrastructure { error: String },
+    /// The disk of the store failed, which pauses builds until it is resolved (see [`crate::degraded`]).
+    #[error("{fault}")]
+    Disk { fault: DiskFault, error: String },
+    #[error("invalid build request")]
+    InvalidBody { error: String },
+    #[error("the build request is larger than {limit} bytes")]
+    BodyTooLarge { limit: u64 },
+    #[error("unsupported content type {content_type}")]
+    UnsupportedMediaType { content_type: String },
+    #[error("reading the build request was interrupted")]
+    Interrupted,
+    #[error("failed to record the provenance of the build")]
+    Provenance { error: String },
+    #[error("the build was rejected by a hook")]
+    PolicyRejected {
+        error: String,
+        /// The end of the output of the hook.
+        output: Vec<LogRecord>,
+    },
+    #[error("failed to run a hook")]
+    Hook { error: String },
+    #[error("the build was failed by an operator: {reason}")]
+    Failed { reason: String },
+    #[error("the build was cancelled")]
+    Cancelled,
+    #[error("the quota of the tenant is exhausted")]
+    QuotaExceeded { error: String },
+    #[error("failed to measure the usage of the tenant")]
+    Usage { error: String },
+    #[error("failed to keep the packages of the build from being collected")]
+    TempRoot { error: String },
+}
+
+impl StartError {
+    /// Describes the error along with the error that caused it, which the response carries separately.
+    fn describe(&self) -> String {
+        match self {
+            StartError::SpawnError { error }
+            | StartError::BuildFailed { error }
+            | StartError::Infrastructure { error }
+            | StartError::Disk { error, .. }
+            | StartError::InvalidBody { error }
+            | StartError::Provenance { error }
+            | StartError::PolicyRejected { error, .. }
+            | StartError::Hook { error }
+            | StartError::QuotaExceeded { error }
+            | StartError::Usage { error }
+            | StartError::TempRoot { error } => format!("{self}: {error}"),
+            _ => self.to_string(),
+        }
+    }
+}
+
+impl From<HookError> for StartError {
+    fn from(value: HookError) -> Self {
+        let error = value.to_string();
+        match value {
+            HookError::Rejected { output, .. } | HookError::Timeout { output, .. } => {
+                StartError::PolicyRejected { error, output }
+            }
+            HookError::Spawn { .. } => StartError::Hook { error },
+        }
+    }
+}
+
+impl From<ScheduleError> for StartError {
+    fn from(value: ScheduleError) -> Self {
+        match value {
+            ScheduleError::QueueTimeout(v) => StartError::QueueTimeout {
+                seconds: v.as_secs(),
+            },
+            ScheduleError::DeadlineExceeded(v) => StartError::DeadlineExceeded {
+                seconds: v.as_secs(),
+            },
+        }
+    }
 }
 
 impl ApiError for StartError {
     type Data = Self;
 
     fn status_code(&self) -> StatusCode {
-        StatusCode::BAD_REQUEST
+        match self {
+            StartError::QueueTimeout { .. }
+            | StartError::Infrastructure { .. }
+            | StartError::Disk { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            StartError::DeadlineExceeded { .. } => StatusCode::GATEWAY_TIMEOUT,
+            StartError::BodyTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            StartError::UnsupportedMediaType { .. } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            StartError::PolicyRejected { .. } | StartError::BuildFailed { .. } => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            StartError::LockDrift { .. } | StartError::Cancelled => StatusCode::CONFLICT,
+            StartError::QuotaExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+            StartError::SpawnError { .. }
+            | StartError::Interrupted
+            | StartError::Provenance { .. }
+            | StartError::Hook { .. }
+            | StartError::Failed { .. }
+            | StartError::Usage { .. }
+            | StartError::TempRoot { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::BAD_REQUEST,
+        }
     }
 
     fn data(self) -> Self::Data {
@@ -40,48 +207,739 @@ impl ApiError for StartError {
     }
 }
 
+/// Rejects a lock that omits dependencies that the manifest of the source declares, or has others, as it was resolved
+/// for another version of the manifest.
+async fn check_lock(
+    state: &SharedState,
+    task: &BuildTask,
+    lock: &LockDefinition,
+) -> Result<(), StartError> {
+    let path = state
+        .store
+        .by_hash(task.hash.as_ref())
+        .join("src/porkg.toml");
+    let invalid = |error: String| StartError::Invalid {
+        problems: vec![BuildProblem::InvalidManifest { error }],
+    };
+    let manifest = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|error| invalid(error.to_string()))?;
+    let package: Package = toml::from_str(&manifest).map_err(|error| invalid(error.to_string()))?;
+    let drift = lock.drift(&package);
+    if !drift.is_empty() {
+        tracing::info!(
+            name = task.name,
+            ?drift,
+            "rejecting a lock that drifted from its manifest"
+        );
+        return Err(StartError::LockDrift { drift });
+    }
+    Ok(())
+}
+
+/// Reads at most `limit` bytes, failing if there are more.
+struct Limited<R> {
+    inner: R,
+    remaining: u64,
+    exceeded: bool,
+}
+
+impl<R: Read> Read for Limited<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            if buf.is_empty() || self.inner.read(&mut [0u8])? == 0 {
+                return Ok(0);
+            }
+            self.exceeded = true;
+            return Err(io::Error::other("the request is too large"));
+        }
+
+        let len = buf
+            .len()
+            .min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let len = self.inner.read(&mut buf[..len])?;
+        self.remaining -= len as u64;
+        Ok(len)
+    }
+}
+
+/// Deserializes a build request from a stream, so that large requests are never buffered whole.
+fn read_request(
+    content_type: Option<&str>,
+    reader: impl Read,
+    limit: u64,
+) -> Result<BuildRequest, StartError> {
+    let mut reader = Limited {
+        inner: reader,
+        remaining: limit,
+        exceeded: false,
+    };
+
+    let media_type = content_type.map(|v| v.split(';').next().unwrap_or(v).trim());
+    let result = match media_type {
+        None | Some(JSON) => {
+            serde_json::from_reader(BufReader::new(&mut reader)).map_err(|v| v.to_string())
+        }
+        Some(BINCODE) => {
+            match porkg_private::ser::deserialize_from(BufReader::new(&mut reader), limit) {
+                Err(error) if matches!(*error, ErrorKind::SizeLimit) => {
+                    return Err(StartError::BodyTooLarge { limit })
+                }
+                result => result.map_err(|v| v.to_string()),
+            }
+        }
+        Some(other) => {
+            return Err(StartError::UnsupportedMediaType {
+                content_type: other.to_string(),
+            })
+        }
+    };
+
+    if reader.exceeded {
+        return Err(StartError::BodyTooLarge { limit });
+    }
+    result.map_err(|error| StartError::InvalidBody { error })
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct PostQuery {
+    /// Whether to respond with the id of the job once the request was read, instead of waiting for the build.
+    #[serde(default)]
+    detach: bool,
+}
+
+/// The response to a detached build request.
+#[derive(Debug, serde::Serialize)]
+pub struct Submitted {
+    id: JobId,
+}
+
 // #[cfg_attr(test, axum_macros::debug_handler)]
 pub async fn post(
     State(state): State<SharedState>,
-    Json(req): Json<BuildRequest>,
-) -> Result<String, AppError<StartError>> {
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<PostQuery>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<Response, AppError<StartError>> {
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let limit = state.config.scheduler.max_request_size;
+    let reader = spool_body(&state.store, body, Some(limit))
+        .await
+        .map_err(|error| StartError::InvalidBody {
+            error: error.to_string(),
+        })?;
+    let req = state
+        .blocking
+        .run("read-build-request", move || {
+            read_request(content_type.as_deref(), reader, limit)
+        })
+        .await
+        .map_err(|_| StartError::Interrupted)??;
+
+    // Every request that was read becomes a job, so that clients can poll the outcome of builds that are invalid too.
+    let request = serde_json::to_value(&req).ok().map(|body| JobRequest {
+        hash: req.hash.clone(),
+        body,
+    });
+    let job = state.jobs.register(&req.name, tenant.0.clone(), request);
+    let id = job.id();
+    let building = run_job(state, tenant, req, job);
+    if query.detach {
+        tokio::spawn(building);
+        return Ok((StatusCode::ACCEPTED, Json(Submitted { id })).into_response());
+    }
+    Ok(building.await?.into_response())
+}
+
+/// Builds a package as `job`, and records how the build ended.
+async fn run_job(
+    state: SharedState,
+    tenant: Tenant,
+    req: BuildRequest,
+    job: JobHandle,
+) -> Result<String, StartError> {
+    let result = build(&state, tenant, req, &job).await;
+    match &result {
+        Err(error @ (StartError::Infrastructure { .. } | StartError::Disk { .. })) => {
+            job.finish_infrastructure(error.describe())
+        }
+        Err(error @ StartError::Cancelled) => job.finish_cancelled(error.describe()),
+        result => job.finish(result.as_ref().map(|_| ()).map_err(StartError::describe)),
+    }
+    result
+}
+
+/// Resumes the builds that the daemon was running when it stopped, from the requests that they were submitted with.
+pub async fn resume(state: SharedState) {
+    for interrupted in state.jobs.take_interrupted() {
+        let tenant = Tenant(interrupted.tenant.clone());
+        let request = serde_json::from_value::<BuildRequest>(interrupted.request.body.clone());
+        let job = state.jobs.resume(interrupted);
+        match request {
+            Ok(req) => {
+                tracing::info!(id = %job.id(), name = req.name, "resuming build");
+                tokio::spawn(run_job(state.clone(), tenant, req, job));
+            }
+            Err(error) => job.finish(Err(format!(
+                "failed to read the request of the build: {error}"
+            ))),
+        }
+    }
+}
+
+/// Lists the builds of the tenant of the client that are queued, running or recently finished, oldest first.
+pub async fn list(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+) -> Json<Vec<JobInfo>> {
+    let mut jobs = state.jobs.finished(tenant.scope());
+    jobs.extend(state.jobs.list(tenant.scope()));
+    Json(jobs)
+}
+
+/// Gets a build of the tenant of the client.
+pub async fn get(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<JobId>,
+) -> Result<Json<JobInfo>, AppError<JobError>> {
+    Ok(Json(state.jobs.get(id, tenant.scope())?))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct LogsQuery {
+    /// Whether to keep streaming the output of a running build until it ends.
+    #[serde(default)]
+    follow: bool,
+}
+
+/// Streams the output of a build of the tenant of the client as server-sent events: a `log` event with each line
+/// (see [`LogRecord`]), then an `end` event. A client that follows a build and falls behind receives a `lagged` event
+/// with the number of bytes of output that it missed, in place of the lines that it missed.
+pub async fn logs(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<JobId>,
+    Query(query): Query<LogsQuery>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, axum::Error>>>, AppError<JobError>> {
+    state.jobs.get(id, tenant.scope())?;
+    let (records, live) = state.logs.read(id);
+    let live = live.filter(|_| query.follow);
+    let live = stream::unfold(live, move |live| async move {
+        let mut follower = live?;
+        let followed = follower.next().await?;
+        if let Followed::Lagged { missed } = followed {
+            tracing::debug!(%id, missed, "a client following a build fell behind");
+        }
+        Some((followed, Some(follower)))
+    });
+    let events = stream::iter(records)
+        .map(Followed::Record)
+        .chain(live)
+        .map(|followed| match followed {
+            Followed::Record(record) => sse::Event::default().event("log").json_data(record),
+            Followed::Lagged { missed } => Ok(sse::Event::default()
+                .event("lagged")
+                .data(missed.to_string())),
+        })
+        .chain(stream::once(async {
+            Ok(sse::Event::default().event("end").data(""))
+        }));
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+/// Cancels a build of the tenant of the client that is running, killing its sandbox.
+pub async fn cancel(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<JobId>,
+) -> Result<(), AppError<JobError>> {
+    Ok(state.jobs.control(id, Control::Cancel, tenant.scope())?)
+}
+
+#[derive(Debug, Error)]
+pub enum FilesError {
+    #[error(transparent)]
+    Job(#[from] JobError),
+    #[error(transparent)]
+    Scratch(#[from] ScratchError),
+    #[error("the read was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+}
+
+impl ApiError for FilesError {
+    type Data = ();
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            FilesError::Job(error) => error.status_code(),
+            FilesError::Scratch(ScratchError::InvalidPath(_)) => StatusCode::BAD_REQUEST,
+            FilesError::Scratch(ScratchError::NotFound(_)) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn data(self) -> Self::Data {}
+}
+
+/// Lists the files in the scratch directory of a running build of the tenant of the client.
+pub async fn files(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<JobId>,
+) -> Result<Json<Vec<ScratchEntry>>, AppError<FilesError>> {
+    let dir = state
+        .jobs
+        .scratch(id, tenant.scope())
+        .map_err(FilesError::from)?;
+    let entries = state
+        .blocking
+        .run("list-scratch", move || scratch::list(&dir))
+        .await
+        .map_err(FilesError::from)?
+        .map_err(|error| FilesError::Scratch(error.into()))?;
+    Ok(Json(entries))
+}
+
+/// Downloads a file in the scratch directory of a running build of the tenant of the client, as it is while the build
+/// writes to it.
+pub async fn file(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+    Path((id, path)): Path<(JobId, PathBuf)>,
+) -> Result<Response, AppError<FilesError>> {
+    let dir = state
+        .jobs
+        .scratch(id, tenant.scope())
+        .map_err(FilesError::from)?;
+    let file = state
+        .blocking
+        .run("open-scratch", move || scratch::open(&dir, &path))
+        .await
+        .map_err(FilesError::from)?
+        .map_err(FilesError::from)?;
+    let body = Body::from_stream(ReaderStream::new(tokio::fs::File::from_std(file)));
+    Ok(([(header::CONTENT_TYPE, "application/octet-stream")], body).into_response())
+}
+
+/// Reports a failure of the disk of the store, which fails the build with it.
+fn disk_fault(state: &SharedState, fault: DiskFault, error: String) -> StartError {
+    state.store.report_fault(fault, error.clone());
+    StartError::Disk { fault, error }
+}
+
+/// Kills the task of an attempt that is abandoned before the task finishes, such as when the job is interrupted.
+struct KillOnDrop<'a> {
+    controller: &'a Sandbox,
+    id: Option<u64>,
+}
+
+impl Drop for KillOnDrop<'_> {
+    fn drop(&mut self) {
+        let Some(id) = self.id else {
+            return;
+        };
+        let controller = self.controller.clone();
+        tokio::spawn(async move {
+            if let Err(error) = controller.kill(id).await {
+                tracing::warn!(?error, id, "failed to kill an abandoned task");
+            }
+        });
+    }
+}
+
+/// The directory that the root file system of a build with a base is mounted on, which is removed with it.
+struct BuildRoot(PathBuf);
+
+impl BuildRoot {
+    async fn new(store: &Store) -> io::Result<Self> {
+        let path = store.temp_path("build-root");
+        tokio::fs::create_dir_all(&path).await?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for BuildRoot {
+    fn drop(&mut self) {
+        // The root is only a mount point on the host, as the sandbox mounted its file system in its own namespace.
+        if let Err(error) = std::fs::remove_dir(&self.0) {
+            tracing::warn!(?error, root = ?self.0, "failed to remove the root of a sandbox");
+        }
+    }
+}
+
+/// Builds a package as `job`.
+async fn build(
+    state: &SharedState,
+    tenant: Tenant,
+    req: BuildRequest,
+    job: &JobHandle,
+) -> Result<String, StartError> {
     let BuildRequest {
         name,
         hash,
-        lock: LockDefinition {
-            dependencies,
-            build_dependencies,
-        },
+        lock,
+        queue_timeout,
+        max_runtime,
+        overrides,
+        channel,
+        env,
     } = req;
 
-    let dependencies = dependencies
-        .into_iter()
-        .map(|(name, hash)| {
-            hash.parse()
-                .map(|v| (name.clone(), v))
-                .map_err(|_| StartError::InvalidDependencyHash { name, hash })
-        })
-        .try_collect()?;
-
-    let build_dependencies = build_dependencies
-        .into_iter()
-        .map(|(name, hash)| {
-            hash.parse()
-                .map(|v| (name.clone(), v))
-                .map_err(|_| StartError::InvalidDependencyHash { name, hash })
-        })
-        .try_collect()?;
+    let mut problems = Vec::new();
+    let mut parse_dependencies = |dependencies: &BTreeMap<String, String>| {
+        let mut result = BTreeMap::new();
+        for (name, hash) in dependencies {
+            match hash.parse() {
+                Ok(v) => {
+                    result.insert(name.clone(), v);
+                }
+                Err(_) => problems.push(BuildProblem::InvalidDependencyHash {
+                    name: name.clone(),
+                    hash: hash.clone(),
+                }),
+            }
+        }
+        result
+    };
+    let dependencies = parse_dependencies(&lock.dependencies);
+    let build_dependencies = parse_dependencies(&lock.build_dependencies);
+    let base = match lock
+        .base
+        .clone()
+        .map(|hash| hash.parse().map_err(|_| hash))
+        .transpose()
+    {
+        Ok(base) => base,
+        Err(hash) => {
+            problems.push(BuildProblem::InvalidBaseHash { hash });
+            None
+        }
+    };
+    let hash = match hash.parse() {
+        Ok(hash) if problems.is_empty() => hash,
+        Ok(_) => return Err(StartError::Invalid { problems }),
+        Err(_) => {
+            problems.insert(0, BuildProblem::InvalidHash { hash });
+            return Err(StartError::Invalid { problems });
+        }
+    };
 
-    let task = BuildTask {
+    let mut task = BuildTask {
         name,
-        hash: hash.parse().map_err(|_| StartError::InvalidHash { hash })?,
+        hash,
         dependencies,
         build_dependencies,
+        base,
+        env,
+        source_date_epoch: 0,
+        limits: state.config.scheduler.resource_limits(),
+        timeout: None,
+        scratch: None,
+        store: Some(state.store.mount()),
+        root: None,
+        base_binds: Vec::new(),
     };
 
+    if let Some((name, quota)) = tenant
+        .scope()
+        .and_then(|v| state.config.quota.tenants.get_key_value(v))
+    {
+        let store = state.store.clone();
+        let usage = state.usage.clone();
+        let report = state
+            .blocking
+            .run("report-usage", move || usage.report(&store))
+            .await
+            .map_err(|_| StartError::Interrupted)?
+            .map_err(|error| StartError::Usage {
+                error: error.to_string(),
+            })?;
+        report
+            .get(name)
+            .cloned()
+            .unwrap_or_default()
+            .check(name, quota)
+            .map_err(|error| StartError::QuotaExceeded {
+                error: error.to_string(),
+            })?;
+    }
+
+    let inputs: Vec<_> = task
+        .dependencies
+        .values()
+        .chain(task.build_dependencies.values())
+        .chain(task.base.iter())
+        .map(|&v| v.into())
+        .collect();
+    // The inputs, and the package once it is built, stay in the store until the build has finished.
+    let store = state.store.clone();
+    let rooted = [&inputs[..], &[task.hash.into()]].concat();
+    let _temp_root = state
+        .blocking
+        .run("register-temp-root", move || {
+            TempRoot::new(&store, "build", &rooted)
+        })
+        .await
+        .map_err(|_| StartError::Interrupted)?
+        .map_err(|error| match DiskFault::of(&error) {
+            Some(fault) => disk_fault(state, fault, error.to_string()),
+            None => StartError::TempRoot {
+                error: error.to_string(),
+            },
+        })?;
+
+    // Inputs that are missing from the store may be available from peers.
+    state.peers.substitute(&inputs).await;
+
     task.validate(&state.config.store)
         .await
-        .map_err(|error| StartError::ValidationError { error })?;
+        .map_err(|problems| StartError::Invalid { problems })?;
+    check_lock(state, &task, &lock).await?;
+
+    let store = state.store.clone();
+    let resolving = task.clone();
+    task.source_date_epoch = state
+        .blocking
+        .run("resolve-source-date-epoch", move || {
+            resolving.resolve_source_date_epoch(&store)
+        })
+        .await
+        .map_err(|_| StartError::Interrupted)?;
+    let store = state.store.clone();
+    let resolving = task.clone();
+    task.base_binds = state
+        .blocking
+        .run("resolve-base", move || resolving.resolve_base(&store))
+        .await
+        .map_err(|_| StartError::Interrupted)?
+        .map_err(|error| StartError::SpawnError {
+            error: format!("failed to read the base root file system: {error}"),
+        })?;
+
+    let impurities = task.environment(&state.store).impurities();
+    if !impurities.is_empty() {
+        tracing::info!(name = task.name, ?impurities, "build environment is impure");
+    }
+
+    let limits = state.scheduler.limits(queue_timeout, max_runtime);
+    // The scheduler stops waiting for a build that runs for too long, but only the sandbox can stop the build itself.
+    task.timeout = Some(limits.max_runtime);
+
+    let source = state.store.by_hash(task.hash.as_ref());
+    let context = HookContext {
+        package: &task.name,
+        source: &source,
+    };
+    hook::run(
+        &state.controller,
+        &state.store,
+        &state.config.hooks,
+        HookStage::PreBuild,
+        context,
+    )
+    .await
+    .map_err(StartError::from)?;
+
+    let result = async {
+        loop {
+            let attempt = job.attempt(async {
+                let publish = |change| {
+                    state.events.publish(Event::Sandbox {
+                        job: job.id(),
+                        name: task.name.clone(),
+                        change,
+                    })
+                };
+                let scratch = ScratchDir::new(&state.store).await.map_err(|error| {
+                    match DiskFault::of(&error) {
+                        Some(fault) => disk_fault(state, fault, error.to_string()),
+                        None => StartError::SpawnError {
+                            error: format!("failed to create the scratch directory: {error}"),
+                        },
+                    }
+                })?;
+                job.set_scratch(scratch.path());
+                let mut spawning = task.clone();
+                spawning.scratch = Some(scratch.path().to_path_buf());
+                let root = match task.base {
+                    Some(_) => Some(BuildRoot::new(&state.store).await.map_err(|error| {
+                        StartError::SpawnError {
+                            error: format!("failed to create the root directory: {error}"),
+                        }
+                    })?),
+                    None => None,
+                };
+                spawning.root = root.as_ref().map(|v| v.0.clone());
+                let spawned = state.controller.spawn(Task::Build(spawning), &[]).await;
+                let mut handle = match spawned {
+                    Ok(handle) => handle,
+                    Err(error) => {
+                        let error = error.to_string();
+                        publish(SandboxChange::Failed {
+                            error: error.clone(),
+                        });
+                        return Err(StartError::SpawnError { error });
+                    }
+                };
+                publish(SandboxChange::Started);
+                if let Some(stream) = handle.output_stream() {
+                    tokio::spawn(output::log(
+                        job.id(),
+                        task.name.clone(),
+                        stream,
+                        state.logs.clone(),
+                    ));
+                }
+
+                let mut kill = KillOnDrop {
+                    controller: &state.controller,
+                    id: Some(Sandbox::id(&handle)),
+                };
+                let result = handle.await;
+                kill.id = None;
+                match &result {
+                    Ok(()) => job.set_exit_code(0),
+                    Err(SandboxTaskError::Failed { code, .. }) => job.set_exit_code(*code),
+                    Err(_) => {}
+                }
+                publish(match &result {
+                    Ok(()) => SandboxChange::Finished,
+                    Err(error) => SandboxChange::Failed {
+                        error: error.to_string(),
+                    },
+                });
+                result.map_err(|error| match error {
+                    SandboxTaskError::Lost => StartError::Infrastructure {
+                        error: error.to_string(),
+                    },
+                    error => StartError::BuildFailed {
+                        error: error.to_string(),
+                    },
+                })
+            });
+            match state.scheduler.run(limits, attempt).await? {
+                Ok(result) => return result,
+                Err(Control::Requeue) => {
+                    tracing::info!(id = %job.id(), name = task.name, "requeueing build");
+                }
+                Err(Control::Fail(reason)) => return Err(StartError::Failed { reason }),
+                Err(Control::Cancel) => return Err(StartError::Cancelled),
+            }
+        }
+    }
+    .await;
+    let ran = job.ran();
+    // Failed builds count towards the quota too.
+    if let Some(name) = tenant.0.clone() {
+        let usage = state.usage.clone();
+        let recorded = state
+            .blocking
+            .run("record-usage", move || usage.record_build(&name, ran))
+            .await;
+        if let Err(error) = recorded.map_err(io::Error::from).and_then(|v| v) {
+            tracing::warn!(?error, name = task.name, "failed to record the build time");
+        }
+    }
+    result?;
+
+    hook::run(
+        &state.controller,
+        &state.store,
+        &state.config.hooks,
+        HookStage::PostBuild,
+        context,
+    )
+    .await
+    .map_err(StartError::from)?;
+
+    tracing::info!(
+        name = task.name,
+        ?overrides,
+        ?channel,
+        source_date_epoch = task.source_date_epoch,
+        "recording provenance"
+    );
+    let provenance = Provenance {
+        impurities,
+        reads_tracked: false,
+        overrides,
+        channel,
+        source_date_epoch: Some(task.source_date_epoch),
+    };
+    let store = state.store.clone();
+    let hash = task.hash.into();
+    let build = job.id().0;
+    state
+        .blocking
+        .run("record-provenance", move || {
+            provenance::record(&store, &hash, build, &provenance)?;
+            store.claim(&hash, tenant.scope())
+        })
+        .await
+        .map_err(|_| StartError::Interrupted)?
+        .map_err(|error| match error.disk_fault() {
+            Some(fault) => disk_fault(state, fault, error.to_string()),
+            None => StartError::Provenance {
+                error: error.to_string(),
+            },
+        })?;
 
     Ok(format!("{:?}", task))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn request() -> BuildRequest {
+        BuildRequest {
+            name: "hello".into(),
+            hash: "blake3-aaaa".into(),
+            lock: LockDefinition {
+                dependencies: [("libc".to_string(), "blake3-bbbb".to_string())].into(),
+                build_dependencies: Default::default(),
+                base: None,
+            },
+            queue_timeout: Some(10),
+            max_runtime: None,
+            overrides: BTreeSet::new(),
+            channel: None,
+            env: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn read_requests() {
+        let json = serde_json::to_vec(&request()).unwrap();
+        let req = read_request(Some("application/json; charset=utf-8"), &json[..], 1024).unwrap();
+        assert_eq!(req.lock.dependencies["libc"], "blake3-bbbb");
+        assert_eq!(req.queue_timeout, Some(10));
+
+        let mut bincode = bytes::BytesMut::new();
+        porkg_private::ser::serialize(&request(), &mut bincode).unwrap();
+        let req = read_request(Some(BINCODE), &bincode[..], 1024).unwrap();
+        assert_eq!(req.name, "hello");
+
+        assert!(matches!(
+            read_request(None, &json[..], 16),
+            Err(StartError::BodyTooLarge { limit: 16 })
+        ));
+        assert!(matches!(
+            read_request(Some(BINCODE), &bincode[..], 16),
+            Err(StartError::BodyTooLarge { limit: 16 })
+        ));
+        assert!(matches!(
+            read_request(Some("text/plain"), &json[..], 1024),
+            Err(StartError::UnsupportedMediaType { .. })
+        ));
+        assert!(matches!(
+            read_request(None, &b"{"[..], 1024),
+            Err(StartError::InvalidBody { .. })
+        ));
+    }
+}
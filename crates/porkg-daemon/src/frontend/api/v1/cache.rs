@@ -0,0 +1,121 @@
+//! HTTP caching of store exports.
+//!
+//! The content of a package never changes once it is in the store, but its metadata can (see
+//! [`crate::store::Store::claim`]), and exports include the metadata. An export is therefore identified by the hash of
+//! the package together with a digest of the metadata that it includes, and caches must revalidate it before reuse.
+//! Conditional and `HEAD` requests are answered without producing the export, which lets a standard HTTP cache or CDN
+//! in front of the daemon revalidate cheaply.
+
+use std::io;
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue, Method},
+    response::{IntoResponse, Response},
+};
+use bytes::Bytes;
+use data_encoding::HEXLOWER;
+use futures_util::stream;
+use hyper::StatusCode;
+use porkg_model::hashing::SupportedHash;
+use sha2::{Digest as _, Sha256};
+
+const CACHE_CONTROL: &str = "public, no-cache";
+
+/// The caching headers of an export of a package.
+pub struct Conditional {
+    etag: HeaderValue,
+    content_type: &'static str,
+}
+
+impl Conditional {
+    /// Identifies an export of the package `hash` that includes `metadata`, the serialized metadata of the packages in
+    /// the export.
+    pub fn new(hash: &SupportedHash, metadata: &[u8], content_type: &'static str) -> Self {
+        let digest = HEXLOWER.encode(&Sha256::digest(metadata)[..16]);
+        Self {
+            // Hashes only contain characters that are valid in a header.
+            etag: HeaderValue::from_str(&format!("\"{hash}-{digest}\"")).unwrap(),
+            content_type,
+        }
+    }
+
+    /// Whether the client already has the export, according to `If-None-Match`.
+    fn is_fresh(&self, headers: &HeaderMap) -> bool {
+        let etag = self.etag.to_str().unwrap_or_default();
+        headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            // Weak comparison applies to `If-None-Match`.
+            .map(|tag| tag.strip_prefix("W/").unwrap_or(tag))
+            .any(|tag| tag == "*" || tag == etag)
+    }
+
+    pub fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(self.content_type),
+        );
+        headers.insert(header::ETAG, self.etag.clone());
+        headers.insert(
+            header::CACHE_CONTROL,
+            HeaderValue::from_static(CACHE_CONTROL),
+        );
+        headers
+    }
+
+    /// Answers the request without the export if it is conditional and the client has the export, or if only the
+    /// headers were requested. The package must be known to exist.
+    pub fn respond(&self, method: &Method, headers: &HeaderMap) -> Option<Response> {
+        if self.is_fresh(headers) {
+            Some((StatusCode::NOT_MODIFIED, self.headers()).into_response())
+        } else if method == Method::HEAD {
+            // The length of the export isn't known without producing it, so none is sent.
+            let body = Body::from_stream(stream::empty::<Result<Bytes, io::Error>>());
+            Some((self.headers(), body).into_response())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn conditional_requests() {
+        let hash = SupportedHash::Blake3([1; 32]);
+        let cache = Conditional::new(&hash, b"{}", "application/x-tar");
+        let etag = cache.etag.to_str().unwrap().to_string();
+        let request = |tag: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::IF_NONE_MATCH, HeaderValue::from_str(tag).unwrap());
+            headers
+        };
+
+        assert!(cache.respond(&Method::GET, &HeaderMap::new()).is_none());
+        let head = cache.respond(&Method::HEAD, &HeaderMap::new()).unwrap();
+        assert_eq!(head.status(), StatusCode::OK);
+        assert_eq!(head.headers()[header::ETAG], etag);
+        assert_eq!(head.headers()[header::CACHE_CONTROL], CACHE_CONTROL);
+
+        for tag in [
+            etag.clone(),
+            format!("\"other\", W/{etag}"),
+            "*".to_string(),
+        ] {
+            let response = cache.respond(&Method::GET, &request(&tag)).unwrap();
+            assert_eq!(response.status(), StatusCode::NOT_MODIFIED, "{tag}");
+        }
+        assert!(cache.respond(&Method::GET, &request("\"other\"")).is_none());
+
+        // Once the metadata of the package changes, so does the export.
+        let changed = Conditional::new(&hash, br#"{"tenant":"a"}"#, "application/x-tar");
+        assert!(changed.respond(&Method::GET, &request(&etag)).is_none());
+    }
+}
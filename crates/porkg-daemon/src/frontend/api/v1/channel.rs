@@ -0,0 +1,166 @@
+use axum::{
+    extract::{Path, State},
+    Json,
+};
+use hyper::StatusCode;
+use porkg_model::{channel::Channel, hashing::SupportedHash, workspace::Lock};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    error::{ApiError, AppError},
+    store::{
+        channel::{self, ChannelError, PackageAdvisory},
+        StoreError,
+    },
+};
+
+use super::SharedState;
+
+#[derive(Debug, Error)]
+pub enum ChannelApiError {
+    #[error("invalid hash provided: {0}")]
+    InvalidHash(String),
+    #[error(transparent)]
+    Channel(#[from] ChannelError),
+    #[error("the request was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+}
+
+impl ApiError for ChannelApiError {
+    type Data = ();
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ChannelApiError::InvalidHash(_)
+            | ChannelApiError::Channel(
+                ChannelError::InvalidName(_) | ChannelError::InvalidIndex { .. },
+            ) => StatusCode::BAD_REQUEST,
+            ChannelApiError::Channel(
+                ChannelError::NotFound(_) | ChannelError::Store(StoreError::NotFound(_)),
+            ) => StatusCode::NOT_FOUND,
+            ChannelApiError::Channel(ChannelError::Conflict { .. }) => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn data(self) -> Self::Data {}
+}
+
+fn parse_hash(hash: String) -> Result<SupportedHash, ChannelApiError> {
+    hash.parse().map_err(|_| ChannelApiError::InvalidHash(hash))
+}
+
+/// Lists the channels, with the snapshot that each one pins.
+pub async fn list(
+    State(state): State<SharedState>,
+) -> Result<Json<Vec<Channel>>, AppError<ChannelApiError>> {
+    let store = state.store.clone();
+    let channels = state
+        .blocking
+        .run("list-channels", move || channel::list(&store))
+        .await
+        .map_err(ChannelApiError::from)?
+        .map_err(ChannelApiError::from)?;
+
+    Ok(Json(
+        channels
+            .into_iter()
+            .map(|(name, index)| Channel {
+                name,
+                index: index.to_string(),
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Debug, Serialize)]
+pub struct ResolvedChannel {
+    channel: Channel,
+    lock: Lock,
+}
+
+/// Resolves a channel to the snapshot that it pins, along with the lock of the snapshot. Both are read together, so
+/// that the lock always belongs to the snapshot even if the channel is advanced concurrently.
+pub async fn get(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+) -> Result<Json<ResolvedChannel>, AppError<ChannelApiError>> {
+    let store = state.store.clone();
+    let (name, index, lock) = state
+        .blocking
+        .run("resolve-channel", move || {
+            let index = channel::get(&store, &name)?;
+            let lock = channel::index(&store, &index)?;
+            Ok::<_, ChannelError>((name, index, lock))
+        })
+        .await
+        .map_err(ChannelApiError::from)?
+        .map_err(ChannelApiError::from)?;
+
+    Ok(Json(ResolvedChannel {
+        channel: Channel {
+            name,
+            index: index.to_string(),
+        },
+        lock,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateRequest {
+    /// The hash of the index snapshot to pin.
+    index: String,
+    /// The snapshot that the channel must currently pin for the update to succeed.
+    #[serde(default)]
+    expected: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UpdateResponse {
+    /// The snapshot that the channel pinned before, if it existed.
+    previous: Option<String>,
+}
+
+/// Pins a channel to an index snapshot.
+pub async fn put(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+    Json(request): Json<UpdateRequest>,
+) -> Result<Json<UpdateResponse>, AppError<ChannelApiError>> {
+    let index = parse_hash(request.index)?;
+    let expected = request.expected.map(parse_hash).transpose()?;
+
+    let store = state.store.clone();
+    let previous = state
+        .blocking
+        .run("update-channel", move || {
+            channel::update(&store, &name, &index, expected.as_ref())
+        })
+        .await
+        .map_err(ChannelApiError::from)?
+        .map_err(ChannelApiError::from)?;
+
+    Ok(Json(UpdateResponse {
+        previous: previous.map(|v| v.to_string()),
+    }))
+}
+
+/// Gets the advisories that the channels have for the packages in the closure of a package, such as the versions that
+/// were yanked or are vulnerable.
+pub async fn advisories(
+    State(state): State<SharedState>,
+    Path(hash): Path<String>,
+) -> Result<Json<Vec<PackageAdvisory>>, AppError<ChannelApiError>> {
+    let hash = parse_hash(hash)?;
+    let store = state.store.clone();
+    let advisories = state
+        .blocking
+        .run("read-advisories", move || {
+            channel::advisories(&store, &hash)
+        })
+        .await
+        .map_err(ChannelApiError::from)?
+        .map_err(ChannelApiError::from)?;
+    Ok(Json(advisories))
+}
@@ -0,0 +1,267 @@
+use std::path::Path;
+
+use axum::{
+    extract::{Query, State},
+    Extension, Json,
+};
+use hyper::StatusCode;
+use porkg_model::{
+    nix::NarInfoError,
+    source::{GitSource, Source},
+};
+use thiserror::Error;
+
+use crate::{
+    archive::{ArchiveError, ExtractOptions},
+    error::{ApiError, AppError},
+    fetch::FetchError,
+    frontend::auth::{Actor, Tenant},
+    secret::SecretError,
+    store::{
+        nar::NarError,
+        nix::NixImportError,
+        oci::OciError,
+        source::{self, SourceError},
+        StoreError,
+    },
+};
+
+use super::SharedState;
+
+#[derive(Debug, Error)]
+pub enum FetchSourceError {
+    #[error("failed to download the source: {0}")]
+    Fetch(#[from] FetchError),
+    #[error("failed to import the source: {0}")]
+    Import(#[from] SourceError),
+    #[error("the fetch was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+    #[error("failed to record the tenant of the package: {0}")]
+    Store(#[from] StoreError),
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct OciPullQuery {
+    /// The reference of the image, such as `debian:12`.
+    image: String,
+    /// The name to import the image as.
+    name: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct NixFetchQuery {
+    /// The base name of the Nix store path (`<hash>-<name>`).
+    path: String,
+}
+
+impl ApiError for FetchSourceError {
+    type Data = ();
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            FetchSourceError::Fetch(
+                FetchError::InvalidUrl(_)
+                | FetchError::NoMirrors
+                | FetchError::InvalidRevision(_)
+                | FetchError::Secret(SecretError::Unknown(_))
+                | FetchError::NarInfo(NarInfoError::InvalidStorePath(_))
+                | FetchError::InvalidImage(_),
+            ) => StatusCode::BAD_REQUEST,
+            FetchSourceError::Fetch(FetchError::Secret(SecretError::Origin { .. })) => {
+                StatusCode::FORBIDDEN
+            }
+            FetchSourceError::Fetch(FetchError::NotCached(_)) => StatusCode::NOT_FOUND,
+            FetchSourceError::Fetch(
+                FetchError::Client(_)
+                | FetchError::Body(_)
+                | FetchError::Status { .. }
+                | FetchError::TooManyRedirects(_)
+                | FetchError::HashMismatch { .. }
+                | FetchError::Git { .. }
+                | FetchError::NarInfo(_)
+                | FetchError::InvalidNarInfo(_)
+                | FetchError::ReferenceCycle
+                | FetchError::NixImport(
+                    NixImportError::Nar(
+                        NarError::Unexpected { .. }
+                        | NarError::InvalidName(_)
+                        | NarError::TooLong
+                        | NarError::TooDeep,
+                    )
+                    | NixImportError::InvalidNarInfo(_)
+                    | NixImportError::UnsupportedCompression(_)
+                    | NixImportError::SizeMismatch { .. }
+                    | NixImportError::HashMismatch { .. }
+                    | NixImportError::FileSizeMismatch { .. }
+                    | NixImportError::FileHashMismatch { .. },
+                )
+                | FetchError::Oci(
+                    OciError::Serialization(_)
+                    | OciError::InvalidLayout(_)
+                    | OciError::DigestMismatch { .. }
+                    | OciError::UnsupportedMediaType(_)
+                    | OciError::Archive(
+                        ArchiveError::UnsafePath(_) | ArchiveError::UnsafeLink { .. },
+                    ),
+                ),
+            )
+            | FetchSourceError::Import(SourceError::Archive(
+                ArchiveError::UnsafePath(_)
+                | ArchiveError::UnsafeLink { .. }
+                | ArchiveError::UnknownFormat
+                | ArchiveError::Zip(_),
+            )) => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn data(self) -> Self::Data {}
+}
+
+/// Gets a file name for the download, so that the archive format can be recognized from it.
+fn file_name(source: &Source) -> &str {
+    source
+        .urls
+        .first()
+        .and_then(|v| v.rsplit('/').next())
+        .map(|v| v.split(['?', '#']).next().unwrap_or(v))
+        .filter(|v| !v.is_empty() && *v != "." && *v != "..")
+        .unwrap_or("source")
+}
+
+async fn remove_staging(staging: &Path) {
+    if tokio::fs::try_exists(staging).await.unwrap_or_default() {
+        tokio::fs::remove_dir_all(staging)
+            .await
+            .inspect_err(|error| tracing::warn!(?error, ?staging, "failed to clean up staging"))
+            .ok();
+    }
+}
+
+/// Downloads a source archive from the first mirror that provides it, and imports it into the store.
+pub async fn post(
+    State(state): State<SharedState>,
+    Extension(actor): Extension<Actor>,
+    Json(source): Json<Source>,
+) -> Result<String, AppError<FetchSourceError>> {
+    let staging = state.store.temp_path("fetch");
+    let result = fetch_staged(&state, &actor, source, &staging).await;
+    remove_staging(&staging).await;
+    Ok(result?)
+}
+
+async fn fetch_staged(
+    state: &SharedState,
+    actor: &Actor,
+    source: Source,
+    staging: &Path,
+) -> Result<String, FetchSourceError> {
+    tokio::fs::create_dir_all(staging)
+        .await
+        .map_err(FetchError::from)?;
+    let path = staging.join(file_name(&source));
+    state.fetcher.fetch(&source, &path).await?;
+
+    let store = state.store.clone().with_origin(&actor.0, "fetch");
+    let hash = state
+        .blocking
+        .run("import-source", move || {
+            let options = ExtractOptions {
+                strip_components: source.strip_components,
+                ..Default::default()
+            };
+            source::import_archive(&store, &source.name, &path, None, options)
+        })
+        .await??;
+
+    Ok(hash.to_string())
+}
+
+/// Exports a revision of a git repository from the first mirror that provides it, and imports it into the store.
+pub async fn post_git(
+    State(state): State<SharedState>,
+    Extension(actor): Extension<Actor>,
+    Json(source): Json<GitSource>,
+) -> Result<String, AppError<FetchSourceError>> {
+    let staging = state.store.temp_path("git");
+    let result = fetch_git_staged(&state, &actor, source, &staging).await;
+    remove_staging(&staging).await;
+    Ok(result?)
+}
+
+async fn fetch_git_staged(
+    state: &SharedState,
+    actor: &Actor,
+    source: GitSource,
+    staging: &Path,
+) -> Result<String, FetchSourceError> {
+    let root = state.store.temp_path("git-root");
+    state
+        .fetcher
+        .fetch_git(
+            &state.controller,
+            &source,
+            &root,
+            &source::source_path(staging),
+        )
+        .await?;
+
+    let store = state.store.clone().with_origin(&actor.0, "fetch-git");
+    let staging = staging.to_path_buf();
+    let hash = state
+        .blocking
+        .run("import-source", move || {
+            source::import_staged_tree(&store, &source.name, &staging, None)
+        })
+        .await??;
+
+    Ok(hash.to_string())
+}
+
+/// Fetches the Nix store path named by the `path` query parameter, with everything that it references, from the
+/// configured binary caches.
+pub async fn post_nix(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(actor): Extension<Actor>,
+    Query(query): Query<NixFetchQuery>,
+) -> Result<String, AppError<FetchSourceError>> {
+    let store = state.store.clone().with_origin(&actor.0, "fetch-nix");
+    let hash = state
+        .fetcher
+        .fetch_nix(&store, &query.path)
+        .await
+        .map_err(FetchSourceError::from)?;
+    state
+        .blocking
+        .run("claim", move || store.claim(&hash, tenant.scope()))
+        .await
+        .map_err(FetchSourceError::from)?
+        .map_err(FetchSourceError::from)?;
+
+    Ok(hash.to_string())
+}
+
+/// Pulls the image named by the `image` query parameter from its registry, and imports it as a root file system
+/// named by the `name` query parameter.
+pub async fn post_oci(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(actor): Extension<Actor>,
+    Query(query): Query<OciPullQuery>,
+) -> Result<String, AppError<FetchSourceError>> {
+    let store = state.store.clone().with_origin(&actor.0, "pull-oci");
+    let hash = state
+        .fetcher
+        .pull_oci(&store, &query.image, &query.name)
+        .await
+        .map_err(FetchSourceError::from)?;
+    state
+        .blocking
+        .run("claim", move || store.claim(&hash, tenant.scope()))
+        .await
+        .map_err(FetchSourceError::from)?
+        .map_err(FetchSourceError::from)?;
+
+    Ok(hash.to_string())
+}
@@ -0,0 +1,38 @@
+use axum::Json;
+use hyper::StatusCode;
+use porkg_model::{
+    lint::{self, Finding},
+    package::Package,
+};
+use thiserror::Error;
+
+use crate::error::{ApiError, AppError};
+
+#[derive(Debug, Error)]
+pub enum LintError {
+    #[error("invalid manifest: {0}")]
+    InvalidManifest(#[from] toml::de::Error),
+}
+
+impl ApiError for LintError {
+    type Data = ();
+
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    fn data(self) -> Self::Data {}
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct LintResponse {
+    findings: Vec<Finding>,
+}
+
+/// Lints the `porkg.toml` manifest in the body.
+pub async fn post(body: String) -> Result<Json<LintResponse>, AppError<LintError>> {
+    let package: Package = toml::from_str(&body).map_err(LintError::from)?;
+    Ok(Json(LintResponse {
+        findings: lint::lint(&package),
+    }))
+}
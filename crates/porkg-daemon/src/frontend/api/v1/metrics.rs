@@ -0,0 +1,23 @@
+use std::collections::BTreeMap;
+
+use axum::{extract::State, Json};
+
+use crate::{degraded::DegradedStatus, sync::health::SubstituterMetrics};
+
+use super::SharedState;
+
+#[derive(Debug, serde::Serialize)]
+pub struct Metrics {
+    /// The substituters that recently lacked packages or failed, by name.
+    substituters: BTreeMap<String, SubstituterMetrics>,
+    /// Why builds are paused, if they are (see [`crate::degraded`]).
+    degraded: Option<DegradedStatus>,
+}
+
+/// Reports the state of the daemon.
+pub async fn get(State(state): State<SharedState>) -> Json<Metrics> {
+    Json(Metrics {
+        substituters: state.peers.substituter_metrics(),
+        degraded: state.degraded.status(),
+    })
+}
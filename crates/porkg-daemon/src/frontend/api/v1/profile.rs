@@ -0,0 +1,142 @@
+use std::{io, time::Duration};
+
+use axum::{
+    extract::{Path, Query, State},
+    Json,
+};
+use hyper::StatusCode;
+use porkg_model::{
+    log::LogRecord,
+    profile::{ClosureDiff, SwitchRequest, Switched},
+};
+use thiserror::Error;
+
+use crate::{
+    backend::activation::{self, ActivationError},
+    error::{ApiError, AppError},
+    store::{
+        gc,
+        profile::{self, ProfileError},
+        StoreError,
+    },
+};
+
+use super::SharedState;
+
+#[derive(Debug, Error)]
+pub enum ProfileApiError {
+    #[error(transparent)]
+    Profile(#[from] ProfileError),
+    #[error(transparent)]
+    Activation(#[from] ActivationError),
+    #[error("the request was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+}
+
+impl ApiError for ProfileApiError {
+    /// The end of the output of an activation command that failed.
+    type Data = Vec<LogRecord>;
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ProfileApiError::Profile(ProfileError::InvalidName(_)) => StatusCode::BAD_REQUEST,
+            ProfileApiError::Profile(
+                ProfileError::GenerationNotFound { .. }
+                | ProfileError::Store(StoreError::NotFound(_)),
+            ) => StatusCode::NOT_FOUND,
+            ProfileApiError::Activation(
+                ActivationError::Failed { .. } | ActivationError::Timeout { .. },
+            ) => StatusCode::UNPROCESSABLE_ENTITY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn data(self) -> Self::Data {
+        match self {
+            ProfileApiError::Activation(
+                ActivationError::Failed { output, .. } | ActivationError::Timeout { output, .. },
+            ) => output,
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct DiffQuery {
+    from: u64,
+    to: u64,
+}
+
+/// Compares the closures of two generations of a profile, such as before switching to a new one.
+pub async fn diff(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+    Query(query): Query<DiffQuery>,
+) -> Result<Json<ClosureDiff>, AppError<ProfileApiError>> {
+    let store = state.store.clone();
+    let diff = state
+        .blocking
+        .run("diff-profile", move || {
+            profile::diff(&store, &name, query.from, query.to)
+        })
+        .await
+        .map_err(ProfileApiError::from)?
+        .map_err(ProfileApiError::from)?;
+    Ok(Json(diff))
+}
+
+/// Switches a profile to one of its generations, running the activation commands of its closure. If one of them fails,
+/// the profile is switched back to the generation that it was on before.
+pub async fn switch(
+    State(state): State<SharedState>,
+    Path(name): Path<String>,
+    Json(request): Json<SwitchRequest>,
+) -> Result<Json<Switched>, AppError<ProfileApiError>> {
+    let generation = request.generation;
+    let store = state.store.clone();
+    let profile_name = name.clone();
+    let (switch, tasks) = state
+        .blocking
+        .run("switch-profile", move || -> Result<_, ProfileApiError> {
+            let switch = profile::prepare_switch(&store, &profile_name, generation)?;
+            let tree = gc::generation_path(&store, &profile_name, generation);
+            let tasks = activation::prepare(&store, &switch.closure, &tree, generation)?;
+            gc::set_current(&store, &profile_name, Some(generation)).map_err(ProfileError::from)?;
+            Ok((switch, tasks))
+        })
+        .await
+        .map_err(ProfileApiError::from)??;
+
+    let timeout = Duration::from_secs(state.config.hooks.timeout);
+    let mut activated = Vec::new();
+    for (package, task) in tasks {
+        if let Err(error) = activation::run(&state.controller, &package, task, timeout).await {
+            tracing::warn!(
+                ?error,
+                profile = name,
+                generation,
+                "rolling back a profile switch"
+            );
+            let store = state.store.clone();
+            let previous = switch.previous;
+            let rollback = state
+                .blocking
+                .run("rollback-profile", move || {
+                    gc::set_current(&store, &name, previous)
+                })
+                .await;
+            if let Err(error) = rollback.map_err(io::Error::other).and_then(|v| v) {
+                tracing::error!(?error, "failed to roll back a profile switch");
+            }
+            return Err(ProfileApiError::from(error).into());
+        }
+        activated.push(package);
+    }
+
+    Ok(Json(Switched {
+        previous: switch.previous,
+        generation,
+        diff: switch.diff,
+        activated,
+    }))
+}
@@ -0,0 +1,366 @@
+use std::{io, os::fd::AsRawFd as _, path::PathBuf};
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, HeaderValue},
+    response::Response,
+};
+use hyper::StatusCode;
+use hyper_util::rt::TokioIo;
+use porkg_model::{
+    hashing::SupportedHash,
+    log::LogStream,
+    run::{Frame, RunRequest, MAX_FRAME_SIZE, UPGRADE},
+};
+use porkg_private::{
+    io::{into_async, pair, SocketOptions},
+    sandbox::SandboxBackend as _,
+};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _},
+    net::UnixStream,
+};
+
+use crate::{
+    backend::{run::RunTask, Task},
+    error::{ApiError, AppError},
+    store::{
+        gc::temp::TempRoot,
+        wrapper::{WrapperError, Wrappers},
+        StoreError,
+    },
+};
+
+use super::SharedState;
+
+/// The exit code that is reported when the sandbox exits without reporting the exit code of the command.
+const SANDBOX_FAILED: i32 = 255;
+
+#[derive(Debug, Error, serde::Serialize)]
+pub enum RunError {
+    #[error("invalid hash provided: {hash}")]
+    InvalidHash { hash: String },
+    #[error("package {hash} is not in the store")]
+    NotFound { hash: String },
+    #[error("package {name} has no executable {executable}")]
+    NoExecutable { name: String, executable: String },
+    #[error("package {name} has no default executable")]
+    NoDefaultExecutable { name: String },
+    #[error("invalid executable")]
+    InvalidExecutable { error: String },
+    #[error("invalid run request")]
+    InvalidBody { error: String },
+    #[error("run requests must upgrade the connection to {protocol}")]
+    UpgradeRequired { protocol: &'static str },
+    #[error("failed to read the package")]
+    Store { error: String },
+    #[error("failed to start the sandbox")]
+    SpawnError { error: String },
+    #[error("preparing the sandbox was interrupted")]
+    Interrupted,
+}
+
+impl From<StoreError> for RunError {
+    fn from(value: StoreError) -> Self {
+        match value {
+            StoreError::NotFound(hash) => RunError::NotFound {
+                hash: hash.to_string(),
+            },
+            other => RunError::Store {
+                error: other.to_string(),
+            },
+        }
+    }
+}
+
+impl From<WrapperError> for RunError {
+    fn from(value: WrapperError) -> Self {
+        RunError::InvalidExecutable {
+            error: value.to_string(),
+        }
+    }
+}
+
+impl From<io::Error> for RunError {
+    fn from(value: io::Error) -> Self {
+        RunError::Store {
+            error: value.to_string(),
+        }
+    }
+}
+
+impl ApiError for RunError {
+    type Data = Self;
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            RunError::NotFound { .. }
+            | RunError::NoExecutable { .. }
+            | RunError::NoDefaultExecutable { .. } => StatusCode::NOT_FOUND,
+            RunError::InvalidExecutable { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            RunError::UpgradeRequired { .. } => StatusCode::UPGRADE_REQUIRED,
+            RunError::Store { .. } | RunError::SpawnError { .. } | RunError::Interrupted => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            _ => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    fn data(self) -> Self::Data {
+        self
+    }
+}
+
+/// Runs the default executable of a package (see [`porkg_model::run`] for the protocol).
+pub async fn post_default(
+    State(state): State<SharedState>,
+    Path(hash): Path<String>,
+    request: Request,
+) -> Result<Response, AppError<RunError>> {
+    start(state, hash, None, request).await
+}
+
+/// Runs a named executable of a package (see [`porkg_model::run`] for the protocol).
+pub async fn post(
+    State(state): State<SharedState>,
+    Path((hash, executable)): Path<(String, String)>,
+    request: Request,
+) -> Result<Response, AppError<RunError>> {
+    start(state, hash, Some(executable), request).await
+}
+
+async fn start(
+    state: SharedState,
+    hash: String,
+    executable: Option<String>,
+    mut request: Request,
+) -> Result<Response, AppError<RunError>> {
+    let upgrade = request.headers().get(header::UPGRADE);
+    if !upgrade.is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(UPGRADE.as_bytes())) {
+        return Err(RunError::UpgradeRequired { protocol: UPGRADE }.into());
+    }
+    let on_upgrade = hyper::upgrade::on(&mut request);
+    let limit = state.config.scheduler.max_request_size;
+    let body = axum::body::to_bytes(request.into_body(), limit.try_into().unwrap_or(usize::MAX))
+        .await
+        .map_err(|error| RunError::InvalidBody {
+            error: error.to_string(),
+        })?;
+    let request: RunRequest =
+        serde_json::from_slice(&body).map_err(|error| RunError::InvalidBody {
+            error: error.to_string(),
+        })?;
+    let hash: SupportedHash = hash.parse().map_err(|_| RunError::InvalidHash { hash })?;
+
+    let store = state.store.clone();
+    let (task, temp_root) = state
+        .blocking
+        .run("prepare-run", move || -> Result<_, RunError> {
+            // The closure stays in the store until the command exits.
+            let temp_root = TempRoot::new(&store, "run", &[hash])?;
+            let closure = store.closure(&hash)?;
+            let Some((_, info)) = closure.iter().find(|(v, _)| *v == hash) else {
+                return Err(RunError::NotFound {
+                    hash: hash.to_string(),
+                });
+            };
+            let (name, executable) = match &executable {
+                Some(name) => {
+                    let executable =
+                        info.executables
+                            .get(name)
+                            .ok_or_else(|| RunError::NoExecutable {
+                                name: info.name.clone(),
+                                executable: name.clone(),
+                            })?;
+                    (name.as_str(), executable)
+                }
+                None => {
+                    let executable =
+                        info.executable
+                            .as_ref()
+                            .ok_or_else(|| RunError::NoDefaultExecutable {
+                                name: info.name.clone(),
+                            })?;
+                    (info.name.as_str(), executable)
+                }
+            };
+
+            let mut wrappers = Wrappers::new(&store.by_hash(&hash), &store.logical(&hash))?;
+            for (reference, reference_info) in &closure {
+                if *reference != hash && info.references.contains(reference) {
+                    wrappers.add_reference(
+                        &reference_info.name,
+                        &store.by_hash(reference),
+                        &store.logical(reference),
+                    )?;
+                }
+            }
+            let command = wrappers.command(name, executable)?;
+            let mut env = command.env;
+            env.extend(
+                command
+                    .search_paths
+                    .into_iter()
+                    .map(|(variable, value)| (variable.to_string(), value)),
+            );
+            let mut exec = command.exec;
+            exec.extend(request.args);
+
+            let root = store.temp_path("run");
+            std::fs::create_dir_all(&root)?;
+            let task = RunTask {
+                exec,
+                env,
+                root,
+                closure: closure.iter().map(|(v, _)| store.by_hash(v)).collect(),
+                store: store.mount(),
+                profile: None,
+                binds: Vec::new(),
+                isolate_network: false,
+            };
+            Ok((task, temp_root))
+        })
+        .await
+        .map_err(|_| RunError::Interrupted)??;
+    let root = task.root.clone();
+
+    let result = spawn(&state, task).await;
+    let [stdin, stdout, stderr, status] = match result {
+        Ok(v) => v,
+        Err(error) => {
+            remove_root(root);
+            return Err(error.into());
+        }
+    };
+
+    tokio::spawn(async move {
+        let mut writer: Box<dyn AsyncWrite + Send + Unpin> = match on_upgrade.await {
+            Ok(upgraded) => {
+                let (reader, writer) = tokio::io::split(TokioIo::new(upgraded));
+                tokio::spawn(pass_stdin(reader, stdin));
+                Box::new(writer)
+            }
+            Err(error) => {
+                // Without stdin the command is likely to exit soon, and the root can only be removed after it has.
+                tracing::debug!(?error, "failed to upgrade the connection of a run request");
+                Box::new(tokio::io::sink())
+            }
+        };
+        let code = forward(stdout, stderr, status, &mut writer)
+            .await
+            .unwrap_or_else(|error| {
+                tracing::debug!(?error, "failed to forward the output of a command");
+                SANDBOX_FAILED
+            });
+        let mut buf = Vec::new();
+        Frame::Exit(code).encode(&mut buf);
+        if let Err(error) = async {
+            writer.write_all(&buf).await?;
+            writer.shutdown().await
+        }
+        .await
+        {
+            tracing::debug!(?error, "failed to report the exit code of a command");
+        }
+        remove_root(root);
+        drop(temp_root);
+    });
+
+    let mut response = Response::new(axum::body::Body::empty());
+    *response.status_mut() = StatusCode::SWITCHING_PROTOCOLS;
+    let headers = response.headers_mut();
+    headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+    headers.insert(header::UPGRADE, HeaderValue::from_static(UPGRADE));
+    Ok(response)
+}
+
+/// Passes the input of the client on to the command, ending its input when the client shuts down its side.
+async fn pass_stdin(mut reader: impl AsyncRead + Unpin, mut stdin: UnixStream) {
+    if let Err(error) = tokio::io::copy(&mut reader, &mut stdin).await {
+        tracing::debug!(?error, "failed to pass on the standard input of a command");
+    }
+    stdin.shutdown().await.ok();
+}
+
+/// Starts the sandbox, returning the streams that are connected to the standard input, output and error of the command
+/// and the stream that the sandbox reports its exit code on.
+async fn spawn(state: &SharedState, task: RunTask) -> Result<[UnixStream; 4], RunError> {
+    let mut ours = Vec::new();
+    let mut theirs = Vec::new();
+    for _ in 0..4 {
+        // The command blocks on its side of the streams.
+        let (host, sandbox) = pair(SocketOptions::default())?;
+        ours.push(into_async(host)?);
+        theirs.push(sandbox);
+    }
+    let fds: Vec<_> = theirs.iter().map(|v| v.as_raw_fd()).collect();
+    state
+        .controller
+        .spawn(Task::Run(task), &fds)
+        .await
+        .map_err(|error| RunError::SpawnError {
+            error: error.to_string(),
+        })?;
+    // The sandbox holds its own copies now, so that the streams end when it exits.
+    drop(theirs);
+    Ok(ours.try_into().unwrap())
+}
+
+/// Forwards the output of the command as frames until the sandbox exits, returning the exit code of the command.
+async fn forward(
+    stdout: UnixStream,
+    stderr: UnixStream,
+    mut status: UnixStream,
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> io::Result<i32> {
+    let mut streams = [
+        (LogStream::Stdout, Some(stdout)),
+        (LogStream::Stderr, Some(stderr)),
+    ];
+    let mut stdout_buf = vec![0u8; MAX_FRAME_SIZE];
+    let mut stderr_buf = vec![0u8; MAX_FRAME_SIZE];
+    let mut frame = Vec::new();
+    while streams.iter().any(|(_, v)| v.is_some()) {
+        let [(_, stdout), (_, stderr)] = &mut streams;
+        let (stream, read) = tokio::select! {
+            read = read_some(stdout, &mut stdout_buf) => (LogStream::Stdout, read?),
+            read = read_some(stderr, &mut stderr_buf) => (LogStream::Stderr, read?),
+        };
+        let (index, buf) = match stream {
+            LogStream::Stdout => (0, &stdout_buf),
+            LogStream::Stderr => (1, &stderr_buf),
+        };
+        if read == 0 {
+            streams[index].1 = None;
+            continue;
+        }
+        frame.clear();
+        Frame::Output {
+            stream,
+            data: buf[..read].to_vec(),
+        }
+        .encode(&mut frame);
+        writer.write_all(&frame).await?;
+    }
+
+    let mut code = [0u8; 4];
+    status.read_exact(&mut code).await?;
+    Ok(i32::from_be_bytes(code))
+}
+
+/// Reads from a stream that hasn't ended yet, never completing for one that has.
+async fn read_some(stream: &mut Option<UnixStream>, buf: &mut [u8]) -> io::Result<usize> {
+    match stream {
+        Some(stream) => stream.read(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+fn remove_root(root: PathBuf) {
+    // The root is only a mount point on the host, as the sandbox mounted its file system in its own namespace.
+    if let Err(error) = std::fs::remove_dir(&root) {
+        tracing::warn!(?error, ?root, "failed to remove the root of a sandbox");
+    }
+}
@@ -0,0 +1,506 @@
+use std::io::BufReader;
+
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{HeaderMap, Method},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use hyper::StatusCode;
+use porkg_model::{
+    hashing::SupportedHash,
+    nix::{NarInfo, NarInfoError},
+    provenance::Provenance,
+    time::Timestamp,
+};
+use thiserror::Error;
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    archive::{ArchiveError, ExtractOptions},
+    error::{ApiError, AppError},
+    frontend::auth::{Actor, Tenant},
+    store::{
+        gc::{self, GcError},
+        history,
+        nix::{import_nar, NixImportError},
+        oci::{self, OciError},
+        provenance,
+        source::{self, SourceError},
+        Store, StoreError,
+    },
+};
+
+use super::{cache::Conditional, spool_body, spool_body_to, SharedState};
+
+#[derive(Debug, serde::Deserialize)]
+pub struct NixImportQuery {
+    narinfo: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct OciImportQuery {
+    name: String,
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct SourceImportQuery {
+    name: String,
+    #[serde(default)]
+    strip_components: usize,
+}
+
+#[derive(Debug, Error)]
+pub enum ImportError {
+    #[error("invalid narinfo: {0}")]
+    InvalidNarInfo(#[from] NarInfoError),
+    #[error("failed to import the nar: {0}")]
+    Import(#[from] NixImportError),
+    #[error("failed to import the image: {0}")]
+    Oci(#[from] OciError),
+    #[error("failed to import the source: {0}")]
+    Source(#[from] SourceError),
+    #[error("failed to record the tenant of the package: {0}")]
+    Store(#[from] StoreError),
+    #[error("the import was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+    #[error("failed to receive the import: {0}")]
+    Body(#[from] std::io::Error),
+}
+
+impl ApiError for ImportError {
+    type Data = ();
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ImportError::InvalidNarInfo(_)
+            | ImportError::Import(
+                NixImportError::Nar(_)
+                | NixImportError::InvalidNarInfo(_)
+                | NixImportError::UnsupportedCompression(_)
+                | NixImportError::SizeMismatch { .. }
+                | NixImportError::HashMismatch { .. }
+                | NixImportError::FileSizeMismatch { .. }
+                | NixImportError::FileHashMismatch { .. },
+            )
+            | ImportError::Oci(
+                OciError::Serialization(_)
+                | OciError::InvalidLayout(_)
+                | OciError::DigestMismatch { .. }
+                | OciError::UnsupportedMediaType(_)
+                | OciError::Archive(ArchiveError::UnsafePath(_) | ArchiveError::UnsafeLink { .. }),
+            )
+            | ImportError::Source(SourceError::Archive(
+                ArchiveError::UnsafePath(_)
+                | ArchiveError::UnsafeLink { .. }
+                | ArchiveError::UnknownFormat
+                | ArchiveError::Zip(_),
+            )) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn data(self) -> Self::Data {}
+}
+
+/// Imports a NAR (the request body) described by the `narinfo` query parameter, compressed as it says.
+pub async fn import_nix(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(actor): Extension<Actor>,
+    Query(query): Query<NixImportQuery>,
+    body: Body,
+) -> Result<String, AppError<ImportError>> {
+    let info: NarInfo = query.narinfo.parse().map_err(ImportError::from)?;
+    let store = state.store.clone().with_origin(&actor.0, "import-nix");
+    let reader = spool_body(&store, body, None)
+        .await
+        .map_err(ImportError::from)?;
+
+    let hash = state
+        .blocking
+        .run("import-nar", move || -> Result<_, ImportError> {
+            let hash = import_nar(&store, &info, BufReader::new(reader))?;
+            store.claim(&hash, tenant.scope())?;
+            Ok(hash)
+        })
+        .await
+        .map_err(ImportError::from)??;
+
+    Ok(hash.to_string())
+}
+
+/// Imports an OCI image layout archive (the request body) as a root file system named by the `name` query
+/// parameter.
+pub async fn import_oci(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(actor): Extension<Actor>,
+    Query(query): Query<OciImportQuery>,
+    body: Body,
+) -> Result<String, AppError<ImportError>> {
+    let store = state.store.clone().with_origin(&actor.0, "import-oci");
+    let reader = spool_body(&store, body, None)
+        .await
+        .map_err(ImportError::from)?;
+    let hash = state
+        .blocking
+        .run("import-oci", move || -> Result<_, ImportError> {
+            let hash = oci::import(&store, &query.name, BufReader::new(reader))?;
+            store.claim(&hash, tenant.scope())?;
+            Ok(hash)
+        })
+        .await
+        .map_err(ImportError::from)??;
+
+    Ok(hash.to_string())
+}
+
+/// Imports a source archive (the request body) as the package named by the `name` query parameter. The format of
+/// the archive is detected from its contents.
+pub async fn import_source(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(actor): Extension<Actor>,
+    Query(query): Query<SourceImportQuery>,
+    body: Body,
+) -> Result<String, AppError<ImportError>> {
+    let store = state.store.clone().with_origin(&actor.0, "import-source");
+    // Zip archives can't be read as a stream, so the archive is imported from a file.
+    let path = store.temp_path("source-archive");
+    let result = import_source_from(&state, store, tenant, query, body, &path).await;
+    tokio::fs::remove_file(&path).await.ok();
+    Ok(result?.to_string())
+}
+
+async fn import_source_from(
+    state: &SharedState,
+    store: Store,
+    tenant: Tenant,
+    query: SourceImportQuery,
+    body: Body,
+    path: &std::path::Path,
+) -> Result<SupportedHash, ImportError> {
+    spool_body_to(path, body, None).await?;
+    let path = path.to_path_buf();
+    state
+        .blocking
+        .run("import-source", move || -> Result<_, ImportError> {
+            let options = ExtractOptions {
+                strip_components: query.strip_components,
+                ..Default::default()
+            };
+            let hash = source::import_archive(&store, &query.name, &path, None, options)?;
+            store.claim(&hash, tenant.scope())?;
+            Ok(hash)
+        })
+        .await?
+}
+
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("invalid hash provided: {0}")]
+    InvalidHash(String),
+    #[error("failed to export the image: {0}")]
+    Export(#[from] OciError),
+    #[error("the export was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+}
+
+impl ApiError for ExportError {
+    type Data = ();
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ExportError::InvalidHash(_) | ExportError::Export(OciError::InvalidExecutable(_)) => {
+                StatusCode::BAD_REQUEST
+            }
+            ExportError::Export(OciError::Store(StoreError::NotFound(_))) => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn data(self) -> Self::Data {}
+}
+
+/// Exports the closure of a package as an OCI image layout archive.
+pub async fn export_oci(
+    State(state): State<SharedState>,
+    Path(hash): Path<String>,
+    method: Method,
+    headers: HeaderMap,
+) -> Result<Response, AppError<ExportError>> {
+    let hash: SupportedHash = hash.parse().map_err(|_| ExportError::InvalidHash(hash))?;
+    // The image includes the metadata of every package in the closure.
+    let store = state.store.clone();
+    let metadata = state
+        .blocking
+        .run("read-closure", move || {
+            let closure = store.closure(&hash)?;
+            Ok::<_, StoreError>(serde_json::to_vec(&closure)?)
+        })
+        .await
+        .map_err(ExportError::from)?
+        .map_err(|error| ExportError::Export(error.into()))?;
+    let cache = Conditional::new(&hash, &metadata, "application/x-tar");
+    if let Some(response) = cache.respond(&method, &headers) {
+        return Ok(response);
+    }
+
+    let store = state.store.clone();
+    let path = store.temp_path("oci-export");
+    let file = state
+        .blocking
+        .run("export-oci", move || {
+            let result = std::fs::create_dir_all(path.parent().unwrap_or(&path))
+                .and_then(|_| {
+                    std::fs::File::options()
+                        .read(true)
+                        .write(true)
+                        .create_new(true)
+                        .open(&path)
+                })
+                .map_err(OciError::from)
+                .and_then(|mut file| {
+                    oci::export(&store, &hash, &mut file)?;
+                    std::io::Seek::rewind(&mut file)?;
+                    Ok(file)
+                });
+            // The open file remains readable after it is unlinked.
+            std::fs::remove_file(&path).ok();
+            result
+        })
+        .await
+        .map_err(ExportError::from)?
+        .map_err(ExportError::from)?;
+
+    let body = Body::from_stream(ReaderStream::new(tokio::fs::File::from_std(file)));
+    Ok((cache.headers(), body).into_response())
+}
+
+#[derive(Debug, Error)]
+pub enum ProvenanceError {
+    #[error("invalid hash provided: {0}")]
+    InvalidHash(String),
+    #[error("invalid build provided: {0}")]
+    InvalidBuild(String),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("no provenance was recorded for {0}")]
+    Unrecorded(SupportedHash),
+    #[error("no provenance was recorded for build {build} of {hash}")]
+    UnrecordedBuild { hash: SupportedHash, build: u64 },
+    #[error("the read was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+}
+
+impl ApiError for ProvenanceError {
+    type Data = ();
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ProvenanceError::InvalidHash(_) | ProvenanceError::InvalidBuild(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ProvenanceError::Store(StoreError::NotFound(_))
+            | ProvenanceError::Unrecorded(_)
+            | ProvenanceError::UnrecordedBuild { .. } => StatusCode::NOT_FOUND,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn data(self) -> Self::Data {}
+}
+
+/// Gets the impurity report that was recorded when a package was added to the store.
+pub async fn provenance(
+    State(state): State<SharedState>,
+    Path(hash): Path<String>,
+) -> Result<Json<Provenance>, AppError<ProvenanceError>> {
+    let hash: SupportedHash = hash
+        .parse()
+        .map_err(|_| ProvenanceError::InvalidHash(hash))?;
+
+    let store = state.store.clone();
+    let info = state
+        .blocking
+        .run("read-info", move || store.info(&hash))
+        .await
+        .map_err(ProvenanceError::from)?
+        .map_err(ProvenanceError::from)?;
+
+    Ok(Json(
+        info.provenance.ok_or(ProvenanceError::Unrecorded(hash))?,
+    ))
+}
+
+/// Gets the provenance that was recorded for a build of a package, by the id of the build job.
+pub async fn build_provenance(
+    State(state): State<SharedState>,
+    Path((hash, build)): Path<(String, String)>,
+) -> Result<Json<Provenance>, AppError<ProvenanceError>> {
+    let hash: SupportedHash = hash
+        .parse()
+        .map_err(|_| ProvenanceError::InvalidHash(hash))?;
+    let build: u64 = build
+        .parse()
+        .map_err(|_| ProvenanceError::InvalidBuild(build))?;
+
+    let store = state.store.clone();
+    let recorded = state
+        .blocking
+        .run("read-provenance", move || {
+            provenance::read(&store, &hash, build)
+        })
+        .await
+        .map_err(ProvenanceError::from)?
+        .map_err(ProvenanceError::from)?;
+
+    Ok(Json(
+        recorded.ok_or(ProvenanceError::UnrecordedBuild { hash, build })?,
+    ))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct DeleteQuery {
+    #[serde(default)]
+    recursive: bool,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct DeleteResponse {
+    packages: Vec<String>,
+    bytes: u64,
+    deleted: bool,
+}
+
+#[derive(Debug, Error)]
+pub enum DeleteError {
+    #[error("invalid hash provided: {0}")]
+    InvalidHash(String),
+    #[error("failed to delete the package: {0}")]
+    Gc(#[from] GcError),
+    #[error("the deletion was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+}
+
+impl ApiError for DeleteError {
+    type Data = ();
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            DeleteError::InvalidHash(_) => StatusCode::BAD_REQUEST,
+            DeleteError::Gc(GcError::Store(StoreError::NotFound(_))) => StatusCode::NOT_FOUND,
+            DeleteError::Gc(GcError::Live(_) | GcError::Referenced { .. }) => StatusCode::CONFLICT,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn data(self) -> Self::Data {}
+}
+
+/// Deletes a package, and with `recursive` everything that only it references. With `dry_run`, only reports what
+/// would be deleted.
+pub async fn delete(
+    State(state): State<SharedState>,
+    Extension(actor): Extension<Actor>,
+    Path(hash): Path<String>,
+    Query(query): Query<DeleteQuery>,
+) -> Result<Json<DeleteResponse>, AppError<DeleteError>> {
+    let hash: SupportedHash = hash.parse().map_err(|_| DeleteError::InvalidHash(hash))?;
+
+    let store = state.store.clone().with_origin(&actor.0, "delete");
+    let plan = state
+        .blocking
+        .run("delete", move || {
+            let _lock = gc::lock(&store)?;
+            let plan = gc::plan(&store, &hash, query.recursive)?;
+            if !query.dry_run {
+                gc::delete(&store, &plan)?;
+            }
+            Ok::<_, GcError>(plan)
+        })
+        .await
+        .map_err(DeleteError::from)?
+        .map_err(DeleteError::from)?;
+
+    Ok(Json(DeleteResponse {
+        packages: plan.packages.iter().map(ToString::to_string).collect(),
+        bytes: plan.bytes,
+        deleted: !query.dry_run,
+    }))
+}
+
+#[derive(Debug, serde::Deserialize)]
+pub struct HistoryQuery {
+    #[serde(default)]
+    hash: Option<String>,
+    #[serde(default)]
+    name: Option<String>,
+    #[serde(default)]
+    actor: Option<String>,
+    /// Only changes from this time on, in seconds since the Unix epoch.
+    #[serde(default)]
+    since: Option<Timestamp>,
+    /// The largest number of changes to return.
+    #[serde(default = "default_history_limit")]
+    limit: usize,
+}
+
+fn default_history_limit() -> usize {
+    100
+}
+
+#[derive(Debug, Error)]
+pub enum HistoryError {
+    #[error("invalid hash provided: {0}")]
+    InvalidHash(String),
+    #[error("failed to read the history: {0}")]
+    IO(#[from] std::io::Error),
+    #[error("reading the history was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+}
+
+impl ApiError for HistoryError {
+    type Data = ();
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            HistoryError::InvalidHash(_) => StatusCode::BAD_REQUEST,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn data(self) -> Self::Data {}
+}
+
+/// Lists the packages that were added to or deleted from the store, newest first, with who changed the store and why.
+pub async fn history(
+    State(state): State<SharedState>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<history::Record>>, AppError<HistoryError>> {
+    let hash = query
+        .hash
+        .map(|hash| hash.parse().map_err(|_| HistoryError::InvalidHash(hash)))
+        .transpose()?;
+    let filter = history::Filter {
+        hash,
+        name: query.name,
+        actor: query.actor,
+        since: query.since,
+    };
+
+    let store = state.store.clone();
+    let records = state
+        .blocking
+        .run("read-history", move || {
+            history::read(&store, &filter, query.limit)
+        })
+        .await
+        .map_err(HistoryError::from)?
+        .map_err(HistoryError::from)?;
+    Ok(Json(records))
+}
@@ -0,0 +1,269 @@
+use axum::{
+    body::Body,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderValue, Method},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use hyper::StatusCode;
+use porkg_model::hashing::SupportedHash;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    archive::ArchiveError,
+    error::{ApiError, AppError},
+    frontend::auth::Actor,
+    signing::SIGNATURE_HEADER,
+    store::{
+        cache,
+        pack::Compression,
+        sync::{self, SyncError, Verify},
+        StoreError,
+    },
+    sync::{HashList, PeerError},
+};
+
+use super::{cache::Conditional, spool_body, SharedState};
+
+#[derive(Debug, Error)]
+pub enum SyncApiError {
+    #[error("invalid hash provided: {0}")]
+    InvalidHash(String),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Sync(#[from] SyncError),
+    #[error(transparent)]
+    Peer(#[from] PeerError),
+    #[error("the transfer was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+    #[error("failed to receive the package: {0}")]
+    Body(#[from] std::io::Error),
+}
+
+impl ApiError for SyncApiError {
+    type Data = ();
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            SyncApiError::InvalidHash(_)
+            | SyncApiError::Peer(PeerError::Unknown(_))
+            | SyncApiError::Sync(
+                SyncError::MissingInfo(_)
+                | SyncError::HashMismatch { .. }
+                | SyncError::Archive(ArchiveError::UnsafePath(_) | ArchiveError::UnsafeLink { .. }),
+            ) => StatusCode::BAD_REQUEST,
+            SyncApiError::Store(StoreError::NotFound(_))
+            | SyncApiError::Sync(SyncError::Store(StoreError::NotFound(_))) => {
+                StatusCode::NOT_FOUND
+            }
+            SyncApiError::Sync(SyncError::MissingReference { .. }) => StatusCode::CONFLICT,
+            SyncApiError::Sync(SyncError::Signature { .. }) => StatusCode::FORBIDDEN,
+            SyncApiError::Peer(
+                PeerError::Status { .. }
+                | PeerError::Client(_)
+                | PeerError::Remote { .. }
+                | PeerError::Sync(SyncError::Signature { .. }),
+            ) => StatusCode::BAD_GATEWAY,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn data(self) -> Self::Data {}
+}
+
+fn parse_hash(hash: String) -> Result<SupportedHash, SyncApiError> {
+    hash.parse().map_err(|_| SyncApiError::InvalidHash(hash))
+}
+
+fn parse_hashes(list: HashList) -> Result<Vec<SupportedHash>, SyncApiError> {
+    list.hashes.into_iter().map(parse_hash).collect()
+}
+
+/// Lists the combined closure of the requested packages, with each package after everything that it references.
+pub async fn closure(
+    State(state): State<SharedState>,
+    Json(list): Json<HashList>,
+) -> Result<Json<HashList>, AppError<SyncApiError>> {
+    let roots = parse_hashes(list)?;
+    let store = state.store.clone();
+    let closure = state
+        .blocking
+        .run("closure", move || sync::closure(&store, &roots))
+        .await
+        .map_err(SyncApiError::from)?
+        .map_err(SyncApiError::from)?;
+
+    Ok(Json(HashList::new(&closure)))
+}
+
+/// Filters the requested packages down to the ones that are not in the store.
+pub async fn missing(
+    State(state): State<SharedState>,
+    Json(list): Json<HashList>,
+) -> Result<Json<HashList>, AppError<SyncApiError>> {
+    let hashes = parse_hashes(list)?;
+    let store = state.store.clone();
+    let missing = state
+        .blocking
+        .run("missing", move || sync::missing(&store, &hashes))
+        .await
+        .map_err(SyncApiError::from)?;
+
+    Ok(Json(HashList::new(&missing)))
+}
+
+/// Exports a single package, including its metadata, as a tar archive.
+pub async fn export_package(
+    State(state): State<SharedState>,
+    Path(hash): Path<String>,
+    method: Method,
+    request: HeaderMap,
+) -> Result<Response, AppError<SyncApiError>> {
+    let hash = parse_hash(hash)?;
+    let info = state.store.info(&hash).map_err(SyncApiError::from)?;
+    let metadata = serde_json::to_vec(&info)
+        .map_err(StoreError::from)
+        .map_err(SyncApiError::from)?;
+    let cache = Conditional::new(&hash, &metadata, "application/x-tar");
+    if let Some(response) = cache.respond(&method, &request) {
+        return Ok(response);
+    }
+
+    let (file, signature) = state
+        .peers
+        .export(hash)
+        .await
+        .map_err(|error| match error {
+            PeerError::Sync(error) => SyncApiError::Sync(error),
+            error => SyncApiError::Peer(error),
+        })?;
+
+    let mut headers = cache.headers();
+    if let Some(signature) = signature.and_then(|v| HeaderValue::from_str(&v).ok()) {
+        headers.insert(SIGNATURE_HEADER, signature);
+    }
+    let body = Body::from_stream(ReaderStream::new(tokio::fs::File::from_std(file)));
+    Ok((headers, body).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CacheQuery {
+    /// The zstd compression level of the archives.
+    #[serde(default = "default_cache_level")]
+    level: i32,
+}
+
+fn default_cache_level() -> i32 {
+    19
+}
+
+/// Writes a static binary cache for the closures of the requested packages, as a tar archive of the files of the cache.
+pub async fn export_cache(
+    State(state): State<SharedState>,
+    Query(query): Query<CacheQuery>,
+    Json(list): Json<HashList>,
+) -> Result<impl IntoResponse, AppError<SyncApiError>> {
+    let roots = parse_hashes(list)?;
+    let store = state.store.clone();
+    let key = state.peers.signing_key();
+    let compression = Compression {
+        level: query.level,
+        workers: state.config.scheduler.compression_workers,
+    };
+    let file = state
+        .blocking
+        .run("export-cache", move || {
+            cache::write_file(&store, &roots, compression, key.as_deref())
+        })
+        .await
+        .map_err(SyncApiError::from)?
+        .map_err(SyncApiError::from)?;
+
+    let body = Body::from_stream(ReaderStream::new(tokio::fs::File::from_std(file)));
+    Ok(([(header::CONTENT_TYPE, "application/x-tar")], body))
+}
+
+/// Imports a single package that was exported by a peer. Everything that it references must already be present, and
+/// the package must be signed by a trusted key, as the hash of a package that has no source doesn't cover its content.
+pub async fn import_package(
+    State(state): State<SharedState>,
+    Extension(actor): Extension<Actor>,
+    Path(hash): Path<String>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<StatusCode, AppError<SyncApiError>> {
+    let hash = parse_hash(hash)?;
+    let signature = headers
+        .get(SIGNATURE_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_string);
+    let trusted = state.peers.trusted();
+    let store = state.store.clone().with_origin(&actor.0, "import-package");
+    let reader = spool_body(&store, body, None)
+        .await
+        .map_err(SyncApiError::from)?;
+    let inserted = state
+        .blocking
+        .run("import-package", move || {
+            let verify = Verify {
+                trusted: &trusted,
+                signature: signature.as_deref(),
+            };
+            sync::import(&store, &hash, std::io::BufReader::new(reader), Some(verify))
+        })
+        .await
+        .map_err(SyncApiError::from)?
+        .map_err(SyncApiError::from)?;
+
+    Ok(if inserted {
+        StatusCode::CREATED
+    } else {
+        StatusCode::OK
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SyncRequest {
+    /// The name of the peer, as configured.
+    peer: String,
+    hashes: Vec<String>,
+}
+
+/// Copies the closures of packages from a peer, responding with the packages that were transferred.
+pub async fn pull(
+    State(state): State<SharedState>,
+    Json(request): Json<SyncRequest>,
+) -> Result<Json<HashList>, AppError<SyncApiError>> {
+    let roots = request
+        .hashes
+        .into_iter()
+        .map(parse_hash)
+        .collect::<Result<Vec<_>, _>>()?;
+    let transferred = state
+        .peers
+        .pull(&request.peer, &roots)
+        .await
+        .map_err(SyncApiError::from)?;
+    Ok(Json(HashList::new(&transferred)))
+}
+
+/// Copies the closures of packages to a peer, responding with the packages that were transferred.
+pub async fn push(
+    State(state): State<SharedState>,
+    Json(request): Json<SyncRequest>,
+) -> Result<Json<HashList>, AppError<SyncApiError>> {
+    let roots = request
+        .hashes
+        .into_iter()
+        .map(parse_hash)
+        .collect::<Result<Vec<_>, _>>()?;
+    let transferred = state
+        .peers
+        .push(&request.peer, &roots)
+        .await
+        .map_err(SyncApiError::from)?;
+    Ok(Json(HashList::new(&transferred)))
+}
@@ -0,0 +1,74 @@
+use std::collections::BTreeMap;
+
+use axum::{extract::State, Extension, Json};
+use hyper::StatusCode;
+use thiserror::Error;
+
+use crate::{
+    backend::usage::TenantUsage,
+    config::TenantQuota,
+    error::{ApiError, AppError},
+    frontend::auth::Tenant,
+    store::gc::GcError,
+};
+
+use super::SharedState;
+
+#[derive(Debug, Error)]
+pub enum UsageError {
+    #[error("failed to measure the store: {0}")]
+    Store(#[from] GcError),
+    #[error("measuring the store was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+}
+
+impl ApiError for UsageError {
+    type Data = ();
+
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    fn data(self) -> Self::Data {}
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct TenantReport {
+    #[serde(flatten)]
+    usage: TenantUsage,
+    /// The quota of the tenant, which is absent if it is unlimited.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quota: Option<TenantQuota>,
+}
+
+/// Reports what the tenant of the client has consumed, or every tenant if the client doesn't belong to one.
+pub async fn get(
+    State(state): State<SharedState>,
+    Extension(tenant): Extension<Tenant>,
+) -> Result<Json<BTreeMap<String, TenantReport>>, AppError<UsageError>> {
+    let store = state.store.clone();
+    let usage = state.usage.clone();
+    let mut report = state
+        .blocking
+        .run("report-usage", move || usage.report(&store))
+        .await
+        .map_err(UsageError::from)?
+        .map_err(UsageError::from)?;
+    for name in state.config.quota.tenants.keys() {
+        report.entry(name.clone()).or_default();
+    }
+    if let Some(scope) = tenant.scope() {
+        report.retain(|name, _| name == scope);
+        report.entry(scope.to_string()).or_default();
+    }
+
+    Ok(Json(
+        report
+            .into_iter()
+            .map(|(name, usage)| {
+                let quota = state.config.quota.tenants.get(&name).cloned();
+                (name, TenantReport { usage, quota })
+            })
+            .collect(),
+    ))
+}
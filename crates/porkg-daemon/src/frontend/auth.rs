@@ -0,0 +1,327 @@
+//! Authorization of API requests.
+//!
+//! TCP clients identify themselves with a bearer token and are restricted to the operations configured for that
+//! identity, or to the anonymous operations if they don't present one. See [`AuthConfig`].
+//!
+//! Authorized requests carry the [`Tenant`] of their identity, which handlers use to scope what the client sees.
+//!
+//! Clients of the unix sockets are identified by the credentials of their process instead, and can be limited to some
+//! users and groups (see [`PeerPolicy`]).
+
+use std::{collections::BTreeSet, sync::Arc};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::header,
+    middleware::Next,
+    response::Response,
+};
+use hyper::StatusCode;
+use nix::unistd::{Group, User};
+use thiserror::Error;
+
+use crate::{
+    config::{AuthConfig, Config, Operation},
+    error::{ApiError, AppError},
+};
+
+use super::serve::{ClientInfo, PeerCredentials};
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("a valid token is required to perform {0:?} operations")]
+    Unauthenticated(Operation),
+    #[error("{identity} is not permitted to perform {operation:?} operations")]
+    Forbidden {
+        identity: String,
+        operation: Operation,
+    },
+    #[error("{0} is not permitted to use the unix socket")]
+    PeerForbidden(String),
+}
+
+impl ApiError for AuthError {
+    type Data = ();
+
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            AuthError::Forbidden { .. } | AuthError::PeerForbidden(_) => StatusCode::FORBIDDEN,
+        }
+    }
+
+    fn data(self) -> Self::Data {}
+}
+
+/// The tenant that a request was made for, if any.
+///
+/// Requests without a tenant (from the unix socket, anonymous clients, or identities that aren't assigned to one) are
+/// unscoped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tenant(pub Option<String>);
+
+impl Tenant {
+    /// Gets the tenant that a scoped request is limited to.
+    pub fn scope(&self) -> Option<&str> {
+        self.0.as_deref()
+    }
+}
+
+/// Who made a request, as recorded in the history of the store: the identity that it authenticated as, `unix` for
+/// clients of the unix socket, or `anonymous` with the address of the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Actor(pub String);
+
+impl Actor {
+    fn new(identity: Option<&str>, client: &ClientInfo) -> Self {
+        Self(match (identity, client) {
+            (Some(identity), _) => identity.to_string(),
+            (None, ClientInfo::Unix { .. }) => "unix".to_string(),
+            (None, client) => format!("anonymous ({client})"),
+        })
+    }
+}
+
+/// Compares tokens in constant time, so that a token can't be guessed from how long comparisons take.
+fn token_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Determines whether a client may perform `operation`, returning the name of the identity that it authenticated
+/// as.
+fn check<'a>(
+    config: &'a AuthConfig,
+    client: &ClientInfo,
+    token: Option<&str>,
+    operation: Operation,
+) -> Result<Option<&'a str>, AuthError> {
+    if let ClientInfo::Unix { admin, .. } = client {
+        return if *admin || operation != Operation::Admin {
+            Ok(None)
+        } else {
+            Err(AuthError::Forbidden {
+                identity: "unix".to_string(),
+                operation,
+            })
+        };
+    }
+
+    let Some(token) = token else {
+        return if config.anonymous.contains(&operation) {
+            Ok(None)
+        } else {
+            Err(AuthError::Unauthenticated(operation))
+        };
+    };
+
+    let (name, identity) = config
+        .identities
+        .iter()
+        .find(|(_, v)| token_eq(v.token.as_bytes(), token.as_bytes()))
+        .ok_or(AuthError::Unauthenticated(operation))?;
+    if !identity.operations.contains(&operation) {
+        return Err(AuthError::Forbidden {
+            identity: name.clone(),
+            operation,
+        });
+    }
+    Ok(Some(name))
+}
+
+/// Rejects requests from clients that may not perform `operation`.
+pub async fn authorize(
+    operation: Operation,
+    State(config): State<Arc<Config>>,
+    ConnectInfo(client): ConnectInfo<ClientInfo>,
+    mut request: Request,
+    next: Next,
+) -> Result<Response, AppError<AuthError>> {
+    let token = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    let identity = check(&config.auth, &client, token, operation)
+        .inspect_err(|error| tracing::debug!(%client, ?error, "request denied"))?;
+    let tenant = identity
+        .and_then(|name| config.auth.identities.get(name))
+        .and_then(|v| v.tenant.clone());
+    tracing::trace!(%client, identity, tenant, ?operation, "request authorized");
+    request.extensions_mut().insert(Tenant(tenant));
+    request
+        .extensions_mut()
+        .insert(Actor::new(identity, &client));
+    Ok(next.run(request).await)
+}
+
+/// The users and groups whose processes may use the unix sockets (see [`AuthConfig::allowed_users`]).
+#[derive(Debug, Clone, Default)]
+pub struct PeerPolicy {
+    users: BTreeSet<u32>,
+    groups: BTreeSet<u32>,
+}
+
+impl PeerPolicy {
+    /// Resolves the names of the allowed users and groups to their ids.
+    pub fn new(config: &AuthConfig) -> anyhow::Result<Self> {
+        let users = config
+            .allowed_users
+            .iter()
+            .map(|v| match v.parse() {
+                Ok(uid) => Ok(uid),
+                Err(_) => User::from_name(v)?
+                    .map(|v| v.uid.as_raw())
+                    .ok_or_else(|| anyhow::anyhow!("user {v:?} doesn't exist")),
+            })
+            .collect::<anyhow::Result<_>>()?;
+        let groups = config
+            .allowed_groups
+            .iter()
+            .map(|v| match v.parse() {
+                Ok(gid) => Ok(gid),
+                Err(_) => Group::from_name(v)?
+                    .map(|v| v.gid.as_raw())
+                    .ok_or_else(|| anyhow::anyhow!("group {v:?} doesn't exist")),
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self { users, groups })
+    }
+
+    /// Whether only some users and groups may use the unix sockets.
+    fn is_restricted(&self) -> bool {
+        !self.users.is_empty() || !self.groups.is_empty()
+    }
+
+    /// Determines whether a peer may use the unix sockets.
+    fn allows(&self, peer: Option<&PeerCredentials>) -> bool {
+        if !self.is_restricted() {
+            return true;
+        }
+        let Some(peer) = peer else {
+            return false;
+        };
+        self.users.contains(&peer.uid)
+            || self.groups.contains(&peer.gid)
+            || peer.groups.iter().any(|v| self.groups.contains(v))
+    }
+}
+
+/// Rejects requests from clients of the unix sockets whose users and groups aren't allowed to use them.
+pub async fn authorize_peer(
+    State(policy): State<Arc<PeerPolicy>>,
+    ConnectInfo(client): ConnectInfo<ClientInfo>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AppError<AuthError>> {
+    let ClientInfo::Unix { peer, .. } = &client else {
+        return Ok(next.run(request).await);
+    };
+    if !policy.allows(peer.as_ref()) {
+        tracing::debug!(%client, ?peer, "request denied");
+        return Err(AuthError::PeerForbidden(match peer {
+            Some(peer) => format!("uid {}", peer.uid),
+            None => "a peer without credentials".to_string(),
+        })
+        .into());
+    }
+    Ok(next.run(request).await)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::config::IdentityConfig;
+
+    use super::*;
+
+    #[test]
+    fn check_permissions() {
+        let config = AuthConfig {
+            allowed_users: Vec::new(),
+            allowed_groups: Vec::new(),
+            anonymous: [Operation::Read].into(),
+            identities: [(
+                "ci".to_string(),
+                IdentityConfig {
+                    token: "secret".into(),
+                    operations: [Operation::Read, Operation::Build].into(),
+                    tenant: None,
+                },
+            )]
+            .into(),
+        };
+        let tcp = ClientInfo::Tcp {
+            address: ([127, 0, 0, 1], 1234).into(),
+        };
+
+        let unix = ClientInfo::Unix {
+            admin: false,
+            peer: None,
+        };
+        assert!(check(&config, &unix, None, Operation::Run).is_ok());
+        assert!(matches!(
+            check(&config, &unix, None, Operation::Admin),
+            Err(AuthError::Forbidden { .. })
+        ));
+        let admin = ClientInfo::Unix {
+            admin: true,
+            peer: None,
+        };
+        assert!(check(&config, &admin, None, Operation::Admin).is_ok());
+        assert_eq!(check(&config, &tcp, None, Operation::Read).unwrap(), None);
+        assert!(matches!(
+            check(&config, &tcp, None, Operation::Build),
+            Err(AuthError::Unauthenticated(_))
+        ));
+        assert_eq!(
+            check(&config, &tcp, Some("secret"), Operation::Build).unwrap(),
+            Some("ci")
+        );
+        assert!(matches!(
+            check(&config, &tcp, Some("secret"), Operation::Import),
+            Err(AuthError::Forbidden { .. })
+        ));
+        assert!(matches!(
+            check(&config, &tcp, Some("wrong"), Operation::Read),
+            Err(AuthError::Unauthenticated(_))
+        ));
+    }
+
+    #[test]
+    fn check_peers() {
+        let peer = PeerCredentials {
+            uid: 1000,
+            gid: 1000,
+            groups: Arc::new([]),
+        };
+        let open = PeerPolicy::new(&AuthConfig::default()).unwrap();
+        assert!(open.allows(None));
+
+        let policy = PeerPolicy::new(&AuthConfig {
+            allowed_users: vec!["0".to_string()],
+            allowed_groups: vec!["990".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        assert!(!policy.allows(Some(&peer)));
+        assert!(!policy.allows(None));
+        let groups = PeerCredentials {
+            groups: Arc::new([27, 990]),
+            ..peer.clone()
+        };
+        assert!(policy.allows(Some(&groups)));
+        let user = PeerCredentials {
+            uid: 0,
+            ..peer.clone()
+        };
+        assert!(policy.allows(Some(&user)));
+        let group = PeerCredentials { gid: 990, ..peer };
+        assert!(policy.allows(Some(&group)));
+
+        assert!(PeerPolicy::new(&AuthConfig {
+            allowed_groups: vec!["no such group".to_string()],
+            ..Default::default()
+        })
+        .is_err());
+    }
+}
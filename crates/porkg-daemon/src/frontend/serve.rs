@@ -1,4 +1,11 @@
-use std::net::ToSocketAddrs;
+use std::{
+    fs::Permissions,
+    net::ToSocketAddrs,
+    os::unix::fs::PermissionsExt as _,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use anyhow::Context;
 use axum::extract::connect_info::Connected;
@@ -7,23 +14,63 @@ use hyper::{
     rt::{Read, Write},
     Request,
 };
-use hyper_util::rt::{TokioExecutor, TokioIo};
-use porkg_private::future::OptionalFutureExt as _;
-use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo, TokioTimer},
+    server::conn::auto,
+};
+use nix::unistd::{Gid, Group};
+use porkg_private::{future::OptionalFutureExt as _, io::peer_groups};
+use tokio::{
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::Semaphore,
+};
+use tokio_rustls::{
+    rustls::{self, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor,
+};
 use tokio_util::sync::CancellationToken;
 use tower_service::Service;
 
-use crate::config::BindConfig;
+use crate::config::{BindConfig, TlsConfig};
 
 enum Client {
-    Tcp { stream: TokioIo<TcpStream> },
-    Unix { stream: TokioIo<UnixStream> },
+    Tcp {
+        stream: TokioIo<TcpStream>,
+        address: std::net::SocketAddr,
+    },
+    Tls {
+        /// Boxed, as the state of a TLS connection is much larger than the other clients.
+        stream: TokioIo<Box<TlsStream<TcpStream>>>,
+        address: std::net::SocketAddr,
+    },
+    Unix {
+        stream: TokioIo<UnixStream>,
+        admin: bool,
+        peer: Option<PeerCredentials>,
+    },
 }
 
-impl From<(UnixStream, tokio::net::unix::SocketAddr)> for Client {
-    fn from(value: (UnixStream, tokio::net::unix::SocketAddr)) -> Self {
+impl Client {
+    fn unix(stream: UnixStream, admin: bool) -> Self {
+        let peer = stream
+            .peer_cred()
+            .inspect_err(|error| tracing::debug!(?error, "failed to get the credentials of a peer"))
+            .ok()
+            .map(|v| PeerCredentials {
+                uid: v.uid(),
+                gid: v.gid(),
+                groups: peer_groups(&stream)
+                    .inspect_err(|error| {
+                        tracing::debug!(?error, "failed to get the groups of a peer")
+                    })
+                    .unwrap_or_default()
+                    .into(),
+            });
         Self::Unix {
-            stream: TokioIo::new(value.0),
+            stream: TokioIo::new(stream),
+            admin,
+            peer,
         }
     }
 }
@@ -32,6 +79,7 @@ impl From<(TcpStream, std::net::SocketAddr)> for Client {
     fn from(value: (TcpStream, std::net::SocketAddr)) -> Self {
         Self::Tcp {
             stream: TokioIo::new(value.0),
+            address: value.1,
         }
     }
 }
@@ -44,6 +92,7 @@ impl hyper::rt::Read for Client {
     ) -> std::task::Poll<Result<(), std::io::Error>> {
         match self.get_mut() {
             Client::Tcp { stream, .. } => Read::poll_read(std::pin::pin!(stream), cx, buf),
+            Client::Tls { stream, .. } => Read::poll_read(std::pin::pin!(stream), cx, buf),
             Client::Unix { stream, .. } => Read::poll_read(std::pin::pin!(stream), cx, buf),
         }
     }
@@ -57,6 +106,7 @@ impl Write for Client {
     ) -> std::task::Poll<Result<usize, std::io::Error>> {
         match self.get_mut() {
             Client::Tcp { stream, .. } => Write::poll_write(std::pin::pin!(stream), cx, buf),
+            Client::Tls { stream, .. } => Write::poll_write(std::pin::pin!(stream), cx, buf),
             Client::Unix { stream, .. } => Write::poll_write(std::pin::pin!(stream), cx, buf),
         }
     }
@@ -67,6 +117,7 @@ impl Write for Client {
     ) -> std::task::Poll<Result<(), std::io::Error>> {
         match self.get_mut() {
             Client::Tcp { stream, .. } => Write::poll_flush(std::pin::pin!(stream), cx),
+            Client::Tls { stream, .. } => Write::poll_flush(std::pin::pin!(stream), cx),
             Client::Unix { stream, .. } => Write::poll_flush(std::pin::pin!(stream), cx),
         }
     }
@@ -77,22 +128,55 @@ impl Write for Client {
     ) -> std::task::Poll<Result<(), std::io::Error>> {
         match self.get_mut() {
             Client::Tcp { stream, .. } => Write::poll_shutdown(std::pin::pin!(stream), cx),
+            Client::Tls { stream, .. } => Write::poll_shutdown(std::pin::pin!(stream), cx),
             Client::Unix { stream, .. } => Write::poll_shutdown(std::pin::pin!(stream), cx),
         }
     }
 }
 
+/// The credentials of the process that connected to a unix socket, as of when it connected (see `SO_PEERCRED` and
+/// `SO_PEERGROUPS` in `unix(7)`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// The supplementary groups, which are missing if they couldn't be read.
+    pub groups: Arc<[u32]>,
+}
+
 #[derive(Debug, Clone)]
-enum ClientInfo {
-    Tcp,
-    Unix,
+pub enum ClientInfo {
+    Tcp {
+        address: std::net::SocketAddr,
+    },
+    /// A client of a unix socket, which is trusted with administration if `admin` is set (see
+    /// [`BindConfig::admin_socket`]). `peer` is missing if the credentials of the client couldn't be read.
+    Unix {
+        admin: bool,
+        peer: Option<PeerCredentials>,
+    },
+}
+
+impl std::fmt::Display for ClientInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientInfo::Tcp { address } => write!(f, "tcp:{address}"),
+            ClientInfo::Unix { admin: false, .. } => f.write_str("unix"),
+            ClientInfo::Unix { admin: true, .. } => f.write_str("unix:admin"),
+        }
+    }
 }
 
 impl Connected<&Client> for ClientInfo {
     fn connect_info(target: &Client) -> Self {
         match target {
-            Client::Tcp { .. } => ClientInfo::Tcp,
-            Client::Unix { .. } => ClientInfo::Unix,
+            Client::Tcp { address, .. } | Client::Tls { address, .. } => {
+                ClientInfo::Tcp { address: *address }
+            }
+            Client::Unix { admin, peer, .. } => ClientInfo::Unix {
+                admin: *admin,
+                peer: peer.clone(),
+            },
         }
     }
 }
@@ -104,24 +188,27 @@ pub async fn serve(
 ) -> anyhow::Result<()>
 where
 {
-    let socket_path = &settings.socket;
-    if let Some(parent) = socket_path.parent() {
-        if !tokio::fs::try_exists(parent).await? {
-            tokio::fs::create_dir_all(parent)
-                .await
-                .with_context(|| format!("failed to bind to {:?}", socket_path))?;
-        }
-    }
-
-    if tokio::fs::try_exists(socket_path).await? {
-        tracing::trace!(?socket_path, "cleaning up previous socket");
-        tokio::fs::remove_file(socket_path)
-            .await
-            .with_context(|| format!("failed to bind to {:?}", socket_path))?;
-    }
-
-    tracing::trace!(?socket_path, "binding");
-    let unix = UnixListener::bind(socket_path)?;
+    let unix = bind_unix(
+        &settings.socket,
+        settings.socket_mode.as_deref(),
+        settings.socket_group.as_deref(),
+        settings.create_dirs,
+    )
+    .await?;
+    let admin = match &settings.admin_socket {
+        Some(path) => Some(
+            bind_unix(
+                path,
+                Some(&settings.admin_socket_mode),
+                settings.admin_socket_group.as_deref(),
+                settings.create_dirs,
+            )
+            .await?,
+        ),
+        None => None,
+    };
+    // Without an admin socket, the socket is trusted with everything.
+    let unix_admin = admin.is_none();
 
     let tcp = if !settings.tcp.is_empty() {
         let mut socket_addrs = Vec::with_capacity(settings.tcp.len());
@@ -141,12 +228,28 @@ where
         None
     };
 
+    let tls = match &settings.tls {
+        Some(tls) => Some(tls_acceptor(tls, settings.http2).await?),
+        None => None,
+    };
+    let handshake_timeout = Duration::from_secs(settings.header_timeout);
+
     let mut make = router.into_make_service_with_connect_info::<ClientInfo>();
+    let builder = builder(settings);
+    let connections = Arc::new(Semaphore::new(settings.max_connections.max(1)));
+    let connection_timeout = settings.connection_timeout.map(Duration::from_secs);
 
     loop {
+        // Connections wait in the backlog of the sockets while too many are served.
+        let permit = tokio::select! {
+            permit = connections.clone().acquire_owned() => permit.expect("the semaphore is never closed"),
+            _ = cancellation_token.cancelled() => break Ok(()),
+        };
         let tcp = tcp.as_ref().map(|v| v.accept()).unwrap_future();
+        let admin = admin.as_ref().map(|v| v.accept()).unwrap_future();
         let socket = tokio::select! {
-            result = unix.accept() =>  result.map(Into::into),
+            result = unix.accept() => result.map(|(stream, _)| Client::unix(stream, unix_admin)),
+            result = admin => result.map(|(stream, _)| Client::unix(stream, true)),
             result = tcp => result.map(Into::into),
             _ = cancellation_token.cancelled() => {
                 break Ok(())
@@ -160,11 +263,37 @@ where
 
         let tower_service = make.call(&socket).await.unwrap_or_else(|err| match err {});
 
+        let builder = builder.clone();
+        let tls = tls.clone();
         tokio::spawn(async move {
+            let _permit = permit;
             let client = ClientInfo::connect_info(&socket);
-            let span = tracing::trace_span!("connection", ?client);
+            let span = tracing::trace_span!("connection", %client);
             let _span = span.enter();
 
+            // The handshake happens here rather than when accepting, so that a slow client doesn't hold up others.
+            let socket = match (socket, tls) {
+                (Client::Tcp { stream, address }, Some(tls)) => {
+                    match tokio::time::timeout(handshake_timeout, tls.accept(stream.into_inner()))
+                        .await
+                    {
+                        Ok(Ok(stream)) => Client::Tls {
+                            stream: TokioIo::new(Box::new(stream)),
+                            address,
+                        },
+                        Ok(Err(error)) => {
+                            tracing::debug!(?error, "the TLS handshake failed");
+                            return;
+                        }
+                        Err(_) => {
+                            tracing::debug!("closing a connection whose TLS handshake timed out");
+                            return;
+                        }
+                    }
+                }
+                (socket, _) => socket,
+            };
+
             let hyper_service = hyper::service::service_fn(move |request: Request<Incoming>| {
                 let span = tracing::trace_span!("request", method = ?request.method(), uri = ?request.uri());
                 let _span = span.enter();
@@ -172,16 +301,147 @@ where
                 tower_service.clone().call(request)
             });
 
-            if let Err(err) = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new())
-                .serve_connection_with_upgrades(socket, hyper_service)
-                .await
-            {
+            let connection = builder.serve_connection_with_upgrades(socket, hyper_service);
+            let result = match connection_timeout {
+                Some(timeout) => match tokio::time::timeout(timeout, connection).await {
+                    Ok(result) => result,
+                    Err(_) => {
+                        tracing::debug!(?timeout, "closing a connection that timed out");
+                        return;
+                    }
+                },
+                None => connection.await,
+            };
+            if let Err(err) = result {
                 tracing::info!(?err, "error responding to request")
             }
         });
     }
 }
 
+/// Configures the protocols that clients may speak.
+fn builder(settings: &BindConfig) -> auto::Builder<TokioExecutor> {
+    let mut builder = auto::Builder::new(TokioExecutor::new());
+    builder
+        .http1()
+        .timer(TokioTimer::new())
+        .header_read_timeout(Duration::from_secs(settings.header_timeout));
+    builder
+        .http2()
+        .timer(TokioTimer::new())
+        .keep_alive_interval(settings.http2_keepalive_interval.map(Duration::from_secs))
+        .keep_alive_timeout(Duration::from_secs(settings.http2_keepalive_timeout))
+        .max_concurrent_streams(settings.http2_max_streams);
+    if settings.http2 {
+        builder
+    } else {
+        builder.http1_only()
+    }
+}
+
+/// Loads the certificate that the TCP sockets are served with, offering HTTP/2 to clients if they may speak it.
+async fn tls_acceptor(settings: &TlsConfig, http2: bool) -> anyhow::Result<TlsAcceptor> {
+    let cert = tokio::fs::read(&settings.cert)
+        .await
+        .with_context(|| format!("failed to read the certificate {:?}", settings.cert))?;
+    let certs = rustls_pemfile::certs(&mut &cert[..])
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("invalid certificate {:?}", settings.cert))?;
+    if certs.is_empty() {
+        anyhow::bail!("there is no certificate in {:?}", settings.cert);
+    }
+    let key = tokio::fs::read(&settings.key)
+        .await
+        .with_context(|| format!("failed to read the private key {:?}", settings.key))?;
+    let key = rustls_pemfile::private_key(&mut &key[..])
+        .with_context(|| format!("invalid private key {:?}", settings.key))?
+        .ok_or_else(|| anyhow::anyhow!("there is no private key in {:?}", settings.key))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .with_context(|| format!("invalid certificate {:?}", settings.cert))?;
+    config.alpn_protocols = if http2 {
+        vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+    } else {
+        vec![b"http/1.1".to_vec()]
+    };
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Binds a unix socket at `path` with `mode` (in octal) and `group`, replacing a previous one.
+async fn bind_unix(
+    path: &Path,
+    mode: Option<&str>,
+    group: Option<&str>,
+    create_dirs: bool,
+) -> anyhow::Result<UnixListener> {
+    let mode = mode
+        .map(|v| u32::from_str_radix(v, 8))
+        .transpose()
+        .with_context(|| format!("invalid mode for {path:?}"))?;
+    let group = group
+        .map(|v| match v.parse() {
+            Ok(gid) => Ok(Gid::from_raw(gid)),
+            Err(_) => Group::from_name(v)?
+                .map(|v| v.gid)
+                .ok_or_else(|| anyhow::anyhow!("group {v:?} doesn't exist")),
+        })
+        .transpose()
+        .with_context(|| format!("invalid group for {path:?}"))?;
+
+    if let Some(parent) = path.parent().filter(|_| create_dirs) {
+        if !tokio::fs::try_exists(parent).await? {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("failed to bind to {:?}", path))?;
+        }
+    }
+
+    // The socket is bound in a private directory next to its path, and only replaces a previous socket once it has its
+    // group and mode, so that no client can connect to it before they apply.
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(".new");
+    let staging = PathBuf::from(staging);
+    if let Ok(metadata) = tokio::fs::symlink_metadata(&staging).await {
+        tracing::trace!(path = ?staging, "cleaning up previous socket");
+        if metadata.is_dir() {
+            tokio::fs::remove_dir_all(&staging).await
+        } else {
+            tokio::fs::remove_file(&staging).await
+        }
+        .with_context(|| format!("failed to bind to {:?}", path))?;
+    }
+    tokio::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&staging)
+        .await
+        .with_context(|| format!("failed to bind to {:?}", path))?;
+    let socket = staging.join("socket");
+
+    tracing::trace!(?path, "binding");
+    let listener =
+        UnixListener::bind(&socket).with_context(|| format!("failed to bind to {:?}", path))?;
+    if let Some(gid) = group {
+        std::os::unix::fs::chown(&socket, None, Some(gid.as_raw()))
+            .with_context(|| format!("failed to change the group of {path:?}"))?;
+    }
+    if let Some(mode) = mode {
+        tokio::fs::set_permissions(&socket, Permissions::from_mode(mode))
+            .await
+            .with_context(|| format!("failed to change the mode of {path:?}"))?;
+    }
+    tokio::fs::rename(&socket, path)
+        .await
+        .with_context(|| format!("failed to bind to {:?}", path))?;
+    tokio::fs::remove_dir(&staging)
+        .await
+        .with_context(|| format!("failed to bind to {:?}", path))?;
+    Ok(listener)
+}
+
 fn is_connection_error(e: &std::io::Error) -> bool {
     matches!(
         e.kind(),
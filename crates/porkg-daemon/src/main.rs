@@ -1,47 +1,129 @@
-use std::{future::Future, sync::Arc, time::Duration};
+#[cfg(target_os = "linux")]
+use std::{future::Future, panic::AssertUnwindSafe, pin::Pin, sync::Arc, time::Duration};
 
-use backend::BuildTask;
+#[cfg(target_os = "linux")]
+use backend::{sandbox::Sandbox, Task};
+#[cfg(target_os = "linux")]
 use config::Config;
-use porkg_linux::sandbox::{SandboxController, SandboxProcess};
-use porkg_private::os::proc::IntoExitCode;
+#[cfg(target_os = "linux")]
+use events::{Event, EventBus, SignalAction, TaskError};
+#[cfg(target_os = "linux")]
+use futures_util::FutureExt as _;
+#[cfg(target_os = "linux")]
+use porkg_linux::sandbox::{
+    oci::{self, OciController},
+    SandboxProcess,
+};
+#[cfg(target_os = "linux")]
+use porkg_private::{crash, future::OptionalFutureExt as _, os::proc::IntoExitCode};
+#[cfg(target_os = "linux")]
 use thiserror::Error;
+#[cfg(target_os = "linux")]
 use tokio::runtime::Runtime;
+#[cfg(target_os = "linux")]
 use tokio_util::sync::CancellationToken;
+#[cfg(target_os = "linux")]
 use tracing_subscriber::{layer::SubscriberExt as _, util::SubscriberInitExt as _};
 
+#[cfg(target_os = "linux")]
+mod archive;
+#[cfg(target_os = "linux")]
 mod backend;
+#[cfg(target_os = "linux")]
+mod blocking;
+#[cfg(target_os = "linux")]
+mod clock;
+#[cfg(target_os = "linux")]
 mod config;
+#[cfg(target_os = "linux")]
+mod degraded;
+#[cfg(target_os = "linux")]
+mod discovery;
+#[cfg(target_os = "linux")]
 mod error;
+#[cfg(target_os = "linux")]
+mod events;
+#[cfg(target_os = "linux")]
+mod fetch;
+#[cfg(target_os = "linux")]
 mod frontend;
+#[cfg(target_os = "linux")]
+mod redact;
+#[cfg(target_os = "linux")]
+mod remote;
+#[cfg(target_os = "linux")]
+mod secret;
+#[cfg(target_os = "linux")]
+mod signals;
+#[cfg(target_os = "linux")]
+mod signing;
+#[cfg(target_os = "linux")]
+mod store;
+#[cfg(target_os = "linux")]
+mod sync;
 
+/// The daemon relies on Linux for its sandboxes, so elsewhere it only reports that it can't run.
+#[cfg(not(target_os = "linux"))]
+fn main() -> anyhow::Result<()> {
+    Err(porkg_linux::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
 #[derive(Clone)]
 struct SetupState {
-    controller: SandboxController<backend::BuildTask>,
-    exit: flume::Sender<Option<anyhow::Error>>,
+    controller: Sandbox,
+    events: EventBus,
     config: Arc<Config>,
+    discovered: discovery::Discovered,
+    jobs: backend::jobs::Jobs,
+    time: clock::Time,
+    redactor: redact::Redactor,
+    errors: events::RecentErrors,
+    degraded: degraded::Degraded,
 }
 
+#[cfg(target_os = "linux")]
 #[derive(Debug, Error)]
 #[error("tmp")]
 pub struct Erro;
 
+#[cfg(target_os = "linux")]
 impl IntoExitCode for Erro {
     fn report(&self) -> i32 {
         -1
     }
 }
 
+#[cfg(target_os = "linux")]
 fn main() -> anyhow::Result<()> {
+    let chain = backend::middleware::chain();
+    // Exits if the daemon was started as the worker of a container.
+    oci::worker::<Task>(&chain);
+
     let config = Config::load()?;
+    // Before the zygote starts, which keeps the hook, as do the workers that it starts.
+    crash::install(config.log.crash_dir(&config.store), env!("CARGO_PKG_VERSION"), "daemon");
+    let redactor = redact::Redactor::new(&config)?;
 
     // TODO: Move this into each process and send traces via the channels
     //
+    let (filter, mut log_level) = signals::LogLevel::new();
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
-        .with(tracing_subscriber::EnvFilter::from_default_env())
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(redactor.clone()))
         .try_init()?;
+    // Exits if the daemon was executed as a restarted zygote, which keeps the hook and the logging above.
+    porkg_linux::sandbox::zygote::<Task>(&chain);
 
-    let controller = SandboxProcess::<BuildTask>::start()?;
+    // Without an OCI runtime, tasks run in the namespaces of the zygote.
+    let oci = config.sandbox.oci(&config.store);
+    let process = match &oci {
+        Some(oci) => {
+            tracing::info!(runtime = ?oci.runtime, "running tasks with an OCI runtime");
+            None
+        }
+        None => Some(SandboxProcess::<Task>::start_with(chain)?),
+    };
 
     // cloneing when there are multiple threads is UB, so the above must occur first.
     let runtime = tokio::runtime::Builder::new_multi_thread()
@@ -49,63 +131,201 @@ fn main() -> anyhow::Result<()> {
         .enable_time()
         .build()?;
 
-    let controller = runtime.block_on(controller.connect())?;
+    let controller = match (process, oci) {
+        (Some(process), _) => Sandbox::Namespaces(runtime.block_on(process.connect())?),
+        (None, Some(oci)) => Sandbox::Oci(OciController::new(oci)?),
+        (None, None) => unreachable!("the zygote is started without an OCI runtime"),
+    };
 
-    let (sender, receiver) = flume::bounded(1);
+    let events = EventBus::default();
+    // Subscribe before anything runs, so that no failure is missed.
+    let mut subscription = events.subscribe();
+    let errors = events.subscribe();
+    let faults = events.subscribe();
+    let time = clock::Time::default();
     let state = SetupState {
         controller,
-        exit: sender.clone(),
+        jobs: backend::jobs::Jobs::new(&config.scheduler, events.clone(), time.clone())
+            .with_journal(&config.scheduler.journal(&config.store))?,
+        time,
+        events,
         config: Arc::new(config),
+        discovered: Default::default(),
+        redactor,
+        errors: Default::default(),
+        degraded: Default::default(),
     };
 
+    let jobs = state.jobs.clone();
     let cancellation_token = CancellationToken::new();
+    // Cancelled first when the daemon drains, so that no more jobs are submitted.
+    let accepting = cancellation_token.child_token();
     let result = {
         let _cancel = cancellation_token.clone().drop_guard();
         exit_on_error(
             &runtime,
-            frontend::host(state.clone(), cancellation_token.clone()),
-            sender.clone(),
+            "signals",
+            signals::run(state.events.clone(), cancellation_token.clone()),
+            state.events.clone(),
+        );
+        exit_on_error(
+            &runtime,
+            "errors",
+            events::record_errors(errors, state.errors.clone(), cancellation_token.clone()),
+            state.events.clone(),
+        );
+        exit_on_error(
+            &runtime,
+            "degraded",
+            degraded::monitor(
+                faults,
+                state.degraded.clone(),
+                store::Store::new(&state.config.store),
+                state.events.clone(),
+                cancellation_token.clone(),
+            ),
+            state.events.clone(),
+        );
+        exit_on_error(
+            &runtime,
+            "zygote",
+            backend::sandbox::supervise(
+                state.controller.clone(),
+                state.events.clone(),
+                cancellation_token.clone(),
+            ),
+            state.events.clone(),
+        );
+        exit_on_error(
+            &runtime,
+            "frontend",
+            frontend::host(state.clone(), accepting.clone()),
+            state.events.clone(),
+        );
+        exit_on_error(
+            &runtime,
+            "gc",
+            store::gc::policy::collector(
+                state.config.clone(),
+                state.events.clone(),
+                state.time.clone(),
+                cancellation_token.clone(),
+            ),
+            state.events.clone(),
+        );
+        exit_on_error(
+            &runtime,
+            "job-detector",
+            backend::jobs::detector(
+                state.config.clone(),
+                state.jobs.clone(),
+                cancellation_token.clone(),
+            ),
+            state.events.clone(),
+        );
+        exit_on_error(
+            &runtime,
+            "discovery",
+            discovery::run(
+                state.config.clone(),
+                state.discovered.clone(),
+                cancellation_token.clone(),
+            ),
+            state.events.clone(),
         );
 
-        runtime.block_on(async move {
-            let result = tokio::select! {
-                err = receiver.recv_async() => err,
-                _ = tokio::signal::ctrl_c() => return Ok(())
-            };
+        let result = runtime.block_on(async move {
+            let mut drain: Option<Pin<Box<tokio::time::Sleep>>> = None;
+            loop {
+                let deadline = drain.as_mut().map(Pin::as_mut).unwrap_future();
+                let event = tokio::select! {
+                    event = subscription.recv() => event,
+                    _ = deadline => {
+                        let jobs = state.jobs.list(None).len();
+                        tracing::warn!(jobs, "stopping before the jobs finished draining");
+                        return Ok(());
+                    }
+                };
 
-            match result {
-                Ok(Some(err)) => Err(err),
-                _ => Ok(()),
+                match event.as_deref() {
+                    Some(Event::TaskFailed { task, error }) => {
+                        return Err(anyhow::Error::new(error.clone())
+                            .context(format!("the {task} task failed")))
+                    }
+                    Some(Event::Signal { action }) => match action {
+                        SignalAction::Shutdown => return Ok(()),
+                        SignalAction::Drain if drain.is_none() => {
+                            let timeout = state.config.scheduler.drain_timeout;
+                            tracing::info!(timeout, "draining jobs");
+                            accepting.cancel();
+                            drain =
+                                Some(Box::pin(tokio::time::sleep(Duration::from_secs(timeout))));
+                        }
+                        SignalAction::Drain => {}
+                        SignalAction::Reload => reload(&state),
+                        SignalAction::LogLevel => log_level.toggle(),
+                        SignalAction::DumpState => dump_state(&state),
+                    },
+                    Some(event) => tracing::trace!(?event, "event"),
+                    None => return Ok(()),
+                }
+                if drain.is_some() && state.jobs.list(None).is_empty() {
+                    tracing::info!("drained the jobs");
+                    return Ok(());
+                }
             }
-        })
+        });
+        // The jobs that are still running are resumed when the daemon starts again, rather than recorded as failed
+        // when they are dropped with the runtime.
+        jobs.stop();
+        result
     };
 
     runtime.shutdown_timeout(Duration::from_secs(5));
+    // The jobs that finished are recorded in the background.
+    jobs.flush();
     result
 }
 
+/// Reads the secrets that are redacted from logs again, in the background.
+#[cfg(target_os = "linux")]
+fn reload(state: &SetupState) {
+    let config = state.config.clone();
+    let redactor = state.redactor.clone();
+    tokio::task::spawn_blocking(move || match redactor.reload(&config) {
+        Ok(()) => tracing::info!("reloaded the secrets that are redacted"),
+        Err(error) => tracing::error!(?error, "failed to reload the secrets that are redacted"),
+    });
+}
+
+/// Logs what the daemon is doing, for operators that can't reach the API.
+#[cfg(target_os = "linux")]
+fn dump_state(state: &SetupState) {
+    let jobs = state.jobs.list(None);
+    tracing::info!(
+        jobs = %serde_json::to_string(&jobs).unwrap_or_default(),
+        discovered = ?state.discovered.urls(),
+        "state report"
+    );
+}
+
+#[cfg(target_os = "linux")]
 fn exit_on_error(
     runtime: &Runtime,
+    task: &'static str,
     f: (impl 'static + Send + Future<Output = anyhow::Result<()>>),
-    sender: flume::Sender<Option<anyhow::Error>>,
+    events: EventBus,
 ) {
     runtime.spawn(async move {
-        let mut kill = DropKill(Some(sender.clone()));
-
-        if let Err(error) = f.await {
-            sender.try_send(Some(error)).ok();
-        }
-
-        kill.0 = None;
+        // The panic hook has written a crash report by the time that the panic is caught.
+        let error = match AssertUnwindSafe(f).catch_unwind().await {
+            Ok(Ok(())) => return,
+            Ok(Err(error)) => error,
+            Err(payload) => anyhow::anyhow!("the task panicked: {}", crash::message(&*payload)),
+        };
+        events.publish(Event::TaskFailed {
+            task,
+            error: TaskError::new(error),
+        });
     });
 }
-
-struct DropKill(Option<flume::Sender<Option<anyhow::Error>>>);
-
-impl Drop for DropKill {
-    fn drop(&mut self) {
-        if let Some(v) = self.0.take() {
-            v.try_send(Some(anyhow::anyhow!("A panic occurred"))).ok();
-        }
-    }
-}
@@ -0,0 +1,230 @@
+//! Redaction of secrets from logs.
+//!
+//! Matches of the configured patterns, and the values of the configured secrets, are replaced before a log is written
+//! anywhere. The values of secrets are read when the daemon starts, so a secret that is rotated afterwards is only
+//! redacted once the daemon is reloaded (with SIGHUP) or restarted. Values shorter than [`MIN_SECRET_LENGTH`] are not
+//! redacted, as they would match ordinary text.
+
+use std::{
+    borrow::Cow,
+    io::{self, Write},
+    sync::{Arc, RwLock},
+};
+
+use regex::bytes::Regex;
+use thiserror::Error;
+use tracing_subscriber::fmt::MakeWriter;
+
+use crate::{config::Config, secret::Secrets};
+
+/// What matches are replaced with.
+pub const REDACTED: &str = "[REDACTED]";
+
+const MIN_SECRET_LENGTH: usize = 6;
+
+#[derive(Debug, Error)]
+pub enum RedactError {
+    #[error("the redaction pattern {name:?} is invalid")]
+    InvalidPattern {
+        name: String,
+        #[source]
+        error: regex::Error,
+    },
+}
+
+/// Replaces secrets in logs. Clones share the patterns, including when they are reloaded.
+#[derive(Debug, Clone, Default)]
+pub struct Redactor {
+    pattern: Arc<RwLock<Option<Regex>>>,
+}
+
+/// Combines the patterns and secrets in the configuration into one pattern, if there are any.
+fn pattern(config: &Config) -> Result<Option<Regex>, RedactError> {
+    let mut alternatives = Vec::new();
+    for (name, pattern) in &config.log.redact {
+        // Each pattern is checked on its own, so that an error refers to it.
+        Regex::new(pattern).map_err(|error| RedactError::InvalidPattern {
+            name: name.clone(),
+            error,
+        })?;
+        alternatives.push(format!("(?:{pattern})"));
+    }
+
+    if config.log.redact_secrets {
+        let secrets = Secrets::new(&config.fetch.secrets);
+        // A secret that is unavailable can't be logged either, so it is skipped.
+        for secret in config
+            .fetch
+            .secrets
+            .keys()
+            .filter_map(|name| secrets.get(name).ok())
+        {
+            let value = String::from_utf8_lossy(secret.expose());
+            alternatives.extend(
+                value
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| line.len() >= MIN_SECRET_LENGTH)
+                    .map(regex::escape),
+            );
+        }
+    }
+
+    if alternatives.is_empty() {
+        return Ok(None);
+    }
+    let pattern =
+        Regex::new(&alternatives.join("|")).map_err(|error| RedactError::InvalidPattern {
+            name: "*".to_string(),
+            error,
+        })?;
+    Ok(Some(pattern))
+}
+
+impl Redactor {
+    /// Creates a redactor for the patterns and secrets in the configuration.
+    ///
+    /// This performs blocking IO.
+    pub fn new(config: &Config) -> Result<Self, RedactError> {
+        Ok(Self {
+            pattern: Arc::new(RwLock::new(pattern(config)?)),
+        })
+    }
+
+    /// Reads the secrets in the configuration again, keeping the previous patterns if that fails.
+    ///
+    /// This performs blocking IO.
+    pub fn reload(&self, config: &Config) -> Result<(), RedactError> {
+        let pattern = pattern(config)?;
+        *self.pattern.write().unwrap() = pattern;
+        Ok(())
+    }
+
+    pub fn redact<'a>(&self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        match &*self.pattern.read().unwrap() {
+            Some(pattern) => pattern.replace_all(data, REDACTED.as_bytes()),
+            None => Cow::Borrowed(data),
+        }
+    }
+}
+
+/// Buffers a single log event, and writes it to stdout once it is complete so that secrets can't be split across
+/// writes.
+pub struct RedactedWriter<'a> {
+    redactor: &'a Redactor,
+    buffer: Vec<u8>,
+}
+
+impl Write for RedactedWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for RedactedWriter<'_> {
+    fn drop(&mut self) {
+        let data = self.redactor.redact(&self.buffer);
+        io::stdout().lock().write_all(&data).ok();
+    }
+}
+
+impl<'a> MakeWriter<'a> for Redactor {
+    type Writer = RedactedWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactedWriter {
+            redactor: self,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::config::SecretSource;
+
+    use super::*;
+
+    #[test]
+    fn redact_secrets() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        fs::write(dir.join("token"), "s3cr3t.token+1\n").unwrap();
+        fs::write(dir.join("short"), "abc\n").unwrap();
+
+        let mut config = Config::default();
+        for name in ["token", "short", "missing"] {
+            config
+                .fetch
+                .secrets
+                .insert(name.to_string(), SecretSource::File(dir.join(name)).into());
+        }
+        config
+            .log
+            .redact
+            .insert("github".to_string(), "ghp_[A-Za-z0-9]{8}".to_string());
+
+        let redactor = Redactor::new(&config).unwrap();
+        assert_eq!(
+            redactor.redact(b"fetching with s3cr3t.token+1 and ghp_abcd1234 as abc"),
+            &b"fetching with [REDACTED] and [REDACTED] as abc"[..]
+        );
+        // Regex metacharacters in secrets are matched literally.
+        assert!(matches!(
+            redactor.redact(b"s3cr3tXtoken+1"),
+            Cow::Borrowed(_)
+        ));
+
+        config.log.redact_secrets = false;
+        let redactor = Redactor::new(&config).unwrap();
+        assert_eq!(
+            redactor.redact(b"s3cr3t.token+1 ghp_abcd1234"),
+            &b"s3cr3t.token+1 [REDACTED]"[..]
+        );
+
+        config
+            .log
+            .redact
+            .insert("broken".to_string(), "(".to_string());
+        assert!(matches!(
+            Redactor::new(&config),
+            Err(RedactError::InvalidPattern { name, .. }) if name == "broken"
+        ));
+    }
+
+    #[test]
+    fn reload_secrets() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        fs::write(dir.join("token"), "old-token\n").unwrap();
+        let mut config = Config::default();
+        config.fetch.secrets.insert(
+            "token".to_string(),
+            SecretSource::File(dir.join("token")).into(),
+        );
+
+        let redactor = Redactor::new(&config).unwrap();
+        let writer = redactor.clone();
+        fs::write(dir.join("token"), "new-token\n").unwrap();
+        assert_eq!(redactor.redact(b"new-token"), &b"new-token"[..]);
+        redactor.reload(&config).unwrap();
+        assert_eq!(
+            writer.redact(b"old-token new-token"),
+            &b"old-token [REDACTED]"[..]
+        );
+
+        config
+            .log
+            .redact
+            .insert("broken".to_string(), "(".to_string());
+        assert!(redactor.reload(&config).is_err());
+        assert_eq!(writer.redact(b"new-token"), &b"[REDACTED]"[..]);
+    }
+}
@@ -0,0 +1,573 @@
+//! Binary caches in object storage (see [`RemoteConfig`]), which packages are published to and substituted from.
+//!
+//! A remote uses the layout of static caches (see [`porkg_model::cache`]), so a cache that was written with
+//! `porkg export-cache` and uploaded by other means can be used as a remote, and a remote can be served by any static
+//! host. The metadata of packages never changes, so it is kept on local disk once it has been read.
+//!
+//! Packages are published as chunks (see [`crate::store::chunk`]) unless chunking is disabled, and packages that were
+//! published either way can be pulled.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use tokio::io::AsyncWriteExt as _;
+
+use bytes::Bytes;
+use porkg_model::{
+    cache::{self, CacheEntry, CacheIndex},
+    hashing::SupportedHash,
+};
+use thiserror::Error;
+
+use crate::{
+    blocking::BlockingPool,
+    config::RemoteConfig,
+    secret::{SecretError, Secrets},
+    signing::{SigningKey, TrustedKeys},
+    store::{
+        cache as store_cache, chunk,
+        pack::{self, Compression},
+        sync::{self, SyncError, Verify},
+        Store, StoreError,
+    },
+};
+
+use self::{directory::Directory, s3::S3};
+
+mod directory;
+mod s3;
+mod sigv4;
+
+#[derive(Debug, Error)]
+pub enum RemoteError {
+    #[error("invalid url {0:?}")]
+    InvalidUrl(String),
+    #[error("both an access key ID and a secret access key must be configured")]
+    IncompleteCredentials,
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error(transparent)]
+    Request(#[from] hyper::http::Error),
+    #[error(transparent)]
+    Client(#[from] hyper_util::client::legacy::Error),
+    #[error(transparent)]
+    Body(#[from] hyper::Error),
+    #[error("{method} {key} failed with {status}: {message}")]
+    Status {
+        method: hyper::Method,
+        key: String,
+        status: hyper::StatusCode,
+        message: String,
+    },
+    #[error("the response to {0} is missing {1}")]
+    InvalidResponse(String, &'static str),
+    #[error(transparent)]
+    Secret(#[from] SecretError),
+    #[error("{key} is invalid: {error}")]
+    InvalidMetadata {
+        key: String,
+        error: serde_json::Error,
+    },
+    #[error("{0} is not in the cache")]
+    NotFound(SupportedHash),
+    #[error("the chunk {0} is missing from the cache")]
+    MissingChunk(SupportedHash),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Sync(#[from] SyncError),
+    #[error("the transfer was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+}
+
+/// Storage of objects by key, relative to the root of a cache.
+trait ObjectStore {
+    /// Reads a small object, such as metadata, returning `None` if it doesn't exist.
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, RemoteError>;
+
+    /// Downloads an object into a file, returning `false` if it doesn't exist.
+    async fn download(&self, key: &str, file: &mut tokio::fs::File) -> Result<bool, RemoteError>;
+
+    /// Writes a small object.
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), RemoteError>;
+
+    /// Uploads the contents of a file, which is `size` bytes long.
+    async fn upload(&self, key: &str, file: tokio::fs::File, size: u64) -> Result<(), RemoteError>;
+
+    /// Lists the keys that start with `prefix`.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, RemoteError>;
+}
+
+#[derive(Debug)]
+enum Backend {
+    S3(Box<S3>),
+    Directory(Directory),
+}
+
+impl ObjectStore for Backend {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, RemoteError> {
+        match self {
+            Backend::S3(v) => v.get(key).await,
+            Backend::Directory(v) => v.get(key).await,
+        }
+    }
+
+    async fn download(&self, key: &str, file: &mut tokio::fs::File) -> Result<bool, RemoteError> {
+        match self {
+            Backend::S3(v) => v.download(key, file).await,
+            Backend::Directory(v) => v.download(key, file).await,
+        }
+    }
+
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), RemoteError> {
+        match self {
+            Backend::S3(v) => v.put(key, data).await,
+            Backend::Directory(v) => v.put(key, data).await,
+        }
+    }
+
+    async fn upload(&self, key: &str, file: tokio::fs::File, size: u64) -> Result<(), RemoteError> {
+        match self {
+            Backend::S3(v) => v.upload(key, file, size).await,
+            Backend::Directory(v) => v.upload(key, file, size).await,
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, RemoteError> {
+        match self {
+            Backend::S3(v) => v.list(prefix).await,
+            Backend::Directory(v) => v.list(prefix).await,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Remote {
+    backend: Arc<Backend>,
+    metadata: PathBuf,
+    pub substitute: bool,
+    verify: bool,
+    compression: Compression,
+    chunking: bool,
+}
+
+impl Remote {
+    pub fn new(
+        name: &str,
+        config: &RemoteConfig,
+        store: &Store,
+        secrets: Secrets,
+        blocking: BlockingPool,
+        workers: usize,
+    ) -> Result<Self, RemoteError> {
+        let backend = match config.url.strip_prefix("file://") {
+            Some(path) => {
+                Backend::Directory(Directory::new(PathBuf::from(path).join(&config.prefix)))
+            }
+            None => Backend::S3(Box::new(S3::new(config, secrets, blocking)?)),
+        };
+        Ok(Self {
+            backend: Arc::new(backend),
+            metadata: store.remote_path(name),
+            substitute: config.substitute,
+            verify: config.verify,
+            compression: Compression {
+                level: config.level,
+                workers,
+            },
+            chunking: config.chunking,
+        })
+    }
+
+    /// Reads the metadata of a package, from local disk if it has been read before.
+    async fn entry(&self, hash: &SupportedHash) -> Result<Option<CacheEntry>, RemoteError> {
+        let key = cache::entry_path(&hash.to_string());
+        let local = self.metadata.join(format!("{hash}.json"));
+        let data = match tokio::fs::read(&local).await {
+            Ok(data) => Bytes::from(data),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                let Some(data) = self.backend.get(&key).await? else {
+                    return Ok(None);
+                };
+                tokio::fs::create_dir_all(&self.metadata).await?;
+                let temporary = self.metadata.join(format!("{hash}.json.tmp"));
+                tokio::fs::write(&temporary, &data).await?;
+                tokio::fs::rename(&temporary, &local).await?;
+                data
+            }
+            Err(error) => return Err(error.into()),
+        };
+        serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|error| RemoteError::InvalidMetadata { key, error })
+    }
+
+    /// Writes the archive of a package into a file, returning whether the archive is compressed as a whole.
+    async fn fetch(
+        &self,
+        blocking: &BlockingPool,
+        hash: SupportedHash,
+        entry: &CacheEntry,
+        file: &mut tokio::fs::File,
+    ) -> Result<bool, RemoteError> {
+        let key = cache::entry_path(&hash.to_string());
+        if let Some(archive) = &entry.archive {
+            if !self.backend.download(archive, file).await? {
+                return Err(RemoteError::NotFound(hash));
+            }
+            return Ok(true);
+        }
+        if entry.chunks.is_empty() {
+            return Err(RemoteError::InvalidResponse(key, "an archive or chunks"));
+        }
+
+        for chunk in entry.chunks.iter() {
+            let chunk: SupportedHash = chunk
+                .parse()
+                .map_err(|_| RemoteError::InvalidResponse(key.clone(), "valid chunk hashes"))?;
+            let data = self
+                .backend
+                .get(&cache::chunk_path(&chunk.to_string()))
+                .await?
+                .ok_or(RemoteError::MissingChunk(chunk))?;
+            let data = blocking
+                .run("decompress-chunk", move || chunk::decompress(&chunk, &data))
+                .await??;
+            file.write_all(&data).await?;
+        }
+        file.flush().await?;
+        Ok(false)
+    }
+
+    /// Copies the closures of packages from the cache, returning the packages that were missing.
+    pub async fn pull(
+        &self,
+        store: &Store,
+        blocking: &BlockingPool,
+        trusted: &TrustedKeys,
+        roots: &[SupportedHash],
+    ) -> Result<Vec<SupportedHash>, RemoteError> {
+        // Each package is imported after everything that it references.
+        let mut entries = BTreeMap::new();
+        let mut order = Vec::new();
+        let mut stack: Vec<_> = roots.iter().rev().map(|hash| (*hash, false)).collect();
+        while let Some((hash, expanded)) = stack.pop() {
+            if expanded {
+                order.push(hash);
+                continue;
+            }
+            if entries.contains_key(&hash) || store.by_hash(&hash).exists() {
+                continue;
+            }
+            let entry = self
+                .entry(&hash)
+                .await?
+                .ok_or(RemoteError::NotFound(hash))?;
+            stack.push((hash, true));
+            for reference in entry.references.iter().rev() {
+                let reference = reference
+                    .parse()
+                    .map_err(|_| RemoteError::InvalidResponse(reference.clone(), "a valid hash"))?;
+                stack.push((reference, false));
+            }
+            entries.insert(hash, entry);
+        }
+        tracing::info!(missing = order.len(), "pulling packages from cache");
+
+        for &hash in order.iter() {
+            let entry = entries.remove(&hash).unwrap_or_else(|| unreachable!());
+            let path = store.temp_path("remote");
+            tokio::fs::create_dir_all(path.parent().unwrap_or(&path)).await?;
+            let mut file = tokio::fs::File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .await?;
+            // The open file remains readable after it is unlinked.
+            let compressed = self.fetch(blocking, hash, &entry, &mut file).await;
+            tokio::fs::remove_file(&path).await.ok();
+            let compressed = compressed?;
+
+            let mut file = file.into_std().await;
+            let store = store.clone();
+            let trusted = self.verify.then(|| trusted.clone());
+            blocking
+                .run("import-package", move || {
+                    io::Seek::rewind(&mut file)?;
+                    let verify = trusted.as_ref().map(|trusted| Verify {
+                        trusted,
+                        signature: entry.signature.as_deref(),
+                    });
+                    let imported = if compressed {
+                        sync::import(&store, &hash, pack::Decoder::new(file)?, verify)
+                    } else {
+                        sync::import(&store, &hash, file, verify)
+                    };
+                    imported.map_err(RemoteError::from)
+                })
+                .await??;
+        }
+        Ok(order)
+    }
+
+    /// Uploads a package as a whole archive, returning its metadata.
+    async fn publish_archive(
+        &self,
+        store: &Store,
+        blocking: &BlockingPool,
+        key: Option<Arc<SigningKey>>,
+        hash: SupportedHash,
+    ) -> Result<CacheEntry, RemoteError> {
+        let store = store.clone();
+        let compression = self.compression;
+        let (file, entry) = blocking
+            .run("compress-package", move || {
+                store_cache::package(&store, &hash, compression, key.as_deref())
+            })
+            .await??;
+        let archive = cache::archive_path(&hash.to_string());
+        self.backend
+            .upload(&archive, tokio::fs::File::from_std(file), entry.size)
+            .await?;
+        Ok(entry)
+    }
+
+    /// Uploads the chunks of a package that are not in `existing`, returning its metadata.
+    async fn publish_chunks(
+        &self,
+        store: &Store,
+        blocking: &BlockingPool,
+        key: Option<Arc<SigningKey>>,
+        hash: SupportedHash,
+        existing: &mut BTreeSet<String>,
+    ) -> Result<CacheEntry, RemoteError> {
+        let store = store.clone();
+        let (file, chunks, mut entry) = blocking
+            .run("split-package", move || {
+                let entry = store_cache::describe(&store, &hash, key.as_deref())?;
+                let (file, chunks) = chunk::export(&store, &hash)?;
+                Ok::<_, SyncError>((file, chunks, entry))
+            })
+            .await??;
+
+        let file = Arc::new(file);
+        let mut uploaded = 0;
+        for chunk in chunks {
+            entry.size += chunk.size as u64;
+            entry.chunks.push(chunk.hash.to_string());
+            let path = cache::chunk_path(&chunk.hash.to_string());
+            if !existing.insert(path.clone()) {
+                continue;
+            }
+            let file = file.clone();
+            let level = self.compression.level;
+            let data = blocking
+                .run("compress-chunk", move || {
+                    chunk::compress(&file, &chunk, level)
+                })
+                .await??;
+            self.backend.put(&path, data.into()).await?;
+            uploaded += 1;
+        }
+        tracing::info!(%hash, chunks = entry.chunks.len(), uploaded, "uploaded chunks");
+        Ok(entry)
+    }
+
+    /// Publishes the closures of packages to the cache, returning the packages that the cache was missing.
+    pub async fn publish(
+        &self,
+        store: &Store,
+        blocking: &BlockingPool,
+        key: Option<Arc<SigningKey>>,
+        roots: &[SupportedHash],
+    ) -> Result<Vec<SupportedHash>, RemoteError> {
+        let owned = store.clone();
+        let roots = roots.to_vec();
+        let closure = blocking
+            .run("closure", move || sync::closure(&owned, &roots))
+            .await??;
+        let existing: BTreeSet<_> = self.backend.list("pkg/").await?.into_iter().collect();
+        let missing: Vec<_> = closure
+            .into_iter()
+            .filter(|hash| !existing.contains(&cache::entry_path(&hash.to_string())))
+            .collect();
+        tracing::info!(missing = missing.len(), "publishing packages to cache");
+
+        let mut index = match self.backend.get(cache::INDEX_FILE).await? {
+            Some(data) => {
+                serde_json::from_slice(&data).map_err(|error| RemoteError::InvalidMetadata {
+                    key: cache::INDEX_FILE.to_string(),
+                    error,
+                })?
+            }
+            None => CacheIndex::default(),
+        };
+        let mut chunks = BTreeSet::new();
+        if self.chunking && !missing.is_empty() {
+            chunks.extend(self.backend.list("chunk/").await?);
+        }
+        for &hash in missing.iter() {
+            let entry = if self.chunking {
+                self.publish_chunks(store, blocking, key.clone(), hash, &mut chunks)
+                    .await?
+            } else {
+                self.publish_archive(store, blocking, key.clone(), hash)
+                    .await?
+            };
+
+            // The metadata is written last, so that the cache never lists a package that can't be fetched.
+            let data = serde_json::to_vec_pretty(&entry).map_err(StoreError::from)?;
+            self.backend
+                .put(&cache::entry_path(&hash.to_string()), data.into())
+                .await?;
+            index.packages.insert(hash.to_string(), entry.name);
+        }
+
+        if !missing.is_empty() {
+            let data = serde_json::to_vec_pretty(&index).map_err(StoreError::from)?;
+            self.backend.put(cache::INDEX_FILE, data.into()).await?;
+        }
+        Ok(missing)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use data_encoding::BASE64;
+    use ring::{rand::SystemRandom, signature::Ed25519KeyPair};
+
+    use crate::{
+        signing::SignatureError,
+        store::testing::{add_package, TestStore},
+    };
+
+    use super::*;
+
+    fn config(url: String) -> RemoteConfig {
+        RemoteConfig {
+            url,
+            region: "us-east-1".to_string(),
+            prefix: "porkg/".to_string(),
+            access_key_id: None,
+            secret_access_key: None,
+            substitute: true,
+            verify: true,
+            part_size: 1024,
+            level: 3,
+            chunking: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn publish_and_pull() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let from = TestStore::new("remote-from");
+        let to = TestStore::new("remote-to");
+        let blocking = BlockingPool::new(2);
+        let libc = add_package(&from, "libc", &[], 1);
+        let zlib = add_package(&from, "zlib", &[libc], 2);
+
+        let document = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let secret = format!("office-1:{}", BASE64.encode(document.as_ref()));
+        let key = Arc::new(SigningKey::parse(secret.as_bytes()).unwrap());
+        let trusted =
+            TrustedKeys::new(&[("office-1".to_string(), key.public_key())].into()).unwrap();
+
+        let url = format!("file://{}", root.join("bucket").display());
+        // Packages that were published as whole archives and as chunks can be pulled together.
+        let mut whole = config(url.clone());
+        whole.chunking = false;
+        let publisher = Remote::new(
+            "cache",
+            &whole,
+            &from,
+            Secrets::default(),
+            blocking.clone(),
+            2,
+        )
+        .unwrap();
+        let published = publisher
+            .publish(&from, &blocking, Some(key.clone()), &[libc])
+            .await
+            .unwrap();
+        assert_eq!(published, [libc]);
+        let publisher = Remote::new(
+            "cache",
+            &config(url.clone()),
+            &from,
+            Secrets::default(),
+            blocking.clone(),
+            2,
+        )
+        .unwrap();
+        let published = publisher
+            .publish(&from, &blocking, Some(key), &[zlib])
+            .await
+            .unwrap();
+        assert_eq!(published, [zlib]);
+
+        let entry: CacheEntry = serde_json::from_slice(
+            &fs::read(
+                root.join("bucket/porkg")
+                    .join(cache::entry_path(&zlib.to_string())),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(entry.archive, None);
+        assert_eq!(entry.chunks.len(), 1);
+        assert!(root
+            .join("bucket/porkg")
+            .join(cache::chunk_path(&entry.chunks[0]))
+            .exists());
+
+        let index: CacheIndex = serde_json::from_slice(
+            &fs::read(root.join("bucket/porkg").join(cache::INDEX_FILE)).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(index.packages.len(), 2);
+
+        let remote = Remote::new(
+            "cache",
+            &config(url),
+            &to,
+            Secrets::default(),
+            blocking.clone(),
+            2,
+        )
+        .unwrap();
+        assert!(matches!(
+            remote
+                .pull(&to, &blocking, &TrustedKeys::default(), &[zlib])
+                .await,
+            Err(RemoteError::Sync(SyncError::Signature {
+                error: SignatureError::Untrusted(_),
+                ..
+            }))
+        ));
+        let pulled = remote
+            .pull(&to, &blocking, &trusted, &[zlib])
+            .await
+            .unwrap();
+        assert_eq!(pulled, [libc, zlib]);
+        assert_eq!(to.info(&zlib).unwrap().name, "zlib");
+        assert!(to
+            .remote_path("cache")
+            .join(format!("{zlib}.json"))
+            .exists());
+
+        let other = SupportedHash::Blake3([9; 32]);
+        assert!(matches!(
+            remote.pull(&to, &blocking, &trusted, &[other]).await,
+            Err(RemoteError::NotFound(hash)) if hash == other
+        ));
+    }
+}
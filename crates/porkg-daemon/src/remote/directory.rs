@@ -0,0 +1,112 @@
+//! A cache in a local directory, such as a mounted network filesystem or one that is served by a static host.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use bytes::Bytes;
+
+use super::{ObjectStore, RemoteError};
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug)]
+pub struct Directory {
+    root: PathBuf,
+}
+
+impl Directory {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.root.join(key)
+    }
+
+    /// Gets a path next to `path` that an object is written to before it is renamed into place.
+    async fn temporary(&self, path: &Path) -> io::Result<PathBuf> {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let id = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".{}-{id}.tmp", std::process::id()));
+        Ok(path.with_file_name(name))
+    }
+}
+
+impl ObjectStore for Directory {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, RemoteError> {
+        match tokio::fs::read(self.path(key)).await {
+            Ok(data) => Ok(Some(data.into())),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    async fn download(&self, key: &str, file: &mut tokio::fs::File) -> Result<bool, RemoteError> {
+        let mut source = match tokio::fs::File::open(self.path(key)).await {
+            Ok(source) => source,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(error) => return Err(error.into()),
+        };
+        tokio::io::copy(&mut source, file).await?;
+        Ok(true)
+    }
+
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), RemoteError> {
+        let path = self.path(key);
+        let temporary = self.temporary(&path).await?;
+        tokio::fs::write(&temporary, &data).await?;
+        tokio::fs::rename(&temporary, &path).await?;
+        Ok(())
+    }
+
+    async fn upload(
+        &self,
+        key: &str,
+        mut file: tokio::fs::File,
+        _size: u64,
+    ) -> Result<(), RemoteError> {
+        let path = self.path(key);
+        let temporary = self.temporary(&path).await?;
+        let mut target = tokio::fs::File::create(&temporary).await?;
+        tokio::io::copy(&mut file, &mut target).await?;
+        target.sync_all().await?;
+        tokio::fs::rename(&temporary, &path).await?;
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, RemoteError> {
+        // Keys are listed from the directory that contains the prefix.
+        let (directory, _) = prefix.rsplit_once('/').unwrap_or(("", prefix));
+        let mut entries = match tokio::fs::read_dir(self.root.join(directory)).await {
+            Ok(entries) => entries,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(error) => return Err(error.into()),
+        };
+
+        let mut keys = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            let key = if directory.is_empty() {
+                name
+            } else {
+                format!("{directory}/{name}")
+            };
+            if key.starts_with(prefix)
+                && !key.ends_with(".tmp")
+                && entry.file_type().await?.is_file()
+            {
+                keys.push(key);
+            }
+        }
+        keys.sort();
+        Ok(keys)
+    }
+}
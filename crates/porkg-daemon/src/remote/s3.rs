@@ -0,0 +1,449 @@
+//! A cache in an S3-compatible bucket.
+//!
+//! Requests are signed with [`sigv4`](super::sigv4) when credentials are configured, and are anonymous otherwise,
+//! which is enough to substitute from a public bucket. Archives that are larger than a part are uploaded with a
+//! multipart upload, which is aborted if any part fails, so that the bucket isn't left with orphaned parts.
+
+use std::{io, time::SystemTime};
+
+use bytes::Bytes;
+use futures_util::TryStreamExt as _;
+use http_body_util::{combinators::BoxBody, BodyDataStream, BodyExt as _, Empty, Full, StreamBody};
+use hyper::{body::Frame, body::Incoming, header, Method, Request, Response, StatusCode};
+use hyper_rustls::HttpsConnector;
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt as _};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::{blocking::BlockingPool, config::RemoteConfig, secret::Secrets};
+
+use super::{
+    sigv4::{self, Credentials, UNSIGNED_PAYLOAD},
+    ObjectStore, RemoteError,
+};
+
+type Body = BoxBody<Bytes, io::Error>;
+
+fn empty() -> Body {
+    Empty::new().map_err(|never| match never {}).boxed()
+}
+
+fn full(data: Bytes) -> Body {
+    Full::new(data).map_err(|never| match never {}).boxed()
+}
+
+/// Reads the text of each element named `tag` in an XML document.
+fn elements(xml: &str, tag: &str) -> Vec<String> {
+    let (open, close) = (format!("<{tag}>"), format!("</{tag}>"));
+    let mut values = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        let Some(end) = rest.find(&close) else {
+            break;
+        };
+        values.push(unescape(&rest[..end]));
+        rest = &rest[end + close.len()..];
+    }
+    values
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[derive(Debug)]
+pub struct S3 {
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    scheme: String,
+    host: String,
+    /// The path of the bucket for path-style URLs, which is empty for virtual-hosted ones.
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key_id: Option<String>,
+    secret_access_key: Option<String>,
+    part_size: u64,
+    secrets: Secrets,
+    blocking: BlockingPool,
+}
+
+impl S3 {
+    pub fn new(
+        config: &RemoteConfig,
+        secrets: Secrets,
+        blocking: BlockingPool,
+    ) -> Result<Self, RemoteError> {
+        let invalid = || RemoteError::InvalidUrl(config.url.clone());
+        let url = url::Url::parse(&config.url).map_err(|_| invalid())?;
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err(invalid());
+        }
+        let mut host = url.host_str().ok_or_else(invalid)?.to_string();
+        if let Some(port) = url.port() {
+            host = format!("{host}:{port}");
+        }
+        if config.access_key_id.is_some() != config.secret_access_key.is_some() {
+            return Err(RemoteError::IncompleteCredentials);
+        }
+
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+        Ok(Self {
+            client: Client::builder(TokioExecutor::new()).build(connector),
+            scheme: url.scheme().to_string(),
+            host,
+            bucket: url.path().trim_end_matches('/').to_string(),
+            prefix: config.prefix.clone(),
+            region: config.region.clone(),
+            access_key_id: config.access_key_id.clone(),
+            secret_access_key: config.secret_access_key.clone(),
+            part_size: config.part_size.max(5 * 1024 * 1024),
+            secrets,
+            blocking,
+        })
+    }
+
+    /// Gets the encoded path of an object.
+    fn path(&self, key: &str) -> String {
+        sigv4::encode(&format!("{}/{}{key}", self.bucket, self.prefix), true)
+    }
+
+    /// Reads the credentials, if the remote has any.
+    async fn credentials(&self) -> Result<Option<Credentials>, RemoteError> {
+        let (Some(access_key_id), Some(secret_access_key)) =
+            (self.access_key_id.clone(), self.secret_access_key.clone())
+        else {
+            return Ok(None);
+        };
+        let secrets = self.secrets.clone();
+        let credentials = self
+            .blocking
+            .run("read-secret", move || {
+                let access_key_id = secrets.get(&access_key_id)?;
+                Ok::<_, RemoteError>(Credentials {
+                    access_key_id: String::from_utf8_lossy(access_key_id.expose()).into_owned(),
+                    secret_access_key: secrets.get(&secret_access_key)?.expose().to_vec(),
+                })
+            })
+            .await??;
+        Ok(Some(credentials))
+    }
+
+    /// Sends a request, returning the response if it succeeded or if its status is in `allowed`.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        headers: &[(header::HeaderName, String)],
+        body: Body,
+        allowed: &[StatusCode],
+    ) -> Result<Response<Incoming>, RemoteError> {
+        let query: Vec<_> = query
+            .iter()
+            .map(|(k, v)| (sigv4::encode(k, false), sigv4::encode(v, false)))
+            .collect();
+        let timestamp = sigv4::timestamp(SystemTime::now());
+        let signed = [
+            ("host", self.host.clone()),
+            ("x-amz-content-sha256", UNSIGNED_PAYLOAD.to_string()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+
+        let mut uri = format!("{}://{}{path}", self.scheme, self.host);
+        for (i, (k, v)) in query.iter().enumerate() {
+            uri.push(if i == 0 { '?' } else { '&' });
+            uri.push_str(&format!("{k}={v}"));
+        }
+        let mut request = Request::builder()
+            .method(method.clone())
+            .uri(&uri)
+            .header(header::USER_AGENT, crate::sync::USER_AGENT);
+        for (name, value) in signed.iter() {
+            request = request.header(*name, value);
+        }
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        if let Some(credentials) = self.credentials().await? {
+            let authorization = sigv4::authorization(
+                &sigv4::Request {
+                    method: method.as_str(),
+                    path,
+                    query: &query,
+                    headers: &signed,
+                    payload_hash: UNSIGNED_PAYLOAD,
+                },
+                &credentials,
+                &self.region,
+                &timestamp,
+            );
+            request = request.header(header::AUTHORIZATION, authorization);
+        }
+
+        let response = self.client.request(request.body(body)?).await?;
+        let status = response.status();
+        if status.is_success() || allowed.contains(&status) {
+            return Ok(response);
+        }
+        let body = response.into_body().collect().await?.to_bytes();
+        Err(RemoteError::Status {
+            method,
+            key: path.to_string(),
+            status,
+            message: String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+
+    /// Reads the text of a response, failing if it's an error document, which some operations respond to with a
+    /// success status.
+    async fn text(
+        &self,
+        method: Method,
+        path: &str,
+        response: Response<Incoming>,
+    ) -> Result<String, RemoteError> {
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        let text = String::from_utf8_lossy(&body).into_owned();
+        if text.contains("<Error>") {
+            return Err(RemoteError::Status {
+                method,
+                key: path.to_string(),
+                status,
+                message: text,
+            });
+        }
+        Ok(text)
+    }
+
+    /// Uploads `size` bytes of a file from `offset`.
+    async fn send_part(
+        &self,
+        path: &str,
+        query: &[(&str, &str)],
+        file: &tokio::fs::File,
+        offset: u64,
+        size: u64,
+    ) -> Result<Response<Incoming>, RemoteError> {
+        let mut part = file.try_clone().await?;
+        part.seek(io::SeekFrom::Start(offset)).await?;
+        let stream = ReaderStream::new(part.take(size)).map_ok(Frame::data);
+        self.send(
+            Method::PUT,
+            path,
+            query,
+            &[(header::CONTENT_LENGTH, size.to_string())],
+            StreamBody::new(stream).boxed(),
+            &[],
+        )
+        .await
+    }
+
+    async fn upload_parts(
+        &self,
+        path: &str,
+        upload_id: &str,
+        file: &tokio::fs::File,
+        size: u64,
+    ) -> Result<(), RemoteError> {
+        let mut completion = String::from("<CompleteMultipartUpload>");
+        let mut offset = 0;
+        for number in 1.. {
+            let length = self.part_size.min(size - offset);
+            let number = number.to_string();
+            let response = self
+                .send_part(
+                    path,
+                    &[("partNumber", &number), ("uploadId", upload_id)],
+                    file,
+                    offset,
+                    length,
+                )
+                .await?;
+            let etag = response
+                .headers()
+                .get(header::ETAG)
+                .and_then(|v| v.to_str().ok())
+                .ok_or_else(|| RemoteError::InvalidResponse(path.to_string(), "an ETag"))?;
+            completion.push_str(&format!(
+                "<Part><PartNumber>{number}</PartNumber><ETag>{}</ETag></Part>",
+                escape(etag)
+            ));
+            offset += length;
+            if offset >= size {
+                break;
+            }
+        }
+        completion.push_str("</CompleteMultipartUpload>");
+
+        let response = self
+            .send(
+                Method::POST,
+                path,
+                &[("uploadId", upload_id)],
+                &[],
+                full(completion.into()),
+                &[],
+            )
+            .await?;
+        self.text(Method::POST, path, response).await?;
+        Ok(())
+    }
+}
+
+impl ObjectStore for S3 {
+    async fn get(&self, key: &str) -> Result<Option<Bytes>, RemoteError> {
+        let response = self
+            .send(
+                Method::GET,
+                &self.path(key),
+                &[],
+                &[],
+                empty(),
+                &[StatusCode::NOT_FOUND],
+            )
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        Ok(Some(response.into_body().collect().await?.to_bytes()))
+    }
+
+    async fn download(&self, key: &str, file: &mut tokio::fs::File) -> Result<bool, RemoteError> {
+        let response = self
+            .send(
+                Method::GET,
+                &self.path(key),
+                &[],
+                &[],
+                empty(),
+                &[StatusCode::NOT_FOUND],
+            )
+            .await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        let mut reader =
+            StreamReader::new(BodyDataStream::new(response.into_body()).map_err(io::Error::other));
+        tokio::io::copy(&mut reader, file).await?;
+        file.flush().await?;
+        Ok(true)
+    }
+
+    async fn put(&self, key: &str, data: Bytes) -> Result<(), RemoteError> {
+        let length = data.len().to_string();
+        self.send(
+            Method::PUT,
+            &self.path(key),
+            &[],
+            &[(header::CONTENT_LENGTH, length)],
+            full(data),
+            &[],
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn upload(&self, key: &str, file: tokio::fs::File, size: u64) -> Result<(), RemoteError> {
+        let path = self.path(key);
+        if size <= self.part_size {
+            self.send_part(&path, &[], &file, 0, size).await?;
+            return Ok(());
+        }
+
+        let response = self
+            .send(Method::POST, &path, &[("uploads", "")], &[], empty(), &[])
+            .await?;
+        let text = self.text(Method::POST, &path, response).await?;
+        let upload_id = elements(&text, "UploadId")
+            .pop()
+            .ok_or_else(|| RemoteError::InvalidResponse(path.clone(), "an upload ID"))?;
+
+        let result = self.upload_parts(&path, &upload_id, &file, size).await;
+        if result.is_err() {
+            let abort = self
+                .send(
+                    Method::DELETE,
+                    &path,
+                    &[("uploadId", &upload_id)],
+                    &[],
+                    empty(),
+                    &[],
+                )
+                .await;
+            if let Err(error) = abort {
+                tracing::warn!(key, %error, "failed to abort multipart upload");
+            }
+        }
+        result
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, RemoteError> {
+        let path = sigv4::encode(&format!("{}/", self.bucket), true);
+        let full_prefix = format!("{}{prefix}", self.prefix);
+        let mut keys = Vec::new();
+        let mut token = None;
+        loop {
+            let mut query = vec![("list-type", "2"), ("prefix", full_prefix.as_str())];
+            if let Some(token) = token.as_deref() {
+                query.push(("continuation-token", token));
+            }
+            let response = self
+                .send(Method::GET, &path, &query, &[], empty(), &[])
+                .await?;
+            let text = self.text(Method::GET, &path, response).await?;
+            keys.extend(
+                elements(&text, "Key")
+                    .into_iter()
+                    .filter_map(|key| key.strip_prefix(&self.prefix).map(str::to_string)),
+            );
+            if elements(&text, "IsTruncated").first().map(String::as_str) != Some("true") {
+                break;
+            }
+            token = elements(&text, "NextContinuationToken").pop();
+            if token.is_none() {
+                return Err(RemoteError::InvalidResponse(path, "a continuation token"));
+            }
+        }
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_elements() {
+        let xml = "<ListBucketResult><IsTruncated>false</IsTruncated>\
+                   <Contents><Key>porkg/pkg/a.json</Key></Contents>\
+                   <Contents><Key>porkg/pkg/b&amp;c.json</Key></Contents></ListBucketResult>";
+        assert_eq!(
+            elements(xml, "Key"),
+            ["porkg/pkg/a.json", "porkg/pkg/b&c.json"]
+        );
+        assert_eq!(elements(xml, "IsTruncated"), ["false"]);
+        assert!(elements(xml, "UploadId").is_empty());
+        assert_eq!(escape("\"a&b\""), "&quot;a&amp;b&quot;");
+        assert_eq!(unescape(&escape("<\"&'>")), "<\"&'>");
+    }
+}
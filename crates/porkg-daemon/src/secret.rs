@@ -0,0 +1,246 @@
+//! Secrets that fetches use to access private sources.
+//!
+//! Secrets are declared in the daemon configuration and referenced by name from sources. They are read when they are
+//! needed, so that they can be rotated without restarting the daemon, and they are never part of a source hash,
+//! written to the store or logged.
+//!
+//! Fetches may only send a secret to the origins that it is configured with, which is checked before the secret is
+//! read.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt, fs, io,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+
+use thiserror::Error;
+use url::Url;
+
+use crate::config::{SecretConfig, SecretSource};
+
+const CREDENTIALS_DIRECTORY: &str = "CREDENTIALS_DIRECTORY";
+
+#[derive(Debug, Error)]
+pub enum SecretError {
+    #[error("the secret {0:?} is not configured")]
+    Unknown(String),
+    #[error("the secret {name:?} is unavailable: {source}")]
+    Unavailable { name: String, source: io::Error },
+    #[error("the secret {name:?} may not be sent to {url}")]
+    Origin { name: String, url: String },
+}
+
+/// The value of a secret, which is never printed.
+pub struct Secret(Vec<u8>);
+
+impl Secret {
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(<redacted>)")
+    }
+}
+
+/// The secrets declared in the configuration.
+#[derive(Debug, Clone, Default)]
+pub struct Secrets {
+    config: Arc<BTreeMap<String, SecretConfig>>,
+    /// The environment that secrets are read from, instead of the one of the daemon.
+    env: Option<Arc<BTreeMap<String, OsString>>>,
+}
+
+impl Secrets {
+    pub fn new(config: &BTreeMap<String, SecretConfig>) -> Self {
+        Self {
+            config: Arc::new(config.clone()),
+            env: None,
+        }
+    }
+
+    /// Reads secrets from `env` instead of the environment of the daemon.
+    #[cfg(test)]
+    pub fn with_env(mut self, env: BTreeMap<String, OsString>) -> Self {
+        self.env = Some(Arc::new(env));
+        self
+    }
+
+    fn var(&self, name: &str) -> Option<OsString> {
+        match &self.env {
+            Some(env) => env.get(name).cloned(),
+            None => std::env::var_os(name),
+        }
+    }
+
+    /// Checks that the secret `name` may be sent to each of `urls`, which must be within its origins.
+    pub fn check_origins<'a>(
+        &self,
+        name: &str,
+        urls: impl IntoIterator<Item = &'a str>,
+    ) -> Result<(), SecretError> {
+        let config = self
+            .config
+            .get(name)
+            .ok_or_else(|| SecretError::Unknown(name.to_string()))?;
+        let origins: Vec<_> = config
+            .origins
+            .iter()
+            .filter_map(|v| Url::parse(v).ok())
+            .map(|v| v.origin())
+            .collect();
+        for url in urls {
+            let allowed = Url::parse(url).is_ok_and(|v| origins.contains(&v.origin()));
+            if !allowed {
+                return Err(SecretError::Origin {
+                    name: name.to_string(),
+                    url: url.to_string(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the current value of the secret `name`. Trailing line breaks are removed.
+    ///
+    /// This performs blocking IO.
+    pub fn get(&self, name: &str) -> Result<Secret, SecretError> {
+        let unavailable = |source| SecretError::Unavailable {
+            name: name.to_string(),
+            source,
+        };
+
+        let Some(config) = self.config.get(name) else {
+            return Err(SecretError::Unknown(name.to_string()));
+        };
+        let mut value = match &config.source {
+            SecretSource::File(path) => fs::read(path).map_err(unavailable)?,
+            SecretSource::Env(variable) => self
+                .var(variable)
+                .map(|v| v.into_encoded_bytes())
+                .ok_or_else(|| unavailable(io::ErrorKind::NotFound.into()))?,
+            SecretSource::Credential(credential) => {
+                // Credential names can't contain a path, otherwise they could refer to any file.
+                let mut components = Path::new(credential).components();
+                if !matches!(
+                    (components.next(), components.next()),
+                    (Some(Component::Normal(_)), None)
+                ) {
+                    return Err(unavailable(io::ErrorKind::InvalidInput.into()));
+                }
+                let path = self
+                    .var(CREDENTIALS_DIRECTORY)
+                    .map(|v| PathBuf::from(v).join(credential))
+                    .ok_or_else(|| unavailable(io::ErrorKind::NotFound.into()))?;
+                fs::read(path).map_err(unavailable)?
+            }
+        };
+
+        while matches!(value.last(), Some(b'\n' | b'\r')) {
+            value.pop();
+        }
+        Ok(Secret(value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn resolve_secrets() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        fs::write(dir.join("token"), "file-token\n").unwrap();
+        fs::write(dir.join("credential"), "credential-token\n").unwrap();
+
+        let secrets = Secrets::new(&BTreeMap::from([
+            (
+                "file".to_string(),
+                SecretSource::File(dir.join("token")).into(),
+            ),
+            (
+                "env".to_string(),
+                SecretSource::Env("PORKG_TEST_SECRET".to_string()).into(),
+            ),
+            (
+                "missing".to_string(),
+                SecretSource::Env("PORKG_TEST_SECRET_MISSING".to_string()).into(),
+            ),
+            (
+                "credential".to_string(),
+                SecretSource::Credential("credential".to_string()).into(),
+            ),
+            (
+                "escape".to_string(),
+                SecretSource::Credential("../token".to_string()).into(),
+            ),
+        ]))
+        .with_env(BTreeMap::from([
+            ("PORKG_TEST_SECRET".to_string(), "env-token".into()),
+            (CREDENTIALS_DIRECTORY.to_string(), dir.into()),
+        ]));
+
+        assert_eq!(secrets.get("file").unwrap().expose(), b"file-token");
+        assert_eq!(secrets.get("env").unwrap().expose(), b"env-token");
+        assert_eq!(
+            secrets.get("credential").unwrap().expose(),
+            b"credential-token"
+        );
+        assert_eq!(
+            format!("{:?}", secrets.get("env").unwrap()),
+            "Secret(<redacted>)"
+        );
+        assert!(matches!(
+            secrets.get("missing"),
+            Err(SecretError::Unavailable { .. })
+        ));
+        assert!(matches!(
+            secrets.get("escape"),
+            Err(SecretError::Unavailable { .. })
+        ));
+        assert!(matches!(secrets.get("other"), Err(SecretError::Unknown(_))));
+    }
+
+    #[test]
+    fn check_origins() {
+        let mut config = SecretConfig::from(SecretSource::Env("TOKEN".to_string()));
+        config.origins = vec!["https://git.example.com".to_string()];
+        let secrets = Secrets::new(&BTreeMap::from([
+            ("bound".to_string(), config),
+            (
+                "unbound".to_string(),
+                SecretSource::Env("TOKEN".to_string()).into(),
+            ),
+        ]));
+
+        let allowed = "https://git.example.com:443/repo.git";
+        secrets.check_origins("bound", [allowed]).unwrap();
+        for url in [
+            "http://git.example.com/repo.git",
+            "https://git.example.com.evil/repo.git",
+            "https://other.example.com/repo.git",
+            "not a url",
+        ] {
+            assert!(
+                matches!(
+                    secrets.check_origins("bound", [allowed, url]),
+                    Err(SecretError::Origin { .. })
+                ),
+                "{url}"
+            );
+        }
+        assert!(matches!(
+            secrets.check_origins("unbound", [allowed]),
+            Err(SecretError::Origin { .. })
+        ));
+        assert!(matches!(
+            secrets.check_origins("other", [allowed]),
+            Err(SecretError::Unknown(_))
+        ));
+    }
+}
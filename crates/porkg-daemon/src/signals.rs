@@ -0,0 +1,115 @@
+//! The signals that the daemon handles, which a single task turns into [`Event::Signal`]s for the main loop:
+//!
+//! - SIGINT: stop right away.
+//! - SIGTERM: stop accepting connections, and stop once the jobs have finished (see
+//!   [`crate::config::SchedulerConfig::drain_timeout`]).
+//! - SIGHUP: read the secrets that are redacted from logs again.
+//! - SIGUSR1: switch between the configured log level and debug logs.
+//! - SIGQUIT: log a report of the jobs, and of the daemons that were discovered.
+
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::events::{Event, EventBus, SignalAction};
+
+/// The log filter that SIGUSR1 switches to.
+const VERBOSE: &str = "debug";
+
+/// Publishes the signals that the daemon receives until cancelled.
+pub async fn run(events: EventBus, cancellation_token: CancellationToken) -> anyhow::Result<()> {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut user = signal(SignalKind::user_defined1())?;
+    let mut quit = signal(SignalKind::quit())?;
+    loop {
+        let action = tokio::select! {
+            _ = cancellation_token.cancelled() => return Ok(()),
+            _ = interrupt.recv() => SignalAction::Shutdown,
+            _ = terminate.recv() => SignalAction::Drain,
+            _ = hangup.recv() => SignalAction::Reload,
+            _ = user.recv() => SignalAction::LogLevel,
+            _ = quit.recv() => SignalAction::DumpState,
+        };
+        tracing::info!(?action, "received a signal");
+        events.publish(Event::Signal { action });
+    }
+}
+
+/// Switches the log filter of the daemon between the one it was started with and [`VERBOSE`].
+#[derive(Debug)]
+pub struct LogLevel {
+    handle: reload::Handle<EnvFilter, Registry>,
+    /// The directives of `RUST_LOG`.
+    configured: String,
+    verbose: bool,
+}
+
+impl LogLevel {
+    /// Creates the filter from `RUST_LOG`, which must be the first layer of the registry.
+    pub fn new() -> (reload::Layer<EnvFilter, Registry>, Self) {
+        let configured = std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_default();
+        let (layer, handle) = reload::Layer::new(EnvFilter::new(&configured));
+        let level = Self {
+            handle,
+            configured,
+            verbose: false,
+        };
+        (layer, level)
+    }
+
+    pub fn toggle(&mut self) {
+        self.verbose = !self.verbose;
+        let directives = if self.verbose {
+            VERBOSE
+        } else {
+            &self.configured
+        };
+        match self.handle.reload(EnvFilter::new(directives)) {
+            Ok(()) => tracing::info!(directives, "changed the log filter"),
+            Err(error) => tracing::error!(?error, "failed to change the log filter"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use nix::sys::signal::{raise, Signal};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn publish_signals() {
+        // Handling the signal before the task does keeps it from stopping the tests.
+        let _handled = signal(SignalKind::user_defined1()).unwrap();
+        let events = EventBus::default();
+        let mut subscription = events.subscribe();
+        let cancellation_token = CancellationToken::new();
+        let task = tokio::spawn(run(events, cancellation_token.clone()));
+
+        // The task may not handle the signal yet, so it is raised until it does.
+        let event = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                raise(Signal::SIGUSR1).unwrap();
+                let event = tokio::time::timeout(Duration::from_millis(50), subscription.recv());
+                if let Ok(event) = event.await {
+                    break event.unwrap();
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(
+            *event,
+            Event::Signal {
+                action: SignalAction::LogLevel
+            }
+        ));
+
+        cancellation_token.cancel();
+        task.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,160 @@
+//! Ed25519 signatures over packages, which let a store accept packages from peers that it doesn't otherwise trust.
+//!
+//! A signature covers the hash of a package and the NAR hash of its directory, including its metadata, so a package
+//! can't be altered in transit or substituted for another. Keys are named, so that they can be rotated: signing keys
+//! are secrets containing `<name>:<base64 PKCS#8 document>` (such as the output of `openssl genpkey -algorithm ed25519
+//! -outform DER | base64`), and trusted keys are configured by name as the base64 public key, which the daemon logs
+//! when it starts.
+
+use std::collections::BTreeMap;
+
+use data_encoding::BASE64;
+use porkg_model::hashing::SupportedHash;
+use ring::signature::{Ed25519KeyPair, KeyPair as _, UnparsedPublicKey, ED25519};
+use thiserror::Error;
+
+/// The response header that carries the signature of an exported package.
+pub const SIGNATURE_HEADER: &str = "x-porkg-signature";
+
+#[derive(Debug, Error)]
+pub enum SignatureError {
+    #[error("the signing key is not of the form <name>:<base64 PKCS#8 document>")]
+    InvalidKey,
+    #[error("the trusted key {0:?} is not a base64 public key")]
+    InvalidTrustedKey(String),
+    #[error("the package is not signed")]
+    Unsigned,
+    #[error("{0:?} is not of the form <key name>:<base64 signature>")]
+    Malformed(String),
+    #[error("the package is signed by {0:?}, which is not trusted")]
+    Untrusted(String),
+    #[error("the signature by {0:?} doesn't match the package")]
+    Mismatch(String),
+}
+
+fn message(hash: &SupportedHash, content: &SupportedHash) -> String {
+    format!("porkg-package-1:{hash}:{content}")
+}
+
+#[derive(Debug)]
+pub struct SigningKey {
+    name: String,
+    pair: Ed25519KeyPair,
+}
+
+impl SigningKey {
+    pub fn parse(value: &[u8]) -> Result<Self, SignatureError> {
+        let value = std::str::from_utf8(value).map_err(|_| SignatureError::InvalidKey)?;
+        let (name, document) = value
+            .trim()
+            .split_once(':')
+            .ok_or(SignatureError::InvalidKey)?;
+        let document = BASE64
+            .decode(document.as_bytes())
+            .map_err(|_| SignatureError::InvalidKey)?;
+        let pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(&document)
+            .map_err(|_| SignatureError::InvalidKey)?;
+        Ok(Self {
+            name: name.to_string(),
+            pair,
+        })
+    }
+
+    /// Signs a package, given the NAR hash of its directory.
+    pub fn sign(&self, hash: &SupportedHash, content: &SupportedHash) -> String {
+        let signature = self.pair.sign(message(hash, content).as_bytes());
+        format!("{}:{}", self.name, BASE64.encode(signature.as_ref()))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Gets the public key, in the form that it is trusted by other daemons.
+    pub fn public_key(&self) -> String {
+        BASE64.encode(self.pair.public_key().as_ref())
+    }
+}
+
+/// The keys whose signatures are accepted, by name.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedKeys(BTreeMap<String, Vec<u8>>);
+
+impl TrustedKeys {
+    pub fn new(config: &BTreeMap<String, String>) -> Result<Self, SignatureError> {
+        config
+            .iter()
+            .map(|(name, key)| {
+                BASE64
+                    .decode(key.as_bytes())
+                    .map(|v| (name.clone(), v))
+                    .map_err(|_| SignatureError::InvalidTrustedKey(name.clone()))
+            })
+            .collect::<Result<_, _>>()
+            .map(Self)
+    }
+
+    /// Verifies the signature of a package, given the NAR hash of its directory.
+    pub fn verify(
+        &self,
+        hash: &SupportedHash,
+        content: &SupportedHash,
+        signature: Option<&str>,
+    ) -> Result<(), SignatureError> {
+        let signature = signature.ok_or(SignatureError::Unsigned)?;
+        let (name, value) = signature
+            .split_once(':')
+            .and_then(|(name, value)| Some((name, BASE64.decode(value.as_bytes()).ok()?)))
+            .ok_or_else(|| SignatureError::Malformed(signature.to_string()))?;
+        let key = self
+            .0
+            .get(name)
+            .ok_or_else(|| SignatureError::Untrusted(name.to_string()))?;
+        UnparsedPublicKey::new(&ED25519, key)
+            .verify(message(hash, content).as_bytes(), &value)
+            .map_err(|_| SignatureError::Mismatch(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use ring::rand::SystemRandom;
+
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let document = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let secret = format!("office-1:{}\n", BASE64.encode(document.as_ref()));
+        let key = SigningKey::parse(secret.as_bytes()).unwrap();
+        assert!(matches!(
+            SigningKey::parse(b"office-1"),
+            Err(SignatureError::InvalidKey)
+        ));
+
+        let hash = SupportedHash::Blake3([1; 32]);
+        let content = SupportedHash::Blake3([2; 32]);
+        let signature = key.sign(&hash, &content);
+        assert!(signature.starts_with("office-1:"));
+
+        let trusted =
+            TrustedKeys::new(&[("office-1".to_string(), key.public_key())].into()).unwrap();
+        trusted.verify(&hash, &content, Some(&signature)).unwrap();
+        assert!(matches!(
+            trusted.verify(&content, &content, Some(&signature)),
+            Err(SignatureError::Mismatch(_))
+        ));
+        assert!(matches!(
+            trusted.verify(&hash, &content, None),
+            Err(SignatureError::Unsigned)
+        ));
+        assert!(matches!(
+            TrustedKeys::default().verify(&hash, &content, Some(&signature)),
+            Err(SignatureError::Untrusted(_))
+        ));
+        assert!(matches!(
+            trusted.verify(&hash, &content, Some("office-1")),
+            Err(SignatureError::Malformed(_))
+        ));
+    }
+}
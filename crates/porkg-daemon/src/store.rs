@@ -0,0 +1,691 @@
+//! The on-disk package store.
+//!
+//! See `notes/fs-layout.md` for the layout.
+
+use std::{
+    collections::BTreeSet,
+    ffi::OsString,
+    fs, io,
+    os::unix::fs::PermissionsExt as _,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use ::nix::errno::Errno;
+use porkg_model::{
+    hashing::SupportedHash,
+    store::{self as model, LinkTarget, PackageInfo, SymlinkPolicy},
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    config::StoreConfig,
+    events::{Event, EventBus, StoreChange},
+};
+
+pub mod audit;
+pub mod cache;
+pub mod channel;
+pub mod chunk;
+pub mod elf;
+pub mod gc;
+pub mod history;
+pub mod nar;
+pub mod nix;
+pub mod oci;
+pub mod pack;
+pub mod profile;
+pub mod provenance;
+pub mod relocate;
+pub mod source;
+pub mod sync;
+#[cfg(test)]
+pub(crate) mod testing;
+pub mod wrapper;
+
+const INFO_FILE: &str = "porkg.json";
+/// The directory of packages that contains the wrappers of their executables (see [`wrapper`]).
+const WRAPPERS: &str = "bin";
+
+/// The permissions of directories and executables within packages.
+const MODE_EXECUTABLE: u32 = 0o555;
+/// The permissions of other files within packages.
+const MODE_FILE: u32 = 0o444;
+const MODE_SET_ID: u32 = 0o6000;
+
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+/// Serializes claims, so that the check for an owner and the update happen together.
+static CLAIM: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error(transparent)]
+    Serialization(#[from] serde_json::Error),
+    #[error("package {0} was not found in the store")]
+    NotFound(SupportedHash),
+    #[error("{path:?} links to {target:?}, which is scratch space that won't exist once the package is stored")]
+    ScratchLink { path: PathBuf, target: PathBuf },
+    #[error(transparent)]
+    Wrapper(#[from] wrapper::WrapperError),
+}
+
+impl StoreError {
+    /// Gets the failure of the disk that caused the error, if it was caused by one.
+    pub fn disk_fault(&self) -> Option<DiskFault> {
+        match self {
+            StoreError::IO(error) => DiskFault::of(error),
+            _ => None,
+        }
+    }
+}
+
+/// A failure of the disk that the store is on, which fails every write to the store until it is resolved (see
+/// [`crate::degraded`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiskFault {
+    #[error("the disk of the store is full")]
+    Full,
+    #[error("the disk of the store is failing")]
+    Failing,
+}
+
+impl DiskFault {
+    /// Gets the failure of the disk that caused `error`, if it was caused by one.
+    pub fn of(error: &io::Error) -> Option<Self> {
+        match Errno::from_raw(error.raw_os_error()?) {
+            Errno::ENOSPC | Errno::EDQUOT => Some(DiskFault::Full),
+            Errno::EIO => Some(DiskFault::Failing),
+            _ => None,
+        }
+    }
+}
+
+/// Gets the names of the targets of the package in `dir`: its directories, except for its source and the wrappers of
+/// its executables.
+fn targets(dir: &Path) -> io::Result<Vec<OsString>> {
+    let mut result = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        if entry.file_type()?.is_dir() && name != "src" && name != WRAPPERS {
+            result.push(name);
+        }
+    }
+    result.sort();
+    Ok(result)
+}
+
+/// Normalizes the permissions of a file or directory tree (see [`Store::insert`]). Symlinks have no permissions of
+/// their own, and are left alone.
+fn normalize_permissions(path: &Path, preserve: bool) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_symlink() {
+        return Ok(());
+    }
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            normalize_permissions(&entry?.path(), preserve)?;
+        }
+    }
+
+    let current = metadata.permissions().mode() & 0o7777;
+    let mode = if preserve {
+        current & !MODE_SET_ID
+    } else if metadata.is_dir() || current & 0o100 != 0 {
+        MODE_EXECUTABLE
+    } else {
+        MODE_FILE
+    };
+    if mode != current {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Checks the absolute symlinks in a file or directory tree (see [`Store::insert`]), collecting the packages that they
+/// link to.
+fn check_links(
+    policy: &SymlinkPolicy,
+    path: &Path,
+    references: &mut BTreeSet<SupportedHash>,
+) -> Result<(), StoreError> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            check_links(policy, &entry?.path(), references)?;
+        }
+    } else if metadata.is_symlink() {
+        let target = fs::read_link(path)?;
+        if target.is_absolute() {
+            match policy.classify(&target) {
+                LinkTarget::Package { hash, .. } => {
+                    references.insert(hash);
+                }
+                LinkTarget::Scratch => {
+                    return Err(StoreError::ScratchLink {
+                        path: path.to_path_buf(),
+                        target,
+                    })
+                }
+                LinkTarget::Other => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How the store of the host is bound into sandboxes: its directory on the host appears at its prefix (see
+/// [`StoreConfig::prefix`]).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoreMount {
+    pub path: PathBuf,
+    pub prefix: PathBuf,
+}
+
+impl StoreMount {
+    /// Gets where a path of the store on the host appears within sandboxes.
+    pub fn logical(&self, path: &Path) -> PathBuf {
+        match path.strip_prefix(&self.path) {
+            Ok(relative) => self.prefix.join(relative),
+            Err(_) => path.to_path_buf(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Store {
+    path: PathBuf,
+    prefix: PathBuf,
+    events: EventBus,
+    origin: history::Origin,
+}
+
+impl Store {
+    pub fn new(config: &StoreConfig) -> Self {
+        Self {
+            path: config.path.clone(),
+            prefix: config.prefix.clone(),
+            events: EventBus::default(),
+            origin: history::Origin::default(),
+        }
+    }
+
+    /// Publishes the packages that are added to or deleted from the store to `events`.
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Records `actor` and `reason` in the history (see [`history`]) for the changes made through this store.
+    pub fn with_origin(mut self, actor: &str, reason: &'static str) -> Self {
+        self.origin = history::Origin {
+            actor: actor.into(),
+            reason,
+        };
+        self
+    }
+
+    /// Records a change in the history, and publishes it.
+    ///
+    /// This performs blocking IO.
+    fn publish(&self, hash: &SupportedHash, name: &str, change: StoreChange, bytes: u64) {
+        if let Err(error) = history::append(self, hash, name, change, bytes) {
+            tracing::error!(?error, %hash, ?change, "failed to record a change in the history");
+            if let Some(fault) = DiskFault::of(&error) {
+                self.report_fault(fault, error.to_string());
+            }
+        }
+        self.events.publish(Event::Store {
+            hash: *hash,
+            name: name.to_string(),
+            change,
+        });
+    }
+
+    /// Publishes a failure of the disk, which pauses builds until the store can be written to again (see
+    /// [`crate::degraded`]).
+    pub fn report_fault(&self, fault: DiskFault, error: String) {
+        self.events.publish(Event::DiskFault { fault, error });
+    }
+
+    /// Gets the directory containing a package.
+    pub fn by_hash(&self, hash: &SupportedHash) -> PathBuf {
+        self.path.join("pkg/by-hash").join(hash.to_string())
+    }
+
+    /// Gets where a package is found within sandboxes (see [`StoreConfig::prefix`]), which is the path that other
+    /// packages refer to it by.
+    pub fn logical(&self, hash: &SupportedHash) -> PathBuf {
+        self.prefix.join("pkg/by-hash").join(hash.to_string())
+    }
+
+    /// Gets how the store is bound into sandboxes.
+    pub fn mount(&self) -> StoreMount {
+        StoreMount {
+            path: self.path.clone(),
+            prefix: self.prefix.clone(),
+        }
+    }
+
+    /// Gets the directory containing the links to each version of a package.
+    pub fn by_name(&self, name: &str) -> PathBuf {
+        self.path.join("pkg/by-name").join(name)
+    }
+
+    /// Gets the link to a package that was imported from Nix, by the base name of its Nix store path.
+    pub fn by_nix(&self, base_name: &str) -> PathBuf {
+        self.path.join("pkg/by-nix").join(base_name)
+    }
+
+    /// Gets the directory that the provenance of each build of a package is kept in (see [`provenance::record`]).
+    pub fn builds_path(&self, hash: &SupportedHash) -> PathBuf {
+        self.path.join("builds").join(hash.to_string())
+    }
+
+    /// Gets the directory that the metadata of packages read from a remote binary cache is kept in.
+    pub fn remote_path(&self, remote: &str) -> PathBuf {
+        self.path.join("remote").join(remote)
+    }
+
+    /// Gets a unique path within the store that can be used to stage new content.
+    ///
+    /// Staging within the store ensures that the content can be atomically renamed into place.
+    pub fn temp_path(&self, prefix: &str) -> PathBuf {
+        let id = TEMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        self.path
+            .join("tmp")
+            .join(format!("{prefix}-{}-{id}", std::process::id()))
+    }
+
+    /// Moves a staged package directory into the store and links it by name.
+    ///
+    /// If the package refers to itself (see [`PackageInfo::self_references`]), the placeholder that its build used for
+    /// its final path is rewritten first (see [`relocate`]). If it asks for its ELF files to be patched (see
+    /// [`PackageInfo::patch_elf`]), they are then pointed at the loader and libraries of its closure (see [`elf`]).
+    /// Finally, wrappers are generated for the executables that it declares (see [`wrapper`]).
+    ///
+    /// The contents of the package (but not its metadata) are normalized first: setuid and setgid bits are removed, and
+    /// unless the package asks for its permissions to be preserved, directories and files that their owner can execute
+    /// become `0555` and other files `0444`. This way the store doesn't depend on the umask of whatever created them.
+    ///
+    /// Absolute symlinks are checked too (see [`SymlinkPolicy`]). Links into other packages are added to the references
+    /// of the package, and links into scratch space (the temporary directory of the store, which staging directories are
+    /// in) fail the insertion, as they would be broken.
+    ///
+    /// Returns `false` if the package was already present, in which case `source` is left untouched.
+    pub fn insert(
+        &self,
+        source: &Path,
+        hash: &SupportedHash,
+        name: &str,
+    ) -> Result<bool, StoreError> {
+        self.insert_staged(source, hash, name).inspect_err(|error| {
+            if let Some(fault) = error.disk_fault() {
+                self.report_fault(fault, error.to_string());
+            }
+        })
+    }
+
+    fn insert_staged(
+        &self,
+        source: &Path,
+        hash: &SupportedHash,
+        name: &str,
+    ) -> Result<bool, StoreError> {
+        let destination = self.by_hash(hash);
+        let installed = self.logical(hash);
+        if destination.exists() {
+            tracing::debug!(%hash, "package already exists in the store");
+            return Ok(false);
+        }
+
+        let info = match fs::read(source.join(INFO_FILE)) {
+            Ok(data) => Some(serde_json::from_slice::<PackageInfo>(&data)?),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => None,
+            Err(error) => return Err(error.into()),
+        };
+        if info.as_ref().is_some_and(|v| v.self_references) {
+            let placeholder = model::placeholder(&self.prefix, hash);
+            for entry in fs::read_dir(source)? {
+                let entry = entry?;
+                if entry.file_name() != INFO_FILE {
+                    let count = relocate::rewrite(&entry.path(), &placeholder, &installed)?;
+                    tracing::debug!(%hash, path = ?entry.path(), count, "rewrote self-references");
+                }
+            }
+        }
+        if let Some(info) = info.as_ref().filter(|v| v.patch_elf) {
+            let mut fixup = elf::Fixup::default();
+            fixup.add_package(source, &installed)?;
+            let mut visited = BTreeSet::new();
+            for reference in info.references.iter().filter(|v| *v != hash) {
+                for (dependency, _) in self.closure(reference)? {
+                    if visited.insert(dependency) {
+                        let dir = self.by_hash(&dependency);
+                        fixup.add_package(&dir, &self.logical(&dependency))?;
+                    }
+                }
+            }
+            for entry in fs::read_dir(source)? {
+                let entry = entry?;
+                if entry.file_name() != INFO_FILE {
+                    let count = fixup.patch_tree(&entry.path())?;
+                    tracing::debug!(%hash, path = ?entry.path(), count, "patched ELF files");
+                }
+            }
+        }
+        if let Some(info) = info.as_ref().filter(|v| !v.executables.is_empty()) {
+            let mut wrappers = wrapper::Wrappers::new(source, &installed)?;
+            for reference in info.references.iter().filter(|v| *v != hash) {
+                wrappers.add_reference(
+                    &self.info(reference)?.name,
+                    &self.by_hash(reference),
+                    &self.logical(reference),
+                )?;
+            }
+            wrappers.generate(source, &info.executables)?;
+        }
+        let preserve = info.as_ref().is_some_and(|v| v.preserve_permissions);
+        let policy = SymlinkPolicy::new(&self.prefix)
+            .scratch(self.prefix.join("tmp"))
+            .scratch(self.path.join("tmp"))
+            .scratch(source);
+        let mut references = BTreeSet::new();
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            if entry.file_name() != INFO_FILE {
+                normalize_permissions(&entry.path(), preserve)?;
+                check_links(&policy, &entry.path(), &mut references)?;
+            }
+        }
+        references.remove(hash);
+        let mut info = info.unwrap_or_else(|| PackageInfo::new(name));
+        if !references.is_subset(&info.references) {
+            tracing::debug!(%hash, ?references, "recording references from symlinks");
+            info.references.extend(references);
+            Self::write_info(source, &info)?;
+        }
+
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(source, &destination)?;
+
+        let by_name = self.by_name(name);
+        fs::create_dir_all(&by_name)?;
+        link(&destination, &by_name.join(hash.to_string()))?;
+        self.publish(
+            hash,
+            name,
+            StoreChange::Added,
+            gc::disk_usage(&destination)?,
+        );
+        Ok(true)
+    }
+
+    /// Reads the metadata of a package.
+    ///
+    /// Packages that were placed in the store without metadata are assumed to have no references.
+    pub fn info(&self, hash: &SupportedHash) -> Result<PackageInfo, StoreError> {
+        let dir = self.by_hash(hash);
+        match fs::read(dir.join(INFO_FILE)) {
+            Ok(data) => Ok(serde_json::from_slice(&data)?),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                if dir.is_dir() {
+                    Ok(PackageInfo::new(hash.to_string()))
+                } else {
+                    Err(StoreError::NotFound(*hash))
+                }
+            }
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// Writes the metadata of a package into a package directory.
+    pub fn write_info(dir: &Path, info: &PackageInfo) -> Result<(), StoreError> {
+        fs::write(dir.join(INFO_FILE), serde_json::to_vec_pretty(info)?)?;
+        Ok(())
+    }
+
+    /// Records `tenant` as the owner of a package, unless the package already has one. Packages with the same hash are
+    /// shared between tenants, so the owner is the first tenant that added it.
+    pub fn claim(&self, hash: &SupportedHash, tenant: Option<&str>) -> Result<(), StoreError> {
+        let Some(tenant) = tenant else {
+            return Ok(());
+        };
+        let _guard = CLAIM.lock().unwrap_or_else(|v| v.into_inner());
+        let mut info = self.info(hash)?;
+        if info.tenant.is_some() {
+            return Ok(());
+        }
+        info.tenant = Some(tenant.to_string());
+
+        // The metadata is replaced as a whole, so that readers never see it partially written.
+        let path = self.by_hash(hash).join(INFO_FILE);
+        let temp = self.temp_path("claim");
+        if let Some(parent) = temp.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&temp, serde_json::to_vec_pretty(&info)?)?;
+        let result = match fs::metadata(&path) {
+            Ok(metadata) => fs::set_permissions(&temp, metadata.permissions()),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(error) => Err(error),
+        }
+        .and_then(|()| fs::rename(&temp, &path));
+        if let Err(error) = result {
+            fs::remove_file(&temp).ok();
+            return Err(error.into());
+        }
+        Ok(())
+    }
+
+    /// Gets the closure of a package: the package and everything it references, directly or indirectly.
+    ///
+    /// Packages are ordered such that each package appears after everything that it references.
+    pub fn closure(
+        &self,
+        hash: &SupportedHash,
+    ) -> Result<Vec<(SupportedHash, PackageInfo)>, StoreError> {
+        let mut visited = BTreeSet::new();
+        let mut result = Vec::new();
+        self.visit_closure(hash, &mut visited, &mut result)?;
+        Ok(result)
+    }
+
+    fn visit_closure(
+        &self,
+        hash: &SupportedHash,
+        visited: &mut BTreeSet<SupportedHash>,
+        result: &mut Vec<(SupportedHash, PackageInfo)>,
+    ) -> Result<(), StoreError> {
+        if !visited.insert(*hash) {
+            return Ok(());
+        }
+
+        let info = self.info(hash)?;
+        for reference in info.references.iter().filter(|v| *v != hash) {
+            self.visit_closure(reference, visited, result)?;
+        }
+        result.push((*hash, info));
+        Ok(())
+    }
+}
+
+/// Creates a symlink, succeeding if the link already exists.
+fn link(original: &Path, link: &Path) -> io::Result<()> {
+    match std::os::unix::fs::symlink(original, link) {
+        Err(error) if error.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{testing::TestStore, *};
+
+    fn mode(path: &Path) -> u32 {
+        fs::symlink_metadata(path).unwrap().permissions().mode() & 0o7777
+    }
+
+    #[test]
+    fn normalize_on_insert() {
+        let store = TestStore::new("store");
+        let root = store.root();
+
+        let stage = |name: &str, preserve: bool| {
+            let staging = root.join("staging").join(name);
+            fs::create_dir_all(staging.join("out/bin")).unwrap();
+            for (file, mode) in [("bin/tool", 0o4711), ("data", 0o664), ("private", 0o600)] {
+                let path = staging.join("out").join(file);
+                fs::write(&path, file).unwrap();
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode)).unwrap();
+            }
+            fs::set_permissions(staging.join("out/bin"), fs::Permissions::from_mode(0o2775))
+                .unwrap();
+            std::os::unix::fs::symlink("bin/tool", staging.join("out/tool")).unwrap();
+            let mut info = PackageInfo::new(name);
+            info.preserve_permissions = preserve;
+            Store::write_info(&staging, &info).unwrap();
+            staging
+        };
+
+        let hash = SupportedHash::Blake3([1; 32]);
+        assert!(store
+            .insert(&stage("normal", false), &hash, "normal")
+            .unwrap());
+        let dir = store.by_hash(&hash);
+        assert_eq!(
+            ["out", "out/bin", "out/bin/tool", "out/data", "out/private"]
+                .map(|v| mode(&dir.join(v))),
+            [0o555, 0o555, 0o555, 0o444, 0o444]
+        );
+        assert_eq!(mode(&dir.join("out/tool")), 0o777);
+        // The metadata stays writable, so that it can be updated.
+        assert_ne!(mode(&dir.join(INFO_FILE)) & 0o200, 0);
+
+        let hash = SupportedHash::Blake3([2; 32]);
+        assert!(store
+            .insert(&stage("preserved", true), &hash, "preserved")
+            .unwrap());
+        let dir = store.by_hash(&hash);
+        assert_eq!(
+            ["out/bin", "out/bin/tool", "out/data", "out/private"].map(|v| mode(&dir.join(v))),
+            [0o775, 0o711, 0o664, 0o600]
+        );
+    }
+
+    #[test]
+    fn check_links_on_insert() {
+        let store = TestStore::new("store-links");
+        let root = store.root();
+        let libc = SupportedHash::Blake3([1; 32]);
+        let staging = store.temp_path("stage");
+        fs::create_dir_all(staging.join("out/lib")).unwrap();
+        std::os::unix::fs::symlink(
+            store
+                .by_name("libc")
+                .join(format!("{libc}/out/lib/libc.so")),
+            staging.join("out/lib/libc.so"),
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("/usr/bin/env", staging.join("out/env")).unwrap();
+
+        let hash = SupportedHash::Blake3([2; 32]);
+        assert!(store.insert(&staging, &hash, "app").unwrap());
+        assert_eq!(store.info(&hash).unwrap().references, [libc].into());
+
+        let staging = store.temp_path("stage");
+        fs::create_dir_all(staging.join("out")).unwrap();
+        std::os::unix::fs::symlink(staging.join("build/config"), staging.join("out/config"))
+            .unwrap();
+        assert!(matches!(
+            store.insert(&staging, &SupportedHash::Blake3([3; 32]), "broken"),
+            Err(StoreError::ScratchLink { .. })
+        ));
+
+        // Links through the placeholder of the build are rewritten instead.
+        let hash = SupportedHash::Blake3([4; 32]);
+        let placeholder = porkg_model::store::placeholder(root, &hash);
+        let staging = store.temp_path("stage");
+        fs::create_dir_all(staging.join("out")).unwrap();
+        std::os::unix::fs::symlink(placeholder.join("out/share"), staging.join("out/data"))
+            .unwrap();
+        let mut info = PackageInfo::new("relocatable");
+        info.self_references = true;
+        Store::write_info(&staging, &info).unwrap();
+        assert!(store.insert(&staging, &hash, "relocatable").unwrap());
+        assert_eq!(
+            fs::read_link(store.by_hash(&hash).join("out/data")).unwrap(),
+            store.by_hash(&hash).join("out/share")
+        );
+        assert!(store.info(&hash).unwrap().references.is_empty());
+    }
+
+    #[test]
+    fn refer_by_prefix() {
+        let test = TestStore::new("store-prefix");
+        let root = test.root();
+        let store = Store::new(&StoreConfig {
+            path: root.join("store"),
+            prefix: "/porkg/store".into(),
+        });
+        let libc = SupportedHash::Blake3([1; 32]);
+        let staging = store.temp_path("stage");
+        fs::create_dir_all(staging.join("out")).unwrap();
+        std::os::unix::fs::symlink(
+            store.logical(&libc).join("out/lib"),
+            staging.join("out/lib"),
+        )
+        .unwrap();
+        let hash = SupportedHash::Blake3([2; 32]);
+        let placeholder = porkg_model::store::placeholder(Path::new("/porkg/store"), &hash);
+        std::os::unix::fs::symlink(placeholder.join("out/share"), staging.join("out/data"))
+            .unwrap();
+        let mut info = PackageInfo::new("app");
+        info.self_references = true;
+        Store::write_info(&staging, &info).unwrap();
+        assert!(store.insert(&staging, &hash, "app").unwrap());
+
+        // Packages refer to each other where they are found within sandboxes, rather than on the host.
+        let dir = store.by_hash(&hash);
+        assert!(dir.starts_with(root.join("store")));
+        assert_eq!(
+            fs::read_link(dir.join("out/data")).unwrap(),
+            Path::new("/porkg/store/pkg/by-hash")
+                .join(hash.to_string())
+                .join("out/share")
+        );
+        assert_eq!(store.info(&hash).unwrap().references, [libc].into());
+        assert_eq!(
+            store.mount().logical(&dir),
+            Path::new("/porkg/store/pkg/by-hash").join(hash.to_string())
+        );
+    }
+
+    #[test]
+    fn claim_once() {
+        let store = TestStore::new("claim");
+        let hash = store.add("app", &[]);
+        store.claim(&hash, None).unwrap();
+        assert_eq!(store.info(&hash).unwrap().tenant, None);
+
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let store = &store;
+                scope.spawn(move || store.claim(&hash, Some(&format!("team-{i}"))).unwrap());
+            }
+        });
+        let tenant = store.info(&hash).unwrap().tenant.unwrap();
+        store.claim(&hash, Some("other")).unwrap();
+        assert_eq!(store.info(&hash).unwrap().tenant, Some(tenant));
+        assert_eq!(fs::read_dir(store.root().join("tmp")).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,248 @@
+//! Audits of the closures of packages and profiles against a database of vulnerabilities (see
+//! [`porkg_model::audit`]).
+//!
+//! The database is a directory of OSV files, one vulnerability per file, such as an unpacked export of
+//! `osv-vulnerabilities`. It is read for every audit, so that it can be updated without restarting the daemon.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use porkg_model::{
+    audit::{split_name, AuditReport, Finding, Rebuild, Vulnerability},
+    channel,
+    hashing::SupportedHash,
+};
+use thiserror::Error;
+
+use super::{gc, Store, StoreError};
+
+#[derive(Debug, Error)]
+pub enum AuditError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("failed to read the advisory database {path:?}: {error}")]
+    Database { path: PathBuf, error: io::Error },
+    #[error("{0:?} is not a valid profile name")]
+    InvalidProfile(String),
+    #[error("the profile {0} does not exist")]
+    ProfileNotFound(String),
+}
+
+/// What an audit covers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Package(SupportedHash),
+    /// The packages of the newest generation of a profile.
+    Profile(String),
+}
+
+impl Target {
+    /// Parses a hash, or else the name of a profile.
+    pub fn parse(value: &str) -> Self {
+        match value.parse() {
+            Ok(hash) => Target::Package(hash),
+            Err(_) => Target::Profile(value.to_string()),
+        }
+    }
+
+    /// Gets the packages whose closures are audited.
+    ///
+    /// This performs blocking IO.
+    fn roots(&self, store: &Store) -> Result<BTreeSet<SupportedHash>, AuditError> {
+        match self {
+            Target::Package(hash) => Ok([*hash].into()),
+            Target::Profile(name) if !channel::is_valid_name(name) => {
+                Err(AuditError::InvalidProfile(name.clone()))
+            }
+            Target::Profile(name) => {
+                let roots = match gc::generations(store, name)?.last() {
+                    Some(&newest) => gc::generation(store, name, newest)?,
+                    None => None,
+                };
+                roots.ok_or_else(|| AuditError::ProfileNotFound(name.clone()))
+            }
+        }
+    }
+}
+
+/// Reads the vulnerabilities in `dir`. Files that aren't vulnerabilities are skipped.
+///
+/// This performs blocking IO.
+pub fn load(dir: &Path) -> Result<Vec<Vulnerability>, AuditError> {
+    let error = |error| AuditError::Database {
+        path: dir.to_path_buf(),
+        error,
+    };
+    let mut result = Vec::new();
+    for entry in fs::read_dir(dir).map_err(error)? {
+        let path = entry.map_err(error)?.path();
+        if path.extension().is_some_and(|v| v == "json") {
+            let data = fs::read(&path).map_err(error)?;
+            match serde_json::from_slice(&data) {
+                Ok(vulnerability) => result.push(vulnerability),
+                Err(error) => tracing::warn!(?error, ?path, "skipping an invalid advisory"),
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Audits the closure of `target` against `database`, matching only the vulnerabilities of `ecosystems` if there are
+/// some.
+///
+/// This performs blocking IO.
+pub fn audit(
+    store: &Store,
+    target: &Target,
+    database: &[Vulnerability],
+    ecosystems: &[String],
+) -> Result<AuditReport, AuditError> {
+    let mut by_name = BTreeMap::<&str, Vec<&Vulnerability>>::new();
+    for vulnerability in database {
+        for affected in vulnerability.affected.iter() {
+            by_name
+                .entry(affected.package.name.as_str())
+                .or_default()
+                .push(vulnerability);
+        }
+    }
+
+    // The closures are merged in order, so that every package still comes after everything that it references.
+    let mut visited = BTreeSet::new();
+    let mut closure = Vec::new();
+    for root in target.roots(store)? {
+        for (hash, info) in store.closure(&root)? {
+            if visited.insert(hash) {
+                closure.push((hash, info));
+            }
+        }
+    }
+
+    let mut report = AuditReport {
+        packages: closure.len(),
+        ..Default::default()
+    };
+    let mut tainted = BTreeSet::new();
+    for (hash, info) in closure {
+        let (name, version) = split_name(&info.name);
+        let mut vulnerabilities: Vec<_> = version
+            .and_then(|version| {
+                let candidates = by_name.get(name)?;
+                Some(
+                    candidates
+                        .iter()
+                        .filter(|v| v.affects(name, version, ecosystems))
+                        .map(|v| (*v).clone())
+                        .collect(),
+                )
+            })
+            .unwrap_or_default();
+        vulnerabilities.dedup_by(|a, b| a.id == b.id);
+
+        match version {
+            Some(version) if !vulnerabilities.is_empty() => {
+                tainted.insert(hash);
+                report.findings.push(Finding {
+                    hash: hash.to_string(),
+                    name: name.to_string(),
+                    version: version.to_string(),
+                    path: store.logical(&hash).display().to_string(),
+                    vulnerabilities,
+                });
+            }
+            _ if !info.references.is_disjoint(&tainted) => {
+                tainted.insert(hash);
+                report.rebuild.push(Rebuild {
+                    hash: hash.to_string(),
+                    name: info.name.clone(),
+                });
+            }
+            _ => {}
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+    use crate::store::testing::{add_package, TestStore};
+
+    #[test]
+    fn audit_closures() {
+        let store = TestStore::new("audit");
+        let xz = add_package(&store, "xz-5.6.1", &[], 1);
+        let zlib = add_package(&store, "zlib-1.3.1", &[], 2);
+        let curl = add_package(&store, "curl-8.7.1", &[xz, zlib], 3);
+        let app = add_package(&store, "app", &[curl], 4);
+        let tool = add_package(&store, "tool", &[zlib], 5);
+
+        let database = store.root().join("osv");
+        fs::create_dir_all(&database).unwrap();
+        fs::write(
+            database.join("CVE-2024-3094.json"),
+            r#"{
+                "id": "CVE-2024-3094",
+                "summary": "backdoor in liblzma",
+                "affected": [{
+                    "package": {"name": "xz", "ecosystem": "Nix"},
+                    "ranges": [{"type": "ECOSYSTEM", "events": [{"introduced": "5.6.0"}, {"fixed": "5.6.2"}]}]
+                }]
+            }"#,
+        )
+        .unwrap();
+        fs::write(database.join("README.md"), "not an advisory").unwrap();
+        fs::write(database.join("broken.json"), "{").unwrap();
+        let database = load(&database).unwrap();
+        assert_eq!(database.len(), 1);
+
+        let report = audit(&store, &Target::Package(app), &database, &[]).unwrap();
+        assert_eq!(report.packages, 4);
+        assert_eq!(report.findings.len(), 1);
+        let finding = &report.findings[0];
+        assert_eq!(finding.hash, xz.to_string());
+        assert_eq!(finding.name, "xz");
+        assert_eq!(finding.version, "5.6.1");
+        assert_eq!(finding.path, store.logical(&xz).display().to_string());
+        assert_eq!(finding.vulnerabilities[0].id, "CVE-2024-3094");
+        assert_eq!(
+            report.rebuild,
+            [
+                Rebuild {
+                    hash: curl.to_string(),
+                    name: "curl-8.7.1".to_string(),
+                },
+                Rebuild {
+                    hash: app.to_string(),
+                    name: "app".to_string(),
+                },
+            ]
+        );
+
+        // The newest generation of a profile is audited.
+        let profile = store.root().join("profile/default");
+        fs::create_dir_all(&profile).unwrap();
+        symlink(store.by_hash(&app), profile.join("1")).unwrap();
+        symlink(store.by_hash(&tool), profile.join("2")).unwrap();
+        let report = audit(&store, &Target::parse("default"), &database, &[]).unwrap();
+        assert_eq!(report.packages, 2);
+        assert!(report.findings.is_empty());
+        assert!(report.rebuild.is_empty());
+
+        assert!(matches!(
+            audit(&store, &Target::parse("missing"), &database, &[]),
+            Err(AuditError::ProfileNotFound(_))
+        ));
+        assert!(matches!(
+            audit(&store, &Target::parse(".."), &database, &[]),
+            Err(AuditError::InvalidProfile(_))
+        ));
+    }
+}
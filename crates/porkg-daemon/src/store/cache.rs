@@ -0,0 +1,192 @@
+//! Writes static binary caches (see [`porkg_model::cache`]) for the closures of packages.
+//!
+//! The cache is written as a tar archive of its files, so that it can be streamed to a client and unpacked into a
+//! directory that may already contain a cache.
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+};
+
+use porkg_model::{
+    cache::{self, CacheEntry, CacheIndex},
+    hashing::SupportedHash,
+};
+
+use crate::signing::SigningKey;
+
+use super::{
+    gc::temp::TempRoot,
+    pack::{self, Compression},
+    sync::{self, SyncError},
+    Store, StoreError,
+};
+
+fn append(
+    builder: &mut tar::Builder<impl Write>,
+    path: &str,
+    size: u64,
+    data: impl Read,
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    builder.append_data(&mut header, path, data)
+}
+
+/// Describes a package, signing it with `key` if one is provided. The entry doesn't refer to an archive or chunks yet.
+///
+/// This performs blocking IO.
+pub fn describe(
+    store: &Store,
+    hash: &SupportedHash,
+    key: Option<&SigningKey>,
+) -> Result<CacheEntry, SyncError> {
+    let info = store.info(hash)?;
+    Ok(CacheEntry {
+        name: info.name,
+        references: info
+            .references
+            .iter()
+            .filter(|v| *v != hash)
+            .map(ToString::to_string)
+            .collect(),
+        archive: None,
+        chunks: Vec::new(),
+        compression: "zstd".to_string(),
+        size: 0,
+        signature: key.map(|key| sync::sign(store, hash, key)).transpose()?,
+    })
+}
+
+/// Compresses a package into a temporary file that is ready to be read from the start, and describes it, signing it
+/// with `key` if one is provided.
+///
+/// This performs blocking IO.
+pub fn package(
+    store: &Store,
+    hash: &SupportedHash,
+    compression: Compression,
+    key: Option<&SigningKey>,
+) -> Result<(fs::File, CacheEntry), SyncError> {
+    let mut entry = describe(store, hash, key)?;
+    let archive = sync::spool(store, "cache", |file| {
+        let mut encoder = pack::Encoder::new(file, compression)?;
+        sync::export(store, hash, &mut encoder)?;
+        encoder.finish()?;
+        Ok(())
+    })?;
+
+    entry.archive = Some(cache::archive_path(&hash.to_string()));
+    entry.size = archive.metadata()?.len();
+    Ok((archive, entry))
+}
+
+/// Writes the packages in the closures of `roots`, compressed, and signed with `key` if one is provided.
+///
+/// This performs blocking IO.
+#[tracing::instrument(skip(store, key, writer))]
+pub fn write(
+    store: &Store,
+    roots: &[SupportedHash],
+    compression: Compression,
+    key: Option<&SigningKey>,
+    writer: impl Write,
+) -> Result<CacheIndex, SyncError> {
+    let _root = TempRoot::new(store, "cache-export", roots)?;
+    let mut builder = tar::Builder::new(writer);
+    let mut index = CacheIndex::default();
+    for hash in sync::closure(store, roots)? {
+        let (mut archive, entry) = package(store, &hash, compression, key)?;
+        let archive_path = cache::archive_path(&hash.to_string());
+        append(&mut builder, &archive_path, entry.size, &mut archive)?;
+
+        let data = serde_json::to_vec_pretty(&entry).map_err(StoreError::from)?;
+        let entry_path = cache::entry_path(&hash.to_string());
+        append(&mut builder, &entry_path, data.len() as u64, &data[..])?;
+        index.packages.insert(hash.to_string(), entry.name);
+    }
+
+    let data = serde_json::to_vec_pretty(&index).map_err(StoreError::from)?;
+    append(
+        &mut builder,
+        cache::INDEX_FILE,
+        data.len() as u64,
+        &data[..],
+    )?;
+    builder.into_inner()?.flush()?;
+    tracing::info!(packages = index.packages.len(), "wrote binary cache");
+    Ok(index)
+}
+
+/// Writes a cache into a temporary file, which is unlinked and ready to be read from the start.
+///
+/// This performs blocking IO.
+pub fn write_file(
+    store: &Store,
+    roots: &[SupportedHash],
+    compression: Compression,
+    key: Option<&SigningKey>,
+) -> Result<fs::File, SyncError> {
+    sync::spool(store, "cache-export", |file| {
+        write(store, roots, compression, key, file).map(|_| ())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::store::testing::{add_package, TestStore};
+
+    use super::*;
+
+    #[test]
+    fn write_cache() {
+        let from = TestStore::new("cache-from");
+        let to = TestStore::new("cache-to");
+        let libc = add_package(&from, "libc", &[], 1);
+        let zlib = add_package(&from, "zlib", &[libc], 2);
+
+        let mut data = Vec::new();
+        let compression = Compression {
+            level: 3,
+            workers: 2,
+        };
+        let index = write(&from, &[zlib], compression, None, &mut data).unwrap();
+        assert_eq!(
+            index.packages,
+            [
+                (libc.to_string(), "libc".to_string()),
+                (zlib.to_string(), "zlib".to_string())
+            ]
+            .into()
+        );
+
+        let mut files = BTreeMap::new();
+        for entry in tar::Archive::new(&data[..]).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content).unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            files.insert(path, content);
+        }
+        assert_eq!(files.len(), 5);
+        let written: CacheIndex = serde_json::from_slice(&files[cache::INDEX_FILE]).unwrap();
+        assert_eq!(written, index);
+
+        let entry: CacheEntry =
+            serde_json::from_slice(&files[&cache::entry_path(&zlib.to_string())]).unwrap();
+        assert_eq!(entry.references, [libc.to_string()]);
+        assert_eq!(entry.signature, None);
+
+        // The archives are the exports of the packages, so they can be imported into another store.
+        for hash in [libc, zlib] {
+            let archive = &files[&cache::archive_path(&hash.to_string())];
+            let reader = zstd::Decoder::new(&archive[..]).unwrap();
+            assert!(sync::import(&to, &hash, reader, None).unwrap());
+        }
+        assert_eq!(to.info(&zlib).unwrap().name, "zlib");
+    }
+}
@@ -0,0 +1,300 @@
+//! Channels, which pin a snapshot of a repository index (see [`porkg_model::channel`]).
+//!
+//! Each channel is a link at `channel/<name>` to the index snapshot in the store, so channels keep their snapshot
+//! alive. Advancing a channel replaces the link with a rename, so readers see either the old or the new snapshot.
+//!
+//! The advisories of the snapshots that the channels pin are reported for the packages in the store (see
+//! [`advisories`]).
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use porkg_model::{
+    channel::{self, Advisory},
+    hashing::SupportedHash,
+    workspace::{Lock, LOCK_FILE},
+};
+use serde::Serialize;
+use thiserror::Error;
+
+use super::{gc, Store, StoreError};
+
+pub(super) const CHANNELS: &str = "channel";
+
+/// Serializes updates, so that the check for the expected snapshot and the update happen together.
+static UPDATE: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Error)]
+pub enum ChannelError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("{0:?} is not a valid channel name")]
+    InvalidName(String),
+    #[error("the channel {0} does not exist")]
+    NotFound(String),
+    #[error("the channel {name} pins {}, not the expected snapshot", display_pin(.current))]
+    Conflict {
+        name: String,
+        current: Option<SupportedHash>,
+    },
+    #[error("{hash} is not an index snapshot: {reason}")]
+    InvalidIndex { hash: SupportedHash, reason: String },
+}
+
+fn display_pin(pin: &Option<SupportedHash>) -> String {
+    pin.map_or_else(|| "nothing".to_string(), |v| v.to_string())
+}
+
+impl Store {
+    fn channel(&self, name: &str) -> Result<PathBuf, ChannelError> {
+        if channel::is_valid_name(name) {
+            Ok(self.path.join(CHANNELS).join(name))
+        } else {
+            Err(ChannelError::InvalidName(name.to_string()))
+        }
+    }
+}
+
+/// Reads the snapshot that a link pins.
+fn read_pin(path: &Path) -> io::Result<Option<SupportedHash>> {
+    match fs::read_link(path) {
+        Ok(target) => Ok(gc::package_of(&target)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Gets the snapshot that a channel pins.
+///
+/// This performs blocking IO.
+pub fn get(store: &Store, name: &str) -> Result<SupportedHash, ChannelError> {
+    read_pin(&store.channel(name)?)?.ok_or_else(|| ChannelError::NotFound(name.to_string()))
+}
+
+/// Lists every channel, with the snapshot that it pins.
+///
+/// This performs blocking IO.
+pub fn list(store: &Store) -> Result<BTreeMap<String, SupportedHash>, ChannelError> {
+    let mut result = BTreeMap::new();
+    let entries = match fs::read_dir(store.path.join(CHANNELS)) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(result),
+        other => other?,
+    };
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name();
+        let (Some(name), Some(hash)) = (name.to_str(), read_pin(&entry.path())?) else {
+            continue;
+        };
+        result.insert(name.to_string(), hash);
+    }
+    Ok(result)
+}
+
+/// Reads the lock of an index snapshot.
+///
+/// This performs blocking IO.
+pub fn index(store: &Store, hash: &SupportedHash) -> Result<Lock, ChannelError> {
+    let path = store.by_hash(hash).join("src").join(LOCK_FILE);
+    let text = match fs::read_to_string(path) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            store.info(hash)?;
+            return Err(ChannelError::InvalidIndex {
+                hash: *hash,
+                reason: format!("the source doesn't contain {LOCK_FILE}"),
+            });
+        }
+        other => other?,
+    };
+    toml::from_str(&text).map_err(|error| ChannelError::InvalidIndex {
+        hash: *hash,
+        reason: error.message().to_string(),
+    })
+}
+
+/// An advisory for a package in the closure of another.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PackageAdvisory {
+    pub hash: String,
+    pub name: String,
+    /// The channel whose snapshot has the advisory.
+    pub channel: String,
+    #[serde(flatten)]
+    pub advisory: Advisory,
+}
+
+/// Gets the advisories that the snapshots of the channels have for the packages in the closure of `hash`.
+///
+/// This performs blocking IO.
+pub fn advisories(
+    store: &Store,
+    hash: &SupportedHash,
+) -> Result<Vec<PackageAdvisory>, ChannelError> {
+    let closure = store.closure(hash)?;
+    let mut result = Vec::new();
+    for (name, snapshot) in list(store)? {
+        let lock = match index(store, &snapshot) {
+            Ok(lock) => lock,
+            Err(error) => {
+                tracing::warn!(
+                    ?error,
+                    channel = name,
+                    "failed to read the index of a channel"
+                );
+                continue;
+            }
+        };
+        for (hash, info) in &closure {
+            if let Some(advisory) = lock.advisories.get(&hash.to_string()) {
+                result.push(PackageAdvisory {
+                    hash: hash.to_string(),
+                    name: info.name.clone(),
+                    channel: name.clone(),
+                    advisory: advisory.clone(),
+                });
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Pins a channel to an index snapshot, creating the channel if it doesn't exist, and returns the snapshot that it
+/// pinned before.
+///
+/// With `expected`, the channel is only advanced if it still pins the expected snapshot, so that concurrent updates
+/// can't silently overwrite each other.
+///
+/// This performs blocking IO.
+pub fn update(
+    store: &Store,
+    name: &str,
+    hash: &SupportedHash,
+    expected: Option<&SupportedHash>,
+) -> Result<Option<SupportedHash>, ChannelError> {
+    let path = store.channel(name)?;
+    // Pinning something that isn't an index would break every build that resolves through the channel.
+    index(store, hash)?;
+
+    let _guard = UPDATE.lock().unwrap_or_else(|v| v.into_inner());
+    let current = read_pin(&path)?;
+    if expected.is_some_and(|v| current.as_ref() != Some(v)) {
+        return Err(ChannelError::Conflict {
+            name: name.to_string(),
+            current,
+        });
+    }
+
+    let temp = store.temp_path("channel");
+    fs::create_dir_all(store.path.join(CHANNELS))?;
+    if let Some(parent) = temp.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let target = Path::new("../pkg/by-hash").join(hash.to_string());
+    std::os::unix::fs::symlink(target, &temp)?;
+    if let Err(error) = fs::rename(&temp, &path) {
+        fs::remove_file(&temp).ok();
+        return Err(error.into());
+    }
+    tracing::info!(channel = name, from = ?current, to = %hash, "advanced channel");
+    Ok(current)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::store::testing::{add_package, TestStore};
+
+    use super::*;
+
+    #[test]
+    fn advance_channel() {
+        let store = TestStore::new("channel");
+
+        let first = add_package(&store, "index", &[], 1);
+        let second = add_package(&store, "index", &[], 2);
+        let invalid = add_package(&store, "hello", &[], 3);
+        for (hash, lock) in [
+            (first, "hello = \"blake3-a\""),
+            (second, "hello = \"blake3-b\""),
+        ] {
+            let src = store.by_hash(&hash).join("src");
+            fs::create_dir_all(&src).unwrap();
+            fs::write(src.join(LOCK_FILE), format!("[packages]\n{lock}\n")).unwrap();
+        }
+
+        assert!(matches!(
+            get(&store, "stable"),
+            Err(ChannelError::NotFound(_))
+        ));
+        assert!(matches!(
+            update(&store, "../root", &first, None),
+            Err(ChannelError::InvalidName(_))
+        ));
+        assert!(matches!(
+            update(&store, "stable", &invalid, None),
+            Err(ChannelError::InvalidIndex { .. })
+        ));
+
+        assert_eq!(update(&store, "stable", &first, None).unwrap(), None);
+        assert_eq!(get(&store, "stable").unwrap(), first);
+        assert_eq!(index(&store, &first).unwrap().packages["hello"], "blake3-a");
+        assert!(gc::live(&store).unwrap().contains(&first));
+
+        assert!(matches!(
+            update(&store, "stable", &second, Some(&second)),
+            Err(ChannelError::Conflict { current: Some(v), .. }) if v == first
+        ));
+        assert_eq!(
+            update(&store, "stable", &second, Some(&first)).unwrap(),
+            Some(first)
+        );
+        update(&store, "testing", &second, None).unwrap();
+        assert_eq!(
+            list(&store).unwrap(),
+            [
+                ("stable".to_string(), second),
+                ("testing".to_string(), second)
+            ]
+            .into()
+        );
+        assert!(!gc::live(&store).unwrap().contains(&first));
+    }
+
+    #[test]
+    fn report_advisories() {
+        let store = TestStore::new("channel-advisories");
+        let libc = add_package(&store, "libc", &[], 1);
+        let app = add_package(&store, "app", &[libc], 2);
+        let index = add_package(&store, "index", &[], 3);
+        let src = store.by_hash(&index).join("src");
+        fs::create_dir_all(&src).unwrap();
+        let lock = format!(
+            "[packages]\nlibc = \"{libc}\"\n\n[advisories.{libc}]\nyanked = true\nids = [\"CVE-1\"]\n"
+        );
+        fs::write(src.join(LOCK_FILE), lock).unwrap();
+        update(&store, "stable", &index, None).unwrap();
+
+        assert_eq!(
+            advisories(&store, &app).unwrap(),
+            [PackageAdvisory {
+                hash: libc.to_string(),
+                name: "libc".to_string(),
+                channel: "stable".to_string(),
+                advisory: Advisory {
+                    yanked: true,
+                    ids: vec!["CVE-1".to_string()],
+                    reason: None,
+                },
+            }]
+        );
+        assert!(matches!(
+            advisories(&store, &SupportedHash::Blake3([9; 32])),
+            Err(ChannelError::Store(StoreError::NotFound(_)))
+        ));
+    }
+}
@@ -0,0 +1,190 @@
+//! Content-defined chunking of exported packages with FastCDC.
+//!
+//! Cut points are chosen by a rolling hash of the preceding bytes, so inserting or removing data only changes the
+//! chunks around the change, and packages that were rebuilt with small changes share most of their chunks (see
+//! [`porkg_model::cache`]).
+
+use std::{
+    fs,
+    io::{self, Read},
+    os::unix::fs::FileExt as _,
+};
+
+use porkg_model::hashing::{SupportedHash, SupportedHasher};
+
+use super::{
+    sync::{self, SyncError},
+    Store,
+};
+
+pub const MIN_SIZE: usize = 16 * 1024;
+pub const AVERAGE_SIZE: usize = 64 * 1024;
+pub const MAX_SIZE: usize = 256 * 1024;
+
+// Normalized chunking: cut points are harder to find before the average size and easier after it, which narrows the
+// distribution of chunk sizes.
+const MASK_SMALL: u64 = mask(18);
+const MASK_LARGE: u64 = mask(14);
+
+/// Selects the most significant bits, which depend on the most recent bytes of the rolling hash.
+const fn mask(bits: u32) -> u64 {
+    !0 << (64 - bits)
+}
+
+static GEAR: [u64; 256] = gear();
+
+/// Generates the random values of each byte with splitmix64, so that the table is fixed.
+const fn gear() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Finds the length of the chunk at the start of `data`.
+fn cut(data: &[u8]) -> usize {
+    if data.len() <= MIN_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_SIZE);
+    let normal = end.min(AVERAGE_SIZE);
+
+    let mut hash = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(end).skip(MIN_SIZE) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// A chunk of an exported package.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Chunk {
+    /// The hash of the uncompressed content.
+    pub hash: SupportedHash,
+    pub offset: u64,
+    pub size: usize,
+}
+
+/// Splits the content of a reader into chunks.
+pub fn split(mut reader: impl Read) -> io::Result<Vec<Chunk>> {
+    let mut chunks = Vec::new();
+    let mut buffer = Vec::with_capacity(MAX_SIZE);
+    let mut offset = 0;
+    let mut eof = false;
+    loop {
+        while !eof && buffer.len() < MAX_SIZE {
+            let read = (&mut reader)
+                .take((MAX_SIZE - buffer.len()) as u64)
+                .read_to_end(&mut buffer)?;
+            eof = read == 0;
+        }
+        if buffer.is_empty() {
+            return Ok(chunks);
+        }
+
+        let size = cut(&buffer);
+        let mut hasher = SupportedHasher::blake3();
+        hasher.update(&buffer[..size]);
+        chunks.push(Chunk {
+            hash: hasher.finalize(),
+            offset,
+            size,
+        });
+        buffer.drain(..size);
+        offset += size as u64;
+    }
+}
+
+/// Exports a package into a temporary file, which is unlinked, and splits it into chunks.
+///
+/// This performs blocking IO.
+pub fn export(store: &Store, hash: &SupportedHash) -> Result<(fs::File, Vec<Chunk>), SyncError> {
+    let file = sync::export_file(store, hash)?;
+    let chunks = split(&file)?;
+    Ok((file, chunks))
+}
+
+/// Reads a chunk of a file that was split by [`export`], compressed at `level`.
+///
+/// This performs blocking IO.
+pub fn compress(file: &fs::File, chunk: &Chunk, level: i32) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; chunk.size];
+    file.read_exact_at(&mut data, chunk.offset)?;
+    zstd::bulk::compress(&data, level)
+}
+
+/// Decompresses a chunk, checking that it matches its hash.
+pub fn decompress(hash: &SupportedHash, data: &[u8]) -> Result<Vec<u8>, SyncError> {
+    let data =
+        zstd::bulk::decompress(data, MAX_SIZE).map_err(|_| SyncError::CorruptChunk(*hash))?;
+    let mut hasher = SupportedHasher::blake3();
+    hasher.update(&data);
+    if hasher.finalize() != *hash {
+        return Err(SyncError::CorruptChunk(*hash));
+    }
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeSet;
+
+    use super::*;
+
+    fn random(size: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..size)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn split_content() {
+        let data = random(4 * 1024 * 1024, 1);
+        let chunks = split(&data[..]).unwrap();
+        assert!(chunks.len() > 4 * 1024 * 1024 / MAX_SIZE);
+        let mut offset = 0;
+        for (i, chunk) in chunks.iter().enumerate() {
+            assert_eq!(chunk.offset, offset);
+            assert!(chunk.size <= MAX_SIZE);
+            assert!(chunk.size >= MIN_SIZE || i == chunks.len() - 1);
+            offset += chunk.size as u64;
+        }
+        assert_eq!(offset, data.len() as u64);
+        assert!(split(&[][..]).unwrap().is_empty());
+
+        // An insertion only changes the chunks around it.
+        let mut changed = data.clone();
+        changed.splice(1_000_000..1_000_000, random(100, 2));
+        let before: BTreeSet<_> = chunks.iter().map(|v| v.hash).collect();
+        let after = split(&changed[..]).unwrap();
+        let new = after.iter().filter(|v| !before.contains(&v.hash)).count();
+        assert!(new <= 2, "{new} of {} chunks changed", after.len());
+
+        let chunk = &chunks[1];
+        let range = chunk.offset as usize..chunk.offset as usize + chunk.size;
+        let compressed = zstd::bulk::compress(&data[range.clone()], 3).unwrap();
+        assert_eq!(decompress(&chunk.hash, &compressed).unwrap(), &data[range]);
+        assert!(matches!(
+            decompress(&chunks[0].hash, &compressed),
+            Err(SyncError::CorruptChunk(_))
+        ));
+    }
+}
@@ -0,0 +1,428 @@
+//! Patching of the ELF files of packages, so that they find their dynamic loader and libraries within their closure in
+//! the store instead of at global paths (see
+//! [`Metadata::patch_elf`](porkg_model::package::Metadata::patch_elf)).
+//!
+//! Files are patched in place, which keeps their layout intact but means that the new paths have to fit into the space
+//! of the old ones. Builds reserve space by linking with a long interpreter and `RUNPATH`, such as one padded with
+//! slashes.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fs::{self, File},
+    io,
+    os::unix::{ffi::OsStrExt as _, fs::FileExt as _},
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use super::{relocate::open_writable, targets};
+
+const MAGIC: &[u8] = b"\x7fELF";
+const CLASS_64: u8 = 2;
+const DATA_LSB: u8 = 1;
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+const DYNAMIC_SIZE: usize = 16;
+
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+const PT_INTERP: u32 = 3;
+
+const DT_NULL: u64 = 0;
+const DT_STRTAB: u64 = 5;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+
+#[derive(Debug, Error)]
+pub enum ElfError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error("unsupported ELF file: {0}")]
+    Unsupported(&'static str),
+    #[error("malformed ELF file: {0}")]
+    Malformed(&'static str),
+    #[error("the new {what} needs {needed} bytes, but the file only has room for {available}")]
+    NoSpace {
+        what: &'static str,
+        needed: usize,
+        available: usize,
+    },
+}
+
+/// A string within the file, and the number of bytes that a replacement may use (including its terminator).
+#[derive(Debug)]
+struct Field {
+    offset: u64,
+    value: Vec<u8>,
+    capacity: usize,
+}
+
+impl Field {
+    /// Gets the bytes to write over the field, padded with terminators, or `None` if it already has the value.
+    fn replace(&self, what: &'static str, value: &[u8]) -> Result<Option<Vec<u8>>, ElfError> {
+        if self.value == value {
+            return Ok(None);
+        }
+        if value.len() >= self.capacity {
+            return Err(ElfError::NoSpace {
+                what,
+                needed: value.len() + 1,
+                available: self.capacity,
+            });
+        }
+        let mut data = value.to_vec();
+        data.resize(self.capacity, 0);
+        Ok(Some(data))
+    }
+}
+
+/// The parts of an ELF file that are patched.
+#[derive(Debug, Default)]
+struct Elf {
+    interpreter: Option<Field>,
+    runpath: Option<Field>,
+    dynamic: bool,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Reads `len` bytes at `offset`, checking that they are within the file first.
+fn read(file: &File, size: u64, offset: u64, len: u64) -> Result<Vec<u8>, ElfError> {
+    if offset.checked_add(len).map_or(true, |end| end > size) {
+        return Err(ElfError::Malformed(
+            "a section extends past the end of the file",
+        ));
+    }
+    let mut data = vec![0; len as usize];
+    file.read_exact_at(&mut data, offset)?;
+    Ok(data)
+}
+
+/// Reads a string that is terminated within `data`.
+fn string(data: &[u8]) -> Result<Vec<u8>, ElfError> {
+    data.iter()
+        .position(|v| *v == 0)
+        .map(|end| data[..end].to_vec())
+        .ok_or(ElfError::Malformed("a string isn't terminated"))
+}
+
+impl Elf {
+    /// Reads an ELF file, or returns `None` if the file isn't one.
+    fn read(file: &File) -> Result<Option<Self>, ElfError> {
+        let size = file.metadata()?.len();
+        if size < HEADER_SIZE as u64 {
+            return Ok(None);
+        }
+        let header = read(file, size, 0, HEADER_SIZE as u64)?;
+        if &header[..4] != MAGIC {
+            return Ok(None);
+        }
+        if header[4] != CLASS_64 || header[5] != DATA_LSB {
+            return Err(ElfError::Unsupported(
+                "only 64-bit little-endian files are supported",
+            ));
+        }
+        if usize::from(u16_at(&header, 0x36)) != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::Malformed("unexpected program header size"));
+        }
+        let count = u64::from(u16_at(&header, 0x38));
+        let headers = read(
+            file,
+            size,
+            u64_at(&header, 0x20),
+            count * PROGRAM_HEADER_SIZE as u64,
+        )?;
+
+        let mut result = Self::default();
+        let mut loads = Vec::new();
+        let mut dynamic = None;
+        for header in headers.chunks_exact(PROGRAM_HEADER_SIZE) {
+            let (offset, vaddr, filesz) =
+                (u64_at(header, 8), u64_at(header, 16), u64_at(header, 32));
+            match u32_at(header, 0) {
+                PT_LOAD => loads.push((vaddr, offset, filesz)),
+                PT_DYNAMIC => dynamic = Some((offset, filesz)),
+                PT_INTERP => {
+                    let data = read(file, size, offset, filesz)?;
+                    result.interpreter = Some(Field {
+                        offset,
+                        value: string(&data)?,
+                        capacity: data.len(),
+                    });
+                }
+                _ => {}
+            }
+        }
+
+        let Some((offset, filesz)) = dynamic else {
+            return Ok(Some(result));
+        };
+        result.dynamic = true;
+        let entries = read(file, size, offset, filesz)?;
+        let mut strtab = None;
+        let mut runpath = None;
+        for entry in entries.chunks_exact(DYNAMIC_SIZE) {
+            let (tag, value) = (u64_at(entry, 0), u64_at(entry, 8));
+            match tag {
+                DT_NULL => break,
+                DT_STRTAB => strtab = Some(value),
+                // RUNPATH takes precedence over RPATH for the loader.
+                DT_RUNPATH => runpath = Some(value),
+                DT_RPATH => runpath = runpath.or(Some(value)),
+                _ => {}
+            }
+        }
+
+        if let (Some(strtab), Some(runpath)) = (strtab, runpath) {
+            let strtab = loads
+                .iter()
+                .find(|(vaddr, _, filesz)| {
+                    (*vaddr..vaddr.saturating_add(*filesz)).contains(&strtab)
+                })
+                .map(|(vaddr, offset, _)| strtab - vaddr + offset)
+                .ok_or(ElfError::Malformed("the string table isn't loaded"))?;
+            let offset = strtab.saturating_add(runpath);
+            let mut data = Vec::new();
+            // The string is read in pieces, as the size of the table isn't needed otherwise.
+            loop {
+                let len = 256.min(size.saturating_sub(offset + data.len() as u64));
+                if len == 0 {
+                    return Err(ElfError::Malformed("a string isn't terminated"));
+                }
+                let piece = read(file, size, offset + data.len() as u64, len)?;
+                if let Some(end) = piece.iter().position(|v| *v == 0) {
+                    data.extend_from_slice(&piece[..end]);
+                    break;
+                }
+                data.extend_from_slice(&piece);
+            }
+            result.runpath = Some(Field {
+                offset,
+                capacity: data.len() + 1,
+                value: data,
+            });
+        }
+        Ok(Some(result))
+    }
+}
+
+/// What ELF files are patched to: the dynamic loaders and library directories of the packages in a closure.
+#[derive(Debug, Default, Clone)]
+pub struct Fixup {
+    /// The dynamic loaders that the closure provides, by file name.
+    interpreters: BTreeMap<OsString, PathBuf>,
+    /// The library directories of the closure, in the order that they are searched.
+    runpath: Vec<PathBuf>,
+}
+
+impl Fixup {
+    /// Adds the libraries of the package in `dir`, which will be found at `installed` once it is in the store.
+    ///
+    /// Libraries are in the `lib` directory of each target of the package. Files in them named like `ld-*.so*` are
+    /// dynamic loaders.
+    pub fn add_package(&mut self, dir: &Path, installed: &Path) -> io::Result<()> {
+        for target in targets(dir)? {
+            let lib = dir.join(&target).join("lib");
+            if !lib.is_dir() {
+                continue;
+            }
+            let installed = installed.join(&target).join("lib");
+            for library in fs::read_dir(&lib)? {
+                let name = library?.file_name();
+                let bytes = name.as_bytes();
+                if bytes.starts_with(b"ld-") && bytes.windows(3).any(|v| v == b".so") {
+                    let path = installed.join(&name);
+                    self.interpreters.entry(name).or_insert(path);
+                }
+            }
+            if !self.runpath.contains(&installed) {
+                self.runpath.push(installed);
+            }
+        }
+        Ok(())
+    }
+
+    /// Patches the interpreter and `RUNPATH` (or `RPATH`) of an ELF file. The interpreter is replaced by the loader of
+    /// the closure with the same file name, if there is one.
+    ///
+    /// Returns `false` if the file isn't an ELF file or didn't need to change.
+    pub fn patch(&self, path: &Path) -> Result<bool, ElfError> {
+        let metadata = fs::symlink_metadata(path)?;
+        if !metadata.is_file() {
+            return Ok(false);
+        }
+        let Some(elf) = Elf::read(&File::open(path)?)? else {
+            return Ok(false);
+        };
+
+        let mut writes = Vec::new();
+        if let Some(field) = &elf.interpreter {
+            let name = Path::new(std::ffi::OsStr::from_bytes(&field.value)).file_name();
+            if let Some(loader) = name.and_then(|v| self.interpreters.get(v)) {
+                let data = field.replace("interpreter", loader.as_os_str().as_bytes())?;
+                writes.extend(data.map(|v| (field.offset, v)));
+            }
+        }
+        if elf.dynamic && !self.runpath.is_empty() {
+            let runpath = self
+                .runpath
+                .iter()
+                .map(|v| v.as_os_str().as_bytes())
+                .collect::<Vec<_>>()
+                .join(&b':');
+            let Some(field) = &elf.runpath else {
+                return Err(ElfError::NoSpace {
+                    what: "RUNPATH",
+                    needed: runpath.len() + 1,
+                    available: 0,
+                });
+            };
+            writes.extend(
+                field
+                    .replace("RUNPATH", &runpath)?
+                    .map(|v| (field.offset, v)),
+            );
+        }
+
+        if writes.is_empty() {
+            return Ok(false);
+        }
+        let file = open_writable(path, &metadata)?;
+        for (offset, data) in writes {
+            file.write_all_at(&data, offset)?;
+        }
+        Ok(true)
+    }
+
+    /// Patches every ELF file in a file or directory tree, returning how many were changed. Files that can't be patched
+    /// are logged and left as they are, as they may not be executed at all.
+    pub fn patch_tree(&self, path: &Path) -> io::Result<u64> {
+        if fs::symlink_metadata(path)?.is_dir() {
+            let mut count = 0;
+            for entry in fs::read_dir(path)? {
+                count += self.patch_tree(&entry?.path())?;
+            }
+            return Ok(count);
+        }
+        match self.patch(path) {
+            Ok(patched) => Ok(patched.into()),
+            Err(ElfError::IO(error)) => Err(error),
+            Err(error) => {
+                tracing::warn!(?path, %error, "failed to patch ELF file");
+                Ok(0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BASE: u64 = 0x400000;
+
+    /// Builds an executable with an interpreter, and a `RUNPATH` with `reserved` bytes.
+    fn executable(interpreter: &str, runpath: &str) -> Vec<u8> {
+        let interp_offset = HEADER_SIZE + 3 * PROGRAM_HEADER_SIZE;
+        let mut interp = interpreter.as_bytes().to_vec();
+        interp.resize(256, 0);
+        let strtab_offset = interp_offset + interp.len();
+        let strtab = [&b"\0libc.so.6\0"[..], runpath.as_bytes(), b"\0\0\0\0\0"].concat();
+        let dynamic_offset = strtab_offset + strtab.len();
+        let dynamic: Vec<u8> = [
+            (1, 1),
+            (DT_STRTAB, BASE + strtab_offset as u64),
+            (DT_RUNPATH, 11),
+            (DT_NULL, 0),
+        ]
+        .iter()
+        .flat_map(|(tag, value): &(u64, u64)| [tag.to_le_bytes(), value.to_le_bytes()])
+        .flatten()
+        .collect();
+        let size = (dynamic_offset + dynamic.len()) as u64;
+
+        let mut data = vec![0; HEADER_SIZE];
+        data[..4].copy_from_slice(MAGIC);
+        data[4] = CLASS_64;
+        data[5] = DATA_LSB;
+        data[0x20..0x28].copy_from_slice(&(HEADER_SIZE as u64).to_le_bytes());
+        data[0x36..0x38].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        data[0x38..0x3a].copy_from_slice(&3u16.to_le_bytes());
+        for (kind, offset, vaddr, filesz) in [
+            (PT_LOAD, 0, BASE, size),
+            (PT_INTERP, interp_offset as u64, 0, interp.len() as u64),
+            (PT_DYNAMIC, dynamic_offset as u64, 0, dynamic.len() as u64),
+        ] {
+            let mut header = vec![0; PROGRAM_HEADER_SIZE];
+            header[..4].copy_from_slice(&kind.to_le_bytes());
+            header[8..16].copy_from_slice(&offset.to_le_bytes());
+            header[16..24].copy_from_slice(&vaddr.to_le_bytes());
+            header[32..40].copy_from_slice(&filesz.to_le_bytes());
+            data.extend(header);
+        }
+        [data, interp, strtab, dynamic].concat()
+    }
+
+    #[test]
+    fn patch_closure() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let libc = root.join("libc");
+        fs::create_dir_all(libc.join("x86_64-linux/lib")).unwrap();
+        fs::create_dir_all(libc.join("src/lib")).unwrap();
+        fs::write(libc.join("x86_64-linux/lib/ld-linux-x86-64.so.2"), "").unwrap();
+        let app = root.join("staging");
+        fs::create_dir_all(app.join("x86_64-linux/bin")).unwrap();
+        fs::create_dir_all(app.join("x86_64-linux/lib")).unwrap();
+
+        let padded = format!("/usr/lib{}", "/".repeat(128));
+        let bin = app.join("x86_64-linux/bin/app");
+        fs::write(&bin, executable("/lib64/ld-linux-x86-64.so.2", &padded)).unwrap();
+        let cramped = app.join("x86_64-linux/bin/cramped");
+        fs::write(
+            &cramped,
+            executable("/lib64/ld-linux-x86-64.so.2", "/usr/lib"),
+        )
+        .unwrap();
+        fs::write(app.join("x86_64-linux/bin/script"), "#!/bin/sh\n").unwrap();
+
+        let mut fixup = Fixup::default();
+        let installed = root.join("pkg/app");
+        fixup.add_package(&app, &installed).unwrap();
+        fixup.add_package(&libc, &libc).unwrap();
+        assert!(matches!(
+            fixup.patch(&cramped),
+            Err(ElfError::NoSpace {
+                what: "RUNPATH",
+                ..
+            })
+        ));
+        assert_eq!(fixup.patch_tree(&app).unwrap(), 1);
+        assert!(!fixup.patch(&bin).unwrap());
+
+        let elf = Elf::read(&File::open(&bin).unwrap()).unwrap().unwrap();
+        let loader = libc.join("x86_64-linux/lib/ld-linux-x86-64.so.2");
+        assert_eq!(
+            elf.interpreter.unwrap().value,
+            loader.as_os_str().as_bytes()
+        );
+        let runpath = format!(
+            "{}:{}",
+            installed.join("x86_64-linux/lib").display(),
+            libc.join("x86_64-linux/lib").display()
+        );
+        assert_eq!(elf.runpath.unwrap().value, runpath.as_bytes());
+    }
+}
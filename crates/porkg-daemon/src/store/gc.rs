@@ -0,0 +1,456 @@
+//! Garbage collection of packages.
+//!
+//! Packages are live if they are reachable from a root (`root/<lock hash>` or `tenant/<tenant>/root/<lock hash>`, a
+//! profile generation, a channel or a temporary root of an in-flight operation, see [`temp`] and
+//! `notes/fs-layout.md`), or if a live package references them. Deletions are planned before anything is removed, so that the plan can be shown to the
+//! user first.
+//!
+//! Collections hold the GC lock (see [`lock`]) from finding the live packages until they have deleted the dead ones.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsStr,
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::fs::{MetadataExt as _, PermissionsExt as _},
+    path::{Component, Path, PathBuf},
+};
+
+use nix::fcntl::{Flock, FlockArg};
+use porkg_model::hashing::SupportedHash;
+use thiserror::Error;
+
+use super::{channel::CHANNELS, Store, StoreError};
+use crate::events::StoreChange;
+
+pub mod policy;
+pub mod temp;
+
+const ROOTS: &str = "root";
+const TENANTS: &str = "tenant";
+const PROFILES: &str = "profile";
+/// The link of a profile to the generation that it is switched to.
+const CURRENT: &str = "current";
+const BY_HASH: &str = "by-hash";
+const LOCK_FILE: &str = "gc.lock";
+
+#[derive(Debug, Error)]
+pub enum GcError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("package {0} is reachable from a root")]
+    Live(SupportedHash),
+    #[error("package {hash} is referenced by {by}")]
+    Referenced {
+        hash: SupportedHash,
+        by: SupportedHash,
+    },
+}
+
+/// The packages that a deletion removes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Plan {
+    /// The packages, with each package before the packages that it references.
+    pub packages: Vec<SupportedHash>,
+    /// The disk space that is reclaimed by removing the packages, in bytes.
+    pub bytes: u64,
+}
+
+fn lock_with(store: &Store, arg: FlockArg) -> io::Result<Flock<File>> {
+    fs::create_dir_all(&store.path)?;
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(store.path.join(LOCK_FILE))?;
+    Flock::lock(file, arg).map_err(|(_, errno)| errno.into())
+}
+
+/// Takes the GC lock, which keeps temporary roots from being added until it is released.
+///
+/// This performs blocking IO, and waits for in-flight operations to finish adding temporary roots.
+pub fn lock(store: &Store) -> io::Result<Flock<File>> {
+    lock_with(store, FlockArg::LockExclusive)
+}
+
+/// Finds the package that a path within the store belongs to.
+pub(super) fn package_of(path: &Path) -> Option<SupportedHash> {
+    let mut components = path.components();
+    components.find(|v| *v == Component::Normal(OsStr::new(BY_HASH)))?;
+    components.next()?.as_os_str().to_str()?.parse().ok()
+}
+
+/// Lists every package in the store.
+///
+/// This performs blocking IO.
+pub fn packages(store: &Store) -> io::Result<BTreeSet<SupportedHash>> {
+    let mut result = BTreeSet::new();
+    match fs::read_dir(store.path.join("pkg").join(BY_HASH)) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(result),
+        Err(error) => return Err(error),
+        Ok(entries) => {
+            for entry in entries {
+                if let Some(hash) = entry?.file_name().to_str().and_then(|v| v.parse().ok()) {
+                    result.insert(hash);
+                }
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Gets the packages that a root links to directly. Roots link to a directory of links for a lock, or directly to a
+/// package.
+fn root_packages(path: &Path, result: &mut BTreeSet<SupportedHash>) -> io::Result<()> {
+    let target = fs::read_link(path).unwrap_or_else(|_| path.to_path_buf());
+    result.extend(package_of(&target));
+    if let Ok(links) = fs::read_dir(path) {
+        for link in links {
+            let link = link?.path();
+            result.extend(fs::read_link(&link).ok().as_deref().and_then(package_of));
+        }
+    }
+    Ok(())
+}
+
+/// Lists the entries of a directory, which may not exist.
+fn entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    match fs::read_dir(dir) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(error) => Err(error),
+        Ok(entries) => entries.map(|v| v.map(|v| v.path())).collect(),
+    }
+}
+
+/// Gets the packages that roots (including those of every tenant and temporary roots), profile generations and
+/// channels link to directly.
+fn rooted(store: &Store) -> io::Result<BTreeSet<SupportedHash>> {
+    let mut result = BTreeSet::new();
+    let tenants = entries(&store.path.join(TENANTS))?;
+    let roots =
+        std::iter::once(store.path.join(ROOTS)).chain(tenants.iter().map(|v| v.join(ROOTS)));
+    for dir in roots {
+        for root in entries(&dir)? {
+            root_packages(&root, &mut result)?;
+        }
+    }
+    for profile in entries(&store.path.join(PROFILES))? {
+        for generation in entries(&profile)? {
+            root_packages(&generation, &mut result)?;
+        }
+    }
+    for channel in entries(&store.path.join(CHANNELS))? {
+        root_packages(&channel, &mut result)?;
+    }
+    temp::rooted(store, &mut result)?;
+    Ok(result)
+}
+
+/// Lists the generations of the profile `name`, oldest first.
+///
+/// This performs blocking IO.
+pub fn generations(store: &Store, name: &str) -> io::Result<Vec<u64>> {
+    let mut result: Vec<_> = entries(&store.path.join(PROFILES).join(name))?
+        .into_iter()
+        .filter_map(|path| path.file_name()?.to_str()?.parse::<u64>().ok())
+        .collect();
+    result.sort();
+    Ok(result)
+}
+
+/// Gets the path of a generation of the profile `name`, which links to its packages.
+pub fn generation_path(store: &Store, name: &str, generation: u64) -> PathBuf {
+    store
+        .path
+        .join(PROFILES)
+        .join(name)
+        .join(generation.to_string())
+}
+
+/// Gets the generation that the profile `name` is switched to, if any.
+///
+/// This performs blocking IO.
+pub fn current(store: &Store, name: &str) -> io::Result<Option<u64>> {
+    current_of(&store.path.join(PROFILES).join(name))
+}
+
+fn current_of(profile: &Path) -> io::Result<Option<u64>> {
+    match fs::read_link(profile.join(CURRENT)) {
+        Ok(target) => Ok(target.to_str().and_then(|v| v.parse().ok())),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Switches the profile `name` to `generation`, or to no generation. The link is replaced atomically, so that the
+/// profile is always switched to either generation.
+///
+/// This performs blocking IO.
+pub fn set_current(store: &Store, name: &str, generation: Option<u64>) -> io::Result<()> {
+    let link = store.path.join(PROFILES).join(name).join(CURRENT);
+    let Some(generation) = generation else {
+        return match fs::remove_file(&link) {
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+            other => other,
+        };
+    };
+    let staging = link.with_extension("new");
+    match fs::remove_file(&staging) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        other => other?,
+    }
+    std::os::unix::fs::symlink(generation.to_string(), &staging)?;
+    fs::rename(&staging, &link)
+}
+
+/// Gets the packages that a generation of the profile `name` links to directly, or `None` if it doesn't exist.
+///
+/// This performs blocking IO.
+pub fn generation(
+    store: &Store,
+    name: &str,
+    generation: u64,
+) -> io::Result<Option<BTreeSet<SupportedHash>>> {
+    let path = generation_path(store, name, generation);
+    match fs::symlink_metadata(&path) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(error) => return Err(error),
+        Ok(_) => {}
+    }
+    let mut result = BTreeSet::new();
+    root_packages(&path, &mut result)?;
+    Ok(Some(result))
+}
+
+/// Gets every live package.
+///
+/// This performs blocking IO.
+pub fn live(store: &Store) -> Result<BTreeSet<SupportedHash>, GcError> {
+    let mut result = BTreeSet::new();
+    for hash in rooted(store)? {
+        if result.contains(&hash) {
+            continue;
+        }
+        match store.closure(&hash) {
+            Ok(closure) => result.extend(closure.into_iter().map(|(v, _)| v)),
+            // A dangling root keeps nothing alive.
+            Err(StoreError::NotFound(_)) => {}
+            Err(error) => return Err(error.into()),
+        }
+    }
+    Ok(result)
+}
+
+/// Gets the packages that reference each package.
+fn referrers(store: &Store) -> Result<BTreeMap<SupportedHash, BTreeSet<SupportedHash>>, GcError> {
+    let mut result = BTreeMap::<_, BTreeSet<_>>::new();
+    for hash in packages(store)? {
+        for reference in store.info(&hash)?.references {
+            if reference != hash {
+                result.entry(reference).or_default().insert(hash);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Gets the disk space that a directory uses.
+pub(super) fn disk_usage(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    let mut result = metadata.blocks() * 512;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            result += disk_usage(&entry?.path())?;
+        }
+    }
+    Ok(result)
+}
+
+/// Gets the disk space that the packages of each tenant use, in bytes. Packages that no tenant owns aren't counted.
+///
+/// This performs blocking IO.
+pub fn tenant_usage(store: &Store) -> Result<BTreeMap<String, u64>, GcError> {
+    let mut result = BTreeMap::new();
+    for hash in packages(store)? {
+        if let Some(tenant) = store.info(&hash)?.tenant {
+            *result.entry(tenant).or_default() += disk_usage(&store.by_hash(&hash))?;
+        }
+    }
+    Ok(result)
+}
+
+/// Plans the deletion of `hash`. If `recursive` is set, the packages that only `hash` references (directly or
+/// indirectly) are deleted with it.
+///
+/// Packages that are live, or that are referenced by a package that isn't deleted, can't be deleted. The plan stays
+/// valid for as long as the GC lock (see [`lock`]) is held.
+///
+/// This performs blocking IO.
+pub fn plan(store: &Store, hash: &SupportedHash, recursive: bool) -> Result<Plan, GcError> {
+    let closure = store.closure(hash)?;
+    let live = live(store)?;
+    if live.contains(hash) {
+        return Err(GcError::Live(*hash));
+    }
+
+    let referrers = referrers(store)?;
+    if let Some(by) = referrers.get(hash).and_then(|v| v.first()) {
+        return Err(GcError::Referenced {
+            hash: *hash,
+            by: *by,
+        });
+    }
+
+    let mut selected = BTreeSet::from([*hash]);
+    let mut packages = vec![*hash];
+    if recursive {
+        // The closure lists packages after everything that they reference, so walking it backwards decides on
+        // every referrer within the closure before the packages that it references.
+        for (candidate, _) in closure.iter().rev().skip(1) {
+            let unreferenced = referrers
+                .get(candidate)
+                .map_or(true, |v| v.is_subset(&selected));
+            if unreferenced && !live.contains(candidate) && selected.insert(*candidate) {
+                packages.push(*candidate);
+            }
+        }
+    }
+
+    let mut bytes = 0;
+    for hash in packages.iter() {
+        bytes += disk_usage(&store.by_hash(hash))?;
+    }
+    Ok(Plan { packages, bytes })
+}
+
+/// Allows the contents of a directory to be removed, as packages are read-only.
+pub(super) fn make_writable(path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        let mut permissions = metadata.permissions();
+        permissions.set_mode(permissions.mode() | 0o700);
+        fs::set_permissions(path, permissions)?;
+        for entry in fs::read_dir(path)? {
+            make_writable(&entry?.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes links to the deleted packages from an index directory, such as `pkg/by-nix`.
+fn remove_links(dir: &Path, deleted: &BTreeSet<SupportedHash>) -> io::Result<()> {
+    let entries = match fs::read_dir(dir) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        other => other?,
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if let Some(hash) = fs::read_link(&path).ok().as_deref().and_then(package_of) {
+            if deleted.contains(&hash) {
+                fs::remove_file(&path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the packages of a plan.
+///
+/// Each package is moved out of `pkg/by-hash` before its contents are removed, so that a package is never seen
+/// partially deleted.
+///
+/// This performs blocking IO.
+pub fn delete(store: &Store, plan: &Plan) -> Result<(), GcError> {
+    let deleted = plan.packages.iter().copied().collect::<BTreeSet<_>>();
+    remove_links(&store.path.join("pkg/by-nix"), &deleted)?;
+
+    for hash in plan.packages.iter() {
+        let info = store.info(hash)?;
+        let by_name = store.by_name(&info.name);
+        remove_links(&by_name, &deleted)?;
+        // Only succeeds if no other version of the package remains.
+        fs::remove_dir(&by_name).ok();
+
+        let trash = store.temp_path("gc");
+        if let Some(parent) = trash.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::rename(store.by_hash(hash), &trash)?;
+        let bytes = disk_usage(&trash)?;
+        make_writable(&trash)?;
+        fs::remove_dir_all(&trash)?;
+        match fs::remove_dir_all(store.builds_path(hash)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error.into()),
+            _ => {}
+        }
+        tracing::info!(%hash, name = info.name, "deleted package");
+        store.publish(hash, &info.name, StoreChange::Deleted, bytes);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::symlink;
+
+    use crate::store::testing::TestStore;
+
+    use super::*;
+
+    #[test]
+    fn delete_closure() {
+        let store = TestStore::new("gc");
+        let root = store.root();
+
+        let libc = store.add("libc", &[]);
+        let zlib = store.add("zlib", &[libc]);
+        let app = store.add("app", &[zlib, libc]);
+        let tool = store.add("tool", &[libc]);
+        let rooted = store.add("rooted", &[]);
+        let tenant = store.add("tenant", &[]);
+
+        fs::create_dir_all(root.join("link/lock")).unwrap();
+        symlink(
+            store.by_hash(&rooted).join("out"),
+            root.join("link/lock/rooted"),
+        )
+        .unwrap();
+        fs::create_dir_all(root.join(ROOTS)).unwrap();
+        symlink(root.join("link/lock"), root.join(ROOTS).join("lock")).unwrap();
+        let tenant_roots = root.join(TENANTS).join("team").join(ROOTS);
+        fs::create_dir_all(&tenant_roots).unwrap();
+        symlink(store.by_hash(&tenant), tenant_roots.join("lock")).unwrap();
+
+        assert_eq!(live(&store).unwrap(), [rooted, tenant].into());
+        store.claim(&tenant, Some("team")).unwrap();
+        store.claim(&tenant, Some("other")).unwrap();
+        let usage = tenant_usage(&store).unwrap();
+        assert_eq!(usage.keys().collect::<Vec<_>>(), ["team"]);
+        assert!(usage["team"] > 0);
+        assert!(matches!(
+            plan(&store, &rooted, false),
+            Err(GcError::Live(_))
+        ));
+        assert!(matches!(
+            plan(&store, &zlib, false),
+            Err(GcError::Referenced { by, .. }) if by == app
+        ));
+
+        // libc is still used by tool, so only zlib goes with app.
+        let plan = plan(&store, &app, true).unwrap();
+        assert_eq!(plan.packages, vec![app, zlib]);
+        assert!(plan.bytes > 0);
+        assert!(store.by_hash(&app).exists());
+
+        delete(&store, &plan).unwrap();
+        assert!(!store.by_hash(&app).exists());
+        assert!(!store.by_hash(&zlib).exists());
+        assert!(!store.by_name("app").exists());
+        assert_eq!(
+            packages(&store).unwrap(),
+            [libc, tool, rooted, tenant].into()
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! Automatic garbage collection.
+//!
+//! The collector periodically prunes old profile generations, and then deletes packages that aren't live until the
+//! store is within the configured limits. The oldest packages go first, and a package is only deleted once nothing
+//! that remains in the store references it.
+
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use porkg_model::hashing::SupportedHash;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+
+use super::{
+    current_of, delete, disk_usage, entries, live, lock, packages, referrers, GcError, Plan,
+    PROFILES,
+};
+use crate::{
+    blocking::BlockingPool,
+    clock::Time,
+    config::{Config, GcConfig},
+    events::EventBus,
+    store::Store,
+};
+
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    pub max_size: Option<u64>,
+    pub min_free: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub keep_generations: Option<usize>,
+}
+
+impl Policy {
+    pub fn new(config: &GcConfig) -> Self {
+        Self {
+            max_size: config.max_size,
+            min_free: config.min_free,
+            max_age: config.max_age.map(Duration::from_secs),
+            keep_generations: config.keep_generations,
+        }
+    }
+}
+
+/// What a collection removed.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// The number of profile generations that were removed.
+    pub generations: usize,
+    /// The packages that were deleted, in the order that they were deleted.
+    pub packages: Vec<SupportedHash>,
+    /// The disk space that was reclaimed, in bytes.
+    pub bytes: u64,
+}
+
+/// Removes all but the newest `keep` generations of each profile, and the generation that it is switched to.
+/// Generations are numbered, and entries that aren't numbers are left alone.
+fn prune_generations(store: &Store, keep: usize) -> io::Result<usize> {
+    let mut removed = 0;
+    for profile in entries(&store.path.join(PROFILES))? {
+        let current = current_of(&profile)?;
+        let mut generations = entries(&profile)?
+            .into_iter()
+            .filter_map(|path| {
+                let generation = path.file_name()?.to_str()?.parse::<u64>().ok()?;
+                Some((generation, path))
+            })
+            .collect::<Vec<_>>();
+        generations.sort();
+
+        let old = generations.len().saturating_sub(keep);
+        for (generation, path) in generations.drain(..old) {
+            if Some(generation) == current {
+                continue;
+            }
+            fs::remove_file(&path)?;
+            tracing::info!(?profile, generation, "removed profile generation");
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Gets the space that is available to the store, in bytes.
+fn free_space(path: &Path) -> io::Result<u64> {
+    let stat = nix::sys::statvfs::statvfs(path)?;
+    Ok(stat.blocks_available() * stat.fragment_size())
+}
+
+/// Enforces a policy once. `now` is used to find the age of packages.
+///
+/// This performs blocking IO.
+pub fn collect(store: &Store, policy: &Policy, now: SystemTime) -> Result<Report, GcError> {
+    let _lock = lock(store)?;
+    let mut report = Report::default();
+    if let Some(keep) = policy.keep_generations {
+        report.generations = prune_generations(store, keep)?;
+    }
+
+    let live = live(store)?;
+    let referrers = referrers(store)?;
+    let mut size = 0;
+    let mut dead = BTreeMap::new();
+    for hash in packages(store)? {
+        let path = store.by_hash(&hash);
+        let bytes = disk_usage(&path)?;
+        size += bytes;
+        if !live.contains(&hash) {
+            dead.insert(hash, (fs::symlink_metadata(&path)?.modified()?, bytes));
+        }
+    }
+    let mut free = match policy.min_free {
+        Some(_) => free_space(&store.path)?,
+        None => u64::MAX,
+    };
+
+    let over_limit = |size: u64, free: u64| {
+        policy.max_size.is_some_and(|v| size > v) || policy.min_free.is_some_and(|v| free < v)
+    };
+    let expired = |time: SystemTime| {
+        policy
+            .max_age
+            .is_some_and(|max| now.duration_since(time).is_ok_and(|age| age > max))
+    };
+    loop {
+        let full = over_limit(size, free);
+        // Packages that aren't live are only referenced by other packages that aren't live.
+        let next = dead
+            .iter()
+            .filter(|(hash, _)| {
+                referrers
+                    .get(hash)
+                    .map_or(true, |v| v.iter().all(|v| !dead.contains_key(v)))
+            })
+            .filter(|(_, (time, _))| full || expired(*time))
+            .min_by_key(|(_, (time, _))| *time)
+            .map(|(hash, (_, bytes))| (*hash, *bytes));
+        let Some((hash, bytes)) = next else {
+            break;
+        };
+
+        delete(
+            store,
+            &Plan {
+                packages: vec![hash],
+                bytes,
+            },
+        )?;
+        dead.remove(&hash);
+        size -= bytes;
+        free = free.saturating_add(bytes);
+        report.packages.push(hash);
+        report.bytes += bytes;
+    }
+
+    if over_limit(size, free) {
+        tracing::warn!(
+            size,
+            free,
+            "the store is over its limits, but everything left is live"
+        );
+    }
+    Ok(report)
+}
+
+/// Runs a collection every `gc.interval` seconds until cancelled, judging the ages of packages by `time`. Failed
+/// collections are logged and retried at the next interval.
+pub async fn collector(
+    config: Arc<Config>,
+    events: EventBus,
+    time: Time,
+    cancellation_token: CancellationToken,
+) -> anyhow::Result<()> {
+    let Some(interval) = config.gc.interval else {
+        return Ok(());
+    };
+
+    let store = Store::new(&config.store)
+        .with_events(events)
+        .with_origin("daemon", "gc");
+    let policy = Policy::new(&config.gc);
+    // Collections never overlap, and don't take slots from requests.
+    let blocking = BlockingPool::new(1);
+
+    let period = Duration::from_secs(interval.max(1));
+    let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = cancellation_token.cancelled() => return Ok(()),
+            _ = interval.tick() => {}
+        }
+
+        let store = store.clone();
+        let policy = policy.clone();
+        let now = time.clock.system_time();
+        match blocking
+            .run("gc", move || collect(&store, &policy, now))
+            .await?
+        {
+            Ok(report) if report.generations == 0 && report.packages.is_empty() => {
+                tracing::debug!("nothing to collect")
+            }
+            Ok(report) => tracing::info!(
+                generations = report.generations,
+                packages = report.packages.len(),
+                bytes = report.bytes,
+                "collected garbage"
+            ),
+            Err(error) => tracing::warn!(?error, "garbage collection failed"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::symlink;
+
+    use crate::store::{
+        gc::{current, generations, set_current},
+        testing::{add_package, TestStore},
+    };
+
+    use super::*;
+
+    #[test]
+    fn collect_policies() {
+        let store = TestStore::new("gc-policy");
+        let root = store.root();
+
+        let libc = add_package(&store, "libc", &[], 1);
+        let zlib = add_package(&store, "zlib", &[libc], 2);
+        let app = add_package(&store, "app", &[zlib], 3);
+        let old = add_package(&store, "old", &[libc], 4);
+        let new = add_package(&store, "new", &[libc], 5);
+
+        let profile = root.join(PROFILES).join("default");
+        fs::create_dir_all(&profile).unwrap();
+        symlink(store.by_hash(&old), profile.join("1")).unwrap();
+        symlink(store.by_hash(&new), profile.join("2")).unwrap();
+
+        // Nothing is old enough or over a limit yet.
+        let policy = Policy {
+            max_age: Some(Duration::from_secs(60 * 60)),
+            max_size: Some(u64::MAX),
+            ..Default::default()
+        };
+        let report = collect(&store, &policy, SystemTime::now()).unwrap();
+        assert_eq!(report.packages, vec![]);
+
+        // Pruning the first generation leaves old unreferenced, and app is deleted before what it references.
+        let policy = Policy {
+            keep_generations: Some(1),
+            ..policy
+        };
+        let later = SystemTime::now() + Duration::from_secs(2 * 60 * 60);
+        let report = collect(&store, &policy, later).unwrap();
+        assert_eq!(report.generations, 1);
+        assert_eq!(report.packages.len(), 3);
+        assert!(report.packages.contains(&old));
+        let position = |hash| report.packages.iter().position(|v| *v == hash).unwrap();
+        assert!(position(app) < position(zlib));
+        assert!(report.bytes > 0);
+        assert_eq!(packages(&store).unwrap(), [libc, new].into());
+
+        // Live packages are kept even when the store is over its limit.
+        let policy = Policy {
+            max_size: Some(0),
+            ..Default::default()
+        };
+        let report = collect(&store, &policy, SystemTime::now()).unwrap();
+        assert_eq!(report.packages, vec![]);
+    }
+
+    #[test]
+    fn keep_current_generation() {
+        let store = TestStore::new("gc-current");
+        let profile = store.root().join(PROFILES).join("default");
+        fs::create_dir_all(&profile).unwrap();
+        for generation in 1..=3 {
+            let hash = add_package(&store, &format!("app-{generation}"), &[], generation);
+            symlink(store.by_hash(&hash), profile.join(generation.to_string())).unwrap();
+        }
+        set_current(&store, "default", Some(1)).unwrap();
+
+        assert_eq!(prune_generations(&store, 1).unwrap(), 1);
+        assert_eq!(generations(&store, "default").unwrap(), [1, 3]);
+        assert_eq!(current(&store, "default").unwrap(), Some(1));
+    }
+}
@@ -0,0 +1,138 @@
+//! Temporary roots, which keep the packages that in-flight operations read or are about to create alive.
+//!
+//! A temporary root is a directory of links (`temproot/<name>-<pid>-<id>/<hash>`) that is removed when its
+//! [`TempRoot`] is dropped. Links are only added while the GC lock is held shared (see [`super::lock`]), and
+//! collections hold it exclusively, so a collection either sees a link, or has finished before the link was added. In
+//! the latter case the operation finds the package missing, rather than losing it halfway through.
+//!
+//! Roots that were left behind by a process that no longer runs keep nothing alive, and are removed by the next
+//! collection.
+
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use nix::fcntl::FlockArg;
+use porkg_model::hashing::SupportedHash;
+
+use super::{entries, lock_with, root_packages};
+use crate::store::Store;
+
+pub(super) const TEMP_ROOTS: &str = "temproot";
+
+static TEMP_ROOT_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// A temporary root, which is removed when dropped.
+#[derive(Debug)]
+pub struct TempRoot {
+    store: Store,
+    path: PathBuf,
+}
+
+impl TempRoot {
+    /// Creates a temporary root that keeps `hashes` alive, for an operation described by `name`. Packages that aren't
+    /// in the store yet are kept alive once they are added.
+    ///
+    /// This performs blocking IO, and waits for a running collection to finish.
+    pub fn new(store: &Store, name: &str, hashes: &[SupportedHash]) -> io::Result<Self> {
+        let id = TEMP_ROOT_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let path = store
+            .path
+            .join(TEMP_ROOTS)
+            .join(format!("{name}-{}-{id}", std::process::id()));
+        fs::create_dir_all(&path)?;
+        let result = Self {
+            store: store.clone(),
+            path,
+        };
+        result.add(hashes)?;
+        Ok(result)
+    }
+
+    /// Keeps more packages alive.
+    ///
+    /// This performs blocking IO, and waits for a running collection to finish.
+    pub fn add(&self, hashes: &[SupportedHash]) -> io::Result<()> {
+        let _lock = lock_with(&self.store, FlockArg::LockShared)?;
+        for hash in hashes {
+            match std::os::unix::fs::symlink(
+                self.store.by_hash(hash),
+                self.path.join(hash.to_string()),
+            ) {
+                Err(error) if error.kind() == io::ErrorKind::AlreadyExists => {}
+                other => other?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TempRoot {
+    fn drop(&mut self) {
+        if let Err(error) = fs::remove_dir_all(&self.path) {
+            tracing::warn!(?error, path = ?self.path, "failed to remove a temporary root");
+        }
+    }
+}
+
+/// Whether the process that created a temporary root still runs.
+fn is_active(root: &Path) -> bool {
+    let pid = root
+        .file_name()
+        .and_then(|v| v.to_str())
+        .and_then(|v| v.rsplit('-').nth(1));
+    pid.is_some_and(|v| Path::new("/proc").join(v).exists())
+}
+
+/// Gets the packages that the temporary roots of running processes link to, removing the roots of other processes.
+pub(super) fn rooted(store: &Store, result: &mut BTreeSet<SupportedHash>) -> io::Result<()> {
+    for root in entries(&store.path.join(TEMP_ROOTS))? {
+        if is_active(&root) {
+            root_packages(&root, result)?;
+        } else {
+            tracing::info!(?root, "removing a stale temporary root");
+            fs::remove_dir_all(&root)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::store::{
+        gc,
+        testing::{add_package, TestStore},
+    };
+
+    use super::*;
+
+    #[test]
+    fn keep_in_flight_packages() {
+        let store = TestStore::new("temp-root");
+        let root = store.root();
+
+        let libc = add_package(&store, "libc", &[], 1);
+        let app = add_package(&store, "app", &[libc], 2);
+        let pending = SupportedHash::Blake3([3; 32]);
+
+        let temp = TempRoot::new(&store, "build", &[app, pending]).unwrap();
+        assert_eq!(gc::live(&store).unwrap(), [app, libc].into());
+        assert!(matches!(
+            gc::plan(&store, &libc, false),
+            Err(gc::GcError::Live(_))
+        ));
+        let added = add_package(&store, "pending", &[], 3);
+        assert!(gc::live(&store).unwrap().contains(&added));
+
+        // Roots of processes that no longer run are removed.
+        let stale = root.join(TEMP_ROOTS).join(format!("build-{}-0", u32::MAX));
+        fs::create_dir_all(&stale).unwrap();
+        std::os::unix::fs::symlink(store.by_hash(&libc), stale.join(libc.to_string())).unwrap();
+        drop(temp);
+        assert!(gc::live(&store).unwrap().is_empty());
+        assert!(!stale.exists());
+    }
+}
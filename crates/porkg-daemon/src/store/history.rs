@@ -0,0 +1,166 @@
+//! The history of the store: a record of every package that was added or deleted, with who did it and why.
+//!
+//! Records are appended to `history` in the store as lines of JSON. Each line is written at once to a file that is
+//! opened for appending, so every [`Store`] can record changes without coordinating with the others.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, OpenOptions},
+    io::{self, BufRead as _, BufReader, Write as _},
+    sync::Arc,
+};
+
+use porkg_model::{hashing::SupportedHash, time::Timestamp};
+use serde::{Deserialize, Serialize};
+
+use super::Store;
+use crate::events::StoreChange;
+
+const HISTORY_FILE: &str = "history";
+
+/// Who changes the store through a [`Store`], and why (see [`Store::with_origin`]).
+#[derive(Debug, Clone)]
+pub struct Origin {
+    /// The identity of the client, or `daemon` for changes that the daemon makes on its own.
+    pub actor: Arc<str>,
+    /// The operation that changes the store, such as `import-oci` or `gc`.
+    pub reason: &'static str,
+}
+
+impl Default for Origin {
+    fn default() -> Self {
+        Self {
+            actor: "daemon".into(),
+            reason: "unknown",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// When the change was made.
+    pub time: Timestamp,
+    pub hash: String,
+    pub name: String,
+    pub change: StoreChange,
+    /// The disk space of the package, in bytes.
+    pub bytes: u64,
+    pub actor: String,
+    pub reason: String,
+}
+
+/// Selects records from the history.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub hash: Option<SupportedHash>,
+    pub name: Option<String>,
+    pub actor: Option<String>,
+    /// Only records from this time on.
+    pub since: Option<Timestamp>,
+}
+
+impl Filter {
+    fn matches(&self, record: &Record) -> bool {
+        self.hash.map_or(true, |v| v.to_string() == record.hash)
+            && self.name.as_ref().map_or(true, |v| *v == record.name)
+            && self.actor.as_ref().map_or(true, |v| *v == record.actor)
+            && self.since.map_or(true, |v| record.time >= v)
+    }
+}
+
+/// Appends a change to the history.
+///
+/// This performs blocking IO.
+pub(super) fn append(
+    store: &Store,
+    hash: &SupportedHash,
+    name: &str,
+    change: StoreChange,
+    bytes: u64,
+) -> io::Result<()> {
+    let record = Record {
+        time: Timestamp::now(),
+        hash: hash.to_string(),
+        name: name.to_string(),
+        change,
+        bytes,
+        actor: store.origin.actor.to_string(),
+        reason: store.origin.reason.to_string(),
+    };
+    let mut line = serde_json::to_vec(&record)?;
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(store.path.join(HISTORY_FILE))?
+        .write_all(&line)
+}
+
+/// Reads the newest records that match `filter`, newest first.
+///
+/// This performs blocking IO.
+pub fn read(store: &Store, filter: &Filter, limit: usize) -> io::Result<Vec<Record>> {
+    let file = match fs::File::open(store.path.join(HISTORY_FILE)) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        other => other?,
+    };
+    let mut result = VecDeque::with_capacity(limit.min(1024));
+    for line in BufReader::new(file).lines() {
+        match serde_json::from_str::<Record>(&line?) {
+            Ok(record) if filter.matches(&record) => {
+                if result.len() == limit {
+                    result.pop_front();
+                }
+                if limit > 0 {
+                    result.push_back(record);
+                }
+            }
+            Ok(_) => {}
+            // A crash can leave a partial line behind.
+            Err(error) => tracing::warn!(?error, "ignoring an invalid history record"),
+        }
+    }
+    Ok(result.into_iter().rev().collect())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::store::{gc, testing::TestStore};
+
+    use super::*;
+
+    #[test]
+    fn record_changes() {
+        let store = TestStore::new("history");
+        let staging = store.root().join("staging");
+        fs::create_dir_all(staging.join("out")).unwrap();
+        fs::write(staging.join("out/file"), "hello").unwrap();
+
+        let hash = SupportedHash::Blake3([1; 32]);
+        let importer = store.clone().with_origin("ci", "import-source");
+        assert!(importer.insert(&staging, &hash, "hello").unwrap());
+        let plan = gc::plan(&store, &hash, false).unwrap();
+        gc::delete(&store.clone().with_origin("admin", "delete"), &plan).unwrap();
+
+        let all = read(&store, &Filter::default(), 10).unwrap();
+        assert_eq!(all.len(), 2);
+        assert_eq!(
+            (all[0].change, all[0].actor.as_str(), all[0].reason.as_str()),
+            (StoreChange::Deleted, "admin", "delete")
+        );
+        assert_eq!(
+            (all[1].change, all[1].actor.as_str(), all[1].reason.as_str()),
+            (StoreChange::Added, "ci", "import-source")
+        );
+        assert!(all
+            .iter()
+            .all(|v| v.hash == hash.to_string() && v.bytes > 0));
+
+        let filter = Filter {
+            actor: Some("admin".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(read(&store, &filter, 10).unwrap(), all[..1]);
+        assert_eq!(read(&store, &Filter::default(), 1).unwrap(), all[..1]);
+    }
+}
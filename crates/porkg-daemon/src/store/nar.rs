@@ -0,0 +1,355 @@
+//! A reader and writer for the Nix archive (NAR) format.
+//!
+//! A NAR is a stream of length-prefixed, 8-byte aligned strings that describe a single file system object:
+//!
+//! ```text
+//! nar      = "nix-archive-1" node
+//! node     = "(" "type" (regular | symlink | directory) ")"
+//! regular  = "regular" ["executable" ""] "contents" <bytes>
+//! symlink  = "symlink" "target" <string>
+//! directory= "directory" { "entry" "(" "name" <string> "node" node ")" }
+//! ```
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Write},
+    os::unix::{
+        ffi::OsStrExt as _,
+        fs::{symlink, OpenOptionsExt as _, PermissionsExt as _},
+    },
+    path::{Path, PathBuf},
+};
+
+use porkg_model::hashing::{SupportedHash, SupportedHasher};
+use thiserror::Error;
+
+const MAGIC: &[u8] = b"nix-archive-1";
+const MAX_TOKEN: u64 = 4096;
+/// The deepest that directories may be nested, which bounds the recursion while unpacking.
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Error)]
+pub enum NarError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error("expected {expected:?} in archive")]
+    Unexpected { expected: &'static str },
+    #[error("invalid entry name {0:?}")]
+    InvalidName(String),
+    #[error("archive token is too long")]
+    TooLong,
+    #[error("archive directories are nested too deeply")]
+    TooDeep,
+}
+
+struct Reader<R> {
+    inner: R,
+}
+
+impl<R: Read> Reader<R> {
+    fn read_u64(&mut self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        self.inner.read_exact(&mut buf)?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn skip_padding(&mut self, len: u64) -> io::Result<()> {
+        let padding = (8 - (len % 8)) % 8;
+        let mut buf = [0u8; 8];
+        self.inner.read_exact(&mut buf[..padding as usize])?;
+        Ok(())
+    }
+
+    fn read_token(&mut self) -> Result<Vec<u8>, NarError> {
+        let len = self.read_u64()?;
+        if len > MAX_TOKEN {
+            return Err(NarError::TooLong);
+        }
+
+        let mut buf = vec![0u8; len as usize];
+        self.inner.read_exact(&mut buf)?;
+        self.skip_padding(len)?;
+        Ok(buf)
+    }
+
+    fn expect(&mut self, expected: &'static str) -> Result<(), NarError> {
+        if self.read_token()? == expected.as_bytes() {
+            Ok(())
+        } else {
+            Err(NarError::Unexpected { expected })
+        }
+    }
+
+    fn read_node(&mut self, path: &Path, depth: usize) -> Result<(), NarError> {
+        if depth > MAX_DEPTH {
+            return Err(NarError::TooDeep);
+        }
+        self.expect("(")?;
+        self.expect("type")?;
+
+        match &self.read_token()?[..] {
+            b"regular" => {
+                let mut token = self.read_token()?;
+                let mut executable = false;
+                if token == b"executable" {
+                    self.expect("")?;
+                    executable = true;
+                    token = self.read_token()?;
+                }
+                if token != b"contents" {
+                    return Err(NarError::Unexpected {
+                        expected: "contents",
+                    });
+                }
+
+                let len = self.read_u64()?;
+                let mut file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(if executable { 0o555 } else { 0o444 })
+                    .open(path)?;
+                let copied = io::copy(&mut (&mut self.inner).take(len), &mut file)?;
+                if copied != len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+                self.skip_padding(len)?;
+                self.expect(")")?;
+            }
+            b"symlink" => {
+                self.expect("target")?;
+                let target = self.read_token()?;
+                symlink(bytes_to_path(target), path)?;
+                self.expect(")")?;
+            }
+            b"directory" => {
+                fs::create_dir(path)?;
+                loop {
+                    match &self.read_token()?[..] {
+                        b")" => break,
+                        b"entry" => {
+                            self.expect("(")?;
+                            self.expect("name")?;
+                            let name = self.read_token()?;
+                            validate_name(&name)?;
+                            self.expect("node")?;
+                            self.read_node(&path.join(bytes_to_path(name)), depth + 1)?;
+                            self.expect(")")?;
+                        }
+                        _ => return Err(NarError::Unexpected { expected: "entry" }),
+                    }
+                }
+            }
+            _ => return Err(NarError::Unexpected { expected: "type" }),
+        }
+
+        Ok(())
+    }
+}
+
+fn bytes_to_path(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt as _;
+    std::ffi::OsString::from_vec(bytes).into()
+}
+
+fn validate_name(name: &[u8]) -> Result<(), NarError> {
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') || name.contains(&0)
+    {
+        return Err(NarError::InvalidName(
+            String::from_utf8_lossy(name).into_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Unpacks a NAR into `target`, which must not exist.
+pub fn unpack(reader: impl Read, target: &Path) -> Result<(), NarError> {
+    let mut reader = Reader { inner: reader };
+    if reader.read_token()? != MAGIC {
+        return Err(NarError::Unexpected {
+            expected: "nix-archive-1",
+        });
+    }
+    reader.read_node(target, 0)
+}
+
+struct Writer<W> {
+    inner: W,
+}
+
+impl<W: Write> Writer<W> {
+    fn write_token(&mut self, token: impl AsRef<[u8]>) -> io::Result<()> {
+        let token = token.as_ref();
+        self.inner.write_all(&(token.len() as u64).to_le_bytes())?;
+        self.inner.write_all(token)?;
+        self.write_padding(token.len() as u64)
+    }
+
+    fn write_padding(&mut self, len: u64) -> io::Result<()> {
+        let padding = (8 - (len % 8)) % 8;
+        self.inner.write_all(&[0u8; 8][..padding as usize])
+    }
+
+    fn write_node(&mut self, path: &Path) -> io::Result<()> {
+        let metadata = fs::symlink_metadata(path)?;
+        self.write_token("(")?;
+        self.write_token("type")?;
+
+        if metadata.is_symlink() {
+            self.write_token("symlink")?;
+            self.write_token("target")?;
+            self.write_token(fs::read_link(path)?.as_os_str().as_bytes())?;
+        } else if metadata.is_file() {
+            self.write_token("regular")?;
+            if metadata.permissions().mode() & 0o100 != 0 {
+                self.write_token("executable")?;
+                self.write_token("")?;
+            }
+            self.write_token("contents")?;
+
+            let len = metadata.len();
+            self.inner.write_all(&len.to_le_bytes())?;
+            let copied = io::copy(&mut File::open(path)?.take(len), &mut self.inner)?;
+            if copied != len {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+            self.write_padding(len)?;
+        } else if metadata.is_dir() {
+            self.write_token("directory")?;
+
+            let mut entries = fs::read_dir(path)?
+                .map(|v| v.map(|v| v.file_name()))
+                .collect::<io::Result<Vec<_>>>()?;
+            entries.sort();
+            for name in entries {
+                self.write_token("entry")?;
+                self.write_token("(")?;
+                self.write_token("name")?;
+                self.write_token(name.as_bytes())?;
+                self.write_token("node")?;
+                self.write_node(&path.join(&name))?;
+                self.write_token(")")?;
+            }
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{path:?} cannot be represented in an archive"),
+            ));
+        }
+
+        self.write_token(")")
+    }
+}
+
+/// Serializes `source` as a NAR.
+///
+/// Only the executable bit of each file is retained, so equivalent trees always produce the same archive.
+pub fn dump(source: &Path, writer: impl Write) -> io::Result<()> {
+    let mut writer = Writer { inner: writer };
+    writer.write_token(MAGIC)?;
+    writer.write_node(source)?;
+    writer.inner.flush()
+}
+
+struct HashingWriter(SupportedHasher);
+
+impl Write for HashingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Computes the content hash of `source`, which is the blake3 hash of its NAR serialization.
+pub fn hash(source: &Path) -> io::Result<SupportedHash> {
+    let mut hasher = HashingWriter(SupportedHasher::blake3());
+    dump(source, &mut hasher)?;
+    Ok(hasher.0.finalize())
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Builds NAR files for tests.
+    #[derive(Default)]
+    pub(crate) struct NarBuilder(pub Vec<u8>);
+
+    impl NarBuilder {
+        pub fn token(mut self, v: impl AsRef<[u8]>) -> Self {
+            let v = v.as_ref();
+            self.0.extend_from_slice(&(v.len() as u64).to_le_bytes());
+            self.0.extend_from_slice(v);
+            self.0.resize(self.0.len() + (8 - v.len() % 8) % 8, 0);
+            self
+        }
+
+        pub fn tokens(self, v: &[&str]) -> Self {
+            v.iter().fold(self, |b, v| b.token(v))
+        }
+    }
+
+    pub(crate) fn sample() -> Vec<u8> {
+        NarBuilder::default()
+            .tokens(&["nix-archive-1", "(", "type", "directory"])
+            .tokens(&["entry", "(", "name", "bin", "node"])
+            .tokens(&["(", "type", "regular", "executable", "", "contents"])
+            .token("#!/bin/sh\n")
+            .tokens(&[")", ")"])
+            .tokens(&["entry", "(", "name", "link", "node"])
+            .tokens(&["(", "type", "symlink", "target", "bin", ")", ")"])
+            .token(")")
+            .0
+    }
+
+    #[test]
+    fn unpack_sample() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().join("out");
+        unpack(&sample()[..], &dir).unwrap();
+
+        let bin = dir.join("bin");
+        assert_eq!(fs::read(&bin).unwrap(), b"#!/bin/sh\n");
+        assert_eq!(
+            fs::metadata(&bin).unwrap().permissions().mode() & 0o777,
+            0o555
+        );
+        assert_eq!(fs::read_link(dir.join("link")).unwrap(), Path::new("bin"));
+
+        let mut nar = Vec::new();
+        dump(&dir, &mut nar).unwrap();
+        assert_eq!(nar, sample());
+    }
+
+    #[test]
+    fn unpack_traversal() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().join("out");
+        let nar = NarBuilder::default()
+            .tokens(&["nix-archive-1", "(", "type", "directory"])
+            .tokens(&["entry", "(", "name", "..", "node"])
+            .0;
+
+        assert!(matches!(
+            unpack(&nar[..], &dir),
+            Err(NarError::InvalidName(_))
+        ));
+    }
+
+    #[test]
+    fn unpack_deep() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().join("out");
+        let mut nar = NarBuilder::default().token("nix-archive-1");
+        for _ in 0..=MAX_DEPTH {
+            nar = nar
+                .tokens(&["(", "type", "directory"])
+                .tokens(&["entry", "(", "name", "a", "node"]);
+        }
+
+        assert!(matches!(unpack(&nar.0[..], &dir), Err(NarError::TooDeep)));
+    }
+}
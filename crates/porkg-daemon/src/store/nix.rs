@@ -0,0 +1,294 @@
+//! Imports store paths from Nix binary caches.
+//!
+//! A path is identified in the porkg store by the blake3 hash of its uncompressed NAR. The original `.narinfo` is
+//! stored alongside the unpacked contents (as `nix.json`) so that the Nix store path, its references, and the
+//! original signatures remain available.
+//!
+//! References are resolved through `pkg/by-nix`, so they must be imported before the paths that refer to them.
+//!
+//! NARs compressed with xz, zstd or gzip are decompressed while they are unpacked, and the hash of the compressed file
+//! is checked too when the `.narinfo` has one.
+
+use std::{
+    fs,
+    io::{self, BufReader, Read},
+    path::Path,
+};
+
+use porkg_model::{
+    hashing::{SupportedHash, SupportedHasher},
+    nix::{NarCompression, NarInfo, NarInfoError, NixHash},
+    store::PackageInfo,
+};
+use sha2::{Digest as _, Sha256};
+use thiserror::Error;
+
+use super::{link, nar, Store, StoreError};
+
+const TARGET: &str = "out";
+const ORIGIN_FILE: &str = "nix.json";
+
+#[derive(Debug, Error)]
+pub enum NixImportError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error(transparent)]
+    Nar(#[from] nar::NarError),
+    #[error(transparent)]
+    Serialization(#[from] serde_json::Error),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    InvalidNarInfo(#[from] NarInfoError),
+    #[error("referenced path {0} has not been imported")]
+    MissingReference(String),
+    #[error("unsupported compression {0:?}")]
+    UnsupportedCompression(NarCompression),
+    #[error("nar size mismatch: expected {expected}, got {actual}")]
+    SizeMismatch { expected: u64, actual: u64 },
+    #[error("nar hash mismatch: expected {expected}, got {actual}")]
+    HashMismatch { expected: NixHash, actual: NixHash },
+    #[error("file size mismatch: expected {expected}, got {actual}")]
+    FileSizeMismatch { expected: u64, actual: u64 },
+    #[error("file hash mismatch: expected {expected}, got {actual}")]
+    FileHashMismatch { expected: NixHash, actual: NixHash },
+}
+
+/// Hashes the compressed file read through it.
+struct FileReader<R> {
+    inner: R,
+    size: u64,
+    sha256: Sha256,
+}
+
+impl<R: Read> Read for FileReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.size += len as u64;
+        self.sha256.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Hashes everything read through it.
+struct HashingReader<R> {
+    inner: R,
+    size: u64,
+    sha256: Sha256,
+    hasher: SupportedHasher,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.size += len as u64;
+        self.sha256.update(&buf[..len]);
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+/// Gets the hash that the Nix store path `base_name` was imported under, if it was.
+pub fn imported(store: &Store, base_name: &str) -> Option<SupportedHash> {
+    let target = fs::read_link(store.by_nix(base_name)).ok()?;
+    target.file_name()?.to_str()?.parse().ok()
+}
+
+/// Imports a NAR file described by `info`, compressed as it says, into the store, returning the hash it was stored
+/// under.
+///
+/// This performs blocking IO.
+#[tracing::instrument(skip_all, fields(store_path = info.store_path))]
+pub fn import_nar(
+    store: &Store,
+    info: &NarInfo,
+    reader: impl Read,
+) -> Result<SupportedHash, NixImportError> {
+    info.validate()?;
+    if info.compression == NarCompression::Bzip2 {
+        return Err(NixImportError::UnsupportedCompression(info.compression));
+    }
+
+    let staging = store.temp_path("nix");
+    fs::create_dir_all(&staging)?;
+
+    let result = import_staged(store, info, reader, &staging);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)
+            .inspect_err(|error| tracing::warn!(?error, ?staging, "failed to clean up staging"))
+            .ok();
+    }
+    result
+}
+
+/// Wraps `reader` so that it reads the NAR compressed in it.
+fn decompress<'a>(
+    compression: NarCompression,
+    reader: impl Read + 'a,
+) -> Result<Box<dyn Read + 'a>, NixImportError> {
+    Ok(match compression {
+        NarCompression::None => Box::new(reader),
+        NarCompression::Xz => Box::new(xz2::bufread::XzDecoder::new(BufReader::new(reader))),
+        NarCompression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        NarCompression::Gzip => Box::new(flate2::bufread::GzDecoder::new(BufReader::new(reader))),
+        NarCompression::Bzip2 => return Err(NixImportError::UnsupportedCompression(compression)),
+    })
+}
+
+fn import_staged(
+    store: &Store,
+    info: &NarInfo,
+    reader: impl Read,
+    staging: &Path,
+) -> Result<SupportedHash, NixImportError> {
+    let mut file = FileReader {
+        inner: reader,
+        size: 0,
+        sha256: Sha256::new(),
+    };
+    let (nar_size, sha256, hasher) = {
+        let mut reader = HashingReader {
+            inner: decompress(info.compression, &mut file)?,
+            size: 0,
+            sha256: Sha256::new(),
+            hasher: SupportedHasher::blake3(),
+        };
+        nar::unpack(&mut reader, &staging.join(TARGET))?;
+        // Anything after the archive is still part of the hashed content.
+        io::copy(&mut reader, &mut io::sink())?;
+        (reader.size, reader.sha256, reader.hasher)
+    };
+    io::copy(&mut file, &mut io::sink())?;
+
+    if let Some(expected) = info.file_size.filter(|v| *v != file.size) {
+        return Err(NixImportError::FileSizeMismatch {
+            expected,
+            actual: file.size,
+        });
+    }
+    let actual = NixHash::Sha256(file.sha256.finalize().into());
+    if let Some(expected) = info.file_hash.filter(|v| *v != actual) {
+        return Err(NixImportError::FileHashMismatch { expected, actual });
+    }
+
+    if nar_size != info.nar_size {
+        return Err(NixImportError::SizeMismatch {
+            expected: info.nar_size,
+            actual: nar_size,
+        });
+    }
+
+    let actual = NixHash::Sha256(sha256.finalize().into());
+    if actual != info.nar_hash {
+        return Err(NixImportError::HashMismatch {
+            expected: info.nar_hash,
+            actual,
+        });
+    }
+
+    let mut package = PackageInfo::new(info.name());
+    for reference in info.references.iter().filter(|v| *v != info.base_name()) {
+        let hash = imported(store, reference)
+            .ok_or_else(|| NixImportError::MissingReference(reference.clone()))?;
+        package.references.insert(hash);
+    }
+
+    let hash = hasher.finalize();
+    if store.by_hash(&hash).exists() {
+        tracing::debug!(%hash, "path already exists in the store");
+    } else {
+        fs::write(staging.join(ORIGIN_FILE), serde_json::to_vec_pretty(info)?)?;
+        Store::write_info(staging, &package)?;
+        store.insert(staging, &hash, info.name())?;
+    }
+
+    // Paths with the same contents share the package, but each is resolved as a reference by its own name.
+    let by_nix = store.by_nix(info.base_name());
+    if let Some(parent) = by_nix.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    link(&store.by_hash(&hash), &by_nix)?;
+
+    tracing::info!(%hash, "imported nix store path");
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::store::testing::TestStore;
+
+    use super::*;
+
+    #[test]
+    fn import_sample() {
+        let nar = nar::test::sample();
+        let store = TestStore::new("nix-import");
+
+        let info = NarInfo {
+            store_path: "/nix/store/00000000000000000000000000000000-sample".into(),
+            url: "nar/sample.nar".into(),
+            compression: NarCompression::None,
+            file_hash: None,
+            file_size: None,
+            nar_hash: NixHash::Sha256(Sha256::digest(&nar).into()),
+            nar_size: nar.len() as u64,
+            references: Vec::new(),
+            deriver: None,
+            signatures: Vec::new(),
+            ca: None,
+        };
+
+        let hash = import_nar(&store, &info, &nar[..]).unwrap();
+        let dir = store.by_hash(&hash);
+        assert!(dir.join("out/bin").exists());
+        assert!(dir.join(ORIGIN_FILE).exists());
+        assert!(store.by_name("sample").join(hash.to_string()).exists());
+        assert_eq!(store.info(&hash).unwrap().name, "sample");
+
+        let mut dependent = info.clone();
+        dependent.store_path = "/nix/store/11111111111111111111111111111111-dependent".into();
+        dependent.references = vec![
+            "00000000000000000000000000000000-sample".into(),
+            "22222222222222222222222222222222-missing".into(),
+        ];
+        assert!(matches!(
+            import_nar(&store, &dependent, &nar[..]),
+            Err(NixImportError::MissingReference(_))
+        ));
+
+        let mut corrupt = info.clone();
+        corrupt.nar_size += 1;
+        assert!(matches!(
+            import_nar(&store, &corrupt, &nar[..]),
+            Err(NixImportError::SizeMismatch { .. })
+        ));
+
+        let mut escape = info.clone();
+        escape.store_path = "/nix/store/..".into();
+        assert!(matches!(
+            import_nar(&store, &escape, &nar[..]),
+            Err(NixImportError::InvalidNarInfo(_))
+        ));
+
+        let mut xz = Vec::new();
+        xz2::read::XzEncoder::new(&nar[..], 6)
+            .read_to_end(&mut xz)
+            .unwrap();
+        let mut compressed = info.clone();
+        compressed.store_path = "/nix/store/33333333333333333333333333333333-compressed".into();
+        compressed.compression = NarCompression::Xz;
+        compressed.file_size = Some(xz.len() as u64);
+        compressed.file_hash = Some(NixHash::Sha256(Sha256::digest(&xz).into()));
+        assert_eq!(import_nar(&store, &compressed, &xz[..]).unwrap(), hash);
+        assert_eq!(
+            imported(&store, "33333333333333333333333333333333-compressed"),
+            Some(hash)
+        );
+
+        compressed.file_hash = Some(info.nar_hash);
+        assert!(matches!(
+            import_nar(&store, &compressed, &xz[..]),
+            Err(NixImportError::FileHashMismatch { .. })
+        ));
+    }
+}
@@ -0,0 +1,743 @@
+//! Exports package closures as OCI image layouts, and imports images as root file systems.
+//!
+//! Each package in the closure becomes its own (uncompressed) layer, placed at the same path that it occupies in
+//! the store. Layers are produced deterministically, so the same package always results in the same layer digest and
+//! runtimes can share layers between images.
+//!
+//! Imported images are flattened into a single `rootfs` directory, which builds can use as their base environment.
+//! Like Nix imports, they are stored under the blake3 hash of the NAR serialization of their contents.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use porkg_model::{hashing::SupportedHash, package::Executable, store::PackageInfo};
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest as _, Sha256};
+use thiserror::Error;
+
+use super::{gc::temp::TempRoot, nar, Store, StoreError};
+use crate::archive::ArchiveError;
+
+mod layer;
+
+const MEDIA_TYPE_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+const MEDIA_TYPE_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const MEDIA_TYPE_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+const MEDIA_TYPE_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+const MEDIA_TYPE_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+const MEDIA_TYPE_DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+const MEDIA_TYPE_DOCKER_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const MEDIA_TYPE_DOCKER_LAYER: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+
+/// The directory of an imported image that its root file system is flattened into.
+pub const ROOTFS: &str = "rootfs";
+
+#[derive(Debug, Error)]
+pub enum OciError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Serialization(#[from] serde_json::Error),
+    #[error("invalid executable: {0}")]
+    InvalidExecutable(String),
+    #[error("invalid image layout: {0}")]
+    InvalidLayout(String),
+    #[error("blob digest mismatch: expected {expected}, got {actual}")]
+    DigestMismatch { expected: String, actual: String },
+    #[error("unsupported media type {0}")]
+    UnsupportedMediaType(String),
+    #[error(transparent)]
+    Archive(#[from] ArchiveError),
+}
+
+/// A blob within the image.
+struct Descriptor {
+    digest: String,
+    size: u64,
+}
+
+impl Descriptor {
+    fn of(data: &[u8]) -> Self {
+        Self {
+            digest: hex(&Sha256::digest(data)),
+            size: data.len() as u64,
+        }
+    }
+
+    fn to_json(&self, media_type: &str) -> serde_json::Value {
+        json!({
+            "mediaType": media_type,
+            "digest": format!("sha256:{}", self.digest),
+            "size": self.size,
+        })
+    }
+}
+
+/// Counts and hashes everything written through it.
+struct DigestWriter<W> {
+    inner: W,
+    size: u64,
+    sha256: Sha256,
+}
+
+impl<W: Write> Write for DigestWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.size += len as u64;
+        self.sha256.update(&buf[..len]);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    data_encoding::HEXLOWER.encode(bytes)
+}
+
+fn architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        other => other,
+    }
+}
+
+fn header(kind: tar::EntryType, mode: u32, size: u64) -> tar::Header {
+    let mut header = tar::Header::new_gnu();
+    header.set_entry_type(kind);
+    header.set_mode(mode);
+    header.set_size(size);
+    header.set_mtime(0);
+    header.set_uid(0);
+    header.set_gid(0);
+    header
+}
+
+/// Appends a directory tree to an archive, in a stable order.
+fn append_tree<W: Write>(
+    builder: &mut tar::Builder<W>,
+    source: &Path,
+    path: &Path,
+) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    let metadata = fs::symlink_metadata(source)?;
+    let mode = metadata.permissions().mode() & 0o7777;
+    if metadata.is_symlink() {
+        let target = fs::read_link(source)?;
+        builder.append_link(&mut header(tar::EntryType::Symlink, 0o777, 0), path, target)?;
+    } else if metadata.is_file() {
+        let mut entry = header(tar::EntryType::Regular, mode, metadata.len());
+        builder.append_data(&mut entry, path, File::open(source)?)?;
+    } else if metadata.is_dir() {
+        let mut entry = header(tar::EntryType::Directory, mode, 0);
+        builder.append_data(&mut entry, path, io::empty())?;
+
+        let mut entries = fs::read_dir(source)?
+            .map(|v| v.map(|v| v.file_name()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for name in entries {
+            append_tree(builder, &source.join(&name), &path.join(&name))?;
+        }
+    } else {
+        tracing::warn!(?source, "skipping special file");
+    }
+    Ok(())
+}
+
+/// Writes the layer for a single package to `target`.
+fn write_layer(store: &Store, hash: &SupportedHash, target: &Path) -> io::Result<Descriptor> {
+    let mut writer = DigestWriter {
+        inner: File::create(target)?,
+        size: 0,
+        sha256: Sha256::new(),
+    };
+
+    let source = store.by_hash(hash);
+    let path = image_path(&store.logical(hash));
+    let mut builder = tar::Builder::new(&mut writer);
+
+    // The ancestors are included so that the layer can be applied on its own.
+    let mut ancestors = path.ancestors().skip(1).collect::<Vec<_>>();
+    ancestors.reverse();
+    for ancestor in ancestors.into_iter().filter(|v| !v.as_os_str().is_empty()) {
+        let mut entry = header(tar::EntryType::Directory, 0o755, 0);
+        builder.append_data(&mut entry, ancestor, io::empty())?;
+    }
+
+    append_tree(&mut builder, &source, &path)?;
+    builder.into_inner()?.flush()?;
+
+    Ok(Descriptor {
+        digest: hex(&writer.sha256.finalize()),
+        size: writer.size,
+    })
+}
+
+/// Gets the path within the image of a path in the store.
+fn image_path(path: &Path) -> PathBuf {
+    path.strip_prefix("/").unwrap_or(path).to_path_buf()
+}
+
+/// Creates the image configuration, using the executable of the package as the entrypoint.
+fn create_config(
+    store: &Store,
+    closure: &[(SupportedHash, PackageInfo)],
+    diff_ids: Vec<String>,
+) -> Result<serde_json::Value, OciError> {
+    let mut config = json!({});
+    if let Some((hash, info)) = closure.last() {
+        if let Some(executable) = &info.executable {
+            let context = |name: &str| {
+                if name == "out" {
+                    return Some(store.logical(hash));
+                }
+                closure
+                    .iter()
+                    .find(|(hash, v)| v.name == name && info.references.contains(hash))
+                    .map(|(hash, _)| store.logical(hash))
+            };
+            let expand = |value: &str| {
+                porkg_private::string::expand(value, |name| {
+                    context(name).map(|v| v.to_string_lossy().into_owned())
+                })
+                .map(|v| v.into_owned())
+                .map_err(|error| OciError::InvalidExecutable(error.to_string()))
+            };
+
+            let entrypoint = executable
+                .exec
+                .iter()
+                .map(|v| expand(v))
+                .collect::<Result<Vec<_>, _>>()?;
+            let env = executable
+                .env
+                .iter()
+                .map(|(k, v)| expand(v).map(|v| format!("{k}={v}")))
+                .collect::<Result<Vec<_>, _>>()?;
+            config = json!({
+                "Entrypoint": entrypoint,
+                "Env": env,
+            });
+        }
+    }
+
+    Ok(json!({
+        "architecture": architecture(),
+        "os": "linux",
+        "config": config,
+        "rootfs": {
+            "type": "layers",
+            "diff_ids": diff_ids,
+        },
+    }))
+}
+
+fn append_blob<W: Write>(
+    builder: &mut tar::Builder<W>,
+    descriptor: &Descriptor,
+    reader: impl Read,
+) -> io::Result<()> {
+    let mut entry = header(tar::EntryType::Regular, 0o644, descriptor.size);
+    let path = format!("blobs/sha256/{}", descriptor.digest);
+    builder.append_data(&mut entry, path, reader)
+}
+
+/// Writes the closure of a package as an OCI image layout archive.
+///
+/// This performs blocking IO.
+#[tracing::instrument(skip(store, writer))]
+pub fn export(store: &Store, hash: &SupportedHash, writer: impl Write) -> Result<(), OciError> {
+    let _root = TempRoot::new(store, "oci-export", &[*hash])?;
+    let closure = store.closure(hash)?;
+
+    let staging = store.temp_path("oci");
+    fs::create_dir_all(&staging)?;
+
+    let result = export_staged(store, &closure, &staging, writer);
+    fs::remove_dir_all(&staging)
+        .inspect_err(|error| tracing::warn!(?error, ?staging, "failed to clean up staging"))
+        .ok();
+    result
+}
+
+fn export_staged(
+    store: &Store,
+    closure: &[(SupportedHash, PackageInfo)],
+    staging: &Path,
+    writer: impl Write,
+) -> Result<(), OciError> {
+    let mut layers = Vec::with_capacity(closure.len());
+    for (index, (hash, _)) in closure.iter().enumerate() {
+        let path = staging.join(format!("{index}.tar"));
+        let descriptor = write_layer(store, hash, &path)?;
+        tracing::trace!(%hash, digest = descriptor.digest, "created layer");
+        layers.push((descriptor, path));
+    }
+
+    let diff_ids = layers
+        .iter()
+        .map(|(v, _)| format!("sha256:{}", v.digest))
+        .collect();
+    let config = serde_json::to_vec(&create_config(store, closure, diff_ids)?)?;
+    let config_descriptor = Descriptor::of(&config);
+
+    let manifest = serde_json::to_vec(&json!({
+        "schemaVersion": 2,
+        "mediaType": MEDIA_TYPE_MANIFEST,
+        "config": config_descriptor.to_json(MEDIA_TYPE_CONFIG),
+        "layers": layers.iter().map(|(v, _)| v.to_json(MEDIA_TYPE_LAYER)).collect::<Vec<_>>(),
+    }))?;
+    let manifest_descriptor = Descriptor::of(&manifest);
+
+    let index = serde_json::to_vec(&json!({
+        "schemaVersion": 2,
+        "mediaType": MEDIA_TYPE_INDEX,
+        "manifests": [manifest_descriptor.to_json(MEDIA_TYPE_MANIFEST)],
+    }))?;
+    let layout = serde_json::to_vec(&json!({ "imageLayoutVersion": "1.0.0" }))?;
+
+    let mut builder = tar::Builder::new(writer);
+    let mut entry = header(tar::EntryType::Regular, 0o644, layout.len() as u64);
+    builder.append_data(&mut entry, "oci-layout", &layout[..])?;
+    let mut entry = header(tar::EntryType::Regular, 0o644, index.len() as u64);
+    builder.append_data(&mut entry, "index.json", &index[..])?;
+
+    append_blob(&mut builder, &manifest_descriptor, &manifest[..])?;
+    append_blob(&mut builder, &config_descriptor, &config[..])?;
+    for (descriptor, path) in layers.iter() {
+        append_blob(&mut builder, descriptor, File::open(path)?)?;
+    }
+
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct DescriptorJson {
+    media_type: String,
+    digest: String,
+    #[serde(default)]
+    platform: Option<PlatformJson>,
+}
+
+impl DescriptorJson {
+    /// Determines whether the descriptor can be used on the current platform.
+    fn is_for_platform(&self) -> bool {
+        self.platform.as_ref().map_or(true, |v| {
+            v.os == "linux" && v.architecture == architecture()
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct PlatformJson {
+    architecture: String,
+    os: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct IndexJson {
+    manifests: Vec<DescriptorJson>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ManifestJson {
+    config: DescriptorJson,
+    layers: Vec<DescriptorJson>,
+}
+
+/// A manifest or an index, as a registry returns either for a reference.
+#[derive(Debug, Deserialize)]
+struct PulledJson {
+    #[serde(default)]
+    manifests: Option<Vec<DescriptorJson>>,
+    #[serde(default)]
+    config: Option<DescriptorJson>,
+    #[serde(default)]
+    layers: Vec<DescriptorJson>,
+}
+
+/// What a manifest or index that was pulled from a registry refers to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Pulled {
+    /// A manifest, with the digests of its configuration and layers.
+    Manifest { blobs: Vec<String> },
+    /// An index, with the digest of the manifest (or nested index) for the current platform.
+    Index { digest: String },
+}
+
+impl Pulled {
+    /// Reads a manifest or index that was pulled from a registry.
+    pub fn read(data: &[u8]) -> Result<Self, OciError> {
+        let pulled: PulledJson = serde_json::from_slice(data)?;
+        if let Some(manifests) = pulled.manifests {
+            return manifests
+                .into_iter()
+                .find(DescriptorJson::is_for_platform)
+                .map(|v| Pulled::Index { digest: v.digest })
+                .ok_or_else(|| {
+                    OciError::InvalidLayout(format!("no manifest for linux/{}", architecture()))
+                });
+        }
+        let config = pulled
+            .config
+            .ok_or_else(|| OciError::InvalidLayout("the manifest has no config".into()))?;
+        let blobs = std::iter::once(config)
+            .chain(pulled.layers)
+            .map(|v| v.digest)
+            .collect();
+        Ok(Pulled::Manifest { blobs })
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct ImageConfigJson {
+    #[serde(default)]
+    config: Option<ContainerConfigJson>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContainerConfigJson {
+    #[serde(default)]
+    entrypoint: Option<Vec<String>>,
+    #[serde(default)]
+    cmd: Option<Vec<String>>,
+    #[serde(default)]
+    env: Option<Vec<String>>,
+}
+
+/// The blobs of an image layout that has been unpacked into staging.
+struct Blobs {
+    path: PathBuf,
+}
+
+impl Blobs {
+    fn path(&self, digest: &str) -> Result<PathBuf, OciError> {
+        let name = digest
+            .strip_prefix("sha256:")
+            .filter(|v| v.len() == 64 && v.bytes().all(|v| v.is_ascii_hexdigit()))
+            .ok_or_else(|| OciError::InvalidLayout(format!("unsupported digest {digest}")))?;
+        let path = self.path.join(name);
+        if !path.exists() {
+            return Err(OciError::InvalidLayout(format!("missing blob {digest}")));
+        }
+        Ok(path)
+    }
+
+    fn read_json<T: for<'de> Deserialize<'de>>(&self, digest: &str) -> Result<T, OciError> {
+        Ok(serde_json::from_slice(&fs::read(self.path(digest)?)?)?)
+    }
+
+    /// Unpacks the blobs of an image layout archive, verifying their digests, and returns the index.
+    fn unpack(path: PathBuf, reader: impl Read) -> Result<(Self, IndexJson), OciError> {
+        fs::create_dir_all(&path)?;
+
+        let mut index = None;
+        for entry in tar::Archive::new(reader).entries()? {
+            let mut entry = entry?;
+            let name = entry.path()?.to_string_lossy().into_owned();
+            let name = name.trim_start_matches("./");
+
+            if name == "index.json" {
+                index = Some(serde_json::from_reader(&mut entry)?);
+            } else if let Some(digest) = name.strip_prefix("blobs/sha256/") {
+                if digest.len() != 64 || !digest.bytes().all(|v| v.is_ascii_hexdigit()) {
+                    return Err(OciError::InvalidLayout(format!("invalid blob name {name}")));
+                }
+
+                let mut writer = DigestWriter {
+                    inner: File::create(path.join(digest))?,
+                    size: 0,
+                    sha256: Sha256::new(),
+                };
+                io::copy(&mut entry, &mut writer)?;
+                let actual = hex(&writer.sha256.finalize());
+                if actual != digest {
+                    return Err(OciError::DigestMismatch {
+                        expected: digest.to_string(),
+                        actual,
+                    });
+                }
+            }
+        }
+
+        let index = index.ok_or_else(|| OciError::InvalidLayout("missing index.json".into()))?;
+        Ok((Self { path }, index))
+    }
+
+    /// Finds the manifest for the current platform.
+    fn select_manifest(&self, index: IndexJson) -> Result<ManifestJson, OciError> {
+        let mut manifests = index.manifests;
+        while !manifests.is_empty() {
+            let position = manifests.iter().position(DescriptorJson::is_for_platform);
+            let Some(descriptor) = position.map(|v| manifests.swap_remove(v)) else {
+                break;
+            };
+
+            match descriptor.media_type.as_str() {
+                MEDIA_TYPE_MANIFEST | MEDIA_TYPE_DOCKER_MANIFEST => {
+                    return self.read_json(&descriptor.digest)
+                }
+                MEDIA_TYPE_INDEX | MEDIA_TYPE_DOCKER_LIST => {
+                    manifests = self.read_json::<IndexJson>(&descriptor.digest)?.manifests;
+                }
+                other => tracing::debug!(media_type = other, "skipping manifest"),
+            }
+        }
+
+        Err(OciError::InvalidLayout(format!(
+            "no manifest for linux/{}",
+            architecture()
+        )))
+    }
+
+    fn open_layer(&self, descriptor: &DescriptorJson) -> Result<Box<dyn Read>, OciError> {
+        let file = File::open(self.path(&descriptor.digest)?)?;
+        match descriptor.media_type.as_str() {
+            MEDIA_TYPE_LAYER => Ok(Box::new(file)),
+            MEDIA_TYPE_LAYER_GZIP | MEDIA_TYPE_DOCKER_LAYER => {
+                Ok(Box::new(flate2::read::GzDecoder::new(file)))
+            }
+            other => Err(OciError::UnsupportedMediaType(other.to_string())),
+        }
+    }
+}
+
+/// Creates the default executable of an imported image from its configuration.
+fn image_executable(config: ImageConfigJson) -> Option<Executable> {
+    let config = config.config?;
+    let exec = config
+        .entrypoint
+        .into_iter()
+        .flatten()
+        .chain(config.cmd.into_iter().flatten())
+        .collect::<Vec<_>>();
+    if exec.is_empty() {
+        return None;
+    }
+
+    let env = config
+        .env
+        .into_iter()
+        .flatten()
+        .filter_map(|v| {
+            v.split_once('=')
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+        })
+        .collect::<BTreeMap<_, _>>();
+    Some(Executable { exec, env })
+}
+
+/// Imports an OCI image layout archive into the store as a root file system, returning the hash it was stored under.
+///
+/// This performs blocking IO.
+#[tracing::instrument(skip(store, reader))]
+pub fn import(store: &Store, name: &str, reader: impl Read) -> Result<SupportedHash, OciError> {
+    in_staging(store, |staging| {
+        let (blobs, index) = Blobs::unpack(staging.join("blobs"), reader)?;
+        import_image(store, name, &blobs, index, staging)
+    })
+}
+
+/// Imports an image that was pulled from a registry, whose blobs were downloaded into `blobs` and verified, as a root
+/// file system. `manifest` is the digest of the manifest for the current platform.
+///
+/// This performs blocking IO.
+#[tracing::instrument(skip(store, blobs))]
+pub fn import_pulled(
+    store: &Store,
+    name: &str,
+    blobs: &Path,
+    manifest: &str,
+) -> Result<SupportedHash, OciError> {
+    let blobs = Blobs {
+        path: blobs.to_path_buf(),
+    };
+    let index = IndexJson {
+        manifests: vec![DescriptorJson {
+            media_type: MEDIA_TYPE_MANIFEST.to_string(),
+            digest: manifest.to_string(),
+            platform: None,
+        }],
+    };
+    in_staging(store, |staging| {
+        import_image(store, name, &blobs, index, staging)
+    })
+}
+
+/// Runs `f` with a staging directory, which is removed afterwards.
+fn in_staging<T>(
+    store: &Store,
+    f: impl FnOnce(&Path) -> Result<T, OciError>,
+) -> Result<T, OciError> {
+    let staging = store.temp_path("oci");
+    fs::create_dir_all(&staging)?;
+
+    let result = f(&staging);
+    fs::remove_dir_all(&staging)
+        .inspect_err(|error| tracing::warn!(?error, ?staging, "failed to clean up staging"))
+        .ok();
+    result
+}
+
+fn import_image(
+    store: &Store,
+    name: &str,
+    blobs: &Blobs,
+    index: IndexJson,
+    staging: &Path,
+) -> Result<SupportedHash, OciError> {
+    let manifest = blobs.select_manifest(index)?;
+    let config: ImageConfigJson = blobs.read_json(&manifest.config.digest)?;
+
+    let package = staging.join("package");
+    let rootfs = package.join(ROOTFS);
+    fs::create_dir_all(&rootfs)?;
+    for descriptor in manifest.layers.iter() {
+        tracing::trace!(digest = descriptor.digest, "applying layer");
+        layer::apply(&rootfs, || blobs.open_layer(descriptor))?;
+    }
+
+    let hash = nar::hash(&rootfs)?;
+
+    let mut info = PackageInfo::new(name);
+    info.executable = image_executable(config);
+    Store::write_info(&package, &info)?;
+
+    if store.insert(&package, &hash, name)? {
+        tracing::info!(%hash, "imported image");
+    }
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use porkg_model::package::Executable;
+
+    use crate::config::StoreConfig;
+
+    use super::*;
+
+    fn add_package(store: &Store, info: &PackageInfo, byte: u8) -> SupportedHash {
+        let hash = SupportedHash::Blake3([byte; 32]);
+        let dir = store.by_hash(&hash);
+        fs::create_dir_all(dir.join("out/bin")).unwrap();
+        fs::write(dir.join("out/bin/tool"), info.name.as_bytes()).unwrap();
+        Store::write_info(&dir, info).unwrap();
+        hash
+    }
+
+    #[test]
+    fn export_closure() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        // Packages are laid out in images where they are found within sandboxes.
+        let store = Store::new(&StoreConfig {
+            path: root.to_path_buf(),
+            prefix: "/porkg/store".into(),
+        });
+
+        let dependency = add_package(&store, &PackageInfo::new("busybox"), 1);
+        let mut info = PackageInfo::new("app");
+        info.references.insert(dependency);
+        info.executable = Some(Executable {
+            exec: vec!["${out}/out/bin/tool".into()],
+            env: BTreeMap::from([("PATH".into(), "${busybox}/out/bin".into())]),
+        });
+        let hash = add_package(&store, &info, 2);
+
+        let mut image = Vec::new();
+        export(&store, &hash, &mut image).unwrap();
+
+        let mut files = BTreeMap::new();
+        for entry in tar::Archive::new(&image[..]).entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().into_owned();
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data).unwrap();
+            files.insert(path, data);
+        }
+
+        let index: serde_json::Value = serde_json::from_slice(&files["index.json"]).unwrap();
+        let digest = index["manifests"][0]["digest"].as_str().unwrap();
+        let manifest: serde_json::Value =
+            serde_json::from_slice(&files[&format!("blobs/sha256/{}", &digest["sha256:".len()..])])
+                .unwrap();
+        assert_eq!(manifest["layers"].as_array().unwrap().len(), 2);
+
+        let digest = manifest["config"]["digest"].as_str().unwrap();
+        let config: serde_json::Value =
+            serde_json::from_slice(&files[&format!("blobs/sha256/{}", &digest["sha256:".len()..])])
+                .unwrap();
+        assert_eq!(
+            config["config"]["Entrypoint"][0],
+            format!("{}/out/bin/tool", store.logical(&hash).display())
+        );
+        assert_eq!(
+            config["config"]["Env"][0],
+            format!("PATH={}/out/bin", store.logical(&dependency).display())
+        );
+
+        // Layers are reproducible.
+        let mut again = Vec::new();
+        export(&store, &hash, &mut again).unwrap();
+        assert_eq!(image, again);
+    }
+
+    #[test]
+    fn import_exported() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let store = Store::new(&StoreConfig {
+            path: root.to_path_buf(),
+            prefix: "/porkg/store".into(),
+        });
+
+        let mut info = PackageInfo::new("app");
+        info.executable = Some(Executable {
+            exec: vec!["${out}/out/bin/tool".into()],
+            env: BTreeMap::new(),
+        });
+        let hash = add_package(&store, &info, 3);
+
+        let mut image = Vec::new();
+        export(&store, &hash, &mut image).unwrap();
+
+        let imported = import(&store, "image", &image[..]).unwrap();
+        let rootfs = store.by_hash(&imported).join(ROOTFS);
+        let tool = image_path(&store.logical(&hash)).join("out/bin/tool");
+        assert_eq!(fs::read(rootfs.join(tool)).unwrap(), b"app");
+
+        let info = store.info(&imported).unwrap();
+        assert_eq!(info.name, "image");
+        assert_eq!(
+            info.executable.unwrap().exec,
+            vec![format!("{}/out/bin/tool", store.logical(&hash).display())]
+        );
+
+        // Importing the same image again results in the same package.
+        assert_eq!(import(&store, "image", &image[..]).unwrap(), imported);
+
+        let mut corrupt = image.clone();
+        let len = corrupt.len();
+        corrupt[len / 2] ^= 0xff;
+        assert!(import(&store, "image", &corrupt[..]).is_err());
+    }
+}
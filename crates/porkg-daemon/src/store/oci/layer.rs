@@ -0,0 +1,136 @@
+//! Applies image layers to a root file system.
+//!
+//! Layers are applied in two passes: whiteouts first, so that they only ever affect content from lower layers, and
+//! then everything else. All paths are resolved as if the root file system were `/`, so that symlinks from earlier
+//! layers can't be used to write outside of it.
+
+use std::{fs, io::Read, path::Path};
+
+use super::OciError;
+use crate::archive::{ExtractOptions, Extractor};
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const WHITEOUT_OPAQUE: &str = ".wh..wh..opq";
+
+fn apply_whiteouts(extractor: &Extractor, layer: impl Read) -> Result<(), OciError> {
+    for entry in tar::Archive::new(layer).entries()? {
+        let entry = entry?;
+        let Some(path) = extractor.entry_path(&entry.path()?)? else {
+            continue;
+        };
+        let (Some(name), Some(parent)) = (path.file_name(), path.parent()) else {
+            continue;
+        };
+
+        let name = name.to_string_lossy();
+        if name == WHITEOUT_OPAQUE {
+            let directory = extractor.resolve(parent)?;
+            if directory.is_dir() {
+                for child in fs::read_dir(directory)? {
+                    extractor.remove(&parent.join(child?.file_name()))?;
+                }
+            }
+        } else if let Some(name) = name.strip_prefix(WHITEOUT_PREFIX) {
+            extractor.remove(&parent.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+fn apply_entries(extractor: &Extractor, layer: impl Read) -> Result<(), OciError> {
+    for entry in tar::Archive::new(layer).entries()? {
+        let mut entry = entry?;
+        let is_whiteout = entry
+            .path()?
+            .file_name()
+            .is_some_and(|v| v.to_string_lossy().starts_with(WHITEOUT_PREFIX));
+        if !is_whiteout {
+            extractor.tar_entry(&mut entry)?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies a layer on top of `root`. The layer is opened twice, once for each pass.
+pub fn apply<R: Read>(
+    root: &Path,
+    mut open: impl FnMut() -> Result<R, OciError>,
+) -> Result<(), OciError> {
+    // Root file systems commonly contain absolute symlinks, which are resolved against the root when extracting.
+    let extractor = Extractor::new(
+        root,
+        ExtractOptions {
+            allow_external_links: true,
+            ..Default::default()
+        },
+    );
+    apply_whiteouts(&extractor, open()?)?;
+    apply_entries(&extractor, open()?)
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::PermissionsExt as _;
+
+    use super::*;
+
+    fn layer(build: impl FnOnce(&mut tar::Builder<&mut Vec<u8>>)) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut builder = tar::Builder::new(&mut data);
+        build(&mut builder);
+        builder.finish().unwrap();
+        drop(builder);
+        data
+    }
+
+    fn file(builder: &mut tar::Builder<&mut Vec<u8>>, path: &str, data: &[u8]) {
+        let mut header = super::super::header(tar::EntryType::Regular, 0o4755, data.len() as u64);
+        builder.append_data(&mut header, path, data).unwrap();
+    }
+
+    fn link(builder: &mut tar::Builder<&mut Vec<u8>>, path: &str, target: &str) {
+        let mut header = super::super::header(tar::EntryType::Symlink, 0o777, 0);
+        builder.append_link(&mut header, path, target).unwrap();
+    }
+
+    #[test]
+    fn apply_layers() {
+        let temp = tempfile::tempdir().unwrap();
+        let base = temp.path();
+        let root = base.join("rootfs");
+        fs::create_dir_all(&root).unwrap();
+
+        let lower = layer(|b| {
+            file(b, "etc/a", b"a");
+            file(b, "etc/b", b"b");
+            file(b, "opaque/c", b"c");
+            file(b, "usr/bin/sh", b"sh");
+            link(b, "bin", "/usr/bin");
+            link(b, "escape", "../../..");
+        });
+        let upper = layer(|b| {
+            file(b, "etc/.wh.a", b"");
+            file(b, "opaque/.wh..wh..opq", b"");
+            file(b, "opaque/d", b"d");
+            file(b, "bin/evil", b"evil");
+            file(b, "escape/x", b"x");
+        });
+
+        apply(&root, || Ok(&lower[..])).unwrap();
+        apply(&root, || Ok(&upper[..])).unwrap();
+
+        assert!(!root.join("etc/a").exists());
+        assert_eq!(fs::read(root.join("etc/b")).unwrap(), b"b");
+        assert!(!root.join("opaque/c").exists());
+        assert_eq!(fs::read(root.join("opaque/d")).unwrap(), b"d");
+        assert_eq!(fs::read(root.join("usr/bin/evil")).unwrap(), b"evil");
+        assert_eq!(fs::read(root.join("x")).unwrap(), b"x");
+        assert!(!base.join("x").exists());
+
+        let mode = fs::metadata(root.join("usr/bin/sh"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o555);
+    }
+}
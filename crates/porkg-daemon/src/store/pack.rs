@@ -0,0 +1,273 @@
+//! Parallel compression and decompression of package archives.
+//!
+//! The [`Encoder`] cuts its input into blocks that workers compress as independent zstd frames, which are written in
+//! order. Concatenated frames are a valid zstd stream, so any zstd decoder can read the result. The [`Decoder`]
+//! decompresses on its own thread, so that decompression overlaps with extracting and hashing the archive.
+//!
+//! The throughput of each number of workers is measured by the `archive` benchmark of `porkg-bench`.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    mem,
+    thread::{self, JoinHandle},
+};
+
+/// The size of the blocks that are compressed independently.
+const BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// The size of the buffers that the decoder hands to the reader.
+const DECODE_BUFFER_SIZE: usize = 256 * 1024;
+
+/// How archives are compressed.
+#[derive(Debug, Clone, Copy)]
+pub struct Compression {
+    /// The zstd compression level.
+    pub level: i32,
+    /// The number of threads that compress blocks.
+    pub workers: usize,
+}
+
+fn disconnected() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "a compression worker exited")
+}
+
+/// Compresses into a writer with a pool of workers.
+pub struct Encoder<W: Write> {
+    writer: W,
+    block: Vec<u8>,
+    jobs: Option<flume::Sender<(u64, Vec<u8>)>>,
+    results: flume::Receiver<(u64, io::Result<Vec<u8>>)>,
+    workers: Vec<JoinHandle<()>>,
+    /// Compressed blocks that are waiting for the blocks before them.
+    pending: BTreeMap<u64, Vec<u8>>,
+    /// The number of blocks that have been sent to the workers.
+    sent: u64,
+    /// The number of blocks that have been written.
+    written: u64,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(writer: W, compression: Compression) -> io::Result<Self> {
+        let count = compression.workers.max(1);
+        let (jobs, receiver) = flume::bounded::<(u64, Vec<u8>)>(count);
+        let (sender, results) = flume::unbounded();
+        let workers = (0..count)
+            .map(|_| {
+                let receiver = receiver.clone();
+                let sender = sender.clone();
+                thread::Builder::new()
+                    .name("porkg-compress".to_string())
+                    .spawn(move || {
+                        for (index, block) in receiver.iter() {
+                            let result = zstd::bulk::compress(&block, compression.level);
+                            if sender.send((index, result)).is_err() {
+                                break;
+                            }
+                        }
+                    })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Ok(Self {
+            writer,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            jobs: Some(jobs),
+            results,
+            workers,
+            pending: BTreeMap::new(),
+            sent: 0,
+            written: 0,
+        })
+    }
+
+    /// Writes the compressed blocks that are ready, in order, after waiting for one more result if `wait` is set.
+    fn collect(&mut self, wait: bool) -> io::Result<()> {
+        if wait {
+            let (index, result) = self.results.recv().map_err(|_| disconnected())?;
+            self.pending.insert(index, result?);
+        }
+        while let Ok((index, result)) = self.results.try_recv() {
+            self.pending.insert(index, result?);
+        }
+        while let Some(block) = self.pending.remove(&self.written) {
+            self.writer.write_all(&block)?;
+            self.written += 1;
+        }
+        Ok(())
+    }
+
+    fn dispatch(&mut self) -> io::Result<()> {
+        // Limits the blocks that are held in memory to two for each worker.
+        while self.sent - self.written >= 2 * self.workers.len() as u64 {
+            self.collect(true)?;
+        }
+        let block = mem::replace(&mut self.block, Vec::with_capacity(BLOCK_SIZE));
+        let jobs = self.jobs.as_ref().ok_or_else(disconnected)?;
+        jobs.send((self.sent, block)).map_err(|_| disconnected())?;
+        self.sent += 1;
+        self.collect(false)
+    }
+
+    /// Compresses the remaining input and waits for the workers, returning the writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        // An empty input is still written as a frame, so that the output is a valid zstd stream.
+        if !self.block.is_empty() || self.sent == 0 {
+            self.dispatch()?;
+        }
+        self.jobs = None;
+        while self.written < self.sent {
+            self.collect(true)?;
+        }
+        for worker in self.workers.drain(..) {
+            worker.join().map_err(|_| disconnected())?;
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let size = buf.len().min(BLOCK_SIZE - self.block.len());
+        self.block.extend_from_slice(&buf[..size]);
+        if self.block.len() == BLOCK_SIZE {
+            self.dispatch()?;
+        }
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.collect(false)?;
+        self.writer.flush()
+    }
+}
+
+/// Decompresses a reader on another thread.
+pub struct Decoder {
+    receiver: flume::Receiver<io::Result<Vec<u8>>>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl Decoder {
+    pub fn new(reader: impl Read + Send + 'static) -> io::Result<Self> {
+        let (sender, receiver) = flume::bounded(4);
+        thread::Builder::new()
+            .name("porkg-decompress".to_string())
+            .spawn(move || {
+                let mut decoder = match zstd::Decoder::new(reader) {
+                    Ok(decoder) => decoder,
+                    Err(error) => {
+                        sender.send(Err(error)).ok();
+                        return;
+                    }
+                };
+                loop {
+                    let mut buffer = vec![0; DECODE_BUFFER_SIZE];
+                    let result = match decoder.read(&mut buffer) {
+                        Ok(0) => return,
+                        Ok(size) => {
+                            buffer.truncate(size);
+                            Ok(buffer)
+                        }
+                        Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(error) => Err(error),
+                    };
+                    let failed = result.is_err();
+                    // The reader stops the thread by dropping the decoder.
+                    if sender.send(result).is_err() || failed {
+                        return;
+                    }
+                }
+            })?;
+
+        Ok(Self {
+            receiver,
+            buffer: Vec::new(),
+            position: 0,
+        })
+    }
+}
+
+impl Read for Decoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position == self.buffer.len() {
+            match self.receiver.recv() {
+                Ok(result) => {
+                    self.buffer = result?;
+                    self.position = 0;
+                }
+                // The thread has reached the end of the input.
+                Err(_) => return Ok(0),
+            }
+        }
+        let size = buf.len().min(self.buffer.len() - self.position);
+        buf[..size].copy_from_slice(&self.buffer[self.position..self.position + size]);
+        self.position += size;
+        Ok(size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Generates data that compresses moderately, like most package content.
+    fn sample(size: usize) -> Vec<u8> {
+        let mut state = 1u64;
+        (0..size)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                if i % 3 == 0 {
+                    state as u8
+                } else {
+                    b'a' + (i % 26) as u8
+                }
+            })
+            .collect()
+    }
+
+    fn compress(data: &[u8], workers: usize) -> Vec<u8> {
+        let mut encoder = Encoder::new(Vec::new(), Compression { level: 3, workers }).unwrap();
+        // Writes in uneven pieces, so that blocks span writes.
+        for piece in data.chunks(1_000_003) {
+            encoder.write_all(piece).unwrap();
+        }
+        encoder.finish().unwrap()
+    }
+
+    fn decompress(data: Vec<u8>) -> Vec<u8> {
+        let mut output = Vec::new();
+        Decoder::new(io::Cursor::new(data))
+            .unwrap()
+            .read_to_end(&mut output)
+            .unwrap();
+        output
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = sample(3 * BLOCK_SIZE + 12345);
+        let compressed = compress(&data, 3);
+        assert!(compressed.len() < data.len());
+        // The blocks are written in order, whichever worker finishes first.
+        assert_eq!(compressed, compress(&data, 1));
+        assert_eq!(zstd::decode_all(&compressed[..]).unwrap(), data);
+        assert_eq!(decompress(compressed), data);
+
+        let empty = compress(&[], 2);
+        assert!(!empty.is_empty());
+        assert!(decompress(empty).is_empty());
+
+        let mut corrupt = compress(&data, 2);
+        corrupt.truncate(corrupt.len() / 2);
+        let mut output = Vec::new();
+        assert!(Decoder::new(io::Cursor::new(corrupt))
+            .unwrap()
+            .read_to_end(&mut output)
+            .is_err());
+    }
+}
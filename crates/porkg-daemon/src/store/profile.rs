@@ -0,0 +1,250 @@
+//! The differences between the closures of profile generations (see [`porkg_model::profile`]).
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+};
+
+use porkg_model::{
+    audit::split_name,
+    channel,
+    hashing::SupportedHash,
+    profile::{Changed, ClosureDiff, DiffPackage},
+    store::PackageInfo,
+};
+use thiserror::Error;
+
+use super::{gc, Store, StoreError};
+
+#[derive(Debug, Error)]
+pub enum ProfileError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error("{0:?} is not a valid profile name")]
+    InvalidName(String),
+    #[error("the profile {profile} has no generation {generation}")]
+    GenerationNotFound { profile: String, generation: u64 },
+}
+
+/// Gets the closure of a generation, with every package after everything that it references.
+///
+/// This performs blocking IO.
+fn closure(
+    store: &Store,
+    profile: &str,
+    generation: u64,
+) -> Result<Vec<(SupportedHash, PackageInfo)>, ProfileError> {
+    let roots = gc::generation(store, profile, generation)?.ok_or_else(|| {
+        ProfileError::GenerationNotFound {
+            profile: profile.to_string(),
+            generation,
+        }
+    })?;
+    let mut visited = BTreeSet::new();
+    let mut result = Vec::new();
+    for root in roots {
+        for (hash, info) in store.closure(&root)? {
+            if visited.insert(hash) {
+                result.push((hash, info));
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Compares the closure of the generation `to` of a profile with that of the generation `from`.
+///
+/// This performs blocking IO.
+pub fn diff(store: &Store, profile: &str, from: u64, to: u64) -> Result<ClosureDiff, ProfileError> {
+    if !channel::is_valid_name(profile) {
+        return Err(ProfileError::InvalidName(profile.to_string()));
+    }
+    let before = closure(store, profile, from)?;
+    let after = closure(store, profile, to)?;
+    Ok(diff_closures(store, &before, &after)?)
+}
+
+fn diff_closures(
+    store: &Store,
+    before: &[(SupportedHash, PackageInfo)],
+    after: &[(SupportedHash, PackageInfo)],
+) -> io::Result<ClosureDiff> {
+    let before: BTreeMap<_, _> = before.iter().map(|(k, v)| (k, v)).collect();
+    let after: BTreeMap<_, _> = after.iter().map(|(k, v)| (k, v)).collect();
+    let package = |hash: &SupportedHash, info: &PackageInfo| -> io::Result<DiffPackage> {
+        Ok(DiffPackage {
+            hash: hash.to_string(),
+            name: info.name.clone(),
+            path: store.logical(hash).display().to_string(),
+            size: gc::disk_usage(&store.by_hash(hash))?,
+        })
+    };
+    let mut result = ClosureDiff::default();
+    let mut removed = BTreeMap::<&str, Vec<DiffPackage>>::new();
+    for (&hash, &info) in before.iter() {
+        let package = package(hash, info)?;
+        result.size_from += package.size;
+        if !after.contains_key(hash) {
+            removed
+                .entry(split_name(&info.name).0)
+                .or_default()
+                .push(package);
+        }
+    }
+    let mut added = BTreeMap::<&str, Vec<DiffPackage>>::new();
+    for (&hash, &info) in after.iter() {
+        let package = package(hash, info)?;
+        result.size_to += package.size;
+        if !before.contains_key(hash) {
+            added
+                .entry(split_name(&info.name).0)
+                .or_default()
+                .push(package);
+        }
+    }
+
+    // A package only changed if there is one package of its name on each side, as which replaced which is ambiguous
+    // otherwise.
+    let names: BTreeSet<_> = added.keys().chain(removed.keys()).copied().collect();
+    for name in names {
+        let mut from = removed.remove(name).unwrap_or_default();
+        let mut to = added.remove(name).unwrap_or_default();
+        if from.len() == 1 && to.len() == 1 {
+            result.changed.push(Changed {
+                from: from.remove(0),
+                to: to.remove(0),
+            });
+        } else {
+            result.removed.append(&mut from);
+            result.added.append(&mut to);
+        }
+    }
+    Ok(result)
+}
+
+/// What switching a profile to a generation changes.
+#[derive(Debug)]
+pub struct Switch {
+    /// The generation that the profile is switched to before, if any.
+    pub previous: Option<u64>,
+    pub diff: ClosureDiff,
+    /// The closure of the generation, with every package after everything that it references.
+    pub closure: Vec<(SupportedHash, PackageInfo)>,
+}
+
+/// Prepares switching `profile` to `generation`, without switching it yet.
+///
+/// This performs blocking IO.
+pub fn prepare_switch(
+    store: &Store,
+    profile: &str,
+    generation: u64,
+) -> Result<Switch, ProfileError> {
+    if !channel::is_valid_name(profile) {
+        return Err(ProfileError::InvalidName(profile.to_string()));
+    }
+    let previous = gc::current(store, profile)?;
+    let before = match previous {
+        Some(previous) => closure(store, profile, previous)?,
+        None => Vec::new(),
+    };
+    let after = closure(store, profile, generation)?;
+    Ok(Switch {
+        previous,
+        diff: diff_closures(store, &before, &after)?,
+        closure: after,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, os::unix::fs::symlink};
+
+    use super::*;
+    use crate::store::testing::{add_package, TestStore};
+
+    #[test]
+    fn diff_generations() {
+        let store = TestStore::new("profile");
+        let libc = add_package(&store, "libc-2.39", &[], 1);
+        let old_zlib = add_package(&store, "zlib-1.3", &[], 2);
+        let new_zlib = add_package(&store, "zlib-1.3.1", &[], 3);
+        let old_app = add_package(&store, "app", &[libc, old_zlib], 4);
+        let new_app = add_package(&store, "app", &[libc, new_zlib], 5);
+        let tool = add_package(&store, "tool", &[libc], 6);
+
+        let profile = store.root().join("profile/default");
+        fs::create_dir_all(&profile).unwrap();
+        symlink(store.by_hash(&old_app), profile.join("1")).unwrap();
+        let links = store.root().join("link/generation-2");
+        fs::create_dir_all(&links).unwrap();
+        symlink(store.by_hash(&new_app), links.join("app")).unwrap();
+        symlink(store.by_hash(&tool), links.join("tool")).unwrap();
+        symlink(&links, profile.join("2")).unwrap();
+
+        let diff = diff(&store, "default", 1, 2).unwrap();
+        let hashes =
+            |packages: &[DiffPackage]| packages.iter().map(|v| v.hash.clone()).collect::<Vec<_>>();
+        assert_eq!(hashes(&diff.added), [tool.to_string()]);
+        assert!(diff.removed.is_empty());
+        assert_eq!(
+            diff.changed
+                .iter()
+                .map(|v| (v.from.name.as_str(), v.to.name.as_str()))
+                .collect::<Vec<_>>(),
+            [("app", "app"), ("zlib-1.3", "zlib-1.3.1")]
+        );
+        let zlib = &diff.changed[1];
+        assert_eq!(zlib.to.path, store.logical(&new_zlib).display().to_string());
+        assert!(zlib.to.size > 0);
+        assert!(diff.size_to > diff.size_from);
+
+        assert!(matches!(
+            super::diff(&store, "default", 1, 3),
+            Err(ProfileError::GenerationNotFound { generation: 3, .. })
+        ));
+        assert!(matches!(
+            super::diff(&store, "../default", 1, 2),
+            Err(ProfileError::InvalidName(_))
+        ));
+    }
+
+    #[test]
+    fn prepare_switches() {
+        let store = TestStore::new("profile-switch");
+        let libc = add_package(&store, "libc-2.39", &[], 1);
+        let app = add_package(&store, "app-1.0", &[libc], 2);
+        let new_app = add_package(&store, "app-1.1", &[libc], 3);
+
+        let profile = store.root().join("profile/default");
+        fs::create_dir_all(&profile).unwrap();
+        symlink(store.by_hash(&app), profile.join("1")).unwrap();
+        symlink(store.by_hash(&new_app), profile.join("2")).unwrap();
+
+        // Without a current generation, everything is added.
+        let switch = prepare_switch(&store, "default", 1).unwrap();
+        assert_eq!(switch.previous, None);
+        assert_eq!(switch.diff.added.len(), 2);
+        assert_eq!(
+            switch.closure.iter().map(|(v, _)| *v).collect::<Vec<_>>(),
+            [libc, app]
+        );
+
+        gc::set_current(&store, "default", Some(1)).unwrap();
+        let switch = prepare_switch(&store, "default", 2).unwrap();
+        assert_eq!(switch.previous, Some(1));
+        assert!(switch.diff.added.is_empty());
+        assert_eq!(switch.diff.changed.len(), 1);
+        // Preparing doesn't switch the profile.
+        assert_eq!(gc::current(&store, "default").unwrap(), Some(1));
+
+        gc::set_current(&store, "default", None).unwrap();
+        assert_eq!(gc::current(&store, "default").unwrap(), None);
+        assert!(matches!(
+            prepare_switch(&store, "default", 3),
+            Err(ProfileError::GenerationNotFound { generation: 3, .. })
+        ));
+    }
+}
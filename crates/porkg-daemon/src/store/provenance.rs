@@ -0,0 +1,276 @@
+//! Impurity reports, which record what a package might depend on besides its declared inputs.
+
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    os::unix::fs::PermissionsExt as _,
+    path::{Path, PathBuf},
+};
+
+use porkg_model::{
+    hashing::SupportedHash,
+    provenance::{Impurity, NonNormalized, Provenance},
+};
+use porkg_private::sandbox::{SandboxFlags, SandboxOptions};
+
+use super::{Store, StoreError};
+
+/// What a build could observe besides its declared inputs.
+#[derive(Debug, Default)]
+pub struct Environment {
+    pub network: bool,
+    /// Paths of the host that were bind-mounted into the build.
+    pub host_paths: Vec<PathBuf>,
+    /// Environment variables of the host that were passed into the build without being hashed.
+    pub host_env: BTreeSet<String>,
+    /// The files that the build read, if reads were tracked.
+    pub reads: Option<BTreeSet<PathBuf>>,
+    /// The store directories of the declared inputs.
+    pub inputs: Vec<PathBuf>,
+}
+
+impl Environment {
+    pub fn new(options: &SandboxOptions, inputs: Vec<PathBuf>) -> Self {
+        Self {
+            network: !options.flags().contains(SandboxFlags::NETWORK_ISOLATION),
+            inputs,
+            ..Default::default()
+        }
+    }
+
+    fn is_input(&self, path: &Path) -> bool {
+        self.inputs.iter().any(|v| path.starts_with(v))
+    }
+
+    /// Gets the impurities that follow from the environment alone, which are known before the build runs.
+    pub fn impurities(&self) -> BTreeSet<Impurity> {
+        let mut impurities = BTreeSet::new();
+        if self.network {
+            impurities.insert(Impurity::Network);
+        }
+        for path in self.host_paths.iter() {
+            impurities.insert(Impurity::HostPath {
+                path: path.display().to_string(),
+            });
+        }
+        for name in self.host_env.iter() {
+            impurities.insert(Impurity::HostEnv { name: name.clone() });
+        }
+        for path in self.reads.iter().flatten() {
+            if !self.is_input(path) {
+                impurities.insert(Impurity::UndeclaredRead {
+                    path: path.display().to_string(),
+                });
+            }
+        }
+        impurities
+    }
+}
+
+/// Reports the impurities of an output that was produced in `environment`.
+///
+/// This performs blocking IO.
+pub fn report(environment: &Environment, output: &Path) -> io::Result<Provenance> {
+    let mut impurities = environment.impurities();
+    inspect(environment, output, output, &mut impurities)?;
+    Ok(Provenance {
+        impurities,
+        reads_tracked: environment.reads.is_some(),
+        overrides: BTreeSet::new(),
+        channel: None,
+        source_date_epoch: None,
+    })
+}
+
+fn build_path(store: &Store, hash: &SupportedHash, build: u64) -> PathBuf {
+    store.builds_path(hash).join(format!("{build}.json"))
+}
+
+/// Records the provenance of a build of a package, such as the overrides that it was built with. Each build gets a file
+/// of its own, which is written in full before it is renamed into place, so builds of the same package neither see nor
+/// change each other's provenance.
+///
+/// This performs blocking IO.
+pub fn record(
+    store: &Store,
+    hash: &SupportedHash,
+    build: u64,
+    provenance: &Provenance,
+) -> Result<(), StoreError> {
+    let temporary = store.temp_path("provenance");
+    if let Some(parent) = temporary.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&temporary, serde_json::to_vec_pretty(provenance)?)?;
+    let path = build_path(store, hash, build);
+    let renamed =
+        fs::create_dir_all(store.builds_path(hash)).and_then(|_| fs::rename(&temporary, &path));
+    if renamed.is_err() {
+        fs::remove_file(&temporary).ok();
+    }
+    Ok(renamed?)
+}
+
+/// Reads the provenance that was recorded for a build of a package, if any.
+///
+/// This performs blocking IO.
+pub fn read(
+    store: &Store,
+    hash: &SupportedHash,
+    build: u64,
+) -> Result<Option<Provenance>, StoreError> {
+    match fs::read(build_path(store, hash, build)) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error.into()),
+    }
+}
+
+fn inspect(
+    environment: &Environment,
+    root: &Path,
+    dir: &Path,
+    impurities: &mut BTreeSet<Impurity>,
+) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let metadata = fs::symlink_metadata(&path)?;
+        let kind = metadata.file_type();
+        let mode = metadata.permissions().mode();
+
+        let reason = if kind.is_symlink() {
+            let target = fs::read_link(&path)?;
+            (target.is_absolute() && !environment.is_input(&target))
+                .then_some(NonNormalized::HostLink)
+        } else if !kind.is_dir() && !kind.is_file() {
+            Some(NonNormalized::Special)
+        } else if mode & 0o6000 != 0 {
+            Some(NonNormalized::SetId)
+        } else if mode & 0o022 != 0 {
+            Some(NonNormalized::Writable)
+        } else {
+            None
+        };
+
+        if let Some(reason) = reason {
+            impurities.insert(Impurity::NonNormalized {
+                path: path
+                    .strip_prefix(root)
+                    .unwrap_or(&path)
+                    .display()
+                    .to_string(),
+                reason,
+            });
+        }
+        if kind.is_dir() {
+            inspect(environment, root, &path, impurities)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::fs::symlink;
+
+    use porkg_model::provenance::Override;
+
+    use crate::store::{testing::TestStore, INFO_FILE};
+
+    use super::*;
+
+    #[test]
+    fn record_builds() {
+        let store = TestStore::new("provenance");
+        let hash = store.add("hello", &[]);
+        let info = fs::read(store.by_hash(&hash).join(INFO_FILE)).unwrap();
+
+        let first = Provenance {
+            overrides: [Override::Option {
+                name: "WITH_HTTP3".into(),
+                value: "1".into(),
+            }]
+            .into(),
+            source_date_epoch: Some(1),
+            ..Default::default()
+        };
+        record(&store, &hash, 1, &first).unwrap();
+        let second = Provenance {
+            impurities: [Impurity::Network].into(),
+            ..Default::default()
+        };
+        record(&store, &hash, 2, &second).unwrap();
+
+        // Builds are recorded apart from each other, and from the metadata of the package.
+        assert_eq!(read(&store, &hash, 1).unwrap(), Some(first));
+        assert_eq!(read(&store, &hash, 2).unwrap(), Some(second.clone()));
+        assert_eq!(read(&store, &hash, 3).unwrap(), None);
+        assert_eq!(
+            fs::read(store.by_hash(&hash).join(INFO_FILE)).unwrap(),
+            info
+        );
+
+        record(&store, &hash, 1, &second).unwrap();
+        assert_eq!(read(&store, &hash, 1).unwrap(), Some(second));
+        assert_eq!(fs::read_dir(store.builds_path(&hash)).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn report_impurities() {
+        let temp = tempfile::tempdir().unwrap();
+        let base = temp.path();
+        let input = base.join("input");
+        let output = base.join("output");
+        fs::create_dir_all(output.join("bin")).unwrap();
+        fs::write(output.join("bin/tool"), "").unwrap();
+        fs::set_permissions(output.join("bin/tool"), fs::Permissions::from_mode(0o4755)).unwrap();
+        fs::write(output.join("data"), "").unwrap();
+        fs::set_permissions(output.join("data"), fs::Permissions::from_mode(0o666)).unwrap();
+        symlink("/etc/passwd", output.join("passwd")).unwrap();
+        symlink(input.join("lib"), output.join("lib")).unwrap();
+        symlink("bin/tool", output.join("tool")).unwrap();
+
+        let mut options = SandboxOptions::default();
+        options.with_network_isolation(true);
+        let mut environment = Environment::new(&options, vec![input.clone()]);
+        environment.reads = Some([input.join("lib/libc.so"), "/etc/hosts".into()].into());
+
+        let provenance = report(&environment, &output).unwrap();
+        assert!(provenance.reads_tracked);
+        assert_eq!(
+            provenance.impurities,
+            [
+                Impurity::UndeclaredRead {
+                    path: "/etc/hosts".into()
+                },
+                Impurity::NonNormalized {
+                    path: "bin/tool".into(),
+                    reason: NonNormalized::SetId
+                },
+                Impurity::NonNormalized {
+                    path: "data".into(),
+                    reason: NonNormalized::Writable
+                },
+                Impurity::NonNormalized {
+                    path: "passwd".into(),
+                    reason: NonNormalized::HostLink
+                },
+            ]
+            .into()
+        );
+
+        options.with_network_isolation(false);
+        let mut environment = Environment::new(&options, Vec::new());
+        environment.host_env = ["http_proxy".to_string()].into();
+        assert_eq!(
+            environment.impurities(),
+            [
+                Impurity::Network,
+                Impurity::HostEnv {
+                    name: "http_proxy".into()
+                }
+            ]
+            .into()
+        );
+    }
+}
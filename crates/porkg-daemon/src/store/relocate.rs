@@ -0,0 +1,175 @@
+//! Rewriting of the placeholder that builds use for their own install prefix (see
+//! [`placeholder`](porkg_model::store::placeholder)).
+//!
+//! The placeholder and the final path have the same length, so occurrences are overwritten in place: offsets within
+//! binaries stay valid, and the files don't need to be copied.
+
+use std::{
+    ffi::OsString,
+    fs::{self, File, OpenOptions},
+    io,
+    os::unix::{
+        ffi::{OsStrExt as _, OsStringExt as _},
+        fs::{FileExt as _, PermissionsExt as _},
+    },
+    path::{Path, PathBuf},
+};
+
+/// The size of the chunks that files are searched in.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Replaces every occurrence of `from` in the regular files and symlink targets of a file or directory tree with `to`,
+/// returning the number of occurrences. Files that the build made read-only are made writable while they are rewritten.
+pub fn rewrite(path: &Path, from: &Path, to: &Path) -> io::Result<u64> {
+    let (from, to) = (from.as_os_str().as_bytes(), to.as_os_str().as_bytes());
+    if from.len() != to.len() || from.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the placeholder and the path that replaces it must have the same length",
+        ));
+    }
+    rewrite_tree(path, from, to)
+}
+
+fn rewrite_tree(path: &Path, from: &[u8], to: &[u8]) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        let mut count = 0;
+        for entry in fs::read_dir(path)? {
+            count += rewrite_tree(&entry?.path(), from, to)?;
+        }
+        Ok(count)
+    } else if metadata.is_symlink() {
+        let mut target = fs::read_link(path)?.into_os_string().into_vec();
+        let count = replace(&mut target, from, to);
+        if count != 0 {
+            fs::remove_file(path)?;
+            std::os::unix::fs::symlink(PathBuf::from(OsString::from_vec(target)), path)?;
+        }
+        Ok(count)
+    } else if metadata.is_file() {
+        rewrite_file(&open_writable(path, &metadata)?, from, to)
+    } else {
+        Ok(0)
+    }
+}
+
+/// Opens a file for reading and writing, even if the build made it read-only.
+pub(super) fn open_writable(path: &Path, metadata: &fs::Metadata) -> io::Result<File> {
+    match OpenOptions::new().read(true).write(true).open(path) {
+        Err(error) if error.kind() == io::ErrorKind::PermissionDenied => {
+            let mode = metadata.permissions().mode();
+            fs::set_permissions(path, fs::Permissions::from_mode(mode | 0o200))?;
+            let file = OpenOptions::new().read(true).write(true).open(path);
+            fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+            file
+        }
+        other => other,
+    }
+}
+
+/// Rewrites a file in chunks, keeping the end of each chunk that could be the start of an occurrence.
+fn rewrite_file(file: &File, from: &[u8], to: &[u8]) -> io::Result<u64> {
+    let mut buffer = Vec::with_capacity(CHUNK_SIZE + from.len());
+    // The offset in the file of the start of the buffer.
+    let mut offset = 0u64;
+    let mut count = 0;
+    loop {
+        let kept = buffer.len();
+        buffer.resize(kept + CHUNK_SIZE, 0);
+        let read = file.read_at(&mut buffer[kept..], offset + kept as u64)?;
+        buffer.truncate(kept + read);
+
+        let mut start = 0;
+        while let Some(found) = find(&buffer[start..], from) {
+            let position = start + found;
+            file.write_all_at(to, offset + position as u64)?;
+            buffer[position..position + to.len()].copy_from_slice(to);
+            start = position + to.len();
+            count += 1;
+        }
+        if read == 0 {
+            return Ok(count);
+        }
+
+        let keep = (from.len() - 1).min(buffer.len() - start);
+        let consumed = buffer.len() - keep;
+        buffer.drain(..consumed);
+        offset += consumed as u64;
+    }
+}
+
+/// Replaces the occurrences of `from` in `data`, returning their number.
+fn replace(data: &mut [u8], from: &[u8], to: &[u8]) -> u64 {
+    let mut count = 0;
+    let mut start = 0;
+    while let Some(found) = find(&data[start..], from) {
+        let position = start + found;
+        data[position..position + to.len()].copy_from_slice(to);
+        start = position + to.len();
+        count += 1;
+    }
+    count
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|v| v == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rewrite_placeholder() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let out = root.join("out");
+        fs::create_dir_all(out.join("bin")).unwrap();
+        let (from, to) = (
+            Path::new("/store/tmp/selfref-1"),
+            Path::new("/store/pkg/by-hash/1"),
+        );
+
+        // An occurrence that straddles two chunks, and one at the very end.
+        let mut binary = vec![0u8; CHUNK_SIZE - 5];
+        binary.extend_from_slice(from.as_os_str().as_bytes());
+        binary.extend_from_slice(b"/lib\0");
+        binary.extend_from_slice(from.as_os_str().as_bytes());
+        fs::write(out.join("bin/tool"), &binary).unwrap();
+        fs::set_permissions(out.join("bin/tool"), fs::Permissions::from_mode(0o555)).unwrap();
+        fs::write(out.join("config"), "prefix=/store/tmp/selfref-1\n").unwrap();
+        std::os::unix::fs::symlink(from.join("bin/tool"), out.join("tool")).unwrap();
+        std::os::unix::fs::symlink("bin/tool", out.join("relative")).unwrap();
+
+        assert_eq!(rewrite(&out, from, to).unwrap(), 4);
+        let binary = fs::read(out.join("bin/tool")).unwrap();
+        assert_eq!(find(&binary, from.as_os_str().as_bytes()), None);
+        assert_eq!(
+            find(&binary, to.as_os_str().as_bytes()),
+            Some(CHUNK_SIZE - 5)
+        );
+        assert_eq!(
+            fs::metadata(out.join("bin/tool"))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777,
+            0o555
+        );
+        assert_eq!(
+            fs::read_to_string(out.join("config")).unwrap(),
+            "prefix=/store/pkg/by-hash/1\n"
+        );
+        assert_eq!(
+            fs::read_link(out.join("tool")).unwrap(),
+            to.join("bin/tool")
+        );
+        assert_eq!(
+            fs::read_link(out.join("relative")).unwrap(),
+            Path::new("bin/tool")
+        );
+
+        assert!(rewrite(&out, from, Path::new("/short")).is_err());
+    }
+}
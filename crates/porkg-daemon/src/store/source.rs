@@ -0,0 +1,140 @@
+//! Imports source archives into the store.
+//!
+//! Archives are extracted into the `src` directory of a package, which is stored under the hash of that directory
+//! (see [`nar::hash`]). Fetchers place the archives that they download here.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use porkg_model::{hashing::SupportedHash, store::PackageInfo};
+use thiserror::Error;
+
+use super::{
+    nar,
+    provenance::{self, Environment},
+    Store, StoreError,
+};
+use crate::archive::{self, ArchiveError, ExtractOptions, Format};
+
+const SOURCE: &str = "src";
+
+#[derive(Debug, Error)]
+pub enum SourceError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Archive(#[from] ArchiveError),
+}
+
+/// Extracts the archive at `path` and imports it as the source of the package `name`. The format is detected if it
+/// isn't provided.
+///
+/// This performs blocking IO.
+#[tracing::instrument(skip(store, options))]
+pub fn import_archive(
+    store: &Store,
+    name: &str,
+    path: &Path,
+    format: Option<Format>,
+    options: ExtractOptions,
+) -> Result<SupportedHash, SourceError> {
+    let format = match format {
+        Some(format) => format,
+        None => Format::of(path)?,
+    };
+
+    let staging = store.temp_path("source");
+    let result = import_staged(store, name, path, format, options, &staging);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)
+            .inspect_err(|error| tracing::warn!(?error, ?staging, "failed to clean up staging"))
+            .ok();
+    }
+    result
+}
+
+fn import_staged(
+    store: &Store,
+    name: &str,
+    path: &Path,
+    format: Format,
+    options: ExtractOptions,
+    staging: &Path,
+) -> Result<SupportedHash, SourceError> {
+    let source_date_epoch = archive::extract(format, path, &source_path(staging), options)?;
+    import_staged_tree(store, name, staging, source_date_epoch)
+}
+
+/// Gets the location within `staging` where the source tree is placed before calling [`import_staged_tree`].
+pub fn source_path(staging: &Path) -> PathBuf {
+    staging.join(SOURCE)
+}
+
+/// Imports a source tree that was placed in `staging` as the package `name`, with the latest modification time of its
+/// files if it is known. The staging directory is consumed.
+///
+/// This performs blocking IO.
+pub fn import_staged_tree(
+    store: &Store,
+    name: &str,
+    staging: &Path,
+    source_date_epoch: Option<u64>,
+) -> Result<SupportedHash, SourceError> {
+    let hash = nar::hash(&source_path(staging))?;
+
+    let mut info = PackageInfo::new(name);
+    info.source_date_epoch = source_date_epoch;
+    info.provenance = Some(provenance::report(
+        &Environment::default(),
+        &source_path(staging),
+    )?);
+    Store::write_info(staging, &info)?;
+    if store.insert(staging, &hash, name)? {
+        tracing::info!(%hash, "imported source");
+    }
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::store::testing::TestStore;
+
+    use super::*;
+
+    #[test]
+    fn import_tarball() {
+        let store = TestStore::new("source");
+        let temp = tempfile::tempdir().unwrap();
+
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_mtime(1_700_000_000);
+        builder
+            .append_data(&mut header, "hello-1.0/hello", &b"hello"[..])
+            .unwrap();
+        let archive = temp.path().join("hello-1.0.tar");
+        fs::write(&archive, builder.into_inner().unwrap()).unwrap();
+
+        let options = ExtractOptions {
+            strip_components: 1,
+            ..Default::default()
+        };
+        let hash = import_archive(&store, "hello", &archive, None, options.clone()).unwrap();
+        let path = store.by_hash(&hash);
+        assert_eq!(fs::read(path.join("src/hello")).unwrap(), b"hello");
+        let info = store.info(&hash).unwrap();
+        assert_eq!(info.name, "hello");
+        assert_eq!(info.source_date_epoch, Some(1_700_000_000));
+        assert!(info.provenance.unwrap().is_pure());
+
+        // Importing the same content again results in the same package.
+        let again = import_archive(&store, "hello", &archive, Some(Format::Tar), options).unwrap();
+        assert_eq!(hash, again);
+    }
+}
@@ -0,0 +1,307 @@
+//! Transfers packages between stores, one package at a time.
+//!
+//! A package is transferred as a tar archive of its directory, including its metadata. Packages are imported after
+//! everything that they reference, so a store never contains a package without its closure.
+
+use std::{
+    collections::BTreeSet,
+    fs,
+    io::{self, Read, Seek as _, Write},
+    path::Path,
+};
+
+use porkg_model::{hashing::SupportedHash, store::PackageInfo};
+use thiserror::Error;
+
+use crate::{
+    archive::{ArchiveError, ExtractOptions, Extractor},
+    signing::{SignatureError, SigningKey, TrustedKeys},
+};
+
+use super::{gc::temp::TempRoot, nar, Store, StoreError, INFO_FILE};
+
+/// The directory that the hash of a package is computed from, when it has a source.
+const SOURCE: &str = "src";
+
+#[derive(Debug, Error)]
+pub enum SyncError {
+    #[error(transparent)]
+    IO(#[from] io::Error),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Archive(#[from] ArchiveError),
+    #[error("the archive of {0} doesn't contain the metadata of the package")]
+    MissingInfo(SupportedHash),
+    #[error("{hash} references {reference}, which is not in the store")]
+    MissingReference {
+        hash: SupportedHash,
+        reference: SupportedHash,
+    },
+    #[error("the source of {expected} hashes to {actual}")]
+    HashMismatch {
+        expected: SupportedHash,
+        actual: SupportedHash,
+    },
+    #[error("the chunk {0} doesn't match its hash")]
+    CorruptChunk(SupportedHash),
+    #[error("the signature of {hash} was rejected: {error}")]
+    Signature {
+        hash: SupportedHash,
+        error: SignatureError,
+    },
+}
+
+/// The signature that an imported package must carry, and the keys that may have made it.
+#[derive(Debug, Clone, Copy)]
+pub struct Verify<'a> {
+    pub trusted: &'a TrustedKeys,
+    pub signature: Option<&'a str>,
+}
+
+/// Finds the packages that are not in the store, keeping their order.
+pub fn missing(store: &Store, hashes: &[SupportedHash]) -> Vec<SupportedHash> {
+    let mut seen = BTreeSet::new();
+    hashes
+        .iter()
+        .filter(|v| seen.insert(**v) && !store.by_hash(v).exists())
+        .copied()
+        .collect()
+}
+
+/// Gets the combined closure of several packages, with each package after everything that it references.
+///
+/// This performs blocking IO.
+pub fn closure(store: &Store, roots: &[SupportedHash]) -> Result<Vec<SupportedHash>, StoreError> {
+    let mut seen = BTreeSet::new();
+    let mut result = Vec::new();
+    for root in roots {
+        for (hash, _) in store.closure(root)? {
+            if seen.insert(hash) {
+                result.push(hash);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Writes the directory of a package as a tar archive.
+///
+/// This performs blocking IO.
+pub fn export(store: &Store, hash: &SupportedHash, writer: impl Write) -> Result<(), SyncError> {
+    let _root = TempRoot::new(store, "sync-export", &[*hash])?;
+    let dir = store.by_hash(hash);
+    if !dir.is_dir() {
+        return Err(StoreError::NotFound(*hash).into());
+    }
+
+    let mut builder = tar::Builder::new(writer);
+    builder.follow_symlinks(false);
+    for entry in fs::read_dir(&dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            builder.append_dir_all(entry.file_name(), entry.path())?;
+        } else {
+            builder.append_path_with_name(entry.path(), entry.file_name())?;
+        }
+    }
+    builder.into_inner()?.flush()?;
+    Ok(())
+}
+
+/// Exports a package into a temporary file, which is unlinked and ready to be read from the start.
+///
+/// This performs blocking IO.
+pub fn export_file(store: &Store, hash: &SupportedHash) -> Result<fs::File, SyncError> {
+    spool(store, "sync-export", |file| export(store, hash, file))
+}
+
+/// Writes into a temporary file, which is unlinked and returned ready to be read from the start.
+///
+/// This performs blocking IO.
+pub(super) fn spool(
+    store: &Store,
+    prefix: &str,
+    write: impl FnOnce(&mut fs::File) -> Result<(), SyncError>,
+) -> Result<fs::File, SyncError> {
+    let path = store.temp_path(prefix);
+    let result = fs::create_dir_all(path.parent().unwrap_or(&path))
+        .and_then(|_| {
+            fs::File::options()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&path)
+        })
+        .map_err(SyncError::from)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.rewind()?;
+            Ok(file)
+        });
+    // The open file remains readable after it is unlinked.
+    fs::remove_file(&path).ok();
+    result
+}
+
+/// Signs a package, covering its directory as it is exported.
+///
+/// This performs blocking IO.
+pub fn sign(store: &Store, hash: &SupportedHash, key: &SigningKey) -> Result<String, SyncError> {
+    let content = nar::hash(&store.by_hash(hash))?;
+    Ok(key.sign(hash, &content))
+}
+
+/// Imports a package that was written by [`export`], returning `false` if it was already present. When `verify` is
+/// provided, the signature must cover the extracted package. Otherwise, only the source of a package is checked against
+/// its hash, so the content of packages without a source must come from a trusted peer.
+///
+/// This performs blocking IO.
+#[tracing::instrument(skip(store, reader, verify))]
+pub fn import(
+    store: &Store,
+    hash: &SupportedHash,
+    reader: impl Read,
+    verify: Option<Verify<'_>>,
+) -> Result<bool, SyncError> {
+    if store.by_hash(hash).exists() {
+        return Ok(false);
+    }
+
+    let staging = store.temp_path("sync");
+    let result = import_staged(store, hash, reader, verify, &staging);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)
+            .inspect_err(|error| tracing::warn!(?error, ?staging, "failed to clean up staging"))
+            .ok();
+    }
+    result
+}
+
+fn import_staged(
+    store: &Store,
+    hash: &SupportedHash,
+    reader: impl Read,
+    verify: Option<Verify<'_>>,
+    staging: &Path,
+) -> Result<bool, SyncError> {
+    fs::create_dir_all(staging)?;
+    // Root file systems contain absolute links, which are resolved against the package while extracting, so that nothing
+    // is written outside of staging. Whether the links may be kept is up to the signature, which covers them.
+    let extractor = Extractor::new(
+        staging,
+        ExtractOptions {
+            allow_external_links: true,
+            ..Default::default()
+        },
+    );
+    for entry in tar::Archive::new(reader).entries()? {
+        extractor.tar_entry(&mut entry?)?;
+    }
+
+    let data = match fs::read(staging.join(INFO_FILE)) {
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            return Err(SyncError::MissingInfo(*hash))
+        }
+        other => other?,
+    };
+    let info: PackageInfo = serde_json::from_slice(&data).map_err(StoreError::from)?;
+    for reference in info.references.iter().filter(|v| *v != hash) {
+        if !store.by_hash(reference).exists() {
+            return Err(SyncError::MissingReference {
+                hash: *hash,
+                reference: *reference,
+            });
+        }
+    }
+
+    let source = staging.join(SOURCE);
+    if source.is_dir() {
+        let actual = nar::hash(&source)?;
+        if actual != *hash {
+            return Err(SyncError::HashMismatch {
+                expected: *hash,
+                actual,
+            });
+        }
+    }
+
+    if let Some(verify) = verify {
+        let content = nar::hash(staging)?;
+        verify
+            .trusted
+            .verify(hash, &content, verify.signature)
+            .map_err(|error| SyncError::Signature { hash: *hash, error })?;
+    }
+    let inserted = store.insert(staging, hash, &info.name)?;
+    if inserted {
+        tracing::info!(name = info.name, "imported package from a peer");
+    }
+    Ok(inserted)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::store::testing::{add_package, TestStore};
+
+    use super::*;
+
+    #[test]
+    fn transfer_package() {
+        let from = TestStore::new("sync-from");
+        let to = TestStore::new("sync-to");
+
+        let libc = add_package(&from, "libc", &[], 1);
+        let zlib = add_package(&from, "zlib", &[libc], 2);
+        let source = from.by_hash(&zlib).join(SOURCE);
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("zlib.c"), "int main;").unwrap();
+        let source_hash = nar::hash(&source).unwrap();
+        fs::rename(from.by_hash(&zlib), from.by_hash(&source_hash)).unwrap();
+        let zlib = source_hash;
+
+        assert_eq!(closure(&from, &[zlib, libc]).unwrap(), [libc, zlib]);
+        assert_eq!(missing(&to, &[libc, zlib, libc]), [libc, zlib]);
+
+        let mut archive = Vec::new();
+        export(&from, &zlib, &mut archive).unwrap();
+        assert!(matches!(
+            import(&to, &zlib, &archive[..], None),
+            Err(SyncError::MissingReference { reference, .. }) if reference == libc
+        ));
+
+        let mut dependency = Vec::new();
+        export(&from, &libc, &mut dependency).unwrap();
+        assert!(import(&to, &libc, &dependency[..], None).unwrap());
+        assert!(matches!(
+            import(
+                &to,
+                &zlib,
+                &archive[..],
+                Some(Verify {
+                    trusted: &TrustedKeys::default(),
+                    signature: None
+                })
+            ),
+            Err(SyncError::Signature {
+                error: SignatureError::Unsigned,
+                ..
+            })
+        ));
+        assert_eq!(missing(&to, &[zlib]), [zlib]);
+        assert!(import(&to, &zlib, &archive[..], None).unwrap());
+        assert!(!import(&to, &zlib, &archive[..], None).unwrap());
+        assert_eq!(
+            fs::read_to_string(to.by_hash(&zlib).join("src/zlib.c")).unwrap(),
+            "int main;"
+        );
+        assert_eq!(to.info(&zlib).unwrap().references, [libc].into());
+        assert!(missing(&to, &[libc, zlib]).is_empty());
+
+        let other = SupportedHash::Blake3([9; 32]);
+        assert!(matches!(
+            import(&to, &other, &archive[..], None),
+            Err(SyncError::HashMismatch { actual, .. }) if actual == zlib
+        ));
+    }
+}
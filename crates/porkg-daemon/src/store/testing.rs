@@ -0,0 +1,111 @@
+//! A store in a temporary directory, so that tests neither touch `/var/lib/porkg` nor need permissions to write to it.
+
+use std::{
+    fs,
+    ops::Deref,
+    os::unix::fs::{symlink, PermissionsExt as _},
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use porkg_model::{
+    hashing::{SupportedHash, SupportedHasher},
+    store::PackageInfo,
+};
+
+use super::{gc, Store};
+use crate::config::StoreConfig;
+
+static NEXT_STORE: AtomicU64 = AtomicU64::new(0);
+
+/// Derives a hash from `name`, so that tests get the same hashes on every run without picking them.
+pub fn hash(name: &str) -> SupportedHash {
+    let mut hasher = SupportedHasher::blake3();
+    hasher.update(name);
+    hasher.finalize()
+}
+
+/// Adds a package with a file and a read-only target directory, linked by name, whose hash is made of `byte`.
+pub fn add_package(
+    store: &Store,
+    name: &str,
+    references: &[SupportedHash],
+    byte: u8,
+) -> SupportedHash {
+    write_package(store, SupportedHash::Blake3([byte; 32]), name, references)
+}
+
+fn write_package(
+    store: &Store,
+    hash: SupportedHash,
+    name: &str,
+    references: &[SupportedHash],
+) -> SupportedHash {
+    let dir = store.by_hash(&hash);
+    fs::create_dir_all(dir.join("out")).unwrap();
+    fs::write(dir.join("out/file"), name).unwrap();
+    fs::set_permissions(dir.join("out"), fs::Permissions::from_mode(0o555)).unwrap();
+
+    let mut info = PackageInfo::new(name);
+    info.references.extend(references);
+    Store::write_info(&dir, &info).unwrap();
+    fs::create_dir_all(store.by_name(name)).unwrap();
+    symlink(&dir, store.by_name(name).join(hash.to_string())).unwrap();
+    hash
+}
+
+/// A store that is removed when it is dropped, including its read-only packages.
+#[derive(Debug)]
+pub struct TestStore {
+    store: Store,
+}
+
+impl TestStore {
+    /// Creates an empty store, in a directory named after `name` that no other store of the process uses.
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!(
+            "porkg-{name}-{}-{}",
+            std::process::id(),
+            NEXT_STORE.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path).unwrap();
+        Self {
+            // Packages refer to each other by their paths on the host, as if the store were bound at the same path.
+            store: Store::new(&StoreConfig {
+                prefix: path.clone(),
+                path,
+            }),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.store.path
+    }
+
+    pub fn config(&self) -> StoreConfig {
+        StoreConfig {
+            path: self.store.path.clone(),
+            prefix: self.store.prefix.clone(),
+        }
+    }
+
+    /// Adds a package like [`add_package`], with the hash of its name (see [`hash`]).
+    pub fn add(&self, name: &str, references: &[SupportedHash]) -> SupportedHash {
+        write_package(&self.store, hash(name), name, references)
+    }
+}
+
+impl Deref for TestStore {
+    type Target = Store;
+
+    fn deref(&self) -> &Self::Target {
+        &self.store
+    }
+}
+
+impl Drop for TestStore {
+    fn drop(&mut self) {
+        gc::make_writable(&self.store.path).ok();
+        fs::remove_dir_all(&self.store.path).ok();
+    }
+}
@@ -0,0 +1,258 @@
+//! Wrapper scripts for the commands that packages provide (see
+//! [`PackageInfo::executables`](porkg_model::store::PackageInfo::executables)).
+//!
+//! A wrapper puts the `bin` and `lib` directories of the targets of the package and of the packages that it references
+//! on `PATH` and `LD_LIBRARY_PATH`, sets the environment of the command and then runs it with the arguments of the
+//! wrapper. Wrappers are in the `bin` directory of the package, so that profiles can expose them.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs, io,
+    os::unix::fs::PermissionsExt as _,
+    path::{Path, PathBuf},
+};
+
+use porkg_model::package::{is_valid_executable_name, Executable};
+use thiserror::Error;
+
+use super::{targets, StoreError, WRAPPERS};
+
+#[derive(Debug, Error)]
+pub enum WrapperError {
+    #[error("{0:?} can't be the name of an executable")]
+    InvalidName(String),
+    #[error("executable {0} doesn't run a command")]
+    Empty(String),
+    #[error("executable {name} sets {variable}, which isn't a valid variable name")]
+    InvalidVariable { name: String, variable: String },
+    #[error("invalid executable {name}: {error}")]
+    Expand { name: String, error: String },
+}
+
+/// Quotes a value for the shell.
+fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+fn is_valid_variable(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|v| v.is_ascii_alphabetic() || v == '_')
+        && chars.all(|v| v.is_ascii_alphanumeric() || v == '_')
+}
+
+/// The command that an executable runs, with every reference expanded.
+#[derive(Debug, Clone)]
+pub struct Command {
+    /// `PATH` and `LD_LIBRARY_PATH`, which are put in front of the search paths that the command inherits.
+    pub search_paths: Vec<(&'static str, String)>,
+    /// The environment of the executable.
+    pub env: BTreeMap<String, String>,
+    /// The program and its arguments.
+    pub exec: Vec<String>,
+}
+
+/// Generates the wrappers of a package.
+#[derive(Debug, Default)]
+pub struct Wrappers {
+    /// The paths that `${name}` refers to: the package as `out`, and the packages that it references by name.
+    names: BTreeMap<String, PathBuf>,
+    path: Vec<PathBuf>,
+    library_path: Vec<PathBuf>,
+}
+
+impl Wrappers {
+    /// Creates the wrappers of the package in `dir`, which will be found at `installed` once it is in the store.
+    pub fn new(dir: &Path, installed: &Path) -> io::Result<Self> {
+        let mut result = Self::default();
+        result.add(dir, installed)?;
+        result
+            .names
+            .insert("out".to_string(), installed.to_path_buf());
+        Ok(result)
+    }
+
+    /// Adds a package in `dir` that the package references, which is found at `installed`.
+    pub fn add_reference(&mut self, name: &str, dir: &Path, installed: &Path) -> io::Result<()> {
+        self.add(dir, installed)?;
+        self.names
+            .entry(name.to_string())
+            .or_insert_with(|| installed.to_path_buf());
+        Ok(())
+    }
+
+    fn add(&mut self, dir: &Path, installed: &Path) -> io::Result<()> {
+        for target in targets(dir)? {
+            for (name, paths) in [("bin", &mut self.path), ("lib", &mut self.library_path)] {
+                if dir.join(&target).join(name).is_dir() {
+                    paths.push(installed.join(&target).join(name));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Resolves the command that an executable runs.
+    pub fn command(&self, name: &str, executable: &Executable) -> Result<Command, WrapperError> {
+        if !is_valid_executable_name(name) {
+            return Err(WrapperError::InvalidName(name.to_string()));
+        }
+        if executable.exec.is_empty() {
+            return Err(WrapperError::Empty(name.to_string()));
+        }
+        let expand = |value: &str| {
+            porkg_private::string::expand(value, |v| {
+                self.names.get(v).map(|v| v.to_string_lossy().into_owned())
+            })
+            .map(|v| v.into_owned())
+            .map_err(|error| WrapperError::Expand {
+                name: name.to_string(),
+                error: error.to_string(),
+            })
+        };
+
+        let search_paths = [
+            ("PATH", &self.path),
+            ("LD_LIBRARY_PATH", &self.library_path),
+        ]
+        .into_iter()
+        .filter(|(_, paths)| !paths.is_empty())
+        .map(|(variable, paths)| {
+            let value = paths
+                .iter()
+                .map(|v| v.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(":");
+            (variable, value)
+        })
+        .collect();
+        let mut env = BTreeMap::new();
+        for (variable, value) in &executable.env {
+            if !is_valid_variable(variable) {
+                return Err(WrapperError::InvalidVariable {
+                    name: name.to_string(),
+                    variable: variable.clone(),
+                });
+            }
+            env.insert(variable.clone(), expand(value)?);
+        }
+        let exec = executable
+            .exec
+            .iter()
+            .map(|v| expand(v))
+            .collect::<Result<_, _>>()?;
+        Ok(Command {
+            search_paths,
+            env,
+            exec,
+        })
+    }
+
+    /// Creates the wrapper script of an executable.
+    pub fn script(&self, name: &str, executable: &Executable) -> Result<String, WrapperError> {
+        let command = self.command(name, executable)?;
+        let mut script = format!("#!/bin/sh\n# The {name} command, generated by porkg.\n");
+        for (variable, value) in &command.search_paths {
+            writeln!(
+                script,
+                "export {variable}={}\"${{{variable}:+:${variable}}}\"",
+                quote(value)
+            )
+            .unwrap();
+        }
+        for (variable, value) in &command.env {
+            writeln!(script, "export {variable}={}", quote(value)).unwrap();
+        }
+        let exec = command.exec.iter().map(|v| quote(v)).collect::<Vec<_>>();
+        writeln!(script, "exec {} \"$@\"", exec.join(" ")).unwrap();
+        Ok(script)
+    }
+
+    /// Writes the wrappers of `executables` into the package in `dir`.
+    pub fn generate(
+        &self,
+        dir: &Path,
+        executables: &BTreeMap<String, Executable>,
+    ) -> Result<(), StoreError> {
+        let bin = dir.join(WRAPPERS);
+        fs::create_dir_all(&bin)?;
+        for (name, executable) in executables {
+            let path = bin.join(name);
+            fs::write(&path, self.script(name, executable)?)?;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn run_wrapper() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        let greeter = root.join("greeter");
+        fs::create_dir_all(greeter.join("x86_64-linux/bin")).unwrap();
+        fs::create_dir_all(greeter.join("src/bin")).unwrap();
+        let greet = greeter.join("x86_64-linux/bin/greet");
+        fs::write(
+            &greet,
+            "#!/bin/sh\necho \"$GREETING, $1 from $PORKG_HOME\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&greet, fs::Permissions::from_mode(0o755)).unwrap();
+        let app = root.join("app");
+        fs::create_dir_all(app.join("x86_64-linux/lib")).unwrap();
+
+        let installed = root.join("pkg/app");
+        let mut wrappers = Wrappers::new(&app, &installed).unwrap();
+        wrappers
+            .add_reference("greeter", &greeter, &greeter)
+            .unwrap();
+        let executable = Executable {
+            exec: vec!["greet".to_string()],
+            env: BTreeMap::from([
+                ("GREETING".to_string(), "it's me".to_string()),
+                ("PORKG_HOME".to_string(), "${out}".to_string()),
+            ]),
+        };
+        wrappers
+            .generate(&app, &[("hello".to_string(), executable.clone())].into())
+            .unwrap();
+
+        let script = fs::read_to_string(app.join("bin/hello")).unwrap();
+        assert!(script.contains(&format!(
+            "export LD_LIBRARY_PATH='{}'",
+            installed.join("x86_64-linux/lib").display()
+        )));
+        let output = std::process::Command::new(app.join("bin/hello"))
+            .arg("world")
+            .output()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!("it's me, world from {}\n", installed.display())
+        );
+
+        assert!(matches!(
+            wrappers.script("../hello", &executable),
+            Err(WrapperError::InvalidName(_))
+        ));
+        let mut invalid = executable;
+        invalid.env.insert("A B".to_string(), String::new());
+        assert!(matches!(
+            wrappers.script("hello", &invalid),
+            Err(WrapperError::InvalidVariable { .. })
+        ));
+        invalid.exec = vec!["${missing}/bin/greet".to_string()];
+        invalid.env.clear();
+        assert!(matches!(
+            wrappers.script("hello", &invalid),
+            Err(WrapperError::Expand { .. })
+        ));
+    }
+}
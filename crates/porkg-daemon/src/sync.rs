@@ -0,0 +1,560 @@
+//! Synchronizes the store with peers: other daemons that are configured in [`SyncConfig`].
+//!
+//! Only the packages that the receiving store lacks are transferred. When pulling, the peer lists the closure of the
+//! requested packages and the missing packages are found locally; when pushing, the local closure is sent to the peer
+//! as a list of hashes, and the peer answers with the packages that it lacks. Each package is transferred with its own
+//! request (see [`crate::store::sync`]), after the packages that it references.
+//!
+//! Peers can also substitute build inputs that are missing from the store, followed by the daemons that were found
+//! by [`crate::discovery`]. Exported packages are signed when a key is configured (see [`crate::signing`]), and
+//! packages from discovered daemons are only accepted with a trusted signature. Packages that are pushed are always
+//! signed, as peers only accept them with a signature that they trust.
+//!
+//! Remotes (see [`crate::remote`]) are pulled from and pushed to by name like peers, and substitute build inputs
+//! after the configured peers.
+//!
+//! Substituters that lack a package or fail are skipped for a while (see [`health`]), so that an unreachable cache
+//! doesn't slow down every substitution.
+
+pub mod health;
+
+use std::{collections::BTreeMap, fs, io, sync::Arc};
+
+use bytes::Bytes;
+use futures_util::TryStreamExt as _;
+use http_body_util::{combinators::BoxBody, BodyDataStream, BodyExt as _, Empty, Full, StreamBody};
+use hyper::{
+    body::Frame, body::Incoming, header, header::HeaderValue, Method, Request, Response,
+    StatusCode, Uri,
+};
+use hyper_rustls::HttpsConnector;
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use porkg_model::hashing::SupportedHash;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio_util::io::{ReaderStream, StreamReader, SyncIoBridge};
+
+use crate::{
+    blocking::BlockingPool,
+    config::{Config, PeerConfig},
+    discovery::Discovered,
+    remote::{Remote, RemoteError},
+    secret::{SecretError, Secrets},
+    signing::{SignatureError, SigningKey, TrustedKeys, SIGNATURE_HEADER},
+    store::{
+        sync::{self, SyncError, Verify},
+        Store, StoreError,
+    },
+    sync::health::{Health, SubstituterMetrics},
+};
+
+pub(crate) const USER_AGENT: &str = concat!("porkg/", env!("CARGO_PKG_VERSION"));
+
+type Body = BoxBody<Bytes, io::Error>;
+
+#[derive(Debug, Error)]
+pub enum PeerError {
+    #[error("the peer or remote {0:?} is not configured")]
+    Unknown(String),
+    #[error("invalid url {0:?}")]
+    InvalidUrl(String),
+    #[error(transparent)]
+    Request(#[from] hyper::http::Error),
+    #[error(transparent)]
+    Client(#[from] hyper_util::client::legacy::Error),
+    #[error(transparent)]
+    Body(#[from] hyper::Error),
+    #[error("the peer {peer} responded with {status}: {message}")]
+    Status {
+        peer: String,
+        status: hyper::StatusCode,
+        message: String,
+    },
+    #[error("the peer {peer} sent an invalid hash: {hash}")]
+    InvalidHash { peer: String, hash: String },
+    #[error(transparent)]
+    Serialization(#[from] serde_json::Error),
+    #[error(transparent)]
+    Secret(#[from] SecretError),
+    #[error("the secret {0:?} is not a valid token")]
+    InvalidSecret(String),
+    #[error(transparent)]
+    Store(#[from] StoreError),
+    #[error(transparent)]
+    Sync(#[from] SyncError),
+    #[error(transparent)]
+    Signature(#[from] SignatureError),
+    #[error("pushing requires a signing key, as peers only accept signed packages")]
+    Unsigned,
+    #[error("the remote {name} failed: {error}")]
+    Remote { name: String, error: RemoteError },
+    #[error("the transfer was interrupted")]
+    Interrupted(#[from] tokio::task::JoinError),
+}
+
+/// A list of packages, as sent between peers.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct HashList {
+    pub hashes: Vec<String>,
+}
+
+impl HashList {
+    pub fn new(hashes: &[SupportedHash]) -> Self {
+        Self {
+            hashes: hashes.iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+fn empty() -> Body {
+    Empty::new().map_err(|never| match never {}).boxed()
+}
+
+/// Reads a response body from blocking code.
+fn reader(body: Incoming) -> impl io::Read {
+    SyncIoBridge::new(StreamReader::new(
+        BodyDataStream::new(body).map_err(io::Error::other),
+    ))
+}
+
+/// A daemon that packages are transferred with, either configured or discovered.
+#[derive(Debug, Clone)]
+struct Peer {
+    name: String,
+    url: String,
+    token: Option<String>,
+    verify: bool,
+}
+
+/// A source of build inputs that are missing from the store.
+#[derive(Debug, Clone)]
+enum Substituter {
+    Peer(Peer),
+    Remote(String, Remote),
+}
+
+#[derive(Debug, Clone)]
+pub struct Peers {
+    client: Client<HttpsConnector<HttpConnector>, Body>,
+    peers: Arc<BTreeMap<String, PeerConfig>>,
+    remotes: Arc<BTreeMap<String, Remote>>,
+    discovered: Discovered,
+    signing_key: Option<Arc<SigningKey>>,
+    trusted: TrustedKeys,
+    secrets: Secrets,
+    store: Store,
+    blocking: BlockingPool,
+    health: Health,
+}
+
+impl Peers {
+    pub fn new(
+        config: &Config,
+        discovered: Discovered,
+        store: Store,
+        blocking: BlockingPool,
+    ) -> Result<Self, PeerError> {
+        let connector = hyper_rustls::HttpsConnectorBuilder::new()
+            .with_webpki_roots()
+            .https_or_http()
+            .enable_http1()
+            .build();
+
+        let secrets = Secrets::new(&config.fetch.secrets);
+        let signing_key = match &config.signing.key {
+            Some(name) => {
+                let key = SigningKey::parse(secrets.get(name)?.expose())?;
+                tracing::info!(
+                    name = key.name(),
+                    public_key = key.public_key(),
+                    "signing exported packages"
+                );
+                Some(Arc::new(key))
+            }
+            None => None,
+        };
+
+        let mut remotes = BTreeMap::new();
+        for (name, remote) in config.sync.remotes.iter() {
+            let remote = Remote::new(
+                name,
+                remote,
+                &store,
+                secrets.clone(),
+                blocking.clone(),
+                config.scheduler.compression_workers,
+            )
+            .map_err(|error| PeerError::Remote {
+                name: name.clone(),
+                error,
+            })?;
+            remotes.insert(name.clone(), remote);
+        }
+
+        Ok(Self {
+            client: Client::builder(TokioExecutor::new()).build(connector),
+            peers: Arc::new(config.sync.peers.clone()),
+            remotes: Arc::new(remotes),
+            discovered,
+            signing_key,
+            trusted: TrustedKeys::new(&config.signing.trusted)?,
+            secrets,
+            store,
+            blocking,
+            health: Health::new(&config.sync),
+        })
+    }
+
+    fn peer(&self, name: &str) -> Result<Peer, PeerError> {
+        let config = self
+            .peers
+            .get(name)
+            .ok_or_else(|| PeerError::Unknown(name.to_string()))?;
+        Ok(Peer {
+            name: name.to_string(),
+            url: config.url.clone(),
+            token: config.token.clone(),
+            verify: config.verify,
+        })
+    }
+
+    /// Gets the remote that is configured as `name`, unless a peer has the same name.
+    fn remote(&self, name: &str) -> Option<&Remote> {
+        self.remotes
+            .get(name)
+            .filter(|_| !self.peers.contains_key(name))
+    }
+
+    /// Lists the sources that build inputs are substituted from, in order of preference: the configured peers, the
+    /// remotes, then the discovered peers.
+    fn substituters(&self) -> Vec<Substituter> {
+        let mut substituters: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|(_, config)| config.substitute)
+            .map(|(name, config)| {
+                Substituter::Peer(Peer {
+                    name: name.clone(),
+                    url: config.url.clone(),
+                    token: config.token.clone(),
+                    verify: config.verify,
+                })
+            })
+            .collect();
+        for (name, remote) in self.remotes.iter() {
+            if remote.substitute && !self.peers.contains_key(name) {
+                substituters.push(Substituter::Remote(name.clone(), remote.clone()));
+            }
+        }
+        for url in self.discovered.urls() {
+            if !self.peers.values().any(|config| config.url == url) {
+                substituters.push(Substituter::Peer(Peer {
+                    name: url.clone(),
+                    url,
+                    token: None,
+                    verify: true,
+                }));
+            }
+        }
+        substituters
+    }
+
+    async fn pull_remote(
+        &self,
+        name: &str,
+        remote: &Remote,
+        roots: &[SupportedHash],
+    ) -> Result<Vec<SupportedHash>, PeerError> {
+        remote
+            .pull(&self.store, &self.blocking, &self.trusted, roots)
+            .await
+            .map_err(|error| PeerError::Remote {
+                name: name.to_string(),
+                error,
+            })
+    }
+
+    /// Reports the state of the substituters that recently lacked packages or failed, by name.
+    pub fn substituter_metrics(&self) -> BTreeMap<String, SubstituterMetrics> {
+        self.health.metrics()
+    }
+
+    /// Gets the key that exported packages are signed with, if one is configured.
+    pub fn signing_key(&self) -> Option<Arc<SigningKey>> {
+        self.signing_key.clone()
+    }
+
+    /// Gets the keys that packages from peers may be signed with.
+    pub fn trusted(&self) -> TrustedKeys {
+        self.trusted.clone()
+    }
+
+    /// Sends a request to a peer, failing if the peer responds with an error.
+    async fn send(
+        &self,
+        peer: &Peer,
+        method: Method,
+        path: &str,
+        content_type: Option<&'static str>,
+        signature: Option<&str>,
+        body: Body,
+    ) -> Result<Response<Incoming>, PeerError> {
+        let url = format!("{}/api/v1{path}", peer.url.trim_end_matches('/'));
+        let uri: Uri = url.parse().map_err(|_| PeerError::InvalidUrl(url))?;
+
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::USER_AGENT, USER_AGENT);
+        if let Some(content_type) = content_type {
+            request = request.header(header::CONTENT_TYPE, content_type);
+        }
+        if let Some(signature) = signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+        if let Some(name) = peer.token.clone() {
+            let secrets = self.secrets.clone();
+            let owned = name.clone();
+            let secret = self
+                .blocking
+                .run("read-secret", move || secrets.get(&owned))
+                .await??;
+            let mut token = b"Bearer ".to_vec();
+            token.extend_from_slice(secret.expose());
+            let mut value =
+                HeaderValue::from_bytes(&token).map_err(|_| PeerError::InvalidSecret(name))?;
+            value.set_sensitive(true);
+            request = request.header(header::AUTHORIZATION, value);
+        }
+
+        let response = self.client.request(request.body(body)?).await?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.into_body().collect().await?.to_bytes();
+        Err(PeerError::Status {
+            peer: peer.name.clone(),
+            status,
+            message: String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+
+    /// Sends a list of packages to a peer, and parses the list that it responds with.
+    async fn exchange(
+        &self,
+        peer: &Peer,
+        path: &str,
+        hashes: &[SupportedHash],
+    ) -> Result<Vec<SupportedHash>, PeerError> {
+        let body = Full::new(Bytes::from(serde_json::to_vec(&HashList::new(hashes))?))
+            .map_err(|never| match never {})
+            .boxed();
+        let response = self
+            .send(
+                peer,
+                Method::POST,
+                path,
+                Some("application/json"),
+                None,
+                body,
+            )
+            .await?;
+        let list: HashList =
+            serde_json::from_slice(&response.into_body().collect().await?.to_bytes())?;
+        list.hashes
+            .into_iter()
+            .map(|hash| {
+                hash.parse().map_err(|_| PeerError::InvalidHash {
+                    peer: peer.name.clone(),
+                    hash,
+                })
+            })
+            .collect()
+    }
+
+    /// Exports a package for a peer, along with its signature when a signing key is configured.
+    pub async fn export(
+        &self,
+        hash: SupportedHash,
+    ) -> Result<(fs::File, Option<String>), PeerError> {
+        let store = self.store.clone();
+        let signing_key = self.signing_key.clone();
+        self.blocking
+            .run("export-package", move || {
+                let file = sync::export_file(&store, &hash)?;
+                let signature = signing_key
+                    .map(|key| sync::sign(&store, &hash, &key))
+                    .transpose()?;
+                Ok((file, signature))
+            })
+            .await?
+    }
+
+    /// Copies the closures of packages from a peer or remote, returning the packages that were missing.
+    #[tracing::instrument(skip(self, roots))]
+    pub async fn pull(
+        &self,
+        peer: &str,
+        roots: &[SupportedHash],
+    ) -> Result<Vec<SupportedHash>, PeerError> {
+        match self.remote(peer) {
+            Some(remote) => self.pull_remote(peer, remote, roots).await,
+            None => self.pull_from(&self.peer(peer)?, roots).await,
+        }
+    }
+
+    async fn pull_from(
+        &self,
+        peer: &Peer,
+        roots: &[SupportedHash],
+    ) -> Result<Vec<SupportedHash>, PeerError> {
+        let closure = self.exchange(peer, "/store/closure", roots).await?;
+        let missing = sync::missing(&self.store, &closure);
+        tracing::info!(
+            peer = peer.name,
+            total = closure.len(),
+            missing = missing.len(),
+            "pulling packages"
+        );
+
+        for &hash in missing.iter() {
+            let response = self
+                .send(
+                    peer,
+                    Method::GET,
+                    &format!("/store/{hash}/package"),
+                    None,
+                    None,
+                    empty(),
+                )
+                .await?;
+            let signature = response
+                .headers()
+                .get(SIGNATURE_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            let trusted = peer.verify.then(|| self.trusted.clone());
+            let reader = reader(response.into_body());
+            let store = self.store.clone();
+            self.blocking
+                .run("import-package", move || {
+                    let verify = trusted.as_ref().map(|trusted| Verify {
+                        trusted,
+                        signature: signature.as_deref(),
+                    });
+                    sync::import(&store, &hash, reader, verify)
+                })
+                .await??;
+        }
+        Ok(missing)
+    }
+
+    /// Pulls the packages that are not in the store from the peers and remotes that substitute them, returning the
+    /// packages that were transferred. Sources that fail are skipped, and backed off from.
+    pub async fn substitute(&self, hashes: &[SupportedHash]) -> Vec<SupportedHash> {
+        let mut transferred = Vec::new();
+        if sync::missing(&self.store, hashes).is_empty() {
+            return transferred;
+        }
+
+        let substituters = self.substituters();
+        for &hash in hashes {
+            for substituter in substituters.iter() {
+                if self.store.by_hash(&hash).exists() {
+                    break;
+                }
+                let name = match substituter {
+                    Substituter::Peer(peer) => &peer.name,
+                    Substituter::Remote(name, _) => name,
+                };
+                if !self.health.should_try(name, &hash) {
+                    tracing::debug!(peer = name, %hash, "skipping substituter");
+                    continue;
+                }
+                let result = match substituter {
+                    Substituter::Peer(peer) => self.pull_from(peer, &[hash]).await,
+                    Substituter::Remote(name, remote) => {
+                        self.pull_remote(name, remote, &[hash]).await
+                    }
+                };
+                match result {
+                    Ok(pulled) => {
+                        self.health.succeeded(name);
+                        transferred.extend(pulled)
+                    }
+                    Err(
+                        PeerError::Status {
+                            status: StatusCode::NOT_FOUND,
+                            ..
+                        }
+                        | PeerError::Remote {
+                            error: RemoteError::NotFound(_),
+                            ..
+                        },
+                    ) => {
+                        tracing::debug!(peer = name, %hash, "peer doesn't have package");
+                        self.health.missing(name, &hash);
+                    }
+                    Err(error) => {
+                        tracing::warn!(peer = name, %hash, %error, "failed to substitute package");
+                        self.health.failed(name, &error);
+                    }
+                }
+            }
+        }
+        transferred
+    }
+
+    /// Copies the closures of packages to a peer or remote, returning the packages that it was missing.
+    #[tracing::instrument(skip(self, roots))]
+    pub async fn push(
+        &self,
+        peer: &str,
+        roots: &[SupportedHash],
+    ) -> Result<Vec<SupportedHash>, PeerError> {
+        if let Some(remote) = self.remote(peer) {
+            return remote
+                .publish(&self.store, &self.blocking, self.signing_key(), roots)
+                .await
+                .map_err(|error| PeerError::Remote {
+                    name: peer.to_string(),
+                    error,
+                });
+        }
+        let peer = self.peer(peer)?;
+        let store = self.store.clone();
+        let roots = roots.to_vec();
+        let closure = self
+            .blocking
+            .run("closure", move || sync::closure(&store, &roots))
+            .await??;
+        let missing = self.exchange(&peer, "/store/missing", &closure).await?;
+        tracing::info!(
+            total = closure.len(),
+            missing = missing.len(),
+            "pushing packages"
+        );
+
+        for &hash in missing.iter() {
+            let (file, signature) = self.export(hash).await?;
+            let signature = signature.ok_or(PeerError::Unsigned)?;
+
+            let stream = ReaderStream::new(tokio::fs::File::from_std(file)).map_ok(Frame::data);
+            let body = StreamBody::new(stream).boxed();
+            self.send(
+                &peer,
+                Method::PUT,
+                &format!("/store/{hash}/package"),
+                Some("application/x-tar"),
+                Some(&signature),
+                body,
+            )
+            .await?;
+        }
+        Ok(missing)
+    }
+}
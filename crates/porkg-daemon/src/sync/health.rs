@@ -0,0 +1,190 @@
+//! What is known about the substituters, so that substitution doesn't wait on the same failures over and over.
+//!
+//! A substituter that lacked a package isn't asked for it again until [`SyncConfig::negative_ttl`] has passed, and a
+//! substituter that failed isn't used at all for a while (see [`SyncConfig::backoff`]). Both are only kept in memory.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use porkg_model::hashing::SupportedHash;
+use porkg_private::backoff::Backoff;
+use serde::Serialize;
+use tokio::time::Instant;
+
+use crate::config::SyncConfig;
+
+#[derive(Debug, Default)]
+struct State {
+    /// The packages that the substituter lacked, with when they may be asked for again.
+    missing: HashMap<SupportedHash, Instant>,
+    failures: u32,
+    down_until: Option<Instant>,
+    last_error: Option<String>,
+    skipped: u64,
+}
+
+/// The state of a substituter, as reported by the metrics endpoint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SubstituterMetrics {
+    /// The number of packages that the substituter isn't asked for, because it lacked them.
+    pub missing: usize,
+    /// The number of times that the substituter failed in a row.
+    pub failures: u32,
+    /// How long the substituter isn't used for, in seconds, if it is backing off.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub backoff: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+    /// The number of lookups that were skipped because of either.
+    pub skipped: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Health {
+    ttl: Duration,
+    /// The backoff isn't jittered: it only decides which substituters are skipped, and doesn't time any requests.
+    backoff: Backoff,
+    state: Arc<Mutex<BTreeMap<String, State>>>,
+}
+
+impl Health {
+    pub fn new(config: &SyncConfig) -> Self {
+        Self {
+            ttl: Duration::from_secs(config.negative_ttl),
+            backoff: Backoff::new(Duration::from_secs(config.backoff))
+                .with_max(Duration::from_secs(config.max_backoff)),
+            state: Default::default(),
+        }
+    }
+
+    /// Whether the substituter `name` should be asked for a package, which it isn't if it recently lacked the package
+    /// or failed.
+    pub fn should_try(&self, name: &str, hash: &SupportedHash) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let Some(state) = state.get_mut(name) else {
+            return true;
+        };
+        let down = state.down_until.is_some_and(|v| v > now);
+        let missing = match state.missing.get(hash) {
+            Some(&until) if until > now => true,
+            Some(_) => {
+                state.missing.remove(hash);
+                false
+            }
+            None => false,
+        };
+        if down || missing {
+            state.skipped += 1;
+        }
+        !(down || missing)
+    }
+
+    /// Records that the substituter `name` answered, but lacked a package.
+    pub fn missing(&self, name: &str, hash: &SupportedHash) {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        let state = state.entry(name.to_string()).or_default();
+        state.missing.retain(|_, until| *until > now);
+        state.missing.insert(*hash, now + self.ttl);
+        state.failures = 0;
+        state.down_until = None;
+    }
+
+    /// Records that the substituter `name` answered.
+    pub fn succeeded(&self, name: &str) {
+        if let Some(state) = self.state.lock().unwrap().get_mut(name) {
+            state.failures = 0;
+            state.down_until = None;
+        }
+    }
+
+    /// Records that the substituter `name` failed, so that it isn't used until it has backed off.
+    pub fn failed(&self, name: &str, error: &impl Display) {
+        let mut state = self.state.lock().unwrap();
+        let state = state.entry(name.to_string()).or_default();
+        state.failures = state.failures.saturating_add(1);
+        let backoff = self.backoff.delay(state.failures - 1);
+        state.down_until = Some(Instant::now() + backoff);
+        state.last_error = Some(error.to_string());
+        tracing::info!(
+            substituter = name,
+            failures = state.failures,
+            backoff = backoff.as_secs(),
+            "backing off from a failing substituter"
+        );
+    }
+
+    /// Reports the state of every substituter that lacked a package or failed.
+    pub fn metrics(&self) -> BTreeMap<String, SubstituterMetrics> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state
+            .iter_mut()
+            .map(|(name, state)| {
+                state.missing.retain(|_, until| *until > now);
+                let metrics = SubstituterMetrics {
+                    missing: state.missing.len(),
+                    failures: state.failures,
+                    backoff: state
+                        .down_until
+                        .filter(|v| *v > now)
+                        .map(|v| (v - now).as_secs()),
+                    last_error: state.last_error.clone(),
+                    skipped: state.skipped,
+                };
+                (name.clone(), metrics)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn skip_missing_and_failing_substituters() {
+        let health = Health::new(&SyncConfig {
+            negative_ttl: 60,
+            backoff: 10,
+            max_backoff: 25,
+            ..Default::default()
+        });
+        let hash = SupportedHash::Blake3([1; 32]);
+        let other = SupportedHash::Blake3([2; 32]);
+
+        health.missing("cache", &hash);
+        assert!(!health.should_try("cache", &hash));
+        assert!(health.should_try("cache", &other));
+        assert!(health.should_try("peer", &hash));
+        tokio::time::sleep(Duration::from_secs(61)).await;
+        assert!(health.should_try("cache", &hash));
+
+        health.failed("peer", &"connection refused");
+        assert!(!health.should_try("peer", &other));
+        tokio::time::sleep(Duration::from_secs(11)).await;
+        assert!(health.should_try("peer", &other));
+        health.failed("peer", &"connection refused");
+        health.failed("peer", &"connection refused");
+        assert_eq!(
+            health.metrics()["peer"],
+            SubstituterMetrics {
+                missing: 0,
+                failures: 3,
+                backoff: Some(25),
+                last_error: Some("connection refused".to_string()),
+                skipped: 1,
+            }
+        );
+
+        health.succeeded("peer");
+        assert!(health.should_try("peer", &other));
+        assert_eq!(health.metrics()["peer"].failures, 0);
+        assert_eq!(health.metrics()["cache"].skipped, 1);
+    }
+}
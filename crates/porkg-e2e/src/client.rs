@@ -0,0 +1,83 @@
+use std::path::PathBuf;
+
+use anyhow::Context as _;
+use bytes::Bytes;
+use http_body_util::{BodyExt as _, Empty, Full};
+use hyper::{body::Body, Request, StatusCode};
+use hyper_util::rt::TokioIo;
+use tokio::net::UnixStream;
+
+/// A client for the API of the daemon under test.
+#[derive(Debug, Clone)]
+pub struct Client {
+    socket: PathBuf,
+}
+
+/// A response of the daemon, which is kept whether it succeeded or not, so that failures can be asserted on.
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl Response {
+    /// Fails with the body of the response unless it succeeded.
+    pub fn success(self) -> anyhow::Result<String> {
+        anyhow::ensure!(self.status.is_success(), "{}: {}", self.status, self.body);
+        Ok(self.body)
+    }
+}
+
+impl Client {
+    pub fn new(socket: PathBuf) -> Self {
+        Self { socket }
+    }
+
+    pub async fn get(&self, path: &str) -> anyhow::Result<Response> {
+        self.send(Request::get(path).body(Empty::<Bytes>::new())?)
+            .await
+    }
+
+    pub async fn post(&self, path: &str, body: impl Into<Bytes>) -> anyhow::Result<Response> {
+        self.send(Request::post(path).body(Full::new(body.into()))?)
+            .await
+    }
+
+    /// Sends a POST request with a JSON body.
+    pub async fn post_json(
+        &self,
+        path: &str,
+        body: &serde_json::Value,
+    ) -> anyhow::Result<Response> {
+        let request = Request::post(path)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(serde_json::to_vec(body)?)))?;
+        self.send(request).await
+    }
+
+    async fn send<B>(&self, mut request: Request<B>) -> anyhow::Result<Response>
+    where
+        B: Body + Send + 'static,
+        B::Data: Send,
+        B::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+    {
+        let stream = UnixStream::connect(&self.socket)
+            .await
+            .with_context(|| format!("failed to connect to {:?}", self.socket))?;
+        let (mut sender, connection) =
+            hyper::client::conn::http1::handshake(TokioIo::new(stream)).await?;
+        tokio::spawn(connection);
+
+        request.headers_mut().insert(
+            hyper::header::HOST,
+            hyper::header::HeaderValue::from_static("localhost"),
+        );
+        let response = sender.send_request(request).await?;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        Ok(Response {
+            status,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+}
@@ -0,0 +1,227 @@
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::Context as _;
+use porkg_model::package::LockDefinition;
+use tokio::process::{Child, Command};
+
+use crate::client::{Client, Response};
+
+/// How long the daemon may take to start listening.
+const START_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The manifest of the stub toolchain that every daemon starts with, so that fixtures can depend on it as `bootstrap`
+/// without building a real one.
+const BOOTSTRAP_MANIFEST: &str = r#"
+[package]
+name = "bootstrap"
+version = "0.1.0"
+targets = ["x86_64-linux", "aarch64-linux"]
+
+[dependencies]
+
+[build-dependencies]
+"#;
+const BOOTSTRAP_FILES: &[(&str, &str)] = &[
+    ("bin/cc", "#!/bin/sh\nexit 0\n"),
+    ("bin/make", "#!/bin/sh\nexit 0\n"),
+];
+
+static NEXT_DAEMON: AtomicUsize = AtomicUsize::new(0);
+
+/// Finds the daemon binary, which is `PORKG_DAEMON` or otherwise built next to the test binary.
+fn daemon_path() -> anyhow::Result<PathBuf> {
+    if let Some(path) = std::env::var_os("PORKG_DAEMON") {
+        return Ok(path.into());
+    }
+    let exe = std::env::current_exe()?;
+    // Test binaries are in the `deps` directory of the profile.
+    let path = exe
+        .ancestors()
+        .nth(2)
+        .map(|v| v.join("porkg-daemon"))
+        .context("the test binary isn't in a target directory")?;
+    anyhow::ensure!(
+        path.exists(),
+        "{path:?} doesn't exist, build it with `cargo build -p porkg-daemon` or set PORKG_DAEMON"
+    );
+    Ok(path)
+}
+
+/// Archives the source of a package, in the layout that the CLI imports it with.
+fn archive(manifest: &str, files: &BTreeMap<String, String>) -> anyhow::Result<Vec<u8>> {
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut append = |path: &str, contents: &str| {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(if path.starts_with("bin/") {
+            0o755
+        } else {
+            0o644
+        });
+        header.set_cksum();
+        builder.append_data(
+            &mut header,
+            Path::new("src").join(path),
+            contents.as_bytes(),
+        )
+    };
+    append("porkg.toml", manifest)?;
+    for (path, contents) in files {
+        append(path, contents)?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// A daemon with its own temporary store, which is stopped and removed when it is dropped.
+#[derive(Debug)]
+pub struct Daemon {
+    child: Child,
+    dir: PathBuf,
+    client: Client,
+    bootstrap: String,
+}
+
+impl Daemon {
+    /// Starts a daemon, and builds the stub toolchain in its store.
+    pub async fn start() -> anyhow::Result<Self> {
+        let dir = std::env::temp_dir().join(format!(
+            "porkg-e2e-{}-{}",
+            std::process::id(),
+            NEXT_DAEMON.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).with_context(|| format!("failed to create {dir:?}"))?;
+        let socket = dir.join("porkg.sock");
+        let log = File::create(dir.join("daemon.log"))?;
+
+        let child = Command::new(daemon_path()?)
+            .env("PORKG__STORE__PATH", dir.join("store"))
+            .env("PORKG__SCHEDULER__JOURNAL", dir.join("jobs"))
+            .env("PORKG__LOG__CRASH_DIR", dir.join("crash"))
+            .env("PORKG__BIND__SOCKET", &socket)
+            .env(
+                "RUST_LOG",
+                std::env::var("PORKG_E2E_LOG").unwrap_or_else(|_| "info".to_string()),
+            )
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .kill_on_drop(true)
+            .spawn()
+            .context("failed to start the daemon")?;
+        let mut daemon = Self {
+            child,
+            dir,
+            client: Client::new(socket),
+            bootstrap: String::new(),
+        };
+        daemon.wait_ready().await?;
+
+        let files = BOOTSTRAP_FILES
+            .iter()
+            .map(|(path, contents)| (path.to_string(), contents.to_string()))
+            .collect();
+        let hash = daemon
+            .import("bootstrap", BOOTSTRAP_MANIFEST, &files)
+            .await?;
+        let lock = LockDefinition {
+            dependencies: BTreeMap::new(),
+            build_dependencies: BTreeMap::new(),
+            base: None,
+        };
+        daemon
+            .build("bootstrap", &hash, &lock)
+            .await?
+            .success()
+            .context("failed to build the stub toolchain")?;
+        daemon.bootstrap = hash;
+        Ok(daemon)
+    }
+
+    async fn wait_ready(&mut self) -> anyhow::Result<()> {
+        let start = Instant::now();
+        loop {
+            if let Some(status) = self.child.try_wait()? {
+                anyhow::bail!("the daemon exited with {status}:\n{}", self.logs()?);
+            }
+            if self.client.get("/api/v1/").await.is_ok() {
+                return Ok(());
+            }
+            if start.elapsed() > START_TIMEOUT {
+                anyhow::bail!("the daemon didn't start listening:\n{}", self.logs()?);
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    /// The hash of the stub toolchain.
+    pub fn bootstrap(&self) -> &str {
+        &self.bootstrap
+    }
+
+    /// Everything that the daemon has logged so far.
+    pub fn logs(&self) -> anyhow::Result<String> {
+        Ok(std::fs::read_to_string(self.dir.join("daemon.log"))?)
+    }
+
+    /// The directory of the package with `hash` in the store.
+    pub fn package(&self, hash: &str) -> PathBuf {
+        self.dir.join("store/pkg/by-hash").join(hash)
+    }
+
+    /// Imports the source of a package, returning its hash.
+    pub async fn import(
+        &self,
+        name: &str,
+        manifest: &str,
+        files: &BTreeMap<String, String>,
+    ) -> anyhow::Result<String> {
+        let encoded: String = url::form_urlencoded::byte_serialize(name.as_bytes()).collect();
+        self.client
+            .post(
+                &format!("/api/v1/store/source?name={encoded}&strip_components=1"),
+                archive(manifest, files)?,
+            )
+            .await?
+            .success()
+            .with_context(|| format!("failed to import the source of {name}"))
+    }
+
+    /// Builds the package with `hash`, returning the response whether the build succeeded or not.
+    pub async fn build(
+        &self,
+        name: &str,
+        hash: &str,
+        lock: &LockDefinition,
+    ) -> anyhow::Result<Response> {
+        let request = serde_json::json!({
+            "name": name,
+            "hash": hash,
+            "lock": lock,
+        });
+        self.client.post_json("/api/v1/build", &request).await
+    }
+
+    /// The jobs that are queued or running.
+    pub async fn jobs(&self) -> anyhow::Result<Vec<serde_json::Value>> {
+        let body = self.client.get("/api/v1/admin/jobs").await?.success()?;
+        Ok(serde_json::from_str(&body)?)
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        self.child.start_kill().ok();
+        std::fs::remove_dir_all(&self.dir).ok();
+    }
+}
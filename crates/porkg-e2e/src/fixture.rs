@@ -0,0 +1,182 @@
+//! Fixtures describe the packages to build in TOML, and what the daemon should have done once they were built:
+//!
+//! ```toml
+//! [packages.hello]
+//! manifest = """
+//! [package]
+//! name = "hello"
+//! ...
+//! """
+//! files = { "hello.txt" = "Hello World" }
+//!
+//! [[builds]]
+//! package = "hello"
+//! state = "succeeded"
+//!
+//! [expect]
+//! store = ["hello/src/hello.txt"]
+//! logs = ["recording provenance"]
+//! jobs = 0
+//! ```
+//!
+//! Dependencies are locked to the packages that were built before, by name, or to the stub toolchain for `bootstrap`.
+
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Context as _;
+use porkg_model::package::{Dependency, LockDefinition, Package};
+use serde::Deserialize;
+
+use crate::daemon::Daemon;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Fixture {
+    /// The sources of the packages, by name.
+    #[serde(default)]
+    pub packages: BTreeMap<String, FixturePackage>,
+    /// The builds to submit, in order.
+    #[serde(default)]
+    pub builds: Vec<FixtureBuild>,
+    #[serde(default)]
+    pub expect: Expectations,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FixturePackage {
+    /// The contents of the `porkg.toml` of the package.
+    pub manifest: String,
+    /// The other files of the source, by their path.
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FixtureBuild {
+    pub package: String,
+    #[serde(default)]
+    pub state: BuildState,
+    /// Text that the error of a failed build contains.
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildState {
+    #[default]
+    Succeeded,
+    Failed,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expectations {
+    /// Paths that exist in the store, starting with the name of the package that they are in.
+    #[serde(default)]
+    pub store: Vec<String>,
+    /// Text that the logs of the daemon contain.
+    #[serde(default)]
+    pub logs: Vec<String>,
+    /// The number of jobs that are left queued or running.
+    #[serde(default)]
+    pub jobs: Option<usize>,
+}
+
+/// Locks the dependencies of `package` to the packages that were built, leaving out the others.
+fn lock(package: &Package, built: &BTreeMap<String, String>) -> LockDefinition {
+    let resolve = |dependencies: &BTreeMap<String, Dependency>| {
+        dependencies
+            .iter()
+            .filter_map(|(key, v)| Some((key.clone(), built.get(&v.name)?.clone())))
+            .collect()
+    };
+    LockDefinition {
+        dependencies: resolve(&package.dependencies),
+        build_dependencies: resolve(&package.build_dependencies),
+        base: package
+            .base
+            .as_ref()
+            .and_then(|v| built.get(&v.name))
+            .cloned(),
+    }
+}
+
+impl Fixture {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        toml::from_str(&text).with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    /// Submits the builds to `daemon`, failing at the first expectation that isn't met.
+    pub async fn run(&self, daemon: &Daemon) -> anyhow::Result<()> {
+        let mut built = BTreeMap::from([("bootstrap".to_string(), daemon.bootstrap().to_string())]);
+        for (i, build) in self.builds.iter().enumerate() {
+            let name = &build.package;
+            let source = self
+                .packages
+                .get(name)
+                .with_context(|| format!("build {i} refers to the unknown package {name}"))?;
+            let package: Package = toml::from_str(&source.manifest)
+                .with_context(|| format!("the manifest of {name} is invalid"))?;
+            let hash = daemon.import(name, &source.manifest, &source.files).await?;
+            let response = daemon.build(name, &hash, &lock(&package, &built)).await?;
+
+            let state = if response.status.is_success() {
+                BuildState::Succeeded
+            } else {
+                BuildState::Failed
+            };
+            anyhow::ensure!(
+                state == build.state,
+                "build {i} of {name} was expected to have {:?}, but {:?} with {}: {}",
+                build.state,
+                state,
+                response.status,
+                response.body
+            );
+            if let Some(error) = &build.error {
+                anyhow::ensure!(
+                    response.body.contains(error.as_str()),
+                    "build {i} of {name} was expected to fail with {error:?}, but failed with {}",
+                    response.body
+                );
+            }
+            if state == BuildState::Succeeded {
+                built.insert(name.clone(), hash);
+            }
+        }
+
+        for path in &self.expect.store {
+            let (name, rest) = path.split_once('/').unwrap_or((path, ""));
+            let hash = built
+                .get(name)
+                .with_context(|| format!("{name} of {path:?} wasn't built"))?;
+            anyhow::ensure!(
+                daemon.package(hash).join(rest).exists(),
+                "{path:?} isn't in the store"
+            );
+        }
+
+        let logs = daemon.logs()?;
+        for expected in &self.expect.logs {
+            anyhow::ensure!(
+                logs.contains(expected.as_str()),
+                "the logs don't contain {expected:?}"
+            );
+        }
+
+        if let Some(expected) = self.expect.jobs {
+            let jobs = daemon.jobs().await?;
+            anyhow::ensure!(
+                jobs.len() == expected,
+                "expected {expected} jobs, but there are {}: {jobs:?}",
+                jobs.len()
+            );
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,9 @@
+//! A harness for end-to-end tests, which starts daemons with temporary stores and builds the packages of TOML
+//! fixtures in them (see [`fixture`]).
+
+pub mod client;
+pub mod daemon;
+pub mod fixture;
+
+pub use daemon::Daemon;
+pub use fixture::Fixture;
@@ -0,0 +1,26 @@
+use std::path::Path;
+
+use porkg_e2e::{Daemon, Fixture};
+
+#[tokio::test]
+#[ignore = "starts the daemon, which has to be built and needs user namespaces"]
+async fn fixtures() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut paths: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|v| v.unwrap().path())
+        .filter(|v| v.extension().is_some_and(|v| v == "toml"))
+        .collect();
+    paths.sort();
+
+    let mut failures = Vec::new();
+    for path in paths {
+        let fixture = Fixture::load(&path).unwrap();
+        // Every fixture starts with an empty store.
+        let daemon = Daemon::start().await.unwrap();
+        if let Err(error) = fixture.run(&daemon).await {
+            failures.push(format!("{}: {error:#}", path.display()));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
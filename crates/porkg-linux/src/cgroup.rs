@@ -0,0 +1,141 @@
+//! Limits the resources of tasks with cgroups (v2), within the cgroup that the daemon was started in.
+
+use std::{
+    fs::File,
+    io::ErrorKind,
+    os::fd::{AsFd, BorrowedFd},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use porkg_private::sandbox::ResourceLimits;
+use thiserror::Error;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// The controllers that the limits of [`ResourceLimits`] need.
+const CONTROLLERS: &str = "+memory +cpu +pids";
+/// The number of times that removing the cgroup of a task is attempted, as it can still be busy with the processes
+/// that are exiting along with the worker.
+const REMOVE_ATTEMPTS: usize = 10;
+
+#[derive(Debug, Error)]
+pub enum CgroupError {
+    #[error("the daemon isn't in a cgroup v2 hierarchy")]
+    Unsupported,
+    #[error("failed to set up the cgroup {path:?}: {source}")]
+    IO {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
+fn write(path: PathBuf, value: impl AsRef<[u8]>) -> Result<(), CgroupError> {
+    std::fs::write(&path, value).map_err(|source| CgroupError::IO { path, source })
+}
+
+/// The cgroup that the daemon was started in, whose children limit the tasks.
+#[derive(Debug, Clone)]
+pub struct Cgroups {
+    root: PathBuf,
+}
+
+impl Cgroups {
+    /// Uses the cgroups that another process delegated to tasks.
+    pub(crate) fn from_root(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    pub(crate) fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Moves the daemon into a child of its cgroup, as the controllers can only be enabled for the cgroups of tasks
+    /// once no processes are left in their parent.
+    #[tracing::instrument(err(level = "debug"))]
+    pub fn delegate() -> Result<Self, CgroupError> {
+        let path = PathBuf::from("/proc/self/cgroup");
+        let own =
+            std::fs::read_to_string(&path).map_err(|source| CgroupError::IO { path, source })?;
+        let own = own
+            .lines()
+            .find_map(|v| v.strip_prefix("0::"))
+            .ok_or(CgroupError::Unsupported)?;
+        let root = Path::new(CGROUP_ROOT).join(own.trim_start_matches('/'));
+
+        let daemon = root.join("daemon");
+        std::fs::create_dir_all(&daemon).map_err(|source| CgroupError::IO {
+            path: daemon.clone(),
+            source,
+        })?;
+        // Zero is the process that writes it.
+        write(daemon.join("cgroup.procs"), "0")?;
+        write(root.join("cgroup.subtree_control"), CONTROLLERS)?;
+        tracing::debug!(?root, "delegated cgroups to tasks");
+        Ok(Self { root })
+    }
+
+    /// Creates the cgroup that limits the task with `id`.
+    #[tracing::instrument(skip(self), err(level = "debug"))]
+    pub fn create(&self, id: u64, limits: ResourceLimits) -> Result<TaskCgroup, CgroupError> {
+        let path = self.root.join(format!("task-{id}"));
+        std::fs::create_dir_all(&path).map_err(|source| CgroupError::IO {
+            path: path.clone(),
+            source,
+        })?;
+        let cgroup = TaskCgroup {
+            dir: File::open(&path).map_err(|source| CgroupError::IO {
+                path: path.clone(),
+                source,
+            })?,
+            path,
+        };
+
+        if let Some(memory) = limits.memory {
+            write(cgroup.path.join("memory.max"), memory.to_string())?;
+        }
+        if let Some(weight) = limits.cpu_weight {
+            write(cgroup.path.join("cpu.weight"), weight.to_string())?;
+        }
+        if let Some(pids) = limits.pids {
+            write(cgroup.path.join("pids.max"), pids.to_string())?;
+        }
+        Ok(cgroup)
+    }
+}
+
+/// The cgroup of a task, which is removed when it is dropped, once its worker has been reaped.
+#[derive(Debug)]
+pub struct TaskCgroup {
+    path: PathBuf,
+    dir: File,
+}
+
+impl TaskCgroup {
+    /// Kills every process in the cgroup at once, so that none of them can fork while the others are being killed.
+    pub fn kill(&self) -> Result<(), CgroupError> {
+        // Only kernels since 5.14 have cgroup.kill.
+        write(self.path.join("cgroup.kill"), "1")
+    }
+}
+
+impl AsFd for TaskCgroup {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.dir.as_fd()
+    }
+}
+
+impl Drop for TaskCgroup {
+    fn drop(&mut self) {
+        for attempt in 1..=REMOVE_ATTEMPTS {
+            match std::fs::remove_dir(&self.path) {
+                Ok(()) => return,
+                Err(error) if error.kind() == ErrorKind::NotFound => return,
+                Err(error) if attempt == REMOVE_ATTEMPTS => {
+                    tracing::warn!(?error, path = ?self.path, "failed to remove the cgroup of a task")
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+}
@@ -4,6 +4,7 @@
 use std::{
     ffi::{c_int, c_long},
     num::NonZeroUsize,
+    os::fd::{AsRawFd as _, BorrowedFd, RawFd},
 };
 
 use crate::Syscall;
@@ -52,6 +53,9 @@ bitflags::bitflags! {
     }
 }
 
+/// Starts the child in the cgroup that is passed to clone3.
+const CLONE_INTO_CGROUP: u64 = 0x200000000;
+
 /// Syscalls related to cloning a process.
 pub trait CloneSyscall {
     /// Clones the current process and invokes the `callback` inside the clone.
@@ -59,41 +63,75 @@ pub trait CloneSyscall {
         callback: F,
         flags: CloneFlags,
     ) -> Result<Pid, CloneError>;
+
+    /// Like [`CloneSyscall::clone`], but starts the clone in the cgroup of the `cgroup` directory.
+    fn clone_into_cgroup<R: IntoExitCode + std::fmt::Debug, F: 'static + FnMut() -> R>(
+        callback: F,
+        flags: CloneFlags,
+        cgroup: BorrowedFd<'_>,
+    ) -> Result<Pid, CloneError>;
 }
 
 impl CloneSyscall for Syscall {
     #[tracing::instrument(skip(callback), err(level = "debug"))]
     fn clone<R: IntoExitCode + std::fmt::Debug, F: 'static + FnMut() -> R>(
-        mut callback: F,
+        callback: F,
         flags: CloneFlags,
     ) -> Result<Pid, CloneError> {
-        let current = Span::current().id();
-        let mut cb = Box::new(move || {
-            let pid = Pid::this().as_raw();
-            let new = span!(parent: None, Level::TRACE, "cloned", ?pid);
-            new.follows_from(current.clone());
-            let _span = new.entered();
-
-            callback()
-        });
-
-        let exit_signal = if flags.contains(CloneFlags::PARENT) {
-            0
-        } else {
-            SIGCHLD
-        } as u64;
-        match clone3(&mut cb, flags, exit_signal) {
-            Ok(pid) => Ok(pid),
-            // For now, we decide to only fallback on ENOSYS
-            Err(nix::Error::ENOSYS) => {
-                let flags = flags.difference(CloneFlags::TEST_FALLBACK).bits();
-                let pid = clone_fallback(cb, flags, exit_signal)
-                    .map_err(|source| CloneError { source })?;
-
-                Ok(pid)
+        clone_in(callback, flags, None)
+    }
+
+    #[tracing::instrument(skip(callback), err(level = "debug"))]
+    fn clone_into_cgroup<R: IntoExitCode + std::fmt::Debug, F: 'static + FnMut() -> R>(
+        callback: F,
+        flags: CloneFlags,
+        cgroup: BorrowedFd<'_>,
+    ) -> Result<Pid, CloneError> {
+        clone_in(callback, flags, Some(cgroup.as_raw_fd()))
+    }
+}
+
+fn clone_in<R: IntoExitCode + std::fmt::Debug, F: 'static + FnMut() -> R>(
+    mut callback: F,
+    flags: CloneFlags,
+    cgroup: Option<RawFd>,
+) -> Result<Pid, CloneError> {
+    let current = Span::current().id();
+    let mut cb = Box::new(move || {
+        let pid = Pid::this().as_raw();
+        let new = span!(parent: None, Level::TRACE, "cloned", ?pid);
+        new.follows_from(current.clone());
+        let _span = new.entered();
+
+        callback()
+    });
+
+    let exit_signal = if flags.contains(CloneFlags::PARENT) {
+        0
+    } else {
+        SIGCHLD
+    } as u64;
+    match clone3(&mut cb, flags, exit_signal, cgroup) {
+        Ok(pid) => Ok(pid),
+        // For now, we decide to only fallback on ENOSYS
+        Err(nix::Error::ENOSYS) => {
+            let flags = flags.difference(CloneFlags::TEST_FALLBACK).bits();
+            let pid =
+                clone_fallback(cb, flags, exit_signal).map_err(|source| CloneError { source })?;
+            if let Some(cgroup) = cgroup {
+                // Without clone3 the child can only be moved once it has started.
+                std::fs::write(
+                    format!("/proc/self/fd/{cgroup}/cgroup.procs"),
+                    pid.to_string(),
+                )
+                .map_err(|error| CloneError {
+                    source: Errno::from_raw(error.raw_os_error().unwrap_or_default()),
+                })?;
             }
-            Err(err) => Err(CloneError { source: err }),
+
+            Ok(pid)
         }
+        Err(err) => Err(CloneError { source: err }),
     }
 }
 
@@ -104,6 +142,7 @@ fn clone3<R: IntoExitCode + std::fmt::Debug, F: FnMut() -> R>(
     cb: &mut Box<F>,
     flags: CloneFlags,
     exit_signal: u64,
+    cgroup: Option<RawFd>,
 ) -> Result<Pid, nix::Error> {
     #[repr(C)]
     struct Clone3Args {
@@ -121,6 +160,8 @@ fn clone3<R: IntoExitCode + std::fmt::Debug, F: FnMut() -> R>(
     }
     let flags = if flags.intersects(CloneFlags::TEST_FALLBACK) {
         return Err(Errno::ENOSYS);
+    } else if cgroup.is_some() {
+        flags.bits() | CLONE_INTO_CGROUP
     } else {
         flags.bits()
     };
@@ -136,7 +177,7 @@ fn clone3<R: IntoExitCode + std::fmt::Debug, F: FnMut() -> R>(
         tls: 0,
         set_tid: 0,
         set_tid_size: 0,
-        cgroup: 0,
+        cgroup: cgroup.map_or(0, |v| v as u64),
     };
     let args_ptr = &mut args as *mut Clone3Args;
     let args_size = std::mem::size_of::<Clone3Args>();
@@ -307,10 +348,7 @@ extern "C" fn clone_main<R: IntoExitCode + std::fmt::Debug, F: 'static + FnMut()
 
 #[cfg(test)]
 mod tests {
-    use std::{
-        io::{Read as _, Write as _},
-        os::unix::net::UnixStream,
-    };
+    use std::io::{Read as _, Write as _};
 
     use crate::private::Syscall;
     use anyhow::{bail, Context as _};
@@ -318,6 +356,7 @@ mod tests {
         sys::wait::{waitpid, WaitPidFlag, WaitStatus},
         unistd::{ForkResult, Pid},
     };
+    use porkg_private::io::{pair, SocketOptions};
 
     use super::{CloneFlags, CloneSyscall as _};
     use porkg_test::{fork_test, init_test_logging};
@@ -390,7 +429,7 @@ mod tests {
 
         // We need to use a channel so that the forked process can pass the pid
         // of the sibling process to the testing process.
-        let (mut child_socket, mut server_socket) = UnixStream::pair()?;
+        let (mut child_socket, mut server_socket) = pair(SocketOptions::default())?;
 
         match unsafe { nix::unistd::fork() }? {
             ForkResult::Parent { child } => {
@@ -10,7 +10,10 @@ use nix::{
 use procfs::process::MountOptFields;
 use std::{
     ffi::OsStr,
-    os::unix::prelude::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd},
+    os::unix::{
+        fs::PermissionsExt as _,
+        prelude::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd},
+    },
     path::{Path, PathBuf},
 };
 use thiserror::Error;
@@ -67,6 +70,32 @@ pub enum DeviceKind {
     Ptmx,
 }
 
+impl DeviceKind {
+    /// The devices that are created in `/dev` of the sandbox (see [`setup_dev`]). `ptmx` links to the `devpts` instance
+    /// of the sandbox instead.
+    pub const STANDARD: [DeviceKind; 6] = [
+        DeviceKind::Null,
+        DeviceKind::Zero,
+        DeviceKind::Full,
+        DeviceKind::Random,
+        DeviceKind::URandom,
+        DeviceKind::Tty,
+    ];
+
+    /// The name of the device in `/dev`.
+    pub fn name(self) -> &'static str {
+        match self {
+            DeviceKind::Null => "null",
+            DeviceKind::Zero => "zero",
+            DeviceKind::Full => "full",
+            DeviceKind::Random => "random",
+            DeviceKind::URandom => "urandom",
+            DeviceKind::Tty => "tty",
+            DeviceKind::Ptmx => "ptmx",
+        }
+    }
+}
+
 impl From<DeviceKind> for SFlag {
     #[inline]
     fn from(value: DeviceKind) -> Self {
@@ -110,6 +139,10 @@ bitflags::bitflags! {
     pub struct MountFlags: u64 {
         /// Mount read-only
         const READ_ONLY = MsFlags::MS_RDONLY.bits();
+        /// Ignore set-user-ID and set-group-ID bits.
+        const NO_SUID = MsFlags::MS_NOSUID.bits();
+        /// Do not allow programs to be executed.
+        const NO_EXEC = MsFlags::MS_NOEXEC.bits();
         /// Do not update access times.
         const NO_ATIME = MsFlags::MS_NOATIME.bits();
         /// Do not update access times for directories.
@@ -153,6 +186,30 @@ bitflags::bitflags! {
     }
 }
 
+#[derive(Debug, Clone, Error)]
+#[error("failed to create the device {path:?}: {source}")]
+pub struct DeviceError {
+    path: PathBuf,
+    #[source]
+    source: Errno,
+}
+
+#[derive(Debug, Error)]
+pub enum SetupDevError {
+    #[error(transparent)]
+    Mount(#[from] MountError),
+    #[error(transparent)]
+    Bind(#[from] BindError),
+    #[error(transparent)]
+    Device(#[from] DeviceError),
+    #[error("failed to populate {path:?}: {source}")]
+    IO {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+}
+
 #[derive(Debug, Clone, Error)]
 #[error("failed to unmount {path:?}: {source}")]
 pub struct UnmountError {
@@ -209,6 +266,9 @@ pub trait FsSyscall {
 
     fn unmount(path: impl AsRef<Path>, flags: UnmountFlags) -> Result<(), UnmountError>;
 
+    /// Creates the node of a device, which is only permitted with `CAP_MKNOD` in the initial user namespace.
+    fn make_device(path: impl AsRef<Path>, kind: DeviceKind) -> Result<(), DeviceError>;
+
     fn pivot(new_root: impl AsRef<Path>) -> Result<(), PivotError>;
 }
 
@@ -259,6 +319,27 @@ impl FsSyscall for Syscall {
             })
     }
 
+    #[tracing::instrument(skip_all, fields(
+        path = ?path.as_ref(),
+        ?kind,
+    ))]
+    fn make_device(path: impl AsRef<Path>, kind: DeviceKind) -> Result<(), DeviceError> {
+        let path = path.as_ref();
+
+        nix::sys::stat::mknod(
+            path,
+            kind.into(),
+            Mode::from_bits_truncate(0o666),
+            kind.into(),
+        )
+        .inspect_err(|error| tracing::debug!(?error, "failed to create device"))
+        .inspect(|_| tracing::trace!("created device"))
+        .map_err(|source| DeviceError {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
     #[tracing::instrument(skip_all, fields(
         path = ?new_root.as_ref(),
     ))]
@@ -417,6 +498,73 @@ impl FsSyscall for Syscall {
     }
 }
 
+/// Populates `dev` (the `/dev` of a new root file system, before pivoting to it) on a new tmpfs, with the standard
+/// devices, a new `devpts` instance for pseudo-terminals, and the usual links into `/proc`.
+///
+/// Devices can't be created in a user namespace, in which case the devices of the host are bound instead.
+#[tracing::instrument(err(level = "debug"))]
+pub fn setup_dev<S: FsSyscall>(dev: &Path) -> Result<(), SetupDevError> {
+    let io = |path: &Path| {
+        let path = path.to_path_buf();
+        move |source| SetupDevError::IO { path, source }
+    };
+
+    std::fs::create_dir_all(dev).map_err(io(dev))?;
+    S::mount(
+        Some("tmpfs"),
+        dev,
+        Some(MountKind::TmpFs),
+        MountFlags::NO_SUID,
+        Some("mode=0755"),
+    )?;
+
+    for kind in DeviceKind::STANDARD {
+        let path = dev.join(kind.name());
+        match S::make_device(&path, kind) {
+            // The umask of the worker applies to the node.
+            Ok(()) => std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o666))
+                .map_err(io(&path))?,
+            Err(DeviceError {
+                source: Errno::EPERM,
+                ..
+            }) => {
+                std::fs::File::create(&path).map_err(io(&path))?;
+                S::bind(
+                    Path::new("/dev").join(kind.name()),
+                    &path,
+                    BindFlags::empty(),
+                )?;
+            }
+            Err(error) => return Err(error.into()),
+        }
+    }
+
+    let pts = dev.join("pts");
+    std::fs::create_dir(&pts).map_err(io(&pts))?;
+    S::mount(
+        Some("devpts"),
+        &pts,
+        Some(MountKind::DevPts),
+        MountFlags::NO_SUID | MountFlags::NO_EXEC,
+        Some("newinstance,ptmxmode=0666,mode=0620"),
+    )?;
+
+    let shm = dev.join("shm");
+    std::fs::create_dir(&shm).map_err(io(&shm))?;
+    for (link, target) in [
+        ("ptmx", "pts/ptmx"),
+        ("fd", "/proc/self/fd"),
+        ("stdin", "/proc/self/fd/0"),
+        ("stdout", "/proc/self/fd/1"),
+        ("stderr", "/proc/self/fd/2"),
+    ] {
+        let link = dev.join(link);
+        std::os::unix::fs::symlink(target, &link).map_err(io(&link))?;
+    }
+    tracing::trace!(?dev, "populated the devices");
+    Ok(())
+}
+
 pub fn has_existing_shared_mount(path: &Path) -> Option<bool> {
     // Don't bail on errors, it's not the end of the world if we make a superfluous bind mount.
     let myself = procfs::process::Process::myself().ok()?;
@@ -536,6 +684,35 @@ mod tests {
         Ok(())
     }
 
+    #[fork_test]
+    #[test]
+    fn populate_dev() -> Result {
+        init_test_logging();
+        let pid = Pid::this().as_raw();
+
+        crate::test::as_root(
+            Box::new(move || {
+                let dev = PathBuf::from(format!("/tmp/tmp_dev_{pid}"));
+                setup_dev::<Syscall>(&dev).context("when populating the devices")?;
+
+                std::fs::write(dev.join("null"), "discarded").context("when writing to null")?;
+                let mut zeros = [1u8; 4];
+                std::io::Read::read_exact(
+                    &mut std::fs::File::open(dev.join("zero")).context("when opening zero")?,
+                    &mut zeros,
+                )
+                .context("when reading from zero")?;
+                assert_eq!(zeros, [0; 4]);
+                std::fs::File::open(dev.join("ptmx")).context("when opening ptmx")?;
+
+                Ok(())
+            }),
+            CloneFlags::NEWNS | CloneFlags::NEWUSER | CloneFlags::NEWPID,
+        )?;
+
+        Ok(())
+    }
+
     #[fork_test]
     #[test]
     fn test_bind() -> Result {
@@ -1,10 +1,31 @@
+//! The sandboxes that porkg builds and runs packages in, which rely on Linux namespaces, cgroups and seccomp. On other
+//! platforms the crate only has `Unsupported`, so that the rest of the workspace still builds there.
+
+#[cfg(target_os = "linux")]
+mod cgroup;
+#[cfg(target_os = "linux")]
 mod clone;
+#[cfg(target_os = "linux")]
 mod fs;
+#[cfg(target_os = "linux")]
+mod net;
+#[cfg(target_os = "linux")]
 mod proc;
+#[cfg(target_os = "linux")]
 pub mod sandbox;
+#[cfg(target_os = "linux")]
+mod seccomp;
 
+#[cfg(target_os = "linux")]
 use private::{Syscall, NO_PATH};
 
+/// The error that is reported on platforms other than Linux, where packages can't be sandboxed.
+#[cfg(not(target_os = "linux"))]
+#[derive(Debug, Clone, Copy, thiserror::Error)]
+#[error("porkg sandboxes packages with Linux namespaces, so the daemon only runs on Linux")]
+pub struct Unsupported;
+
+#[cfg(target_os = "linux")]
 pub mod private {
     use std::path::Path;
 
@@ -12,18 +33,16 @@ pub mod private {
     pub const NO_PATH: Option<&Path> = None::<&Path>;
 }
 
-#[cfg(test)]
+#[cfg(all(test, target_os = "linux"))]
 mod test {
-    use std::{
-        io::{ErrorKind, Read as _, Write as _},
-        os::unix::net::UnixStream,
-    };
+    use std::io::{ErrorKind, Read as _, Write as _};
 
     use anyhow::{bail, Context as _};
     use nix::{
         sys::wait::{waitpid, WaitPidFlag, WaitStatus},
         unistd::{setresgid, setresuid, Gid, Uid},
     };
+    use porkg_private::io::{pair, SocketOptions};
 
     use crate::{
         clone::{CloneFlags, CloneSyscall as _},
@@ -36,7 +55,8 @@ mod test {
     ) -> anyhow::Result<()> {
         let my_uid = Uid::current().as_raw();
         let my_gid = Gid::current().as_raw();
-        let (mut outer, mut inner) = UnixStream::pair().context("when creating socket")?;
+        let (mut outer, mut inner) =
+            pair(SocketOptions::default()).context("when creating socket")?;
 
         let pid = Syscall::clone(
             Box::new(move || {
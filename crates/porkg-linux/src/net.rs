@@ -0,0 +1,64 @@
+use std::os::fd::{AsRawFd as _, FromRawFd as _, OwnedFd};
+
+use nix::{errno::Errno, libc};
+use thiserror::Error;
+
+use crate::Syscall;
+
+const LOOPBACK: &[u8] = b"lo";
+
+#[derive(Debug, Clone, Error)]
+#[error("failed to bring up the loopback interface: {source}")]
+pub struct LoopbackError {
+    #[source]
+    #[from]
+    source: Errno,
+}
+
+/// The parts of `struct ifreq` that are used to change the flags of an interface.
+#[repr(C)]
+struct InterfaceFlags {
+    name: [u8; libc::IFNAMSIZ],
+    flags: libc::c_short,
+    // The union of the request is larger than the flags.
+    _padding: [u8; 24 - std::mem::size_of::<libc::c_short>()],
+}
+
+/// Syscalls related to the network namespace of the current process.
+pub trait NetSyscall {
+    /// Brings up the loopback interface, which starts out down in new network namespaces.
+    fn loopback_up() -> Result<(), LoopbackError>;
+}
+
+impl NetSyscall for Syscall {
+    #[tracing::instrument(err(level = "debug"))]
+    fn loopback_up() -> Result<(), LoopbackError> {
+        let socket = Errno::result(unsafe {
+            libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0)
+        })?;
+        let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+
+        let mut request = InterfaceFlags {
+            name: [0; libc::IFNAMSIZ],
+            flags: 0,
+            _padding: [0; 24 - std::mem::size_of::<libc::c_short>()],
+        };
+        request.name[..LOOPBACK.len()].copy_from_slice(LOOPBACK);
+        Errno::result(unsafe {
+            libc::ioctl(
+                socket.as_raw_fd(),
+                libc::SIOCGIFFLAGS,
+                &mut request as *mut InterfaceFlags,
+            )
+        })?;
+        request.flags |= libc::IFF_UP as libc::c_short;
+        Errno::result(unsafe {
+            libc::ioctl(
+                socket.as_raw_fd(),
+                libc::SIOCSIFFLAGS,
+                &request as *const InterfaceFlags,
+            )
+        })?;
+        Ok(())
+    }
+}
@@ -1,36 +1,76 @@
 use std::{
     fmt,
+    hash::{Hash as _, Hasher as _},
     io::{Read as _, Write as _},
     marker::PhantomData,
     os::{
-        fd::OwnedFd,
-        unix::{net::UnixStream, prelude::RawFd},
+        fd::{AsFd as _, AsRawFd as _, FromRawFd as _, OwnedFd},
+        unix::{fs::MetadataExt as _, net::UnixStream, prelude::RawFd, process::CommandExt as _},
     },
-    sync::Arc,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    process::{Command, Stdio},
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
 };
 
 use anyhow::Context as _;
 use async_lock::Mutex;
+use nix::{
+    errno::Errno,
+    fcntl::{fcntl, FcntlArg, FdFlag, OFlag},
+    libc,
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    sys::{
+        signal::{kill, Signal},
+        stat::Mode,
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{dup2, pipe2, Pid},
+};
 use porkg_private::{
-    io::{DomainSocket, DomainSocketAsync as _, DomainSocketAsyncExt, SocketMessageError},
+    crash,
+    io::{
+        self, DomainSocket, DomainSocketAsync as _, DomainSocketAsyncExt, Keepalive, SendOptions,
+        SocketMessageError, SocketOptions,
+    },
     os::proc::{ChildProcess, IntoExitCode},
-    sandbox::{SandboxOptions, SandboxTask},
+    sandbox::{Bind, SandboxBackend, SandboxFlags, SandboxOptions, SandboxTask, TaskChain},
 };
 use thiserror::Error;
-use tokio::net::UnixStream as UnixStreamAsync;
+use tokio::{
+    net::UnixStream as UnixStreamAsync,
+    sync::{mpsc, watch},
+};
 
 use crate::{
+    cgroup::{Cgroups, TaskCgroup},
     clone::{CloneError, CloneFlags, CloneSyscall},
+    fs::{
+        setup_dev, BindError, BindFlags, FsSyscall, MountError, MountFlags, MountKind, PivotError,
+        SetupDevError,
+    },
+    net::{LoopbackError, NetSyscall},
     private::Syscall,
     proc::{IdMapping, IdMappingTools, ProcSyscall},
+    seccomp::{SeccompError, SeccompSyscall},
 };
 
+pub mod oci;
+mod task;
+
+pub use task::{OutputStream, SandboxTaskError, SandboxTaskHandle, TaskStatus};
+
+use task::{Exited, Tasks};
+
 #[derive(Debug, Error)]
 pub enum StartControllerProcessError {
     #[error(transparent)]
     IO(#[from] std::io::Error),
     #[error(transparent)]
     Clone(#[from] CloneError),
+    #[error("the sandbox zygote ({zygote}) is incompatible with the daemon ({controller}), was the daemon upgraded while it was running?")]
+    Incompatible { controller: Hello, zygote: Hello },
 }
 
 #[derive(Debug, Error)]
@@ -45,6 +85,8 @@ pub enum CreateSandboxError {
     IO(#[from] std::io::Error),
     #[error(transparent)]
     Serialization(#[from] porkg_private::ser::Error),
+    #[error("the sandbox zygote did not respond within {0:?}")]
+    Timeout(Duration),
 }
 
 impl From<SocketMessageError> for CreateSandboxError {
@@ -52,30 +94,158 @@ impl From<SocketMessageError> for CreateSandboxError {
         match value {
             SocketMessageError::IO(i) => Self::IO(i),
             SocketMessageError::Serialize(i) => Self::Serialization(i),
+            SocketMessageError::Timeout(i) => Self::Timeout(i),
         }
     }
 }
 
+/// The umask of tasks, so that the permissions of the files that they create don't depend on the umask that the daemon
+/// was started with.
+const TASK_UMASK: u32 = 0o022;
+
 const CMD_HELLO: u8 = 0x1;
 const CMD_START: u8 = 0x2;
+/// Echoed back by the zygote once it has handled the commands before it.
+const CMD_PING: u8 = 0x3;
+/// Sent by the zygote when the worker of a task has exited, followed by [`Exited`].
+const EVT_EXITED: u8 = 0x4;
+/// Followed by the id of the task whose worker the zygote kills.
+const CMD_KILL: u8 = 0x5;
+/// Sent by the zygote once it has started the worker of a task, followed by the id of the task along with the read ends
+/// of the pipes of its stdout and stderr.
+const EVT_OUTPUT: u8 = 0x6;
+/// Sent by the zygote when it panics, followed by the message of the panic. The zygote exits afterwards.
+const EVT_PANICKED: u8 = 0x7;
+
+/// Set in the environment of the daemon when it was executed as the zygote (see [`zygote`]).
+const ZYGOTE_ENV: &str = "PORKG_ZYGOTE";
+/// The cgroup that the zygote creates the cgroups of tasks in, if any.
+const ZYGOTE_CGROUPS_ENV: &str = "PORKG_ZYGOTE_CGROUPS";
+/// The socket that an executed zygote is connected to the daemon with, which is the first fd after the standard
+/// streams.
+const ZYGOTE_FD: RawFd = 3;
+
+/// The version of the messages exchanged with the zygote, which must be incremented whenever they change.
+const PROTOCOL_VERSION: u32 = 7;
+const HELLO_SIZE: usize = 16;
+
+bitflags::bitflags! {
+    /// The optional parts of the protocol that one side of the connection to the zygote supports. Only the features
+    /// that both sides support are used.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct Features: u32 {
+        /// Large tasks are compressed (see [`SendOptions::compressed`]).
+        const COMPRESSION = 0x1;
+    }
+}
+
+/// Identifies the protocol and build of one side of the connection to the zygote. Both sides must run the same
+/// protocol and build.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hello {
+    pub protocol: u32,
+    /// A hash of the version and identity of the executable.
+    pub build: u64,
+    pub features: Features,
+}
+
+impl Hello {
+    fn current() -> std::io::Result<Self> {
+        let exe = std::fs::metadata("/proc/self/exe")?;
+        let mut hasher = std::hash::DefaultHasher::new();
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+        (
+            exe.dev(),
+            exe.ino(),
+            exe.size(),
+            exe.mtime(),
+            exe.mtime_nsec(),
+        )
+            .hash(&mut hasher);
+        Ok(Self {
+            protocol: PROTOCOL_VERSION,
+            build: hasher.finish(),
+            features: Features::all(),
+        })
+    }
+
+    fn is_compatible(&self, other: &Self) -> bool {
+        self.protocol == other.protocol && self.build == other.build
+    }
+
+    fn to_bytes(self) -> [u8; HELLO_SIZE] {
+        let mut result = [0u8; HELLO_SIZE];
+        result[..4].copy_from_slice(&self.protocol.to_le_bytes());
+        result[4..12].copy_from_slice(&self.build.to_le_bytes());
+        result[12..].copy_from_slice(&self.features.bits().to_le_bytes());
+        result
+    }
+
+    fn from_bytes(value: [u8; HELLO_SIZE]) -> Self {
+        Self {
+            protocol: u32::from_le_bytes(value[..4].try_into().unwrap()),
+            build: u64::from_le_bytes(value[4..12].try_into().unwrap()),
+            // Features that this side doesn't know about are never used.
+            features: Features::from_bits_truncate(u32::from_le_bytes(
+                value[12..].try_into().unwrap(),
+            )),
+        }
+    }
+}
 
-fn make_async(s: UnixStream) -> std::io::Result<UnixStreamAsync> {
-    s.set_nonblocking(true)?;
-    UnixStreamAsync::from_std(s)
+impl fmt::Display for Hello {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "protocol {}, build {:016x}", self.protocol, self.build)
+    }
 }
 
 #[derive(Debug)]
 pub struct SandboxProcess<T: SandboxTask, S: CloneSyscall + ProcSyscall = Syscall> {
     stream: UnixStream,
     proc: ChildProcess,
+    /// The features that both sides support, once the handshake has completed.
+    features: Features,
+    cgroups: Option<Cgroups>,
     _p: PhantomData<(T, S)>,
 }
 
-impl<T: SandboxTask, S: CloneSyscall + ProcSyscall> SandboxProcess<T, S> {
-    #[tracing::instrument]
+impl<
+        T: SandboxTask,
+        S: CloneSyscall + ProcSyscall + FsSyscall + NetSyscall + SeccompSyscall + 'static,
+    > SandboxProcess<T, S>
+{
+    /// Starts the zygote and checks that it runs the same build as the daemon. An incompatible zygote is replaced
+    /// once before giving up.
     pub fn start() -> Result<Self, StartControllerProcessError> {
+        Self::start_with(TaskChain::default())
+    }
+
+    /// Like [`SandboxProcess::start`], but tasks are executed through the middleware of `chain`.
+    #[tracing::instrument]
+    pub fn start_with(chain: TaskChain<T>) -> Result<Self, StartControllerProcessError> {
+        // Tasks with resource limits fail to start without cgroups.
+        let cgroups = Cgroups::delegate()
+            .inspect_err(|error| tracing::warn!(?error, "resource limits are unavailable"))
+            .ok();
+        let mut process = Self::spawn(cgroups.clone(), chain.clone())?;
+        match process.handshake() {
+            Err(StartControllerProcessError::Incompatible { controller, zygote }) => {
+                tracing::warn!(%controller, %zygote, "zygote is incompatible, restarting it");
+                drop(process);
+                let mut process = Self::spawn(cgroups, chain)?;
+                process.handshake()?;
+                Ok(process)
+            }
+            result => result.map(|_| process),
+        }
+    }
+
+    fn spawn(
+        cgroups: Option<Cgroups>,
+        chain: TaskChain<T>,
+    ) -> Result<Self, StartControllerProcessError> {
         let tools = S::find_tools();
-        let (parent, child) = UnixStream::pair()
+        let (parent, child) = io::pair(SocketOptions::default())
             .inspect(|_| tracing::trace!("created socket pair for controller communication"))
             .inspect_err(|error| {
                 tracing::error!(
@@ -84,9 +254,9 @@ impl<T: SandboxTask, S: CloneSyscall + ProcSyscall> SandboxProcess<T, S> {
                 )
             })?;
 
-        let cb = move || match child.try_clone() {
-            Ok(child) => zygote_main::<T, S>(child, tools.clone()),
-            Err(e) => Err(anyhow::anyhow!("failed to clone child socket: {0}", e)),
+        let zygote_cgroups = cgroups.clone();
+        let cb = move || {
+            run_zygote::<T, S>(&child, tools.clone(), zygote_cgroups.clone(), chain.clone())
         };
 
         let zygote: ChildProcess = S::clone(cb, CloneFlags::empty())
@@ -97,32 +267,278 @@ impl<T: SandboxTask, S: CloneSyscall + ProcSyscall> SandboxProcess<T, S> {
         Ok(Self {
             stream: parent,
             proc: zygote,
+            features: Features::empty(),
+            cgroups,
             _p: PhantomData,
         })
     }
 
-    #[tracing::instrument(skip_all)]
+    /// Starts the zygote by executing the daemon again instead of cloning it, which is safe once the daemon has
+    /// threads. The daemon must call [`zygote`] as it starts.
+    fn exec(cgroups: Option<Cgroups>) -> Result<Self, StartControllerProcessError> {
+        let (parent, child) = io::pair(SocketOptions::default())?;
+        let child_fd = child.as_raw_fd();
+        // The executable of the running daemon, even if it was replaced since.
+        let mut command = Command::new("/proc/self/exe");
+        command.env(ZYGOTE_ENV, "1").stdin(Stdio::null());
+        if let Some(cgroups) = &cgroups {
+            command.env(ZYGOTE_CGROUPS_ENV, cgroups.root());
+        }
+        unsafe {
+            command.pre_exec(move || {
+                // The copy isn't closed when the daemon is executed.
+                if libc::dup2(child_fd, ZYGOTE_FD) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let zygote = command
+            .spawn()
+            .inspect_err(|error| tracing::error!(?error, "failed to execute the zygote"))?;
+        drop(child);
+        tracing::trace!(pid = zygote.id(), "executed the zygote");
+
+        Ok(Self {
+            stream: parent,
+            proc: Pid::from_raw(zygote.id() as i32).into(),
+            features: Features::empty(),
+            cgroups,
+            _p: PhantomData,
+        })
+    }
+
+    fn handshake(&mut self) -> Result<(), StartControllerProcessError> {
+        let controller = Hello::current()?;
+        self.stream
+            .send_all(&mut &[CMD_HELLO][..], &[])
+            .and_then(|_| self.stream.send_all(&mut &controller.to_bytes()[..], &[]))
+            .inspect(|_| tracing::trace!("sent hello message"))
+            .inspect_err(|error| tracing::trace!(?error, "failed to send hello message"))?;
+
+        let mut buf = [0u8; HELLO_SIZE];
+        self.stream
+            .recv_exact(&mut &mut buf[..], &mut Vec::new())
+            .inspect_err(|error| tracing::error!(?error, "failed to receive hello from zygote"))?;
+        let zygote = Hello::from_bytes(buf);
+
+        if !zygote.is_compatible(&controller) {
+            return Err(StartControllerProcessError::Incompatible { controller, zygote });
+        }
+        self.features = controller.features & zygote.features;
+        tracing::trace!(%zygote, features = ?self.features, "zygote is compatible");
+        Ok(())
+    }
+
     pub async fn connect(self) -> Result<SandboxController<T, S>, ConnectControllerError> {
-        let stream = make_async(self.stream)
+        self.connect_with(Keepalive::default()).await
+    }
+
+    /// Connects to the zygote, which is checked at the interval of `keepalive` while it is idle.
+    #[tracing::instrument(skip_all)]
+    pub async fn connect_with(
+        self,
+        keepalive: Keepalive,
+    ) -> Result<SandboxController<T, S>, ConnectControllerError> {
+        let state = Arc::new(Mutex::new(self.into_state(keepalive, 0, 0)?));
+        tokio::spawn(heartbeat(Arc::downgrade(&state), keepalive.interval, 0));
+        Ok(SandboxController(state))
+    }
+
+    /// Reads the events of the zygote, which number the tasks from `next_id`.
+    fn into_state(
+        self,
+        keepalive: Keepalive,
+        next_id: u64,
+        generation: u64,
+    ) -> std::io::Result<State<T, S>> {
+        let stream = io::into_async(self.stream)
+            .map(Arc::new)
             .inspect_err(|error| tracing::error!(?error, "failed to make socket async"))?;
-        stream
-            .send_all(&mut &[CMD_HELLO][..], &[])
-            .await
-            .inspect(|_| tracing::trace!("sent connect message"))
-            .inspect_err(|error| tracing::trace!(?error, "failed to send connect message"))?;
-        let state = Arc::new(Mutex::new(State {
+        let tasks = Tasks::default();
+        let (pongs, pinged) = mpsc::unbounded_channel();
+        let down = Arc::new(watch::channel(None).0);
+        tokio::spawn({
+            let (stream, tasks, down) = (stream.clone(), tasks.clone(), down.clone());
+            async move {
+                let error = task::read_events(stream, tasks, pongs).await;
+                report_down(&down, format!("lost the connection to the zygote: {error}"));
+            }
+        });
+        Ok(State {
             stream,
+            tasks,
+            next_id,
+            pongs: pinged,
+            features: self.features,
+            keepalive,
+            timed_out: false,
+            down,
+            cgroups: self.cgroups,
+            generation,
             _proc: self.proc,
             _p: PhantomData,
-        }));
-        Ok(SandboxController(state))
+        })
+    }
+}
+
+/// Runs the zygote if the process was executed as one (see [`SandboxController::restart`]), and exits once the daemon
+/// disconnects. Otherwise, returns immediately.
+pub fn zygote<T: SandboxTask>(chain: &TaskChain<T>) {
+    if std::env::var_os(ZYGOTE_ENV).is_none() {
+        return;
+    }
+    let cgroups = std::env::var_os(ZYGOTE_CGROUPS_ENV).map(|v| Cgroups::from_root(v.into()));
+    // Workers are cloned from the zygote, and must not take themselves for one.
+    std::env::remove_var(ZYGOTE_ENV);
+    std::env::remove_var(ZYGOTE_CGROUPS_ENV);
+    // Tasks that execute programs must not inherit the socket.
+    if let Err(error) = fcntl(ZYGOTE_FD, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+        tracing::error!(?error, "failed to close the socket of the zygote on exec");
+        std::process::exit(-1);
+    }
+    let host = unsafe { UnixStream::from_raw_fd(ZYGOTE_FD) };
+    let result = run_zygote::<T, Syscall>(&host, Syscall::find_tools(), cgroups, chain.clone());
+    std::process::exit(result.report())
+}
+
+/// Runs the zygote, notifying the controller if it panics.
+fn run_zygote<
+    T: SandboxTask,
+    S: CloneSyscall + ProcSyscall + FsSyscall + NetSyscall + SeccompSyscall,
+>(
+    host: &UnixStream,
+    tools: IdMappingTools,
+    cgroups: Option<Cgroups>,
+    chain: TaskChain<T>,
+) -> anyhow::Result<()> {
+    crash::set_process("zygote");
+    let result = panic::catch_unwind(AssertUnwindSafe(|| match host.try_clone() {
+        Ok(host) => zygote_main::<T, S>(host, tools, cgroups, chain),
+        Err(e) => Err(anyhow::anyhow!("failed to clone child socket: {0}", e)),
+    }));
+    result.unwrap_or_else(|payload| {
+        let message = crash::message(&*payload);
+        // The controller fails to read the event if the zygote panicked while sending a message, but it loses the
+        // connection either way.
+        if host.send_all(&mut &[EVT_PANICKED][..], &[]).is_ok() {
+            host.send_message(&message, &[]).ok();
+        }
+        Err(anyhow::anyhow!("the zygote panicked: {message}"))
+    })
+}
+
+/// Records why the zygote is down, unless it already was.
+fn report_down(down: &watch::Sender<Option<String>>, reason: String) {
+    down.send_if_modified(|v| {
+        if v.is_some() {
+            return false;
+        }
+        tracing::error!(reason, "the sandbox zygote is down");
+        *v = Some(reason);
+        true
+    });
+}
+
+/// Pings the zygote while the controller is idle, so that a zygote that is stuck is noticed before the next task. Stops
+/// once the zygote of `generation` has been replaced.
+async fn heartbeat<T: SandboxTask, S: CloneSyscall + ProcSyscall>(
+    state: Weak<Mutex<State<T, S>>>,
+    interval: Duration,
+    generation: u64,
+) {
+    loop {
+        tokio::time::sleep(interval).await;
+
+        let Some(state) = state.upgrade() else {
+            return;
+        };
+        let mut state = state.lock_arc().await;
+        if state.generation != generation || state.timed_out {
+            return;
+        }
+        if let Err(error) = state.ping().await {
+            report_down(
+                &state.down,
+                format!("the zygote is not responding: {error}"),
+            );
+            return;
+        }
     }
 }
 
 struct State<T: SandboxTask, S: CloneSyscall + ProcSyscall = Syscall> {
-    stream: UnixStreamAsync,
+    /// Written to with the state locked, and read from by [`task::read_events`].
+    stream: Arc<UnixStreamAsync>,
+    tasks: Tasks,
+    next_id: u64,
+    /// Receives the answers to pings.
+    pongs: mpsc::UnboundedReceiver<()>,
+    features: Features,
+    keepalive: Keepalive,
+    /// Set once an exchange with the zygote timed out, after which the stream may be in the middle of a message.
+    timed_out: bool,
+    /// Why the zygote is down, once it is.
+    down: Arc<watch::Sender<Option<String>>>,
+    cgroups: Option<Cgroups>,
+    /// The number of times that the zygote was restarted.
+    generation: u64,
     _proc: ChildProcess,
-    _p: PhantomData<(T, S)>,
+    // Only marks the types of the tasks, which the state doesn't hold, so that it can be sent to the heartbeat.
+    _p: PhantomData<fn() -> (T, S)>,
+}
+
+impl<T: SandboxTask, S: CloneSyscall + ProcSyscall> State<T, S> {
+    /// Fails if the zygote has stopped responding, now or before.
+    async fn ping(&mut self) -> Result<(), CreateSandboxError> {
+        self.check()?;
+        let stream = &self.stream;
+        let pongs = &mut self.pongs;
+        let result = self
+            .keepalive
+            .deadline(async {
+                stream.send_all(&mut &[CMD_PING][..], &[]).await?;
+                pongs.recv().await.ok_or_else(|| {
+                    SocketMessageError::from(std::io::Error::from(
+                        std::io::ErrorKind::UnexpectedEof,
+                    ))
+                })
+            })
+            .await;
+        self.record(result)
+    }
+
+    fn check(&self) -> Result<(), CreateSandboxError> {
+        if self.timed_out {
+            return Err(CreateSandboxError::Timeout(self.keepalive.timeout));
+        }
+        Ok(())
+    }
+
+    fn record<R>(
+        &mut self,
+        result: Result<R, SocketMessageError>,
+    ) -> Result<R, CreateSandboxError> {
+        if let Err(SocketMessageError::Timeout(timeout)) = &result {
+            self.timed_out = true;
+            report_down(
+                &self.down,
+                format!("the zygote did not respond within {timeout:?}"),
+            );
+        }
+        result.map_err(CreateSandboxError::from)
+    }
+}
+
+/// The state of the zygote, as reported to operators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct ZygoteStatus {
+    /// Whether the zygote answered every exchange in time.
+    pub responding: bool,
+    /// The number of tasks that haven't finished.
+    pub tasks: usize,
+    /// The number of times that the zygote was restarted.
+    pub restarts: u64,
 }
 
 pub struct SandboxController<T: SandboxTask, S: CloneSyscall + ProcSyscall = Syscall>(
@@ -140,6 +556,9 @@ impl<T: SandboxTask, S: CloneSyscall + ProcSyscall> std::fmt::Debug for SandboxC
         let v = self.0.lock_arc_blocking();
         f.debug_struct("SandboxController")
             .field("stream", &v.stream)
+            .field("features", &v.features)
+            .field("keepalive", &v.keepalive)
+            .field("timed_out", &v.timed_out)
             .field("_proc", &v._proc)
             .field("_p", &v._p)
             .finish()
@@ -147,38 +566,227 @@ impl<T: SandboxTask, S: CloneSyscall + ProcSyscall> std::fmt::Debug for SandboxC
 }
 
 impl<T: SandboxTask, S: CloneSyscall + ProcSyscall> SandboxController<T, S> {
+    /// Starts a task in a new sandbox, returning a handle that completes once the task has finished.
     #[tracing::instrument(skip_all)]
-    pub async fn spawn_async(&self, task: T, fds: &[RawFd]) -> Result<(), CreateSandboxError> {
+    pub async fn spawn_async(
+        &self,
+        task: T,
+        fds: &[RawFd],
+    ) -> Result<SandboxTaskHandle, CreateSandboxError> {
+        let mut state = self.0.lock_arc().await;
+        state.check()?;
+        let id = state.next_id;
+        state.next_id += 1;
+        let options = SendOptions {
+            // Tasks can carry secrets, such as credentials that are passed on to builds.
+            secret: true,
+            ..Default::default()
+        }
+        .compressed(state.features.contains(Features::COMPRESSION));
+
+        let handle = state.tasks.register(id);
+        let stream = &state.stream;
+        let result = state
+            .keepalive
+            .deadline(async {
+                let mut command = [0u8; 9];
+                command[0] = CMD_START;
+                command[1..].copy_from_slice(&id.to_le_bytes());
+                stream
+                    .send_all(&mut &command[..], &[])
+                    .await
+                    .inspect_err(|error| tracing::trace!(?error, "failed to send start message"))?;
+                stream
+                    .send_message_with(&task, fds, options)
+                    .await
+                    .inspect(|_| tracing::trace!("sent start message"))
+                    .inspect_err(|error| tracing::trace!(?error, "failed to send start message"))
+            })
+            .await;
+        if let Err(error) = state.record(result) {
+            state.tasks.remove(id);
+            return Err(error);
+        }
+
+        // The zygote answers once it has started the task.
+        state.ping().await?;
+        Ok(handle)
+    }
+
+    /// Reports the state of the zygote, without contacting it.
+    pub async fn status(&self) -> ZygoteStatus {
         let state = self.0.lock_arc().await;
-        state
-            .stream
-            .send_all(&mut &[CMD_START][..], &[])
-            .await
-            .inspect_err(|error| tracing::trace!(?error, "failed to send start message"))
-            .map_err(CreateSandboxError::from)?;
-        state
-            .stream
-            .send_message(&task, fds)
-            .await
-            .inspect(|_| tracing::trace!("sent start message"))
-            .inspect_err(|error| tracing::trace!(?error, "failed to send start message"))
-            .map_err(CreateSandboxError::from)?;
+        ZygoteStatus {
+            responding: !state.timed_out,
+            tasks: state.tasks.len(),
+            restarts: state.generation,
+        }
+    }
 
+    /// Waits until the zygote has exited or stopped responding, returning why.
+    pub async fn exited(&self) -> String {
+        let mut down = self.0.lock_arc().await.down.subscribe();
+        let reason = down.wait_for(Option::is_some).await;
+        match reason {
+            Ok(reason) => reason.clone().unwrap_or_default(),
+            Err(_) => "the zygote was replaced".to_string(),
+        }
+    }
+
+    /// Kills the worker of the task with `id`, whose handle completes once the zygote has reaped it.
+    #[tracing::instrument(skip(self))]
+    pub async fn kill(&self, id: u64) -> Result<(), CreateSandboxError> {
+        let mut state = self.0.lock_arc().await;
+        state.check()?;
+        let stream = &state.stream;
+        let result = state
+            .keepalive
+            .deadline(async {
+                let mut command = [0u8; 9];
+                command[0] = CMD_KILL;
+                command[1..].copy_from_slice(&id.to_le_bytes());
+                Ok(stream
+                    .send_all(&mut &command[..], &[])
+                    .await
+                    .inspect_err(|error| tracing::trace!(?error, "failed to send kill message"))?)
+            })
+            .await;
+        state.record(result)
+    }
+}
+
+impl<
+        T: SandboxTask,
+        S: CloneSyscall + ProcSyscall + FsSyscall + NetSyscall + SeccompSyscall + Send + 'static,
+    > SandboxController<T, S>
+{
+    /// Replaces the zygote with a new one. The tasks of the previous zygote are lost (see
+    /// [`SandboxTaskError::Lost`]).
+    ///
+    /// The zygote is executed instead of cloned, as the daemon has threads by now (see [`zygote`]).
+    #[tracing::instrument(skip(self))]
+    pub async fn restart(&self) -> Result<(), StartControllerProcessError> {
+        let mut state = self.0.lock_arc().await;
+        let cgroups = state.cgroups.clone();
+        let process = tokio::task::spawn_blocking(move || {
+            let mut process = SandboxProcess::<T, S>::exec(cgroups)?;
+            process.handshake()?;
+            Ok::<_, StartControllerProcessError>(process)
+        })
+        .await
+        .map_err(std::io::Error::other)??;
+
+        let generation = state.generation + 1;
+        let next = process.into_state(state.keepalive, state.next_id, generation)?;
+        let previous = std::mem::replace(&mut *state, next);
+        tokio::spawn(heartbeat(
+            Arc::downgrade(&self.0),
+            state.keepalive.interval,
+            generation,
+        ));
+        drop(state);
+        // Stopping the previous zygote blocks until it has exited.
+        tokio::task::spawn_blocking(move || drop(previous));
+        tracing::info!(generation, "restarted the sandbox zygote");
         Ok(())
     }
 }
 
-fn zygote_main<T: SandboxTask, S: CloneSyscall + ProcSyscall>(
+impl<T: SandboxTask, S: CloneSyscall + ProcSyscall + 'static> SandboxBackend<T>
+    for SandboxController<T, S>
+{
+    type Handle = SandboxTaskHandle;
+    type TaskError = SandboxTaskError;
+    type Error = CreateSandboxError;
+
+    async fn spawn(&self, task: T, fds: &[RawFd]) -> Result<Self::Handle, Self::Error> {
+        self.spawn_async(task, fds).await
+    }
+
+    async fn kill(&self, id: u64) -> Result<(), Self::Error> {
+        SandboxController::kill(self, id).await
+    }
+
+    fn id(handle: &Self::Handle) -> u64 {
+        handle.id()
+    }
+}
+
+fn zygote_main<
+    T: SandboxTask,
+    S: CloneSyscall + ProcSyscall + FsSyscall + NetSyscall + SeccompSyscall,
+>(
     host: UnixStream,
     tools: IdMappingTools,
+    cgroups: Option<Cgroups>,
+    chain: TaskChain<T>,
 ) -> anyhow::Result<()> {
     let mut cmd_buf = [0u8; 1];
     host.recv_exact(&mut &mut cmd_buf[..], &mut Vec::new())
         .context("while reading command from host")?;
+    anyhow::ensure!(
+        cmd_buf[0] == CMD_HELLO,
+        "expected hello, got {}",
+        cmd_buf[0]
+    );
+
+    let mut hello_buf = [0u8; HELLO_SIZE];
+    host.recv_exact(&mut &mut hello_buf[..], &mut Vec::new())
+        .context("while reading hello from host")?;
+    let controller = Hello::from_bytes(hello_buf);
+    let zygote = Hello::current().context("while identifying the build")?;
+    host.send_all(&mut &zygote.to_bytes()[..], &[])
+        .context("while sending hello to host")?;
+    anyhow::ensure!(
+        controller.is_compatible(&zygote),
+        "the daemon ({controller}) is incompatible with this zygote ({zygote})"
+    );
 
+    let mut workers = Vec::<Worker>::new();
     loop {
-        let mut fds = Vec::new();
+        let now = Instant::now();
+        let mut deadline = None::<Instant>;
+        for worker in workers.iter_mut().filter(|v| !v.timed_out) {
+            match worker.deadline {
+                Some(v) if v <= now => {
+                    tracing::debug!(id = worker.id, "the task timed out");
+                    worker.kill();
+                    worker.timed_out = true;
+                }
+                Some(v) => deadline = Some(deadline.map_or(v, |d| d.min(v))),
+                None => {}
+            }
+        }
+        let timeout = deadline.map_or(PollTimeout::NONE, |v| {
+            PollTimeout::try_from(v - now).unwrap_or(PollTimeout::MAX)
+        });
+
+        // The socket of a worker is closed when it exits.
+        let mut polls: Vec<_> = std::iter::once(host.as_fd())
+            .chain(workers.iter().map(|v| v.socket.as_fd()))
+            .map(|v| PollFd::new(v, PollFlags::POLLIN))
+            .collect();
+        match poll(&mut polls, timeout) {
+            Err(Errno::EINTR) => continue,
+            result => result.context("while waiting for the host and workers")?,
+        };
+        let ready: Vec<_> = polls
+            .iter()
+            .map(|v| v.revents().is_some_and(|v| !v.is_empty()))
+            .collect();
+        drop(polls);
 
+        for index in (1..ready.len()).rev() {
+            if ready[index] {
+                let exited = workers.swap_remove(index - 1).reap()?;
+                report(&host, &exited)?;
+            }
+        }
+        if !ready[0] {
+            continue;
+        }
+
+        let mut fds = Vec::new();
         host.recv_exact(&mut &mut cmd_buf[..], &mut fds)
             .context("while reading command from host")?;
 
@@ -186,43 +794,213 @@ fn zygote_main<T: SandboxTask, S: CloneSyscall + ProcSyscall>(
         match cmd_buf[0] {
             CMD_START => {
                 tracing::trace!("received start message");
+                let mut id = [0u8; 8];
+                host.recv_exact(&mut &mut id[..], &mut fds)
+                    .context("while reading the id of a task from the host")?;
+                let id = u64::from_le_bytes(id);
                 let task: T = host
-                    .recv_message(&mut fds)
+                    .recv_secret_message(&mut fds)
                     .context("while reading the task from the host")?;
                 let opts = task.create_sandbox_options();
-                start_worker::<T, S>(task, fds, opts, tools.clone())?;
+                match start_worker::<T, S>(
+                    id,
+                    task,
+                    fds,
+                    opts,
+                    tools.clone(),
+                    cgroups.as_ref(),
+                    chain.clone(),
+                ) {
+                    Ok((worker, output)) => {
+                        send_output(&host, id, output)?;
+                        workers.push(worker);
+                    }
+                    Err(error) => {
+                        tracing::error!(?error, id, "failed to start a worker");
+                        let exited = Exited {
+                            id,
+                            status: TaskStatus::NotStarted {
+                                error: format!("{error:#}"),
+                            },
+                        };
+                        report(&host, &exited)?;
+                    }
+                }
             }
+            CMD_KILL => {
+                let mut id = [0u8; 8];
+                host.recv_exact(&mut &mut id[..], &mut fds)
+                    .context("while reading the id of a task from the host")?;
+                let id = u64::from_le_bytes(id);
+                // The worker is reaped, and reported, once its socket closes.
+                match workers.iter().find(|v| v.id == id) {
+                    Some(worker) => worker.kill(),
+                    None => tracing::trace!(id, "the task to kill has already finished"),
+                }
+            }
+            CMD_PING => host
+                .send_all(&mut &[CMD_PING][..], &[])
+                .context("while answering a ping from the host")?,
             other => anyhow::bail!("unknown command {other}"),
         }
     }
 }
 
+/// Passes the read ends of the output of a task on to the host, which closes them in the zygote.
+fn send_output(host: &UnixStream, id: u64, output: [OwnedFd; 2]) -> anyhow::Result<()> {
+    host.send_all(&mut &[EVT_OUTPUT][..], &[])
+        .context("while sending the output of a task to the host")?;
+    // The fds are sent with the id, which is read separately from the event.
+    host.send_all(
+        &mut &id.to_le_bytes()[..],
+        &[output[0].as_raw_fd(), output[1].as_raw_fd()],
+    )
+    .context("while sending the output of a task to the host")?;
+    Ok(())
+}
+
+fn report(host: &UnixStream, exited: &Exited) -> anyhow::Result<()> {
+    host.send_all(&mut &[EVT_EXITED][..], &[])
+        .context("while reporting a task to the host")?;
+    host.send_message(exited, &[])
+        .context("while reporting a task to the host")?;
+    Ok(())
+}
+
+/// A worker that the zygote started, and must reap.
+struct Worker {
+    id: u64,
+    pid: Pid,
+    /// The zygote's side of the socket of the worker, which receives the error that the task failed with.
+    socket: UnixStream,
+    /// Limits the resources of the worker, if the task has limits, and kills all of its processes at once. Removed along
+    /// with the worker once it is reaped.
+    cgroup: Option<TaskCgroup>,
+    /// When the worker is killed, if the task has a timeout.
+    deadline: Option<Instant>,
+    timeout: Option<Duration>,
+    timed_out: bool,
+}
+
+impl Worker {
+    /// Kills the worker, which is the init process of its PID namespace, so every process of the task dies with it.
+    fn kill(&self) {
+        if let Some(cgroup) = &self.cgroup {
+            match cgroup.kill() {
+                Ok(()) => {
+                    tracing::trace!(id = self.id, pid = ?self.pid, "killed the cgroup of worker");
+                    return;
+                }
+                Err(error) => {
+                    tracing::debug!(
+                        ?error,
+                        id = self.id,
+                        "failed to kill the cgroup of a worker"
+                    )
+                }
+            }
+        }
+        match kill(self.pid, Signal::SIGKILL) {
+            Ok(()) | Err(Errno::ESRCH) => {
+                tracing::trace!(id = self.id, pid = ?self.pid, "killed worker")
+            }
+            Err(error) => tracing::error!(?error, id = self.id, "failed to kill a worker"),
+        }
+    }
+
+    /// Waits for the worker, which has closed its socket.
+    fn reap(mut self) -> anyhow::Result<Exited> {
+        let mut error = Vec::new();
+        // The worker writes the error before it exits, so only the remains of a broken worker could be left.
+        self.socket.read_to_end(&mut error).ok();
+        let status = match waitpid(self.pid, Some(WaitPidFlag::__WALL))
+            .with_context(|| format!("while waiting for worker {}", self.pid))?
+        {
+            WaitStatus::Exited(_, code) => TaskStatus::Exited {
+                code,
+                error: (!error.is_empty()).then(|| String::from_utf8_lossy(&error).into_owned()),
+            },
+            WaitStatus::Signaled(_, Signal::SIGKILL, _) if self.timed_out => TaskStatus::TimedOut {
+                timeout: self.timeout.unwrap_or_default(),
+            },
+            WaitStatus::Signaled(_, signal, _) => TaskStatus::Signaled {
+                signal: signal as i32,
+            },
+            other => anyhow::bail!("unexpected status of worker {}: {other:?}", self.pid),
+        };
+        tracing::trace!(id = self.id, pid = ?self.pid, ?status, "reaped worker");
+        Ok(Exited {
+            id: self.id,
+            status,
+        })
+    }
+}
+
 fn clone_fds(fds: &[OwnedFd]) -> Vec<OwnedFd> {
     fds.iter().map(|v| v.try_clone().unwrap()).collect()
 }
 
-fn start_worker<T: SandboxTask, S: CloneSyscall + ProcSyscall>(
+fn start_worker<
+    T: SandboxTask,
+    S: CloneSyscall + ProcSyscall + FsSyscall + NetSyscall + SeccompSyscall,
+>(
+    id: u64,
     task: T,
     fds: Vec<OwnedFd>,
     opts: SandboxOptions,
     tools: IdMappingTools,
-) -> anyhow::Result<()> {
-    let (mut host, child) =
-        UnixStream::pair().context("while creating uds for supervisor communication")?;
+    cgroups: Option<&Cgroups>,
+    chain: TaskChain<T>,
+) -> anyhow::Result<(Worker, [OwnedFd; 2])> {
+    let (mut host, child) = io::pair(SocketOptions::default())
+        .context("while creating uds for supervisor communication")?;
+    let (stdout, stdout_write) =
+        pipe2(OFlag::O_CLOEXEC).context("while creating the pipe of the stdout of the task")?;
+    let (stderr, stderr_write) =
+        pipe2(OFlag::O_CLOEXEC).context("while creating the pipe of the stderr of the task")?;
 
-    let cb = move || {
-        worker_main::<T, S>(
-            &task,
-            clone_fds(&fds[..]),
-            opts.clone(),
-            child.try_clone().unwrap(),
+    let limits = opts.limits();
+    let timeout = opts.timeout();
+    let cgroup = if limits.is_empty() {
+        None
+    } else {
+        let cgroups = cgroups.context("the task has resource limits, which need cgroups (v2)")?;
+        Some(
+            cgroups
+                .create(id, limits)
+                .context("while creating the cgroup of the task")?,
         )
     };
 
-    let pid = S::clone(
-        cb,
-        CloneFlags::NEWPID | CloneFlags::NEWNS | CloneFlags::NEWUSER,
-    )
+    let mut flags = CloneFlags::NEWPID | CloneFlags::NEWNS | CloneFlags::NEWUSER;
+    if opts.flags().contains(SandboxFlags::NETWORK_ISOLATION) {
+        flags |= CloneFlags::NEWNET;
+    }
+
+    let cb = move || {
+        crash::set_process("worker");
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            worker_main::<T, S>(
+                &task,
+                &chain,
+                clone_fds(&fds[..]),
+                opts.clone(),
+                child.try_clone().unwrap(),
+                [&stdout_write, &stderr_write],
+            )
+        }))
+        .unwrap_or_else(|payload| Err(WorkerError::Panicked(crash::message(&*payload))));
+        if let Err(error) = &result {
+            // The zygote reports the error along with the exit code.
+            (&child).write_all(error.to_string().as_bytes()).ok();
+        }
+        result
+    };
+
+    let pid = match &cgroup {
+        Some(cgroup) => S::clone_into_cgroup(cb, flags, cgroup.as_fd()),
+        None => S::clone(cb, flags),
+    }
     .context("while creating supervisor process")?;
 
     S::write_mappings(
@@ -236,7 +1014,17 @@ fn start_worker<T: SandboxTask, S: CloneSyscall + ProcSyscall>(
     host.write_all(&[0x01u8][..])
         .context("while informing supervisor to proceed")?;
 
-    Ok(())
+    // The write ends were dropped along with the callback, so the pipes end once the worker exits.
+    let worker = Worker {
+        id,
+        pid,
+        socket: host,
+        cgroup,
+        deadline: timeout.map(|v| Instant::now() + v),
+        timeout,
+        timed_out: false,
+    };
+    Ok((worker, [stdout, stderr]))
 }
 
 #[derive(Debug, Error)]
@@ -247,6 +1035,20 @@ enum WorkerError<T> {
     Task(T),
     #[error(transparent)]
     SetId(#[from] super::proc::SetIdsError),
+    #[error(transparent)]
+    Mount(#[from] MountError),
+    #[error(transparent)]
+    Bind(#[from] BindError),
+    #[error(transparent)]
+    Pivot(#[from] PivotError),
+    #[error(transparent)]
+    Dev(#[from] SetupDevError),
+    #[error(transparent)]
+    Seccomp(#[from] SeccompError),
+    #[error(transparent)]
+    Loopback(#[from] LoopbackError),
+    #[error("the worker panicked: {0}")]
+    Panicked(String),
 }
 
 impl<T: IntoExitCode + fmt::Debug> IntoExitCode for WorkerError<T> {
@@ -261,20 +1063,100 @@ impl<T: IntoExitCode + fmt::Debug> IntoExitCode for WorkerError<T> {
     }
 }
 
-fn worker_main<T: SandboxTask, S: ProcSyscall>(
+/// Replaces the root file system with an empty one on `root`, which only contains the binds of the options, the
+/// standard devices in `/dev` and an empty `/tmp`.
+fn enter_root<T, S: FsSyscall>(root: &Path, binds: &[Bind]) -> Result<(), WorkerError<T>> {
+    S::mount(
+        Some("tmpfs"),
+        root,
+        Some(MountKind::TmpFs),
+        MountFlags::empty(),
+        Some("mode=0755"),
+    )?;
+    // The devices of the host are only reachable before pivoting.
+    setup_dev::<S>(&root.join("dev"))?;
+    for bind in binds {
+        let target = root.join(bind.target.strip_prefix("/").unwrap_or(&bind.target));
+        if bind.source.is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else {
+            if let Some(parent) = target.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::fs::File::create(&target)?;
+        }
+        let mut flags = BindFlags::RECURSIVE;
+        if bind.read_only {
+            flags |= BindFlags::READ_ONLY;
+        }
+        S::bind(&bind.source, &target, flags)?;
+    }
+    std::fs::create_dir_all(root.join("tmp"))?;
+    S::pivot(root)?;
+    tracing::trace!(?root, binds = binds.len(), "entered the root file system");
+    Ok(())
+}
+
+fn worker_main<T: SandboxTask, S: ProcSyscall + FsSyscall + NetSyscall + SeccompSyscall>(
     task: &T,
+    chain: &TaskChain<T>,
     fds: Vec<OwnedFd>,
     opts: SandboxOptions,
     mut host: UnixStream,
+    output: [&OwnedFd; 2],
 ) -> Result<(), WorkerError<T::ExecuteError>> {
     let mut buf = [0u8; 1];
 
     host.read_exact(&mut buf)
         .inspect(|_| tracing::trace!("received signal to start"))
         .inspect_err(|error| tracing::error!(?error, "failed to read signal from host"))?;
+    if let Some(root) = opts.root() {
+        enter_root::<_, S>(root, opts.binds())?;
+    }
+    if opts.flags().contains(SandboxFlags::NETWORK_ISOLATION) {
+        // The network namespace of the worker is new, so the task can only reach itself.
+        S::loopback_up()?;
+    }
     S::set_ids(opts.sandbox_uid(), opts.sandbox_gid())
         .inspect(|_| tracing::trace!("updated uid and gid"))
         .inspect_err(|error| tracing::error!(?error, "failed to update uid and gid"))?;
+    nix::sys::stat::umask(Mode::from_bits_truncate(TASK_UMASK));
+    // Last, as setting up the sandbox makes syscalls that the task can't.
+    S::filter_syscalls(opts.syscall_filter())
+        .inspect_err(|error| tracing::error!(?error, "failed to filter syscalls"))?;
+
+    // The zygote passes the read ends on to the controller, which collects the output. Until now, the worker logged to
+    // the stderr of the zygote.
+    dup2(output[0].as_raw_fd(), 1).map_err(std::io::Error::from)?;
+    dup2(output[1].as_raw_fd(), 2).map_err(std::io::Error::from)?;
+    chain.execute(task, fds).map_err(WorkerError::Task)
+}
 
-    task.execute(fds).map_err(WorkerError::Task)
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hello_roundtrip() {
+        let hello = Hello::current().unwrap();
+        assert_eq!(hello.protocol, PROTOCOL_VERSION);
+        assert_eq!(Hello::from_bytes(hello.to_bytes()), hello);
+        // The executable doesn't change while it is running.
+        assert_eq!(Hello::current().unwrap(), hello);
+
+        // Features from newer builds are ignored, and don't make the sides incompatible.
+        let mut bytes = hello.to_bytes();
+        bytes[12..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Hello::from_bytes(bytes).features, Features::all());
+        let older = Hello {
+            features: Features::empty(),
+            ..hello
+        };
+        assert!(older.is_compatible(&hello));
+        assert!(!Hello {
+            protocol: PROTOCOL_VERSION - 1,
+            ..hello
+        }
+        .is_compatible(&hello));
+    }
 }
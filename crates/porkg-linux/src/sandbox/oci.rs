@@ -0,0 +1,413 @@
+//! Runs tasks in the containers of an OCI runtime (such as runsc for gVisor, or crun) instead of the namespaces of the
+//! zygote, for isolation that doesn't depend on the kernel of the host.
+//!
+//! The container runs the executable of the daemon, which receives the task over a socket that the runtime passes on to
+//! it (with `--preserve-fds`), and must call [`worker`] before anything else.
+
+use std::{
+    io::Write as _,
+    marker::PhantomData,
+    os::{
+        fd::{AsRawFd as _, FromRawFd as _, RawFd},
+        unix::{net::UnixStream, process::ExitStatusExt as _},
+    },
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use nix::{
+    libc,
+    sys::stat::Mode,
+    unistd::{Gid, Uid},
+};
+use porkg_private::{
+    crash,
+    io::{self, DomainSocket as _, DomainSocketAsyncExt as _, SendOptions, SocketOptions},
+    os::proc::IntoExitCode as _,
+    sandbox::{
+        SandboxBackend, SandboxFlags, SandboxOptions, SandboxTask, SyscallFilter, TaskChain,
+    },
+};
+use serde_json::{json, Value};
+use tokio::{io::AsyncReadExt as _, process::Command};
+
+use super::{
+    task::{Exited, Tasks},
+    CreateSandboxError, SandboxTaskError, SandboxTaskHandle, TaskStatus, WorkerError, TASK_UMASK,
+};
+
+/// Set in the environment of the daemon when it runs as the worker of a container.
+const WORKER_ENV: &str = "PORKG_OCI_WORKER";
+/// Where the executable of the daemon is bound in containers.
+const WORKER_PATH: &str = "/.porkg/worker";
+/// The socket that the worker receives its task from, which is the first fd after the standard streams.
+const WORKER_FD: RawFd = 3;
+
+/// The OCI runtime that runs the tasks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OciRuntime {
+    /// The executable of the runtime, which must support `run --preserve-fds`.
+    pub runtime: PathBuf,
+    /// The directory that the bundles of the containers are created in.
+    pub bundles: PathBuf,
+}
+
+#[derive(Debug)]
+struct Inner {
+    runtime: OciRuntime,
+    exe: PathBuf,
+    tasks: Tasks,
+    next_id: AtomicU64,
+}
+
+/// Starts tasks in the containers of an OCI runtime.
+#[derive(Debug)]
+pub struct OciController<T: SandboxTask>(Arc<Inner>, PhantomData<fn() -> T>);
+
+impl<T: SandboxTask> Clone for OciController<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone(), PhantomData)
+    }
+}
+
+impl<T: SandboxTask> OciController<T> {
+    pub fn new(runtime: OciRuntime) -> std::io::Result<Self> {
+        std::fs::create_dir_all(&runtime.bundles)?;
+        Ok(Self(
+            Arc::new(Inner {
+                runtime,
+                exe: std::fs::read_link("/proc/self/exe")?,
+                tasks: Tasks::default(),
+                next_id: AtomicU64::new(0),
+            }),
+            PhantomData,
+        ))
+    }
+
+    /// Names the container of a task, which is unique among the daemons that share the runtime.
+    fn container(id: u64) -> String {
+        format!("porkg-{}-{id}", std::process::id())
+    }
+
+    /// Starts a task in a new container, returning a handle that completes once the task has finished.
+    #[tracing::instrument(skip_all)]
+    pub async fn spawn_async(
+        &self,
+        task: T,
+        fds: &[RawFd],
+    ) -> Result<SandboxTaskHandle, CreateSandboxError> {
+        let id = self.0.next_id.fetch_add(1, Ordering::Relaxed);
+        let container = Self::container(id);
+        let bundle = self.0.runtime.bundles.join(&container);
+        tokio::fs::create_dir_all(&bundle).await?;
+        let options = task.create_sandbox_options();
+        let timeout = options.timeout();
+        let spec = spec(&options, &self.0.exe);
+        tokio::fs::write(bundle.join("config.json"), spec.to_string()).await?;
+
+        let (host, worker) = io::pair(SocketOptions::default())?;
+        let mut host = io::into_async(host)?;
+        let worker_fd = worker.as_raw_fd();
+        let mut command = Command::new(&self.0.runtime.runtime);
+        command
+            .arg("run")
+            .arg("--bundle")
+            .arg(&bundle)
+            .arg("--preserve-fds")
+            .arg("1")
+            .arg(&container)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null());
+        unsafe {
+            command.pre_exec(move || {
+                // The copy isn't closed when the runtime is executed.
+                if libc::dup2(worker_fd, WORKER_FD) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = command
+            .spawn()
+            .inspect_err(|error| tracing::error!(?error, "failed to start the OCI runtime"))?;
+        drop(worker);
+
+        let handle = self.0.tasks.register(id);
+        let options = SendOptions {
+            // Tasks can carry secrets, such as credentials that are passed on to builds.
+            secret: true,
+            ..Default::default()
+        };
+        if let Err(error) = host.send_message_with(&task, fds, options).await {
+            tracing::trace!(?error, "failed to send the task to the container");
+            child.start_kill().ok();
+        }
+
+        let inner = self.0.clone();
+        tokio::spawn(async move {
+            let mut error = Vec::new();
+            // The worker writes the error before it exits, so the socket closes with the container.
+            let read = host.read_to_end(&mut error);
+            let timed_out = match timeout {
+                Some(timeout) => tokio::time::timeout(timeout, read).await.is_err(),
+                None => {
+                    read.await.ok();
+                    false
+                }
+            };
+            if timed_out {
+                tracing::debug!(id, "the task timed out");
+                inner.kill(&container).await.ok();
+            }
+            let status = match child.wait().await {
+                Ok(_) if timed_out => TaskStatus::TimedOut {
+                    timeout: timeout.unwrap_or_default(),
+                },
+                Ok(status) => match status.signal() {
+                    Some(signal) => TaskStatus::Signaled { signal },
+                    None => TaskStatus::Exited {
+                        code: status.code().unwrap_or(-1),
+                        error: (!error.is_empty())
+                            .then(|| String::from_utf8_lossy(&error).into_owned()),
+                    },
+                },
+                Err(error) => TaskStatus::NotStarted {
+                    error: error.to_string(),
+                },
+            };
+            inner.remove(&container, &bundle).await;
+            inner.tasks.finish(Exited { id, status });
+        });
+        Ok(handle)
+    }
+
+    /// The number of tasks whose containers haven't exited.
+    pub fn tasks(&self) -> usize {
+        self.0.tasks.len()
+    }
+
+    /// Kills the container of the task with `id`, whose handle completes once the runtime has exited.
+    #[tracing::instrument(skip(self))]
+    pub async fn kill(&self, id: u64) -> Result<(), CreateSandboxError> {
+        Ok(self.0.kill(&Self::container(id)).await?)
+    }
+}
+
+impl Inner {
+    async fn kill(&self, container: &str) -> std::io::Result<()> {
+        let status = Command::new(&self.runtime.runtime)
+            .arg("kill")
+            .arg(container)
+            .arg("KILL")
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()
+            .await?;
+        if !status.success() {
+            tracing::trace!(
+                ?status,
+                container,
+                "the container to kill has already finished"
+            );
+        }
+        Ok(())
+    }
+
+    async fn remove(&self, container: &str, bundle: &Path) {
+        let result = Command::new(&self.runtime.runtime)
+            .arg("delete")
+            .arg("--force")
+            .arg(container)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .status()
+            .await;
+        if let Err(error) = result {
+            tracing::warn!(?error, container, "failed to delete a container");
+        }
+        if let Err(error) = tokio::fs::remove_dir_all(bundle).await {
+            tracing::warn!(
+                ?error,
+                ?bundle,
+                "failed to remove the bundle of a container"
+            );
+        }
+    }
+}
+
+impl<T: SandboxTask> SandboxBackend<T> for OciController<T> {
+    type Handle = SandboxTaskHandle;
+    type TaskError = SandboxTaskError;
+    type Error = CreateSandboxError;
+
+    async fn spawn(&self, task: T, fds: &[RawFd]) -> Result<Self::Handle, Self::Error> {
+        self.spawn_async(task, fds).await
+    }
+
+    async fn kill(&self, id: u64) -> Result<(), Self::Error> {
+        OciController::kill(self, id).await
+    }
+
+    fn id(handle: &Self::Handle) -> u64 {
+        handle.id()
+    }
+}
+
+fn seccomp(filter: &SyscallFilter) -> Option<Value> {
+    let (names, action, default) = match filter {
+        SyscallFilter::Unrestricted => return None,
+        SyscallFilter::Deny(names) => (names, "SCMP_ACT_ERRNO", "SCMP_ACT_ALLOW"),
+        SyscallFilter::Allow(names) => (names, "SCMP_ACT_ALLOW", "SCMP_ACT_ERRNO"),
+    };
+    Some(json!({
+        "defaultAction": default,
+        "defaultErrnoRet": libc::EPERM,
+        "syscalls": [{ "names": names, "action": action, "errnoRet": libc::EPERM }],
+    }))
+}
+
+/// Describes the container of a task with the same file system, ids and limits as the namespaces of the zygote would
+/// give it.
+fn spec(opts: &SandboxOptions, exe: &Path) -> Value {
+    let mut mounts = vec![
+        json!({ "destination": "/proc", "type": "proc", "source": "proc" }),
+        json!({ "destination": "/tmp", "type": "tmpfs", "source": "tmpfs", "options": ["mode=1777"] }),
+        json!({ "destination": WORKER_PATH, "type": "bind", "source": exe, "options": ["bind", "ro"] }),
+    ];
+    mounts.extend(opts.binds().iter().map(|bind| {
+        let access = if bind.read_only { "ro" } else { "rw" };
+        json!({
+            "destination": bind.target,
+            "type": "bind",
+            "source": bind.source,
+            "options": ["rbind", access],
+        })
+    }));
+
+    let mut namespaces = vec![
+        json!({ "type": "pid" }),
+        json!({ "type": "mount" }),
+        json!({ "type": "ipc" }),
+        json!({ "type": "uts" }),
+        json!({ "type": "user" }),
+    ];
+    if opts.flags().contains(SandboxFlags::NETWORK_ISOLATION) {
+        namespaces.push(json!({ "type": "network" }));
+    }
+
+    let limits = opts.limits();
+    let mut resources = json!({});
+    if let Some(memory) = limits.memory {
+        resources["memory"] = json!({ "limit": memory });
+    }
+    if let Some(pids) = limits.pids {
+        resources["pids"] = json!({ "limit": pids });
+    }
+    if let Some(weight) = limits.cpu_weight {
+        resources["unified"] = json!({ "cpu.weight": weight.to_string() });
+    }
+
+    let mut linux = json!({
+        "namespaces": namespaces,
+        "uidMappings": [{ "containerID": 0, "hostID": Uid::current().as_raw(), "size": 1 }],
+        "gidMappings": [{ "containerID": 0, "hostID": Gid::current().as_raw(), "size": 1 }],
+        "resources": resources,
+    });
+    if let Some(seccomp) = seccomp(opts.syscall_filter()) {
+        linux["seccomp"] = seccomp;
+    }
+
+    json!({
+        "ociVersion": "1.0.2",
+        "root": {
+            // Without a root of its own, the container sees the file system of the host, which it can't change.
+            "path": opts.root().unwrap_or(Path::new("/")),
+            "readonly": opts.root().is_none(),
+        },
+        "mounts": mounts,
+        "process": {
+            "terminal": false,
+            "user": { "uid": opts.sandbox_uid().as_raw(), "gid": opts.sandbox_gid().as_raw() },
+            "args": [WORKER_PATH],
+            "env": [format!("{WORKER_ENV}=1")],
+            "cwd": "/",
+            "noNewPrivileges": true,
+        },
+        "linux": linux,
+    })
+}
+
+/// Runs the task that the controller sends through the middleware of `chain` if the process is the worker of a
+/// container, and exits once it has finished. Otherwise, returns immediately.
+pub fn worker<T: SandboxTask>(chain: &TaskChain<T>) {
+    if std::env::var_os(WORKER_ENV).is_none() {
+        return;
+    }
+    let host = unsafe { UnixStream::from_raw_fd(WORKER_FD) };
+    crash::set_process("worker");
+    let result = panic::catch_unwind(AssertUnwindSafe(|| worker_main(&host, chain)))
+        .unwrap_or_else(|payload| Err(WorkerError::Panicked(crash::message(&*payload))));
+    let code = match result {
+        Ok(()) => 0,
+        Err(error) => {
+            // The controller reports the error along with the exit code.
+            (&host).write_all(error.to_string().as_bytes()).ok();
+            error.report()
+        }
+    };
+    std::process::exit(code)
+}
+
+fn worker_main<T: SandboxTask>(
+    host: &UnixStream,
+    chain: &TaskChain<T>,
+) -> Result<(), WorkerError<T::ExecuteError>> {
+    let mut fds = Vec::new();
+    let task: T = host
+        .recv_secret_message(&mut fds)
+        .map_err(std::io::Error::other)
+        .inspect_err(|error| tracing::error!(?error, "failed to receive the task"))?;
+    nix::sys::stat::umask(Mode::from_bits_truncate(TASK_UMASK));
+
+    chain.execute(&task, fds).map_err(WorkerError::Task)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spec_of_options() {
+        let mut opts = SandboxOptions::default();
+        opts.with_root("/var/lib/porkg/root")
+            .with_bind("/nix/store/a", "/nix/store/a", true)
+            .with_network_isolation(true)
+            .with_syscall_filter(SyscallFilter::Deny(vec!["mount".to_string()]))
+            .with_limits(porkg_private::sandbox::ResourceLimits {
+                memory: Some(1024),
+                ..Default::default()
+            });
+        let spec = super::spec(&opts, Path::new("/usr/bin/porkg-daemon"));
+
+        assert_eq!(spec["root"]["path"], "/var/lib/porkg/root");
+        assert_eq!(spec["root"]["readonly"], false);
+        assert_eq!(spec["mounts"][2]["source"], "/usr/bin/porkg-daemon");
+        assert_eq!(spec["mounts"][3]["destination"], "/nix/store/a");
+        assert_eq!(spec["mounts"][3]["options"][1], "ro");
+        assert_eq!(spec["linux"]["namespaces"][5]["type"], "network");
+        assert_eq!(spec["linux"]["resources"]["memory"]["limit"], 1024);
+        assert!(spec["linux"]["resources"]["pids"].is_null());
+        assert_eq!(spec["linux"]["seccomp"]["syscalls"][0]["names"][0], "mount");
+        assert_eq!(spec["process"]["args"][0], WORKER_PATH);
+
+        let spec = super::spec(&SandboxOptions::default(), Path::new("/porkg"));
+        assert_eq!(spec["root"]["path"], "/");
+        assert_eq!(spec["root"]["readonly"], true);
+        assert!(spec["linux"]["seccomp"].is_null());
+    }
+}
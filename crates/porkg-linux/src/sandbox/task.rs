@@ -0,0 +1,341 @@
+//! The tasks that the controller has started, which finish when the zygote reports that their workers have exited.
+
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    future::Future,
+    io,
+    os::fd::OwnedFd,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use porkg_private::io::{DomainSocketAsync as _, DomainSocketAsyncExt as _, SocketMessageError};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    net::{unix::pipe, UnixStream as UnixStreamAsync},
+    sync::{mpsc, oneshot},
+};
+
+use super::{CMD_PING, EVT_EXITED, EVT_OUTPUT, EVT_PANICKED};
+
+/// How a task ended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TaskStatus {
+    /// The worker exited, with the error that the task failed with if it reported one.
+    Exited { code: i32, error: Option<String> },
+    /// The worker was killed by a signal.
+    Signaled { signal: i32 },
+    /// The worker was killed because the task ran for longer than the timeout of its options.
+    TimedOut { timeout: Duration },
+    /// The zygote couldn't start the worker.
+    NotStarted { error: String },
+}
+
+/// Sent by the zygote after [`EVT_EXITED`], once it has reaped the worker of a task.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct Exited {
+    pub id: u64,
+    pub status: TaskStatus,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum SandboxTaskError {
+    #[error("the task failed with exit code {code}: {}", .error.as_deref().unwrap_or("no error was reported"))]
+    Failed { code: i32, error: Option<String> },
+    #[error("the task was killed by signal {signal}")]
+    Killed { signal: i32 },
+    #[error("the task did not finish within {timeout:?}")]
+    TimedOut { timeout: Duration },
+    #[error("the sandbox of the task could not be started: {error}")]
+    NotStarted { error: String },
+    #[error("the sandbox zygote exited before the task finished")]
+    Lost,
+}
+
+impl From<TaskStatus> for Result<(), SandboxTaskError> {
+    fn from(value: TaskStatus) -> Self {
+        match value {
+            TaskStatus::Exited { code: 0, .. } => Ok(()),
+            TaskStatus::Exited { code, error } => Err(SandboxTaskError::Failed { code, error }),
+            TaskStatus::Signaled { signal } => Err(SandboxTaskError::Killed { signal }),
+            TaskStatus::TimedOut { timeout } => Err(SandboxTaskError::TimedOut { timeout }),
+            TaskStatus::NotStarted { error } => Err(SandboxTaskError::NotStarted { error }),
+        }
+    }
+}
+
+/// The output of a task, as the read ends of the pipes that its stdout and stderr were redirected to. Both end once
+/// the task has exited.
+#[derive(Debug)]
+pub struct OutputStream {
+    pub stdout: pipe::Receiver,
+    pub stderr: pipe::Receiver,
+}
+
+impl OutputStream {
+    fn new(fds: Vec<OwnedFd>) -> io::Result<Self> {
+        let [stdout, stderr] = <[OwnedFd; 2]>::try_from(fds).map_err(|fds| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("expected the 2 pipes of the output, got {} fds", fds.len()),
+            )
+        })?;
+        Ok(Self {
+            stdout: pipe::Receiver::from_owned_fd(stdout)?,
+            stderr: pipe::Receiver::from_owned_fd(stderr)?,
+        })
+    }
+}
+
+/// Completes once the task has finished, with the error that it failed with.
+///
+/// Dropping the handle doesn't stop the task.
+#[derive(Debug)]
+pub struct SandboxTaskHandle {
+    id: u64,
+    status: oneshot::Receiver<TaskStatus>,
+    output: oneshot::Receiver<OutputStream>,
+}
+
+impl SandboxTaskHandle {
+    /// Identifies the task among the others that the controller started.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Takes the output of the task, which is only available once, and only from sandboxes that redirect it (the OCI
+    /// runtime doesn't). Output that isn't taken isn't read either, so a task that fills the pipes blocks until the
+    /// handle is dropped.
+    pub fn output_stream(&mut self) -> Option<OutputStream> {
+        self.output.try_recv().ok()
+    }
+}
+
+impl Future for SandboxTaskHandle {
+    type Output = Result<(), SandboxTaskError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Poll::Ready(match ready!(Pin::new(&mut self.status).poll(cx)) {
+            Ok(status) => status.into(),
+            Err(_) => Err(SandboxTaskError::Lost),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    status: oneshot::Sender<TaskStatus>,
+    /// Taken once the zygote has sent the output of the task.
+    output: Option<oneshot::Sender<OutputStream>>,
+}
+
+/// The tasks that are running, by their ids.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Tasks(Arc<Mutex<HashMap<u64, Entry>>>);
+
+impl Tasks {
+    /// Waits for the task with `id`, which must be registered before the zygote can report it.
+    pub fn register(&self, id: u64) -> SandboxTaskHandle {
+        let (status_sender, status) = oneshot::channel();
+        let (output_sender, output) = oneshot::channel();
+        self.0.lock().unwrap().insert(
+            id,
+            Entry {
+                status: status_sender,
+                output: Some(output_sender),
+            },
+        );
+        SandboxTaskHandle { id, status, output }
+    }
+
+    /// Passes the output of the task with `id` on to its handle.
+    fn output(&self, id: u64, output: OutputStream) {
+        let mut tasks = self.0.lock().unwrap();
+        match tasks.get_mut(&id).and_then(|v| v.output.take()) {
+            // The handle may have been dropped, which closes the pipes.
+            Some(sender) => sender.send(output).ok(),
+            None => {
+                tracing::warn!(id, "the zygote sent output for a task that isn't running");
+                None
+            }
+        };
+    }
+
+    /// The number of tasks that haven't finished.
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    /// Forgets a task that wasn't started.
+    pub fn remove(&self, id: u64) {
+        self.0.lock().unwrap().remove(&id);
+    }
+
+    /// Completes the handle of a task that has exited.
+    pub fn finish(&self, exited: Exited) {
+        tracing::debug!(id = exited.id, status = ?exited.status, "task finished");
+        match self.0.lock().unwrap().remove(&exited.id) {
+            // The handle may have been dropped.
+            Some(entry) => entry.status.send(exited.status).ok(),
+            None => {
+                tracing::warn!(
+                    id = exited.id,
+                    "the zygote reported a task that isn't running"
+                );
+                None
+            }
+        };
+    }
+}
+
+/// Reads what the zygote reports until it disconnects, after which the tasks that are still running are lost. Returns
+/// why the connection was lost.
+pub(super) async fn read_events(
+    stream: Arc<UnixStreamAsync>,
+    tasks: Tasks,
+    pongs: mpsc::UnboundedSender<()>,
+) -> SocketMessageError {
+    let result: Result<Infallible, SocketMessageError> = async {
+        loop {
+            let mut event = [0u8; 1];
+            stream
+                .recv_exact(&mut &mut event[..], &mut Vec::new())
+                .await?;
+            match event[0] {
+                CMD_PING => {
+                    pongs.send(()).ok();
+                }
+                EVT_EXITED => tasks.finish(stream.recv_message(&mut Vec::new()).await?),
+                EVT_OUTPUT => {
+                    let mut id = [0u8; 8];
+                    let mut fds = Vec::new();
+                    stream.recv_exact(&mut &mut id[..], &mut fds).await?;
+                    tasks.output(u64::from_le_bytes(id), OutputStream::new(fds)?);
+                }
+                EVT_PANICKED => {
+                    let message: String = stream.recv_message(&mut Vec::new()).await?;
+                    tracing::error!(message, "the sandbox zygote panicked");
+                }
+                other => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("unknown event {other}"),
+                    )
+                    .into())
+                }
+            }
+        }
+    }
+    .await;
+    tasks.0.lock().unwrap().clear();
+    match result {
+        Ok(never) => match never {},
+        Err(error) => error,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use porkg_private::io::{pair_async, SocketOptions};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn finish_tasks() {
+        let (controller, zygote) = pair_async(SocketOptions::default()).unwrap();
+        let tasks = Tasks::default();
+        let (pongs, mut pinged) = mpsc::unbounded_channel();
+        let reader = tokio::spawn(read_events(Arc::new(controller), tasks.clone(), pongs));
+
+        let succeeded = tasks.register(1);
+        let failed = tasks.register(2);
+        let lost = tasks.register(3);
+        let timed_out = tasks.register(4);
+        for exited in [
+            Exited {
+                id: 4,
+                status: TaskStatus::TimedOut {
+                    timeout: Duration::from_secs(10),
+                },
+            },
+            Exited {
+                id: 2,
+                status: TaskStatus::Exited {
+                    code: 1,
+                    error: Some("failed".to_string()),
+                },
+            },
+            Exited {
+                id: 1,
+                status: TaskStatus::Exited {
+                    code: 0,
+                    error: None,
+                },
+            },
+        ] {
+            zygote.send_all(&mut &[EVT_EXITED][..], &[]).await.unwrap();
+            zygote.send_message(&exited, &[]).await.unwrap();
+        }
+        zygote.send_all(&mut &[CMD_PING][..], &[]).await.unwrap();
+
+        assert_eq!(succeeded.await, Ok(()));
+        assert_eq!(
+            failed.await,
+            Err(SandboxTaskError::Failed {
+                code: 1,
+                error: Some("failed".to_string())
+            })
+        );
+        assert_eq!(
+            timed_out.await,
+            Err(SandboxTaskError::TimedOut {
+                timeout: Duration::from_secs(10)
+            })
+        );
+        assert_eq!(pinged.recv().await, Some(()));
+
+        drop(zygote);
+        reader.await.unwrap();
+        assert_eq!(lost.await, Err(SandboxTaskError::Lost));
+    }
+
+    #[tokio::test]
+    async fn pass_output() {
+        use std::{io::Write as _, os::fd::AsRawFd as _};
+
+        use nix::{fcntl::OFlag, unistd::pipe2};
+        use tokio::io::AsyncReadExt as _;
+
+        let (controller, zygote) = pair_async(SocketOptions::default()).unwrap();
+        let tasks = Tasks::default();
+        let (pongs, mut pinged) = mpsc::unbounded_channel();
+        tokio::spawn(read_events(Arc::new(controller), tasks.clone(), pongs));
+
+        let mut handle = tasks.register(1);
+        let (stdout, stdout_write) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        let (stderr, stderr_write) = pipe2(OFlag::O_CLOEXEC).unwrap();
+        zygote.send_all(&mut &[EVT_OUTPUT][..], &[]).await.unwrap();
+        zygote
+            .send_all(
+                &mut &1u64.to_le_bytes()[..],
+                &[stdout.as_raw_fd(), stderr.as_raw_fd()],
+            )
+            .await
+            .unwrap();
+        zygote.send_all(&mut &[CMD_PING][..], &[]).await.unwrap();
+        assert_eq!(pinged.recv().await, Some(()));
+
+        let mut output = handle.output_stream().unwrap();
+        assert!(handle.output_stream().is_none());
+        std::fs::File::from(stdout_write).write_all(b"out").unwrap();
+        std::fs::File::from(stderr_write).write_all(b"err").unwrap();
+        let mut text = String::new();
+        output.stdout.read_to_string(&mut text).await.unwrap();
+        output.stderr.read_to_string(&mut text).await.unwrap();
+        assert_eq!(text, "outerr");
+    }
+}
@@ -0,0 +1,404 @@
+use std::ffi::c_long;
+
+use nix::{errno::Errno, libc};
+use porkg_private::sandbox::SyscallFilter;
+use thiserror::Error;
+
+use crate::Syscall;
+
+// See linux/filter.h and linux/seccomp.h.
+/// `BPF_LD | BPF_W | BPF_ABS`
+const BPF_LD_W_ABS: u16 = 0x20;
+/// `BPF_JMP | BPF_JEQ | BPF_K`
+const BPF_JMP_JEQ_K: u16 = 0x15;
+/// `BPF_JMP | BPF_JGE | BPF_K`
+const BPF_JMP_JGE_K: u16 = 0x35;
+/// `BPF_RET | BPF_K`
+const BPF_RET_K: u16 = 0x06;
+
+const SECCOMP_RET_KILL_PROCESS: u32 = 0x8000_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+/// The offsets of the fields of `struct seccomp_data`.
+const DATA_NR: u32 = 0;
+const DATA_ARCH: u32 = 4;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Syscalls of the x32 ABI, which would otherwise bypass the filter, have this bit set.
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// The syscalls that filters can name.
+const SYSCALLS: &[(&str, c_long)] = &[
+    ("accept", libc::SYS_accept),
+    ("accept4", libc::SYS_accept4),
+    ("acct", libc::SYS_acct),
+    ("add_key", libc::SYS_add_key),
+    ("bind", libc::SYS_bind),
+    ("bpf", libc::SYS_bpf),
+    ("brk", libc::SYS_brk),
+    ("capget", libc::SYS_capget),
+    ("capset", libc::SYS_capset),
+    ("chdir", libc::SYS_chdir),
+    ("chroot", libc::SYS_chroot),
+    ("clock_adjtime", libc::SYS_clock_adjtime),
+    ("clock_getres", libc::SYS_clock_getres),
+    ("clock_gettime", libc::SYS_clock_gettime),
+    ("clock_nanosleep", libc::SYS_clock_nanosleep),
+    ("clock_settime", libc::SYS_clock_settime),
+    ("clone", libc::SYS_clone),
+    ("clone3", libc::SYS_clone3),
+    ("close", libc::SYS_close),
+    ("close_range", libc::SYS_close_range),
+    ("connect", libc::SYS_connect),
+    ("delete_module", libc::SYS_delete_module),
+    ("dup", libc::SYS_dup),
+    ("dup3", libc::SYS_dup3),
+    ("epoll_create1", libc::SYS_epoll_create1),
+    ("epoll_ctl", libc::SYS_epoll_ctl),
+    ("epoll_pwait", libc::SYS_epoll_pwait),
+    ("eventfd2", libc::SYS_eventfd2),
+    ("execve", libc::SYS_execve),
+    ("execveat", libc::SYS_execveat),
+    ("exit", libc::SYS_exit),
+    ("exit_group", libc::SYS_exit_group),
+    ("faccessat", libc::SYS_faccessat),
+    ("faccessat2", libc::SYS_faccessat2),
+    ("fadvise64", libc::SYS_fadvise64),
+    ("fallocate", libc::SYS_fallocate),
+    ("fchdir", libc::SYS_fchdir),
+    ("fchmod", libc::SYS_fchmod),
+    ("fchmodat", libc::SYS_fchmodat),
+    ("fchown", libc::SYS_fchown),
+    ("fchownat", libc::SYS_fchownat),
+    ("fcntl", libc::SYS_fcntl),
+    ("fdatasync", libc::SYS_fdatasync),
+    ("fgetxattr", libc::SYS_fgetxattr),
+    ("finit_module", libc::SYS_finit_module),
+    ("flock", libc::SYS_flock),
+    ("fsetxattr", libc::SYS_fsetxattr),
+    ("fsmount", libc::SYS_fsmount),
+    ("fsopen", libc::SYS_fsopen),
+    ("fspick", libc::SYS_fspick),
+    ("fstat", libc::SYS_fstat),
+    ("fstatfs", libc::SYS_fstatfs),
+    ("fsync", libc::SYS_fsync),
+    ("ftruncate", libc::SYS_ftruncate),
+    ("futex", libc::SYS_futex),
+    ("getcwd", libc::SYS_getcwd),
+    ("getdents64", libc::SYS_getdents64),
+    ("getegid", libc::SYS_getegid),
+    ("geteuid", libc::SYS_geteuid),
+    ("getgid", libc::SYS_getgid),
+    ("getgroups", libc::SYS_getgroups),
+    ("getpeername", libc::SYS_getpeername),
+    ("getpgid", libc::SYS_getpgid),
+    ("getpid", libc::SYS_getpid),
+    ("getppid", libc::SYS_getppid),
+    ("getpriority", libc::SYS_getpriority),
+    ("getrandom", libc::SYS_getrandom),
+    ("getresgid", libc::SYS_getresgid),
+    ("getresuid", libc::SYS_getresuid),
+    ("getrlimit", libc::SYS_getrlimit),
+    ("getrusage", libc::SYS_getrusage),
+    ("getsid", libc::SYS_getsid),
+    ("getsockname", libc::SYS_getsockname),
+    ("getsockopt", libc::SYS_getsockopt),
+    ("gettid", libc::SYS_gettid),
+    ("gettimeofday", libc::SYS_gettimeofday),
+    ("getuid", libc::SYS_getuid),
+    ("getxattr", libc::SYS_getxattr),
+    ("init_module", libc::SYS_init_module),
+    ("inotify_add_watch", libc::SYS_inotify_add_watch),
+    ("inotify_init1", libc::SYS_inotify_init1),
+    ("inotify_rm_watch", libc::SYS_inotify_rm_watch),
+    ("io_uring_enter", libc::SYS_io_uring_enter),
+    ("io_uring_register", libc::SYS_io_uring_register),
+    ("io_uring_setup", libc::SYS_io_uring_setup),
+    ("ioctl", libc::SYS_ioctl),
+    ("kcmp", libc::SYS_kcmp),
+    ("kexec_load", libc::SYS_kexec_load),
+    ("keyctl", libc::SYS_keyctl),
+    ("kill", libc::SYS_kill),
+    ("lgetxattr", libc::SYS_lgetxattr),
+    ("linkat", libc::SYS_linkat),
+    ("listen", libc::SYS_listen),
+    ("lseek", libc::SYS_lseek),
+    ("madvise", libc::SYS_madvise),
+    ("memfd_create", libc::SYS_memfd_create),
+    ("mkdirat", libc::SYS_mkdirat),
+    ("mknodat", libc::SYS_mknodat),
+    ("mlock", libc::SYS_mlock),
+    ("mmap", libc::SYS_mmap),
+    ("mount", libc::SYS_mount),
+    ("move_mount", libc::SYS_move_mount),
+    ("mprotect", libc::SYS_mprotect),
+    ("mremap", libc::SYS_mremap),
+    ("msync", libc::SYS_msync),
+    ("munmap", libc::SYS_munmap),
+    ("name_to_handle_at", libc::SYS_name_to_handle_at),
+    ("nanosleep", libc::SYS_nanosleep),
+    ("newfstatat", libc::SYS_newfstatat),
+    ("open_by_handle_at", libc::SYS_open_by_handle_at),
+    ("open_tree", libc::SYS_open_tree),
+    ("openat", libc::SYS_openat),
+    ("openat2", libc::SYS_openat2),
+    ("perf_event_open", libc::SYS_perf_event_open),
+    ("personality", libc::SYS_personality),
+    ("pidfd_open", libc::SYS_pidfd_open),
+    ("pidfd_send_signal", libc::SYS_pidfd_send_signal),
+    ("pipe2", libc::SYS_pipe2),
+    ("pivot_root", libc::SYS_pivot_root),
+    ("ppoll", libc::SYS_ppoll),
+    ("prctl", libc::SYS_prctl),
+    ("pread64", libc::SYS_pread64),
+    ("preadv", libc::SYS_preadv),
+    ("prlimit64", libc::SYS_prlimit64),
+    ("process_vm_readv", libc::SYS_process_vm_readv),
+    ("process_vm_writev", libc::SYS_process_vm_writev),
+    ("pselect6", libc::SYS_pselect6),
+    ("ptrace", libc::SYS_ptrace),
+    ("pwrite64", libc::SYS_pwrite64),
+    ("pwritev", libc::SYS_pwritev),
+    ("quotactl", libc::SYS_quotactl),
+    ("read", libc::SYS_read),
+    ("readlinkat", libc::SYS_readlinkat),
+    ("readv", libc::SYS_readv),
+    ("reboot", libc::SYS_reboot),
+    ("recvfrom", libc::SYS_recvfrom),
+    ("recvmsg", libc::SYS_recvmsg),
+    ("renameat", libc::SYS_renameat),
+    ("renameat2", libc::SYS_renameat2),
+    ("request_key", libc::SYS_request_key),
+    ("rt_sigaction", libc::SYS_rt_sigaction),
+    ("rt_sigprocmask", libc::SYS_rt_sigprocmask),
+    ("rt_sigreturn", libc::SYS_rt_sigreturn),
+    ("rt_sigsuspend", libc::SYS_rt_sigsuspend),
+    ("sched_getaffinity", libc::SYS_sched_getaffinity),
+    ("sched_yield", libc::SYS_sched_yield),
+    ("sendmsg", libc::SYS_sendmsg),
+    ("sendto", libc::SYS_sendto),
+    ("set_robust_list", libc::SYS_set_robust_list),
+    ("set_tid_address", libc::SYS_set_tid_address),
+    ("setdomainname", libc::SYS_setdomainname),
+    ("setgid", libc::SYS_setgid),
+    ("setgroups", libc::SYS_setgroups),
+    ("sethostname", libc::SYS_sethostname),
+    ("setns", libc::SYS_setns),
+    ("setpgid", libc::SYS_setpgid),
+    ("setresgid", libc::SYS_setresgid),
+    ("setresuid", libc::SYS_setresuid),
+    ("setrlimit", libc::SYS_setrlimit),
+    ("setsid", libc::SYS_setsid),
+    ("setsockopt", libc::SYS_setsockopt),
+    ("settimeofday", libc::SYS_settimeofday),
+    ("setuid", libc::SYS_setuid),
+    ("shutdown", libc::SYS_shutdown),
+    ("sigaltstack", libc::SYS_sigaltstack),
+    ("socket", libc::SYS_socket),
+    ("socketpair", libc::SYS_socketpair),
+    ("statx", libc::SYS_statx),
+    ("swapoff", libc::SYS_swapoff),
+    ("swapon", libc::SYS_swapon),
+    ("symlinkat", libc::SYS_symlinkat),
+    ("sync", libc::SYS_sync),
+    ("syslog", libc::SYS_syslog),
+    ("tgkill", libc::SYS_tgkill),
+    ("umask", libc::SYS_umask),
+    ("umount2", libc::SYS_umount2),
+    ("uname", libc::SYS_uname),
+    ("unlinkat", libc::SYS_unlinkat),
+    ("unshare", libc::SYS_unshare),
+    ("userfaultfd", libc::SYS_userfaultfd),
+    ("utimensat", libc::SYS_utimensat),
+    ("vhangup", libc::SYS_vhangup),
+    ("wait4", libc::SYS_wait4),
+    ("waitid", libc::SYS_waitid),
+    ("write", libc::SYS_write),
+    ("writev", libc::SYS_writev),
+    // Only the older architectures have the syscalls that were replaced by the `*at` and other variants.
+    #[cfg(target_arch = "x86_64")]
+    ("access", libc::SYS_access),
+    #[cfg(target_arch = "x86_64")]
+    ("arch_prctl", libc::SYS_arch_prctl),
+    #[cfg(target_arch = "x86_64")]
+    ("chmod", libc::SYS_chmod),
+    #[cfg(target_arch = "x86_64")]
+    ("chown", libc::SYS_chown),
+    #[cfg(target_arch = "x86_64")]
+    ("creat", libc::SYS_creat),
+    #[cfg(target_arch = "x86_64")]
+    ("dup2", libc::SYS_dup2),
+    #[cfg(target_arch = "x86_64")]
+    ("epoll_wait", libc::SYS_epoll_wait),
+    #[cfg(target_arch = "x86_64")]
+    ("fork", libc::SYS_fork),
+    #[cfg(target_arch = "x86_64")]
+    ("ioperm", libc::SYS_ioperm),
+    #[cfg(target_arch = "x86_64")]
+    ("iopl", libc::SYS_iopl),
+    #[cfg(target_arch = "x86_64")]
+    ("lstat", libc::SYS_lstat),
+    #[cfg(target_arch = "x86_64")]
+    ("mkdir", libc::SYS_mkdir),
+    #[cfg(target_arch = "x86_64")]
+    ("open", libc::SYS_open),
+    #[cfg(target_arch = "x86_64")]
+    ("pipe", libc::SYS_pipe),
+    #[cfg(target_arch = "x86_64")]
+    ("poll", libc::SYS_poll),
+    #[cfg(target_arch = "x86_64")]
+    ("readlink", libc::SYS_readlink),
+    #[cfg(target_arch = "x86_64")]
+    ("rename", libc::SYS_rename),
+    #[cfg(target_arch = "x86_64")]
+    ("rmdir", libc::SYS_rmdir),
+    #[cfg(target_arch = "x86_64")]
+    ("select", libc::SYS_select),
+    #[cfg(target_arch = "x86_64")]
+    ("stat", libc::SYS_stat),
+    #[cfg(target_arch = "x86_64")]
+    ("symlink", libc::SYS_symlink),
+    #[cfg(target_arch = "x86_64")]
+    ("unlink", libc::SYS_unlink),
+    #[cfg(target_arch = "x86_64")]
+    ("vfork", libc::SYS_vfork),
+];
+
+#[derive(Debug, Clone, Error)]
+pub enum SeccompError {
+    #[error("the syscall {0:?} is unknown")]
+    UnknownSyscall(String),
+    #[error("the filter lists {0} syscalls, which is more than a filter can jump over")]
+    TooLong(usize),
+    #[error("failed to install the syscall filter: {0}")]
+    Install(#[from] Errno),
+}
+
+/// Syscalls related to filtering the syscalls of the current process.
+pub trait SeccompSyscall {
+    /// Restricts the syscalls that the current process and its children can make, which can't be undone.
+    fn filter_syscalls(filter: &SyscallFilter) -> Result<(), SeccompError>;
+}
+
+impl SeccompSyscall for Syscall {
+    #[tracing::instrument(err(level = "debug"))]
+    fn filter_syscalls(filter: &SyscallFilter) -> Result<(), SeccompError> {
+        let Some(mut program) = compile(filter)? else {
+            return Ok(());
+        };
+        let prog = libc::sock_fprog {
+            len: program.len() as u16,
+            filter: program.as_mut_ptr(),
+        };
+        // Unprivileged processes can only install a filter once they can't gain privileges.
+        Errno::result(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) })?;
+        Errno::result(unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &prog as *const libc::sock_fprog,
+            )
+        })?;
+        Ok(())
+    }
+}
+
+fn statement(code: u16, k: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+fn lookup(name: &str) -> Result<u32, SeccompError> {
+    SYSCALLS
+        .iter()
+        .find(|(v, _)| *v == name)
+        .map(|(_, nr)| *nr as u32)
+        .ok_or_else(|| SeccompError::UnknownSyscall(name.to_string()))
+}
+
+/// Compiles a filter into a BPF program, or `None` if it doesn't filter anything.
+fn compile(filter: &SyscallFilter) -> Result<Option<Vec<libc::sock_filter>>, SeccompError> {
+    let denied = SECCOMP_RET_ERRNO | Errno::EPERM as u32;
+    let (names, matched, otherwise) = match filter {
+        SyscallFilter::Unrestricted => return Ok(None),
+        SyscallFilter::Deny(names) => (names, denied, SECCOMP_RET_ALLOW),
+        SyscallFilter::Allow(names) => (names, SECCOMP_RET_ALLOW, denied),
+    };
+    let numbers = names
+        .iter()
+        .map(|v| lookup(v))
+        .collect::<Result<Vec<_>, _>>()?;
+    if numbers.len() > u8::MAX as usize {
+        return Err(SeccompError::TooLong(numbers.len()));
+    }
+
+    let mut program = vec![
+        // Syscalls of other architectures have different numbers.
+        statement(BPF_LD_W_ABS, DATA_ARCH),
+        jump(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+        statement(BPF_RET_K, SECCOMP_RET_KILL_PROCESS),
+        statement(BPF_LD_W_ABS, DATA_NR),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    program.extend([
+        jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1),
+        statement(BPF_RET_K, denied),
+    ]);
+    // Each comparison jumps over the ones after it, and the default, when the syscall matches.
+    let count = numbers.len();
+    program.extend(
+        numbers
+            .into_iter()
+            .enumerate()
+            .map(|(index, nr)| jump(BPF_JMP_JEQ_K, nr, (count - index) as u8, 0)),
+    );
+    program.extend([
+        statement(BPF_RET_K, otherwise),
+        statement(BPF_RET_K, matched),
+    ]);
+    Ok(Some(program))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compile_filters() {
+        assert!(compile(&SyscallFilter::Unrestricted).unwrap().is_none());
+        assert!(matches!(
+            compile(&SyscallFilter::Deny(vec!["frobnicate".to_string()])),
+            Err(SeccompError::UnknownSyscall(v)) if v == "frobnicate"
+        ));
+        // Every syscall that is restricted by default is known.
+        compile(&SyscallFilter::restrictive()).unwrap().unwrap();
+
+        let program = compile(&SyscallFilter::Allow(vec![
+            "read".to_string(),
+            "write".to_string(),
+        ]))
+        .unwrap()
+        .unwrap();
+        let [.., read, write, otherwise, matched] = &program[..] else {
+            panic!("the program is too short");
+        };
+        assert_eq!((read.k, read.jt), (libc::SYS_read as u32, 2));
+        assert_eq!((write.k, write.jt), (libc::SYS_write as u32, 1));
+        assert_eq!(otherwise.k, SECCOMP_RET_ERRNO | Errno::EPERM as u32);
+        assert_eq!(matched.k, SECCOMP_RET_ALLOW);
+    }
+}
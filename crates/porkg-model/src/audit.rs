@@ -0,0 +1,270 @@
+//! Audits of the packages in the store against a database of vulnerabilities in the [OSV](https://ossf.github.io/osv-schema/)
+//! format.
+//!
+//! Packages only have a name in the store, so they are matched by the version at the end of their name, such as
+//! `zlib-1.3.1`, the way Nix names packages (see [`split_name`]).
+
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+
+/// A vulnerability, of which only what is needed to match packages is read.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Vulnerability {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
+    /// Other identifiers of the vulnerability, such as its CVE.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    #[serde(default, skip_serializing)]
+    pub affected: Vec<Affected>,
+}
+
+/// The versions of a package that a vulnerability affects.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Affected {
+    pub package: AffectedPackage,
+    #[serde(default)]
+    pub ranges: Vec<Range>,
+    /// Versions that are affected, in addition to those in the ranges.
+    #[serde(default)]
+    pub versions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AffectedPackage {
+    pub name: String,
+    #[serde(default)]
+    pub ecosystem: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Range {
+    /// How the versions of the range are compared. Only `SEMVER` and `ECOSYSTEM` ranges are matched, the latter as
+    /// described in [`compare_versions`]; `GIT` ranges name commits, which packages don't record.
+    #[serde(rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub events: Vec<Event>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Introduced(String),
+    Fixed(String),
+    LastAffected(String),
+    Limit(String),
+}
+
+impl Event {
+    fn version(&self) -> &str {
+        match self {
+            Event::Introduced(v) | Event::Fixed(v) | Event::LastAffected(v) | Event::Limit(v) => v,
+        }
+    }
+}
+
+impl Range {
+    /// Checks whether `version` is in the range.
+    pub fn contains(&self, version: &str) -> bool {
+        if !matches!(self.kind.as_str(), "SEMVER" | "ECOSYSTEM") {
+            return false;
+        }
+        let mut events: Vec<_> = self.events.iter().collect();
+        events.sort_by(|a, b| compare_versions(a.version(), b.version()));
+
+        let mut affected = false;
+        for event in events {
+            let order = compare_versions(version, event.version());
+            match event {
+                Event::Introduced(_) if order.is_ge() => affected = true,
+                Event::Fixed(_) | Event::Limit(_) if order.is_ge() => affected = false,
+                Event::LastAffected(_) if order.is_gt() => affected = false,
+                _ => {}
+            }
+        }
+        affected
+    }
+}
+
+impl Vulnerability {
+    /// Checks whether the vulnerability affects `version` of the package `name`, in any of `ecosystems` if there are
+    /// some.
+    pub fn affects(&self, name: &str, version: &str, ecosystems: &[String]) -> bool {
+        self.affected.iter().any(|affected| {
+            affected.package.name == name
+                && (ecosystems.is_empty() || ecosystems.contains(&affected.package.ecosystem))
+                && (affected.versions.iter().any(|v| v == version)
+                    || affected.ranges.iter().any(|v| v.contains(version)))
+        })
+    }
+}
+
+/// Splits a package name into its name and version, at the first `-` that is followed by something other than a
+/// letter, such as `zlib-1.3.1` or `openssl-3.0.13-dev`.
+pub fn split_name(name: &str) -> (&str, Option<&str>) {
+    let split = name.char_indices().find(|(i, v)| {
+        *v == '-'
+            && name[i + 1..]
+                .chars()
+                .next()
+                .is_some_and(|v| !v.is_ascii_alphabetic())
+    });
+    match split {
+        Some((i, _)) => (&name[..i], Some(&name[i + 1..])),
+        None => (name, None),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Segment<'a> {
+    /// Text sorts before numbers, as it marks a pre-release, such as the `rc` of `1.0rc1`.
+    Text(&'a str),
+    /// The digits of a number, without leading zeros.
+    Number(usize, &'a str),
+}
+
+fn segments(version: &str) -> Vec<Segment<'_>> {
+    let mut result = Vec::new();
+    let mut rest = version;
+    while let Some(start) = rest.find(|v: char| v.is_ascii_alphanumeric()) {
+        rest = &rest[start..];
+        let digits = rest.starts_with(|v: char| v.is_ascii_digit());
+        let end = rest
+            .find(|v: char| !v.is_ascii_alphanumeric() || v.is_ascii_digit() != digits)
+            .unwrap_or(rest.len());
+        let segment = &rest[..end];
+        result.push(if digits {
+            let number = segment.trim_start_matches('0');
+            Segment::Number(number.len(), number)
+        } else {
+            Segment::Text(segment)
+        });
+        rest = &rest[end..];
+    }
+    result
+}
+
+/// Compares versions by their runs of digits, which are compared as numbers, and the runs of letters between them,
+/// which are compared as text and mark pre-releases, so that `1.0rc1` is earlier than `1.0` and `1.0.1`. Other
+/// characters only separate runs. `0` is the earliest version, as OSV uses it to mean that every version before a fix
+/// is affected.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    match (a, b) {
+        _ if a == b => return Ordering::Equal,
+        ("0", _) => return Ordering::Less,
+        (_, "0") => return Ordering::Greater,
+        _ => {}
+    }
+
+    let (a, b) = (segments(a), segments(b));
+    for (x, y) in a.iter().zip(b.iter()) {
+        let order = x.cmp(y);
+        if order.is_ne() {
+            return order;
+        }
+    }
+    // A version that continues with text after the other one ends is a pre-release of it.
+    match (a.get(b.len()), b.get(a.len())) {
+        (Some(Segment::Text(_)), _) => Ordering::Less,
+        (_, Some(Segment::Text(_))) => Ordering::Greater,
+        _ => a.len().cmp(&b.len()),
+    }
+}
+
+/// A package in the store that a vulnerability affects.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finding {
+    pub hash: String,
+    pub name: String,
+    pub version: String,
+    /// The path of the package in the store.
+    pub path: String,
+    pub vulnerabilities: Vec<Vulnerability>,
+}
+
+/// A package that references an affected package, directly or indirectly, and so is rebuilt once the affected
+/// packages are updated.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Rebuild {
+    pub hash: String,
+    pub name: String,
+}
+
+/// The result of auditing the closure of a package or profile.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditReport {
+    /// The number of packages in the closure.
+    pub packages: usize,
+    pub findings: Vec<Finding>,
+    pub rebuild: Vec<Rebuild>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn package_names() {
+        assert_eq!(split_name("zlib-1.3.1"), ("zlib", Some("1.3.1")));
+        assert_eq!(
+            split_name("openssl-3.0.13-dev"),
+            ("openssl", Some("3.0.13-dev"))
+        );
+        assert_eq!(split_name("xz-utils-5.6.0"), ("xz-utils", Some("5.6.0")));
+        assert_eq!(split_name("busybox"), ("busybox", None));
+    }
+
+    #[test]
+    fn version_order() {
+        let ordered = ["0", "1.0rc1", "1.0", "1.0.1", "1.2", "1.10", "2.0"];
+        for (i, a) in ordered.iter().enumerate() {
+            for (j, b) in ordered.iter().enumerate() {
+                assert_eq!(compare_versions(a, b), i.cmp(&j), "{a} and {b}");
+            }
+        }
+        assert_eq!(compare_versions("1.01", "1.1"), Ordering::Equal);
+    }
+
+    #[test]
+    fn match_vulnerabilities() {
+        let vulnerability: Vulnerability = serde_json::from_str(
+            r#"{
+                "id": "OSV-2024-1",
+                "aliases": ["CVE-2024-3094"],
+                "modified": "2024-03-29T00:00:00Z",
+                "affected": [{
+                    "package": {"name": "xz", "ecosystem": "Nix"},
+                    "ranges": [
+                        {"type": "ECOSYSTEM", "events": [{"introduced": "5.6.0"}, {"fixed": "5.6.2"}]},
+                        {"type": "GIT", "events": [{"introduced": "0"}]}
+                    ],
+                    "versions": ["5.4.0-backdoored"]
+                }]
+            }"#,
+        )
+        .unwrap();
+
+        let affects = |version| vulnerability.affects("xz", version, &[]);
+        assert!(affects("5.6.0"));
+        assert!(affects("5.6.1"));
+        assert!(affects("5.4.0-backdoored"));
+        assert!(!affects("5.4.6"));
+        assert!(!affects("5.6.2"));
+        assert!(!vulnerability.affects("zlib", "5.6.0", &[]));
+        assert!(!vulnerability.affects("xz", "5.6.0", &["Debian".to_string()]));
+
+        let range = Range {
+            kind: "SEMVER".to_string(),
+            events: vec![
+                Event::Introduced("0".to_string()),
+                Event::LastAffected("1.2".to_string()),
+            ],
+        };
+        assert!(range.contains("0.1"));
+        assert!(range.contains("1.2"));
+        assert!(!range.contains("1.2.1"));
+    }
+}
@@ -11,7 +11,7 @@ const BASE32: Encoding = new_encoding! {
     padding: None,
 };
 
-pub(crate) struct Base32<const SIZE: usize>(pub [u8; SIZE]);
+pub struct Base32<const SIZE: usize>(pub [u8; SIZE]);
 
 impl<const SIZE: usize> Default for Base32<SIZE> {
     fn default() -> Self {
@@ -45,7 +45,7 @@ impl<const SIZE: usize> std::fmt::Display for Base32<SIZE> {
 
 #[derive(Debug, Error)]
 #[error("expected base32 of {} bytes", _0)]
-pub(crate) struct InvalidBase32(usize);
+pub struct InvalidBase32(usize);
 
 impl<const SIZE: usize> FromStr for Base32<SIZE> {
     type Err = InvalidBase32;
@@ -0,0 +1,81 @@
+//! Static binary caches: directories of plain files that publish packages without a daemon, so that they can be
+//! served from object storage or any static host.
+//!
+//! ```text
+//! porkg-cache.json      the index of the cache (CacheIndex)
+//! pkg/<hash>.json       the metadata of a package (CacheEntry)
+//! pkg/<hash>.tar.zst    the directory of the package in the store, as a zstd-compressed tar archive
+//! chunk/<hash>.zst      a zstd-compressed piece of the archives of one or more packages
+//! ```
+//!
+//! A package is either stored as a whole archive, or as the uncompressed tar archive split into content-defined
+//! chunks, which are named by their hash, so that packages with similar content share most of their chunks and only
+//! the chunks that the cache lacks are uploaded.
+//!
+//! Each package is published after everything that it references, and a package's metadata is written after its
+//! archive or chunks, so a cache that is being updated never lists a package that can't be fetched.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+pub const INDEX_FILE: &str = "porkg-cache.json";
+pub const VERSION: u32 = 1;
+
+pub fn entry_path(hash: &str) -> String {
+    format!("pkg/{hash}.json")
+}
+
+pub fn archive_path(hash: &str) -> String {
+    format!("pkg/{hash}.tar.zst")
+}
+
+pub fn chunk_path(hash: &str) -> String {
+    format!("chunk/{hash}.zst")
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheIndex {
+    pub version: u32,
+    /// The names of the packages in the cache, by hash.
+    #[serde(default)]
+    pub packages: BTreeMap<String, String>,
+}
+
+impl Default for CacheIndex {
+    fn default() -> Self {
+        Self {
+            version: VERSION,
+            packages: BTreeMap::new(),
+        }
+    }
+}
+
+impl CacheIndex {
+    /// Adds the packages of another index, such as one written for more closures into the same directory.
+    pub fn merge(&mut self, other: CacheIndex) {
+        self.packages.extend(other.packages);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub name: String,
+    /// The hashes of the other packages that must be present for this package to be usable.
+    #[serde(default)]
+    pub references: Vec<String>,
+    /// The path of the archive, relative to the root of the cache, unless the package is stored as chunks.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive: Option<String>,
+    /// The hashes of the chunks that the archive is split into, in order.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub chunks: Vec<String>,
+    /// The compression of the archive or of each chunk, which is currently always `zstd`.
+    pub compression: String,
+    /// The size of the archive, in bytes. Whole archives are measured compressed, while chunked archives are measured
+    /// uncompressed, because their chunks may be shared.
+    pub size: u64,
+    /// The signature of the package by the daemon that wrote the cache, if it has a signing key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
+}
@@ -0,0 +1,152 @@
+//! Channels, such as `stable` and `testing`, which each pin a snapshot of a repository index.
+//!
+//! An index snapshot is a package in the store whose source contains a [`crate::workspace::LOCK_FILE`], listing the
+//! hash of every package in the repository. Builds that resolve their dependencies through a channel use the lock of
+//! the snapshot that the channel pins, so every machine that follows the channel builds with the same packages until
+//! the channel is advanced.
+//!
+//! An index can warn about versions of packages with an [`Advisory`], such as when a version is vulnerable. Versions
+//! that were yanked aren't resolved through the index anymore, unless the lock of the package already used them (see
+//! [`check_yanked`]).
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{package::LockDefinition, workspace::Lock};
+
+/// The snapshot that a channel pinned when it was resolved.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Channel {
+    pub name: String,
+    /// The hash of the index snapshot.
+    pub index: String,
+}
+
+/// What an index says about a version of a package.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Advisory {
+    /// Whether the version was withdrawn, so that new locks don't resolve to it.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub yanked: bool,
+    /// The identifiers of the vulnerabilities that affect the version, such as `CVE-2024-3094`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// A dependency that was resolved to a version that the index yanked.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{name} resolves to {hash}, which was yanked{}", display_reason(&.advisory.reason))]
+pub struct Yanked {
+    pub name: String,
+    pub hash: String,
+    pub advisory: Advisory,
+}
+
+fn display_reason(reason: &Option<String>) -> String {
+    reason
+        .as_ref()
+        .map_or_else(String::new, |v| format!(": {v}"))
+}
+
+/// Checks that none of the dependencies that `definition` resolved through `index` were yanked. Versions that
+/// `existing` (the lock that the package was built with before) already pins are allowed, so that packages keep
+/// building until they are updated.
+pub fn check_yanked(
+    index: &Lock,
+    definition: &LockDefinition,
+    existing: &Lock,
+) -> Result<(), Vec<Yanked>> {
+    let resolved = definition
+        .dependencies
+        .iter()
+        .chain(definition.build_dependencies.iter())
+        .map(|(name, hash)| (name.as_str(), hash))
+        .chain(definition.base.iter().map(|hash| ("base", hash)));
+    let yanked: Vec<_> = resolved
+        .filter_map(|(name, hash)| {
+            let advisory = index.advisories.get(hash).filter(|v| v.yanked)?;
+            if existing.packages.values().any(|v| v == hash) {
+                return None;
+            }
+            Some(Yanked {
+                name: name.to_string(),
+                hash: hash.clone(),
+                advisory: advisory.clone(),
+            })
+        })
+        .collect();
+    if yanked.is_empty() {
+        Ok(())
+    } else {
+        Err(yanked)
+    }
+}
+
+/// Checks whether a channel name can be used as a file name in the store.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with('.')
+        && name
+            .chars()
+            .all(|v| v.is_ascii_alphanumeric() || matches!(v, '-' | '_' | '.'))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn channel_names() {
+        assert!(is_valid_name("stable"));
+        assert!(is_valid_name("nightly-2024.06"));
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name(".."));
+        assert!(!is_valid_name("stable/x"));
+    }
+
+    #[test]
+    fn refuse_yanked_versions() {
+        let index: Lock = toml::from_str(
+            r#"
+            [packages]
+            xz = "blake3-xz"
+            zlib = "blake3-zlib"
+
+            [advisories.blake3-xz]
+            yanked = true
+            ids = ["CVE-2024-3094"]
+            reason = "backdoored"
+
+            [advisories.blake3-zlib]
+            ids = ["CVE-2022-37434"]
+            "#,
+        )
+        .unwrap();
+        let definition = LockDefinition {
+            dependencies: [
+                ("xz".to_string(), "blake3-xz".to_string()),
+                ("zlib".to_string(), "blake3-zlib".to_string()),
+            ]
+            .into(),
+            build_dependencies: Default::default(),
+            base: None,
+        };
+
+        // Vulnerable versions are only reported, but yanked ones are refused.
+        let yanked = check_yanked(&index, &definition, &Lock::default()).unwrap_err();
+        assert_eq!(
+            yanked.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            ["xz resolves to blake3-xz, which was yanked: backdoored"]
+        );
+        assert_eq!(yanked[0].advisory.ids, ["CVE-2024-3094"]);
+
+        // Unless the existing lock already pins them.
+        let existing = Lock {
+            packages: [("xz".to_string(), "blake3-xz".to_string())].into(),
+            ..Default::default()
+        };
+        check_yanked(&index, &definition, &existing).unwrap();
+    }
+}
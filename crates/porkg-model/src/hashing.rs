@@ -1,12 +1,14 @@
+mod kinds;
+pub mod resumable;
 mod supported;
 
 use std::{
     collections::{BTreeMap, BTreeSet},
     ffi::{OsStr, OsString},
-    os::unix::ffi::OsStrExt as _,
     path::{Path, PathBuf},
 };
 
+pub use kinds::*;
 pub use supported::*;
 
 /// A hashing mechanism that is stable.
@@ -118,6 +120,22 @@ impl StableHash for i8 {
     }
 }
 
+/// Floats are hashed as their bit patterns, with every NaN hashed as the same one.
+macro_rules! impl_float {
+    ($ty: ident) => {
+        impl StableHash for $ty {
+            #[inline(always)]
+            fn update<H: StableHasher>(&self, h: &mut H) {
+                let value = if self.is_nan() { $ty::NAN } else { *self };
+                value.to_bits().update(h);
+            }
+        }
+    };
+}
+
+impl_float!(f32);
+impl_float!(f64);
+
 impl StableHash for usize {
     #[inline(always)]
     fn update<H: StableHasher>(&self, h: &mut H) {
@@ -135,28 +153,29 @@ impl StableHash for isize {
 impl StableHash for OsString {
     #[inline(always)]
     fn update<H: StableHasher>(&self, h: &mut H) {
-        self.as_os_str().as_bytes().update(h)
+        self.as_os_str().as_encoded_bytes().update(h)
     }
 }
 
 impl StableHash for &OsStr {
     #[inline(always)]
     fn update<H: StableHasher>(&self, h: &mut H) {
-        self.as_bytes().update(h)
+        // These are the raw bytes on unix, so hashes don't change from using the platform-neutral accessor.
+        self.as_encoded_bytes().update(h)
     }
 }
 
 impl StableHash for PathBuf {
     #[inline(always)]
     fn update<H: StableHasher>(&self, h: &mut H) {
-        self.as_os_str().as_bytes().update(h)
+        self.as_os_str().as_encoded_bytes().update(h)
     }
 }
 
 impl StableHash for &Path {
     #[inline(always)]
     fn update<H: StableHasher>(&self, h: &mut H) {
-        self.as_os_str().as_bytes().update(h)
+        self.as_os_str().as_encoded_bytes().update(h)
     }
 }
 
@@ -220,3 +239,19 @@ impl<T1: StableHash, T2: StableHash> StableHash for (T1, T2) {
         h.update_hash(&self.0).update_hash(&self.1);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn hash_floats() {
+        let hash = |v: f64| v.hash(SupportedHasher::blake3());
+        assert_eq!(hash(f64::NAN), hash(-f64::NAN));
+        assert_eq!(hash(f64::NAN), hash(f64::from_bits(f64::NAN.to_bits() | 1)));
+        assert_ne!(hash(0.0), hash(-0.0));
+        assert_ne!(hash(1.0), hash(1.5));
+        assert_eq!(1.5f64.hash(SupportedHasher::blake3()), hash(1.5));
+        assert_ne!(1.5f32.hash(SupportedHasher::blake3()), hash(1.5));
+    }
+}
@@ -0,0 +1,95 @@
+//! Hashes that refer to specific kinds of packages in the store.
+//!
+//! A build is identified by the package that holds its manifest and source, and its inputs are packages that were
+//! built (or imported) before. Both are [`SupportedHash`]es in the store, so they are wrapped to keep one from being
+//! passed where the other is expected. Wrapping a hash is explicit (`new`), while getting the [`SupportedHash`] back
+//! for the store is not, through [`From`] and [`AsRef`]. Checksums of upstream downloads are a separate type
+//! altogether (see [`crate::source::SourceHash`]).
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+use super::{ParseError, StableHash, StableHasher, SupportedHash};
+
+macro_rules! hash_kind {
+    ($(#[$meta:meta])* $name:ident) => {
+        $(#[$meta])*
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+        #[serde(transparent)]
+        pub struct $name(SupportedHash);
+
+        impl $name {
+            pub const fn new(hash: SupportedHash) -> Self {
+                Self(hash)
+            }
+        }
+
+        impl From<$name> for SupportedHash {
+            fn from(value: $name) -> Self {
+                value.0
+            }
+        }
+
+        impl AsRef<SupportedHash> for $name {
+            fn as_ref(&self) -> &SupportedHash {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                self.0.fmt(f)
+            }
+        }
+
+        impl FromStr for $name {
+            type Err = ParseError<String>;
+
+            fn from_str(s: &str) -> Result<Self, Self::Err> {
+                s.parse().map(Self)
+            }
+        }
+
+        impl StableHash for $name {
+            #[inline(always)]
+            fn update<H: StableHasher>(&self, h: &mut H) {
+                self.0.update(h)
+            }
+        }
+    };
+}
+
+hash_kind!(
+    /// The hash of a package that holds the manifest and source of a build (`<hash>/src/porkg.toml`).
+    ManifestHash
+);
+
+hash_kind!(
+    /// The hash of a package that was built or imported, which builds can depend on.
+    OutputHash
+);
+
+#[cfg(test)]
+mod test {
+    use crate::hashing::{StableHashExt as _, SupportedHasher};
+
+    use super::*;
+
+    #[test]
+    fn wrap_transparently() {
+        let hash = SupportedHash::Blake3([7; 32]);
+        let output = OutputHash::new(hash);
+        assert_eq!(output.to_string(), hash.to_string());
+        assert_eq!(output.to_string().parse::<OutputHash>().unwrap(), output);
+        assert_eq!(
+            toml::Value::try_from(output).unwrap(),
+            toml::Value::try_from(hash).unwrap()
+        );
+        assert_eq!(
+            ManifestHash::new(hash).hash(SupportedHasher::blake3()),
+            hash.hash(SupportedHasher::blake3())
+        );
+        assert_eq!(SupportedHash::from(output), hash);
+    }
+}
@@ -0,0 +1,200 @@
+//! Blake3 hashing that can be resumed, so that a large input that was partly hashed by an earlier attempt (such as an
+//! interrupted download) doesn't have to be read again.
+//!
+//! The input is hashed in segments of [`SEGMENT_LEN`], which are subtrees of the Blake3 tree. A [`Checkpoint`] holds
+//! the chaining values of the finished segments, merged as far as they can be, and the hash continues from the end of
+//! the last one.
+
+use blake3::hazmat::{
+    merge_subtrees_non_root, merge_subtrees_root, ChainingValue, HasherExt as _, Mode,
+};
+use serde::{Deserialize, Serialize};
+
+use super::SupportedHash;
+
+/// The length of the segments of the input, which is a power of two chunks.
+pub const SEGMENT_LEN: u64 = 1 << 20;
+
+/// The state of a [`ResumableHasher`] at the end of a segment.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Checkpoint {
+    offset: u64,
+    stack: Vec<ChainingValue>,
+}
+
+impl Checkpoint {
+    /// Gets the length of the input that was hashed, where the input continues after resuming.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+/// A Blake3 hasher with the same output as [`SupportedHasher::blake3`](super::SupportedHasher::blake3).
+#[derive(Debug, Clone, Default)]
+pub struct ResumableHasher {
+    /// The chaining values of the finished segments, merged into subtrees like [`blake3::Hasher`] does: the last one
+    /// is only merged once there is more input, as it could be the last of the input.
+    stack: Vec<ChainingValue>,
+    segments: u64,
+    segment: blake3::Hasher,
+    segment_len: u64,
+    checkpoint: Option<Checkpoint>,
+}
+
+impl ResumableHasher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Continues hashing from a checkpoint, or fails if the checkpoint is invalid.
+    pub fn resume(checkpoint: &Checkpoint) -> Option<Self> {
+        let segments = checkpoint.offset / SEGMENT_LEN;
+        let valid = checkpoint.offset % SEGMENT_LEN == 0
+            && segments >= 2
+            && checkpoint.stack.len() == (segments - 1).count_ones() as usize + 1;
+        if !valid {
+            return None;
+        }
+
+        let mut segment = blake3::Hasher::new();
+        segment.set_input_offset(checkpoint.offset);
+        Some(Self {
+            stack: checkpoint.stack.clone(),
+            segments,
+            segment,
+            segment_len: 0,
+            checkpoint: Some(checkpoint.clone()),
+        })
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            // A full segment is only finished once there is more input, as it is the root if it is the only one.
+            if self.segment_len == SEGMENT_LEN {
+                self.finish_segment();
+            }
+            if self.segment_len == 0 {
+                self.merge_stack();
+            }
+            let len = data.len().min((SEGMENT_LEN - self.segment_len) as usize);
+            self.segment.update(&data[..len]);
+            self.segment_len += len as u64;
+            data = &data[len..];
+        }
+    }
+
+    /// Merges the subtrees that are complete, as more input follows the finished segments.
+    fn merge_stack(&mut self) {
+        let post_merge_len = self.segments.count_ones() as usize;
+        while self.stack.len() > post_merge_len {
+            let right = self.stack.pop().unwrap();
+            let left = self.stack.pop().unwrap();
+            self.stack
+                .push(merge_subtrees_non_root(&left, &right, Mode::Hash));
+        }
+    }
+
+    fn finish_segment(&mut self) {
+        self.stack.push(self.segment.finalize_non_root());
+        self.segments += 1;
+        self.segment = blake3::Hasher::new();
+        self.segment.set_input_offset(self.segments * SEGMENT_LEN);
+        self.segment_len = 0;
+        // Before the first two segments are merged, the first could still turn out to be the root.
+        if self.segments >= 2 {
+            self.checkpoint = Some(Checkpoint {
+                offset: self.segments * SEGMENT_LEN,
+                stack: self.stack.clone(),
+            });
+        }
+    }
+
+    /// Gets the state at the end of the last segment that was finished, if enough of the input was hashed to resume.
+    pub fn checkpoint(&self) -> Option<&Checkpoint> {
+        self.checkpoint.as_ref()
+    }
+
+    pub fn finalize(&self) -> SupportedHash {
+        let (mut right, rest) = match self.stack.split_last() {
+            None => return SupportedHash::Blake3(*self.segment.finalize().as_bytes()),
+            // A resumed hasher without more input ends with the last segment that was finished.
+            Some((last, rest)) if self.segment_len == 0 => (*last, rest),
+            Some(_) => (self.segment.finalize_non_root(), &self.stack[..]),
+        };
+        let (root, rest) = rest
+            .split_first()
+            .expect("the input has at least two segments");
+        for left in rest.iter().rev() {
+            right = merge_subtrees_non_root(left, &right, Mode::Hash);
+        }
+        SupportedHash::Blake3(*merge_subtrees_root(root, &right, Mode::Hash).as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn input(len: u64) -> Vec<u8> {
+        (0..len).map(|v| (v % 251) as u8).collect()
+    }
+
+    #[test]
+    fn match_blake3() {
+        const SEGMENT: u64 = SEGMENT_LEN;
+        for len in [
+            0,
+            1,
+            SEGMENT,
+            SEGMENT + 1,
+            2 * SEGMENT,
+            3 * SEGMENT + 5,
+            4 * SEGMENT,
+            5 * SEGMENT + 1024,
+        ] {
+            let data = input(len);
+            let expected = SupportedHash::Blake3(*blake3::hash(&data).as_bytes());
+            let mut hasher = ResumableHasher::new();
+            for piece in data.chunks(300_000) {
+                hasher.update(piece);
+            }
+            assert_eq!(hasher.finalize(), expected, "{len}");
+        }
+    }
+
+    #[test]
+    fn resume_from_checkpoint() {
+        for len in [3 * SEGMENT_LEN, 4 * SEGMENT_LEN + 7, 5 * SEGMENT_LEN] {
+            let data = input(len);
+            let expected = SupportedHash::Blake3(*blake3::hash(&data).as_bytes());
+
+            let mut hasher = ResumableHasher::new();
+            let mut checkpoint = None;
+            for piece in data.chunks(SEGMENT_LEN as usize / 2) {
+                hasher.update(piece);
+                checkpoint = hasher.checkpoint().cloned().or(checkpoint);
+            }
+            let checkpoint = checkpoint.unwrap();
+            assert!(checkpoint.offset() >= 2 * SEGMENT_LEN);
+
+            let offset = checkpoint.offset() as usize;
+            let mut resumed = ResumableHasher::resume(&checkpoint).unwrap();
+            // Without more input, the hash is of the input up to the checkpoint.
+            assert_eq!(
+                resumed.finalize(),
+                SupportedHash::Blake3(*blake3::hash(&data[..offset]).as_bytes())
+            );
+            resumed.update(&data[offset..]);
+            assert_eq!(resumed.finalize(), expected, "{len}");
+        }
+
+        let mut hasher = ResumableHasher::new();
+        hasher.update(&input(SEGMENT_LEN + 1));
+        assert_eq!(hasher.checkpoint(), None);
+        let invalid = Checkpoint {
+            offset: 3 * SEGMENT_LEN,
+            stack: Vec::new(),
+        };
+        assert!(ResumableHasher::resume(&invalid).is_none());
+    }
+}
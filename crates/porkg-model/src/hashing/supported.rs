@@ -6,6 +6,7 @@ use thiserror::Error;
 use crate::{
     base32::Base32,
     hashing::{StableHash, StableHasher, StableHasherExt},
+    nix::{decode_base64, decode_hex},
 };
 
 /// Supported hashing algorithms.
@@ -67,6 +68,7 @@ impl PartialOrd for SupportedHash {
 }
 
 const PREFIX_BLAKE3: &str = "blake3-";
+const PREFIX_BLAKE3_COLON: &str = "blake3:";
 
 impl std::fmt::Debug for SupportedHash {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -87,13 +89,21 @@ impl std::fmt::Display for SupportedHash {
 impl FromStr for SupportedHash {
     type Err = ParseError<String>;
 
+    /// Parses a hash written as base32 (which is how hashes are displayed), hex, or base64 as in SRI strings. The
+    /// algorithm can also be separated with a colon, as in checksums published by other tools.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if let Some(val) = s.strip_prefix(PREFIX_BLAKE3) {
-            let b32: Base32<32> = val.parse().map_err(Into::<ParseError<String>>::into)?;
-            Ok(SupportedHash::Blake3(b32.0))
-        } else {
-            Err(ParseError::UnknownType(s.to_string()))
+        let Some(val) = s
+            .strip_prefix(PREFIX_BLAKE3)
+            .or_else(|| s.strip_prefix(PREFIX_BLAKE3_COLON))
+        else {
+            return Err(ParseError::UnknownType(s.to_string()));
+        };
+
+        if let Some(digest) = decode_hex(val).or_else(|| decode_base64(val)) {
+            return Ok(SupportedHash::Blake3(digest));
         }
+        let b32: Base32<32> = val.parse().map_err(Into::<ParseError<String>>::into)?;
+        Ok(SupportedHash::Blake3(b32.0))
     }
 }
 
@@ -128,3 +138,37 @@ impl<T: std::fmt::Debug> From<crate::base32::InvalidBase32> for ParseError<T> {
         Self::InvalidBase32
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_hash_encodings() {
+        let hash = SupportedHasher::blake3().finalize();
+        let SupportedHash::Blake3(digest) = hash;
+        let hex = data_encoding::HEXLOWER.encode(&digest);
+        let mut base64 = String::new();
+        data_encoding::BASE64
+            .encode_write(&digest, &mut base64)
+            .unwrap();
+
+        for text in [
+            hash.to_string(),
+            format!("blake3-{hex}"),
+            format!("blake3:{}", hex.to_uppercase()),
+            format!("blake3-{base64}"),
+            format!("blake3-{}", base64.trim_end_matches('=')),
+        ] {
+            let parsed: SupportedHash = text.parse().unwrap();
+            assert_eq!(parsed, hash, "{text}");
+            assert_eq!(parsed.to_string(), hash.to_string());
+        }
+
+        assert!(matches!(
+            format!("sha256-{base64}").parse::<SupportedHash>(),
+            Err(ParseError::UnknownType(_))
+        ));
+        assert!("blake3-abc".parse::<SupportedHash>().is_err());
+    }
+}
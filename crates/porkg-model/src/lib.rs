@@ -1,3 +1,20 @@
+pub mod audit;
+#[cfg(not(feature = "__fuzz"))]
 mod base32;
+#[cfg(feature = "__fuzz")]
+pub mod base32;
+pub mod cache;
+pub mod channel;
 pub mod hashing;
+pub mod lint;
+pub mod log;
+pub mod nix;
+pub mod overlay;
 pub mod package;
+pub mod profile;
+pub mod provenance;
+pub mod run;
+pub mod source;
+pub mod store;
+pub mod time;
+pub mod workspace;
@@ -0,0 +1,349 @@
+//! Checks manifests for problems that are valid according to the schema, but are probably mistakes.
+
+use std::{collections::BTreeSet, fmt};
+
+use serde::{Deserialize, Serialize};
+
+use crate::package::{is_valid_executable_name, Dependency, Package, FORMAT_VERSION};
+
+/// Environment variables that change how every program in the build is linked.
+const LINKER_VARIABLES: &[&str] = &["LD_PRELOAD", "LD_LIBRARY_PATH", "LD_AUDIT"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    Info,
+    Warning,
+    /// The package can't be built as written.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finding {
+    pub severity: Severity,
+    /// Identifies the kind of problem, such as `unused-build-dependency`.
+    pub code: String,
+    /// The location of the problem within the manifest, such as `build-phase.env.PATH`.
+    pub path: String,
+    pub message: String,
+}
+
+#[derive(Default)]
+struct Findings(Vec<Finding>);
+
+impl Findings {
+    fn push(&mut self, severity: Severity, code: &str, path: String, message: String) {
+        self.0.push(Finding {
+            severity,
+            code: code.to_string(),
+            path,
+            message,
+        });
+    }
+}
+
+/// Finds the names that a phase refers to with `${name}`.
+fn references(value: &str) -> impl Iterator<Item = &str> {
+    value
+        .split("${")
+        .skip(1)
+        .filter_map(|v| Some(v.split_once('}')?.0))
+}
+
+/// Checks whether a version requirement matches more than one version.
+fn is_pinned(version: &str) -> bool {
+    !version.is_empty()
+        && !version.contains(['*', '^', '~', '<', '>', '|', ' '])
+        && !version.split('.').any(|v| v == "x")
+}
+
+fn check_dependency(findings: &mut Findings, path: String, dependency: &Dependency) {
+    if dependency.target.is_empty() {
+        findings.push(
+            Severity::Error,
+            "missing-target",
+            format!("{path}.target"),
+            format!("no target of {} is selected", dependency.name),
+        );
+    }
+    if !is_pinned(&dependency.version) {
+        findings.push(
+            Severity::Warning,
+            "unpinned-dependency",
+            format!("{path}.version"),
+            format!(
+                "{:?} allows more than one version of {}, so the lock decides which one is built with",
+                dependency.version, dependency.name
+            ),
+        );
+    }
+}
+
+/// Lints a manifest.
+pub fn lint(package: &Package) -> Vec<Finding> {
+    let mut findings = Findings::default();
+
+    if package.package.format > FORMAT_VERSION {
+        findings.push(
+            Severity::Error,
+            "unsupported-format",
+            "package.format".to_string(),
+            format!(
+                "format {} is newer than the supported format {FORMAT_VERSION}",
+                package.package.format
+            ),
+        );
+    }
+    if package.package.targets.is_empty() {
+        findings.push(
+            Severity::Error,
+            "missing-targets",
+            "package.targets".to_string(),
+            "the package doesn't produce any targets".to_string(),
+        );
+    }
+
+    for (section, dependencies) in [
+        ("dependencies", &package.dependencies),
+        ("build-dependencies", &package.build_dependencies),
+    ] {
+        for (name, dependency) in dependencies {
+            check_dependency(&mut findings, format!("{section}.{name}"), dependency);
+        }
+    }
+    if let Some(base) = &package.base {
+        check_dependency(&mut findings, "base".to_string(), base);
+    }
+
+    if package.phases().next().is_none() {
+        findings.push(
+            Severity::Info,
+            "no-phases",
+            "build-phase".to_string(),
+            "the package doesn't run anything, so its targets will be empty".to_string(),
+        );
+    }
+
+    // Phases can refer to dependencies and to the targets that they produce.
+    let known = package
+        .dependencies
+        .keys()
+        .chain(package.build_dependencies.keys())
+        .chain(package.package.targets.iter())
+        .map(String::as_str)
+        .collect::<BTreeSet<_>>();
+    let mut used = BTreeSet::new();
+    for (phase, executable) in package.phases() {
+        if executable.exec.is_empty() {
+            findings.push(
+                Severity::Error,
+                "empty-exec",
+                format!("{phase}.exec"),
+                "the phase doesn't run a command".to_string(),
+            );
+        }
+
+        let values = executable
+            .exec
+            .iter()
+            .enumerate()
+            .map(|(i, v)| (format!("{phase}.exec[{i}]"), v))
+            .chain(
+                executable
+                    .env
+                    .iter()
+                    .map(|(k, v)| (format!("{phase}.env.{k}"), v)),
+            );
+        for (path, value) in values {
+            for name in references(value) {
+                used.insert(name);
+                if !known.contains(name) {
+                    findings.push(
+                        Severity::Error,
+                        "undeclared-reference",
+                        path.clone(),
+                        format!("${{{name}}} is not a dependency or target of the package"),
+                    );
+                }
+            }
+            // Paths outside of the inputs aren't available in the sandbox, or make the build depend on the host.
+            if value.split(':').any(|v| v.starts_with('/')) {
+                findings.push(
+                    Severity::Warning,
+                    "host-path",
+                    path.clone(),
+                    format!("{value:?} refers to the host file system instead of a dependency"),
+                );
+            }
+        }
+
+        for variable in executable.env.keys() {
+            if LINKER_VARIABLES.contains(&variable.as_str()) {
+                findings.push(
+                    Severity::Warning,
+                    "linker-override",
+                    format!("{phase}.env.{variable}"),
+                    format!("{variable} changes how every program in the build is linked"),
+                );
+            }
+        }
+    }
+
+    for (name, executable) in &package.executables {
+        if !is_valid_executable_name(name) {
+            findings.push(
+                Severity::Error,
+                "invalid-executable-name",
+                format!("executables.{name}"),
+                format!("{name:?} can't be the name of a file"),
+            );
+        }
+        if executable.exec.is_empty() {
+            findings.push(
+                Severity::Error,
+                "empty-exec",
+                format!("executables.{name}.exec"),
+                "the executable doesn't run a command".to_string(),
+            );
+        }
+    }
+
+    // Runtime dependencies are used by the targets rather than the phases, so only build dependencies are checked.
+    for name in package.build_dependencies.keys() {
+        if !used.contains(name.as_str()) {
+            findings.push(
+                Severity::Warning,
+                "unused-build-dependency",
+                format!("build-dependencies.{name}"),
+                format!("${{{name}}} isn't used by any phase"),
+            );
+        }
+    }
+
+    findings.0
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use crate::package::{Executable, Metadata};
+
+    use super::*;
+
+    fn dependency(version: &str, target: &str) -> Dependency {
+        Dependency {
+            name: "dep".to_string(),
+            version: version.to_string(),
+            target: target.to_string(),
+        }
+    }
+
+    #[test]
+    fn lint_manifest() {
+        let mut package = Package {
+            package: Metadata {
+                format: FORMAT_VERSION,
+                name: "hello".to_string(),
+                version: "1.0.0".to_string(),
+                description: None,
+                compatibility: None,
+                targets: ["out".to_string()].into(),
+                preserve_permissions: false,
+                self_references: false,
+                patch_elf: false,
+            },
+            dependencies: [("libc".to_string(), dependency("2.39", "lib"))].into(),
+            build_dependencies: [
+                ("busybox".to_string(), dependency("1.36.1", "out")),
+                ("make".to_string(), dependency("^4", "out")),
+            ]
+            .into(),
+            base: None,
+            build_phase: Some(Executable {
+                exec: vec!["${busybox}/bin/sh".to_string(), "build.sh".to_string()],
+                env: BTreeMap::from([
+                    ("PATH".to_string(), "${busybox}/bin:/usr/bin".to_string()),
+                    ("PREFIX".to_string(), "${out}".to_string()),
+                ]),
+            }),
+            install_phase: Some(Executable {
+                exec: vec!["${gcc}/bin/cc".to_string()],
+                env: BTreeMap::from([("LD_PRELOAD".to_string(), "${libc}/lib/x.so".to_string())]),
+            }),
+            env_passthrough: Default::default(),
+            executables: BTreeMap::new(),
+            activate: None,
+        };
+
+        let findings = lint(&package)
+            .into_iter()
+            .map(|v| (v.severity, v.code, v.path))
+            .collect::<Vec<_>>();
+        let expected = [
+            (
+                Severity::Warning,
+                "unpinned-dependency",
+                "build-dependencies.make.version",
+            ),
+            (Severity::Warning, "host-path", "build-phase.env.PATH"),
+            (
+                Severity::Error,
+                "undeclared-reference",
+                "install-phase.exec[0]",
+            ),
+            (
+                Severity::Warning,
+                "linker-override",
+                "install-phase.env.LD_PRELOAD",
+            ),
+            (
+                Severity::Warning,
+                "unused-build-dependency",
+                "build-dependencies.make",
+            ),
+        ]
+        .map(|(severity, code, path)| (severity, code.to_string(), path.to_string()));
+        assert_eq!(findings, expected);
+
+        package.package.format = FORMAT_VERSION + 1;
+        package.package.targets.clear();
+        package.build_phase = None;
+        package.install_phase = None;
+        package.build_dependencies.clear();
+        package.dependencies = [("libc".to_string(), dependency("2.39", ""))].into();
+        package.executables = [(
+            "../hello".to_string(),
+            Executable {
+                exec: vec![],
+                env: BTreeMap::new(),
+            },
+        )]
+        .into();
+        let codes = lint(&package)
+            .into_iter()
+            .map(|v| v.code)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            codes,
+            [
+                "unsupported-format",
+                "missing-targets",
+                "missing-target",
+                "no-phases",
+                "invalid-executable-name",
+                "empty-exec"
+            ]
+        );
+    }
+}
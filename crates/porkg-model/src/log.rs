@@ -0,0 +1,243 @@
+//! Build logs, as line-oriented records.
+//!
+//! The output of a build is split into [`LogRecord`]s by [`LogLines`], one for each line of each stream. Records are
+//! streamed live as they are produced, terminal escapes included, and are stored after [`LogRecord::plain`] removed
+//! what only makes sense on a terminal.
+
+use std::{
+    borrow::Cow,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The length at which a line is cut into several records, so that output without line breaks can't grow a record
+/// without bound.
+pub const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+const ESC: u8 = 0x1b;
+const BEL: u8 = 0x07;
+
+/// The stream that a line of output was written to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+/// A line of output.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogRecord {
+    /// When the line was completed, in milliseconds since the Unix epoch.
+    pub time: u64,
+    pub stream: LogStream,
+    /// The line, without its line break. Invalid UTF-8 is replaced.
+    pub line: String,
+    /// Whether the line continues in the next record of the stream, because it was longer than [`MAX_LINE_LENGTH`].
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+}
+
+impl LogRecord {
+    /// Removes terminal escapes from the line, and the text that a carriage return would overwrite on a terminal, such
+    /// as the previous states of a progress bar.
+    pub fn plain(mut self) -> Self {
+        let line = match self.line.rfind('\r') {
+            Some(index) => &self.line[index + 1..],
+            None => &self.line,
+        };
+        if let Cow::Owned(line) = strip_ansi(line) {
+            self.line = line;
+        } else if line.len() != self.line.len() {
+            self.line = line.to_string();
+        }
+        self
+    }
+}
+
+/// Removes ANSI escape sequences (colors, cursor movement, titles and the like) from text.
+pub fn strip_ansi(text: &str) -> Cow<'_, str> {
+    let bytes = text.as_bytes();
+    if !bytes.contains(&ESC) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] != ESC {
+            result.push(bytes[index]);
+            index += 1;
+            continue;
+        }
+        index += 1;
+        match bytes.get(index) {
+            // CSI: parameters and intermediates, up to a final byte in `@`..=`~`.
+            Some(b'[') => {
+                index += 1;
+                while index < bytes.len() && !(0x40..=0x7e).contains(&bytes[index]) {
+                    index += 1;
+                }
+                index += 1;
+            }
+            // OSC, DCS and the like: up to BEL or ST (`ESC \`).
+            Some(b']' | b'P' | b'X' | b'^' | b'_') => {
+                index += 1;
+                while index < bytes.len() {
+                    if bytes[index] == BEL {
+                        index += 1;
+                        break;
+                    }
+                    if bytes[index] == ESC && bytes.get(index + 1) == Some(&b'\\') {
+                        index += 2;
+                        break;
+                    }
+                    index += 1;
+                }
+            }
+            // A two-byte sequence, or a character set designation such as `ESC ( B`.
+            Some(b'(' | b')' | b'*' | b'+') => index += 2,
+            Some(_) => index += 1,
+            None => {}
+        }
+    }
+    // Sequences only consist of ASCII, so removing them doesn't split a character.
+    Cow::Owned(String::from_utf8(result).expect("escape sequences are ASCII"))
+}
+
+/// Splits the output of a build into records.
+#[derive(Debug, Default)]
+pub struct LogLines {
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
+
+fn record(time: u64, stream: LogStream, mut line: &[u8], partial: bool) -> LogRecord {
+    if !partial {
+        line = line.strip_suffix(b"\n").unwrap_or(line);
+        line = line.strip_suffix(b"\r").unwrap_or(line);
+    }
+    LogRecord {
+        time,
+        stream,
+        line: String::from_utf8_lossy(line).into_owned(),
+        partial,
+    }
+}
+
+impl LogLines {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn buffer(&mut self, stream: LogStream) -> &mut Vec<u8> {
+        match stream {
+            LogStream::Stdout => &mut self.stdout,
+            LogStream::Stderr => &mut self.stderr,
+        }
+    }
+
+    /// Adds output that was written to `stream` at `time`, returning the lines that it completed.
+    pub fn push(&mut self, stream: LogStream, data: &[u8], time: SystemTime) -> Vec<LogRecord> {
+        let time = millis(time);
+        let buffer = self.buffer(stream);
+        buffer.extend_from_slice(data);
+
+        let mut records = Vec::new();
+        let mut start = 0;
+        while start < buffer.len() {
+            let rest = &buffer[start..];
+            let limit = rest.len().min(MAX_LINE_LENGTH);
+            match rest[..limit].iter().position(|&b| b == b'\n') {
+                Some(end) => {
+                    records.push(record(time, stream, &rest[..=end], false));
+                    start += end + 1;
+                }
+                None if rest.len() >= MAX_LINE_LENGTH => {
+                    // A character that spans the cut is kept whole, in the next record.
+                    let mut end = MAX_LINE_LENGTH;
+                    while end > MAX_LINE_LENGTH - 4 && std::str::from_utf8(&rest[..end]).is_err() {
+                        end -= 1;
+                    }
+                    records.push(record(time, stream, &rest[..end], true));
+                    start += end;
+                }
+                None => break,
+            }
+        }
+        buffer.drain(..start);
+        records
+    }
+
+    /// Ends the output, returning the last lines of the streams if they weren't terminated.
+    pub fn finish(mut self, time: SystemTime) -> Vec<LogRecord> {
+        let time = millis(time);
+        [LogStream::Stdout, LogStream::Stderr]
+            .into_iter()
+            .filter_map(|stream| {
+                let buffer = std::mem::take(self.buffer(stream));
+                (!buffer.is_empty()).then(|| record(time, stream, &buffer, false))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn split_lines() {
+        let time = UNIX_EPOCH + Duration::from_millis(1500);
+        let mut lines = LogLines::new();
+        assert!(lines.push(LogStream::Stdout, b"configur", time).is_empty());
+        let records = lines.push(LogStream::Stderr, b"warning\r\n", time);
+        assert_eq!(
+            records,
+            [LogRecord {
+                time: 1500,
+                stream: LogStream::Stderr,
+                line: "warning".to_string(),
+                partial: false,
+            }]
+        );
+        let records = lines.push(LogStream::Stdout, b"ing\n\nmake", time);
+        let text: Vec<_> = records.iter().map(|r| r.line.as_str()).collect();
+        assert_eq!(text, ["configuring", ""]);
+
+        let long = "é".repeat(MAX_LINE_LENGTH / 2 + 1);
+        let records = lines.push(LogStream::Stderr, long.as_bytes(), time);
+        assert_eq!(records.len(), 1);
+        assert!(records[0].partial);
+        assert_eq!(records[0].line.len(), MAX_LINE_LENGTH);
+
+        let records = lines.finish(time);
+        let text: Vec<_> = records.iter().map(|r| r.line.as_str()).collect();
+        assert_eq!(text, ["make", "é"]);
+    }
+
+    #[test]
+    fn plain_records() {
+        assert_eq!(
+            strip_ansi("\x1b[1;31merror\x1b[0m: \x1b]0;title\x07failed\x1b(B"),
+            "error: failed"
+        );
+        assert!(matches!(strip_ansi("plain"), Cow::Borrowed(_)));
+
+        let record = LogRecord {
+            time: 0,
+            stream: LogStream::Stdout,
+            line: " 10% [\x1b[32m#\x1b[0m   ]\r100% [\x1b[32m####\x1b[0m]".to_string(),
+            partial: false,
+        };
+        assert_eq!(record.plain().line, "100% [####]");
+    }
+}
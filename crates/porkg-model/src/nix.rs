@@ -0,0 +1,419 @@
+//! Interoperability types for Nix binary caches.
+//!
+//! Only the subset required to import store paths from an existing cache is modelled: `.narinfo` metadata and the
+//! hashes and signatures it carries.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+const NIX_BASE32: &[u8; 32] = b"0123456789abcdfghijklmnpqrsvwxyz";
+const PREFIX_SHA256: &str = "sha256:";
+const SHA256_LEN: usize = 32;
+/// The length of the hash part of a store path base name.
+const HASH_PART_LEN: usize = 32;
+
+/// Decodes the base32 variant used by Nix, which uses a custom alphabet and encodes starting at the last byte.
+pub(crate) fn decode_nix_base32<const SIZE: usize>(s: &str) -> Option<[u8; SIZE]> {
+    if s.len() != (SIZE * 8 - 1) / 5 + 1 {
+        return None;
+    }
+
+    let mut result = [0u8; SIZE];
+    for (n, c) in s.bytes().rev().enumerate() {
+        let digit = NIX_BASE32.iter().position(|v| *v == c)? as u16;
+        let b = n * 5;
+        let (i, j) = (b / 8, b % 8);
+        let value = digit << j;
+        result[i] |= value as u8;
+        let carry = (value >> 8) as u8;
+        if i + 1 < SIZE {
+            result[i + 1] |= carry;
+        } else if carry != 0 {
+            return None;
+        }
+    }
+
+    Some(result)
+}
+
+fn encode_nix_base32(bytes: &[u8], f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let len = (bytes.len() * 8 - 1) / 5 + 1;
+    for n in (0..len).rev() {
+        let b = n * 5;
+        let (i, j) = (b / 8, b % 8);
+        let low = (bytes[i] as u16) >> j;
+        let high = bytes
+            .get(i + 1)
+            .map(|v| (*v as u16) << (8 - j))
+            .unwrap_or(0);
+        let c = NIX_BASE32[((low | high) & 0x1f) as usize];
+        write!(f, "{}", c as char)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn decode_hex<const SIZE: usize>(s: &str) -> Option<[u8; SIZE]> {
+    if s.len() != SIZE * 2 || !s.is_ascii() {
+        return None;
+    }
+
+    let mut result = [0u8; SIZE];
+    for (i, v) in result.iter_mut().enumerate() {
+        *v = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(result)
+}
+
+/// Decodes standard base64, with or without padding, as used by SRI strings (`sha256-<base64>`).
+pub(crate) fn decode_base64<const SIZE: usize>(s: &str) -> Option<[u8; SIZE]> {
+    let encoding = if s.ends_with('=') {
+        data_encoding::BASE64
+    } else {
+        data_encoding::BASE64_NOPAD
+    };
+    // The decoded length is only known once the padding is removed.
+    let mut buffer = vec![0u8; encoding.decode_len(s.len()).ok()?];
+    match encoding.decode_mut(s.as_bytes(), &mut buffer) {
+        Ok(len) => buffer[..len].try_into().ok(),
+        Err(_) => None,
+    }
+}
+
+/// Checks that `base` is the base name of a Nix store path (`<hash>-<name>`), so that it can be used as a single path
+/// component. The hash is 32 characters of Nix base32, and the name only uses the characters that Nix allows and does
+/// not start with a dot.
+pub fn validate_base_name(base: &str) -> Result<(), NarInfoError> {
+    let invalid = || NarInfoError::InvalidStorePath(base.to_string());
+    let (hash, name) = base.split_once('-').ok_or_else(invalid)?;
+    if hash.len() != HASH_PART_LEN || !hash.bytes().all(|v| NIX_BASE32.contains(&v)) {
+        return Err(invalid());
+    }
+    let allowed = |v: u8| v.is_ascii_alphanumeric() || b"+-._?=".contains(&v);
+    if name.is_empty() || name.starts_with('.') || !name.bytes().all(allowed) {
+        return Err(invalid());
+    }
+    Ok(())
+}
+
+/// A hash as written by Nix (`<algorithm>:<digest>`).
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NixHash {
+    Sha256([u8; SHA256_LEN]),
+}
+
+impl NixHash {
+    /// Gets the raw digest.
+    pub fn digest(&self) -> &[u8] {
+        match self {
+            NixHash::Sha256(v) => v,
+        }
+    }
+}
+
+impl fmt::Debug for NixHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NixHash(\"{}\")", self)
+    }
+}
+
+impl fmt::Display for NixHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NixHash::Sha256(v) => {
+                f.write_str(PREFIX_SHA256)?;
+                encode_nix_base32(v, f)
+            }
+        }
+    }
+}
+
+impl FromStr for NixHash {
+    type Err = NarInfoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(digest) = s.strip_prefix(PREFIX_SHA256) else {
+            return Err(NarInfoError::UnsupportedHash(s.to_string()));
+        };
+
+        decode_nix_base32(digest)
+            .or_else(|| decode_hex(digest))
+            .map(NixHash::Sha256)
+            .ok_or_else(|| NarInfoError::InvalidHash(s.to_string()))
+    }
+}
+
+impl Serialize for NixHash {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for NixHash {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <&str>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A detached signature over a store path (`<key name>:<base64 signature>`).
+///
+/// Signatures are recorded verbatim so that the provenance of imported paths can be checked later.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NixSignature {
+    pub key: String,
+    pub signature: String,
+}
+
+impl FromStr for NixSignature {
+    type Err = NarInfoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((key, signature)) if !key.is_empty() && !signature.is_empty() => Ok(Self {
+                key: key.to_string(),
+                signature: signature.to_string(),
+            }),
+            _ => Err(NarInfoError::InvalidSignature(s.to_string())),
+        }
+    }
+}
+
+/// The compression applied to a NAR file in a binary cache.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NarCompression {
+    None,
+    Xz,
+    Zstd,
+    Bzip2,
+    Gzip,
+}
+
+impl FromStr for NarCompression {
+    type Err = NarInfoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "xz" => Ok(Self::Xz),
+            "zstd" => Ok(Self::Zstd),
+            "bzip2" => Ok(Self::Bzip2),
+            "gzip" => Ok(Self::Gzip),
+            other => Err(NarInfoError::UnsupportedCompression(other.to_string())),
+        }
+    }
+}
+
+/// The metadata describing a single store path in a Nix binary cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NarInfo {
+    /// The full Nix store path, e.g. `/nix/store/<hash>-<name>`.
+    pub store_path: String,
+    /// The location of the NAR file, relative to the cache.
+    pub url: String,
+    pub compression: NarCompression,
+    /// The hash of the compressed NAR file.
+    pub file_hash: Option<NixHash>,
+    /// The size of the compressed NAR file.
+    pub file_size: Option<u64>,
+    /// The hash of the uncompressed NAR.
+    pub nar_hash: NixHash,
+    /// The size of the uncompressed NAR.
+    pub nar_size: u64,
+    /// The base names of the store paths that this path references.
+    pub references: Vec<String>,
+    pub deriver: Option<String>,
+    pub signatures: Vec<NixSignature>,
+    /// The content address, for fixed-output paths.
+    pub ca: Option<String>,
+}
+
+impl NarInfo {
+    /// Gets the base name of the store path (`<hash>-<name>`).
+    pub fn base_name(&self) -> &str {
+        self.store_path
+            .rsplit_once('/')
+            .map(|(_, v)| v)
+            .unwrap_or(&self.store_path)
+    }
+
+    /// Gets the hash portion of the store path, which names its `.narinfo` in a cache.
+    pub fn hash_part(&self) -> &str {
+        let base = self.base_name();
+        base.split_once('-').map(|(v, _)| v).unwrap_or(base)
+    }
+
+    /// Gets the name portion of the store path.
+    pub fn name(&self) -> &str {
+        let base = self.base_name();
+        base.split_once('-').map(|(_, v)| v).unwrap_or(base)
+    }
+
+    /// Checks that the store path and the references are valid base names, so that none of them can escape the
+    /// directory they are imported into.
+    pub fn validate(&self) -> Result<(), NarInfoError> {
+        validate_base_name(self.base_name())?;
+        self.references
+            .iter()
+            .try_for_each(|v| validate_base_name(v))
+    }
+}
+
+impl FromStr for NarInfo {
+    type Err = NarInfoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut store_path = None;
+        let mut url = None;
+        let mut compression = None;
+        let mut file_hash = None;
+        let mut file_size = None;
+        let mut nar_hash = None;
+        let mut nar_size = None;
+        let mut references = Vec::new();
+        let mut deriver = None;
+        let mut signatures = Vec::new();
+        let mut ca = None;
+
+        for (index, line) in s.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let Some((key, value)) = line.split_once(": ") else {
+                return Err(NarInfoError::InvalidLine(index + 1));
+            };
+
+            let parse_size = |v: &str| {
+                v.parse::<u64>()
+                    .map_err(|_| NarInfoError::InvalidSize(v.to_string()))
+            };
+
+            match key {
+                "StorePath" => store_path = Some(value.to_string()),
+                "URL" => url = Some(value.to_string()),
+                "Compression" => compression = Some(value.parse()?),
+                "FileHash" => file_hash = Some(value.parse()?),
+                "FileSize" => file_size = Some(parse_size(value)?),
+                "NarHash" => nar_hash = Some(value.parse()?),
+                "NarSize" => nar_size = Some(parse_size(value)?),
+                "References" => {
+                    references = value.split_whitespace().map(ToString::to_string).collect()
+                }
+                "Deriver" if value != "unknown-deriver" => deriver = Some(value.to_string()),
+                "Sig" => signatures.push(value.parse()?),
+                "CA" => ca = Some(value.to_string()),
+                other => tracing::trace!(key = other, "ignoring unknown narinfo field"),
+            }
+        }
+
+        let info = Self {
+            store_path: store_path.ok_or(NarInfoError::MissingField("StorePath"))?,
+            url: url.ok_or(NarInfoError::MissingField("URL"))?,
+            // Older caches omit the compression, which implies bzip2.
+            compression: compression.unwrap_or(NarCompression::Bzip2),
+            file_hash,
+            file_size,
+            nar_hash: nar_hash.ok_or(NarInfoError::MissingField("NarHash"))?,
+            nar_size: nar_size.ok_or(NarInfoError::MissingField("NarSize"))?,
+            references,
+            deriver,
+            signatures,
+            ca,
+        };
+        info.validate()?;
+        Ok(info)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum NarInfoError {
+    #[error("malformed narinfo line {0}")]
+    InvalidLine(usize),
+    #[error("narinfo is missing the {0} field")]
+    MissingField(&'static str),
+    #[error("unsupported hash {0:?}")]
+    UnsupportedHash(String),
+    #[error("invalid hash {0:?}")]
+    InvalidHash(String),
+    #[error("invalid size {0:?}")]
+    InvalidSize(String),
+    #[error("invalid signature {0:?}")]
+    InvalidSignature(String),
+    #[error("unsupported compression {0:?}")]
+    UnsupportedCompression(String),
+    #[error("invalid store path {0:?}")]
+    InvalidStorePath(String),
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HELLO: &str = "StorePath: /nix/store/4dlhzcsgfnha93ifkgmgmfj0a4lsq0d6-hello-2.12.1
+URL: nar/1kzs7gzagkv2jg2bbrgfm1yjkfqirrp4iqinnwmb1rvjyflmsbgw.nar.xz
+Compression: xz
+FileHash: sha256:1kzs7gzagkv2jg2bbrgfm1yjkfqirrp4iqinnwmb1rvjyflmsbgw
+FileSize: 50092
+NarHash: sha256:0yadwh6gpjx0zsfgs6jx1ygc9gyh6wf4qv0gxwij4bzp6m2vcjxz
+NarSize: 226560
+References: 4dlhzcsgfnha93ifkgmgmfj0a4lsq0d6-hello-2.12.1 yaz7pyf0ah88g2v505l38n0f3wg2vzdj-glibc-2.37-8
+Deriver: 0mc3kqrjxdx4vi7x1gsj3y39gw0xjrbk-hello-2.12.1.drv
+Sig: cache.nixos.org-1:8ijECciSFzWHwwGVOIVYdp2fOIOJAfmzGHPQVwpktfTQJF6kMPPDre7UtFw3o+VqenC5P8RikKOAAfN7CvPEAg==
+";
+
+    #[test]
+    fn parse_narinfo() {
+        let info: NarInfo = HELLO.parse().unwrap();
+
+        assert_eq!(info.name(), "hello-2.12.1");
+        assert_eq!(info.compression, NarCompression::Xz);
+        assert_eq!(info.nar_size, 226560);
+        assert_eq!(info.references.len(), 2);
+        assert_eq!(info.signatures[0].key, "cache.nixos.org-1");
+        assert_eq!(
+            info.nar_hash.to_string(),
+            "sha256:0yadwh6gpjx0zsfgs6jx1ygc9gyh6wf4qv0gxwij4bzp6m2vcjxz"
+        );
+    }
+
+    #[test]
+    fn parse_narinfo_missing() {
+        let err = "StorePath: /nix/store/a-b\n"
+            .parse::<NarInfo>()
+            .unwrap_err();
+        assert_eq!(err, NarInfoError::MissingField("URL"));
+    }
+
+    #[test]
+    fn parse_narinfo_escape() {
+        for path in [
+            "..",
+            "4dlhzcsgfnha93ifkgmgmfj0a4lsq0d6-..",
+            "4dlhzcsgfnha93ifkgmgmfj0a4lsq0d6-a/b",
+        ] {
+            let text = HELLO.replace(
+                "4dlhzcsgfnha93ifkgmgmfj0a4lsq0d6-hello-2.12.1\n",
+                &format!("{path}\n"),
+            );
+            assert!(matches!(
+                text.parse::<NarInfo>(),
+                Err(NarInfoError::InvalidStorePath(_))
+            ));
+        }
+        let text = HELLO.replace(" yaz7pyf0ah88g2v505l38n0f3wg2vzdj-glibc", " ../glibc");
+        assert!(matches!(
+            text.parse::<NarInfo>(),
+            Err(NarInfoError::InvalidStorePath(_))
+        ));
+    }
+
+    #[test]
+    fn nix_hash_hex() {
+        let hex = "sha256:0000000000000000000000000000000000000000000000000000000000000001";
+        let NixHash::Sha256(digest) = hex.parse().unwrap();
+        assert_eq!(digest[31], 1);
+    }
+}
@@ -0,0 +1,212 @@
+//! Overlays, which change how packages are resolved and built without editing their manifests.
+//!
+//! An overlay can replace a package everywhere that it is depended on, or set options for the phases of a package.
+//! The changes are recorded as [`Override`]s in the provenance of each package that they apply to, so that
+//! overridden builds can be told apart from vanilla ones.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{package::Package, provenance::Override, workspace::Lock};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Overlay {
+    /// Replacements for packages, by package name.
+    #[serde(default)]
+    pub packages: BTreeMap<String, Replacement>,
+    /// Options for packages, by package name. Options are set as environment variables of every phase.
+    #[serde(default)]
+    pub options: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// A version of a package that is used instead of the version that is depended on.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Replacement {
+    pub version: String,
+    /// The hash of the replacement, which takes precedence over the lock.
+    pub hash: String,
+}
+
+impl Overlay {
+    /// Adds the changes of another overlay, which take precedence over the changes of this one.
+    pub fn extend(&mut self, other: Overlay) {
+        self.packages.extend(other.packages);
+        for (package, options) in other.options {
+            self.options.entry(package).or_default().extend(options);
+        }
+    }
+
+    /// Locks the replaced packages to their replacements.
+    pub fn apply_lock(&self, lock: &mut Lock) {
+        for (name, replacement) in self.packages.iter() {
+            lock.packages.insert(name.clone(), replacement.hash.clone());
+        }
+    }
+
+    /// Applies the overlay to the manifest of a package, returning what was changed. The `lock` is the one that the
+    /// package would be resolved with if the overlay were not applied.
+    pub fn apply(&self, package: &mut Package, lock: &Lock) -> BTreeSet<Override> {
+        let mut overrides = BTreeSet::new();
+
+        let dependencies = package
+            .dependencies
+            .values_mut()
+            .chain(package.build_dependencies.values_mut())
+            .chain(package.base.iter_mut());
+        for dependency in dependencies {
+            let Some(replacement) = self.packages.get(&dependency.name) else {
+                continue;
+            };
+            if dependency.version != replacement.version {
+                overrides.insert(Override::Version {
+                    package: dependency.name.clone(),
+                    from: std::mem::replace(&mut dependency.version, replacement.version.clone()),
+                    to: replacement.version.clone(),
+                });
+            }
+            let locked = lock.packages.get(&dependency.name);
+            if locked != Some(&replacement.hash) {
+                overrides.insert(Override::Hash {
+                    package: dependency.name.clone(),
+                    from: locked.cloned(),
+                    to: replacement.hash.clone(),
+                });
+            }
+        }
+
+        let options = self.options.get(&package.package.name);
+        for (name, value) in options.into_iter().flatten() {
+            for phase in [&mut package.build_phase, &mut package.install_phase]
+                .into_iter()
+                .flatten()
+            {
+                phase.env.insert(name.clone(), value.clone());
+            }
+            overrides.insert(Override::Option {
+                name: name.clone(),
+                value: value.clone(),
+            });
+        }
+
+        overrides
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::package::{Dependency, Executable, Metadata, FORMAT_VERSION};
+
+    use super::*;
+
+    #[test]
+    fn apply_overlay() {
+        let dependency = |version: &str| Dependency {
+            name: "openssl".to_string(),
+            version: version.to_string(),
+            target: "out".to_string(),
+        };
+        let mut package = Package {
+            package: Metadata {
+                format: FORMAT_VERSION,
+                name: "curl".to_string(),
+                version: "8.7.1".to_string(),
+                description: None,
+                compatibility: None,
+                targets: ["out".to_string()].into(),
+                preserve_permissions: false,
+                self_references: false,
+                patch_elf: false,
+            },
+            dependencies: [("openssl".to_string(), dependency("3.0.13"))].into(),
+            build_dependencies: BTreeMap::new(),
+            base: None,
+            build_phase: Some(Executable {
+                exec: vec!["make".to_string()],
+                env: BTreeMap::new(),
+            }),
+            install_phase: None,
+            env_passthrough: Default::default(),
+            executables: BTreeMap::new(),
+            activate: None,
+        };
+
+        let mut overlay: Overlay = Default::default();
+        overlay.packages.insert(
+            "openssl".to_string(),
+            Replacement {
+                version: "3.3.0".to_string(),
+                hash: "blake3-old".to_string(),
+            },
+        );
+        overlay.extend(Overlay {
+            packages: [(
+                "openssl".to_string(),
+                Replacement {
+                    version: "3.3.1".to_string(),
+                    hash: "blake3-openssl".to_string(),
+                },
+            )]
+            .into(),
+            options: [(
+                "curl".to_string(),
+                [("WITH_HTTP3".to_string(), "1".to_string())].into(),
+            )]
+            .into(),
+        });
+
+        let mut lock = Lock::default();
+        lock.packages
+            .insert("openssl".to_string(), "blake3-vanilla".to_string());
+        let overrides = overlay.apply(&mut package, &lock);
+        assert_eq!(package.dependencies["openssl"].version, "3.3.1");
+        assert_eq!(package.build_phase.as_ref().unwrap().env["WITH_HTTP3"], "1");
+        assert_eq!(
+            overrides,
+            [
+                Override::Version {
+                    package: "openssl".to_string(),
+                    from: "3.0.13".to_string(),
+                    to: "3.3.1".to_string(),
+                },
+                Override::Hash {
+                    package: "openssl".to_string(),
+                    from: Some("blake3-vanilla".to_string()),
+                    to: "blake3-openssl".to_string(),
+                },
+                Override::Option {
+                    name: "WITH_HTTP3".to_string(),
+                    value: "1".to_string(),
+                },
+            ]
+            .into()
+        );
+
+        overlay.apply_lock(&mut lock);
+        assert_eq!(lock.packages["openssl"], "blake3-openssl");
+
+        // Replacing a dependency with another build of the same version is an override too, unless the lock already
+        // resolves it to that build.
+        let mut rebuilt = Overlay::default();
+        rebuilt.packages.insert(
+            "openssl".to_string(),
+            Replacement {
+                version: "3.3.1".to_string(),
+                hash: "blake3-patched".to_string(),
+            },
+        );
+        assert_eq!(
+            rebuilt.apply(&mut package, &lock),
+            [Override::Hash {
+                package: "openssl".to_string(),
+                from: Some("blake3-openssl".to_string()),
+                to: "blake3-patched".to_string(),
+            }]
+            .into()
+        );
+        assert!(overlay
+            .apply(&mut package, &lock)
+            .iter()
+            .all(|v| matches!(v, Override::Option { .. })));
+    }
+}
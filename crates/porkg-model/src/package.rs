@@ -4,22 +4,146 @@ use serde::{Deserialize, Serialize};
 
 use crate::hashing::StableHash;
 
+/// The version of the manifest format that this version of porkg writes and understands.
+pub const FORMAT_VERSION: u32 = 1;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Package {
     pub package: Metadata,
     pub dependencies: BTreeMap<String, Dependency>,
     #[serde(rename = "build-dependencies")]
     pub build_dependencies: BTreeMap<String, Dependency>,
+    /// The root file system that the package is built in, typically an imported container image.
+    #[serde(default)]
+    pub base: Option<Dependency>,
+    #[serde(default, rename = "build-phase")]
+    pub build_phase: Option<Executable>,
+    #[serde(default, rename = "install-phase")]
+    pub install_phase: Option<Executable>,
+    /// The environment variables of the host that are passed into the build. Nothing else is inherited from the host.
+    #[serde(
+        default,
+        rename = "env-passthrough",
+        skip_serializing_if = "EnvPassthrough::is_empty"
+    )]
+    pub env_passthrough: EnvPassthrough,
+    /// The commands that the package provides, by name. A wrapper script named after each of them is generated in the
+    /// `bin` directory of the package, which sets up the environment and runs the command, so that profiles can expose
+    /// it. `${out}` refers to the package, and other names to its dependencies.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub executables: BTreeMap<String, Executable>,
+    /// A command that is run whenever a profile switches to a generation that contains the package, such as to update
+    /// a cache of the profile. It runs in a sandbox that only contains the closure of the package and, at `/profile`,
+    /// the tree of the generation, which it can't write to. The switch is undone if the command fails.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub activate: Option<Executable>,
+}
+
+impl Package {
+    /// Gets the phases of the build, by the name of their manifest section.
+    pub fn phases(&self) -> impl Iterator<Item = (&'static str, &Executable)> {
+        [
+            ("build-phase", &self.build_phase),
+            ("install-phase", &self.install_phase),
+        ]
+        .into_iter()
+        .filter_map(|(name, phase)| Some((name, phase.as_ref()?)))
+    }
+
+    /// Reads the variables that are passed through from the host with `lookup`, skipping those that aren't set.
+    ///
+    /// The values of hashed variables are written into the environment of every phase, so that they become part of the
+    /// manifest and therefore of the source hash. The values of unhashed variables are returned, to be passed to the
+    /// build alongside its source.
+    pub fn pass_env(
+        &mut self,
+        mut lookup: impl FnMut(&str) -> Option<String>,
+    ) -> BTreeMap<String, String> {
+        let hashed: Vec<_> = self
+            .env_passthrough
+            .hashed
+            .iter()
+            .filter_map(|name| Some((name.clone(), lookup(name)?)))
+            .collect();
+        for phase in [&mut self.build_phase, &mut self.install_phase]
+            .into_iter()
+            .flatten()
+        {
+            phase.env.extend(hashed.iter().cloned());
+        }
+
+        self.env_passthrough
+            .unhashed
+            .iter()
+            .filter_map(|name| Some((name.clone(), lookup(name)?)))
+            .collect()
+    }
+}
+
+/// Checks whether a command can be named `name`, which is the file name of its wrapper.
+pub fn is_valid_executable_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\0'])
+}
+
+/// The environment variables of the host that a build receives, by name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct EnvPassthrough {
+    /// Variables that affect the output, such as compiler flags.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub hashed: BTreeSet<String>,
+    /// Variables that don't affect the output, such as proxies. They are recorded as impurities of the package.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub unhashed: BTreeSet<String>,
+}
+
+impl EnvPassthrough {
+    pub fn is_empty(&self) -> bool {
+        self.hashed.is_empty() && self.unhashed.is_empty()
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Metadata {
+    /// The version of the manifest format. Manifests without one are assumed to be the first version.
+    #[serde(default = "default_format")]
+    pub format: u32,
     pub name: String,
     pub version: String,
     pub description: Option<String>,
     #[serde(rename = "compat")]
     pub compatibility: Option<Compatibility>,
     pub targets: BTreeSet<String>,
+    /// Keeps the permissions that the build gave to its outputs, instead of making them read-only with only the
+    /// executable bit kept. Setuid and setgid bits are removed regardless.
+    #[serde(
+        default,
+        rename = "preserve-permissions",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub preserve_permissions: bool,
+    /// Whether the outputs refer to their own install prefix. The build can't write to the final path of the package in
+    /// the store, so it uses a placeholder of the same length instead (see [`placeholder`](crate::store::placeholder)),
+    /// which it finds in `PORKG_OUT`. Every occurrence of the placeholder in files and symlink targets is rewritten to the
+    /// final path when the package enters the store, so it must not be compressed or otherwise encoded.
+    #[serde(
+        default,
+        rename = "self-references",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub self_references: bool,
+    /// Whether the ELF files of the outputs are patched after the build to use the dynamic loader and libraries of the
+    /// closure of the package in the store, so that they run outside of the sandbox. Paths are patched in place, so the
+    /// build has to link with an interpreter and `RUNPATH` that are long enough to hold them.
+    #[serde(
+        default,
+        rename = "patch-elf",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub patch_elf: bool,
+}
+
+fn default_format() -> u32 {
+    1
 }
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
@@ -45,11 +169,165 @@ pub struct LockDefinition {
     pub dependencies: BTreeMap<String, String>,
     #[serde(rename = "build-dependencies")]
     pub build_dependencies: BTreeMap<String, String>,
+    #[serde(default)]
+    pub base: Option<String>,
+}
+
+impl LockDefinition {
+    /// Compares the lock with the dependencies that `package` declares, which it must lock exactly.
+    pub fn drift(&self, package: &Package) -> LockDrift {
+        let mut drift = LockDrift::default();
+        let sections = [
+            ("dependencies", &package.dependencies, &self.dependencies),
+            (
+                "build-dependencies",
+                &package.build_dependencies,
+                &self.build_dependencies,
+            ),
+        ];
+        for (section, declared, locked) in sections {
+            for name in declared.keys().filter(|v| !locked.contains_key(*v)) {
+                drift.missing.push(format!("{section}.{name}"));
+            }
+            for name in locked.keys().filter(|v| !declared.contains_key(*v)) {
+                drift.unexpected.push(format!("{section}.{name}"));
+            }
+        }
+        match (&package.base, &self.base) {
+            (Some(_), None) => drift.missing.push("base".to_string()),
+            (None, Some(_)) => drift.unexpected.push("base".to_string()),
+            _ => {}
+        }
+        drift
+    }
+}
+
+/// How a lock differs from the dependencies of its manifest, by section and name, such as `build-dependencies.cc`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockDrift {
+    /// The dependencies that the manifest declares, but the lock omits.
+    pub missing: Vec<String>,
+    /// The dependencies that the lock has, but the manifest doesn't declare.
+    pub unexpected: Vec<String>,
+}
+
+impl LockDrift {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.unexpected.is_empty()
+    }
 }
 
 impl StableHash for LockDefinition {
     fn update<H: crate::hashing::StableHasher>(&self, h: &mut H) {
         self.dependencies.update(h);
         self.build_dependencies.update(h);
+        // Locks from before bases existed keep their hashes.
+        if let Some(base) = &self.base {
+            base.update(h);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pass_env() {
+        let mut package: Package = toml::from_str(
+            r#"
+            dependencies = {}
+            build-dependencies = {}
+            build-phase = { exec = ["make"] }
+            install-phase = { exec = ["make", "install"], env = { DESTDIR = "out" } }
+
+            [package]
+            name = "hello"
+            version = "1.0.0"
+            targets = ["out"]
+
+            [env-passthrough]
+            hashed = ["CFLAGS", "UNSET"]
+            unhashed = ["http_proxy"]
+            "#,
+        )
+        .unwrap();
+
+        let host = BTreeMap::from([("CFLAGS", "-O2"), ("http_proxy", "http://proxy:3128")]);
+        let unhashed = package.pass_env(|name| host.get(name).map(|v| v.to_string()));
+        assert_eq!(
+            unhashed,
+            BTreeMap::from([("http_proxy".to_string(), "http://proxy:3128".to_string())])
+        );
+        for (_, phase) in package.phases() {
+            assert_eq!(phase.env["CFLAGS"], "-O2");
+            assert!(!phase.env.contains_key("UNSET"));
+        }
+        assert!(toml::to_string(&package)
+            .unwrap()
+            .contains("[env-passthrough]"));
+    }
+
+    #[test]
+    fn lock_drift() {
+        let package: Package = toml::from_str(
+            r#"
+            dependencies = { libc = { name = "libc", version = "2", target = "out" } }
+            build-dependencies = { cc = { name = "cc", version = "1", target = "out" } }
+            base = { name = "debian", version = "12", target = "rootfs" }
+
+            [package]
+            name = "hello"
+            version = "1.0.0"
+            targets = ["out"]
+            "#,
+        )
+        .unwrap();
+
+        let mut lock = LockDefinition {
+            dependencies: BTreeMap::from([("libc".to_string(), "a".to_string())]),
+            build_dependencies: BTreeMap::from([("cc".to_string(), "b".to_string())]),
+            base: Some("c".to_string()),
+        };
+        assert!(lock.drift(&package).is_empty());
+
+        lock.dependencies
+            .insert("zlib".to_string(), "d".to_string());
+        lock.build_dependencies.clear();
+        lock.base = None;
+        assert_eq!(
+            lock.drift(&package),
+            LockDrift {
+                missing: vec!["build-dependencies.cc".to_string(), "base".to_string()],
+                unexpected: vec!["dependencies.zlib".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn lock_hash_without_base() {
+        use crate::hashing::{StableHasherExt as _, SupportedHasher};
+
+        let hash = |v: &LockDefinition| {
+            let mut hasher = SupportedHasher::blake3();
+            hasher.update_hash(v);
+            hasher.finalize()
+        };
+        let mut lock = LockDefinition {
+            dependencies: BTreeMap::from([("libc".to_string(), "a".to_string())]),
+            build_dependencies: BTreeMap::new(),
+            base: None,
+        };
+
+        // Locks without a base hash as they did before bases existed.
+        let mut hasher = SupportedHasher::blake3();
+        hasher
+            .update_hash(&lock.dependencies)
+            .update_hash(&lock.build_dependencies);
+        let unchanged = hasher.finalize();
+        assert_eq!(hash(&lock), unchanged);
+
+        lock.base = Some("c".to_string());
+        assert_ne!(hash(&lock), unchanged);
     }
 }
@@ -0,0 +1,53 @@
+//! Profiles, which are numbered generations of links to packages (see `notes/fs-layout.md`), one of which is current,
+//! and the difference between the closures of two generations, so that switching generations can be reviewed first.
+
+use serde::{Deserialize, Serialize};
+
+/// A package in the closure of a generation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffPackage {
+    pub hash: String,
+    pub name: String,
+    /// The path of the package in the store.
+    pub path: String,
+    /// The disk space that the package uses, in bytes.
+    pub size: u64,
+}
+
+/// A package that is in both closures under the same name (without its version, see
+/// [`split_name`](crate::audit::split_name)), but with different contents.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Changed {
+    pub from: DiffPackage,
+    pub to: DiffPackage,
+}
+
+/// How the closure of a generation differs from that of another one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClosureDiff {
+    pub added: Vec<DiffPackage>,
+    pub removed: Vec<DiffPackage>,
+    pub changed: Vec<Changed>,
+    /// The disk space that the closure of the first generation uses, in bytes.
+    pub size_from: u64,
+    /// The disk space that the closure of the second generation uses, in bytes.
+    pub size_to: u64,
+}
+
+/// Switches a profile to one of its generations.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SwitchRequest {
+    pub generation: u64,
+}
+
+/// A profile that was switched to a generation.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Switched {
+    /// The generation that the profile was switched to before, if any.
+    pub previous: Option<u64>,
+    pub generation: u64,
+    /// How the closure differs from that of the previous generation.
+    pub diff: ClosureDiff,
+    /// The packages whose activation commands ran, in the order that they ran in.
+    pub activated: Vec<String>,
+}
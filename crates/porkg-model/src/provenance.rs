@@ -0,0 +1,86 @@
+//! How much a package can be trusted to depend only on its declared inputs.
+
+use std::collections::BTreeSet;
+
+use serde::{Deserialize, Serialize};
+
+use crate::channel::Channel;
+
+/// A way in which a package might depend on more than its declared inputs.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Impurity {
+    /// The build could access the network.
+    Network,
+    /// A path of the host was bind-mounted into the build.
+    HostPath { path: String },
+    /// An environment variable of the host was passed into the build without being hashed.
+    HostEnv { name: String },
+    /// The build read a file outside of its declared inputs.
+    UndeclaredRead { path: String },
+    /// An output file has metadata that isn't captured by its hash.
+    NonNormalized { path: String, reason: NonNormalized },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum NonNormalized {
+    /// The file is setuid or setgid.
+    SetId,
+    /// The file is writable by its group or others.
+    Writable,
+    /// The file is a device node, fifo or socket.
+    Special,
+    /// A symbolic link points to an absolute path outside of the declared inputs.
+    HostLink,
+}
+
+/// A change that an overlay made to a package before it was built (see [`crate::overlay`]).
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+pub enum Override {
+    /// The package was built against another version of a dependency than its manifest asks for.
+    Version {
+        package: String,
+        from: String,
+        to: String,
+    },
+    /// The package was built against another build of a dependency than the lock resolves it to, which is absent
+    /// from the lock if the dependency was not locked.
+    Hash {
+        package: String,
+        from: Option<String>,
+        to: String,
+    },
+    /// The phases of the package were run with an option set.
+    Option { name: String, value: String },
+}
+
+/// The impurity report of a package, recorded when it is added to the store.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub impurities: BTreeSet<Impurity>,
+    /// Whether the files read by the build were tracked. Otherwise, the absence of undeclared reads means nothing.
+    #[serde(default, rename = "reads-tracked")]
+    pub reads_tracked: bool,
+    /// The changes that overlays made to the package, which are empty for a vanilla build.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub overrides: BTreeSet<Override>,
+    /// The channel snapshot that the dependencies of the package were resolved against, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<Channel>,
+    /// The `SOURCE_DATE_EPOCH` that the package was built with, which reproducible builds embed in place of the time.
+    #[serde(
+        default,
+        rename = "source-date-epoch",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub source_date_epoch: Option<u64>,
+}
+
+impl Provenance {
+    /// Determines whether no impurities were detected.
+    pub fn is_pure(&self) -> bool {
+        self.impurities.is_empty()
+    }
+}
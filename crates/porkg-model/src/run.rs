@@ -0,0 +1,154 @@
+//! The protocol of running the executables of packages from the store.
+//!
+//! A run request is a JSON [`RunRequest`] that asks to upgrade the connection to [`UPGRADE`]. Once the sandbox has
+//! started, the daemon switches protocols: the client then writes the standard input of the command to the connection
+//! (shutting it down for writing at the end), and the daemon writes a sequence of [`Frame`]s that carry its output,
+//! ending with its exit code. Unlike build logs, output is passed on byte for byte, so that interactive and binary
+//! output works.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::log::LogStream;
+
+/// The protocol that run requests upgrade their connection to.
+pub const UPGRADE: &str = "porkg-run";
+
+/// The largest payload of a frame.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+const HEADER_SIZE: usize = 5;
+const KIND_STDOUT: u8 = 1;
+const KIND_STDERR: u8 = 2;
+const KIND_EXIT: u8 = 3;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RunRequest {
+    /// The arguments that are passed to the executable after those that it declares.
+    #[serde(default)]
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum FrameError {
+    #[error("unknown frame kind {0}")]
+    UnknownKind(u8),
+    #[error("a frame of {0} bytes is larger than the limit of {MAX_FRAME_SIZE} bytes")]
+    TooLarge(usize),
+    #[error("an exit frame has {0} bytes instead of 4")]
+    InvalidExit(usize),
+}
+
+/// A part of the response to a run request: a kind byte and a big-endian 32-bit length, followed by the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    Output {
+        stream: LogStream,
+        data: Vec<u8>,
+    },
+    /// The command exited with a code, or was killed by a signal (`128 + signal`). This is the last frame.
+    Exit(i32),
+}
+
+impl Frame {
+    /// Appends the encoded frame to `buf`. Output larger than [`MAX_FRAME_SIZE`] must be split by the caller.
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        let (kind, payload) = match self {
+            Frame::Output {
+                stream: LogStream::Stdout,
+                data,
+            } => (KIND_STDOUT, &data[..]),
+            Frame::Output {
+                stream: LogStream::Stderr,
+                data,
+            } => (KIND_STDERR, &data[..]),
+            Frame::Exit(code) => (KIND_EXIT, &code.to_be_bytes()[..]),
+        };
+        debug_assert!(payload.len() <= MAX_FRAME_SIZE);
+        buf.push(kind);
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(payload);
+    }
+
+    /// Decodes the frame at the start of `data`, returning it with the number of bytes that it took up, or `None` if
+    /// `data` doesn't contain all of it yet.
+    pub fn decode(data: &[u8]) -> Result<Option<(Frame, usize)>, FrameError> {
+        let Some(header) = data.get(..HEADER_SIZE) else {
+            return Ok(None);
+        };
+        let len = u32::from_be_bytes(header[1..].try_into().unwrap()) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(FrameError::TooLarge(len));
+        }
+        let Some(payload) = data.get(HEADER_SIZE..HEADER_SIZE + len) else {
+            return Ok(None);
+        };
+
+        let stream = match header[0] {
+            KIND_STDOUT => LogStream::Stdout,
+            KIND_STDERR => LogStream::Stderr,
+            KIND_EXIT => {
+                let code = payload
+                    .try_into()
+                    .map_err(|_| FrameError::InvalidExit(len))?;
+                return Ok(Some((
+                    Frame::Exit(i32::from_be_bytes(code)),
+                    HEADER_SIZE + len,
+                )));
+            }
+            other => return Err(FrameError::UnknownKind(other)),
+        };
+        let frame = Frame::Output {
+            stream,
+            data: payload.to_vec(),
+        };
+        Ok(Some((frame, HEADER_SIZE + len)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frame_roundtrip() {
+        let frames = [
+            Frame::Output {
+                stream: LogStream::Stdout,
+                data: b"hello\n".to_vec(),
+            },
+            Frame::Output {
+                stream: LogStream::Stderr,
+                data: vec![0xff; MAX_FRAME_SIZE],
+            },
+            Frame::Exit(-1),
+        ];
+        let mut buf = Vec::new();
+        for frame in &frames {
+            frame.encode(&mut buf);
+        }
+
+        let mut decoded = Vec::new();
+        let mut data = &buf[..];
+        while let Some((frame, len)) = Frame::decode(data).unwrap() {
+            decoded.push(frame);
+            data = &data[len..];
+        }
+        assert_eq!(decoded, frames);
+        // Incomplete frames are left for later.
+        assert!(Frame::decode(&buf[..HEADER_SIZE + 2]).unwrap().is_none());
+
+        assert!(matches!(
+            Frame::decode(&[9, 0, 0, 0, 0]),
+            Err(FrameError::UnknownKind(9))
+        ));
+        assert!(matches!(
+            Frame::decode(&[KIND_EXIT, 0, 0, 0, 1, 0]),
+            Err(FrameError::InvalidExit(1))
+        ));
+        assert!(matches!(
+            Frame::decode(&[KIND_STDOUT, 0xff, 0, 0, 0]),
+            Err(FrameError::TooLarge(_))
+        ));
+    }
+}
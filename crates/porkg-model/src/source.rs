@@ -0,0 +1,176 @@
+//! Upstream sources that packages are built from.
+
+use std::{fmt, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    base32::Base32,
+    nix::{decode_base64, decode_hex, decode_nix_base32},
+};
+
+const PREFIX_SHA256: &str = "sha256:";
+const PREFIX_BLAKE3: &str = "blake3:";
+const SRI_SHA256: &str = "sha256-";
+const SRI_BLAKE3: &str = "blake3-";
+
+#[derive(Debug, Error)]
+pub enum SourceHashError {
+    #[error("unsupported hash algorithm in {0:?}")]
+    UnsupportedAlgorithm(String),
+    #[error("invalid digest in {0:?}")]
+    InvalidDigest(String),
+}
+
+/// The expected checksum of a downloaded file (`<algorithm>:<hex digest>`).
+///
+/// Upstream projects usually publish SHA-256 checksums, so those are accepted alongside blake3. Checksums can also be
+/// written as SRI strings (`sha256-<base64>`) or with a base32 digest, and are displayed in the canonical form.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SourceHash {
+    Sha256([u8; 32]),
+    Blake3([u8; 32]),
+}
+
+impl SourceHash {
+    /// Gets the raw digest.
+    pub fn digest(&self) -> &[u8] {
+        match self {
+            SourceHash::Sha256(v) | SourceHash::Blake3(v) => v,
+        }
+    }
+}
+
+impl fmt::Debug for SourceHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SourceHash(\"{}\")", self)
+    }
+}
+
+impl fmt::Display for SourceHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceHash::Sha256(_) => f.write_str(PREFIX_SHA256)?,
+            SourceHash::Blake3(_) => f.write_str(PREFIX_BLAKE3)?,
+        }
+        for v in self.digest() {
+            write!(f, "{v:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SourceHash {
+    type Err = SourceHashError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let decode = |digest: &str, base32: fn(&str) -> Option<[u8; 32]>| {
+            decode_hex(digest)
+                .or_else(|| decode_base64(digest))
+                .or_else(|| base32(digest))
+        };
+
+        // SHA-256 checksums in base32 come from Nix, while blake3 ones are written like store hashes.
+        let hash = if let Some(digest) = s
+            .strip_prefix(PREFIX_SHA256)
+            .or_else(|| s.strip_prefix(SRI_SHA256))
+        {
+            decode(digest, decode_nix_base32).map(SourceHash::Sha256)
+        } else if let Some(digest) = s
+            .strip_prefix(PREFIX_BLAKE3)
+            .or_else(|| s.strip_prefix(SRI_BLAKE3))
+        {
+            decode(digest, |v| v.parse::<Base32<32>>().ok().map(|v| v.0)).map(SourceHash::Blake3)
+        } else {
+            return Err(SourceHashError::UnsupportedAlgorithm(s.to_string()));
+        };
+
+        hash.ok_or_else(|| SourceHashError::InvalidDigest(s.to_string()))
+    }
+}
+
+impl Serialize for SourceHash {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for SourceHash {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = <&str>::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// A source archive, which can be downloaded from any of its mirrors.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Source {
+    pub name: String,
+    /// The locations that the archive can be downloaded from, in order of preference.
+    pub urls: Vec<String>,
+    pub hash: SourceHash,
+    /// The number of leading path components to remove when extracting the archive.
+    #[serde(default, rename = "strip-components")]
+    pub strip_components: usize,
+    /// The name of a secret configured in the daemon, which is sent as the `Authorization` header to the mirrors.
+    /// It doesn't affect the hash of the source.
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+/// A revision of a git repository, which can be cloned from any of its mirrors.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GitSource {
+    pub name: String,
+    /// The locations that the repository can be cloned from, in order of preference.
+    pub urls: Vec<String>,
+    /// The full id of the commit to export.
+    pub rev: String,
+    /// Whether submodules are exported along with the repository.
+    #[serde(default)]
+    pub submodules: bool,
+    /// Whether files stored with Git LFS are downloaded, instead of exporting their pointers.
+    #[serde(default)]
+    pub lfs: bool,
+    /// The expected hash of the NAR serialization of the export.
+    pub hash: SourceHash,
+    /// The name of a secret configured in the daemon, which is sent as the `Authorization` header to HTTP(S)
+    /// mirrors. It doesn't affect the hash of the source.
+    #[serde(default)]
+    pub secret: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_source_hash() {
+        let text = "sha256:e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let hash: SourceHash = text.parse().unwrap();
+        assert_eq!(hash.to_string(), text);
+        assert_eq!(hash.digest()[0], 0xe3);
+
+        assert!(matches!(
+            "md5:d41d8cd98f00b204e9800998ecf8427e".parse::<SourceHash>(),
+            Err(SourceHashError::UnsupportedAlgorithm(_))
+        ));
+        assert!(matches!(
+            "blake3:abc".parse::<SourceHash>(),
+            Err(SourceHashError::InvalidDigest(_))
+        ));
+
+        // SRI strings and Nix base32 digests are canonicalized.
+        for other in [
+            "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=",
+            "sha256-47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU",
+            "sha256:0mdqa9w1p6cmli6976v4wi0sw9r4p5prkj7lzfd1877wk11c9c73",
+            "sha256:E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855",
+        ] {
+            let parsed: SourceHash = other.parse().unwrap();
+            assert_eq!(parsed, hash, "{other}");
+            assert_eq!(parsed.to_string(), text);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Component, Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{hashing::SupportedHash, package::Executable, provenance::Provenance};
+
+/// Metadata recorded alongside each package in the store.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PackageInfo {
+    pub name: String,
+    /// Other packages that must be present in the store for this package to be usable.
+    #[serde(default)]
+    pub references: BTreeSet<SupportedHash>,
+    /// The default executable of the package, if any.
+    #[serde(default)]
+    pub executable: Option<Executable>,
+    /// The commands that the package provides, by name (see
+    /// [`Package::executables`](crate::package::Package::executables)).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub executables: BTreeMap<String, Executable>,
+    /// The command that is run when a profile switches to a generation that contains the package (see
+    /// [`Package::activate`](crate::package::Package::activate)).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub activate: Option<Executable>,
+    /// The impurities that were detected when the package was added, which is absent for older packages.
+    #[serde(default)]
+    pub provenance: Option<Provenance>,
+    /// The latest modification time in the archive that a source was first imported from, in seconds since the Unix
+    /// epoch. Builds of the source use it as their `SOURCE_DATE_EPOCH`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_date_epoch: Option<u64>,
+    /// The tenant of the client that first added the package, if it belongs to one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// Whether the permissions of the package were left as they were created (see
+    /// [`Metadata::preserve_permissions`](crate::package::Metadata::preserve_permissions)).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub preserve_permissions: bool,
+    /// Whether the package refers to its own path through the placeholder of its build (see
+    /// [`Metadata::self_references`](crate::package::Metadata::self_references)).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub self_references: bool,
+    /// Whether the ELF files of the package are patched to use its closure (see
+    /// [`Metadata::patch_elf`](crate::package::Metadata::patch_elf)).
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub patch_elf: bool,
+}
+
+impl PackageInfo {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            references: BTreeSet::new(),
+            executable: None,
+            executables: BTreeMap::new(),
+            activate: None,
+            provenance: None,
+            source_date_epoch: None,
+            tenant: None,
+            preserve_permissions: false,
+            self_references: false,
+            patch_elf: false,
+        }
+    }
+}
+
+/// Gets the placeholder that the build of a package uses in place of its final path in the store at `store`. It is in
+/// the temporary directory of the store and has the same length as the final path, so that it can be rewritten within
+/// binaries without moving anything.
+pub fn placeholder(store: &Path, hash: &SupportedHash) -> PathBuf {
+    store.join("tmp").join(format!("selfref-{hash}"))
+}
+
+/// What an absolute symlink in a package points at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LinkTarget {
+    /// A path within a package in the store, which the package must reference to keep it alive.
+    Package { hash: SupportedHash, path: PathBuf },
+    /// The scratch space of whatever created the package, which doesn't exist once the package is in the store.
+    Scratch,
+    /// Anything else, such as a path on the host or within the root file system that the package is used in.
+    Other,
+}
+
+/// Classifies the absolute symlinks of packages by where they point.
+#[derive(Debug, Clone)]
+pub struct SymlinkPolicy {
+    store: PathBuf,
+    scratch: Vec<PathBuf>,
+}
+
+impl SymlinkPolicy {
+    /// Creates a policy for packages in the store at `store`.
+    pub fn new(store: impl Into<PathBuf>) -> Self {
+        Self {
+            store: store.into(),
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Treats links into `path` as links into scratch space.
+    pub fn scratch(mut self, path: impl Into<PathBuf>) -> Self {
+        self.scratch.push(path.into());
+        self
+    }
+
+    /// Classifies the target of a symlink. Packages can be reached through `pkg/by-hash/<hash>` or
+    /// `pkg/by-name/<name>/<hash>`, and other paths in the store are [`LinkTarget::Other`].
+    pub fn classify(&self, target: &Path) -> LinkTarget {
+        let target = normalize_path(target);
+        if self.scratch.iter().any(|v| target.starts_with(v)) {
+            return LinkTarget::Scratch;
+        }
+
+        let Ok(relative) = target.strip_prefix(self.store.join("pkg")) else {
+            return LinkTarget::Other;
+        };
+        let mut components = relative.components();
+        fn name(component: Option<Component<'_>>) -> Option<&str> {
+            component?.as_os_str().to_str()
+        }
+        let hash = match name(components.next()) {
+            Some("by-hash") => name(components.next()),
+            Some("by-name") => name(components.nth(1)),
+            _ => None,
+        };
+        match hash.and_then(|v| v.parse().ok()) {
+            Some(hash) => LinkTarget::Package {
+                hash,
+                path: components.as_path().to_path_buf(),
+            },
+            None => LinkTarget::Other,
+        }
+    }
+
+    /// Rewrites a link into a package to its canonical form, `<store>/pkg/by-hash/<hash>/<path>`. Returns `None` if the
+    /// link doesn't point into a package or is canonical already.
+    pub fn canonicalize(&self, target: &Path) -> Option<PathBuf> {
+        let LinkTarget::Package { hash, path } = self.classify(target) else {
+            return None;
+        };
+        let mut canonical = self.store.join("pkg/by-hash").join(hash.to_string());
+        if !path.as_os_str().is_empty() {
+            canonical.push(path);
+        }
+        (canonical != target).then_some(canonical)
+    }
+}
+
+/// Resolves `.` and `..` in a path without accessing the file system, which is what a symlink into a package that
+/// isn't present yet resolves to.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                result.pop();
+            }
+            other => result.push(other),
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classify_links() {
+        let hash = SupportedHash::Blake3([7; 32]);
+        let policy = SymlinkPolicy::new("/store").scratch("/store/tmp");
+        let package = |path: &str| LinkTarget::Package {
+            hash,
+            path: path.into(),
+        };
+
+        assert_eq!(
+            policy.classify(&Path::new("/store/pkg/by-hash").join(format!("{hash}/out/lib"))),
+            package("out/lib")
+        );
+        assert_eq!(
+            policy.classify(&Path::new("/store/pkg/by-name/zlib").join(hash.to_string())),
+            package("")
+        );
+        assert_eq!(
+            policy.classify(Path::new("/store/pkg/by-hash/../../tmp/build-1/out")),
+            LinkTarget::Scratch
+        );
+        for other in [
+            "/usr/bin/env",
+            "/store/pkg/by-hash/invalid",
+            "/store/root/lock",
+        ] {
+            assert_eq!(
+                policy.classify(Path::new(other)),
+                LinkTarget::Other,
+                "{other}"
+            );
+        }
+
+        let canonical = Path::new("/store/pkg/by-hash").join(format!("{hash}/out"));
+        assert_eq!(
+            policy
+                .canonicalize(&Path::new("/store/pkg/by-name/zlib/./").join(format!("{hash}/out"))),
+            Some(canonical.clone())
+        );
+        assert_eq!(policy.canonicalize(&canonical), None);
+        assert_eq!(policy.canonicalize(Path::new("/etc/passwd")), None);
+    }
+
+    #[test]
+    fn placeholder_length() {
+        let hash = SupportedHash::Blake3([7; 32]);
+        let store = Path::new("/var/lib/porkg");
+        let placeholder = placeholder(store, &hash);
+        let destination = store.join("pkg/by-hash").join(hash.to_string());
+        assert_eq!(placeholder.as_os_str().len(), destination.as_os_str().len());
+        assert_eq!(
+            SymlinkPolicy::new(store)
+                .scratch(store.join("tmp"))
+                .classify(&placeholder),
+            LinkTarget::Scratch
+        );
+    }
+}
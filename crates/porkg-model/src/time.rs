@@ -0,0 +1,52 @@
+//! Points in time, as they are recorded in the store and its indexes.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::hashing::{StableHash, StableHasher};
+
+/// A point in time, in seconds since the Unix epoch. Times before the epoch are recorded as the epoch.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        SystemTime::now().into()
+    }
+
+    pub fn as_secs(&self) -> u64 {
+        self.0
+    }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(value: SystemTime) -> Self {
+        Self(value.duration_since(UNIX_EPOCH).map_or(0, |v| v.as_secs()))
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(value: Timestamp) -> Self {
+        UNIX_EPOCH + Duration::from_secs(value.0)
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl StableHash for Timestamp {
+    #[inline(always)]
+    fn update<H: StableHasher>(&self, h: &mut H) {
+        self.0.update(h)
+    }
+}
@@ -0,0 +1,259 @@
+//! Workspaces, which build several packages from one repository.
+//!
+//! A workspace lists the directories of its members in `porkg-workspace.toml`. Members can depend on each other by
+//! package name, and resolve every other dependency through a lock that is shared by the whole workspace, so all
+//! members build with the same version of each package.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{
+    channel::Advisory,
+    package::{Dependency, LockDefinition, Package},
+};
+
+pub const WORKSPACE_FILE: &str = "porkg-workspace.toml";
+pub const WORKSPACE_LOCK_FILE: &str = "porkg-workspace.lock";
+/// The lock of a package that isn't part of a workspace.
+pub const LOCK_FILE: &str = "porkg.lock";
+
+#[derive(Debug, Error)]
+pub enum WorkspaceError {
+    #[error("the package {0} is a member of the workspace more than once")]
+    DuplicateMember(String),
+    #[error("members depend on each other in a cycle: {}", .0.join(" -> "))]
+    Cycle(Vec<String>),
+    #[error("{package} is required at version {first} and at version {second}")]
+    VersionConflict {
+        package: String,
+        first: String,
+        second: String,
+    },
+    #[error("{package}, which {member} depends on, is not in the lock")]
+    Unlocked { package: String, member: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Workspace {
+    pub workspace: WorkspaceMetadata,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkspaceMetadata {
+    /// The directories of the members, relative to the workspace.
+    pub members: Vec<String>,
+}
+
+/// The hashes of the packages that are used by a workspace, or by a single package, by package name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Lock {
+    #[serde(default)]
+    pub packages: BTreeMap<String, String>,
+    /// The versions that an index warns about, by hash (see [`Advisory`]). Only index snapshots have advisories.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub advisories: BTreeMap<String, Advisory>,
+}
+
+fn dependencies(package: &Package) -> impl Iterator<Item = &Dependency> {
+    package
+        .dependencies
+        .values()
+        .chain(package.build_dependencies.values())
+        .chain(package.base.iter())
+}
+
+/// Orders the members of a workspace so that each member comes after the members that it depends on.
+///
+/// Members must agree on the version of every package that isn't a member, and depend on the version of members
+/// that the workspace contains.
+pub fn plan(members: &[Package]) -> Result<Vec<usize>, WorkspaceError> {
+    let mut by_name = BTreeMap::new();
+    for (i, member) in members.iter().enumerate() {
+        if by_name.insert(member.package.name.as_str(), i).is_some() {
+            return Err(WorkspaceError::DuplicateMember(member.package.name.clone()));
+        }
+    }
+
+    let mut versions = BTreeMap::<&str, &str>::new();
+    for member in members {
+        versions.insert(&member.package.name, &member.package.version);
+    }
+    for dependency in members.iter().flat_map(dependencies) {
+        let version = versions
+            .entry(&dependency.name)
+            .or_insert(&dependency.version);
+        if *version != dependency.version {
+            return Err(WorkspaceError::VersionConflict {
+                package: dependency.name.clone(),
+                first: version.to_string(),
+                second: dependency.version.clone(),
+            });
+        }
+    }
+
+    let mut order = Vec::with_capacity(members.len());
+    let mut state = vec![Visit::New; members.len()];
+    let mut stack = Vec::new();
+    for i in 0..members.len() {
+        visit(members, &by_name, i, &mut state, &mut stack, &mut order)?;
+    }
+    Ok(order)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Visit {
+    New,
+    InProgress,
+    Done,
+}
+
+fn visit(
+    members: &[Package],
+    by_name: &BTreeMap<&str, usize>,
+    i: usize,
+    state: &mut [Visit],
+    stack: &mut Vec<usize>,
+    order: &mut Vec<usize>,
+) -> Result<(), WorkspaceError> {
+    match state[i] {
+        Visit::Done => return Ok(()),
+        Visit::InProgress => {
+            let start = stack.iter().position(|v| *v == i).unwrap_or_default();
+            let cycle = stack[start..]
+                .iter()
+                .chain(std::iter::once(&i))
+                .map(|v| members[*v].package.name.clone())
+                .collect();
+            return Err(WorkspaceError::Cycle(cycle));
+        }
+        Visit::New => {}
+    }
+
+    state[i] = Visit::InProgress;
+    stack.push(i);
+    for dependency in dependencies(&members[i]) {
+        if let Some(&j) = by_name.get(dependency.name.as_str()) {
+            visit(members, by_name, j, state, stack, order)?;
+        }
+    }
+    stack.pop();
+    state[i] = Visit::Done;
+    order.push(i);
+    Ok(())
+}
+
+/// Resolves the dependencies of a member to the hashes of the members that were already imported (by package
+/// name), or to the hashes in the lock.
+pub fn lock_member(
+    package: &Package,
+    lock: &Lock,
+    members: &BTreeMap<String, String>,
+) -> Result<LockDefinition, WorkspaceError> {
+    let resolve = |dependency: &Dependency| {
+        members
+            .get(&dependency.name)
+            .or_else(|| lock.packages.get(&dependency.name))
+            .cloned()
+            .ok_or_else(|| WorkspaceError::Unlocked {
+                package: dependency.name.clone(),
+                member: package.package.name.clone(),
+            })
+    };
+    let resolve_all = |dependencies: &BTreeMap<String, Dependency>| {
+        dependencies
+            .iter()
+            .map(|(key, dependency)| Ok((key.clone(), resolve(dependency)?)))
+            .collect::<Result<BTreeMap<_, _>, WorkspaceError>>()
+    };
+
+    Ok(LockDefinition {
+        dependencies: resolve_all(&package.dependencies)?,
+        build_dependencies: resolve_all(&package.build_dependencies)?,
+        base: package.base.as_ref().map(resolve).transpose()?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::package::{Metadata, FORMAT_VERSION};
+
+    use super::*;
+
+    fn package(name: &str, dependencies: &[(&str, &str)]) -> Package {
+        Package {
+            package: Metadata {
+                format: FORMAT_VERSION,
+                name: name.to_string(),
+                version: "1.0.0".to_string(),
+                description: None,
+                compatibility: None,
+                targets: ["out".to_string()].into(),
+                preserve_permissions: false,
+                self_references: false,
+                patch_elf: false,
+            },
+            dependencies: dependencies
+                .iter()
+                .map(|(name, version)| {
+                    let dependency = Dependency {
+                        name: name.to_string(),
+                        version: version.to_string(),
+                        target: "out".to_string(),
+                    };
+                    (name.to_string(), dependency)
+                })
+                .collect(),
+            build_dependencies: BTreeMap::new(),
+            base: None,
+            build_phase: None,
+            install_phase: None,
+            env_passthrough: Default::default(),
+            executables: BTreeMap::new(),
+            activate: None,
+        }
+    }
+
+    #[test]
+    fn plan_workspace() {
+        let members = [
+            package("app", &[("libfoo", "1.0.0"), ("libc", "2.39")]),
+            package("libfoo", &[("libc", "2.39")]),
+            package("tool", &[]),
+        ];
+        assert_eq!(plan(&members).unwrap(), [1, 0, 2]);
+
+        let lock = Lock {
+            packages: [("libc".to_string(), "blake3-libc".to_string())].into(),
+            ..Default::default()
+        };
+        let built = [("libfoo".to_string(), "blake3-libfoo".to_string())].into();
+        let definition = lock_member(&members[0], &lock, &built).unwrap();
+        assert_eq!(definition.dependencies["libfoo"], "blake3-libfoo");
+        assert_eq!(definition.dependencies["libc"], "blake3-libc");
+        assert!(matches!(
+            lock_member(&members[0], &Lock::default(), &built),
+            Err(WorkspaceError::Unlocked { package, .. }) if package == "libc"
+        ));
+
+        let conflict = [members[0].clone(), package("libfoo", &[("libc", "2.40")])];
+        assert!(matches!(
+            plan(&conflict),
+            Err(WorkspaceError::VersionConflict { package, .. }) if package == "libc"
+        ));
+
+        let cycle = [
+            package("a", &[("b", "1.0.0")]),
+            package("b", &[("a", "1.0.0")]),
+        ];
+        assert!(matches!(
+            plan(&cycle),
+            Err(WorkspaceError::Cycle(names)) if names == ["a", "b", "a"]
+        ));
+        assert!(matches!(
+            plan(&[package("a", &[]), package("a", &[])]),
+            Err(WorkspaceError::DuplicateMember(_))
+        ));
+    }
+}
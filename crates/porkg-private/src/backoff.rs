@@ -0,0 +1,171 @@
+//! Delays between retries, which grow exponentially up to a cap, and are randomized so that clients that failed
+//! together don't retry together.
+//!
+//! A [`Backoff`] describes the delays, and [`Backoff::start`] begins a [`Retry`] of one operation, which stops once
+//! the retries or the time budget run out:
+//!
+//! ```ignore
+//! let mut retry = Backoff::new(Duration::from_millis(500)).with_retries(3).start();
+//! loop {
+//!     match operation().await {
+//!         Err(error) if error.is_transient() && retry.wait().await => continue,
+//!         result => break result,
+//!     }
+//! }
+//! ```
+
+use std::time::Duration;
+
+use rand::Rng as _;
+use tokio::time::Instant;
+
+/// How long to wait between retries.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    jitter: f64,
+    retries: Option<u32>,
+    budget: Option<Duration>,
+}
+
+impl Backoff {
+    /// Doubles the delay after each retry, starting at `initial`, without a cap, jitter, or a limit.
+    pub fn new(initial: Duration) -> Self {
+        Self {
+            initial,
+            max: Duration::MAX,
+            jitter: 0.0,
+            retries: None,
+            budget: None,
+        }
+    }
+
+    /// Caps the delay at `max`.
+    pub fn with_max(mut self, max: Duration) -> Self {
+        self.max = max.max(self.initial);
+        self
+    }
+
+    /// Randomly shortens each delay by up to `fraction` of it, where `fraction` is between 0 and 1.
+    pub fn with_jitter(mut self, fraction: f64) -> Self {
+        self.jitter = fraction.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Gives up after `retries` retries.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = Some(retries);
+        self
+    }
+
+    /// Gives up on retries that would start more than `budget` after the operation started.
+    pub fn with_budget(mut self, budget: Duration) -> Self {
+        self.budget = Some(budget);
+        self
+    }
+
+    /// Gets the delay before retry `retry` (counting from 0) without jitter.
+    pub fn delay(&self, retry: u32) -> Duration {
+        self.initial
+            .saturating_mul(1 << retry.min(31))
+            .min(self.max)
+    }
+
+    /// Gets the delay before retry `retry` (counting from 0) with jitter.
+    pub fn jittered(&self, retry: u32) -> Duration {
+        let delay = self.delay(retry);
+        if self.jitter == 0.0 {
+            return delay;
+        }
+        delay.mul_f64(1.0 - rand::thread_rng().gen_range(0.0..=self.jitter))
+    }
+
+    /// Starts retrying an operation, which is taken to start now.
+    pub fn start(&self) -> Retry {
+        Retry {
+            backoff: *self,
+            started: Instant::now(),
+            retry: 0,
+        }
+    }
+}
+
+/// The retries of one operation.
+#[derive(Debug, Clone)]
+pub struct Retry {
+    backoff: Backoff,
+    started: Instant,
+    retry: u32,
+}
+
+impl Retry {
+    /// The number of retries so far.
+    pub fn retries(&self) -> u32 {
+        self.retry
+    }
+
+    /// Gets the delay before the next retry, or `None` if the operation should be given up on.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.backoff.retries.is_some_and(|v| self.retry >= v) {
+            return None;
+        }
+        let delay = self.backoff.jittered(self.retry);
+        if let Some(budget) = self.backoff.budget {
+            if self.started.elapsed() + delay > budget {
+                return None;
+            }
+        }
+        self.retry += 1;
+        Some(delay)
+    }
+
+    /// Waits for the next retry, returning false without waiting if the operation should be given up on.
+    pub async fn wait(&mut self) -> bool {
+        match self.next_delay() {
+            Some(delay) => {
+                tokio::time::sleep(delay).await;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn delay() {
+        let backoff = Backoff::new(Duration::from_secs(1)).with_max(Duration::from_secs(5));
+        let delays: Vec<_> = (0..5).map(|v| backoff.delay(v).as_secs()).collect();
+        assert_eq!(delays, [1, 2, 4, 5, 5]);
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(5));
+
+        let backoff = backoff.with_jitter(0.5);
+        for retry in 0..5 {
+            let delay = backoff.jittered(retry);
+            assert!(delay <= backoff.delay(retry));
+            assert!(delay >= backoff.delay(retry) / 2);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn give_up() {
+        let mut retry = Backoff::new(Duration::from_secs(1)).with_retries(2).start();
+        assert!(retry.wait().await);
+        assert!(retry.wait().await);
+        assert!(!retry.wait().await);
+        assert_eq!(retry.retries(), 2);
+
+        let mut retry = Backoff::new(Duration::from_secs(1))
+            .with_budget(Duration::from_secs(5))
+            .start();
+        let started = Instant::now();
+        while retry.wait().await {}
+        // The third retry would have started after 1 + 2 + 4 seconds.
+        assert_eq!(retry.retries(), 2);
+        assert_eq!(started.elapsed(), Duration::from_secs(3));
+    }
+}
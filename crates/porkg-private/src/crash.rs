@@ -0,0 +1,175 @@
+//! Crash reports, which a panic hook writes to a directory for every panic, so that a panic leaves more behind than a
+//! line in a log that may already be gone.
+//!
+//! The hook is installed once by the daemon, and processes that are cloned from it (the zygote and the workers) keep
+//! it. They only rename themselves with [`set_process`], and workers record the task that they run with
+//! [`set_task`].
+
+use std::{
+    any::Any,
+    backtrace::Backtrace,
+    fs,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        PoisonError, RwLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+// PanicHookInfo replaces this from Rust 1.81, which is newer than the toolchain that the workspace is pinned to.
+#[allow(deprecated)]
+use std::panic::PanicInfo;
+
+use serde::Serialize;
+
+struct State {
+    dir: Option<PathBuf>,
+    version: &'static str,
+    process: &'static str,
+    task: Option<String>,
+}
+
+static STATE: RwLock<State> = RwLock::new(State {
+    dir: None,
+    version: "",
+    process: "",
+    task: None,
+});
+
+/// The number of reports that the process wrote, which tells apart the reports of panics in the same second.
+static REPORTS: AtomicU32 = AtomicU32::new(0);
+
+/// What a process was doing when it panicked.
+#[derive(Debug, Clone, Serialize)]
+pub struct CrashReport {
+    pub process: &'static str,
+    pub version: &'static str,
+    pub pid: u32,
+    /// When the process panicked, in seconds since the Unix epoch.
+    pub time: u64,
+    /// The task that the process was running.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub task: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread: Option<String>,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    pub backtrace: String,
+}
+
+impl CrashReport {
+    #[allow(deprecated)]
+    fn new(info: &PanicInfo<'_>) -> Self {
+        let state = STATE.read().unwrap_or_else(PoisonError::into_inner);
+        Self {
+            process: state.process,
+            version: state.version,
+            pid: std::process::id(),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |v| v.as_secs()),
+            task: state.task.clone(),
+            thread: std::thread::current().name().map(str::to_string),
+            message: message(info.payload()),
+            location: info.location().map(ToString::to_string),
+            backtrace: Backtrace::force_capture().to_string(),
+        }
+    }
+
+    /// Writes the report as JSON to a new file in `dir`, returning its path.
+    pub fn write(&self, dir: &Path) -> io::Result<PathBuf> {
+        fs::create_dir_all(dir)?;
+        let n = REPORTS.fetch_add(1, Ordering::Relaxed);
+        let name = format!("{}-{}-{}-{n}.json", self.process, self.pid, self.time);
+        let path = dir.join(name);
+        let mut file = fs::File::options()
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        serde_json::to_writer_pretty(&mut file, self)?;
+        file.write_all(b"\n")?;
+        Ok(path)
+    }
+}
+
+/// Writes a crash report to `dir` whenever the process (or a process that is cloned from it) panics, before the hook
+/// that was installed before runs.
+pub fn install(dir: impl Into<PathBuf>, version: &'static str, process: &'static str) {
+    {
+        let mut state = STATE.write().unwrap_or_else(PoisonError::into_inner);
+        state.dir = Some(dir.into());
+        state.version = version;
+        state.process = process;
+    }
+
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let report = CrashReport::new(info);
+        let dir = STATE
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .dir
+            .clone();
+        if let Some(dir) = dir {
+            match report.write(&dir) {
+                Ok(path) => tracing::error!(?path, "wrote a crash report"),
+                Err(error) => tracing::error!(?error, ?dir, "failed to write a crash report"),
+            }
+        }
+        previous(info);
+    }));
+}
+
+/// Renames the process in the crash reports that it writes.
+pub fn set_process(process: &'static str) {
+    STATE
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .process = process;
+}
+
+/// Records the task that the process runs in the crash reports that it writes.
+pub fn set_task(task: Option<String>) {
+    STATE.write().unwrap_or_else(PoisonError::into_inner).task = task;
+}
+
+/// Gets the message that a panic was raised with.
+pub fn message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "a panic occurred".to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn write_reports() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        install(dir, "1.2.3", "test");
+        set_task(Some("blake3-aaaa".to_string()));
+        let payload = std::panic::catch_unwind(|| panic!("broken {}", 42)).unwrap_err();
+        assert_eq!(message(&*payload), "broken 42");
+
+        let reports: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|v| v.unwrap().path())
+            .collect();
+        assert_eq!(reports.len(), 1);
+        let report: serde_json::Value =
+            serde_json::from_slice(&fs::read(&reports[0]).unwrap()).unwrap();
+        assert_eq!(report["process"], "test");
+        assert_eq!(report["version"], "1.2.3");
+        assert_eq!(report["task"], "blake3-aaaa");
+        assert_eq!(report["message"], "broken 42");
+        assert_eq!(report["pid"], std::process::id());
+    }
+}
@@ -0,0 +1,269 @@
+//! Broadcasts a growing stream, such as the log of a job, to any number of readers without letting slow readers hold
+//! memory.
+//!
+//! The stream is kept in a ring buffer of its most recent bytes, and each reader has a position in the stream.
+//! Writing never waits for readers: a reader that falls so far behind that its position has been overwritten is told
+//! which range it missed ([`Chunk::Lagged`]) and continues from the oldest buffered byte, so that it can either tail
+//! the missed range from a copy of the stream on disk, or give up. The buffer keeps an account of what was evicted
+//! and missed ([`Overflow`]).
+
+use std::{
+    collections::VecDeque,
+    pin::pin,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use tokio::sync::Notify;
+
+/// The bytes that were evicted from the buffer, and the bytes that readers missed because of it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Overflow {
+    /// The number of bytes that were overwritten.
+    pub evicted: u64,
+    /// The number of times that a reader fell behind the buffer.
+    pub lagged: u64,
+    /// The number of bytes that readers skipped over, in total.
+    pub missed: u64,
+}
+
+/// What a reader receives next.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Chunk {
+    /// The bytes of the stream that follow the reader's position.
+    Data(Vec<u8>),
+    /// The reader fell behind, and the bytes from offset `from` up to `to` are no longer buffered.
+    Lagged { from: u64, to: u64 },
+}
+
+#[derive(Debug)]
+struct State {
+    ring: VecDeque<u8>,
+    capacity: usize,
+    /// The offset in the stream of the oldest buffered byte.
+    start: u64,
+    closed: bool,
+    overflow: Overflow,
+}
+
+impl State {
+    fn end(&self) -> u64 {
+        self.start + self.ring.len() as u64
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<State>,
+    written: Notify,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        // The state is consistent between statements, so a panicking reader doesn't invalidate it.
+        self.state.lock().unwrap_or_else(|error| error.into_inner())
+    }
+}
+
+/// The writing end of a stream, which keeps the last `capacity` bytes.
+#[derive(Debug, Clone)]
+pub struct FanOut {
+    shared: Arc<Shared>,
+}
+
+impl FanOut {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    ring: VecDeque::with_capacity(capacity),
+                    capacity: capacity.max(1),
+                    start: 0,
+                    closed: false,
+                    overflow: Overflow::default(),
+                }),
+                written: Notify::new(),
+            }),
+        }
+    }
+
+    /// Appends to the stream, evicting the oldest bytes if the buffer is full.
+    pub fn write(&self, data: &[u8]) {
+        {
+            let mut state = self.shared.lock();
+            let excess = (state.ring.len() + data.len()).saturating_sub(state.capacity);
+            let dropped = excess.min(state.ring.len());
+            state.ring.drain(..dropped);
+            // Data that is larger than the buffer only keeps its end.
+            let skipped = excess - dropped;
+            state.ring.extend(&data[skipped..]);
+            state.start += excess as u64;
+            state.overflow.evicted += excess as u64;
+        }
+        self.shared.written.notify_waiters();
+    }
+
+    /// Ends the stream. Readers receive what is buffered, and then the end of the stream.
+    pub fn close(&self) {
+        self.shared.lock().closed = true;
+        self.shared.written.notify_waiters();
+    }
+
+    /// Gets the offset of the end of the stream, which is the number of bytes that have been written.
+    pub fn position(&self) -> u64 {
+        self.shared.lock().end()
+    }
+
+    pub fn overflow(&self) -> Overflow {
+        self.shared.lock().overflow
+    }
+
+    /// Creates a reader that starts from the oldest buffered byte.
+    pub fn subscribe(&self) -> Subscriber {
+        let position = self.shared.lock().start;
+        self.subscribe_at(position)
+    }
+
+    /// Creates a reader that starts from an offset in the stream, such as the end of the part that it read from disk.
+    pub fn subscribe_at(&self, position: u64) -> Subscriber {
+        Subscriber {
+            shared: self.shared.clone(),
+            position,
+        }
+    }
+}
+
+enum Poll {
+    Ready(Chunk),
+    Pending,
+    Closed,
+}
+
+/// A reader of a stream, which never holds more than one chunk of it.
+#[derive(Debug)]
+pub struct Subscriber {
+    shared: Arc<Shared>,
+    position: u64,
+}
+
+impl Subscriber {
+    /// Gets the offset in the stream of the next byte that the reader receives.
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn poll(&mut self, max: usize) -> Poll {
+        let mut state = self.shared.lock();
+        if self.position < state.start {
+            let from = self.position;
+            self.position = state.start;
+            state.overflow.lagged += 1;
+            state.overflow.missed += state.start - from;
+            return Poll::Ready(Chunk::Lagged {
+                from,
+                to: state.start,
+            });
+        }
+
+        // A position past the end only receives the bytes that are written after it.
+        let offset = (self.position - state.start) as usize;
+        if offset >= state.ring.len() {
+            return if state.closed {
+                Poll::Closed
+            } else {
+                Poll::Pending
+            };
+        }
+        let size = max.max(1).min(state.ring.len() - offset);
+        let data: Vec<u8> = state.ring.range(offset..offset + size).copied().collect();
+        self.position += size as u64;
+        Poll::Ready(Chunk::Data(data))
+    }
+
+    /// Receives up to `max` bytes that are already buffered, without waiting.
+    pub fn try_next(&mut self, max: usize) -> Option<Chunk> {
+        match self.poll(max) {
+            Poll::Ready(chunk) => Some(chunk),
+            Poll::Pending | Poll::Closed => None,
+        }
+    }
+
+    /// Receives up to `max` bytes, waiting for them to be written. Returns `None` at the end of the stream.
+    pub async fn next(&mut self, max: usize) -> Option<Chunk> {
+        let shared = self.shared.clone();
+        loop {
+            // Waiting is registered before polling, so that a write in between isn't missed.
+            let mut written = pin!(shared.written.notified());
+            written.as_mut().enable();
+            match self.poll(max) {
+                Poll::Ready(chunk) => return Some(chunk),
+                Poll::Closed => return None,
+                Poll::Pending => written.await,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn overflow() {
+        let fanout = FanOut::new(8);
+        let mut fast = fanout.subscribe();
+        let mut slow = fanout.subscribe();
+
+        fanout.write(b"hello");
+        assert_eq!(fast.try_next(3), Some(Chunk::Data(b"hel".to_vec())));
+        assert_eq!(fast.try_next(16), Some(Chunk::Data(b"lo".to_vec())));
+        assert_eq!(fast.try_next(16), None);
+
+        // The slow reader is told what it missed instead of being buffered for.
+        fanout.write(b" world");
+        assert_eq!(fanout.position(), 11);
+        assert_eq!(fast.try_next(16), Some(Chunk::Data(b" world".to_vec())));
+        assert_eq!(slow.try_next(16), Some(Chunk::Lagged { from: 0, to: 3 }));
+        assert_eq!(slow.position(), 3);
+        assert_eq!(slow.try_next(16), Some(Chunk::Data(b"lo world".to_vec())));
+
+        fanout.write(b"0123456789");
+        assert_eq!(fast.try_next(16), Some(Chunk::Lagged { from: 11, to: 13 }));
+        assert_eq!(fast.try_next(16), Some(Chunk::Data(b"23456789".to_vec())));
+        assert_eq!(
+            fanout.overflow(),
+            Overflow {
+                evicted: 13,
+                lagged: 2,
+                missed: 5,
+            }
+        );
+
+        // Readers can resume from where they stopped reading a copy on disk.
+        let mut resumed = fanout.subscribe_at(19);
+        assert_eq!(resumed.try_next(16), Some(Chunk::Data(b"89".to_vec())));
+    }
+
+    #[tokio::test]
+    async fn wait_for_writes() {
+        let fanout = FanOut::new(1024);
+        let mut reader = fanout.subscribe();
+        let writer = fanout.clone();
+        let task = tokio::spawn(async move {
+            let mut received = Vec::new();
+            while let Some(chunk) = reader.next(4).await {
+                match chunk {
+                    Chunk::Data(data) => received.extend(data),
+                    Chunk::Lagged { .. } => unreachable!(),
+                }
+            }
+            received
+        });
+
+        for line in [&b"first\n"[..], b"second\n"] {
+            tokio::task::yield_now().await;
+            writer.write(line);
+        }
+        writer.close();
+        assert_eq!(task.await.unwrap(), b"first\nsecond\n");
+    }
+}
@@ -8,17 +8,63 @@ use std::{
             prelude::{FromRawFd, OwnedFd},
         },
     },
+    time::Duration,
 };
 
 use bytes::{buf::Limit, Buf, BufMut, BytesMut};
 use thiserror::Error;
 use uds::{tokio::UnixStreamExt as _, UnixStreamExt};
 
-use crate::{mem::get_buffer, ser};
+mod credentials;
+mod socket;
+
+pub use credentials::Credentials;
+pub use socket::{
+    bind_abstract, connect_abstract, into_async, pair, pair_async, peer_groups, SocketOptions,
+};
+
+use crate::{
+    mem::{get_buffer, get_secret_buffer, Pooled},
+    ser,
+};
 
 const READ_BUFFER_SIZE: usize = 8192;
 const FD_BUFFER_SIZE: usize = 128;
 const HEADER_SIZE: usize = size_of::<usize>();
+/// Set in the header of a frame whose payload is the length of the message followed by the message compressed with
+/// zstd.
+const COMPRESSED: usize = 1 << (usize::BITS - 1);
+
+/// The size above which messages are compressed by [`SendOptions::compressed`].
+pub const COMPRESS_ABOVE: usize = 64 * 1024;
+
+/// How a message is sent.
+///
+/// Messages are received the same way however they were sent, so compression only needs the peer to support it,
+/// which is usually agreed when the connection is set up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SendOptions {
+    /// Whether the message may contain secrets, such as tokens or keys, in which case it is sent from buffers that
+    /// are zeroed before they are reused (see [`get_secret_buffer`]).
+    pub secret: bool,
+    /// Compresses messages that are larger than this with zstd.
+    pub compress_above: Option<usize>,
+}
+
+impl SendOptions {
+    /// Compresses messages larger than [`COMPRESS_ABOVE`].
+    pub fn compressed(self, enabled: bool) -> Self {
+        Self {
+            compress_above: enabled.then_some(COMPRESS_ABOVE),
+            ..self
+        }
+    }
+}
+
+const SECRET: SendOptions = SendOptions {
+    secret: true,
+    compress_above: None,
+};
 
 pub trait LimitExt {
     fn reserve_and_limit(&mut self, len: usize) -> Limit<&mut Self>;
@@ -37,6 +83,66 @@ pub enum SocketMessageError {
     IO(#[from] std::io::Error),
     #[error(transparent)]
     Serialize(#[from] ser::Error),
+    #[error("the peer did not respond within {0:?}")]
+    Timeout(Duration),
+}
+
+/// How a long-lived connection checks that its peer is still responding, which a closed socket doesn't reveal when
+/// the peer is stuck.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// How long an idle connection waits before checking the peer.
+    pub interval: Duration,
+    /// How long the peer may take to respond to a check, or to any other exchange.
+    pub timeout: Duration,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+impl Keepalive {
+    /// Fails with [`SocketMessageError::Timeout`] if `exchange` doesn't complete within the timeout.
+    pub async fn deadline<T>(
+        &self,
+        exchange: impl Future<Output = Result<T, SocketMessageError>>,
+    ) -> Result<T, SocketMessageError> {
+        tokio::time::timeout(self.timeout, exchange)
+            .await
+            .map_err(|_| SocketMessageError::Timeout(self.timeout))?
+    }
+
+    /// Sends `request` and waits for the peer to echo it back.
+    pub async fn ping(
+        &self,
+        socket: &impl DomainSocketAsync,
+        request: u8,
+    ) -> Result<(), SocketMessageError> {
+        self.deadline(async {
+            socket.send_all(&mut &[request][..], &[]).await?;
+            let mut response = [0u8; 1];
+            socket
+                .recv_exact(&mut &mut response[..], &mut Vec::new())
+                .await?;
+            if response[0] != request {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "expected {request} in response to a ping, got {}",
+                        response[0]
+                    ),
+                )
+                .into());
+            }
+            Ok(())
+        })
+        .await
+    }
 }
 
 pub trait DomainSocket {
@@ -47,39 +153,182 @@ pub trait DomainSocket {
         fds: &mut impl Extend<OwnedFd>,
     ) -> Result<(), std::io::Error>;
 
+    /// Gets the credentials of the process that connected the socket (`SO_PEERCRED`), which may have passed it on to
+    /// another process since.
+    fn peer_credentials(&self) -> Result<Credentials, std::io::Error>;
+
+    /// Asks the kernel to report the credentials of the process that sent each message (`SCM_CREDENTIALS`), which it
+    /// only does for data sent after this was called.
+    fn pass_credentials(&self) -> Result<(), std::io::Error>;
+
+    /// Like [`DomainSocket::recv_exact`], but also returns the credentials of the process that sent the start of the
+    /// data, which must have been sent after [`DomainSocket::pass_credentials`] was called.
+    fn recv_exact_with_credentials(
+        &self,
+        data: &mut impl BufMut,
+        fds: &mut impl Extend<OwnedFd>,
+    ) -> Result<Credentials, std::io::Error>;
+
     fn send_message<T: crate::ser::Serialize>(
         &self,
         message: &T,
         fds: &[RawFd],
     ) -> Result<(), SocketMessageError> {
-        let mut buf = get_buffer();
-
-        buf.put_slice(&[0u8; HEADER_SIZE]);
-        ser::serialize(message, buf.as_mut())?;
-
-        let len = buf.len() - HEADER_SIZE;
-        buf[..HEADER_SIZE].copy_from_slice(&len.to_ne_bytes());
+        self.send_message_with(message, fds, SendOptions::default())
+    }
 
+    fn send_message_with<T: crate::ser::Serialize>(
+        &self,
+        message: &T,
+        fds: &[RawFd],
+        options: SendOptions,
+    ) -> Result<(), SocketMessageError> {
+        let mut buf = buffer(options.secret);
+        encode(message, buf.as_mut(), options)?;
         self.send_all(buf.as_mut(), fds)?;
-
         Ok(())
     }
 
+    /// Sends a message that may contain secrets, such as tokens or keys, from a buffer that is zeroed before it is
+    /// reused (see [`get_secret_buffer`]).
+    fn send_secret_message<T: crate::ser::Serialize>(
+        &self,
+        message: &T,
+        fds: &[RawFd],
+    ) -> Result<(), SocketMessageError> {
+        self.send_message_with(message, fds, SECRET)
+    }
+
     fn recv_message<T: crate::ser::Deserialize>(
         &self,
         fds: &mut impl Extend<OwnedFd>,
     ) -> Result<T, SocketMessageError> {
         let mut buf = get_buffer();
+        recv_frame(self, buf.as_mut(), fds, false)?;
+        Ok(ser::deserialize(buf.as_mut())?)
+    }
+
+    /// Receives a message with the credentials of the process that sent it, which unlike
+    /// [`DomainSocket::peer_credentials`] can't be a process that was handed the socket without being trusted with it.
+    fn recv_message_with_credentials<T: crate::ser::Deserialize>(
+        &self,
+        fds: &mut impl Extend<OwnedFd>,
+    ) -> Result<(T, Credentials), SocketMessageError> {
+        let mut buf = get_buffer();
+        let credentials = self
+            .recv_exact_with_credentials(&mut buf.as_mut().reserve_and_limit(HEADER_SIZE), fds)?;
+        recv_payload(self, buf.as_mut(), fds, false)?;
+        Ok((ser::deserialize(buf.as_mut())?, credentials))
+    }
+
+    /// Receives a message that was sent with [`DomainSocket::send_secret_message`].
+    fn recv_secret_message<T: crate::ser::Deserialize>(
+        &self,
+        fds: &mut impl Extend<OwnedFd>,
+    ) -> Result<T, SocketMessageError> {
+        let mut buf = get_secret_buffer();
+        recv_frame(self, buf.as_mut(), fds, true)?;
+        Ok(ser::deserialize(buf.as_mut())?)
+    }
+}
+
+fn buffer(secret: bool) -> Pooled<'static, BytesMut> {
+    if secret {
+        get_secret_buffer()
+    } else {
+        get_buffer()
+    }
+}
 
-        self.recv_exact(&mut buf.reserve_and_limit(HEADER_SIZE), fds)?;
-        let len = usize::from_ne_bytes(buf[..HEADER_SIZE].try_into().unwrap());
+/// Writes a message with its header to `buf`, which is grown to fit it first so that it is written to one allocation.
+fn encode<T: crate::ser::Serialize + ?Sized>(
+    message: &T,
+    buf: &mut BytesMut,
+    options: SendOptions,
+) -> Result<(), SocketMessageError> {
+    let len = ser::serialized_size(message)? as usize;
+    match options.compress_above {
+        Some(threshold) if len > threshold => {
+            let mut plain = buffer(options.secret);
+            plain.reserve(len);
+            ser::serialize(message, plain.as_mut())?;
+
+            let start = 2 * HEADER_SIZE;
+            buf.resize(start + zstd::zstd_safe::compress_bound(len), 0);
+            let size = zstd::bulk::compress_to_buffer(
+                &plain,
+                &mut buf[start..],
+                zstd::DEFAULT_COMPRESSION_LEVEL,
+            )?;
+            buf.truncate(start + size);
+            buf[..HEADER_SIZE].copy_from_slice(&((HEADER_SIZE + size) | COMPRESSED).to_ne_bytes());
+            buf[HEADER_SIZE..start].copy_from_slice(&len.to_ne_bytes());
+        }
+        _ => {
+            buf.reserve(HEADER_SIZE + len);
+            buf.put_slice(&len.to_ne_bytes());
+            ser::serialize(message, buf)?;
+        }
+    }
+    Ok(())
+}
 
-        buf.clear();
-        self.recv_exact(&mut buf.reserve_and_limit(len), fds)?;
+/// Reads the header of a frame, returning the length of its payload and whether it is compressed.
+fn decode_header(buf: &[u8]) -> (usize, bool) {
+    let header = usize::from_ne_bytes(buf[..HEADER_SIZE].try_into().unwrap());
+    (header & !COMPRESSED, header & COMPRESSED != 0)
+}
 
-        let result = ser::deserialize(buf.as_mut())?;
-        Ok(result)
+/// Replaces the compressed payload in `buf` with the message that it contains.
+fn decompress(buf: &mut BytesMut, secret: bool) -> Result<(), std::io::Error> {
+    if buf.len() < HEADER_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "truncated compressed message",
+        ));
     }
+    let len = usize::from_ne_bytes(buf[..HEADER_SIZE].try_into().unwrap());
+    let mut compressed = buffer(secret);
+    compressed.extend_from_slice(&buf[HEADER_SIZE..]);
+
+    buf.clear();
+    buf.resize(len, 0);
+    let size = zstd::bulk::decompress_to_buffer(&compressed, &mut buf[..])?;
+    if size != len {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "compressed message has the wrong length",
+        ));
+    }
+    Ok(())
+}
+
+/// Receives the payload of a message into `buf`.
+fn recv_frame(
+    socket: &(impl DomainSocket + ?Sized),
+    buf: &mut BytesMut,
+    fds: &mut impl Extend<OwnedFd>,
+    secret: bool,
+) -> Result<(), std::io::Error> {
+    socket.recv_exact(&mut buf.reserve_and_limit(HEADER_SIZE), fds)?;
+    recv_payload(socket, buf, fds, secret)
+}
+
+/// Receives the payload of a message into `buf`, which holds its header.
+fn recv_payload(
+    socket: &(impl DomainSocket + ?Sized),
+    buf: &mut BytesMut,
+    fds: &mut impl Extend<OwnedFd>,
+    secret: bool,
+) -> Result<(), std::io::Error> {
+    let (len, compressed) = decode_header(buf);
+
+    buf.clear();
+    socket.recv_exact(&mut buf.reserve_and_limit(len), fds)?;
+    if compressed {
+        decompress(buf, secret)?;
+    }
+    Ok(())
 }
 
 impl DomainSocket for UnixStream {
@@ -109,10 +358,53 @@ impl DomainSocket for UnixStream {
                     .iter()
                     .map(|v| unsafe { OwnedFd::from_raw_fd(*v) }),
             );
+            if buf_size == 0 {
+                // The peer closed the socket in the middle of the data.
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
             data.put(&buffer[..buf_size]);
         }
         Ok(())
     }
+
+    fn peer_credentials(&self) -> Result<Credentials, std::io::Error> {
+        credentials::peer(self)
+    }
+
+    fn pass_credentials(&self) -> Result<(), std::io::Error> {
+        credentials::enable(self)
+    }
+
+    fn recv_exact_with_credentials(
+        &self,
+        data: &mut impl BufMut,
+        fds: &mut impl Extend<OwnedFd>,
+    ) -> Result<Credentials, std::io::Error> {
+        let mut buffer = [0u8; READ_BUFFER_SIZE];
+        let to_read = buffer.len().min(data.remaining_mut());
+        let (size, credentials) = credentials::recv(self, &mut buffer[..to_read], fds)?;
+        data.put(&buffer[..size]);
+        let credentials = check_credentials(size, to_read, credentials)?;
+        self.recv_exact(data, fds)?;
+        Ok(credentials)
+    }
+}
+
+/// Checks that a receive that asked for credentials got them, along with some data.
+fn check_credentials(
+    size: usize,
+    to_read: usize,
+    credentials: Option<Credentials>,
+) -> Result<Credentials, std::io::Error> {
+    if size == 0 && to_read != 0 {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    credentials.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "the kernel did not report the credentials of the sender, were they passed before it sent?",
+        )
+    })
 }
 
 pub trait DomainSocketAsync {
@@ -127,6 +419,19 @@ pub trait DomainSocketAsync {
         data: &mut (impl BufMut + Send + Sync),
         fds: &mut (impl Extend<OwnedFd> + Send),
     ) -> impl Send + Future<Output = Result<(), std::io::Error>>;
+
+    /// See [`DomainSocket::peer_credentials`].
+    fn peer_credentials(&self) -> Result<Credentials, std::io::Error>;
+
+    /// See [`DomainSocket::pass_credentials`].
+    fn pass_credentials(&self) -> Result<(), std::io::Error>;
+
+    /// See [`DomainSocket::recv_exact_with_credentials`].
+    fn recv_exact_with_credentials(
+        &self,
+        data: &mut (impl BufMut + Send + Sync),
+        fds: &mut (impl Extend<OwnedFd> + Send),
+    ) -> impl Send + Future<Output = Result<Credentials, std::io::Error>>;
 }
 
 pub trait DomainSocketAsyncExt {
@@ -136,10 +441,37 @@ pub trait DomainSocketAsyncExt {
         fds: &[RawFd],
     ) -> impl Send + Future<Output = Result<(), SocketMessageError>>;
 
+    fn send_message_with<T: crate::ser::Serialize + Send + Sync>(
+        &self,
+        message: &T,
+        fds: &[RawFd],
+        options: SendOptions,
+    ) -> impl Send + Future<Output = Result<(), SocketMessageError>>;
+
+    /// Sends a message that may contain secrets, such as tokens or keys, from a buffer that is zeroed before it is
+    /// reused (see [`get_secret_buffer`]).
+    fn send_secret_message<T: crate::ser::Serialize + Send + Sync>(
+        &self,
+        message: &T,
+        fds: &[RawFd],
+    ) -> impl Send + Future<Output = Result<(), SocketMessageError>>;
+
     fn recv_message<T: crate::ser::Deserialize + Send + Sync>(
         &self,
         fds: &mut (impl Extend<OwnedFd> + Send),
     ) -> impl Send + Future<Output = Result<T, SocketMessageError>>;
+
+    /// See [`DomainSocket::recv_message_with_credentials`].
+    fn recv_message_with_credentials<T: crate::ser::Deserialize + Send + Sync>(
+        &self,
+        fds: &mut (impl Extend<OwnedFd> + Send),
+    ) -> impl Send + Future<Output = Result<(T, Credentials), SocketMessageError>>;
+
+    /// Receives a message that was sent with [`DomainSocketAsyncExt::send_secret_message`].
+    fn recv_secret_message<T: crate::ser::Deserialize + Send + Sync>(
+        &self,
+        fds: &mut (impl Extend<OwnedFd> + Send),
+    ) -> impl Send + Future<Output = Result<T, SocketMessageError>>;
 }
 
 impl<S: DomainSocketAsync + Send + Sync> DomainSocketAsyncExt for S {
@@ -148,36 +480,91 @@ impl<S: DomainSocketAsync + Send + Sync> DomainSocketAsyncExt for S {
         message: &T,
         fds: &[RawFd],
     ) -> Result<(), SocketMessageError> {
-        let mut buf = get_buffer();
-
-        buf.put_slice(&[0u8; HEADER_SIZE]);
-        ser::serialize(message, buf.as_mut())?;
-
-        let len = buf.len() - HEADER_SIZE;
-        buf[..HEADER_SIZE].copy_from_slice(&len.to_ne_bytes());
+        self.send_message_with(message, fds, SendOptions::default())
+            .await
+    }
 
+    async fn send_message_with<T: crate::ser::Serialize + Send + Sync>(
+        &self,
+        message: &T,
+        fds: &[RawFd],
+        options: SendOptions,
+    ) -> Result<(), SocketMessageError> {
+        let mut buf = buffer(options.secret);
+        encode(message, buf.as_mut(), options)?;
         self.send_all(buf.as_mut(), fds).await?;
-
         Ok(())
     }
 
+    async fn send_secret_message<T: crate::ser::Serialize + Send + Sync>(
+        &self,
+        message: &T,
+        fds: &[RawFd],
+    ) -> Result<(), SocketMessageError> {
+        self.send_message_with(message, fds, SECRET).await
+    }
+
     async fn recv_message<T: crate::ser::Deserialize>(
         &self,
         fds: &mut (impl Extend<OwnedFd> + Send),
     ) -> Result<T, SocketMessageError> {
         let mut buf = get_buffer();
+        recv_frame_async(self, buf.as_mut(), fds, false).await?;
+        Ok(ser::deserialize(buf.as_mut())?)
+    }
 
-        self.recv_exact(&mut buf.reserve_and_limit(HEADER_SIZE), fds)
+    async fn recv_message_with_credentials<T: crate::ser::Deserialize>(
+        &self,
+        fds: &mut (impl Extend<OwnedFd> + Send),
+    ) -> Result<(T, Credentials), SocketMessageError> {
+        let mut buf = get_buffer();
+        let credentials = self
+            .recv_exact_with_credentials(&mut buf.as_mut().reserve_and_limit(HEADER_SIZE), fds)
             .await?;
-        let len = usize::from_ne_bytes(buf[..HEADER_SIZE].try_into().unwrap());
+        recv_payload_async(self, buf.as_mut(), fds, false).await?;
+        Ok((ser::deserialize(buf.as_mut())?, credentials))
+    }
 
-        buf.clear();
-        self.recv_exact(&mut buf.reserve_and_limit(len), fds)
-            .await?;
+    async fn recv_secret_message<T: crate::ser::Deserialize>(
+        &self,
+        fds: &mut (impl Extend<OwnedFd> + Send),
+    ) -> Result<T, SocketMessageError> {
+        let mut buf = get_secret_buffer();
+        recv_frame_async(self, buf.as_mut(), fds, true).await?;
+        Ok(ser::deserialize(buf.as_mut())?)
+    }
+}
 
-        let result = ser::deserialize(buf.as_mut())?;
-        Ok(result)
+/// Receives the payload of a message into `buf`.
+async fn recv_frame_async(
+    socket: &impl DomainSocketAsync,
+    buf: &mut BytesMut,
+    fds: &mut (impl Extend<OwnedFd> + Send),
+    secret: bool,
+) -> Result<(), std::io::Error> {
+    socket
+        .recv_exact(&mut buf.reserve_and_limit(HEADER_SIZE), fds)
+        .await?;
+    recv_payload_async(socket, buf, fds, secret).await
+}
+
+/// Receives the payload of a message into `buf`, which holds its header.
+async fn recv_payload_async(
+    socket: &impl DomainSocketAsync,
+    buf: &mut BytesMut,
+    fds: &mut (impl Extend<OwnedFd> + Send),
+    secret: bool,
+) -> Result<(), std::io::Error> {
+    let (len, compressed) = decode_header(buf);
+
+    buf.clear();
+    socket
+        .recv_exact(&mut buf.reserve_and_limit(len), fds)
+        .await?;
+    if compressed {
+        decompress(buf, secret)?;
     }
+    Ok(())
 }
 
 impl DomainSocketAsync for tokio::net::UnixStream {
@@ -209,10 +596,40 @@ impl DomainSocketAsync for tokio::net::UnixStream {
                     .iter()
                     .map(|v| unsafe { OwnedFd::from_raw_fd(*v) }),
             );
+            if buf_size == 0 {
+                // The peer closed the socket in the middle of the data.
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
             data.put(&buffer[..buf_size]);
         }
         Ok(())
     }
+
+    fn peer_credentials(&self) -> Result<Credentials, std::io::Error> {
+        credentials::peer(self)
+    }
+
+    fn pass_credentials(&self) -> Result<(), std::io::Error> {
+        credentials::enable(self)
+    }
+
+    async fn recv_exact_with_credentials(
+        &self,
+        data: &mut (impl BufMut + Send + Sync),
+        fds: &mut (impl Extend<OwnedFd> + Send),
+    ) -> Result<Credentials, std::io::Error> {
+        let mut buffer = [0u8; READ_BUFFER_SIZE];
+        let to_read = buffer.len().min(data.remaining_mut());
+        let (size, credentials) = self
+            .async_io(tokio::io::Interest::READABLE, || {
+                credentials::recv(self, &mut buffer[..to_read], fds)
+            })
+            .await?;
+        data.put(&buffer[..size]);
+        let credentials = check_credentials(size, to_read, credentials)?;
+        DomainSocketAsync::recv_exact(self, data, fds).await?;
+        Ok(credentials)
+    }
 }
 
 #[cfg(test)]
@@ -221,11 +638,10 @@ mod test {
 
     use pretty_assertions::assert_eq;
     use serde::{Deserialize, Serialize};
-    use tokio::net::UnixStream as UnixStreamAsync;
 
     use crate::io::DomainSocketAsyncExt as _;
 
-    use super::DomainSocket;
+    use super::*;
 
     #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
     pub struct SomeMessage {
@@ -234,7 +650,7 @@ mod test {
 
     #[test]
     pub fn send_recv_message() {
-        let (a, b) = UnixStream::pair().unwrap();
+        let (a, b) = pair(SocketOptions::default()).unwrap();
         let msg = SomeMessage { value: 42 };
 
         a.send_message(&msg, &[]).unwrap();
@@ -246,10 +662,115 @@ mod test {
         assert!(fds.is_empty());
     }
 
+    #[test]
+    pub fn send_recv_secret_message() {
+        let (a, b) = pair(SocketOptions::default()).unwrap();
+        let msg = SomeMessage { value: 42 };
+
+        a.send_secret_message(&msg, &[]).unwrap();
+        a.send_message(&msg, &[]).unwrap();
+
+        let r: SomeMessage = b.recv_secret_message(&mut Vec::new()).unwrap();
+        assert_eq!(msg, r);
+        let r: SomeMessage = b.recv_message(&mut Vec::new()).unwrap();
+        assert_eq!(msg, r);
+    }
+
+    #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+    pub struct LargeMessage {
+        values: Vec<String>,
+    }
+
+    #[test]
+    pub fn send_recv_compressed_message() {
+        let (a, b) = pair(SocketOptions::default()).unwrap();
+        let msg = LargeMessage {
+            values: (0..10_000)
+                .map(|v| format!("VALUE_{}=/nix/store", v % 100))
+                .collect(),
+        };
+        let options = SendOptions::default().compressed(true);
+
+        let size = std::thread::scope(|s| {
+            s.spawn(|| a.send_message_with(&msg, &[], options).unwrap());
+            let mut header = BytesMut::new();
+            b.recv_exact(&mut header.reserve_and_limit(HEADER_SIZE), &mut Vec::new())
+                .unwrap();
+            let (size, compressed) = decode_header(&header);
+            assert!(compressed);
+            b.recv_exact(
+                &mut BytesMut::new().reserve_and_limit(size),
+                &mut Vec::new(),
+            )
+            .unwrap();
+            size
+        });
+        assert!(size < COMPRESS_ABOVE);
+
+        a.send_message_with(&msg, &[], options).unwrap();
+        a.send_message_with(
+            &msg,
+            &[],
+            SendOptions {
+                secret: true,
+                ..options
+            },
+        )
+        .unwrap();
+        // Small messages are sent as they are.
+        a.send_message_with(&SomeMessage { value: 42 }, &[], options)
+            .unwrap();
+
+        let r: LargeMessage = b.recv_message(&mut Vec::new()).unwrap();
+        assert_eq!(msg, r);
+        let r: LargeMessage = b.recv_secret_message(&mut Vec::new()).unwrap();
+        assert_eq!(msg, r);
+        let r: SomeMessage = b.recv_message(&mut Vec::new()).unwrap();
+        assert_eq!(SomeMessage { value: 42 }, r);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    pub fn recv_message_with_credentials() {
+        let (a, b) = pair(SocketOptions::default()).unwrap();
+        let msg = SomeMessage { value: 42 };
+        assert_eq!(
+            DomainSocket::peer_credentials(&b).unwrap(),
+            Credentials::current()
+        );
+
+        b.pass_credentials().unwrap();
+        a.send_message(&msg, &[]).unwrap();
+        a.send_message(&msg, &[]).unwrap();
+
+        let (r, credentials): (SomeMessage, _) =
+            b.recv_message_with_credentials(&mut Vec::new()).unwrap();
+        assert_eq!(msg, r);
+        assert_eq!(credentials, Credentials::current());
+        let r: SomeMessage = b.recv_message(&mut Vec::new()).unwrap();
+        assert_eq!(msg, r);
+
+        drop(a);
+        assert!(b
+            .recv_message_with_credentials::<SomeMessage>(&mut Vec::new())
+            .is_err());
+    }
+
+    #[test]
+    pub fn recv_closed() {
+        let (a, b) = pair(SocketOptions::default()).unwrap();
+        drop(a);
+        let error = b.recv_message::<SomeMessage>(&mut Vec::new()).unwrap_err();
+        assert!(
+            matches!(&error, SocketMessageError::IO(v) if v.kind() == std::io::ErrorKind::UnexpectedEof),
+            "{error:?}"
+        );
+    }
+
     #[test]
     pub fn send_recv_message_fds() {
-        let (c, d) = UnixStream::pair().unwrap();
-        let (a, b) = UnixStream::pair().unwrap();
+        let (c, d) = pair(SocketOptions::default()).unwrap();
+        let (a, b) = pair(SocketOptions::default()).unwrap();
 
         let msg = SomeMessage { value: 42 };
 
@@ -274,8 +795,8 @@ mod test {
 
     #[test]
     pub fn send_recv_message_fds_many() {
-        let (c, d) = UnixStream::pair().unwrap();
-        let (a, b) = UnixStream::pair().unwrap();
+        let (c, d) = pair(SocketOptions::default()).unwrap();
+        let (a, b) = pair(SocketOptions::default()).unwrap();
 
         let msg = SomeMessage { value: 42 };
 
@@ -300,16 +821,58 @@ mod test {
         assert_eq!(msg, r);
     }
 
-    fn make_async(s: UnixStream) -> UnixStreamAsync {
-        s.set_nonblocking(true).expect("set nonblocking");
-        UnixStreamAsync::from_std(s).expect("to tokio unix stream")
+    #[tokio::test]
+    pub async fn keepalive_ping() {
+        let (a, b) = pair_async(SocketOptions::default()).unwrap();
+        let keepalive = Keepalive {
+            timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+
+        let echo = async {
+            let mut request = [0u8; 1];
+            DomainSocketAsync::recv_exact(&b, &mut &mut request[..], &mut Vec::new())
+                .await
+                .unwrap();
+            DomainSocketAsync::send_all(&b, &mut &request[..], &[])
+                .await
+                .unwrap();
+        };
+        let (result, _) = tokio::join!(keepalive.ping(&a, 7), echo);
+        result.unwrap();
+
+        // The peer is still connected, but doesn't respond.
+        assert!(matches!(
+            keepalive.ping(&a, 7).await,
+            Err(SocketMessageError::Timeout(timeout)) if timeout == keepalive.timeout
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    pub async fn async_recv_message_with_credentials() {
+        let (a, b) = pair_async(SocketOptions::default()).unwrap();
+        let msg = SomeMessage { value: 42 };
+        assert_eq!(
+            DomainSocketAsync::peer_credentials(&b).unwrap(),
+            Credentials::current()
+        );
+        DomainSocketAsync::pass_credentials(&b).unwrap();
+
+        let mut fds = Vec::new();
+        let (sent, received) = tokio::join!(
+            a.send_message(&msg, &[]),
+            b.recv_message_with_credentials::<SomeMessage>(&mut fds)
+        );
+        sent.unwrap();
+        let (r, credentials) = received.unwrap();
+        assert_eq!(msg, r);
+        assert_eq!(credentials, Credentials::current());
     }
 
     #[tokio::test]
     pub async fn async_send_recv_message() {
-        let (a, b) = UnixStream::pair().unwrap();
-        let a = make_async(a);
-        let b = make_async(b);
+        let (a, b) = pair_async(SocketOptions::default()).unwrap();
         let msg = SomeMessage { value: 42 };
 
         a.send_message(&msg, &[]).await.unwrap();
@@ -323,12 +886,8 @@ mod test {
 
     #[tokio::test]
     pub async fn async_send_recv_message_fds() {
-        let (c, d) = UnixStream::pair().unwrap();
-        let (a, b) = UnixStream::pair().unwrap();
-        let a = make_async(a);
-        let b = make_async(b);
-        let c = make_async(c);
-        let d = make_async(d);
+        let (c, d) = pair_async(SocketOptions::default()).unwrap();
+        let (a, b) = pair_async(SocketOptions::default()).unwrap();
 
         let msg = SomeMessage { value: 42 };
 
@@ -342,7 +901,7 @@ mod test {
 
         let c = fds.into_iter().next().unwrap();
         let c: UnixStream = c.into();
-        let c = make_async(c);
+        let c = into_async(c).unwrap();
 
         c.send_message(&msg, &[]).await.unwrap();
 
@@ -354,12 +913,8 @@ mod test {
 
     #[tokio::test]
     pub async fn async_send_recv_message_fds_many() {
-        let (c, d) = UnixStream::pair().unwrap();
-        let (a, b) = UnixStream::pair().unwrap();
-        let a = make_async(a);
-        let b = make_async(b);
-        let c = make_async(c);
-        let d = make_async(d);
+        let (c, d) = pair_async(SocketOptions::default()).unwrap();
+        let (a, b) = pair_async(SocketOptions::default()).unwrap();
 
         let msg = SomeMessage { value: 42 };
 
@@ -375,7 +930,7 @@ mod test {
 
         let c = fds.into_iter().next().unwrap();
         let c: UnixStream = c.into();
-        let c = make_async(c);
+        let c = into_async(c).unwrap();
 
         c.send_message(&msg, &[]).await.unwrap();
 
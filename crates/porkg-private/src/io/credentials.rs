@@ -0,0 +1,124 @@
+//! The credentials of the process on the other side of a socket, which the kernel reports either for the process that
+//! connected it (`SO_PEERCRED`) or for the process that sent each message (`SCM_CREDENTIALS`).
+//!
+//! Only Linux reports credentials this way, so elsewhere asking for them fails with [`std::io::ErrorKind::Unsupported`].
+
+use std::os::fd::{AsFd, OwnedFd};
+#[cfg(target_os = "linux")]
+use std::{
+    io::IoSliceMut,
+    os::fd::{AsRawFd as _, FromRawFd as _, RawFd},
+};
+
+#[cfg(target_os = "linux")]
+use nix::sys::socket::{
+    getsockopt, recvmsg, setsockopt, sockopt, ControlMessageOwned, MsgFlags, UnixCredentials,
+};
+use nix::unistd::{Gid, Pid, Uid};
+
+#[cfg(target_os = "linux")]
+use super::FD_BUFFER_SIZE;
+
+/// The identity of a process, as the kernel reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Credentials {
+    pub pid: Pid,
+    pub uid: Uid,
+    pub gid: Gid,
+}
+
+#[cfg(target_os = "linux")]
+impl From<UnixCredentials> for Credentials {
+    fn from(value: UnixCredentials) -> Self {
+        Self {
+            pid: Pid::from_raw(value.pid()),
+            uid: Uid::from_raw(value.uid()),
+            gid: Gid::from_raw(value.gid()),
+        }
+    }
+}
+
+impl Credentials {
+    /// The credentials of the current process.
+    pub fn current() -> Self {
+        Self {
+            pid: nix::unistd::getpid(),
+            uid: nix::unistd::getuid(),
+            gid: nix::unistd::getgid(),
+        }
+    }
+}
+
+/// Gets the credentials of the process that connected the socket, or created the pair.
+#[cfg(target_os = "linux")]
+pub(super) fn peer(socket: &impl AsFd) -> std::io::Result<Credentials> {
+    Ok(getsockopt(socket, sockopt::PeerCredentials)?.into())
+}
+
+/// Asks the kernel to attach the credentials of the sender to the data that is received.
+#[cfg(target_os = "linux")]
+pub(super) fn enable(socket: &impl AsFd) -> std::io::Result<()> {
+    Ok(setsockopt(socket, sockopt::PassCred, &true)?)
+}
+
+/// Receives into `buffer` once, returning the number of bytes that were received and the credentials of their sender.
+/// Credentials are only attached to data that was sent after they were enabled with [`enable`].
+#[cfg(target_os = "linux")]
+pub(super) fn recv(
+    socket: &impl AsFd,
+    buffer: &mut [u8],
+    fds: &mut impl Extend<OwnedFd>,
+) -> std::io::Result<(usize, Option<Credentials>)> {
+    let mut control = nix::cmsg_space!([RawFd; FD_BUFFER_SIZE], UnixCredentials);
+    let mut iov = [IoSliceMut::new(buffer)];
+    let message = recvmsg::<()>(
+        socket.as_fd().as_raw_fd(),
+        &mut iov,
+        Some(&mut control),
+        MsgFlags::MSG_CMSG_CLOEXEC,
+    )?;
+
+    let mut credentials = None;
+    for item in message.cmsgs()? {
+        match item {
+            ControlMessageOwned::ScmRights(received) => fds.extend(
+                received
+                    .into_iter()
+                    .map(|v| unsafe { OwnedFd::from_raw_fd(v) }),
+            ),
+            // Data that was sent before credentials were enabled has none.
+            ControlMessageOwned::ScmCredentials(value) if value.pid() != 0 => {
+                credentials = Some(value.into())
+            }
+            _ => {}
+        }
+    }
+    Ok((message.bytes, credentials))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "the credentials of peers are only reported on Linux",
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn peer(_socket: &impl AsFd) -> std::io::Result<Credentials> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn enable(_socket: &impl AsFd) -> std::io::Result<()> {
+    Err(unsupported())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(super) fn recv(
+    _socket: &impl AsFd,
+    _buffer: &mut [u8],
+    _fds: &mut impl Extend<OwnedFd>,
+) -> std::io::Result<(usize, Option<Credentials>)> {
+    Err(unsupported())
+}
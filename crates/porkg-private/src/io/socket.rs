@@ -0,0 +1,263 @@
+//! Constructors for the unix sockets that processes use to talk to each other, so that their flags are set in one
+//! place rather than after the fact by each caller.
+
+use std::os::{
+    fd::{AsFd, OwnedFd},
+    unix::net::{UnixListener, UnixStream},
+};
+#[cfg(target_os = "linux")]
+use std::os::{linux::net::SocketAddrExt as _, unix::net::SocketAddr};
+
+use nix::sys::socket::{setsockopt, socketpair, sockopt, AddressFamily, SockFlag, SockType};
+
+/// How the sockets that are created here are configured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// Closes the sockets in programs that the process executes. Sockets are handed to other processes as fds
+    /// instead, which keeps them open.
+    pub cloexec: bool,
+    pub nonblocking: bool,
+    /// The size of the send and receive buffers (`SO_SNDBUF` and `SO_RCVBUF`), which the kernel doubles and limits.
+    pub buffer_size: Option<usize>,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        Self {
+            cloexec: true,
+            nonblocking: false,
+            buffer_size: None,
+        }
+    }
+}
+
+impl SocketOptions {
+    fn apply(&self, socket: &impl AsFd) -> std::io::Result<()> {
+        if let Some(size) = self.buffer_size {
+            setsockopt(socket, sockopt::SndBuf, &size)?;
+            setsockopt(socket, sockopt::RcvBuf, &size)?;
+        }
+        Ok(())
+    }
+}
+
+/// Creates a pair of connected sockets.
+#[cfg(target_os = "linux")]
+pub fn pair(options: SocketOptions) -> std::io::Result<(UnixStream, UnixStream)> {
+    let mut flags = SockFlag::empty();
+    flags.set(SockFlag::SOCK_CLOEXEC, options.cloexec);
+    flags.set(SockFlag::SOCK_NONBLOCK, options.nonblocking);
+    let (a, b): (OwnedFd, OwnedFd) =
+        socketpair(AddressFamily::Unix, SockType::Stream, None, flags)?;
+    options.apply(&a)?;
+    options.apply(&b)?;
+    Ok((a.into(), b.into()))
+}
+
+/// Creates a pair of connected sockets. Other platforms can't create them with their flags, so there is a moment in
+/// which a program that another thread executes may inherit them.
+#[cfg(not(target_os = "linux"))]
+pub fn pair(options: SocketOptions) -> std::io::Result<(UnixStream, UnixStream)> {
+    use std::os::fd::AsRawFd as _;
+
+    use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+
+    let (a, b): (OwnedFd, OwnedFd) = socketpair(
+        AddressFamily::Unix,
+        SockType::Stream,
+        None,
+        SockFlag::empty(),
+    )?;
+    for socket in [&a, &b] {
+        if options.cloexec {
+            fcntl(socket.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        }
+        options.apply(socket)?;
+    }
+    let (a, b) = (UnixStream::from(a), UnixStream::from(b));
+    a.set_nonblocking(options.nonblocking)?;
+    b.set_nonblocking(options.nonblocking)?;
+    Ok((a, b))
+}
+
+/// Creates a pair of connected sockets for the current tokio runtime.
+pub fn pair_async(
+    options: SocketOptions,
+) -> std::io::Result<(tokio::net::UnixStream, tokio::net::UnixStream)> {
+    let (a, b) = pair(SocketOptions {
+        nonblocking: true,
+        ..options
+    })?;
+    Ok((into_async(a)?, into_async(b)?))
+}
+
+/// Moves a socket to the current tokio runtime, such as one side of a [`pair`] after the other was handed to another
+/// process.
+pub fn into_async(socket: UnixStream) -> std::io::Result<tokio::net::UnixStream> {
+    socket.set_nonblocking(true)?;
+    tokio::net::UnixStream::from_std(socket)
+}
+
+/// Listens on a socket in the abstract namespace, which has no file and goes away with the listener. The namespace
+/// belongs to the network namespace, so it can't be reached from sandboxes that have their own.
+#[cfg(target_os = "linux")]
+pub fn bind_abstract(name: &[u8], options: SocketOptions) -> std::io::Result<UnixListener> {
+    let listener = UnixListener::bind_addr(&SocketAddr::from_abstract_name(name)?)?;
+    listener.set_nonblocking(options.nonblocking)?;
+    options.apply(&listener)?;
+    Ok(listener)
+}
+
+/// Connects to a socket in the abstract namespace (see [`bind_abstract`]).
+#[cfg(target_os = "linux")]
+pub fn connect_abstract(name: &[u8], options: SocketOptions) -> std::io::Result<UnixStream> {
+    let stream = UnixStream::connect_addr(&SocketAddr::from_abstract_name(name)?)?;
+    stream.set_nonblocking(options.nonblocking)?;
+    options.apply(&stream)?;
+    Ok(stream)
+}
+
+/// Gets the supplementary groups of the process that connected to `socket`, as of when it connected (see
+/// `SO_PEERGROUPS` in `unix(7)`).
+#[cfg(target_os = "linux")]
+pub fn peer_groups(socket: &impl AsFd) -> std::io::Result<Vec<u32>> {
+    use std::os::fd::AsRawFd as _;
+
+    use nix::libc;
+
+    const SIZE: usize = std::mem::size_of::<libc::gid_t>();
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut len = (groups.len() * SIZE) as libc::socklen_t;
+        // Safety: the kernel writes at most `len` bytes, which is the size of `groups`.
+        let result = unsafe {
+            libc::getsockopt(
+                socket.as_fd().as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERGROUPS,
+                groups.as_mut_ptr().cast(),
+                &mut len,
+            )
+        };
+        let count = len as usize / SIZE;
+        if result == 0 {
+            groups.truncate(count);
+            return Ok(groups);
+        }
+        // When the groups don't fit, `len` is set to the size that they need.
+        let error = std::io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::ERANGE) || count <= groups.len() {
+            return Err(error);
+        }
+        groups.resize(count, 0);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn peer_groups(_socket: &impl AsFd) -> std::io::Result<Vec<u32>> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "the groups of peers are only available on Linux",
+    ))
+}
+
+/// Only Linux has the abstract namespace.
+#[cfg(not(target_os = "linux"))]
+fn no_abstract_namespace() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "abstract unix sockets are only available on Linux",
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn bind_abstract(_name: &[u8], _options: SocketOptions) -> std::io::Result<UnixListener> {
+    Err(no_abstract_namespace())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn connect_abstract(_name: &[u8], _options: SocketOptions) -> std::io::Result<UnixStream> {
+    Err(no_abstract_namespace())
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use std::{
+        io::{ErrorKind, Read as _, Write as _},
+        os::fd::AsRawFd as _,
+    };
+
+    use super::*;
+
+    /// Reads the flags that the kernel reports for an fd of this process.
+    fn fd_flags(socket: &impl AsFd) -> u32 {
+        let info =
+            std::fs::read_to_string(format!("/proc/self/fdinfo/{}", socket.as_fd().as_raw_fd()))
+                .unwrap();
+        let flags = info.lines().find_map(|v| v.strip_prefix("flags:")).unwrap();
+        u32::from_str_radix(flags.trim(), 8).unwrap()
+    }
+
+    #[test]
+    fn pair_options() {
+        let (a, b) = pair(SocketOptions::default()).unwrap();
+        assert_ne!(fd_flags(&a) & nix::libc::O_CLOEXEC as u32, 0);
+        assert_eq!(fd_flags(&b) & nix::libc::O_NONBLOCK as u32, 0);
+
+        let (mut a, mut b) = pair(SocketOptions {
+            cloexec: false,
+            nonblocking: true,
+            buffer_size: Some(64 * 1024),
+        })
+        .unwrap();
+        assert_eq!(fd_flags(&a) & nix::libc::O_CLOEXEC as u32, 0);
+        assert_eq!(
+            b.read(&mut [0u8; 1]).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        assert!(nix::sys::socket::getsockopt(&a, sockopt::SndBuf).unwrap() >= 64 * 1024);
+
+        a.write_all(b"data").unwrap();
+        let mut buffer = [0u8; 4];
+        b.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"data");
+    }
+
+    #[test]
+    fn abstract_sockets() {
+        let name = format!("porkg-test-{}", std::process::id());
+        let listener = bind_abstract(name.as_bytes(), SocketOptions::default()).unwrap();
+        let mut client = connect_abstract(name.as_bytes(), SocketOptions::default()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        client.write_all(b"data").unwrap();
+        let mut buffer = [0u8; 4];
+        server.read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"data");
+
+        drop((listener, server));
+        assert!(connect_abstract(name.as_bytes(), SocketOptions::default()).is_err());
+    }
+
+    #[test]
+    fn groups_of_peers() {
+        let (a, _b) = pair(SocketOptions::default()).unwrap();
+        let groups: Vec<_> = nix::unistd::getgroups()
+            .unwrap()
+            .into_iter()
+            .map(|v| v.as_raw())
+            .collect();
+        assert_eq!(peer_groups(&a).unwrap(), groups);
+    }
+
+    #[tokio::test]
+    async fn async_pair() {
+        use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+        let (mut a, mut b) = pair_async(SocketOptions::default()).unwrap();
+        a.write_all(b"data").await.unwrap();
+        let mut buffer = [0u8; 4];
+        b.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"data");
+    }
+}
@@ -0,0 +1,285 @@
+//! An append-only record log that survives crashes, such as the journal of the job queue.
+//!
+//! The file starts with [`MAGIC`], followed by records that each consist of the length of the payload (a little-endian
+//! `u32`), a checksum of the length and payload (the first 8 bytes of their blake3 hash) and the payload. Every append
+//! is synced before it returns.
+//!
+//! A crash can only leave the last record partially written. When the journal is opened, records are read up to the
+//! first one that is incomplete or fails its checksum, and the file is truncated there, so later appends follow the
+//! last intact record. Corruption in the middle of the file discards everything after it, as the records that follow
+//! can't be applied without the one before them.
+//!
+//! [`Journal::compact`] replaces the records with a snapshot of the state that they describe. The snapshot is written
+//! to a temporary file that is renamed over the journal, so a crash leaves either the old or the new journal.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, Read, Seek as _, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+/// Identifies journal files, and their format version.
+pub const MAGIC: &[u8; 8] = b"PORKGJ01";
+
+/// The largest record that is accepted, so that a corrupt length can't cause a huge allocation.
+pub const MAX_RECORD_SIZE: usize = 64 * 1024 * 1024;
+
+const LENGTH_SIZE: usize = 4;
+const CHECKSUM_SIZE: usize = 8;
+const HEADER_SIZE: usize = LENGTH_SIZE + CHECKSUM_SIZE;
+
+fn checksum(length: &[u8; LENGTH_SIZE], payload: &[u8]) -> [u8; CHECKSUM_SIZE] {
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(length);
+    hasher.update(payload);
+    let mut result = [0; CHECKSUM_SIZE];
+    result.copy_from_slice(&hasher.finalize().as_bytes()[..CHECKSUM_SIZE]);
+    result
+}
+
+fn encode(payload: &[u8], buffer: &mut Vec<u8>) -> io::Result<()> {
+    if payload.len() > MAX_RECORD_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the record is too large for the journal",
+        ));
+    }
+    let length = (payload.len() as u32).to_le_bytes();
+    buffer.extend_from_slice(&length);
+    buffer.extend_from_slice(&checksum(&length, payload));
+    buffer.extend_from_slice(payload);
+    Ok(())
+}
+
+/// Reads as many bytes as are available, up to the size of `buf`.
+fn read_up_to(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match reader.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(size) => total += size,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => {}
+            Err(error) => return Err(error),
+        }
+    }
+    Ok(total)
+}
+
+/// Reads the intact records of a journal, returning them and the offset at which they end.
+fn replay(reader: &mut impl Read) -> io::Result<(Vec<Vec<u8>>, u64)> {
+    let mut magic = [0; MAGIC.len()];
+    if read_up_to(reader, &mut magic)? < MAGIC.len() || &magic != MAGIC {
+        return Ok((Vec::new(), 0));
+    }
+
+    let mut records = Vec::new();
+    let mut end = MAGIC.len() as u64;
+    loop {
+        let mut header = [0; HEADER_SIZE];
+        if read_up_to(reader, &mut header)? < HEADER_SIZE {
+            break;
+        }
+        let length: [u8; LENGTH_SIZE] = header[..LENGTH_SIZE].try_into().unwrap();
+        let size = u32::from_le_bytes(length) as usize;
+        if size > MAX_RECORD_SIZE {
+            break;
+        }
+        let mut payload = vec![0; size];
+        if read_up_to(reader, &mut payload)? < size
+            || header[LENGTH_SIZE..] != checksum(&length, &payload)
+        {
+            break;
+        }
+        records.push(payload);
+        end += (HEADER_SIZE + size) as u64;
+    }
+    Ok((records, end))
+}
+
+fn sync_parent(path: &Path) -> io::Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => File::open(parent)?.sync_all(),
+        _ => File::open(".")?.sync_all(),
+    }
+}
+
+/// An open journal, which records are appended to.
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    len: usize,
+}
+
+impl Journal {
+    fn compact_path(path: &Path) -> PathBuf {
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(".compact");
+        path.with_file_name(name)
+    }
+
+    /// Opens or creates the journal at `path`, returning the records that it contains.
+    ///
+    /// A partially written record at the end, or anything after a corrupt record, is discarded.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<(Self, Vec<Vec<u8>>)> {
+        let path = path.into();
+        // A compaction that didn't finish left the journal as it was.
+        match fs::remove_file(Self::compact_path(&path)) {
+            Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+            _ => {}
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let (records, end) = replay(&mut BufReader::new(&mut file))?;
+        let size = file.metadata()?.len();
+
+        if end == 0 {
+            if size != 0 {
+                tracing::warn!(?path, size, "discarding a journal without a valid header");
+            }
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(MAGIC)?;
+            file.sync_all()?;
+            sync_parent(&path)?;
+        } else if end < size {
+            tracing::warn!(
+                ?path,
+                discarded = size - end,
+                "discarding a torn or corrupt journal tail"
+            );
+            file.set_len(end)?;
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::End(0))?;
+
+        let len = records.len();
+        Ok((Self { path, file, len }, records))
+    }
+
+    /// Gets the number of records in the journal, which callers can compare to the size of their state to decide when
+    /// to compact.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Appends a record, returning once it is durable.
+    pub fn append(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut buffer = Vec::with_capacity(HEADER_SIZE + payload.len());
+        encode(payload, &mut buffer)?;
+        self.file.write_all(&buffer)?;
+        self.file.sync_data()?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Replaces the records of the journal with `records`, which usually describe the current state in full.
+    pub fn compact<'a>(&mut self, records: impl IntoIterator<Item = &'a [u8]>) -> io::Result<()> {
+        let temp = Self::compact_path(&self.path);
+        let mut buffer = MAGIC.to_vec();
+        let mut len = 0;
+        for record in records {
+            encode(record, &mut buffer)?;
+            len += 1;
+        }
+
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp)?;
+        file.write_all(&buffer)?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.path)?;
+        sync_parent(&self.path)?;
+
+        self.file = file;
+        self.len = len;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn open(path: &Path) -> (Journal, Vec<Vec<u8>>) {
+        Journal::open(path).unwrap()
+    }
+
+    #[test]
+    fn replay_torn_writes() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("torn");
+        let (mut journal, records) = open(&path);
+        assert!(records.is_empty());
+        for record in [&b"first"[..], b"", b"third record"] {
+            journal.append(record).unwrap();
+        }
+        drop(journal);
+        let full = fs::read(&path).unwrap();
+        let last = full.len() - (HEADER_SIZE + b"third record".len());
+
+        // Every way of tearing the last record leaves the first two, and the journal can be appended to again.
+        for end in last..full.len() {
+            fs::write(&path, &full[..end]).unwrap();
+            let (mut journal, records) = open(&path);
+            assert_eq!(records, [&b"first"[..], b""], "torn at {end}");
+            assert_eq!(fs::metadata(&path).unwrap().len(), last as u64);
+            journal.append(b"again").unwrap();
+            drop(journal);
+            assert_eq!(open(&path).1, [&b"first"[..], b"", b"again"]);
+        }
+
+        // A corrupt record discards itself and everything after it.
+        let mut corrupt = full.clone();
+        corrupt[MAGIC.len() + HEADER_SIZE] ^= 1;
+        fs::write(&path, &corrupt).unwrap();
+        let (journal, records) = open(&path);
+        assert!(records.is_empty());
+        assert!(journal.is_empty());
+
+        // So does a length that is impossibly large.
+        let mut corrupt = full.clone();
+        corrupt[last..last + LENGTH_SIZE].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, &corrupt).unwrap();
+        assert_eq!(open(&path).0.len(), 2);
+
+        // A torn header starts over.
+        fs::write(&path, &MAGIC[..3]).unwrap();
+        let (mut journal, records) = open(&path);
+        assert!(records.is_empty());
+        journal.append(b"new").unwrap();
+        assert_eq!(open(&path).1, [b"new"]);
+    }
+
+    #[test]
+    fn compact() {
+        let temp = tempfile::tempdir().unwrap();
+        let path = temp.path().join("compact");
+        let (mut journal, _) = open(&path);
+        for i in 0..10u8 {
+            journal.append(&[i]).unwrap();
+        }
+        journal.compact([&b"snapshot"[..]]).unwrap();
+        assert_eq!(journal.len(), 1);
+        journal.append(b"after").unwrap();
+        drop(journal);
+        assert_eq!(open(&path).1, [&b"snapshot"[..], b"after"]);
+
+        // A compaction that was interrupted before the rename is ignored.
+        fs::write(Journal::compact_path(&path), b"partial").unwrap();
+        assert_eq!(open(&path).1, [&b"snapshot"[..], b"after"]);
+        assert!(!Journal::compact_path(&path).exists());
+    }
+}
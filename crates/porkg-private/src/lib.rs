@@ -1,7 +1,12 @@
+pub mod backoff;
+pub mod crash;
 pub mod debug;
+pub mod fanout;
 pub mod future;
 pub mod io;
+pub mod journal;
 pub mod mem;
+#[cfg(target_os = "linux")]
 pub mod os;
 pub mod sandbox;
 pub mod ser;
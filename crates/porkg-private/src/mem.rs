@@ -4,49 +4,118 @@
 //! returned than the pool can contain, they will be dropped immediately.
 //!
 //! There are no ordering guarantees.
+//!
+//! The concurrency of the pool is tested with loom, which replaces its atomics with ones that it can model:
+//!
+//! ```text
+//! cargo test --release -p porkg-private --features loom mem::loom_test
+//! ```
 
+#[cfg(not(feature = "loom"))]
 use std::sync::atomic::{AtomicUsize, Ordering};
 
+#[cfg(not(feature = "loom"))]
+mod local;
 mod owned_pooled_item;
 mod pooled_item;
 use bytes::BytesMut;
 use flume::TrySendError;
-use nix::unistd::gettid;
+#[cfg(feature = "loom")]
+use loom::sync::atomic::{AtomicUsize, Ordering};
 use once_cell::sync::{Lazy, OnceCell};
 pub use owned_pooled_item::OwnedPooled;
 pub use pooled_item::Pooled;
+use zeroize::Zeroize as _;
 
+#[cfg(not(feature = "loom"))]
 const MB: usize = 131072;
+#[cfg(not(feature = "loom"))]
 const MAX_TOTAL_BUFFERS: usize = 128 * MB;
+#[cfg(not(feature = "loom"))]
 const MAX_SINGLE_BUFFER: usize = 16 * MB;
 const DEFAULT_BUFFER_LEN: usize = 16384;
 
-static CURRENT_SIZE: AtomicUsize = AtomicUsize::new(0);
-static BUFFER_POOL: Pool<'static, BytesMut> = PoolBuilder::<BytesMut>::new(16)
-    .with_max_search(8)
-    .with_take_hook(&|v| {
-        CURRENT_SIZE.fetch_sub(v.capacity(), std::sync::atomic::Ordering::Release);
-        v
-    })
-    .with_return_hook(&|mut v| {
-        let capacity = v.capacity();
-        if capacity > MAX_SINGLE_BUFFER
-            || CURRENT_SIZE.load(std::sync::atomic::Ordering::Acquire) + capacity
-                > MAX_TOTAL_BUFFERS
+/// The memory that pooled buffers hold, which is limited softly: buffers that are returned at the same time can exceed
+/// it.
+#[cfg(any(not(feature = "loom"), test))]
+struct Budget {
+    current: AtomicUsize,
+    max_total: usize,
+    max_single: usize,
+}
+
+#[cfg(any(not(feature = "loom"), test))]
+impl Budget {
+    fn take(&self, value: BytesMut) -> BytesMut {
+        self.current.fetch_sub(value.capacity(), Ordering::Release);
+        value
+    }
+
+    fn give(&self, mut value: BytesMut) -> Option<BytesMut> {
+        let capacity = value.capacity();
+        if capacity > self.max_single
+            || self.current.load(Ordering::Acquire) + capacity > self.max_total
         {
             None
         } else {
-            // It's a soft limit.
-            CURRENT_SIZE.fetch_add(capacity, std::sync::atomic::Ordering::Release);
-            v.clear();
-            Some(v)
+            self.current.fetch_add(capacity, Ordering::Release);
+            value.clear();
+            Some(value)
         }
-    })
+    }
+}
+
+#[cfg(not(feature = "loom"))]
+static BUFFER_BUDGET: Budget = Budget {
+    current: AtomicUsize::new(0),
+    max_total: MAX_TOTAL_BUFFERS,
+    max_single: MAX_SINGLE_BUFFER,
+};
+#[cfg(not(feature = "loom"))]
+static BUFFER_POOL: Pool<'static, BytesMut> = PoolBuilder::<BytesMut>::new(16)
+    .with_max_search(8)
+    .with_take_hook(&|v| BUFFER_BUDGET.take(v))
+    .with_return_hook(&|v| BUFFER_BUDGET.give(v))
     .build(|| BytesMut::with_capacity(DEFAULT_BUFFER_LEN));
 
 /// Gets a pooled memory buffer.
+#[cfg(not(feature = "loom"))]
+pub fn get_buffer() -> Pooled<'static, BytesMut> {
+    local::take()
+}
+
+/// Gets a pooled memory buffer for data that must not outlive its use, such as secrets. The buffer is zeroed before it
+/// is reused, including its spare capacity. Data is only kept out of memory that is reused if the buffer is never
+/// grown while it holds the data, as growing it leaves the previous allocation to the allocator as it is.
+pub fn get_secret_buffer() -> Pooled<'static, BytesMut> {
+    Pooled::new(get_buffer().forget(), &ZEROIZING_POOL)
+}
+
+/// Zeroes buffers before they are returned to the buffer pool.
+struct ZeroizingPool;
+
+const ZEROIZING_POOL: ZeroizingPool = ZeroizingPool;
+
+impl crate::sealed::Sealed for ZeroizingPool {}
+
+impl PoolReturn<BytesMut> for ZeroizingPool {
+    fn return_value(&self, mut value: BytesMut) {
+        zeroize(&mut value);
+        #[cfg(not(feature = "loom"))]
+        local::return_buffer(value);
+    }
+}
+
+/// Clears a buffer and overwrites all of its memory with zeros.
+fn zeroize(buffer: &mut BytesMut) {
+    buffer.clear();
+    buffer.spare_capacity_mut().zeroize();
+}
+
+/// Gets a memory buffer that isn't pooled, as the atomics of pools only work within loom models.
+#[cfg(feature = "loom")]
 pub fn get_buffer() -> Pooled<'static, BytesMut> {
-    BUFFER_POOL.take()
+    Pooled::new(BytesMut::with_capacity(DEFAULT_BUFFER_LEN), &NULL_POOL)
 }
 
 /// The return portion of a pool.
@@ -103,7 +172,8 @@ impl<'a, T: Send> PoolBuilder<'a, T> {
 
     /// Sets a hook that can mutate values when they are taken from the pool.
     ///
-    /// This hook will not run when a value is created, only when an value is found in the pool and is returned.
+    /// This hook will not run when a value is created, only when an value is found in the pool and is returned. It also
+    /// runs on a value that the return hook accepted but that the pool had no room for, before it is dropped.
     pub const fn with_take_hook(mut self, take_hook: &'a (impl Sync + Fn(T) -> T)) -> Self {
         self.take_hook = Some(take_hook);
         self
@@ -134,6 +204,20 @@ struct PoolState<'a, T: Send, F: Sync + Send + Fn() -> T> {
     create: &'a F,
 }
 
+/// The fractional part of the golden ratio, which spreads consecutive numbers over the bits of a `usize`.
+const GOLDEN_RATIO: usize = 0x9e37_79b9_7f4a_7c15_u64 as usize;
+
+/// Numbers the threads that use pools. It isn't modelled by loom, as it only affects where pools start searching.
+static NEXT_THREAD: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(1);
+
+thread_local! {
+    /// What each thread advances the search position of pools by, which differs between threads so that they spread
+    /// across the buckets.
+    static THREAD_SEED: usize = NEXT_THREAD
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+        .wrapping_mul(GOLDEN_RATIO);
+}
+
 static DEFAULT_BUCKETS: Lazy<usize> = Lazy::new(|| {
     if let Some(result) = std::env::var("PORKG_MEM_BUCKETS")
         .ok()
@@ -157,7 +241,8 @@ impl<'a, T: Send, F: Sync + Send + Fn() -> T> PoolState<'a, T, F> {
         if buckets == 0 {
             buckets = *DEFAULT_BUCKETS;
         }
-        buckets = buckets.max(config.capacity);
+        // Every bucket holds at least one value.
+        buckets = buckets.min(config.capacity);
 
         let mut entries = Vec::with_capacity(buckets);
         let per_bucket = config.capacity / buckets;
@@ -178,7 +263,7 @@ impl<'a, T: Send, F: Sync + Send + Fn() -> T> PoolState<'a, T, F> {
     }
 
     fn next_id(&self) -> usize {
-        let id = gettid().as_raw() as usize;
+        let id = THREAD_SEED.with(|v| *v);
         self.skip.fetch_add(id, Ordering::Relaxed)
     }
 
@@ -222,11 +307,15 @@ impl<'a, T: Send, F: Sync + Send + Fn() -> T> PoolReturn<T> for PoolState<'a, T,
         for i in 0..=self.config.max_loop {
             let i = i.wrapping_add(id).wrapping_rem(self.entries.len());
             match self.entries[i].sender.try_send(value) {
-                Ok(_) => break,
-                Err(TrySendError::Disconnected(_)) => break,
-                Err(TrySendError::Full(e)) => value = e,
+                Ok(_) => return,
+                Err(TrySendError::Disconnected(e)) | Err(TrySendError::Full(e)) => value = e,
             }
         }
+
+        // The return hook accepted the value, so it leaves the pool like a value that was taken.
+        if let Some(hook) = self.config.take_hook {
+            hook(value);
+        }
     }
 }
 
@@ -270,7 +359,7 @@ impl<T> PoolReturn<T> for NullPool {
     fn return_value(&self, _: T) {}
 }
 
-#[cfg(test)]
+#[cfg(all(test, not(feature = "loom")))]
 mod test {
     use std::{
         hint::black_box,
@@ -281,6 +370,7 @@ mod test {
         },
     };
 
+    use bytes::BytesMut;
     use once_cell::sync::Lazy;
 
     use crate::mem::Pooled;
@@ -393,4 +483,95 @@ mod test {
             assert!(*pool.take().as_ref() > 1);
         }
     }
+
+    #[test]
+    pub fn zeroize_secret_buffers() {
+        let mut buffer = BytesMut::with_capacity(16);
+        buffer.extend_from_slice(b"token");
+        super::zeroize(&mut buffer);
+        assert!(buffer.is_empty());
+        // SAFETY: The spare capacity was zeroed, so it is initialized.
+        unsafe { buffer.set_len(buffer.capacity()) };
+        assert!(buffer.iter().all(|v| *v == 0));
+    }
+}
+
+#[cfg(all(test, feature = "loom"))]
+mod loom_test {
+    use std::collections::BTreeSet;
+
+    use bytes::BytesMut;
+    use loom::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::{Budget, Pool, PoolBuilder};
+
+    const LEN: usize = 16;
+
+    loom::lazy_static! {
+        static ref CREATED: AtomicUsize = AtomicUsize::new(0);
+        // More buckets than values, which are clamped to one value each.
+        static ref POOL: Pool<'static, usize> = PoolBuilder::<usize>::new(2)
+            .with_buckets(4)
+            .build(|| CREATED.fetch_add(1, Ordering::Relaxed));
+        static ref BUDGET: Budget = Budget {
+            current: AtomicUsize::new(0),
+            max_total: 2 * LEN,
+            max_single: LEN,
+        };
+        static ref BUFFERS: Pool<'static, BytesMut> = PoolBuilder::<BytesMut>::new(1)
+            .with_take_hook(&|v| BUDGET.take(v))
+            .with_return_hook(&|v| BUDGET.give(v))
+            .build(|| BytesMut::with_capacity(LEN));
+    }
+
+    /// Runs `threads` threads of `f` in a model. The state of the pool is created before, as the cell that holds it
+    /// isn't modelled.
+    fn model<T>(pool: &'static Pool<'static, T>, threads: usize, f: fn())
+    where
+        T: Send + 'static,
+    {
+        drop(pool.take());
+        let handles = (0..threads).map(|_| thread::spawn(f)).collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn take_and_return() {
+        loom::model(|| {
+            model(&POOL, 2, || {
+                let first = POOL.take();
+                drop(POOL.take());
+                drop(first);
+            });
+
+            // The returned values fill the pool, and are taken again without creating more.
+            let created = CREATED.load(Ordering::Relaxed);
+            let values = (0..POOL.capacity())
+                .map(|_| POOL.take().forget())
+                .collect::<BTreeSet<_>>();
+            assert_eq!(values.len(), POOL.capacity());
+            assert!(values.iter().all(|v| *v < created));
+            assert_eq!(CREATED.load(Ordering::Relaxed), created);
+        });
+    }
+
+    #[test]
+    fn account_for_buffers() {
+        loom::model(|| {
+            model(&BUFFERS, 2, || {
+                let mut buffer = BUFFERS.take();
+                buffer.extend_from_slice(b"data");
+            });
+
+            // The pool only has room for one buffer, and the budget only accounts for the buffer that it holds.
+            assert_eq!(BUDGET.current.load(Ordering::Acquire), LEN);
+            assert!(BUFFERS.take().forget().is_empty());
+            assert_eq!(BUDGET.current.load(Ordering::Acquire), 0);
+        });
+    }
 }
@@ -0,0 +1,109 @@
+//! The buffers that each thread keeps in front of the shared buffer pool, so that most buffers are taken and returned
+//! without touching state that other threads use.
+
+use std::cell::RefCell;
+
+use bytes::BytesMut;
+
+use super::{PoolReturn, Pooled, BUFFER_POOL, DEFAULT_BUFFER_LEN};
+
+/// The number of buffers that each thread keeps for itself.
+const LOCAL_BUFFERS: usize = 4;
+/// The largest buffer that a thread keeps for itself. Larger buffers are only kept in the shared pool, which limits
+/// their total size.
+const MAX_LOCAL_BUFFER: usize = 4 * DEFAULT_BUFFER_LEN;
+
+/// The buffers of a thread, which are moved to the shared pool when the thread exits.
+struct LocalBuffers(RefCell<Vec<BytesMut>>);
+
+impl Drop for LocalBuffers {
+    fn drop(&mut self) {
+        for buffer in self.0.get_mut().drain(..) {
+            BUFFER_POOL.state().return_value(buffer);
+        }
+    }
+}
+
+thread_local! {
+    static BUFFERS: LocalBuffers = LocalBuffers(RefCell::new(Vec::with_capacity(LOCAL_BUFFERS)));
+}
+
+/// Returns buffers with [`return_buffer`].
+struct LocalPool;
+
+const LOCAL_POOL: LocalPool = LocalPool;
+
+impl crate::sealed::Sealed for LocalPool {}
+
+impl PoolReturn<BytesMut> for LocalPool {
+    fn return_value(&self, value: BytesMut) {
+        return_buffer(value);
+    }
+}
+
+/// Returns a buffer to the current thread, or to the shared pool if the thread has no room for it.
+pub(super) fn return_buffer(value: BytesMut) {
+    let mut value = Some(value);
+    if value
+        .as_ref()
+        .is_some_and(|v| v.capacity() <= MAX_LOCAL_BUFFER)
+    {
+        // The buffers of the thread are gone while it exits.
+        let _ = BUFFERS.try_with(|v| {
+            let mut buffers = v.0.borrow_mut();
+            if buffers.len() < LOCAL_BUFFERS {
+                buffers.extend(value.take().map(|mut v| {
+                    v.clear();
+                    v
+                }));
+            }
+        });
+    }
+    if let Some(value) = value {
+        BUFFER_POOL.state().return_value(value);
+    }
+}
+
+/// Takes a buffer of the current thread, or from the shared pool if the thread has none.
+pub(super) fn take() -> Pooled<'static, BytesMut> {
+    let buffer = BUFFERS
+        .try_with(|v| v.0.borrow_mut().pop())
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| BUFFER_POOL.state().take());
+    Pooled::new(buffer, &LOCAL_POOL)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reuse_local_buffers() {
+        std::thread::spawn(|| {
+            // Buffers from the shared pool may have grown past what a thread keeps.
+            let mut buffer = BytesMut::with_capacity(DEFAULT_BUFFER_LEN);
+            buffer.extend_from_slice(b"data");
+            let ptr = buffer.as_ptr();
+            return_buffer(buffer);
+
+            // The buffer is kept by the thread, and cleared.
+            let buffer = take();
+            assert_eq!(buffer.as_ptr(), ptr);
+            assert!(buffer.is_empty());
+
+            // Large buffers go to the shared pool.
+            let mut large = take();
+            large.reserve(2 * MAX_LOCAL_BUFFER);
+            drop(large);
+            drop(buffer);
+            BUFFERS.with(|v| {
+                let buffers = v.0.borrow();
+                assert_eq!(buffers.len(), 1);
+                assert_eq!(buffers[0].as_ptr(), ptr);
+            });
+        })
+        .join()
+        .unwrap();
+    }
+}
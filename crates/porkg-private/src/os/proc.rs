@@ -13,33 +13,7 @@ use nix::{
     unistd::Pid,
 };
 
-/// A value that can be converted into an exit code.
-pub trait IntoExitCode {
-    /// Converts the current value into an exit code.
-    fn report(&self) -> i32;
-}
-
-impl<T, E: IntoExitCode> IntoExitCode for Result<T, E> {
-    fn report(&self) -> i32 {
-        match self {
-            Ok(_) => 0,
-            Err(v) => v.report(),
-        }
-    }
-}
-
-impl IntoExitCode for anyhow::Error {
-    fn report(&self) -> i32 {
-        tracing::error!(?self, "process failed");
-        -1
-    }
-}
-
-impl IntoExitCode for i32 {
-    fn report(&self) -> i32 {
-        *self
-    }
-}
+pub use crate::sandbox::IntoExitCode;
 
 static CHILD_DROP_WAIT_MILLIS: AtomicU64 = AtomicU64::new(4500);
 static CHILD_KILL_WAIT_MILLIS: AtomicU64 = AtomicU64::new(500);
@@ -1,9 +1,13 @@
-use std::os::fd::OwnedFd;
+use std::{
+    future::Future,
+    os::fd::{OwnedFd, RawFd},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
 
 use nix::unistd::{Gid, Uid};
 
-use crate::os::proc::IntoExitCode;
-
 bitflags::bitflags! {
     #[derive(Default, Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
     pub struct SandboxFlags: u64 {
@@ -12,11 +16,104 @@ bitflags::bitflags! {
     }
 }
 
+/// A path of the host that is bound into the root file system of the sandbox.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Bind {
+    pub source: PathBuf,
+    /// The absolute path within the sandbox.
+    pub target: PathBuf,
+    pub read_only: bool,
+}
+
+/// The syscalls that are denied by [`SyscallFilter::restrictive`], which administer the host or escape the sandbox.
+const RESTRICTED_SYSCALLS: &[&str] = &[
+    "acct",
+    "add_key",
+    "bpf",
+    "clock_adjtime",
+    "clock_settime",
+    "delete_module",
+    "finit_module",
+    "fsmount",
+    "fsopen",
+    "fspick",
+    "init_module",
+    "kexec_load",
+    "keyctl",
+    "mount",
+    "move_mount",
+    "open_by_handle_at",
+    "open_tree",
+    "perf_event_open",
+    "pivot_root",
+    "process_vm_readv",
+    "process_vm_writev",
+    "ptrace",
+    "quotactl",
+    "reboot",
+    "request_key",
+    "setdomainname",
+    "sethostname",
+    "setns",
+    "settimeofday",
+    "swapoff",
+    "swapon",
+    "syslog",
+    "umount2",
+    "unshare",
+    "userfaultfd",
+    "vhangup",
+];
+
+/// The syscalls that a sandboxed task can make, by name. Syscalls that are filtered out fail with `EPERM`.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SyscallFilter {
+    /// Every syscall is allowed.
+    #[default]
+    Unrestricted,
+    /// Every syscall except these is allowed.
+    Deny(Vec<String>),
+    /// Only these syscalls are allowed.
+    Allow(Vec<String>),
+}
+
+impl SyscallFilter {
+    /// Denies the syscalls that administer the host or could escape the sandbox, such as mounting and loading kernel
+    /// modules, which builds don't need.
+    pub fn restrictive() -> Self {
+        Self::Deny(RESTRICTED_SYSCALLS.iter().map(|v| v.to_string()).collect())
+    }
+}
+
+/// Limits on the resources that a sandbox, and every process in it, can use.
+#[derive(
+    Default, Debug, Clone, Copy, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize,
+)]
+pub struct ResourceLimits {
+    /// The most memory that can be used, in bytes, past which the processes are killed.
+    pub memory: Option<u64>,
+    /// The share of CPU time relative to other sandboxes, from 1 to 10000 (100 if it isn't set).
+    pub cpu_weight: Option<u16>,
+    /// The most processes and threads that can run at once.
+    pub pids: Option<u64>,
+}
+
+impl ResourceLimits {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Hash)]
 pub struct SandboxOptions {
     flags: SandboxFlags,
     sandbox_uid: u32,
     sandbox_gid: u32,
+    root: Option<PathBuf>,
+    binds: Vec<Bind>,
+    syscall_filter: SyscallFilter,
+    limits: ResourceLimits,
+    timeout: Option<Duration>,
 }
 
 impl SandboxOptions {
@@ -32,6 +129,67 @@ impl SandboxOptions {
         Gid::from_raw(self.sandbox_gid)
     }
 
+    /// The directory that the new root file system of the sandbox is mounted on, if the sandbox doesn't share the root
+    /// file system of the host.
+    pub fn root(&self) -> Option<&Path> {
+        self.root.as_deref()
+    }
+
+    pub fn binds(&self) -> &[Bind] {
+        &self.binds
+    }
+
+    /// The syscalls that the task can make once the sandbox has been set up.
+    pub fn syscall_filter(&self) -> &SyscallFilter {
+        &self.syscall_filter
+    }
+
+    pub fn limits(&self) -> ResourceLimits {
+        self.limits
+    }
+
+    /// How long the task may run for before it is killed, along with every process that it started.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Gives the sandbox an empty root file system, which is mounted on `root` (an empty directory of the host) and only
+    /// contains what is bound into it.
+    pub fn with_root(&mut self, root: impl Into<PathBuf>) -> &mut Self {
+        self.root = Some(root.into());
+        self
+    }
+
+    /// Binds a file or directory of the host into the root file system of the sandbox.
+    pub fn with_bind(
+        &mut self,
+        source: impl Into<PathBuf>,
+        target: impl Into<PathBuf>,
+        read_only: bool,
+    ) -> &mut Self {
+        self.binds.push(Bind {
+            source: source.into(),
+            target: target.into(),
+            read_only,
+        });
+        self
+    }
+
+    pub fn with_syscall_filter(&mut self, filter: SyscallFilter) -> &mut Self {
+        self.syscall_filter = filter;
+        self
+    }
+
+    pub fn with_limits(&mut self, limits: ResourceLimits) -> &mut Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn with_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
     pub fn with_network_isolation(&mut self, isolate: bool) -> &mut Self {
         if isolate {
             self.flags.insert(SandboxFlags::NETWORK_ISOLATION)
@@ -42,6 +200,34 @@ impl SandboxOptions {
     }
 }
 
+/// A value that can be converted into an exit code.
+pub trait IntoExitCode {
+    /// Converts the current value into an exit code.
+    fn report(&self) -> i32;
+}
+
+impl<T, E: IntoExitCode> IntoExitCode for Result<T, E> {
+    fn report(&self) -> i32 {
+        match self {
+            Ok(_) => 0,
+            Err(v) => v.report(),
+        }
+    }
+}
+
+impl IntoExitCode for anyhow::Error {
+    fn report(&self) -> i32 {
+        tracing::error!(?self, "process failed");
+        -1
+    }
+}
+
+impl IntoExitCode for i32 {
+    fn report(&self) -> i32 {
+        *self
+    }
+}
+
 pub trait SandboxTask:
     crate::ser::Serialize + crate::ser::Deserialize + Send + Sync + 'static
 {
@@ -49,3 +235,154 @@ pub trait SandboxTask:
     fn execute(&self, fds: impl AsRef<[OwnedFd]>) -> Result<(), Self::ExecuteError>;
     fn create_sandbox_options(&self) -> SandboxOptions;
 }
+
+/// Wraps the execution of tasks in the sandbox, such as to time them or to set up their environment, so that each
+/// concern doesn't need to be built into the worker.
+pub trait TaskMiddleware<T: SandboxTask>: Send + Sync + 'static {
+    /// Executes the task by calling `next`, or fails without executing it.
+    fn execute(
+        &self,
+        task: &T,
+        fds: Vec<OwnedFd>,
+        next: Next<'_, T>,
+    ) -> Result<(), T::ExecuteError>;
+}
+
+/// The middleware after the current one, which ends with the task itself.
+pub struct Next<'a, T: SandboxTask>(&'a [Arc<dyn TaskMiddleware<T>>]);
+
+impl<T: SandboxTask> Next<'_, T> {
+    pub fn run(self, task: &T, fds: Vec<OwnedFd>) -> Result<(), T::ExecuteError> {
+        match self.0.split_first() {
+            Some((middleware, rest)) => middleware.execute(task, fds, Next(rest)),
+            None => task.execute(fds),
+        }
+    }
+}
+
+/// The middleware that tasks are executed through, outermost first.
+pub struct TaskChain<T: SandboxTask>(Vec<Arc<dyn TaskMiddleware<T>>>);
+
+impl<T: SandboxTask> Default for TaskChain<T> {
+    fn default() -> Self {
+        Self(Vec::new())
+    }
+}
+
+impl<T: SandboxTask> Clone for TaskChain<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: SandboxTask> std::fmt::Debug for TaskChain<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("TaskChain").field(&self.0.len()).finish()
+    }
+}
+
+impl<T: SandboxTask> TaskChain<T> {
+    /// Adds middleware inside the middleware that was added before it.
+    pub fn with(mut self, middleware: impl TaskMiddleware<T>) -> Self {
+        self.0.push(Arc::new(middleware));
+        self
+    }
+
+    pub fn execute(&self, task: &T, fds: Vec<OwnedFd>) -> Result<(), T::ExecuteError> {
+        Next(&self.0).run(task, fds)
+    }
+}
+
+/// Starts tasks in sandboxes, independently of how the platform isolates them (namespaces on Linux). The file system
+/// of a sandbox is described by the [`SandboxOptions`] of its task.
+pub trait SandboxBackend<T: SandboxTask>: Clone + Send + Sync + 'static {
+    /// Completes once the task has finished, with the error that it failed with.
+    type Handle: Future<Output = Result<(), Self::TaskError>> + Send + 'static;
+    type TaskError: std::error::Error + Send + Sync + 'static;
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Starts a task in a new sandbox, passing `fds` on to it.
+    fn spawn(
+        &self,
+        task: T,
+        fds: &[RawFd],
+    ) -> impl Send + Future<Output = Result<Self::Handle, Self::Error>>;
+
+    /// Kills the task with `id` (see [`SandboxBackend::id`]), whose handle then completes. Tasks that have already
+    /// finished are ignored.
+    fn kill(&self, id: u64) -> impl Send + Future<Output = Result<(), Self::Error>>;
+
+    /// Identifies the task of a handle among the others that the backend started.
+    fn id(handle: &Self::Handle) -> u64;
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[derive(Debug, thiserror::Error)]
+    #[error("failed")]
+    struct Failed;
+
+    impl IntoExitCode for Failed {
+        fn report(&self) -> i32 {
+            1
+        }
+    }
+
+    #[derive(serde::Serialize, serde::Deserialize)]
+    struct Task;
+
+    static EVENTS: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+    impl SandboxTask for Task {
+        type ExecuteError = Failed;
+
+        fn execute(&self, _fds: impl AsRef<[OwnedFd]>) -> Result<(), Self::ExecuteError> {
+            EVENTS.lock().unwrap().push("task");
+            Ok(())
+        }
+
+        fn create_sandbox_options(&self) -> SandboxOptions {
+            SandboxOptions::default()
+        }
+    }
+
+    struct Record(&'static str);
+
+    impl TaskMiddleware<Task> for Record {
+        fn execute(
+            &self,
+            task: &Task,
+            fds: Vec<OwnedFd>,
+            next: Next<'_, Task>,
+        ) -> Result<(), Failed> {
+            EVENTS.lock().unwrap().push(self.0);
+            next.run(task, fds)
+        }
+    }
+
+    struct Refuse;
+
+    impl TaskMiddleware<Task> for Refuse {
+        fn execute(&self, _: &Task, _: Vec<OwnedFd>, _: Next<'_, Task>) -> Result<(), Failed> {
+            Err(Failed)
+        }
+    }
+
+    #[test]
+    fn execute_chain() {
+        let chain = TaskChain::default()
+            .with(Record("outer"))
+            .with(Record("inner"));
+        chain.execute(&Task, Vec::new()).unwrap();
+        assert_eq!(*EVENTS.lock().unwrap(), ["outer", "inner", "task"]);
+
+        EVENTS.lock().unwrap().clear();
+        let chain = chain.with(Refuse);
+        assert!(chain.execute(&Task, Vec::new()).is_err());
+        assert_eq!(*EVENTS.lock().unwrap(), ["outer", "inner"]);
+    }
+}
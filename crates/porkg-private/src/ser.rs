@@ -2,7 +2,7 @@
 
 use std::mem::size_of;
 
-pub use bincode::Error;
+pub use bincode::{Error, ErrorKind};
 use bytes::{Buf, BufMut};
 pub use serde::{de::DeserializeOwned as Deserialize, Serialize};
 
@@ -13,11 +13,31 @@ pub fn serialize<T: Serialize + ?Sized>(data: &T, buf: &mut impl BufMut) -> Resu
     bincode::serialize_into(writer, data)
 }
 
+/// Gets the number of bytes that [`serialize`] writes for `data`.
+pub fn serialized_size<T: Serialize + ?Sized>(data: &T) -> Result<u64, Error> {
+    bincode::serialized_size(data)
+}
+
 pub fn deserialize<T: Deserialize + ?Sized>(buf: &mut impl Buf) -> Result<T, Error> {
     let reader = buf.reader();
     bincode::deserialize_from(reader)
 }
 
+/// Deserializes untrusted data from a stream, failing with [`ErrorKind::SizeLimit`] instead of allocating if it claims
+/// to contain more than `limit` bytes.
+pub fn deserialize_from<T: Deserialize + ?Sized>(
+    reader: impl std::io::Read,
+    limit: u64,
+) -> Result<T, Error> {
+    use bincode::Options as _;
+
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(limit)
+        .deserialize_from(reader)
+}
+
 pub mod fromstr {
     use serde::{de, Deserialize};
     use std::str::FromStr;
@@ -84,3 +104,132 @@ pub mod pathbuf {
         )
     }
 }
+
+pub mod string {
+    use serde::de;
+    use std::fmt;
+
+    struct SerializedString;
+
+    /// An element of a string that was split on the list separator, which `config` may have parsed as a number.
+    struct Element(String);
+
+    impl<'de> de::Deserialize<'de> for Element {
+        fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: de::Deserializer<'de>,
+        {
+            struct ElementVisitor;
+
+            impl<'de> de::Visitor<'de> for ElementVisitor {
+                type Value = Element;
+
+                fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                    formatter.write_str("a string or number")
+                }
+
+                fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                    Ok(Element(v.to_string()))
+                }
+
+                fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                    Ok(Element(v.to_string()))
+                }
+
+                fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                    Ok(Element(v.to_string()))
+                }
+            }
+
+            deserializer.deserialize_any(ElementVisitor)
+        }
+    }
+
+    impl<'de> de::Visitor<'de> for SerializedString {
+        type Value = String;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a string")
+        }
+
+        fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: de::Error,
+        {
+            Ok(v.to_string())
+        }
+
+        fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+        where
+            A: de::SeqAccess<'de>,
+        {
+            // Environment variables in config always appear as sequences, split on the list separator, which is
+            // restored so that values such as URLs survive.
+            let mut parts = Vec::new();
+            while let Some(Element(v)) = seq.next_element()? {
+                parts.push(v);
+            }
+            if parts.is_empty() {
+                return Err(de::Error::invalid_length(0, &self));
+            }
+            Ok(parts.join(":"))
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<String, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        deserializer.deserialize_any(SerializedString)
+    }
+}
+
+/// Like [`pathbuf`], for values that are optional.
+pub mod option_pathbuf {
+    use serde::{de, Deserialize};
+    use std::path::PathBuf;
+
+    #[derive(Deserialize)]
+    struct Value(#[serde(with = "super::pathbuf")] PathBuf);
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<PathBuf>, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        Ok(Option::<Value>::deserialize(deserializer)?.map(|Value(v)| v))
+    }
+}
+
+/// Like [`string`], for values that are optional.
+pub mod option_string {
+    use serde::{de, Deserialize};
+
+    #[derive(Deserialize)]
+    struct Value(#[serde(with = "super::string")] String);
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<String>, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        Ok(Option::<Value>::deserialize(deserializer)?.map(|Value(v)| v))
+    }
+}
+
+/// Like [`string`], for the values of maps.
+pub mod string_map {
+    use serde::{de, Deserialize};
+    use std::collections::BTreeMap;
+
+    #[derive(Deserialize)]
+    struct Value(#[serde(with = "super::string")] String);
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error>
+    where
+        D: de::Deserializer<'de>,
+    {
+        Ok(BTreeMap::<String, Value>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(k, Value(v))| (k, v))
+            .collect())
+    }
+}
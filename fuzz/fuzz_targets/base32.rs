@@ -0,0 +1,23 @@
+//! The base32 that hashes are displayed as, with the lengths that are in use.
+
+#![no_main]
+
+use std::str::FromStr;
+
+use libfuzzer_sys::fuzz_target;
+use porkg_model::base32::Base32;
+
+fn round_trip<const SIZE: usize>(data: &str) {
+    if let Ok(value) = Base32::<SIZE>::from_str(data) {
+        assert_eq!(data.to_ascii_lowercase(), value.to_string());
+        assert_eq!(
+            value.to_string().parse::<Base32<SIZE>>().unwrap().0,
+            value.0
+        );
+    }
+}
+
+fuzz_target!(|data: &str| {
+    round_trip::<20>(data);
+    round_trip::<32>(data);
+});
@@ -0,0 +1,16 @@
+//! Variables are expanded in the strings of manifests.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use porkg_private::string::expand;
+
+fuzz_target!(|data: &str| {
+    // Only some variables are known, so that both outcomes of a lookup are reached.
+    let result = expand(data, |name| name.starts_with('a').then(|| name.repeat(2)));
+    if let Err(error) = result {
+        assert!(error.offset() <= data.len());
+    } else if !data.contains(['$', '\\']) {
+        assert_eq!(result.unwrap(), data);
+    }
+});
@@ -0,0 +1,13 @@
+//! Manifests (`porkg.toml`) are submitted by clients to be built and linted.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use porkg_model::{lint::lint, package::Package};
+
+fuzz_target!(|data: &str| {
+    if let Ok(package) = toml::from_str::<Package>(data) {
+        lint(&package);
+        for _ in package.phases() {}
+    }
+});
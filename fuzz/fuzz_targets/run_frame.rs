@@ -0,0 +1,18 @@
+//! The frames that carry the output of commands that clients run through the daemon.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use porkg_model::run::Frame;
+
+fuzz_target!(|data: &[u8]| {
+    let mut rest = data;
+    while let Ok(Some((frame, len))) = Frame::decode(rest) {
+        assert!(len > 0 && len <= rest.len());
+        // A frame that was decoded is encoded the same way.
+        let mut encoded = Vec::new();
+        frame.encode(&mut encoded);
+        assert_eq!(encoded, rest[..len]);
+        rest = &rest[len..];
+    }
+});
@@ -0,0 +1,20 @@
+//! Hashes are parsed from requests, manifests and locks.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use porkg_model::hashing::SupportedHash;
+
+fn round_trip(data: &str) {
+    if let Ok(hash) = data.parse::<SupportedHash>() {
+        // Hashes are displayed in one form, which parses back to the same hash.
+        assert_eq!(hash.to_string().parse::<SupportedHash>().unwrap(), hash);
+    }
+}
+
+fuzz_target!(|data: &str| {
+    round_trip(data);
+    // The digest is where the parsing happens, which the fuzzer would rarely reach behind the algorithm otherwise.
+    round_trip(&format!("blake3-{data}"));
+    round_trip(&format!("blake3:{data}"));
+});